@@ -0,0 +1,32 @@
+import { EventEmitter } from 'events';
+
+export class DhtMsgNode extends EventEmitter {
+  /** Start a node with the identity of a hex secret key (random if omitted). */
+  static start(secret?: string): Promise<DhtMsgNode>;
+
+  readonly localId: string;
+  readonly infohash: string;
+
+  announce(): Promise<void>;
+  /** Candidate `ip:port` addresses of a peer ID. */
+  findPeer(peerId: string): Promise<string[]>;
+  send(addr: string, data: Buffer | string): void;
+  /** Send a message once; returns its sequence number. */
+  sendMessage(addr: string, data: Buffer | string): number;
+  /** Send a message and resolve with its sequence number once acknowledged. */
+  sendMessageReliable(addr: string, data: Buffer | string): Promise<number>;
+  sendHello(addr: string): void;
+  /** Say goodbye to peers and stop the node's threads. */
+  close(): Promise<void>;
+
+  on(event: 'peer' | 'hello-sent', listener: (addr: string) => void): this;
+  on(event: 'hello' | 'ack' | 'goodbye', listener: (addr: string, message: string) => void): this;
+  on(event: 'message', listener: (addr: string, data: Buffer, sender: string) => void): this;
+  on(event: 'message-ack', listener: (addr: string, seq: string) => void): this;
+  on(event: 'version-mismatch', listener: (addr: string, version: string) => void): this;
+  on(event: 'message-expired', listener: (peerId: string, data: Buffer) => void): this;
+  on(event: 'announce-failed', listener: (error: string) => void): this;
+  on(event: 'peer-authenticated', listener: (addr: string, peerId: string) => void): this;
+  on(event: 'record', listener: (addr: string, peerId: string) => void): this;
+  on(event: 'mailbox-message', listener: (sender: string, data: Buffer) => void): this;
+}
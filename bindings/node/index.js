@@ -0,0 +1,80 @@
+'use strict';
+
+const { EventEmitter } = require('events');
+const native = require('./dhtmsg.node');
+
+// Emits 'peer' (addr), 'hello-sent' (addr), 'hello' (addr, message),
+// 'ack' (addr, message), 'peer-authenticated' (addr, peer ID),
+// 'message' (addr, Buffer, sender ID), 'message-ack' (addr, sequence number as string),
+// 'goodbye' (addr, message), 'version-mismatch' (addr, newest protocol version
+// the peer offered, as string), 'message-expired' (peer ID, Buffer),
+// 'announce-failed' (error message), 'mailbox-message' (sender ID, Buffer) and
+// 'record' (address of a newer endpoint record, peer ID).
+class DhtMsgNode extends EventEmitter {
+  constructor(inner) {
+    super();
+    this.inner = inner;
+    inner.onEvent((event) => {
+      switch (event.kind) {
+        case 'peer':
+        case 'hello-sent':
+          this.emit(event.kind, event.addr);
+          break;
+        case 'message':
+          this.emit('message', event.addr, event.data, event.message);
+          break;
+        case 'message-expired':
+        case 'mailbox-message':
+          this.emit(event.kind, event.message, event.data);
+          break;
+        case 'announce-failed':
+          this.emit('announce-failed', event.message);
+          break;
+        default:
+          this.emit(event.kind, event.addr, event.message);
+      }
+    });
+  }
+
+  static async start(secret) {
+    return new DhtMsgNode(await native.start(secret));
+  }
+
+  get localId() {
+    return this.inner.localId;
+  }
+
+  get infohash() {
+    return this.inner.infohash;
+  }
+
+  announce() {
+    return this.inner.announce();
+  }
+
+  findPeer(peerId) {
+    return this.inner.findPeer(peerId);
+  }
+
+  send(addr, data) {
+    this.inner.send(addr, Buffer.from(data));
+  }
+
+  sendMessage(addr, data) {
+    return this.inner.sendMessage(addr, Buffer.from(data));
+  }
+
+  sendMessageReliable(addr, data) {
+    return this.inner.sendMessageReliable(addr, Buffer.from(data));
+  }
+
+  sendHello(addr) {
+    this.inner.sendHello(addr);
+  }
+
+  close() {
+    return this.inner.close();
+  }
+}
+
+module.exports = { DhtMsgNode };
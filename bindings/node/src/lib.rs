@@ -0,0 +1,286 @@
+//! Node.js bindings for dhtmsg. `index.js` wraps [`Node`] into an EventEmitter.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use dhtmsg::{DhtMsg, Event, Identity};
+use napi::{
+    Env, Error, JsFunction, Result, Task,
+    bindgen_prelude::{AsyncTask, Buffer},
+    threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode},
+};
+use napi_derive::napi;
+
+fn to_napi(err: dhtmsg::DhtMsgError) -> Error {
+    Error::from_reason(format!("{:#}", anyhow::Error::from(err)))
+}
+
+fn parse_addr(addr: &str) -> Result<SocketAddr> {
+    addr.parse()
+        .map_err(|_| Error::from_reason(format!("invalid address: {addr}")))
+}
+
+/// Event passed to `onEvent` callbacks.
+#[napi(object)]
+pub struct NodeEvent {
+    /// `peer`, `hello-sent`, `hello`, `ack`, `peer-authenticated`, `message`,
+    /// `message-ack`, `goodbye`, `message-expired`, `version-mismatch`,
+    /// `announce-failed`, `mailbox-message` or `record`.
+    pub kind: String,
+    pub addr: Option<String>,
+    pub message: Option<String>,
+    /// Payload of a `message`, `message-expired` or `mailbox-message` event,
+    /// byte for byte.
+    pub data: Option<Buffer>,
+}
+
+impl From<&Event> for NodeEvent {
+    fn from(event: &Event) -> Self {
+        let data = match event {
+            Event::MessageReceived { payload, .. }
+            | Event::MessageExpired { payload, .. }
+            | Event::MailboxMessage { payload, .. } => Some(payload.clone().into()),
+            _ => None,
+        };
+        let (kind, addr, message) = match event {
+            Event::PeerDiscovered { addr } => ("peer", Some(addr.to_string()), None),
+            Event::HelloSent { to } => ("hello-sent", Some(to.to_string()), None),
+            Event::HelloReceived { from, message } => {
+                ("hello", Some(from.to_string()), Some(message.clone()))
+            }
+            Event::AckReceived { from, message } => {
+                ("ack", Some(from.to_string()), Some(message.clone()))
+            }
+            Event::PeerAuthenticated { from, id } => (
+                "peer-authenticated",
+                Some(from.to_string()),
+                Some(id.clone()),
+            ),
+            Event::MessageReceived { from, sender, .. } => {
+                ("message", Some(from.to_string()), Some(sender.clone()))
+            }
+            Event::MessageAcked { from, seq } => (
+                "message-ack",
+                Some(from.to_string()),
+                Some(seq.to_string()),
+            ),
+            Event::PeerLeft { from, message } => {
+                ("goodbye", Some(from.to_string()), Some(message.clone()))
+            }
+            Event::MessageExpired { peer_id, .. } => {
+                ("message-expired", None, Some(peer_id.clone()))
+            }
+            Event::VersionMismatch { from, version } => (
+                "version-mismatch",
+                Some(from.to_string()),
+                Some(version.to_string()),
+            ),
+            Event::AnnounceFailed { error, .. } => ("announce-failed", None, Some(error.clone())),
+            Event::RecordReceived { peer_id, record } => (
+                "record",
+                Some(record.addr.to_string()),
+                Some(peer_id.clone()),
+            ),
+            Event::MailboxMessage { sender, .. } => ("mailbox-message", None, Some(sender.clone())),
+        };
+        Self {
+            kind: kind.to_string(),
+            addr,
+            message,
+            data,
+        }
+    }
+}
+
+/// A running dhtmsg node.
+#[napi]
+pub struct Node {
+    inner: Arc<DhtMsg>,
+}
+
+pub struct StartTask {
+    secret: Option<String>,
+}
+
+impl Task for StartTask {
+    type Output = DhtMsg;
+    type JsValue = Node;
+
+    fn compute(&mut self) -> Result<DhtMsg> {
+        let mut builder = DhtMsg::builder();
+        if let Some(secret) = self.secret.take() {
+            builder = builder.identity(Identity::from_secret_hex(&secret).map_err(to_napi)?);
+        }
+        let node = builder.build().map_err(to_napi)?;
+        node.spawn_receiver();
+        Ok(node)
+    }
+
+    fn resolve(&mut self, _env: Env, node: DhtMsg) -> Result<Node> {
+        Ok(Node {
+            inner: Arc::new(node),
+        })
+    }
+}
+
+/// Start a node with the identity of a hex secret key (random if omitted).
+/// Resolves once the DHT has bootstrapped.
+#[napi]
+pub fn start(secret: Option<String>) -> AsyncTask<StartTask> {
+    AsyncTask::new(StartTask { secret })
+}
+
+pub struct FindPeerTask {
+    node: Arc<DhtMsg>,
+    peer_id: String,
+}
+
+impl Task for FindPeerTask {
+    type Output = Vec<SocketAddr>;
+    type JsValue = Vec<String>;
+
+    fn compute(&mut self) -> Result<Vec<SocketAddr>> {
+        self.node.find_peer(&self.peer_id).map_err(to_napi)
+    }
+
+    fn resolve(&mut self, _env: Env, found: Vec<SocketAddr>) -> Result<Vec<String>> {
+        Ok(found.iter().map(ToString::to_string).collect())
+    }
+}
+
+pub struct AnnounceTask {
+    node: Arc<DhtMsg>,
+}
+
+impl Task for AnnounceTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> Result<()> {
+        self.node.announce().map_err(to_napi)
+    }
+
+    fn resolve(&mut self, _env: Env, _output: ()) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct SendMessageTask {
+    node: Arc<DhtMsg>,
+    addr: SocketAddr,
+    data: Vec<u8>,
+}
+
+impl Task for SendMessageTask {
+    type Output = u32;
+    type JsValue = u32;
+
+    fn compute(&mut self) -> Result<u32> {
+        self.node
+            .send_message_reliable(self.addr, &self.data)
+            .map_err(to_napi)
+    }
+
+    fn resolve(&mut self, _env: Env, seq: u32) -> Result<u32> {
+        Ok(seq)
+    }
+}
+
+pub struct ShutdownTask {
+    node: Arc<DhtMsg>,
+}
+
+impl Task for ShutdownTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> Result<()> {
+        self.node.shutdown();
+        Ok(())
+    }
+
+    fn resolve(&mut self, _env: Env, _output: ()) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[napi]
+impl Node {
+    #[napi(getter)]
+    pub fn local_id(&self) -> String {
+        self.inner.local_id().to_string()
+    }
+
+    #[napi(getter)]
+    pub fn infohash(&self) -> String {
+        self.inner.infohash().to_string()
+    }
+
+    #[napi]
+    pub fn announce(&self) -> AsyncTask<AnnounceTask> {
+        AsyncTask::new(AnnounceTask {
+            node: self.inner.clone(),
+        })
+    }
+
+    /// Look up candidate `ip:port` addresses for a peer ID.
+    #[napi]
+    pub fn find_peer(&self, peer_id: String) -> AsyncTask<FindPeerTask> {
+        AsyncTask::new(FindPeerTask {
+            node: self.inner.clone(),
+            peer_id,
+        })
+    }
+
+    #[napi]
+    pub fn send(&self, addr: String, data: Buffer) -> Result<()> {
+        self.inner
+            .send(parse_addr(&addr)?, &data)
+            .map_err(to_napi)
+    }
+
+    /// Send a message once; returns its sequence number.
+    #[napi]
+    pub fn send_message(&self, addr: String, data: Buffer) -> Result<u32> {
+        self.inner
+            .send_message(parse_addr(&addr)?, &data)
+            .map_err(to_napi)
+    }
+
+    /// Send a message and resolve once the peer acknowledges it.
+    #[napi]
+    pub fn send_message_reliable(
+        &self,
+        addr: String,
+        data: Buffer,
+    ) -> Result<AsyncTask<SendMessageTask>> {
+        Ok(AsyncTask::new(SendMessageTask {
+            node: self.inner.clone(),
+            addr: parse_addr(&addr)?,
+            data: data.to_vec(),
+        }))
+    }
+
+    #[napi]
+    pub fn send_hello(&self, addr: String) -> Result<()> {
+        self.inner.send_hello(parse_addr(&addr)?).map_err(to_napi)
+    }
+
+    /// Say goodbye to peers and stop the node's threads.
+    #[napi]
+    pub fn close(&self) -> AsyncTask<ShutdownTask> {
+        AsyncTask::new(ShutdownTask {
+            node: self.inner.clone(),
+        })
+    }
+
+    /// Call `callback(event)` on the JS thread for every node event.
+    #[napi(ts_args_type = "callback: (event: NodeEvent) => void")]
+    pub fn on_event(&self, callback: JsFunction) -> Result<()> {
+        let tsfn: ThreadsafeFunction<NodeEvent, ErrorStrategy::Fatal> =
+            callback.create_threadsafe_function(0, |ctx| Ok(vec![ctx.value]))?;
+        self.inner.on_event(move |event| {
+            tsfn.call(event.into(), ThreadsafeFunctionCallMode::NonBlocking);
+        });
+        Ok(())
+    }
+}
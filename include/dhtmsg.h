@@ -0,0 +1,101 @@
+/*
+ * C API for dhtmsg: DHT rendezvous and UDP hello messaging.
+ *
+ * Mirrors src/ffi.rs; keep both in sync. Link against the cdylib built by
+ * `cargo build --release` (libdhtmsg.so / dhtmsg.dll / libdhtmsg.dylib).
+ *
+ * Functions returning int use 0 for success and -1 for failure; call
+ * dhtmsg_last_error() on the same thread for the failure message.
+ */
+#ifndef DHTMSG_H
+#define DHTMSG_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct DhtMsgHandle dhtmsg_t;
+
+typedef struct {
+    uint8_t ip[4];
+    uint16_t port;
+} dhtmsg_addr_t;
+
+#define DHTMSG_EVENT_PEER_DISCOVERED 1
+#define DHTMSG_EVENT_HELLO_RECEIVED 2
+#define DHTMSG_EVENT_ACK_RECEIVED 3
+#define DHTMSG_EVENT_ANNOUNCE_FAILED 4
+#define DHTMSG_EVENT_PEER_LEFT 5
+/* message is the payload, lossily UTF-8; see dhtmsg_set_message_callback */
+#define DHTMSG_EVENT_MESSAGE_RECEIVED 6
+#define DHTMSG_EVENT_MESSAGE_ACKED 7 /* message is the sequence number */
+/* message is the newest protocol version the peer offered */
+#define DHTMSG_EVENT_VERSION_MISMATCH 8
+/* a queued message outlived its TTL; message is the peer ID */
+#define DHTMSG_EVENT_MESSAGE_EXPIRED 9
+/* the peer answered our challenge and is established; message is its ID */
+#define DHTMSG_EVENT_PEER_AUTHENTICATED 10
+/* a lookup fetched a newer endpoint record; message is the peer ID */
+#define DHTMSG_EVENT_RECORD_RECEIVED 11
+/* a peer left a message in our mailbox; addr is its ID, not an address */
+#define DHTMSG_EVENT_MAILBOX_MESSAGE 12
+/* we sent a hello to addr */
+#define DHTMSG_EVENT_HELLO_SENT 13
+
+/* addr and message are only valid during the call; message may be empty. */
+typedef void (*dhtmsg_callback_t)(void *user_data, int kind, const char *addr,
+                                  const char *message);
+
+/* Message payloads byte for byte: data holds len bytes; all pointers are
+ * only valid during the call. */
+typedef void (*dhtmsg_message_callback_t)(void *user_data, const char *addr,
+                                          const char *sender,
+                                          const uint8_t *data, size_t len);
+
+/* Start a node with the identity of a hex Ed25519 secret key, as printed by
+ * `dhtmsg keygen` (random identity if secret is NULL), announce it and answer
+ * hellos. Blocks while the DHT bootstraps. Returns NULL on failure. */
+dhtmsg_t *dhtmsg_start(const char *secret);
+
+/* Local hex ID (the public key), owned by the handle. */
+const char *dhtmsg_local_id(const dhtmsg_t *node);
+
+int dhtmsg_announce(const dhtmsg_t *node);
+
+/* Write up to cap IPv4 candidates to out; returns the number found (may
+ * exceed cap) or -1. */
+int dhtmsg_find_peer(const dhtmsg_t *node, const char *peer_id,
+                     dhtmsg_addr_t *out, size_t cap);
+
+int dhtmsg_send(const dhtmsg_t *node, const dhtmsg_addr_t *addr,
+                const uint8_t *data, size_t len);
+
+int dhtmsg_send_hello(const dhtmsg_t *node, const dhtmsg_addr_t *addr);
+
+/* Send a message; the peer acks it with DHTMSG_EVENT_MESSAGE_ACKED. Its
+ * sequence number is stored in seq unless seq is NULL. */
+int dhtmsg_send_message(const dhtmsg_t *node, const dhtmsg_addr_t *addr,
+                        const uint8_t *data, size_t len, uint32_t *seq);
+
+/* The callback runs on the node's threads. */
+int dhtmsg_set_callback(const dhtmsg_t *node, dhtmsg_callback_t callback,
+                        void *user_data);
+
+/* Like dhtmsg_set_callback, for received messages only. */
+int dhtmsg_set_message_callback(const dhtmsg_t *node,
+                                dhtmsg_message_callback_t callback,
+                                void *user_data);
+
+const char *dhtmsg_last_error(void);
+
+/* Shut the node down (goodbye to peers, threads joined) and free it. */
+void dhtmsg_free(dhtmsg_t *node);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* DHTMSG_H */
@@ -0,0 +1,71 @@
+//! Which candidate addresses are worth a hello.
+//!
+//! Lookups return whatever was announced, and some of it can never answer:
+//! unspecified, multicast, broadcast and reserved addresses, or port 0.
+//! Those are always dropped. Private addresses can answer, but only on the
+//! network they belong to. Elsewhere a hello to one is wasted or reaches an
+//! unrelated host. So whether they count is up to the node (see
+//! [`DhtMsgBuilder::allow_private`](crate::DhtMsgBuilder::allow_private)).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Which addresses a node accepts as candidates and publishes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Scope {
+    /// Public addresses only.
+    #[default]
+    Public,
+    /// Public and private ones.
+    Any,
+    /// Private ones only.
+    Lan,
+}
+
+impl Scope {
+    pub(crate) fn accepts(self, addr: &SocketAddr) -> bool {
+        if is_bogon(addr) {
+            return false;
+        }
+        match self {
+            Scope::Public => !is_private(addr.ip()),
+            Scope::Any => true,
+            Scope::Lan => is_private(addr.ip()),
+        }
+    }
+}
+
+/// Whether nothing can answer at `addr`.
+fn is_bogon(addr: &SocketAddr) -> bool {
+    if addr.port() == 0 {
+        return true;
+    }
+    match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            ip.is_unspecified()
+                || ip.is_multicast()
+                || ip.is_broadcast()
+                // 0.0.0.0/8 and 240.0.0.0/4
+                || ip.octets()[0] == 0
+                || ip.octets()[0] >= 240
+        }
+        IpAddr::V6(ip) => ip.is_unspecified() || ip.is_multicast(),
+    }
+}
+
+/// Whether `ip` is only reachable on a local network: RFC 1918, shared
+/// (carrier-grade NAT), loopback, link-local or unique local addresses.
+pub(crate) fn is_private(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => is_private_v6(ip),
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || (a == 100 && b & 0xc0 == 64)
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    ip.is_loopback() || ip.is_unicast_link_local() || ip.is_unique_local()
+}
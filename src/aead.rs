@@ -0,0 +1,282 @@
+//! ChaCha20-Poly1305 authenticated encryption (RFC 8439).
+//!
+//! Implemented here, like the LZ4 codec, so the crate keeps building from
+//! the few dependencies it already has. Keys are 32 bytes, nonces 12 and
+//! tags 16; a tag is appended to every ciphertext.
+
+pub(crate) const KEY_BYTES: usize = 32;
+pub(crate) const NONCE_BYTES: usize = 12;
+pub(crate) const TAG_BYTES: usize = 16;
+
+/// Encrypt `plaintext` and append the tag authenticating it and `aad`.
+pub(crate) fn seal(
+    key: &[u8; KEY_BYTES],
+    nonce: &[u8; NONCE_BYTES],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Vec<u8> {
+    let mut sealed = plaintext.to_vec();
+    chacha20_xor(key, nonce, 1, &mut sealed);
+    let tag = tag(key, nonce, aad, &sealed);
+    sealed.extend_from_slice(&tag);
+    sealed
+}
+
+/// Plaintext of `sealed`, or `None` if it or `aad` was tampered with.
+pub(crate) fn open(
+    key: &[u8; KEY_BYTES],
+    nonce: &[u8; NONCE_BYTES],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Option<Vec<u8>> {
+    let (ciphertext, received) = sealed.split_last_chunk::<TAG_BYTES>()?;
+    let expected = tag(key, nonce, aad, ciphertext);
+    // Constant time, so the comparison does not leak how much matched.
+    let difference = expected
+        .iter()
+        .zip(received)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    if difference != 0 {
+        return None;
+    }
+    let mut plaintext = ciphertext.to_vec();
+    chacha20_xor(key, nonce, 1, &mut plaintext);
+    Some(plaintext)
+}
+
+fn tag(
+    key: &[u8; KEY_BYTES],
+    nonce: &[u8; NONCE_BYTES],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> [u8; 16] {
+    let block = chacha20_block(key, nonce, 0);
+    let mut poly = Poly1305::new(block[..32].try_into().unwrap());
+    poly.update_padded(aad);
+    poly.update_padded(ciphertext);
+    let mut lengths = [0u8; 16];
+    lengths[..8].copy_from_slice(&(aad.len() as u64).to_le_bytes());
+    lengths[8..].copy_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    poly.update_padded(&lengths);
+    poly.finish()
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u8; KEY_BYTES], nonce: &[u8; NONCE_BYTES], counter: u32) -> [u8; 64] {
+    let word = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for (i, chunk) in key.chunks_exact(4).enumerate() {
+        initial[4 + i] = word(chunk);
+    }
+    initial[12] = counter;
+    for (i, chunk) in nonce.chunks_exact(4).enumerate() {
+        initial[13 + i] = word(chunk);
+    }
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    let mut block = [0u8; 64];
+    for (i, chunk) in block.chunks_exact_mut(4).enumerate() {
+        chunk.copy_from_slice(&state[i].wrapping_add(initial[i]).to_le_bytes());
+    }
+    block
+}
+
+fn chacha20_xor(key: &[u8; KEY_BYTES], nonce: &[u8; NONCE_BYTES], counter: u32, data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let block = chacha20_block(key, nonce, counter.wrapping_add(i as u32));
+        for (byte, key_byte) in chunk.iter_mut().zip(block) {
+            *byte ^= key_byte;
+        }
+    }
+}
+
+/// Poly1305 with 26-bit limbs, after poly1305-donna.
+struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+}
+
+impl Poly1305 {
+    fn new(key: &[u8; 32]) -> Self {
+        let word = |i: usize| u32::from_le_bytes(key[i..i + 4].try_into().unwrap());
+        Self {
+            r: [
+                word(0) & 0x03ff_ffff,
+                (word(3) >> 2) & 0x03ff_ff03,
+                (word(6) >> 4) & 0x03ff_c0ff,
+                (word(9) >> 6) & 0x03f0_3fff,
+                (word(12) >> 8) & 0x000f_ffff,
+            ],
+            h: [0; 5],
+            pad: [word(16), word(20), word(24), word(28)],
+        }
+    }
+
+    /// Absorb `data` zero-padded to a multiple of 16 bytes.
+    fn update_padded(&mut self, data: &[u8]) {
+        for chunk in data.chunks(16) {
+            let mut block = [0u8; 16];
+            block[..chunk.len()].copy_from_slice(chunk);
+            self.block(&block);
+        }
+    }
+
+    fn block(&mut self, block: &[u8; 16]) {
+        let word = |i: usize| u32::from_le_bytes(block[i..i + 4].try_into().unwrap());
+        let [r0, r1, r2, r3, r4] = self.r;
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+        let h = &mut self.h;
+        h[0] += word(0) & 0x03ff_ffff;
+        h[1] += (word(3) >> 2) & 0x03ff_ffff;
+        h[2] += (word(6) >> 4) & 0x03ff_ffff;
+        h[3] += (word(9) >> 6) & 0x03ff_ffff;
+        h[4] += (word(12) >> 8) | (1 << 24);
+
+        let m = |a: u32, b: u32| u64::from(a) * u64::from(b);
+        let d0 = m(h[0], r0) + m(h[1], s4) + m(h[2], s3) + m(h[3], s2) + m(h[4], s1);
+        let mut d1 = m(h[0], r1) + m(h[1], r0) + m(h[2], s4) + m(h[3], s3) + m(h[4], s2);
+        let mut d2 = m(h[0], r2) + m(h[1], r1) + m(h[2], r0) + m(h[3], s4) + m(h[4], s3);
+        let mut d3 = m(h[0], r3) + m(h[1], r2) + m(h[2], r1) + m(h[3], r0) + m(h[4], s4);
+        let mut d4 = m(h[0], r4) + m(h[1], r3) + m(h[2], r2) + m(h[3], r1) + m(h[4], r0);
+
+        let mut carry = d0 >> 26;
+        h[0] = d0 as u32 & 0x03ff_ffff;
+        d1 += carry;
+        carry = d1 >> 26;
+        h[1] = d1 as u32 & 0x03ff_ffff;
+        d2 += carry;
+        carry = d2 >> 26;
+        h[2] = d2 as u32 & 0x03ff_ffff;
+        d3 += carry;
+        carry = d3 >> 26;
+        h[3] = d3 as u32 & 0x03ff_ffff;
+        d4 += carry;
+        carry = d4 >> 26;
+        h[4] = d4 as u32 & 0x03ff_ffff;
+        h[0] += carry as u32 * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= 0x03ff_ffff;
+    }
+
+    fn finish(self) -> [u8; 16] {
+        let mut h = self.h;
+        // Fully carry h.
+        let mut carry = h[1] >> 26;
+        h[1] &= 0x03ff_ffff;
+        for i in [2, 3, 4] {
+            h[i] += carry;
+            carry = h[i] >> 26;
+            h[i] &= 0x03ff_ffff;
+        }
+        h[0] += carry * 5;
+        carry = h[0] >> 26;
+        h[0] &= 0x03ff_ffff;
+        h[1] += carry;
+
+        // g = h + 5 - 2^130; use it instead of h if that does not underflow.
+        let mut g = [0u32; 5];
+        g[0] = h[0].wrapping_add(5);
+        carry = g[0] >> 26;
+        g[0] &= 0x03ff_ffff;
+        for i in 1..4 {
+            g[i] = h[i].wrapping_add(carry);
+            carry = g[i] >> 26;
+            g[i] &= 0x03ff_ffff;
+        }
+        g[4] = h[4].wrapping_add(carry).wrapping_sub(1 << 26);
+        let mask = (g[4] >> 31).wrapping_sub(1);
+        for i in 0..5 {
+            h[i] = (h[i] & !mask) | (g[i] & mask);
+        }
+
+        // h mod 2^128, plus the pad.
+        let words = [
+            h[0] | (h[1] << 26),
+            (h[1] >> 6) | (h[2] << 20),
+            (h[2] >> 12) | (h[3] << 14),
+            (h[3] >> 18) | (h[4] << 8),
+        ];
+        let mut tag = [0u8; 16];
+        let mut carry = 0u64;
+        for (i, chunk) in tag.chunks_exact_mut(4).enumerate() {
+            let sum = u64::from(words[i]) + u64::from(self.pad[i]) + carry;
+            chunk.copy_from_slice(&(sum as u32).to_le_bytes());
+            carry = sum >> 32;
+        }
+        tag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 8439, section 2.8.2.
+    const PLAINTEXT: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+        only one tip for the future, sunscreen would be it.";
+    const CIPHERTEXT: &str = "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+        3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+        92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+        3ff4def08e4b7a9de576d26586cec64b6116";
+    const TAG: &str = "1ae10b594f09e26a7e902ecbd0600691";
+
+    fn vector() -> ([u8; KEY_BYTES], [u8; NONCE_BYTES], Vec<u8>) {
+        let key: [u8; KEY_BYTES] = std::array::from_fn(|i| 0x80 + i as u8);
+        let nonce = hex::decode("070000004041424344454647").unwrap();
+        let aad = hex::decode("50515253c0c1c2c3c4c5c6c7").unwrap();
+        (key, nonce.try_into().unwrap(), aad)
+    }
+
+    #[test]
+    fn rfc8439_vector() {
+        let (key, nonce, aad) = vector();
+        let sealed = seal(&key, &nonce, &aad, PLAINTEXT);
+        assert_eq!(hex::encode(&sealed), format!("{CIPHERTEXT}{TAG}"));
+        assert_eq!(open(&key, &nonce, &aad, &sealed).unwrap(), PLAINTEXT);
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let (key, nonce, aad) = vector();
+        let sealed = seal(&key, &nonce, &aad, PLAINTEXT);
+        for i in [0, PLAINTEXT.len(), sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert_eq!(open(&key, &nonce, &aad, &tampered), None);
+        }
+        assert_eq!(open(&key, &nonce, b"other aad", &sealed), None);
+        let mut other_nonce = nonce;
+        other_nonce[0] ^= 1;
+        assert_eq!(open(&key, &other_nonce, &aad, &sealed), None);
+        assert_eq!(open(&key, &nonce, &aad, &sealed[..TAG_BYTES - 1]), None);
+    }
+
+    #[test]
+    fn seals_empty_plaintext() {
+        let (key, nonce, aad) = vector();
+        let sealed = seal(&key, &nonce, &aad, &[]);
+        assert_eq!(sealed.len(), TAG_BYTES);
+        assert_eq!(open(&key, &nonce, &aad, &sealed).unwrap(), b"");
+    }
+}
@@ -0,0 +1,322 @@
+//! Argon2id (RFC 9106, version 0x13), for deriving keys from passphrases.
+//!
+//! Lanes are filled one after another on the calling thread; the result is
+//! the same as with threads, only slower for more than one lane.
+
+use crate::blake2b::{MAX_DIGEST_BYTES, blake2b};
+
+const VERSION: u32 = 0x13;
+/// The `y` of the spec for Argon2id.
+const ARGON2ID: u32 = 2;
+const BLOCK_WORDS: usize = 128;
+const SYNC_POINTS: usize = 4;
+
+/// Cost parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Params {
+    /// Memory in KiB.
+    pub(crate) memory_kib: u32,
+    /// Passes over the memory.
+    pub(crate) passes: u32,
+    pub(crate) lanes: u32,
+}
+
+type Block = [u64; BLOCK_WORDS];
+
+/// `out_len` bytes derived from `password` and `salt`.
+pub(crate) fn hash(password: &[u8], salt: &[u8], params: Params, out_len: usize) -> Vec<u8> {
+    keyed_hash(password, salt, &[], &[], params, out_len)
+}
+
+/// [`hash`] with the optional secret key and associated data of the spec.
+fn keyed_hash(
+    password: &[u8],
+    salt: &[u8],
+    secret: &[u8],
+    data: &[u8],
+    params: Params,
+    out_len: usize,
+) -> Vec<u8> {
+    let Params {
+        memory_kib,
+        passes,
+        lanes,
+    } = params;
+    assert!(lanes >= 1 && passes >= 1 && memory_kib >= 8 * lanes);
+    let le = |value: usize| (value as u32).to_le_bytes();
+    let h0 = blake2b(
+        MAX_DIGEST_BYTES,
+        &[
+            &lanes.to_le_bytes(),
+            &le(out_len),
+            &memory_kib.to_le_bytes(),
+            &passes.to_le_bytes(),
+            &VERSION.to_le_bytes(),
+            &ARGON2ID.to_le_bytes(),
+            &le(password.len()),
+            password,
+            &le(salt.len()),
+            salt,
+            &le(secret.len()),
+            secret,
+            &le(data.len()),
+            data,
+        ],
+    );
+
+    let lanes = lanes as usize;
+    let segment_len = memory_kib as usize / (SYNC_POINTS * lanes);
+    let lane_len = segment_len * SYNC_POINTS;
+    let mut memory = vec![[0u64; BLOCK_WORDS]; lane_len * lanes];
+    for lane in 0..lanes {
+        for column in 0..2 {
+            let bytes = long_hash(&[&h0, &le(column), &le(lane)], 1024);
+            memory[lane * lane_len + column] = block_from_bytes(&bytes);
+        }
+    }
+
+    let geometry = Geometry {
+        lanes,
+        lane_len,
+        segment_len,
+        passes,
+        total: (lane_len * lanes) as u64,
+    };
+    for pass in 0..passes {
+        for slice in 0..SYNC_POINTS {
+            for lane in 0..lanes {
+                fill_segment(&mut memory, &geometry, pass, slice, lane);
+            }
+        }
+    }
+
+    let mut last = memory[lane_len - 1];
+    for lane in 1..lanes {
+        xor_into(&mut last, &memory[lane * lane_len + lane_len - 1]);
+    }
+    let last: Vec<u8> = last.iter().flat_map(|word| word.to_le_bytes()).collect();
+    long_hash(&[&last], out_len)
+}
+
+struct Geometry {
+    lanes: usize,
+    lane_len: usize,
+    segment_len: usize,
+    passes: u32,
+    /// Blocks in all lanes, the `m'` of the spec.
+    total: u64,
+}
+
+fn fill_segment(memory: &mut [Block], geometry: &Geometry, pass: u32, slice: usize, lane: usize) {
+    let Geometry {
+        lanes,
+        lane_len,
+        segment_len,
+        ..
+    } = *geometry;
+    // Argon2id takes reference indices from a counter in the first half of
+    // the first pass, and from the memory itself afterwards.
+    let independent = pass == 0 && slice < SYNC_POINTS / 2;
+    let mut input = [0u64; BLOCK_WORDS];
+    let mut addresses = [0u64; BLOCK_WORDS];
+    if independent {
+        input[..6].copy_from_slice(&[
+            u64::from(pass),
+            lane as u64,
+            slice as u64,
+            geometry.total,
+            u64::from(geometry.passes),
+            u64::from(ARGON2ID),
+        ]);
+    }
+    let first = if pass == 0 && slice == 0 { 2 } else { 0 };
+    if independent && first != 0 {
+        next_addresses(&mut input, &mut addresses);
+    }
+
+    for index in first..segment_len {
+        let column = slice * segment_len + index;
+        let current = lane * lane_len + column;
+        let previous = if column == 0 {
+            current + lane_len - 1
+        } else {
+            current - 1
+        };
+        let random = if independent {
+            if index % BLOCK_WORDS == 0 {
+                next_addresses(&mut input, &mut addresses);
+            }
+            addresses[index % BLOCK_WORDS]
+        } else {
+            memory[previous][0]
+        };
+
+        let ref_lane = if pass == 0 && slice == 0 {
+            lane
+        } else {
+            (random >> 32) as usize % lanes
+        };
+        let same_lane = ref_lane == lane;
+        // Blocks that may be referenced: the finished segments, and in our
+        // own lane the current one up to the previous block. Another lane's
+        // last block is off limits at the start of a segment, since that
+        // lane may still be filling it.
+        let finished = if pass == 0 {
+            slice * segment_len
+        } else {
+            lane_len - segment_len
+        };
+        let area = if same_lane {
+            finished + index - 1
+        } else {
+            finished - usize::from(index == 0)
+        } as u64;
+        let x = (random & 0xffff_ffff).pow(2) >> 32;
+        let y = (area * x) >> 32;
+        let relative = (area - 1 - y) as usize;
+        let start = if pass == 0 || slice == SYNC_POINTS - 1 {
+            0
+        } else {
+            (slice + 1) * segment_len
+        };
+        let reference = ref_lane * lane_len + (start + relative) % lane_len;
+
+        let mut block = compress(&memory[previous], &memory[reference]);
+        if pass > 0 {
+            xor_into(&mut block, &memory[current]);
+        }
+        memory[current] = block;
+    }
+}
+
+fn next_addresses(input: &mut Block, addresses: &mut Block) {
+    input[6] += 1;
+    let zero = [0u64; BLOCK_WORDS];
+    *addresses = compress(&zero, &compress(&zero, input));
+}
+
+/// The compression function `G`.
+fn compress(x: &Block, y: &Block) -> Block {
+    let mut r = *x;
+    xor_into(&mut r, y);
+    let mut q = r;
+    for row in 0..8 {
+        let mut indices = [0; 16];
+        for (i, index) in indices.iter_mut().enumerate() {
+            *index = row * 16 + i;
+        }
+        permute(&mut q, indices);
+    }
+    for column in 0..8 {
+        let mut indices = [0; 16];
+        for (i, index) in indices.iter_mut().enumerate() {
+            *index = (i / 2) * 16 + column * 2 + i % 2;
+        }
+        permute(&mut q, indices);
+    }
+    xor_into(&mut q, &r);
+    q
+}
+
+/// The permutation `P` on the 16 words of `block` at `i`.
+fn permute(block: &mut Block, i: [usize; 16]) {
+    let mut v = i.map(|index| block[index]);
+    mix(&mut v, 0, 4, 8, 12);
+    mix(&mut v, 1, 5, 9, 13);
+    mix(&mut v, 2, 6, 10, 14);
+    mix(&mut v, 3, 7, 11, 15);
+    mix(&mut v, 0, 5, 10, 15);
+    mix(&mut v, 1, 6, 11, 12);
+    mix(&mut v, 2, 7, 8, 13);
+    mix(&mut v, 3, 4, 9, 14);
+    for (index, word) in i.into_iter().zip(v) {
+        block[index] = word;
+    }
+}
+
+/// BLAKE2b's `G` with the multiplications Argon2 adds.
+fn mix(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize) {
+    let fbla = |x: u64, y: u64| {
+        x.wrapping_add(y).wrapping_add(
+            2u64.wrapping_mul(x & 0xffff_ffff)
+                .wrapping_mul(y & 0xffff_ffff),
+        )
+    };
+    v[a] = fbla(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = fbla(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = fbla(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = fbla(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+/// The variable-length hash `H'`.
+fn long_hash(parts: &[&[u8]], out_len: usize) -> Vec<u8> {
+    let len = (out_len as u32).to_le_bytes();
+    let mut input = vec![&len[..]];
+    input.extend_from_slice(parts);
+    if out_len <= MAX_DIGEST_BYTES {
+        return blake2b(out_len, &input);
+    }
+    // Half of each of `rounds` chained digests, then one of what remains.
+    let half = MAX_DIGEST_BYTES / 2;
+    let rounds = out_len.div_ceil(half) - 2;
+    let mut out = Vec::with_capacity(out_len);
+    let mut digest = blake2b(MAX_DIGEST_BYTES, &input);
+    for _ in 1..rounds {
+        out.extend_from_slice(&digest[..half]);
+        digest = blake2b(MAX_DIGEST_BYTES, &[&digest]);
+    }
+    out.extend_from_slice(&digest[..half]);
+    out.extend(blake2b(out_len - half * rounds, &[&digest]));
+    out
+}
+
+fn block_from_bytes(bytes: &[u8]) -> Block {
+    let mut block = [0u64; BLOCK_WORDS];
+    for (word, chunk) in block.iter_mut().zip(bytes.chunks_exact(8)) {
+        *word = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    block
+}
+
+fn xor_into(block: &mut Block, other: &Block) {
+    for (word, other) in block.iter_mut().zip(other) {
+        *word ^= other;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc9106_vector() {
+        // Section 5.3.
+        let params = Params {
+            memory_kib: 32,
+            passes: 3,
+            lanes: 4,
+        };
+        let tag = keyed_hash(&[1; 32], &[2; 16], &[3; 8], &[4; 12], params, 32);
+        assert_eq!(
+            hex::encode(tag),
+            "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659"
+        );
+    }
+
+    #[test]
+    fn unkeyed_hash() {
+        let params = Params {
+            memory_kib: 64,
+            passes: 2,
+            lanes: 1,
+        };
+        assert_eq!(
+            hex::encode(hash(b"password", b"somesalt", params, 32)),
+            "16a1a498734609dd01456da406de9f3d9da93e6c86c300a12fc1465214ce4922"
+        );
+    }
+}
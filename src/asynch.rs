@@ -0,0 +1,521 @@
+//! Async variant of [`DhtMsg`](crate::DhtMsg).
+//!
+//! The API is runtime-agnostic: DHT queries go through mainline's async client
+//! and inbound datagrams are handed over through an async channel, so the node
+//! can be driven from tokio, async-std or any other executor.
+
+use std::{
+    collections::HashSet,
+    io,
+    net::{SocketAddr, SocketAddrV4},
+    thread,
+    time::Duration,
+};
+
+use futures_lite::StreamExt;
+use log::info;
+use mainline::{Id, async_dht::AsyncDht};
+
+use crate::{
+    DhtMsg, DhtMsgBuilder, DhtStats, EndpointRecord, Event, NatMapping, PeerStream, ShutdownHandle,
+    TransportKind,
+    delegation::{self, MAX_DELEGATIONS},
+    endpoint,
+    error::{DhtMsgError, Result},
+    id::{derive_relay_infohash, public_key},
+};
+
+/// Inbound datagrams buffered for `recv()`; newer ones are dropped while it is full.
+const INBOUND_QUEUE: usize = 64;
+
+/// Async DHT rendezvous node.
+pub struct Node {
+    inner: DhtMsg,
+    dht: AsyncDht,
+    inbound: flume::Receiver<(Vec<u8>, SocketAddr)>,
+}
+
+impl Node {
+    /// Start a node from `builder` without blocking the executor: port discovery and
+    /// bootstrap run on a helper thread.
+    pub async fn start(builder: DhtMsgBuilder) -> Result<Node> {
+        let (tx, rx) = flume::bounded(1);
+        thread::spawn(move || {
+            let _ = tx.send(builder.build());
+        });
+        let inner = rx
+            .recv_async()
+            .await
+            .map_err(|_| DhtMsgError::Closed("node startup thread"))??;
+
+        let (inbound_tx, inbound) = flume::bounded(INBOUND_QUEUE);
+        inner.spawn_receiver_with(move |datagram, peer| {
+            let _ = inbound_tx.try_send((datagram.to_vec(), peer));
+        });
+
+        Ok(Node {
+            dht: inner.dht().clone().as_async(),
+            inner,
+            inbound,
+        })
+    }
+
+    pub fn local_id(&self) -> &str {
+        self.inner.local_id()
+    }
+
+    pub fn infohash(&self) -> Id {
+        self.inner.infohash()
+    }
+
+    pub fn announced_port(&self) -> u16 {
+        self.inner.announced_port()
+    }
+
+    /// Statistics of the DHT (see [`DhtMsg::dht_stats`]).
+    pub fn dht_stats(&self) -> DhtStats {
+        self.inner.dht_stats()
+    }
+
+    /// The DHT, for a lookup that counts in the stats.
+    fn dht_lookup(&self) -> &AsyncDht {
+        self.inner.queries().lookup();
+        &self.dht
+    }
+
+    /// The DHT, for an announcement or put that counts in the stats.
+    fn dht_store(&self) -> &AsyncDht {
+        self.inner.queries().store();
+        &self.dht
+    }
+
+    /// Whether the DHT runs as a full node (see [`DhtMsg::dht_server_mode`]).
+    pub fn dht_server_mode(&self) -> bool {
+        self.inner.dht_server_mode()
+    }
+
+    /// Where the router forwards the hello port from (see
+    /// [`DhtMsg::mapped_address`]).
+    pub fn mapped_address(&self) -> Option<SocketAddrV4> {
+        self.inner.mapped_address()
+    }
+
+    /// How the NAT maps the hello socket (see [`DhtMsg::nat_mapping`]).
+    pub fn nat_mapping(&self) -> Option<NatMapping> {
+        self.inner.nat_mapping()
+    }
+
+    /// The address the TURN server relays to us (see
+    /// [`DhtMsg::turn_address`]).
+    pub fn turn_address(&self) -> Option<SocketAddrV4> {
+        self.inner.turn_address()
+    }
+
+    /// The address the SOCKS5 proxy relays our datagrams at (see
+    /// [`DhtMsg::proxy_address`]).
+    pub fn proxy_address(&self) -> Option<SocketAddr> {
+        self.inner.proxy_address()
+    }
+
+    /// Subscribe to node events; use `recv_async()` on the returned receiver.
+    pub fn subscribe(&self) -> flume::Receiver<Event> {
+        self.inner.subscribe()
+    }
+
+    /// Announce the local infohash with the hello port (the infohashes of
+    /// the current and adjacent windows if they rotate), unless
+    /// [signed endpoints](DhtMsgBuilder::signed_endpoints) replace it;
+    /// publish our endpoint records with signed endpoints or
+    /// [signaling](DhtMsgBuilder::signaling); and put our
+    /// [delegations](DhtMsgBuilder::delegation). Fails if any announcement
+    /// does.
+    pub async fn announce(&self) -> Result<()> {
+        let port = self.announced_port();
+        let mut result = Ok(());
+        for delegation in self.inner.delegations() {
+            let item = delegation.item().clone();
+            let target = *item.target();
+            match self.dht_store().put_mutable(item, None).await {
+                Ok(_) => info!(
+                    "published delegation from {} to {}",
+                    delegation.from_id(),
+                    delegation.to_id()
+                ),
+                Err(source) => {
+                    self.inner.report_announce_failed(target, &source);
+                    result = Err(DhtMsgError::PublishDelegation { target, source });
+                }
+            }
+        }
+        if self.inner.signaling() {
+            for item in self.inner.endpoint_records()? {
+                let target = *item.target();
+                match self.dht_store().put_mutable(item, None).await {
+                    Ok(_) => info!("published endpoint record {target} with port {port}"),
+                    Err(source) => {
+                        self.inner.report_announce_failed(target, &source);
+                        result = Err(DhtMsgError::PublishEndpoint { target, source });
+                    }
+                }
+            }
+        }
+        if self.inner.signed_endpoints() {
+            return result;
+        }
+        for infohash in self.inner.announced_infohashes()? {
+            match self.dht_store().announce_peer(infohash, Some(port)).await {
+                Ok(_) => info!("announced infohash {infohash} on port {port}"),
+                Err(source) => {
+                    self.inner.report_announce_failed(infohash, &source);
+                    result = Err(DhtMsgError::Announce { infohash, source });
+                }
+            }
+        }
+        result
+    }
+
+    /// Write the DHT nodes we know to the
+    /// [routing table cache](DhtMsgBuilder::routing_cache), if there is one.
+    pub fn save_routing_cache(&self) -> Result<()> {
+        self.inner.save_routing_cache()
+    }
+
+    /// Run one `get_peers` lookup for each of the peer's derived infohashes,
+    /// unless [signed endpoints](DhtMsgBuilder::signed_endpoints) replace
+    /// it, and with signed endpoints or [signaling](DhtMsgBuilder::signaling)
+    /// fetch its records and add the addresses in the newest validly signed
+    /// one. If the peer has moved to another ID, that one is looked up
+    /// instead (see [`resolve_peer`](Self::resolve_peer)).
+    pub async fn find_peer(&self, peer_id: &str) -> Result<Vec<SocketAddr>> {
+        let peer_id = &self.resolve_peer(peer_id).await?;
+        let mut seen = HashSet::new();
+        let mut found = Vec::new();
+        if !self.inner.signed_endpoints() {
+            for infohash in self.inner.peer_infohashes(peer_id)? {
+                let mut stream = self.dht_lookup().get_peers(infohash);
+                while let Some(peers) = stream.next().await {
+                    for addr in peers {
+                        if seen.insert(addr.into()) {
+                            found.push(addr.into());
+                        }
+                    }
+                }
+            }
+        }
+        if self.inner.signaling() {
+            let key = public_key(peer_id)?;
+            let mut newest: Option<EndpointRecord> = None;
+            for salt in self.inner.endpoint_salts() {
+                let items: Vec<_> = self
+                    .dht_lookup()
+                    .get_mutable(key.as_bytes(), Some(&salt), None)
+                    .collect()
+                    .await;
+                if let Some(record) = endpoint::newest(items, &key, &salt)
+                    && newest.as_ref().is_none_or(|newest| newest.seq < record.seq)
+                {
+                    newest = Some(record);
+                }
+            }
+            for addr in self.inner.received_record(peer_id, newest) {
+                if seen.insert(addr) {
+                    found.push(addr);
+                }
+            }
+        }
+        Ok(self.inner.report_discovered(found))
+    }
+
+    /// Where `peer_id` last proved it holds the key of its ID.
+    pub fn verified_peer(&self, peer_id: &str) -> Option<SocketAddr> {
+        self.inner.verified_peer(peer_id)
+    }
+
+    /// How long since `peer_id` last sent an authentic frame from where it
+    /// proved itself.
+    pub fn peer_silence(&self, peer_id: &str) -> Option<Duration> {
+        self.inner.peer_silence(peer_id)
+    }
+
+    /// Find the address that proves to be `peer_id`, as
+    /// [`DhtMsg::verify_peer`] does, on a helper thread.
+    pub async fn verify_peer(&self, peer_id: &str, timeout: Duration) -> Result<SocketAddr> {
+        let (tx, rx) = flume::bounded(1);
+        let inner = self.inner.clone();
+        let peer_id = peer_id.to_string();
+        thread::spawn(move || {
+            let _ = tx.send(inner.verify_peer(&peer_id, timeout));
+        });
+        rx.recv_async()
+            .await
+            .map_err(|_| DhtMsgError::Closed("peer verification thread"))?
+    }
+
+    /// Punch holes through the NATs between us and `peer_id`, as
+    /// [`DhtMsg::punch`] does, on a helper thread.
+    pub async fn punch(&self, peer_id: &str, timeout: Duration) -> Result<SocketAddr> {
+        let (tx, rx) = flume::bounded(1);
+        let inner = self.inner.clone();
+        let peer_id = peer_id.to_string();
+        thread::spawn(move || {
+            let _ = tx.send(inner.punch(&peer_id, timeout));
+        });
+        rx.recv_async()
+            .await
+            .map_err(|_| DhtMsgError::Closed("hole punching thread"))?
+    }
+
+    /// Reach `peer_id` through the relay at `relay`, as
+    /// [`DhtMsg::relay_to`] does, on a helper thread.
+    pub async fn relay_to(&self, peer_id: &str, relay: SocketAddr) -> Result<SocketAddr> {
+        let (tx, rx) = flume::bounded(1);
+        let inner = self.inner.clone();
+        let peer_id = peer_id.to_string();
+        thread::spawn(move || {
+            let _ = tx.send(inner.relay_to(&peer_id, relay));
+        });
+        rx.recv_async()
+            .await
+            .map_err(|_| DhtMsgError::Closed("relay thread"))?
+    }
+
+    /// Relays announced under the relay infohash, other than us.
+    pub async fn find_relays(&self) -> Result<Vec<SocketAddr>> {
+        let infohash = derive_relay_infohash(self.inner.infohash_salt());
+        let mut found = Vec::new();
+        let mut stream = self.dht_lookup().get_peers(infohash);
+        while let Some(peers) = stream.next().await {
+            for addr in peers {
+                let addr = SocketAddr::from(addr);
+                if !found.contains(&addr) {
+                    found.push(addr);
+                }
+            }
+        }
+        Ok(self.inner.other_relays(found))
+    }
+
+    /// Relays that answered our registration lately.
+    pub fn registered_relays(&self) -> Vec<SocketAddr> {
+        self.inner.registered_relays()
+    }
+
+    /// The relay that `addr` leads through (see
+    /// [`DhtMsg::relayed_through`]).
+    pub fn relayed_through(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        self.inner.relayed_through(addr)
+    }
+
+    /// Reach `addr` over TCP, as [`DhtMsg::connect_tcp`] does, on a helper
+    /// thread.
+    pub async fn connect_tcp(&self, addr: SocketAddr) -> Result<()> {
+        let (tx, rx) = flume::bounded(1);
+        let inner = self.inner.clone();
+        thread::spawn(move || {
+            let _ = tx.send(inner.connect_tcp(addr));
+        });
+        rx.recv_async()
+            .await
+            .map_err(|_| DhtMsgError::Closed("TCP connect thread"))?
+    }
+
+    /// Whether datagrams to `addr` go over TCP (see [`DhtMsg::over_tcp`]).
+    pub fn over_tcp(&self, addr: &SocketAddr) -> bool {
+        self.inner.over_tcp(addr)
+    }
+
+    /// Reach `peer_id` at its onion service, as [`DhtMsg::connect_onion`]
+    /// does, on a helper thread.
+    pub async fn connect_onion(&self, peer_id: &str) -> Result<SocketAddr> {
+        let (tx, rx) = flume::bounded(1);
+        let inner = self.inner.clone();
+        let peer_id = peer_id.to_string();
+        thread::spawn(move || {
+            let _ = tx.send(inner.connect_onion(&peer_id));
+        });
+        rx.recv_async()
+            .await
+            .map_err(|_| DhtMsgError::Closed("onion connect thread"))?
+    }
+
+    /// Our onion service's address (see [`DhtMsg::onion_address`]).
+    pub fn onion_address(&self) -> Option<&str> {
+        self.inner.onion_address()
+    }
+
+    /// Whether datagrams to `addr` go through Tor (see
+    /// [`DhtMsg::over_tor`]).
+    pub fn over_tor(&self, addr: &SocketAddr) -> bool {
+        self.inner.over_tor(addr)
+    }
+
+    /// How datagrams to `addr` travel (see [`DhtMsg::peer_transport`]).
+    pub fn peer_transport(&self, addr: SocketAddr) -> TransportKind {
+        self.inner.peer_transport(addr)
+    }
+
+    /// The kind of path costing us and `peer` least together (see
+    /// [`DhtMsg::negotiated_transport`]).
+    pub fn negotiated_transport(&self, peer: SocketAddr) -> Option<TransportKind> {
+        self.inner.negotiated_transport(peer)
+    }
+
+    /// The newest endpoint record that lookups of `peer_id` have fetched.
+    pub fn peer_record(&self, peer_id: &str) -> Option<EndpointRecord> {
+        self.inner.peer_record(peer_id)
+    }
+
+    /// The ID that `peer_id` has moved to, as
+    /// [`DhtMsg::resolve_peer`] finds it.
+    pub async fn resolve_peer(&self, peer_id: &str) -> Result<String> {
+        let from = public_key(peer_id)?;
+        let mut key = from;
+        let mut seen = HashSet::from([key.to_bytes()]);
+        for _ in 0..MAX_DELEGATIONS {
+            let items: Vec<_> = self
+                .dht_lookup()
+                .get_mutable(key.as_bytes(), Some(delegation::SALT), None)
+                .collect()
+                .await;
+            match delegation::newest(items, &key) {
+                Some(next) if seen.insert(next.to_bytes()) => key = next,
+                _ => break,
+            }
+        }
+        Ok(self.inner.moved(&from, &key))
+    }
+
+    /// Send a raw datagram from the hello socket. The socket is non-blocking, so
+    /// this never parks the executor.
+    pub async fn send(&self, addr: SocketAddr, payload: &[u8]) -> Result<()> {
+        self.inner.send(addr, payload)
+    }
+
+    pub async fn send_hello(&self, addr: SocketAddr) -> Result<()> {
+        self.inner.send_hello(addr)
+    }
+
+    pub async fn send_message(&self, addr: SocketAddr, payload: &[u8]) -> Result<u32> {
+        self.inner.send_message(addr, payload)
+    }
+
+    /// Send a message and wait for the peer's ack, retransmitting as
+    /// [`DhtMsg::send_message_reliable`] does. The waiting happens on a helper
+    /// thread.
+    pub async fn send_message_reliable(&self, addr: SocketAddr, payload: &[u8]) -> Result<u32> {
+        let (tx, rx) = flume::bounded(1);
+        let inner = self.inner.clone();
+        let payload = payload.to_vec();
+        thread::spawn(move || {
+            let _ = tx.send(inner.send_message_reliable(addr, &payload));
+        });
+        rx.recv_async()
+            .await
+            .map_err(|_| DhtMsgError::Closed("message sender thread"))?
+    }
+
+    /// Leave a message in the mailbox of `peer_id`, as
+    /// [`DhtMsg::leave_in_mailbox`] does, on a helper thread.
+    pub async fn leave_in_mailbox(&self, peer_id: &str, payload: &[u8]) -> Result<Id> {
+        let (tx, rx) = flume::bounded(1);
+        let inner = self.inner.clone();
+        let (peer_id, payload) = (peer_id.to_string(), payload.to_vec());
+        thread::spawn(move || {
+            let _ = tx.send(inner.leave_in_mailbox(&peer_id, &payload));
+        });
+        rx.recv_async()
+            .await
+            .map_err(|_| DhtMsgError::Closed("mailbox thread"))?
+    }
+
+    /// Fetch what `sender_id` left in our mailbox, as
+    /// [`DhtMsg::check_mailbox`] does, on a helper thread.
+    pub async fn check_mailbox(&self, sender_id: &str) -> Result<usize> {
+        let (tx, rx) = flume::bounded(1);
+        let inner = self.inner.clone();
+        let sender_id = sender_id.to_string();
+        thread::spawn(move || {
+            let _ = tx.send(inner.check_mailbox(&sender_id));
+        });
+        rx.recv_async()
+            .await
+            .map_err(|_| DhtMsgError::Closed("mailbox thread"))?
+    }
+
+    /// Open a reliable byte stream to a peer we exchanged a hello or ack with.
+    pub fn stream(&self, peer: SocketAddr) -> Result<AsyncPeerStream> {
+        self.inner.stream(peer).map(AsyncPeerStream)
+    }
+
+    /// Wait for a peer to open a stream to us.
+    pub async fn accept_stream(&self) -> Result<AsyncPeerStream> {
+        self.inner
+            .stream_acceptor()
+            .recv_async()
+            .await
+            .map(AsyncPeerStream)
+            .map_err(|_| DhtMsgError::Closed("stream table"))
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.inner.shutdown_handle()
+    }
+
+    /// Say goodbye to established peers and stop the node's threads, joining
+    /// them on a helper thread so the executor is not blocked.
+    pub async fn shutdown(&self) {
+        let (tx, rx) = flume::bounded(1);
+        let handle = self.shutdown_handle();
+        thread::spawn(move || {
+            handle.shutdown();
+            let _ = tx.send(());
+        });
+        let _ = rx.recv_async().await;
+    }
+
+    /// Wait for the next inbound datagram. Hellos are acknowledged automatically
+    /// before they are returned here. Fails once the node is shut down.
+    pub async fn recv(&self) -> Result<(Vec<u8>, SocketAddr)> {
+        self.inbound
+            .recv_async()
+            .await
+            .map_err(|_| DhtMsgError::Closed("receive thread"))
+    }
+}
+
+/// Async handle to a [`PeerStream`].
+pub struct AsyncPeerStream(PeerStream);
+
+impl AsyncPeerStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.0.peer_addr()
+    }
+
+    /// Read available bytes; returns 0 once the peer finished the stream.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read_async(buf).await
+    }
+
+    /// Write up to one segment of `buf`, waiting for window space.
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_async(buf).await
+    }
+
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let n = self.write(buf).await?;
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    /// Wait until every written byte has been acknowledged by the peer.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.0.flush_async().await
+    }
+
+    /// Signal end of stream to the peer.
+    pub fn finish(&self) {
+        self.0.finish()
+    }
+}
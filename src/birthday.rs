@@ -0,0 +1,283 @@
+//! Port prediction for punching holes between two NATs that map every
+//! destination to another public port ("symmetric" NATs, see
+//! [`NatMapping::EndpointDependent`](crate::NatMapping::EndpointDependent)).
+//!
+//! A punch request (see the `punch` module) only helps if each side knows
+//! the port the other's NAT will send from, and such a NAT picks a new one
+//! for the burst. Many pick the next free port, though, so two STUN probes
+//! from fresh sockets tell roughly where the next mappings land: a
+//! [`Prediction`]. Requests and answers carry the sender's prediction, and
+//! at the start each side sprays hellos over the other's predicted range:
+//!
+//! - a side behind such a NAT opens [`SOCKETS`] extra sockets, each of
+//!   which sends to one port of the range, so that its mappings land in
+//!   its own predicted range;
+//! - a side whose NAT keeps one port sends from the hello socket to
+//!   [`PROBES`] ports of the range, drawn at random.
+//!
+//! Like with birthdays, among that many mappings and probes on each side
+//! some are likely to meet: an easy side all but surely finds one of 256
+//! mappings, and two hard sides still get through now and then, without a
+//! relay. Extra sockets that hear nothing are closed after the burst; the
+//! one that does carries the peer's traffic from then on (see
+//! [`SprayTransport`]).
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use rand::seq::SliceRandom;
+
+use crate::{
+    stun,
+    transport::{Bind, SharedTransport, Transport},
+};
+
+/// Extra sockets a side behind an endpoint-dependent NAT opens for a burst.
+pub(crate) const SOCKETS: usize = 256;
+/// Ports a side sending from the hello socket probes.
+pub(crate) const PROBES: usize = 256;
+/// Ports of a predicted range, leaving room for mappings other hosts
+/// behind the NAT take in the meantime.
+const SPAN: u16 = 2 * SOCKETS as u16;
+/// Steps between consecutive mappings beyond which they count as random.
+const MAX_STEP: i32 = 16;
+/// Ports NATs hand out, when any of them may come next.
+const PORTS: std::ops::RangeInclusive<u16> = 1024..=u16::MAX;
+/// Sockets that carry a peer's traffic are closed once they have been
+/// silent this long.
+const IDLE: Duration = Duration::from_secs(300);
+
+/// Where the next mappings of a NAT are expected: `port`, then every
+/// `step` ports, for `span` ports; anywhere if `span` is zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Prediction {
+    pub(crate) port: u16,
+    pub(crate) step: i32,
+    pub(crate) span: u16,
+}
+
+impl Prediction {
+    /// The predicted ports, without repeats.
+    pub(crate) fn ports(&self) -> Vec<u16> {
+        if self.span == 0 {
+            return PORTS.collect();
+        }
+        let mut ports: Vec<u16> = (0..i32::from(self.span))
+            .map(|k| i32::from(self.port) + k * self.step)
+            .filter_map(|port| u16::try_from(port).ok())
+            .filter(|port| PORTS.contains(port))
+            .collect();
+        ports.dedup();
+        ports
+    }
+}
+
+/// Predict where the NAT maps the next sockets, from two fresh sockets
+/// bound as `bind` says, asking the first of `servers` that answers. None
+/// if none does.
+pub(crate) fn predict(servers: &[String], bind: &Bind) -> Option<Prediction> {
+    for server in servers {
+        let server = std::slice::from_ref(server);
+        let Some(first) = probe(server, bind) else {
+            continue;
+        };
+        let second = probe(server, bind)?;
+        let step = i32::from(second.port()) - i32::from(first.port());
+        debug!("fresh sockets mapped to {first} and then {second}");
+        if step != 0 && step.abs() <= MAX_STEP {
+            let next = i32::from(second.port()) + step;
+            if let Ok(port) = u16::try_from(next) {
+                return Some(Prediction {
+                    port,
+                    step,
+                    span: SPAN,
+                });
+            }
+        }
+        return Some(Prediction {
+            port: 0,
+            step: 0,
+            span: 0,
+        });
+    }
+    None
+}
+
+/// The public address `server` sees a fresh socket at.
+fn probe(server: &[String], bind: &Bind) -> Option<SocketAddrV4> {
+    let socket = bind.udp(Ipv4Addr::UNSPECIFIED.into(), 0).ok()?;
+    socket.set_nonblocking(true).ok()?;
+    stun::discover(&socket, server).map(|binding| binding.public)
+}
+
+/// Non-blocking sockets on any free IPv4 ports, bound as `bind` says, to
+/// spray from.
+pub(crate) fn open(count: usize, bind: &Bind) -> io::Result<Vec<Arc<UdpSocket>>> {
+    (0..count)
+        .map(|_| {
+            let socket = bind.udp(Ipv4Addr::UNSPECIFIED.into(), 0)?;
+            socket.set_nonblocking(true)?;
+            Ok(Arc::new(socket))
+        })
+        .collect()
+}
+
+/// `count` of `pool`, drawn at random, or all of them if there are fewer.
+pub(crate) fn draw(pool: &[SocketAddr], count: usize) -> Vec<SocketAddr> {
+    pool.choose_multiple(&mut rand::thread_rng(), count)
+        .copied()
+        .collect()
+}
+
+/// Which extra sockets datagrams to an address leave from.
+enum Route {
+    /// All of these, until one of them hears from the address.
+    Spraying(Vec<Arc<UdpSocket>>),
+    /// The one that did, and when it last heard.
+    Heard(Arc<UdpSocket>, Instant),
+}
+
+/// Sends and receives through the extra sockets of bursts, below the relay
+/// layer; everything else goes through the hello socket beneath.
+pub(crate) struct SprayTransport {
+    inner: SharedTransport,
+    routes: Mutex<HashMap<SocketAddr, Route>>,
+    /// The sockets of `routes`, once each, to receive from.
+    sockets: Mutex<Arc<[Arc<UdpSocket>]>>,
+    /// Which of `sockets` to read first, rotating so that none starves.
+    next: AtomicUsize,
+}
+
+impl SprayTransport {
+    pub(crate) fn new(inner: SharedTransport) -> Self {
+        Self {
+            inner,
+            routes: Mutex::default(),
+            sockets: Mutex::new(Arc::new([])),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Send to each of `aims` from its sockets from now on, unless one of
+    /// ours already hears from it.
+    pub(crate) fn aim(&self, aims: HashMap<SocketAddr, Vec<Arc<UdpSocket>>>) {
+        let mut routes = self.routes.lock().unwrap();
+        for (addr, sockets) in aims {
+            if !matches!(routes.get(&addr), Some(Route::Heard(..))) {
+                routes.insert(addr, Route::Spraying(sockets));
+            }
+        }
+        self.collect(&routes);
+    }
+
+    /// Stop spraying to `targets` once a burst is over, closing the sockets
+    /// that heard nothing, and those that fell silent.
+    pub(crate) fn settle(&self, targets: &[SocketAddr]) {
+        let mut routes = self.routes.lock().unwrap();
+        for target in targets {
+            if matches!(routes.get(target), Some(Route::Spraying(_))) {
+                routes.remove(target);
+            }
+        }
+        routes.retain(|_, route| match route {
+            Route::Spraying(_) => true,
+            Route::Heard(_, heard) => heard.elapsed() < IDLE,
+        });
+        self.collect(&routes);
+    }
+
+    /// Whether datagrams to `addr` leave from an extra socket.
+    pub(crate) fn carries(&self, addr: &SocketAddr) -> bool {
+        matches!(
+            self.routes.lock().unwrap().get(addr),
+            Some(Route::Heard(..))
+        )
+    }
+
+    fn collect(&self, routes: &HashMap<SocketAddr, Route>) {
+        let mut sockets: Vec<Arc<UdpSocket>> = Vec::new();
+        for route in routes.values() {
+            let route_sockets = match route {
+                Route::Spraying(sockets) => sockets.as_slice(),
+                Route::Heard(socket, _) => std::slice::from_ref(socket),
+            };
+            for socket in route_sockets {
+                if !sockets.iter().any(|known| Arc::ptr_eq(known, socket)) {
+                    sockets.push(socket.clone());
+                }
+            }
+        }
+        *self.sockets.lock().unwrap() = sockets.into();
+    }
+
+    fn heard(&self, socket: &Arc<UdpSocket>, from: SocketAddr) {
+        let mut routes = self.routes.lock().unwrap();
+        let first = !matches!(routes.get(&from), Some(Route::Heard(known, _)) if Arc::ptr_eq(known, socket));
+        routes.insert(from, Route::Heard(socket.clone(), Instant::now()));
+        if first {
+            if let Ok(local) = socket.local_addr() {
+                debug!("{from} answered extra socket {local}");
+            }
+            self.collect(&routes);
+        }
+    }
+}
+
+impl Transport for SprayTransport {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let sockets = match self.routes.lock().unwrap().get(&addr) {
+            None => None,
+            Some(Route::Heard(socket, _)) => Some(vec![socket.clone()]),
+            Some(Route::Spraying(sockets)) => Some(sockets.clone()),
+        };
+        let Some(sockets) = sockets else {
+            return self.inner.send_to(datagram, addr);
+        };
+        let mut result = Err(io::Error::from(io::ErrorKind::NotConnected));
+        for socket in sockets {
+            match socket.send_to(datagram, addr) {
+                Ok(sent) => result = Ok(sent),
+                Err(err) if result.is_err() => result = Err(err),
+                Err(_) => {}
+            }
+        }
+        result
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let err = match self.inner.recv_from(buf) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => err,
+            result => return result,
+        };
+        let sockets = self.sockets.lock().unwrap().clone();
+        if sockets.is_empty() {
+            return Err(err);
+        }
+        let first = self.next.fetch_add(1, Ordering::Relaxed) % sockets.len();
+        for socket in sockets[first..].iter().chain(&sockets[..first]) {
+            match socket.recv_from(buf) {
+                Ok((len, from)) => {
+                    self.heard(socket, from);
+                    return Ok((len, from));
+                }
+                // ICMP errors for probes that missed show up here on some
+                // systems.
+                Err(_) => continue,
+            }
+        }
+        Err(err)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
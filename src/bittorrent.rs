@@ -0,0 +1,40 @@
+//! Recognizing traffic from real BitTorrent clients.
+//!
+//! Our infohashes live in the torrent DHT, so clients that find the announced
+//! hello port in a lookup may try to download from it: a TCP-style peer
+//! handshake, a uTP connection or DHT queries of their own. None of it is
+//! meant for us, and none of it gets an answer. Our own uTP connections
+//! never get this far: the `utp` layer handles them below the session.
+
+/// Start of the peer wire handshake, also seen as the first datagram of
+/// clients speaking it over UDP.
+const HANDSHAKE: &[u8] = b"\x13BitTorrent protocol";
+/// uTP header size (BEP 29).
+const UTP_HEADER_BYTES: usize = 20;
+const UTP_VERSION: u8 = 1;
+/// `ST_DATA` through `ST_SYN`.
+const UTP_MAX_TYPE: u8 = 4;
+
+/// Whether `datagram` looks like BitTorrent protocol traffic.
+pub(crate) fn is_bittorrent(datagram: &[u8]) -> bool {
+    datagram.starts_with(HANDSHAKE) || is_utp(datagram) || is_krpc(datagram)
+}
+
+/// `[type u4][version u4][extension u8]...` with a known type and version.
+fn is_utp(datagram: &[u8]) -> bool {
+    let Some(&[first, extension]) = datagram.first_chunk::<2>() else {
+        return false;
+    };
+    datagram.len() >= UTP_HEADER_BYTES
+        && first & 0x0f == UTP_VERSION
+        && first >> 4 <= UTP_MAX_TYPE
+        && extension <= 2
+}
+
+/// A bencoded DHT query, response or error (BEP 5), which always has a
+/// `y` key.
+fn is_krpc(datagram: &[u8]) -> bool {
+    datagram.first() == Some(&b'd')
+        && datagram.last() == Some(&b'e')
+        && datagram.windows(5).any(|window| window == b"1:y1:")
+}
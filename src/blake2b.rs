@@ -0,0 +1,145 @@
+//! BLAKE2b (RFC 7693), unkeyed, with any digest length up to 64 bytes.
+//!
+//! Argon2 is built on it; like the other primitives in the crate it is
+//! implemented here rather than pulled in as a dependency.
+
+pub(crate) const MAX_DIGEST_BYTES: usize = 64;
+const BLOCK_BYTES: usize = 128;
+
+const IV: [u64; 8] = [
+    0x6a09_e667_f3bc_c908,
+    0xbb67_ae85_84ca_a73b,
+    0x3c6e_f372_fe94_f82b,
+    0xa54f_f53a_5f1d_36f1,
+    0x510e_527f_ade6_82d1,
+    0x9b05_688c_2b3e_6c1f,
+    0x1f83_d9ab_fb41_bd6b,
+    0x5be0_cd19_137e_2179,
+];
+
+const SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+/// Digest of `digest_len` bytes over the concatenation of `parts`.
+pub(crate) fn blake2b(digest_len: usize, parts: &[&[u8]]) -> Vec<u8> {
+    assert!((1..=MAX_DIGEST_BYTES).contains(&digest_len));
+    let mut state = IV;
+    state[0] ^= 0x0101_0000 ^ digest_len as u64;
+
+    let mut block = [0u8; BLOCK_BYTES];
+    let mut filled = 0;
+    let mut total = 0u128;
+    for part in parts {
+        for &byte in *part {
+            // The last block is compressed with the final flag, so a full
+            // buffer is only flushed once more input follows.
+            if filled == BLOCK_BYTES {
+                total += BLOCK_BYTES as u128;
+                compress(&mut state, &block, total, false);
+                filled = 0;
+            }
+            block[filled] = byte;
+            filled += 1;
+        }
+    }
+    total += filled as u128;
+    block[filled..].fill(0);
+    compress(&mut state, &block, total, true);
+
+    let mut digest: Vec<u8> = state.iter().flat_map(|word| word.to_le_bytes()).collect();
+    digest.truncate(digest_len);
+    digest
+}
+
+fn compress(state: &mut [u64; 8], block: &[u8; BLOCK_BYTES], total: u128, last: bool) {
+    let mut m = [0u64; 16];
+    for (word, chunk) in m.iter_mut().zip(block.chunks_exact(8)) {
+        *word = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    let mut v = [0u64; 16];
+    v[..8].copy_from_slice(state);
+    v[8..].copy_from_slice(&IV);
+    v[12] ^= total as u64;
+    v[13] ^= (total >> 64) as u64;
+    if last {
+        v[14] = !v[14];
+    }
+    for round in 0..12 {
+        let s = &SIGMA[round % 10];
+        g(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+        g(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+        g(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+        g(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+        g(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+        g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+        g(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+        g(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+    }
+    for i in 0..8 {
+        state[i] ^= v[i] ^ v[i + 8];
+    }
+}
+
+fn g(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc7693_vector() {
+        // Appendix A: BLAKE2b-512("abc").
+        assert_eq!(
+            hex::encode(blake2b(64, &[b"abc"])),
+            "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+             7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+        );
+    }
+
+    #[test]
+    fn other_lengths() {
+        assert_eq!(
+            hex::encode(blake2b(64, &[])),
+            "786a02f742015903c6c6fd852552d272912f4740e15847618a86e217f71f5419\
+             d25e1031afee585313896444934eb04b903a685b1448b755d56f701afe9be2ce"
+        );
+        assert_eq!(
+            hex::encode(blake2b(32, &[b"abc"])),
+            "bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319"
+        );
+    }
+
+    #[test]
+    fn parts_are_concatenated() {
+        let input = Vec::from_iter((0..512).map(|i| i as u8));
+        let whole = blake2b(64, &[&input]);
+        assert_eq!(
+            hex::encode(&whole),
+            "c59ab1095ca4579525338b6b74689ff234bc3fe9765fe26dfb04ddceaee0ab84\
+             dfd8967594cb261fcd88687f4454d80f718116c1b3c32f9f7e169357468cbe67"
+        );
+        for split in [1, 127, 128, 129, 256, 511] {
+            let (head, tail) = input.split_at(split);
+            assert_eq!(blake2b(64, &[head, &[], tail]), whole, "split at {split}");
+        }
+    }
+}
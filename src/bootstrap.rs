@@ -0,0 +1,137 @@
+//! Standalone DHT nodes that others bootstrap from.
+//!
+//! A private DHT needs a few nodes that stay up for the others to join it
+//! through, and the public one can always use more reachable nodes. A
+//! [`BootstrapNode`] is just that: a mainline node in server mode on a
+//! fixed port, with no hello socket and no identity.
+
+use std::{net::SocketAddrV4, path::PathBuf};
+
+use log::info;
+
+use crate::{
+    error::{DhtMsgError, Result},
+    node::{with_cached_nodes, write_routing_cache},
+    stats::{DhtStats, QueryCounts},
+};
+
+/// Port of bootstrap nodes unless set otherwise, the usual DHT port.
+pub const DEFAULT_BOOTSTRAP_PORT: u16 = 6881;
+
+/// Configures and starts a [`BootstrapNode`].
+#[derive(Clone)]
+pub struct BootstrapNodeBuilder {
+    port: u16,
+    bootstrap: Vec<String>,
+    private_dht: bool,
+    routing_cache: Option<PathBuf>,
+}
+
+impl Default for BootstrapNodeBuilder {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_BOOTSTRAP_PORT,
+            bootstrap: Vec::new(),
+            private_dht: false,
+            routing_cache: None,
+        }
+    }
+}
+
+impl BootstrapNodeBuilder {
+    /// Listen on UDP `port` (6881 by default), which others then name as
+    /// `host:port` to bootstrap from.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Join the DHT through `node` (`host:port`), as
+    /// [`DhtMsgBuilder::bootstrap`](crate::DhtMsgBuilder::bootstrap) does.
+    pub fn bootstrap(mut self, node: impl Into<String>) -> Self {
+        self.bootstrap.push(node.into());
+        self
+    }
+
+    /// Never contact the public bootstrap nodes. Without
+    /// [`bootstrap`](Self::bootstrap) nodes or a routing table cache, the
+    /// node is the first of a new private DHT and waits for others to join.
+    pub fn private_dht(mut self, enabled: bool) -> Self {
+        self.private_dht = enabled;
+        self
+    }
+
+    /// Keep the nodes we know in `path` and bootstrap from them on the next
+    /// start, as
+    /// [`DhtMsgBuilder::routing_cache`](crate::DhtMsgBuilder::routing_cache)
+    /// does. The file is written on
+    /// [`save_routing_cache`](BootstrapNode::save_routing_cache).
+    pub fn routing_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.routing_cache = Some(path.into());
+        self
+    }
+
+    /// Bind the port and start joining the DHT, without waiting for it: a
+    /// bootstrap node is useful as soon as others reach it.
+    pub fn build(self) -> Result<BootstrapNode> {
+        let bootstrap = with_cached_nodes(
+            self.routing_cache.as_deref(),
+            self.bootstrap,
+            self.private_dht,
+        );
+        let mut builder = mainline::Dht::builder();
+        builder.server_mode().port(self.port);
+        if !bootstrap.is_empty() {
+            builder.bootstrap(&bootstrap);
+        } else if self.private_dht {
+            info!("no nodes to bootstrap from; starting a new DHT");
+            builder.no_bootstrap();
+        }
+        let dht = builder.build().map_err(DhtMsgError::Bootstrap)?;
+        info!("bootstrap node listening on {}", dht.info().local_addr());
+        Ok(BootstrapNode {
+            dht,
+            routing_cache: self.routing_cache,
+        })
+    }
+}
+
+/// A running bootstrap node. It stops when dropped.
+///
+/// mainline picks the node ID and gives no way to set it, so the ID changes
+/// with every start; nodes that knew the old one learn the new one on their
+/// next query, as they do for any node.
+pub struct BootstrapNode {
+    dht: mainline::Dht,
+    routing_cache: Option<PathBuf>,
+}
+
+impl BootstrapNode {
+    pub fn builder() -> BootstrapNodeBuilder {
+        BootstrapNodeBuilder::default()
+    }
+
+    /// Where the node listens.
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.dht.info().local_addr()
+    }
+
+    pub fn dht(&self) -> &mainline::Dht {
+        &self.dht
+    }
+
+    /// Statistics of the node, with no queries of its own to count.
+    pub fn stats(&self) -> DhtStats {
+        QueryCounts::default().stats(&self.dht)
+    }
+
+    /// Write the nodes we know to the
+    /// [routing table cache](BootstrapNodeBuilder::routing_cache), if there
+    /// is one.
+    pub fn save_routing_cache(&self) -> Result<()> {
+        match &self.routing_cache {
+            Some(path) => write_routing_cache(&self.dht, path),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Feature bitmap exchanged in hellos and acks.
+
+use std::{fmt, ops::BitOr};
+
+use serde::{Deserialize, Serialize};
+
+/// Optional features a node supports, advertised in its hellos and acks so
+/// that peers can adapt instead of assuming identical binaries.
+///
+/// Bits this version does not know are kept as received.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Decompresses LZ4 messages and file transfers.
+    pub const LZ4: Self = Self(1 << 0);
+    /// Answers Noise handshakes; set on every node that encrypts.
+    pub const ENCRYPTION: Self = Self(1 << 1);
+    /// Relays traffic for peers that cannot reach each other directly.
+    pub const RELAY: Self = Self(1 << 2);
+    /// Accepts files sent over a stream (see [`file`](crate::file)).
+    pub const FILE_TRANSFER: Self = Self(1 << 3);
+    /// Reachable over IPv6.
+    pub const IPV6: Self = Self(1 << 4);
+    /// Accepts uTP streams (see [`DhtMsg::utp_stream`](crate::DhtMsg::utp_stream)).
+    pub const UTP: Self = Self(1 << 5);
+    /// Accepts TCP connections tunneled over uTP streams (see
+    /// [`tunnel`](crate::tunnel)).
+    pub const TUNNEL: Self = Self(1 << 6);
+    /// Takes IP packets ([`DhtMsg::send_packet`](crate::DhtMsg::send_packet)),
+    /// as the other end of a VPN.
+    pub const VPN: Self = Self(1 << 7);
+    /// Joins a uTP stream to a local endpoint, as the other end of
+    /// `dhtmsg bridge`.
+    pub const BRIDGE: Self = Self(1 << 8);
+    /// Accepts uTP streams and streams on named channels (see
+    /// [`DhtMsg::channel`](crate::DhtMsg::channel) and
+    /// [`DhtMsg::stream_channel`](crate::DhtMsg::stream_channel)).
+    pub const CHANNELS: Self = Self(1 << 9);
+    /// Takes messages on topics (see
+    /// [`DhtMsg::send_topic`](crate::DhtMsg::send_topic)).
+    pub const TOPICS: Self = Self(1 << 10);
+
+    const NAMES: [(Self, &'static str); 11] = [
+        (Self::LZ4, "lz4"),
+        (Self::ENCRYPTION, "encryption"),
+        (Self::RELAY, "relay"),
+        (Self::FILE_TRANSFER, "files"),
+        (Self::IPV6, "ipv6"),
+        (Self::UTP, "utp"),
+        (Self::TUNNEL, "tunnel"),
+        (Self::VPN, "vpn"),
+        (Self::BRIDGE, "bridge"),
+        (Self::CHANNELS, "channels"),
+        (Self::TOPICS, "topics"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Whether every capability in `other` is set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Space-separated names, e.g. `lz4 files`; unknown bits show as hex.
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rest = *self;
+        let mut separator = "";
+        for (capability, name) in Self::NAMES {
+            if rest.contains(capability) {
+                write!(f, "{separator}{name}")?;
+                rest.remove(capability);
+                separator = " ";
+            }
+        }
+        if !rest.is_empty() {
+            write!(f, "{separator}{:#x}", rest.0)?;
+        }
+        Ok(())
+    }
+}
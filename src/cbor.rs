@@ -0,0 +1,1158 @@
+//! Minimal CBOR (RFC 8949) serde backend for the [`schema`](crate::schema)
+//! messages.
+//!
+//! Covers what serde data models map onto: integers, floats, booleans, null,
+//! text and byte strings, arrays and maps (definite or indefinite length),
+//! with tags skipped on input. Enums use the external representation
+//! (`"Variant"` or `{"Variant": value}`), like other serde CBOR crates.
+
+use std::fmt;
+
+use serde::{
+    de::{self, DeserializeSeed, Visitor},
+    ser::{self, Serialize},
+};
+
+/// Nesting beyond this is refused rather than recursed into.
+const MAX_DEPTH: usize = 64;
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_NEGATIVE: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+const MAJOR_SIMPLE: u8 = 7;
+
+const FALSE: u8 = 0xf4;
+const TRUE: u8 = 0xf5;
+const NULL: u8 = 0xf6;
+const UNDEFINED: u8 = 0xf7;
+const HALF: u8 = 0xf9;
+const SINGLE: u8 = 0xfa;
+const DOUBLE: u8 = 0xfb;
+const BREAK: u8 = 0xff;
+/// Additional-information value announcing an indefinite length.
+const INDEFINITE: u8 = 31;
+
+/// A value that could not be encoded or decoded.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{0}")]
+pub struct Error(String);
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+pub(crate) fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut serializer = Serializer { out: Vec::new() };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.out)
+}
+
+pub(crate) fn from_slice<T: de::DeserializeOwned>(input: &[u8]) -> Result<T, Error> {
+    let mut deserializer = Deserializer { input, depth: 0 };
+    let value = T::deserialize(&mut deserializer)?;
+    if !deserializer.input.is_empty() {
+        return Err(Error("trailing bytes after the CBOR value".into()));
+    }
+    Ok(value)
+}
+
+struct Serializer {
+    out: Vec<u8>,
+}
+
+impl Serializer {
+    fn head(&mut self, major: u8, value: u64) {
+        let major = major << 5;
+        match value {
+            0..=23 => self.out.push(major | value as u8),
+            24..=0xff => self.out.extend_from_slice(&[major | 24, value as u8]),
+            0x100..=0xffff => {
+                self.out.push(major | 25);
+                self.out.extend_from_slice(&(value as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                self.out.push(major | 26);
+                self.out.extend_from_slice(&(value as u32).to_be_bytes());
+            }
+            _ => {
+                self.out.push(major | 27);
+                self.out.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+    }
+
+    /// Array or map head; indefinite when the length is unknown.
+    fn container(&mut self, major: u8, len: Option<usize>) -> Compound<'_> {
+        match len {
+            Some(len) => self.head(major, len as u64),
+            None => self.out.push(major << 5 | INDEFINITE),
+        }
+        Compound {
+            ser: self,
+            indefinite: len.is_none(),
+        }
+    }
+
+    /// `{"variant": ...}` around an enum variant's content.
+    fn variant_key(&mut self, variant: &str) {
+        self.head(MAJOR_MAP, 1);
+        self.head(MAJOR_TEXT, variant.len() as u64);
+        self.out.extend_from_slice(variant.as_bytes());
+    }
+}
+
+struct Compound<'a> {
+    ser: &'a mut Serializer,
+    indefinite: bool,
+}
+
+impl Compound<'_> {
+    fn finish(self) {
+        if self.indefinite {
+            self.ser.out.push(BREAK);
+        }
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.out.push(if v { TRUE } else { FALSE });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        if v < 0 {
+            self.head(MAJOR_NEGATIVE, !v as u64);
+        } else {
+            self.head(MAJOR_UNSIGNED, v as u64);
+        }
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.head(MAJOR_UNSIGNED, v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.out.push(SINGLE);
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.out.push(DOUBLE);
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.head(MAJOR_TEXT, v.len() as u64);
+        self.out.extend_from_slice(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.head(MAJOR_BYTES, v.len() as u64);
+        self.out.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.out.push(NULL);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.serialize_none()
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.serialize_none()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.variant_key(variant);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'a>, Error> {
+        Ok(self.container(MAJOR_ARRAY, len))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a>, Error> {
+        Ok(self.container(MAJOR_ARRAY, Some(len)))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        Ok(self.container(MAJOR_ARRAY, Some(len)))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.variant_key(variant);
+        Ok(self.container(MAJOR_ARRAY, Some(len)))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'a>, Error> {
+        Ok(self.container(MAJOR_MAP, len))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a>, Error> {
+        Ok(self.container(MAJOR_MAP, Some(len)))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.variant_key(variant);
+        Ok(self.container(MAJOR_MAP, Some(len)))
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish();
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish();
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish();
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish();
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        key.serialize(&mut *self.ser)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish();
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        ser::Serializer::serialize_str(&mut *self.ser, key)?;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish();
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        ser::Serializer::serialize_str(&mut *self.ser, key)?;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish();
+        Ok(())
+    }
+}
+
+struct Deserializer<'de> {
+    input: &'de [u8],
+    depth: usize,
+}
+
+fn eof() -> Error {
+    Error("unexpected end of CBOR input".into())
+}
+
+/// IEEE 754 half precision to double.
+fn half_to_f64(bits: u16) -> f64 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = (bits >> 10) & 0x1f;
+    let mantissa = f64::from(bits & 0x3ff);
+    sign * match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f64.powi(i32::from(exponent) - 15),
+    }
+}
+
+impl<'de> Deserializer<'de> {
+    fn peek(&self) -> Result<u8, Error> {
+        self.input.first().copied().ok_or_else(eof)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'de [u8], Error> {
+        if self.input.len() < len {
+            return Err(eof());
+        }
+        let (taken, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    /// Major type and argument; `None` for an indefinite length.
+    fn head(&mut self) -> Result<(u8, Option<u64>), Error> {
+        let initial = self.take(1)?[0];
+        let major = initial >> 5;
+        let value = match initial & 0x1f {
+            info @ 0..=23 => Some(u64::from(info)),
+            24 => Some(u64::from(self.take(1)?[0])),
+            25 => Some(u64::from(u16::from_be_bytes(self.take_array()?))),
+            26 => Some(u64::from(u32::from_be_bytes(self.take_array()?))),
+            27 => Some(u64::from_be_bytes(self.take_array()?)),
+            INDEFINITE if matches!(major, MAJOR_BYTES..=MAJOR_MAP) => None,
+            _ => return Err(Error(format!("invalid CBOR initial byte {initial:#04x}"))),
+        };
+        Ok((major, value))
+    }
+
+    /// Length of a definite string, checked against the remaining input.
+    fn length(&self, len: Option<u64>) -> Result<usize, Error> {
+        let len = len.ok_or_else(|| Error("indefinite-length strings are not supported".into()))?;
+        usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= self.input.len())
+            .ok_or_else(eof)
+    }
+
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        if self.depth == MAX_DEPTH {
+            return Err(Error("CBOR nesting too deep".into()));
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Consume a break byte if it is next.
+    fn at_break(&mut self) -> Result<bool, Error> {
+        if self.peek()? == BREAK {
+            self.input = &self.input[1..];
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.peek()? {
+            FALSE => {
+                self.input = &self.input[1..];
+                return visitor.visit_bool(false);
+            }
+            TRUE => {
+                self.input = &self.input[1..];
+                return visitor.visit_bool(true);
+            }
+            NULL | UNDEFINED => {
+                self.input = &self.input[1..];
+                return visitor.visit_unit();
+            }
+            HALF => {
+                self.input = &self.input[1..];
+                return visitor.visit_f64(half_to_f64(u16::from_be_bytes(self.take_array()?)));
+            }
+            SINGLE => {
+                self.input = &self.input[1..];
+                return visitor.visit_f32(f32::from_be_bytes(self.take_array()?));
+            }
+            DOUBLE => {
+                self.input = &self.input[1..];
+                return visitor.visit_f64(f64::from_be_bytes(self.take_array()?));
+            }
+            _ => {}
+        }
+        let (major, value) = self.head()?;
+        match major {
+            MAJOR_UNSIGNED => visitor.visit_u64(value.unwrap_or_default()),
+            MAJOR_NEGATIVE => {
+                let value = value.unwrap_or_default();
+                match i64::try_from(value) {
+                    Ok(value) => visitor.visit_i64(!value),
+                    Err(_) => visitor.visit_i128(!i128::from(value)),
+                }
+            }
+            MAJOR_BYTES => {
+                let len = self.length(value)?;
+                visitor.visit_borrowed_bytes(self.take(len)?)
+            }
+            MAJOR_TEXT => {
+                let len = self.length(value)?;
+                let text = std::str::from_utf8(self.take(len)?)
+                    .map_err(|_| Error("CBOR text string is not UTF-8".into()))?;
+                visitor.visit_borrowed_str(text)
+            }
+            MAJOR_ARRAY => self.nested(|de| {
+                let mut access = Access { de, left: value };
+                let value = visitor.visit_seq(&mut access)?;
+                access.end()?;
+                Ok(value)
+            }),
+            MAJOR_MAP => self.nested(|de| {
+                let mut access = Access { de, left: value };
+                let value = visitor.visit_map(&mut access)?;
+                access.end()?;
+                Ok(value)
+            }),
+            MAJOR_TAG => self.nested(|de| de.deserialize_any(visitor)),
+            MAJOR_SIMPLE => Err(Error(format!(
+                "unsupported CBOR simple value {}",
+                value.unwrap_or_default()
+            ))),
+            _ => unreachable!("major type is three bits"),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if matches!(self.peek()?, NULL | UNDEFINED) {
+            self.input = &self.input[1..];
+            return visitor.visit_none();
+        }
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        if self.peek()? >> 5 == MAJOR_TEXT {
+            return visitor.visit_enum(UnitVariant { de: self });
+        }
+        match self.head()? {
+            (MAJOR_MAP, Some(1)) => self.nested(|de| visitor.visit_enum(Variant { de })),
+            _ => Err(Error("expected a CBOR enum variant".into())),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+/// Elements of an array or entries of a map; `left` is `None` when the
+/// container runs until a break byte.
+struct Access<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    left: Option<u64>,
+}
+
+impl Access<'_, '_> {
+    fn has_next(&mut self) -> Result<bool, Error> {
+        match &mut self.left {
+            Some(0) => Ok(false),
+            Some(left) => {
+                *left -= 1;
+                Ok(true)
+            }
+            None if self.de.at_break()? => {
+                // Nothing follows the break.
+                self.left = Some(0);
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Check that the visitor took every item, and consume the break of an
+    /// indefinite container (tuples stop reading after their last element).
+    fn end(&mut self) -> Result<(), Error> {
+        if self.has_next()? {
+            return Err(Error("CBOR container has more items than expected".into()));
+        }
+        Ok(())
+    }
+
+    fn hint(&self) -> Option<usize> {
+        // Every item takes at least one byte, so this never over-allocates.
+        self.left.map(|left| {
+            usize::try_from(left)
+                .unwrap_or(usize::MAX)
+                .min(self.de.input.len())
+        })
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for Access<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if !self.has_next()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.hint()
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Access<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        if !self.has_next()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.hint()
+    }
+}
+
+/// A variant written as a bare text string.
+struct UnitVariant<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'de> de::EnumAccess<'de> for UnitVariant<'_, 'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        Ok((seed.deserialize(&mut *self.de)?, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for UnitVariant<'_, 'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, _seed: T) -> Result<T::Value, Error> {
+        Err(Error("expected a unit variant".into()))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error("expected a unit variant".into()))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Error> {
+        Err(Error("expected a unit variant".into()))
+    }
+}
+
+/// A variant written as a one-entry map `{name: content}`.
+struct Variant<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'de> de::EnumAccess<'de> for Variant<'_, 'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        Ok((seed.deserialize(&mut *self.de)?, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Variant<'_, 'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        de::Deserialize::deserialize(&mut *self.de)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(&mut *self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(&mut *self.de, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize, de::IgnoredAny};
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::{Capabilities, schema::Message};
+
+    fn decode<T: de::DeserializeOwned>(input: &str) -> Result<T, Error> {
+        from_slice(&hex::decode(input).unwrap())
+    }
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> String {
+        hex::encode(to_vec(value).unwrap())
+    }
+
+    /// Encodes to `input` and decodes back from it (RFC 8949, Appendix A).
+    fn both<T: Serialize + de::DeserializeOwned + PartialEq + fmt::Debug>(value: T, input: &str) {
+        assert_eq!(encode(&value), input);
+        assert_eq!(decode::<T>(input).unwrap(), value);
+    }
+
+    type Pair = (u64, u64);
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct AB {
+        a: u64,
+        b: Vec<u64>,
+    }
+
+    #[test]
+    fn rfc8949_integers() {
+        both(0u64, "00");
+        both(1u64, "01");
+        both(10u64, "0a");
+        both(23u64, "17");
+        both(24u64, "1818");
+        both(25u64, "1819");
+        both(100u64, "1864");
+        both(1000u64, "1903e8");
+        both(1_000_000u64, "1a000f4240");
+        both(1_000_000_000_000u64, "1b000000e8d4a51000");
+        both(u64::MAX, "1bffffffffffffffff");
+        both(-1i64, "20");
+        both(-10i64, "29");
+        both(-100i64, "3863");
+        both(-1000i64, "3903e7");
+        assert_eq!(decode::<i128>("3bffffffffffffffff").unwrap(), -(1 << 64));
+        // Bignums are tagged byte strings.
+        assert_eq!(
+            decode::<ByteBuf>("c249010000000000000000").unwrap(),
+            [1, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn rfc8949_floats() {
+        // Always written in double precision.
+        both(1.1f64, "fb3ff199999999999a");
+        both(1.0e300f64, "fb7e37e43c8800759c");
+        both(-4.1f64, "fbc010666666666666");
+        both(100_000f32, "fa47c35000");
+        both(3.402_823_5e38_f32, "fa7f7fffff");
+        for (input, value) in [
+            ("f90000", 0.0),
+            ("f93c00", 1.0),
+            ("f93e00", 1.5),
+            ("f97bff", 65504.0),
+            ("f90001", 5.960_464_477_539_063e-8),
+            ("f90400", 6.103_515_625e-5),
+            ("f9c400", -4.0),
+            ("f97c00", f64::INFINITY),
+            ("f9fc00", f64::NEG_INFINITY),
+            ("fa7f800000", f64::INFINITY),
+            ("fb7ff0000000000000", f64::INFINITY),
+        ] {
+            assert_eq!(decode::<f64>(input).unwrap(), value, "{input}");
+        }
+        assert!(decode::<f64>("f98000").unwrap().is_sign_negative());
+        assert!(decode::<f64>("f97e00").unwrap().is_nan());
+        assert!(decode::<f64>("fa7fc00000").unwrap().is_nan());
+    }
+
+    #[test]
+    fn rfc8949_simple_values_and_tags() {
+        both(false, "f4");
+        both(true, "f5");
+        both(None::<u64>, "f6");
+        assert_eq!(decode::<Option<u64>>("f7").unwrap(), None);
+        both((), "f6");
+        assert_eq!(
+            decode::<String>("c074323031332d30332d32315432303a30343a30305a").unwrap(),
+            "2013-03-21T20:04:00Z"
+        );
+        assert_eq!(decode::<u64>("c11a514b67b0").unwrap(), 1_363_896_240);
+        assert_eq!(decode::<ByteBuf>("d74401020304").unwrap(), [1, 2, 3, 4]);
+        assert_eq!(decode::<ByteBuf>("d818456449455446").unwrap(), b"dIETF");
+        assert_eq!(
+            decode::<String>("d82076687474703a2f2f7777772e6578616d706c652e636f6d").unwrap(),
+            "http://www.example.com"
+        );
+        for input in ["f0", "f818", "f8ff"] {
+            assert!(decode::<IgnoredAny>(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn rfc8949_strings() {
+        both(ByteBuf::new(), "40");
+        both(ByteBuf::from([1, 2, 3, 4]), "4401020304");
+        both(String::new(), "60");
+        both("a".to_string(), "6161");
+        both("IETF".to_string(), "6449455446");
+        both("\"\\".to_string(), "62225c");
+        both("\u{fc}".to_string(), "62c3bc");
+        both("\u{6c34}".to_string(), "63e6b0b4");
+        both("\u{10151}".to_string(), "64f0908591");
+    }
+
+    #[test]
+    fn rfc8949_containers() {
+        both(Vec::<u64>::new(), "80");
+        both(vec![1u64, 2, 3], "83010203");
+        both((1u64, vec![2u64, 3], vec![4u64, 5]), "8301820203820405");
+        both(
+            Vec::from_iter(1u64..=25),
+            "98190102030405060708090a0b0c0d0e0f101112131415161718181819",
+        );
+        both(BTreeMap::<u64, u64>::new(), "a0");
+        both(BTreeMap::from([(1u64, 2u64), (3, 4)]), "a201020304");
+        both(
+            AB {
+                a: 1,
+                b: vec![2, 3],
+            },
+            "a26161016162820203",
+        );
+        both(
+            (
+                "a".to_string(),
+                BTreeMap::from([("b".to_string(), "c".to_string())]),
+            ),
+            "826161a161626163",
+        );
+        both(
+            BTreeMap::from_iter(
+                ["a", "b", "c", "d", "e"].map(|key| (key.to_string(), key.to_uppercase())),
+            ),
+            "a56161614161626142616361436164614461656145",
+        );
+    }
+
+    #[test]
+    fn rfc8949_indefinite_containers() {
+        type Nested = (u64, Vec<u64>, Vec<u64>);
+        let nested = (1, vec![2, 3], vec![4, 5]);
+        assert_eq!(decode::<Vec<u64>>("9fff").unwrap(), []);
+        for input in [
+            "9f018202039f0405ffff",
+            "9f01820203820405ff",
+            "83018202039f0405ff",
+            "83019f0203ff820405",
+        ] {
+            assert_eq!(decode::<Nested>(input).unwrap(), nested, "{input}");
+        }
+        assert_eq!(
+            decode::<Vec<u64>>("9f0102030405060708090a0b0c0d0e0f101112131415161718181819ff")
+                .unwrap(),
+            Vec::from_iter(1..=25)
+        );
+        assert_eq!(
+            decode::<AB>("bf61610161629f0203ffff").unwrap(),
+            AB {
+                a: 1,
+                b: vec![2, 3]
+            }
+        );
+        assert_eq!(
+            decode::<(String, BTreeMap<String, String>)>("826161bf61626163ff").unwrap(),
+            (
+                "a".to_string(),
+                BTreeMap::from([("b".to_string(), "c".to_string())])
+            )
+        );
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct FunAmt {
+            fun: bool,
+            amt: i64,
+        }
+        assert_eq!(
+            decode::<FunAmt>("bf6346756ef563416d7421ff").unwrap(),
+            FunAmt { fun: true, amt: -2 }
+        );
+    }
+
+    #[test]
+    fn refuses_indefinite_items() {
+        // Indefinite strings are valid CBOR, but not supported.
+        assert!(decode::<ByteBuf>("5f42010243030405ff").is_err());
+        assert!(decode::<String>("7f657374726561646d696e67ff").is_err());
+        // Not valid for integers, tags and simple values.
+        for input in ["1f", "3f", "df00", "ff"] {
+            assert!(decode::<IgnoredAny>(input).is_err(), "{input}");
+        }
+        // Reserved lengths.
+        for input in ["1c", "3d", "5e", "9c"] {
+            assert!(decode::<IgnoredAny>(input).is_err(), "{input}");
+        }
+        // Breaks that never come, or come early.
+        assert!(decode::<Vec<u64>>("9f0102").is_err());
+        assert!(decode::<Pair>("9f01ff").is_err());
+        assert!(decode::<BTreeMap<u64, u64>>("bf01ff").is_err());
+    }
+
+    #[test]
+    fn refuses_truncated_input() {
+        for input in [
+            "1818",
+            "1903e8",
+            "1a000f4240",
+            "1b000000e8d4a51000",
+            "3bffffffffffffffff",
+            "f93c00",
+            "fa47c35000",
+            "fb3ff199999999999a",
+            "4401020304",
+            "6449455446",
+            "8301820203820405",
+            "9f01820203820405ff",
+            "a26161016162820203",
+            "bf61610161629f0203ffff",
+            "c11a514b67b0",
+        ] {
+            let bytes = hex::decode(input).unwrap();
+            assert!(from_slice::<IgnoredAny>(&bytes).is_ok(), "{input}");
+            for len in 0..bytes.len() {
+                assert!(
+                    from_slice::<IgnoredAny>(&bytes[..len]).is_err(),
+                    "{input} cut to {len}"
+                );
+            }
+        }
+        let message = Message::Data {
+            id: "ab".repeat(32),
+            seq: 8,
+            payload: b"hi".to_vec(),
+        }
+        .to_cbor();
+        for len in 0..message.len() {
+            assert!(Message::from_cbor(&message[..len]).is_err(), "{len} bytes");
+        }
+        // A length beyond the input is not allocated for.
+        assert!(decode::<ByteBuf>("5bffffffffffffffff").is_err());
+        assert!(decode::<Vec<u64>>("9bffffffffffffffff").is_err());
+    }
+
+    #[test]
+    fn refuses_deep_nesting() {
+        // Arrays of one element, maps of one entry nesting in the value, and
+        // tags, each around a final 0.
+        for nesting in [&[0x81][..], &[0xa1, 0x00], &[0xc0]] {
+            let nest = |depth: usize| [nesting.repeat(depth), vec![0]].concat();
+            assert!(
+                from_slice::<IgnoredAny>(&nest(MAX_DEPTH)).is_ok(),
+                "{nesting:x?}"
+            );
+            assert_eq!(
+                from_slice::<IgnoredAny>(&nest(MAX_DEPTH + 1)),
+                Err(Error("CBOR nesting too deep".into())),
+                "{nesting:x?}",
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_trailing_bytes() {
+        assert!(decode::<u64>("0000").is_err());
+        assert!(decode::<Vec<u64>>("8101ff").is_err());
+        // More items than a tuple takes.
+        assert!(decode::<Pair>("83010203").is_err());
+        let mut message = Message::Ping {
+            id: "ab".repeat(32),
+            seq: 1,
+        }
+        .to_cbor();
+        message.push(0);
+        assert!(Message::from_cbor(&message).is_err());
+    }
+
+    fn messages() -> Vec<Message> {
+        let id = "0123456789abcdef".repeat(4);
+        vec![
+            Message::Hello {
+                id: id.clone(),
+                seq: 7,
+                caps: Capabilities::UTP | Capabilities::TUNNEL,
+                ext: vec![0, 1, 0, 2, 0xaa, 0xbb],
+                vmin: 2,
+                v: 7,
+                ch: vec![9; 16],
+            },
+            Message::Ack {
+                id: id.clone(),
+                caps: Capabilities::default(),
+                ext: Vec::new(),
+                v: 7,
+                re: vec![9; 16],
+                ch: vec![3; 16],
+            },
+            Message::Proof {
+                id: id.clone(),
+                re: vec![3; 16],
+            },
+            Message::Data {
+                id: id.clone(),
+                seq: u32::MAX,
+                payload: (0..=255).collect(),
+            },
+            Message::Ping { id, seq: 0 },
+        ]
+    }
+
+    #[test]
+    fn schema_round_trip() {
+        for message in messages() {
+            let encoded = message.to_cbor();
+            assert_eq!(Message::from_cbor(&encoded).unwrap(), message);
+        }
+        // Maps with text keys, the kind of message under "type".
+        let ping = Message::Ping {
+            id: "ab".into(),
+            seq: 1,
+        };
+        assert_eq!(
+            encode(&ping),
+            concat!(
+                "a3",
+                "6474797065",
+                "6470696e67",
+                "626964",
+                "626162",
+                "63736571",
+                "01"
+            )
+        );
+    }
+
+    #[test]
+    fn schema_defaults_and_unknown_fields() {
+        #[derive(Serialize)]
+        struct Raw {
+            #[serde(rename = "type")]
+            kind: &'static str,
+            id: &'static str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            seq: Option<u32>,
+            extra: Vec<u8>,
+        }
+        let raw = |kind, seq| {
+            let raw = Raw {
+                kind,
+                id: "ab",
+                seq,
+                extra: vec![1],
+            };
+            Message::from_cbor(&to_vec(&raw).unwrap())
+        };
+        // Absent fields take their defaults, unknown ones are ignored.
+        assert_eq!(
+            raw("hello", Some(1)).unwrap(),
+            Message::Hello {
+                id: "ab".into(),
+                seq: 1,
+                caps: Capabilities::default(),
+                ext: Vec::new(),
+                vmin: 2,
+                v: 2,
+                ch: Vec::new(),
+            }
+        );
+        assert_eq!(raw("ping", None), Err(Error("missing field `seq`".into())));
+        assert!(raw("bogus", Some(1)).is_err());
+        let wrong_type = BTreeMap::from([("type", "ping"), ("id", "ab"), ("seq", "one")]);
+        assert!(Message::from_cbor(&to_vec(&wrong_type).unwrap()).is_err());
+    }
+}
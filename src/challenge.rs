@@ -0,0 +1,68 @@
+//! Challenges that peers answer to prove they hold the key of their ID.
+//!
+//! A hello carries a random challenge that the ack echoes, and the ack one
+//! of its own that the hello's sender echoes in a proof. Each echo travels
+//! in a frame signed by the answering side, so after the exchange each
+//! side knows the other signed something it could not have prepared in
+//! advance. Until then the peer is not established and its data frames
+//! are dropped.
+//!
+//! One challenge is kept per address until it is answered or expires, so
+//! hellos crossing on the way, and acks to retransmitted hellos, all carry
+//! the same one.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::{RngCore, thread_rng};
+
+pub(crate) const CHALLENGE_BYTES: usize = 16;
+/// How long a challenge can be answered.
+const CHALLENGE_TTL: Duration = Duration::from_secs(120);
+/// Outstanding challenges before expired ones are dropped.
+const MAX_PENDING: usize = 1024;
+
+#[derive(Default)]
+pub(crate) struct Challenges {
+    pending: Mutex<HashMap<SocketAddr, ([u8; CHALLENGE_BYTES], Instant)>>,
+}
+
+impl Challenges {
+    /// The challenge for `peer`, made up if none is outstanding.
+    pub(crate) fn issue(&self, peer: SocketAddr) -> [u8; CHALLENGE_BYTES] {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING {
+            pending.retain(|_, (_, issued)| issued.elapsed() < CHALLENGE_TTL);
+        }
+        if let Some((challenge, issued)) = pending.get(&peer)
+            && issued.elapsed() < CHALLENGE_TTL
+        {
+            return *challenge;
+        }
+        let mut challenge = [0u8; CHALLENGE_BYTES];
+        thread_rng().fill_bytes(&mut challenge);
+        pending.insert(peer, (challenge, Instant::now()));
+        challenge
+    }
+
+    /// Whether `echo` answers the challenge outstanding for `peer`, which
+    /// is then used up.
+    pub(crate) fn answer(&self, peer: SocketAddr, echo: &[u8]) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let answered = pending.get(&peer).is_some_and(|(challenge, issued)| {
+            issued.elapsed() < CHALLENGE_TTL && challenge == echo
+        });
+        if answered {
+            pending.remove(&peer);
+        }
+        answered
+    }
+
+    pub(crate) fn forget(&self, peer: &SocketAddr) {
+        self.pending.lock().unwrap().remove(peer);
+    }
+}
@@ -0,0 +1,130 @@
+//! Named channels for streams: one that belongs to a channel opens with
+//! `[name length u8][name]`, and goes to whoever accepts that name.
+//!
+//! The name is read from the stream itself, so it is as private as the
+//! stream's bytes. Each stream that waits for its name has a thread of its
+//! own, so a peer slow to send one holds up no other; at most
+//! `MAX_UNNAMED` wait at once, and streams beyond that are dropped.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use log::debug;
+
+/// Longest channel name, in bytes.
+pub(crate) const MAX_NAME_BYTES: usize = u8::MAX as usize;
+/// Streams waiting for an accept on each channel.
+const ACCEPT_QUEUE: usize = 16;
+/// Streams whose name has not arrived yet.
+const MAX_UNNAMED: usize = 16;
+
+/// A stream that can belong to a channel.
+pub(crate) trait Channel: Read + Send + Sized + 'static {
+    fn peer(&self) -> SocketAddr;
+
+    /// The stream, as one on the channel `name`.
+    fn named(self, name: String) -> Self;
+
+    /// Give up on the stream, which nobody will read.
+    fn abandon(self);
+}
+
+type Queue<S> = (flume::Sender<S>, flume::Receiver<S>);
+
+/// Streams of one kind by channel, for the channels taken.
+pub(crate) struct Channels<S> {
+    /// What the log calls the streams.
+    kind: &'static str,
+    queues: Mutex<HashMap<String, Queue<S>>>,
+    unnamed: AtomicUsize,
+}
+
+impl<S: Channel> Channels<S> {
+    pub(crate) fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            queues: Mutex::default(),
+            unnamed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Start accepting streams on the channel `name` (on first call) and
+    /// return its queue.
+    pub(crate) fn accept(&self, name: &str) -> flume::Receiver<S> {
+        let mut queues = self.queues.lock().unwrap();
+        let (_, rx) = queues
+            .entry(name.to_string())
+            .or_insert_with(|| flume::bounded(ACCEPT_QUEUE));
+        rx.clone()
+    }
+
+    /// Whether any channel is accepted.
+    pub(crate) fn accepting(&self) -> bool {
+        !self.queues.lock().unwrap().is_empty()
+    }
+
+    /// Read the name of `stream`, opened by a peer, and queue it on that
+    /// channel.
+    pub(crate) fn name(self: &Arc<Self>, stream: S) {
+        let peer = stream.peer();
+        let kind = self.kind;
+        if self.unnamed.fetch_add(1, Ordering::Relaxed) >= MAX_UNNAMED {
+            self.unnamed.fetch_sub(1, Ordering::Relaxed);
+            debug!("too many {kind}s waiting for a channel name; dropping the one from {peer}");
+            return stream.abandon();
+        }
+        let channels = self.clone();
+        // Not a node thread: a read waiting for a name cannot be joined on
+        // shutdown, which breaks the stream instead.
+        thread::spawn(move || {
+            let mut stream = stream;
+            let name = read_name(&mut stream);
+            channels.unnamed.fetch_sub(1, Ordering::Relaxed);
+            let name = match name {
+                Ok(name) => name,
+                Err(err) => {
+                    debug!("{kind} from {peer} sent no channel name: {err}");
+                    return stream.abandon();
+                }
+            };
+            let queue = (channels.queues.lock().unwrap().get(&name)).map(|(tx, _)| tx.clone());
+            let Some(queue) = queue else {
+                debug!("{kind} from {peer} on channel {name:?}, which nobody accepts (dropped)");
+                return stream.abandon();
+            };
+            if let Err(err) = queue.try_send(stream.named(name)) {
+                debug!("channel queue full; dropping the {kind} from {peer}");
+                err.into_inner().abandon();
+            }
+        });
+    }
+
+    /// Stop accepting; waiting accepts fail.
+    pub(crate) fn close(&self) {
+        self.queues.lock().unwrap().clear();
+    }
+}
+
+/// Start a stream on the channel `name`, at most [`MAX_NAME_BYTES`] long.
+pub(crate) fn write_name(stream: &mut impl Write, name: &str) -> io::Result<()> {
+    let mut header = vec![name.len() as u8];
+    header.extend_from_slice(name.as_bytes());
+    stream.write_all(&header)
+}
+
+fn read_name(stream: &mut impl Read) -> io::Result<String> {
+    let mut len = [0u8];
+    stream.read_exact(&mut len)?;
+    let mut name = vec![0u8; usize::from(len[0])];
+    stream.read_exact(&mut name)?;
+    String::from_utf8(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "channel name is not UTF-8"))
+}
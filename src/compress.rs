@@ -0,0 +1,221 @@
+//! LZ4 block compression for message payloads and file transfers.
+//!
+//! Written against the LZ4 block format (no frame header, no checksums) so
+//! that no compression library is needed. The compressor is a plain greedy
+//! matcher; the decompressor checks every bound and refuses to grow past the
+//! announced size.
+
+/// Payloads smaller than this are not worth compressing.
+pub(crate) const THRESHOLD: usize = 256;
+
+const MIN_MATCH: usize = 4;
+/// The last match must start at least this many bytes before the end.
+const MATCH_FIND_LIMIT: usize = 12;
+/// The block always ends with at least this many literals.
+const LAST_LITERALS: usize = 5;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn push_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], offset_and_len: Option<(usize, usize)>) {
+    let literal_nibble = literals.len().min(15) as u8;
+    let match_nibble = offset_and_len.map_or(0, |(_, len)| (len - MIN_MATCH).min(15) as u8);
+    out.push(literal_nibble << 4 | match_nibble);
+    if literals.len() >= 15 {
+        push_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, len)) = offset_and_len {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if len - MIN_MATCH >= 15 {
+            push_length(out, len - MIN_MATCH - 15);
+        }
+    }
+}
+
+/// Compress `input` into one LZ4 block.
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut anchor = 0;
+    if input.len() > MATCH_FIND_LIMIT {
+        let match_limit = input.len() - MATCH_FIND_LIMIT;
+        let match_end_limit = input.len() - LAST_LITERALS;
+        let mut at = 0;
+        while at < match_limit {
+            let sequence = read_u32(input, at);
+            let slot = &mut table[hash(sequence)];
+            let candidate = std::mem::replace(slot, at);
+            if candidate == usize::MAX
+                || at - candidate > MAX_OFFSET
+                || read_u32(input, candidate) != sequence
+            {
+                at += 1;
+                continue;
+            }
+            let mut len = MIN_MATCH;
+            while at + len < match_end_limit && input[candidate + len] == input[at + len] {
+                len += 1;
+            }
+            push_sequence(&mut out, &input[anchor..at], Some((at - candidate, len)));
+            at += len;
+            anchor = at;
+        }
+    }
+    push_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+fn read_length(input: &[u8], at: &mut usize, mut len: usize) -> Option<usize> {
+    loop {
+        let byte = *input.get(*at)?;
+        *at += 1;
+        len = len.checked_add(byte as usize)?;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+/// Decompress an LZ4 block that must expand to exactly `raw_len` bytes.
+pub(crate) fn decompress(input: &[u8], raw_len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(raw_len);
+    let mut at = 0;
+    loop {
+        let token = *input.get(at)?;
+        at += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = read_length(input, &mut at, literals)?;
+        }
+        let end = at.checked_add(literals)?;
+        out.extend_from_slice(input.get(at..end)?);
+        at = end;
+        if out.len() > raw_len {
+            return None;
+        }
+        if at == input.len() {
+            break;
+        }
+
+        let offset = u16::from_le_bytes(input.get(at..at + 2)?.try_into().ok()?) as usize;
+        at += 2;
+        let mut len = (token & 15) as usize;
+        if len == 15 {
+            len = read_length(input, &mut at, len)?;
+        }
+        let len = len + MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + len > raw_len {
+            return None;
+        }
+        // Matches may overlap their own output, so copy byte by byte.
+        let start = out.len() - offset;
+        for index in start..start + len {
+            out.push(out[index]);
+        }
+    }
+    (out.len() == raw_len).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples() -> Vec<Vec<u8>> {
+        let noise =
+            Vec::from_iter((0..5000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8));
+        vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"abcdefghijklm".to_vec(),
+            vec![0; 100_000],
+            b"hello, hello, hello, hello world; ".repeat(300),
+            noise.clone(),
+            [&noise[..], &[7; 1000], &noise[..3000], &noise[..]].concat(),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for input in samples() {
+            let packed = compress(&input);
+            assert_eq!(decompress(&packed, input.len()).unwrap(), input);
+        }
+        assert!(compress(&[0; 100_000]).len() < 1000);
+    }
+
+    #[test]
+    fn refuses_wrong_lengths() {
+        for input in samples() {
+            let packed = compress(&input);
+            assert_eq!(decompress(&packed, input.len() + 1), None);
+            if !input.is_empty() {
+                assert_eq!(decompress(&packed, input.len() - 1), None);
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_blocks() {
+        for input in samples().into_iter().filter(|input| !input.is_empty()) {
+            let packed = compress(&input);
+            for len in 0..packed.len() {
+                assert_eq!(decompress(&packed[..len], input.len()), None, "{len} bytes");
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_bad_matches() {
+        // One literal, then a match at offset 0 or before the start.
+        assert_eq!(decompress(&[0x10, b'a', 0, 0, 0x00], 5), None);
+        assert_eq!(decompress(&[0x10, b'a', 2, 0, 0x00], 5), None);
+        // A match may overlap its own output.
+        let block = [0x16, b'a', 1, 0, 0x10, b'b'];
+        assert_eq!(decompress(&block, 12).unwrap(), b"aaaaaaaaaaab");
+        assert_eq!(decompress(&block, 11), None);
+        // Lengths that never end.
+        assert_eq!(decompress(&[0xf0, 255, 255], 1000), None);
+    }
+
+    #[test]
+    fn survives_garbage() {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..10_000 {
+            let len = (next() % 64) as usize;
+            let garbage = Vec::from_iter((0..len).map(|_| next() as u8));
+            let _ = decompress(&garbage, (next() % 4096) as usize);
+        }
+        // Flipped bits in real blocks.
+        for input in samples().into_iter().filter(|input| !input.is_empty()) {
+            let packed = compress(&input);
+            for _ in 0..200 {
+                let mut flipped = packed.clone();
+                let at = next() as usize % flipped.len();
+                flipped[at] ^= 1 << (next() % 8);
+                let _ = decompress(&flipped, input.len());
+            }
+        }
+    }
+}
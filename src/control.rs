@@ -0,0 +1,291 @@
+//! Control messages (hello, ack, proof, ping, goodbye, and pake and confirm
+//! for pairing) as bencoded dictionaries, the encoding the mainline DHT
+//! itself speaks.
+//!
+//! Every dictionary names its kind under `"t"`; the other keys depend on the
+//! kind, and unknown keys are ignored so new ones can be added later:
+//!
+//! ```text
+//! d4:capsi9e2:ch16:<challenge>3:ext8:<tlv>2:id64:<id>3:seqi7e1:t5:hello1:vi7e4:vmini7ee
+//! d4:capsi9e2:ch16:<challenge>2:id64:<id>2:re16:<echo>1:t3:ack1:vi7ee
+//! d2:id64:<id>2:re16:<echo>1:t5:proofe
+//! d2:id64:<id>3:seqi8e1:t4:pinge
+//! d2:id64:<id>1:t7:goodbyee
+//! d2:id64:<id>3:msg32:<message>1:t4:pakee
+//! d2:id64:<id>3:mac32:<mac>1:t7:confirme
+//! ```
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{capabilities::Capabilities, wire::legacy_version};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "t", rename_all = "lowercase")]
+pub(crate) enum Control {
+    Hello {
+        id: String,
+        seq: u32,
+        #[serde(default)]
+        caps: Capabilities,
+        /// TLV extension area (see the `extension` module).
+        #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+        ext: Vec<u8>,
+        /// Oldest and newest protocol versions the sender speaks.
+        #[serde(default = "legacy_version")]
+        vmin: u8,
+        #[serde(default = "legacy_version")]
+        v: u8,
+        /// Challenge for the ack to echo (see the `challenge` module).
+        #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+        ch: Vec<u8>,
+    },
+    /// Answer to a hello or ping.
+    Ack {
+        id: String,
+        #[serde(default)]
+        caps: Capabilities,
+        #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+        ext: Vec<u8>,
+        /// Version chosen for the session, or the sender's newest one when
+        /// it refuses the hello.
+        #[serde(default = "legacy_version")]
+        v: u8,
+        /// The hello's challenge, echoed; empty in answer to a ping.
+        #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+        re: Vec<u8>,
+        /// Challenge for the proof to echo; empty in answer to a ping.
+        #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+        ch: Vec<u8>,
+    },
+    /// Answer to the challenge of an ack, completing the hello exchange.
+    Proof {
+        id: String,
+        #[serde(with = "serde_bytes")]
+        re: Vec<u8>,
+    },
+    /// Keeps the path to a peer open; answered with an ack.
+    Ping {
+        id: String,
+        seq: u32,
+    },
+    Goodbye {
+        id: String,
+    },
+    /// A PAKE message of pairing (see the `pair` module).
+    Pake {
+        id: String,
+        #[serde(with = "serde_bytes")]
+        msg: Vec<u8>,
+    },
+    /// Proof of the key a pairing derived.
+    Confirm {
+        id: String,
+        #[serde(with = "serde_bytes")]
+        mac: Vec<u8>,
+    },
+}
+
+impl Control {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("control messages always encode")
+    }
+
+    pub(crate) fn decode(body: &[u8]) -> Option<Self> {
+        serde_bencode::from_bytes(body).ok()
+    }
+
+    /// ID of the node that sent the message.
+    pub(crate) fn id(&self) -> &str {
+        match self {
+            Self::Hello { id, .. }
+            | Self::Ack { id, .. }
+            | Self::Proof { id, .. }
+            | Self::Ping { id, .. }
+            | Self::Goodbye { id }
+            | Self::Pake { id, .. }
+            | Self::Confirm { id, .. } => id,
+        }
+    }
+
+    /// The raw extension area of a hello or ack.
+    pub(crate) fn extension_area(&self) -> &[u8] {
+        match self {
+            Self::Hello { ext, .. } | Self::Ack { ext, .. } => ext,
+            Self::Proof { .. }
+            | Self::Ping { .. }
+            | Self::Goodbye { .. }
+            | Self::Pake { .. }
+            | Self::Confirm { .. } => &[],
+        }
+    }
+
+    pub(crate) fn capabilities(&self) -> Capabilities {
+        match self {
+            Self::Hello { caps, .. } | Self::Ack { caps, .. } => *caps,
+            Self::Proof { .. }
+            | Self::Ping { .. }
+            | Self::Goodbye { .. }
+            | Self::Pake { .. }
+            | Self::Confirm { .. } => Capabilities::empty(),
+        }
+    }
+}
+
+/// The text form carried in events, e.g. `hello from <id> lz4 seq 7`.
+impl fmt::Display for Control {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Hello { id, seq, caps, .. } => {
+                write!(f, "hello from {id}")?;
+                if !caps.is_empty() {
+                    write!(f, " {caps}")?;
+                }
+                write!(f, " seq {seq}")
+            }
+            Self::Ack { id, caps, .. } => {
+                write!(f, "hello-ack from {id}")?;
+                if !caps.is_empty() {
+                    write!(f, " {caps}")?;
+                }
+                Ok(())
+            }
+            Self::Proof { id, .. } => write!(f, "proof from {id}"),
+            Self::Ping { id, seq } => write!(f, "ping from {id} seq {seq}"),
+            Self::Goodbye { id } => write!(f, "goodbye from {id}"),
+            Self::Pake { id, .. } => write!(f, "pairing message from {id}"),
+            Self::Confirm { id, .. } => write!(f, "pairing confirmation from {id}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "17c54981ea2b7b3724c73242ad1d62017236b42d4a5ecdfd387f95b0b502a15c";
+
+    fn messages() -> Vec<Control> {
+        let id = ID.to_string();
+        vec![
+            Control::Hello {
+                id: id.clone(),
+                seq: 7,
+                caps: Capabilities::LZ4 | Capabilities::FILE_TRANSFER,
+                ext: b"\x80\x00\x00\x01x".to_vec(),
+                vmin: 7,
+                v: 8,
+                ch: vec![0xcc; 16],
+            },
+            Control::Ack {
+                id: id.clone(),
+                caps: Capabilities::empty(),
+                ext: Vec::new(),
+                v: 7,
+                re: vec![0xcc; 16],
+                ch: vec![0xdd; 16],
+            },
+            Control::Proof {
+                id: id.clone(),
+                re: vec![0xdd; 16],
+            },
+            Control::Ping {
+                id: id.clone(),
+                seq: u32::MAX,
+            },
+            Control::Goodbye { id: id.clone() },
+            Control::Pake {
+                id: id.clone(),
+                msg: vec![1; 32],
+            },
+            Control::Confirm {
+                id,
+                mac: vec![2; 32],
+            },
+        ]
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        for message in messages() {
+            let encoded = message.encode();
+            assert_eq!(
+                Control::decode(&encoded),
+                Some(message.clone()),
+                "{message}"
+            );
+            assert_eq!(message.id(), ID);
+        }
+    }
+
+    #[test]
+    fn documented_layouts() {
+        let goodbye = Control::Goodbye { id: ID.into() };
+        assert_eq!(
+            goodbye.encode(),
+            format!("d2:id64:{ID}1:t7:goodbyee").into_bytes()
+        );
+        let ping = Control::Ping {
+            id: ID.into(),
+            seq: 8,
+        };
+        assert_eq!(
+            ping.encode(),
+            format!("d2:id64:{ID}3:seqi8e1:t4:pinge").into_bytes()
+        );
+        // Empty byte strings are left out.
+        let ack = &messages()[1];
+        assert!(!ack.encode().windows(5).any(|key| key == b"3:ext"));
+    }
+
+    #[test]
+    fn missing_and_unknown_keys() {
+        // A hello from before versions and challenges.
+        let old = format!("d2:id64:{ID}3:seqi1e1:t5:helloe");
+        let Some(Control::Hello {
+            caps,
+            ext,
+            vmin,
+            v,
+            ch,
+            ..
+        }) = Control::decode(old.as_bytes())
+        else {
+            panic!("old hello not decoded");
+        };
+        assert_eq!((caps, vmin, v), (Capabilities::empty(), 2, 2));
+        assert!(ext.is_empty() && ch.is_empty());
+        let newer = format!("d2:id64:{ID}6:futurei1e1:t7:goodbyee");
+        assert_eq!(
+            Control::decode(newer.as_bytes()),
+            Some(Control::Goodbye { id: ID.into() })
+        );
+        // A proof needs its echo, a ping its sequence number.
+        for body in [
+            format!("d2:id64:{ID}1:t5:proofe"),
+            format!("d2:id64:{ID}1:t4:pinge"),
+            "d1:t7:goodbyee".to_string(),
+        ] {
+            assert_eq!(Control::decode(body.as_bytes()), None, "{body}");
+        }
+    }
+
+    #[test]
+    fn malformed_messages() {
+        for body in [
+            format!("d2:id64:{ID}1:t5:helooe"),
+            format!("d2:id64:{ID}e"),
+            format!("d2:id64:{ID}1:ti3ee"),
+            "le".to_string(),
+            "i1e".to_string(),
+            String::new(),
+        ] {
+            assert_eq!(Control::decode(body.as_bytes()), None, "{body}");
+        }
+        let encoded = messages()[0].encode();
+        for len in 0..encoded.len() {
+            assert_eq!(Control::decode(&encoded[..len]), None, "{len}");
+        }
+    }
+}
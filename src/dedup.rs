@@ -0,0 +1,68 @@
+//! Per-peer receive windows that spot datagrams delivered more than once.
+
+use std::{collections::HashMap, net::SocketAddr};
+
+/// Sequence numbers remembered behind the highest one seen.
+const WINDOW: u32 = 64;
+
+/// Sliding window over one peer's sequence numbers: the highest seen so far
+/// and a bitmap of the [`WINDOW`] numbers below it.
+struct ReceiveWindow {
+    highest: u32,
+    /// Bit `i` is set if `highest - i` was seen.
+    seen: u64,
+}
+
+impl ReceiveWindow {
+    fn new(seq: u32) -> Self {
+        Self {
+            highest: seq,
+            seen: 1,
+        }
+    }
+
+    /// Record `seq`; false if it was already seen.
+    fn accept(&mut self, seq: u32) -> bool {
+        let behind = self.highest.wrapping_sub(seq);
+        if behind < WINDOW {
+            let bit = 1u64 << behind;
+            let fresh = self.seen & bit == 0;
+            self.seen |= bit;
+            return fresh;
+        }
+        let ahead = seq.wrapping_sub(self.highest);
+        // Far outside the window: most likely the peer restarted with a new
+        // random starting point, so start over from here.
+        self.seen = if ahead < WINDOW {
+            (self.seen << ahead) | 1
+        } else {
+            1
+        };
+        self.highest = seq;
+        true
+    }
+}
+
+/// Receive windows for every peer that sent us sequence-numbered datagrams.
+#[derive(Default)]
+pub(crate) struct Dedup {
+    windows: HashMap<SocketAddr, ReceiveWindow>,
+}
+
+impl Dedup {
+    /// Record `seq` from `peer`; false if it is a duplicate.
+    pub(crate) fn accept(&mut self, peer: SocketAddr, seq: u32) -> bool {
+        match self.windows.get_mut(&peer) {
+            Some(window) => window.accept(seq),
+            None => {
+                self.windows.insert(peer, ReceiveWindow::new(seq));
+                true
+            }
+        }
+    }
+
+    /// Drop the window of a peer that left.
+    pub(crate) fn forget(&mut self, peer: &SocketAddr) {
+        self.windows.remove(peer);
+    }
+}
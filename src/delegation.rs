@@ -0,0 +1,163 @@
+//! Delegations: an identity's signed statement that it has moved to a new
+//! key, so that contacts who only know the old ID still find the node.
+//!
+//! A delegation is a BEP 44 mutable item under the old key with the salt
+//! `"dhtmsg delegation"`. Its value is the bencoded dictionary
+//! `d1:n32:<new key>1:x64:<signature>e`, where the signature is the new
+//! key's over `"dhtmsg delegation" || old key`: the new key accepts the
+//! move, so nobody can send an old ID's contacts to a key that is not
+//! theirs. The sequence number is the Unix time of signing, so the newest
+//! delegation wins.
+//!
+//! Once signed, a delegation needs no secret key: whoever holds it can put
+//! it again, and the node of the new key republishes it with its
+//! announcements. A key may be rotated again later; lookups follow the
+//! chain.
+
+use std::{fmt, str::FromStr};
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use mainline::MutableItem;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Identity,
+    endpoint::{signable, signed_by},
+    error::{DhtMsgError, Result},
+    wire,
+};
+
+/// BEP 44 salt of delegations, also the context of the new key's
+/// signature.
+pub(crate) const SALT: &[u8] = b"dhtmsg delegation";
+/// Delegations followed from one ID before giving up, in case of a cycle
+/// or a chain made to be long.
+pub(crate) const MAX_DELEGATIONS: usize = 8;
+
+#[derive(Serialize, Deserialize)]
+struct Value {
+    /// The new key.
+    #[serde(with = "serde_bytes")]
+    n: Vec<u8>,
+    /// The new key's signature accepting the move.
+    #[serde(with = "serde_bytes")]
+    x: Vec<u8>,
+}
+
+/// The item as a string: the fields BEP 44 puts, bencoded.
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    #[serde(with = "serde_bytes")]
+    k: Vec<u8>,
+    seq: i64,
+    #[serde(with = "serde_bytes")]
+    sig: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    v: Vec<u8>,
+}
+
+/// A signed move from one ID to another. Displays as hex, which
+/// [`str::parse`] reads back after checking both signatures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delegation {
+    item: MutableItem,
+    to: VerifyingKey,
+}
+
+impl Delegation {
+    /// A delegation from `from` to `to`, signed by both.
+    pub fn new(from: &Identity, to: &Identity) -> Self {
+        let mut accepted = SALT.to_vec();
+        accepted.extend_from_slice(from.verifying_key().as_bytes());
+        let value = Value {
+            n: to.verifying_key().to_bytes().to_vec(),
+            x: to.sign(&accepted).to_bytes().to_vec(),
+        };
+        let value = serde_bencode::to_bytes(&value).expect("delegations always encode");
+        let seq = wire::unix_secs() as i64;
+        let signature = from.sign(&signable(seq, &value, SALT));
+        let item = MutableItem::new_signed_unchecked(
+            from.verifying_key().to_bytes(),
+            signature.to_bytes(),
+            &value,
+            seq,
+            Some(SALT),
+        );
+        Self {
+            item,
+            to: to.verifying_key(),
+        }
+    }
+
+    /// The ID that moved.
+    pub fn from_id(&self) -> String {
+        hex::encode(self.item.key())
+    }
+
+    /// The ID it moved to.
+    pub fn to_id(&self) -> String {
+        hex::encode(self.to.as_bytes())
+    }
+
+    /// The item to put in the DHT.
+    pub(crate) fn item(&self) -> &MutableItem {
+        &self.item
+    }
+}
+
+impl fmt::Display for Delegation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stored = Stored {
+            k: self.item.key().to_vec(),
+            seq: self.item.seq(),
+            sig: self.item.signature().to_vec(),
+            v: self.item.value().to_vec(),
+        };
+        let bytes = serde_bencode::to_bytes(&stored).expect("delegations always encode");
+        f.write_str(&hex::encode(bytes))
+    }
+}
+
+impl FromStr for Delegation {
+    type Err = DhtMsgError;
+
+    fn from_str(hex: &str) -> Result<Self> {
+        let bytes = hex::decode(hex.trim()).map_err(|_| DhtMsgError::InvalidDelegation)?;
+        let stored: Stored =
+            serde_bencode::from_bytes(&bytes).map_err(|_| DhtMsgError::InvalidDelegation)?;
+        let (Ok(key), Ok(signature)) = (stored.k.try_into(), stored.sig.try_into()) else {
+            return Err(DhtMsgError::InvalidDelegation);
+        };
+        let item =
+            MutableItem::new_signed_unchecked(key, signature, &stored.v, stored.seq, Some(SALT));
+        let from = VerifyingKey::from_bytes(&key).map_err(|_| DhtMsgError::InvalidDelegation)?;
+        let to = verify(&item, &from).ok_or(DhtMsgError::InvalidDelegation)?;
+        Ok(Self { item, to })
+    }
+}
+
+/// The new key in `item` if `from` delegated to it and it accepted.
+fn verify(item: &MutableItem, from: &VerifyingKey) -> Option<VerifyingKey> {
+    if !signed_by(item, from, SALT) {
+        return None;
+    }
+    let value: Value = serde_bencode::from_bytes(item.value()).ok()?;
+    let to = VerifyingKey::from_bytes(&value.n.try_into().ok()?).ok()?;
+    let accepted = Signature::from_slice(&value.x).ok()?;
+    let mut message = SALT.to_vec();
+    message.extend_from_slice(from.as_bytes());
+    to.verify_strict(&message, &accepted).ok()?;
+    (to != *from).then_some(to)
+}
+
+/// The key that the newest of `items` valid for `from` delegates to.
+pub(crate) fn newest(
+    items: impl IntoIterator<Item = MutableItem>,
+    from: &VerifyingKey,
+) -> Option<VerifyingKey> {
+    items
+        .into_iter()
+        .filter_map(|item| Some((item.seq(), verify(&item, from)?)))
+        .max_by_key(|&(seq, _)| seq)
+        .map(|(_, to)| to)
+}
@@ -0,0 +1,413 @@
+//! Signed endpoint records: BEP 44 mutable items that name the address a
+//! node answers hellos on, signed with its identity key.
+//!
+//! Anyone may `announce_peer` any address under anyone's infohash, and a
+//! lookup cannot tell which of the addresses is real. A mutable item is
+//! stored under its signer's public key, so a lookup that checks the
+//! signature against the peer's ID learns an address the peer published
+//! itself.
+//!
+//! Records sit under the ID's key with the BEP 44 salt
+//! `SHA1("dhtmsg endpoint" || salt || window)`, the salt and rotation
+//! window being those of the infohashes, so whoever cannot find a node's
+//! infohash cannot find its record either. The value is a bencoded
+//! dictionary; the sequence number is the Unix time of publication, so the
+//! newest record wins.
+//!
+//! Besides the address, a record carries what a peer wants to know before
+//! it connects, which makes it a signaling channel:
+//!
+//! ```text
+//! d1:a6:<address>1:c12:<candidates>2:c618:<candidate>4:capsi9e1:rl14:<relay hint>e1:vi7e4:vmini7ee
+//! ```
+//!
+//! Addresses are compact, the IP address and the port big-endian; `c`
+//! holds further IPv4 candidates, such as one on the local network, and
+//! `c6` IPv6 ones. The mainline DHT only speaks IPv4, so records are where
+//! peers learn of our IPv6 address.
+//! Only `a` is required, and unknown keys are ignored so that new ones can
+//! be added later. A record must fit in the 1000 bytes BEP 44 allows, so
+//! the last relay hints are left out of one that would not.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use log::warn;
+use mainline::MutableItem;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::{Identity, capabilities::Capabilities, wire};
+
+const SALT_CONTEXT: &[u8] = b"dhtmsg endpoint";
+
+/// Relay hints published at most, so that records stay small.
+pub(crate) const MAX_RELAY_HINTS: usize = 8;
+/// Candidates published besides the address, for the same reason.
+const MAX_CANDIDATES: usize = 8;
+/// Largest value BEP 44 lets a mutable item hold.
+const MAX_VALUE_BYTES: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    #[serde(with = "serde_bytes")]
+    a: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+    c: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+    c6: Vec<u8>,
+    #[serde(default)]
+    caps: Capabilities,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    r: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    v: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vmin: Option<u8>,
+}
+
+/// A peer's endpoint record, as its last lookup found it (see
+/// [`DhtMsg::peer_record`](crate::DhtMsg::peer_record)).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointRecord {
+    /// When the peer published the record, in Unix time.
+    pub seq: i64,
+    /// Where the peer answers hellos, as the DHT sees it.
+    pub addr: SocketAddrV4,
+    /// Other addresses the peer may be reached at, IPv4 ones first.
+    pub candidates: Vec<SocketAddr>,
+    /// Relays the peer suggests, as it wrote them (usually `host:port`).
+    pub relays: Vec<String>,
+    /// Oldest and newest protocol versions the peer speaks.
+    pub versions: Option<(u8, u8)>,
+    pub capabilities: Capabilities,
+}
+
+impl EndpointRecord {
+    /// The address and the candidates, without repeats.
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        let mut addresses = vec![SocketAddr::V4(self.addr)];
+        for candidate in &self.candidates {
+            if !addresses.contains(candidate) {
+                addresses.push(*candidate);
+            }
+        }
+        addresses
+    }
+}
+
+/// What we publish in our records.
+pub(crate) struct Published<'a> {
+    pub(crate) addr: SocketAddrV4,
+    pub(crate) candidates: &'a [SocketAddr],
+    pub(crate) relays: &'a [String],
+    pub(crate) capabilities: Capabilities,
+}
+
+/// The BEP 44 salt of records under `salt`, in rotation window `window`.
+pub(crate) fn salt(salt: &[u8], window: Option<u64>) -> [u8; 20] {
+    let mut hasher = <Sha1 as sha1::Digest>::new();
+    sha1::Digest::update(&mut hasher, SALT_CONTEXT);
+    sha1::Digest::update(&mut hasher, salt);
+    if let Some(window) = window {
+        sha1::Digest::update(&mut hasher, window.to_be_bytes());
+    }
+    sha1::Digest::finalize(hasher).into()
+}
+
+/// A record of `published`, signed by `identity`.
+pub(crate) fn record(identity: &Identity, published: &Published, salt: &[u8]) -> MutableItem {
+    let candidates = &published.candidates[..published.candidates.len().min(MAX_CANDIDATES)];
+    let mut record = Record {
+        a: compact(published.addr.into()),
+        c: candidates
+            .iter()
+            .filter(|candidate| candidate.is_ipv4())
+            .flat_map(|&candidate| compact(candidate))
+            .collect(),
+        c6: candidates
+            .iter()
+            .filter(|candidate| candidate.is_ipv6())
+            .flat_map(|&candidate| compact(candidate))
+            .collect(),
+        caps: published.capabilities,
+        r: published.relays.to_vec(),
+        v: Some(wire::VERSION),
+        vmin: Some(wire::MIN_VERSION),
+    };
+    let mut value = serde_bencode::to_bytes(&record).expect("records always encode");
+    // Without relay hints, at most eight candidates fit easily.
+    while value.len() > MAX_VALUE_BYTES {
+        let Some(hint) = record.r.pop() else {
+            break;
+        };
+        warn!("relay hint {hint:?} left out of our endpoint record, which would not fit");
+        value = serde_bencode::to_bytes(&record).expect("records always encode");
+    }
+    let seq = wire::unix_secs() as i64;
+    let signature = identity.sign(&signable(seq, &value, salt));
+    MutableItem::new_signed_unchecked(
+        identity.verifying_key().to_bytes(),
+        signature.to_bytes(),
+        &value,
+        seq,
+        Some(salt),
+    )
+}
+
+/// The record in `item` if `key` signed it under `salt`.
+fn verify(item: &MutableItem, key: &VerifyingKey, salt: &[u8]) -> Option<EndpointRecord> {
+    if !signed_by(item, key, salt) {
+        return None;
+    }
+    let record: Record = serde_bencode::from_bytes(item.value()).ok()?;
+    let SocketAddr::V4(addr) = parse_compact(&record.a)? else {
+        return None;
+    };
+    let v4 = record.c.chunks_exact(6);
+    let v6 = record.c6.chunks_exact(18);
+    Some(EndpointRecord {
+        seq: item.seq(),
+        addr,
+        candidates: v4.chain(v6).filter_map(parse_compact).collect(),
+        relays: record.r,
+        versions: record.vmin.zip(record.v),
+        capabilities: record.caps,
+    })
+}
+
+/// The newest of `items` that `key` signed under `salt`.
+pub(crate) fn newest(
+    items: impl IntoIterator<Item = MutableItem>,
+    key: &VerifyingKey,
+    salt: &[u8],
+) -> Option<EndpointRecord> {
+    items
+        .into_iter()
+        .filter_map(|item| verify(&item, key, salt))
+        .max_by_key(|record| record.seq)
+}
+
+fn compact(addr: SocketAddr) -> Vec<u8> {
+    let mut compact = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    compact.extend_from_slice(&addr.port().to_be_bytes());
+    compact
+}
+
+/// An address from 6 bytes for IPv4 or 18 for IPv6.
+fn parse_compact(compact: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = compact.split_last_chunk::<2>()?;
+    let ip = match ip.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes(*port)))
+}
+
+/// The address of ours that the default route leaves from, if it is a
+/// private one: where peers on the same network can reach us directly.
+/// Connecting a UDP socket sends nothing.
+pub(crate) fn lan_address() -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if ip.is_private() => Some(ip),
+        _ => None,
+    }
+}
+
+/// Our IPv6 address that the default route leaves from, if we have one
+/// beyond the link: IPv6 hosts are usually reachable from anywhere at it,
+/// firewall permitting.
+pub(crate) fn ipv6_address() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket
+        .connect((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 9))
+        .ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if !ip.is_loopback() && !ip.is_unicast_link_local() => Some(ip),
+        _ => None,
+    }
+}
+
+/// Whether `key` signed `item` under `salt`.
+pub(crate) fn signed_by(item: &MutableItem, key: &VerifyingKey, salt: &[u8]) -> bool {
+    let signature = Signature::from_bytes(item.signature());
+    item.key() == key.as_bytes()
+        && item.salt() == Some(salt)
+        && key
+            .verify_strict(&signable(item.seq(), item.value(), salt), &signature)
+            .is_ok()
+}
+
+/// What BEP 44 signs: the bencoded salt, sequence number and value, without
+/// the dictionary around them.
+pub(crate) fn signable(seq: i64, value: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut signable = format!("4:salt{}:", salt.len()).into_bytes();
+    signable.extend_from_slice(salt);
+    signable.extend(format!("3:seqi{seq}e1:v{}:", value.len()).into_bytes());
+    signable.extend_from_slice(value);
+    signable
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+
+    const SALT: &[u8] = b"endpoint salt";
+
+    fn identity() -> (Identity, SigningKey) {
+        let secret = [7; 32];
+        let identity = Identity::from_secret_hex(&hex::encode(secret)).unwrap();
+        (identity, SigningKey::from_bytes(&secret))
+    }
+
+    fn published<'a>(candidates: &'a [SocketAddr], relays: &'a [String]) -> Published<'a> {
+        Published {
+            addr: "203.0.113.7:6881".parse().unwrap(),
+            candidates,
+            relays,
+            capabilities: Capabilities::TUNNEL,
+        }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let (identity, _) = identity();
+        let candidates = [
+            "192.168.1.20:6881".parse().unwrap(),
+            "[2001:db8::7]:6881".parse().unwrap(),
+            "10.0.0.5:7000".parse().unwrap(),
+        ];
+        let relays = ["relay.example:3478".to_string()];
+        let item = record(&identity, &published(&candidates, &relays), SALT);
+        assert!(item.value().len() <= MAX_VALUE_BYTES);
+        let found = verify(&item, &identity.verifying_key(), SALT).unwrap();
+        assert_eq!(found.seq, item.seq());
+        assert_eq!(found.addr, "203.0.113.7:6881".parse().unwrap());
+        // IPv4 candidates come first.
+        assert_eq!(
+            found.candidates,
+            [candidates[0], candidates[2], candidates[1]]
+        );
+        assert_eq!(found.relays, relays);
+        assert_eq!(found.versions, Some((wire::MIN_VERSION, wire::VERSION)));
+        assert_eq!(found.capabilities, Capabilities::TUNNEL);
+    }
+
+    #[test]
+    fn records_fit_in_an_item() {
+        let (identity, _) = identity();
+        let candidates: Vec<SocketAddr> = (0..20)
+            .map(|i| SocketAddr::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i).into(), 1))
+            .collect();
+        let relays: Vec<String> = (0..MAX_RELAY_HINTS)
+            .map(|i| format!("{i}{}", "r".repeat(200)))
+            .collect();
+        let item = record(&identity, &published(&candidates, &relays), SALT);
+        assert!(item.value().len() <= MAX_VALUE_BYTES);
+        let found = verify(&item, &identity.verifying_key(), SALT).unwrap();
+        assert_eq!(found.candidates.len(), MAX_CANDIDATES);
+        // The first hints are kept, the ones that would not fit dropped.
+        assert!(!found.relays.is_empty() && found.relays.len() < relays.len());
+        assert_eq!(found.relays, relays[..found.relays.len()]);
+
+        let huge = ["x".repeat(2000)];
+        let item = record(&identity, &published(&[], &huge), SALT);
+        assert!(item.value().len() <= MAX_VALUE_BYTES);
+        assert!(
+            verify(&item, &identity.verifying_key(), SALT)
+                .unwrap()
+                .relays
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn others_signatures_are_rejected() {
+        let (identity, _) = identity();
+        let item = record(&identity, &published(&[], &[]), SALT);
+        let key = identity.verifying_key();
+        let other = Identity::generate().verifying_key();
+        assert!(verify(&item, &other, SALT).is_none());
+        assert!(verify(&item, &key, b"other salt").is_none());
+
+        // A value or sequence number the signature does not cover.
+        let mut value = item.value().to_vec();
+        *value.last_mut().unwrap() ^= 1;
+        let tampered = MutableItem::new_signed_unchecked(
+            key.to_bytes(),
+            *item.signature(),
+            &value,
+            item.seq(),
+            Some(SALT),
+        );
+        assert!(verify(&tampered, &key, SALT).is_none());
+        let replayed = MutableItem::new_signed_unchecked(
+            key.to_bytes(),
+            *item.signature(),
+            item.value(),
+            item.seq() + 1,
+            Some(SALT),
+        );
+        assert!(verify(&replayed, &key, SALT).is_none());
+        let mut signature = *item.signature();
+        signature[0] ^= 1;
+        let forged = MutableItem::new_signed_unchecked(
+            key.to_bytes(),
+            signature,
+            item.value(),
+            item.seq(),
+            Some(SALT),
+        );
+        assert!(verify(&forged, &key, SALT).is_none());
+    }
+
+    #[test]
+    fn items_signed_by_mainline_verify() {
+        let (identity, signing) = identity();
+        let key = identity.verifying_key();
+        let value = b"d1:a6:\xcb\x00\x71\x07\x1a\xe1e";
+        let older = MutableItem::new(signing.clone(), value, 1, Some(SALT));
+        let newer = MutableItem::new(signing.clone(), value, 2, Some(SALT));
+        assert!(signed_by(&older, &key, SALT));
+        assert_eq!(newest([older.clone(), newer], &key, SALT).unwrap().seq, 2);
+        assert_eq!(
+            newest([older], &key, SALT).unwrap().addr,
+            "203.0.113.7:6881".parse().unwrap()
+        );
+        // Records needing `a`, an IPv4 address.
+        let v6 = b"d1:a18:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x07\x1a\xe1e";
+        for value in [
+            &b"de"[..],
+            b"d1:a5:\xcb\x00\x71\x07\x1ae",
+            v6,
+            b"not bencode",
+        ] {
+            let item = MutableItem::new(signing.clone(), value, 3, Some(SALT));
+            assert!(signed_by(&item, &key, SALT));
+            assert!(verify(&item, &key, SALT).is_none(), "{value:?}");
+        }
+    }
+
+    #[test]
+    fn compact_addresses() {
+        for addr in ["203.0.113.7:6881", "[2001:db8::1]:443", "0.0.0.0:0"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(parse_compact(&compact(addr)), Some(addr));
+        }
+        assert_eq!(
+            compact("203.0.113.7:6881".parse().unwrap()),
+            [203, 0, 113, 7, 0x1a, 0xe1]
+        );
+        for len in [0, 1, 2, 5, 7, 17, 19] {
+            assert_eq!(parse_compact(&vec![1; len]), None, "{len}");
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::{io, net::SocketAddr, path::PathBuf, time::Duration};
+
+use mainline::{
+    Id,
+    errors::{PutMutableError, PutQueryError},
+};
+
+/// Failure classes of the dhtmsg core.
+#[derive(Debug, thiserror::Error)]
+pub enum DhtMsgError {
+    /// The DHT node could not be started.
+    #[error("failed to start DHT node")]
+    Bootstrap(#[source] io::Error),
+
+    #[error("announcing infohash {infohash} failed")]
+    Announce {
+        infohash: Id,
+        #[source]
+        source: PutQueryError,
+    },
+
+    #[error("publishing the endpoint record {target} failed")]
+    PublishEndpoint {
+        target: Id,
+        #[source]
+        source: PutMutableError,
+    },
+
+    #[error("publishing the delegation {target} failed")]
+    PublishDelegation {
+        target: Id,
+        #[source]
+        source: PutMutableError,
+    },
+
+    #[error("publishing the punch request {target} failed")]
+    PublishPunch {
+        target: Id,
+        #[source]
+        source: PutMutableError,
+    },
+
+    #[error("publishing the onion record {target} failed")]
+    PublishOnion {
+        target: Id,
+        #[source]
+        source: PutMutableError,
+    },
+
+    #[error("leaving a message in the mailbox of {peer_id} failed")]
+    LeaveInMailbox {
+        peer_id: String,
+        #[source]
+        source: PutQueryError,
+    },
+
+    #[error("publishing the mailbox index {target} failed")]
+    PublishMailbox {
+        target: Id,
+        #[source]
+        source: PutMutableError,
+    },
+
+    /// Endpoint records name the node's public address, which the DHT has
+    /// not reported yet.
+    #[error("public address not known yet")]
+    UnknownPublicAddress,
+
+    /// A socket operation on the hello path failed.
+    #[error("{context}")]
+    Socket {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("invalid hex ID string: {id}")]
+    InvalidId {
+        id: String,
+        #[source]
+        source: hex::FromHexError,
+    },
+
+    /// IDs are Ed25519 public keys, 64 hex characters.
+    #[error("ID {id} is not an Ed25519 public key")]
+    NotPublicKey { id: String },
+
+    /// The secret is deliberately not part of the message.
+    #[error("secret key must be 64 hex characters, or 128 for a session key")]
+    InvalidSecretKey,
+
+    /// Reading or creating a stored identity failed.
+    #[error("failed to access identity file {}", path.display())]
+    IdentityFile {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The stored identity is protected; load it with
+    /// [`Identity::load_or_generate_with_passphrase`](crate::Identity::load_or_generate_with_passphrase).
+    #[error("identity file {} is protected by a passphrase", path.display())]
+    PassphraseRequired { path: PathBuf },
+
+    /// The passphrase does not open the stored identity, or the file was
+    /// altered.
+    #[error("wrong passphrase for identity file {}", path.display())]
+    WrongPassphrase { path: PathBuf },
+
+    #[error("message of {len} bytes exceeds the {max}-byte limit")]
+    MessageTooLarge { len: usize, max: usize },
+
+    #[error("message {seq} to {addr} was not acknowledged after {attempts} attempts")]
+    Undelivered {
+        addr: SocketAddr,
+        seq: u32,
+        attempts: u32,
+    },
+
+    /// Reading or writing the routing table cache failed.
+    #[error("failed to access routing table cache {}", path.display())]
+    RoutingCache {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// Reading or writing the persistent outbox failed.
+    #[error("failed to access the outbox")]
+    Outbox(#[source] io::Error),
+
+    /// A stream was requested before the peer answered the challenge of a
+    /// hello exchange.
+    #[error("no hello/ack exchanged with {0} yet")]
+    NotEstablished(SocketAddr),
+
+    /// No candidate address of the peer answered a hello's challenge as
+    /// the holder of its ID's key in time.
+    #[error("no candidate proved to be {peer_id} within {timeout:?}")]
+    PeerNotVerified { peer_id: String, timeout: Duration },
+
+    /// Sealed-box mode encrypts messages only; anything else needs a Noise
+    /// session with the peer.
+    #[error("no encrypted session with {0}")]
+    NotEncrypted(SocketAddr),
+
+    /// Pairing codes look like `7-copper-walrus`.
+    #[error("invalid pairing code {code:?}")]
+    InvalidPairingCode { code: String },
+
+    /// Invites look like `dhtmsg://<id>?salt=...`. The invite itself is
+    /// left out, as it may carry the salt.
+    #[error("invalid invite: {reason}")]
+    InvalidInvite { reason: &'static str },
+
+    /// Not a delegation as printed by `Delegation`'s `Display`, or one
+    /// whose signatures do not check out.
+    #[error("invalid delegation")]
+    InvalidDelegation,
+
+    /// The peer at the rendezvous got a different key: it typed another
+    /// code, or guessed.
+    #[error("pairing with {0} failed: the codes differ")]
+    PairingFailed(SocketAddr),
+
+    #[error("no peer paired within {0:?}")]
+    PairingTimedOut(Duration),
+
+    /// The DHT found no other node; the network or the bootstrap nodes may
+    /// be unreachable.
+    #[error("the DHT did not bootstrap within {0:?}")]
+    BootstrapTimedOut(Duration),
+
+    /// A [private DHT](crate::DhtMsgBuilder::private_dht) was asked for
+    /// without nodes to bootstrap from.
+    #[error("a private DHT needs bootstrap nodes")]
+    NoBootstrapNodes,
+
+    /// Channel names are 1 to 255 bytes long.
+    #[error("invalid channel name {name:?}")]
+    InvalidChannel { name: String },
+
+    /// Topics are 1 to 255 bytes long.
+    #[error("invalid topic {topic:?}")]
+    InvalidTopic { topic: String },
+
+    /// A background thread of the node is gone.
+    #[error("{0} stopped")]
+    Closed(&'static str),
+}
+
+impl DhtMsgError {
+    pub(crate) fn socket(context: impl Into<String>, source: io::Error) -> Self {
+        Self::Socket {
+            context: context.into(),
+            source,
+        }
+    }
+}
+
+pub type Result<T, E = DhtMsgError> = std::result::Result<T, E>;
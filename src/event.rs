@@ -0,0 +1,107 @@
+use std::{net::SocketAddr, sync::Mutex};
+
+use mainline::Id;
+
+use crate::endpoint::EndpointRecord;
+
+/// Something that happened on a node, delivered to subscribers and handlers.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A lookup returned a candidate address not seen before.
+    PeerDiscovered { addr: SocketAddr },
+    /// We sent a hello to `to`.
+    HelloSent { to: SocketAddr },
+    /// A peer sent us a hello (already acknowledged).
+    HelloReceived { from: SocketAddr, message: String },
+    /// A peer acknowledged one of our hellos.
+    AckReceived { from: SocketAddr, message: String },
+    /// A peer answered the challenge of our hello or ack, proving it holds
+    /// the key of `id`. Only now are its messages and streams accepted.
+    PeerAuthenticated { from: SocketAddr, id: String },
+    /// A peer sent an application message (already acknowledged). `sender` is
+    /// the ID in the message header, whose key signed the message, and
+    /// `topic` the one it was sent on, if any.
+    MessageReceived {
+        from: SocketAddr,
+        sender: String,
+        topic: Option<String>,
+        payload: Vec<u8>,
+    },
+    /// A peer acknowledged our message with sequence number `seq`.
+    MessageAcked { from: SocketAddr, seq: u32 },
+    /// An established peer said goodbye because it is shutting down.
+    PeerLeft { from: SocketAddr, message: String },
+    /// A message queued for `peer_id` was still unacknowledged when its TTL
+    /// ran out and was dropped.
+    MessageExpired { peer_id: String, payload: Vec<u8> },
+    /// A peer speaks no protocol version we do (`version` is the newest it
+    /// offered), so nothing is exchanged with it.
+    VersionMismatch { from: SocketAddr, version: u8 },
+    /// `announce_peer` for our infohash failed.
+    AnnounceFailed { infohash: Id, error: String },
+    /// A message that `sender` left in our mailbox while we were offline
+    /// (see [`DhtMsg::check_mailbox`](crate::DhtMsg::check_mailbox)).
+    MailboxMessage { sender: String, payload: Vec<u8> },
+    /// A lookup fetched an endpoint record of `peer_id` newer than the last
+    /// one (see [`DhtMsgBuilder::signaling`](crate::DhtMsgBuilder::signaling)).
+    RecordReceived {
+        peer_id: String,
+        record: EndpointRecord,
+    },
+}
+
+type Handler = Box<dyn Fn(&Event) + Send + Sync>;
+
+/// Fan-out of events to channel subscribers and registered callbacks.
+#[derive(Default)]
+pub(crate) struct Events {
+    subscribers: Mutex<Vec<flume::Sender<Event>>>,
+    /// Subscribers to the messages on a topic and below it.
+    topics: Mutex<Vec<(String, flume::Sender<Event>)>>,
+    handlers: Mutex<Vec<Handler>>,
+}
+
+impl Events {
+    pub(crate) fn subscribe(&self) -> flume::Receiver<Event> {
+        let (tx, rx) = flume::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub(crate) fn subscribe_topic(&self, topic: &str) -> flume::Receiver<Event> {
+        let (tx, rx) = flume::unbounded();
+        self.topics.lock().unwrap().push((topic.to_string(), tx));
+        rx
+    }
+
+    pub(crate) fn add_handler(&self, handler: Handler) {
+        self.handlers.lock().unwrap().push(handler);
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        for handler in self.handlers.lock().unwrap().iter() {
+            handler(&event);
+        }
+        // Subscribers that dropped their receiver are forgotten.
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+        if let Event::MessageReceived {
+            topic: Some(topic), ..
+        } = &event
+        {
+            self.topics.lock().unwrap().retain(|(subscribed, tx)| {
+                !within(topic, subscribed) || tx.send(event.clone()).is_ok()
+            });
+        }
+    }
+}
+
+/// Whether `topic` is `subscribed` or below it, as `files/photos` is below
+/// `files`.
+fn within(topic: &str, subscribed: &str) -> bool {
+    topic
+        .strip_prefix(subscribed)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || subscribed.ends_with('/'))
+}
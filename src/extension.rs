@@ -0,0 +1,102 @@
+//! Type-length-value extension area of hellos and acks.
+//!
+//! The area is a byte string of records `[kind u16 BE][length u16 BE][value]`.
+//! Receivers skip kinds they do not know, so new features can be offered
+//! without breaking older peers. Kinds below [`APPLICATION_KINDS`] are
+//! reserved for dhtmsg itself.
+
+/// First extension kind free for applications.
+pub const APPLICATION_KINDS: u16 = 0x8000;
+
+const RECORD_HEADER_BYTES: usize = 4;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Extensions(Vec<(u16, Vec<u8>)>);
+
+impl Extensions {
+    /// Add or replace the record of `kind`.
+    pub(crate) fn set(&mut self, kind: u16, value: Vec<u8>) {
+        match self.0.iter_mut().find(|(existing, _)| *existing == kind) {
+            Some((_, slot)) => *slot = value,
+            None => self.0.push((kind, value)),
+        }
+    }
+
+    pub(crate) fn get(&self, kind: u16) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(existing, _)| *existing == kind)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut area = Vec::new();
+        for (kind, value) in &self.0 {
+            area.extend_from_slice(&kind.to_be_bytes());
+            area.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            area.extend_from_slice(value);
+        }
+        area
+    }
+
+    /// Records of `area`, or `None` if a record runs past its end.
+    pub(crate) fn parse(mut area: &[u8]) -> Option<Self> {
+        let mut records = Vec::new();
+        while !area.is_empty() {
+            if area.len() < RECORD_HEADER_BYTES {
+                return None;
+            }
+            let kind = u16::from_be_bytes([area[0], area[1]]);
+            let len = u16::from_be_bytes([area[2], area[3]]) as usize;
+            let value = area.get(RECORD_HEADER_BYTES..RECORD_HEADER_BYTES + len)?;
+            records.push((kind, value.to_vec()));
+            area = &area[RECORD_HEADER_BYTES + len..];
+        }
+        Some(Self(records))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn areas_round_trip() {
+        let mut extensions = Extensions::default();
+        assert_eq!(extensions.encode(), b"");
+        extensions.set(1, b"one".to_vec());
+        extensions.set(APPLICATION_KINDS, Vec::new());
+        extensions.set(0xffff, vec![0xab; 300]);
+        extensions.set(1, b"uno".to_vec());
+        let area = extensions.encode();
+        assert_eq!(&area[..11], b"\x00\x01\x00\x03uno\x80\x00\x00\x00");
+        assert_eq!(area.len(), 11 + 4 + 300);
+        let parsed = Extensions::parse(&area).unwrap();
+        assert_eq!(parsed, extensions);
+        assert_eq!(parsed.get(1), Some(&b"uno"[..]));
+        assert_eq!(parsed.get(APPLICATION_KINDS), Some(&b""[..]));
+        assert_eq!(parsed.get(2), None);
+        assert_eq!(Extensions::parse(b""), Some(Extensions::default()));
+    }
+
+    #[test]
+    fn unknown_kinds_are_kept() {
+        // Kinds this version knows nothing of, reserved and application ones.
+        let area = b"\x12\x34\x00\x02hi\xfe\xdc\x00\x01!";
+        let parsed = Extensions::parse(area).unwrap();
+        assert_eq!(parsed.get(0x1234), Some(&b"hi"[..]));
+        assert_eq!(parsed.get(0xfedc), Some(&b"!"[..]));
+        assert_eq!(parsed.encode(), area);
+    }
+
+    #[test]
+    fn truncated_areas() {
+        let area = b"\x00\x01\x00\x03uno\x00\x02\x00\x02ab";
+        for len in 1..area.len() {
+            // Only whole records parse.
+            let parsed = Extensions::parse(&area[..len]);
+            assert_eq!(parsed.is_some(), len == 7, "{len}");
+        }
+        assert_eq!(Extensions::parse(b"\x00\x01\xff\xffshort"), None);
+    }
+}
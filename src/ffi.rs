@@ -0,0 +1,439 @@
+//! C API, declared in `include/dhtmsg.h`.
+//!
+//! Functions returning `int` use 0 for success and -1 for failure; the failure
+//! message is available from `dhtmsg_last_error()` on the same thread.
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    ptr, slice,
+};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{DhtMsg, Event, Identity};
+
+/// Opaque node handle handed to C.
+pub struct DhtMsgHandle {
+    node: DhtMsg,
+    local_id: CString,
+}
+
+/// IPv4 peer address as seen from C.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DhtMsgAddr {
+    pub ip: [u8; 4],
+    pub port: u16,
+}
+
+impl From<SocketAddrV4> for DhtMsgAddr {
+    fn from(addr: SocketAddrV4) -> Self {
+        Self {
+            ip: addr.ip().octets(),
+            port: addr.port(),
+        }
+    }
+}
+
+impl From<DhtMsgAddr> for SocketAddr {
+    fn from(addr: DhtMsgAddr) -> Self {
+        SocketAddrV4::new(Ipv4Addr::from(addr.ip), addr.port).into()
+    }
+}
+
+pub const DHTMSG_EVENT_PEER_DISCOVERED: c_int = 1;
+pub const DHTMSG_EVENT_HELLO_RECEIVED: c_int = 2;
+pub const DHTMSG_EVENT_ACK_RECEIVED: c_int = 3;
+pub const DHTMSG_EVENT_ANNOUNCE_FAILED: c_int = 4;
+pub const DHTMSG_EVENT_PEER_LEFT: c_int = 5;
+pub const DHTMSG_EVENT_MESSAGE_RECEIVED: c_int = 6;
+pub const DHTMSG_EVENT_MESSAGE_ACKED: c_int = 7;
+pub const DHTMSG_EVENT_VERSION_MISMATCH: c_int = 8;
+pub const DHTMSG_EVENT_MESSAGE_EXPIRED: c_int = 9;
+pub const DHTMSG_EVENT_PEER_AUTHENTICATED: c_int = 10;
+pub const DHTMSG_EVENT_RECORD_RECEIVED: c_int = 11;
+pub const DHTMSG_EVENT_MAILBOX_MESSAGE: c_int = 12;
+pub const DHTMSG_EVENT_HELLO_SENT: c_int = 13;
+
+/// `kind` is one of `DHTMSG_EVENT_*`; `addr` and `message` are NUL-terminated
+/// strings valid only for the duration of the call (`message` may be empty).
+pub type DhtMsgCallback =
+    extern "C" fn(user_data: *mut c_void, kind: c_int, addr: *const c_char, message: *const c_char);
+
+/// Receives message payloads byte for byte: `data` holds `len` bytes and is
+/// valid only for the duration of the call; `addr` is NUL-terminated.
+pub type DhtMsgMessageCallback = extern "C" fn(
+    user_data: *mut c_void,
+    addr: *const c_char,
+    sender: *const c_char,
+    data: *const u8,
+    len: usize,
+);
+
+struct UserData(*mut c_void);
+
+// The C caller promises `user_data` may be used from the node's threads.
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+impl UserData {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(err: anyhow::Error) {
+    let message = CString::new(format!("{err:#}").replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn status(result: Result<()>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str> {
+    if ptr.is_null() {
+        return Err(anyhow!("{name} is NULL"));
+    }
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .with_context(|| format!("{name} is not valid UTF-8"))
+}
+
+unsafe fn handle_arg<'a>(handle: *const DhtMsgHandle) -> Result<&'a DhtMsgHandle> {
+    unsafe { handle.as_ref() }.ok_or_else(|| anyhow!("handle is NULL"))
+}
+
+/// Start a node with the identity of the given hex secret key (a random one if
+/// `secret` is NULL), announce it and start answering hellos. Returns NULL on
+/// failure.
+///
+/// # Safety
+///
+/// `secret` must be NULL or a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dhtmsg_start(secret: *const c_char) -> *mut DhtMsgHandle {
+    let start = || -> Result<DhtMsgHandle> {
+        let mut builder = DhtMsg::builder();
+        if !secret.is_null() {
+            let secret = unsafe { str_arg(secret, "secret") }?;
+            builder = builder.identity(Identity::from_secret_hex(secret)?);
+        }
+        let node = builder.build()?;
+        let _ = node.announce();
+        node.spawn_receiver();
+        let local_id = CString::new(node.local_id()).context("local ID contains NUL")?;
+        Ok(DhtMsgHandle { node, local_id })
+    };
+    match start() {
+        Ok(handle) => Box::into_raw(Box::new(handle)),
+        Err(err) => {
+            set_last_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Local hex ID, owned by the handle.
+///
+/// # Safety
+///
+/// `handle` must come from `dhtmsg_start` and not be freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dhtmsg_local_id(handle: *const DhtMsgHandle) -> *const c_char {
+    match unsafe { handle.as_ref() } {
+        Some(handle) => handle.local_id.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Re-announce the local infohash.
+///
+/// # Safety
+///
+/// `handle` must come from `dhtmsg_start` and not be freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dhtmsg_announce(handle: *const DhtMsgHandle) -> c_int {
+    status(unsafe { handle_arg(handle) }.and_then(|handle| Ok(handle.node.announce()?)))
+}
+
+/// Look up the peer's infohash and write up to `cap` candidates to `out`.
+/// Returns the number of candidates found (which may exceed `cap`) or -1.
+/// IPv6 candidates are left out, as `DhtMsgAddr` cannot hold them.
+///
+/// # Safety
+///
+/// `handle` must be live, `peer_id` a valid NUL-terminated string and `out`
+/// valid for `cap` writes (it may be NULL when `cap` is 0).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dhtmsg_find_peer(
+    handle: *const DhtMsgHandle,
+    peer_id: *const c_char,
+    out: *mut DhtMsgAddr,
+    cap: usize,
+) -> c_int {
+    let find = || -> Result<Vec<SocketAddrV4>> {
+        let handle = unsafe { handle_arg(handle) }?;
+        let found = handle
+            .node
+            .find_peer(unsafe { str_arg(peer_id, "peer_id") }?)?;
+        Ok(found
+            .into_iter()
+            .filter_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            })
+            .collect())
+    };
+    match find() {
+        Ok(found) => {
+            if !out.is_null() {
+                let out = unsafe { slice::from_raw_parts_mut(out, cap) };
+                for (slot, addr) in out.iter_mut().zip(&found) {
+                    *slot = (*addr).into();
+                }
+            }
+            c_int::try_from(found.len()).unwrap_or(c_int::MAX)
+        }
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// Send `len` bytes from `data` to `addr`.
+///
+/// # Safety
+///
+/// `handle` must be live, `addr` valid, and `data` valid for `len` reads.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dhtmsg_send(
+    handle: *const DhtMsgHandle,
+    addr: *const DhtMsgAddr,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    let send = || -> Result<()> {
+        let handle = unsafe { handle_arg(handle) }?;
+        let addr = unsafe { addr.as_ref() }.ok_or_else(|| anyhow!("addr is NULL"))?;
+        if data.is_null() && len > 0 {
+            return Err(anyhow!("data is NULL"));
+        }
+        let payload = if len == 0 {
+            &[][..]
+        } else {
+            unsafe { slice::from_raw_parts(data, len) }
+        };
+        Ok(handle.node.send((*addr).into(), payload)?)
+    };
+    status(send())
+}
+
+/// Send a hello to `addr`.
+///
+/// # Safety
+///
+/// `handle` must be live and `addr` valid.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dhtmsg_send_hello(
+    handle: *const DhtMsgHandle,
+    addr: *const DhtMsgAddr,
+) -> c_int {
+    let send = || -> Result<()> {
+        let handle = unsafe { handle_arg(handle) }?;
+        let addr = unsafe { addr.as_ref() }.ok_or_else(|| anyhow!("addr is NULL"))?;
+        Ok(handle.node.send_hello((*addr).into())?)
+    };
+    status(send())
+}
+
+/// Register `callback` for node events. It runs on the node's threads.
+///
+/// # Safety
+///
+/// `handle` must be live; `callback` and `user_data` must stay usable from
+/// other threads until the handle is freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dhtmsg_set_callback(
+    handle: *const DhtMsgHandle,
+    callback: DhtMsgCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let register = || -> Result<()> {
+        let handle = unsafe { handle_arg(handle) }?;
+        let user_data = UserData(user_data);
+        handle.node.on_event(move |event| {
+            let (kind, addr, message) = match event {
+                Event::PeerDiscovered { addr } => (
+                    DHTMSG_EVENT_PEER_DISCOVERED,
+                    addr.to_string(),
+                    String::new(),
+                ),
+                Event::HelloSent { to } => (DHTMSG_EVENT_HELLO_SENT, to.to_string(), String::new()),
+                Event::HelloReceived { from, message } => (
+                    DHTMSG_EVENT_HELLO_RECEIVED,
+                    from.to_string(),
+                    message.clone(),
+                ),
+                Event::AckReceived { from, message } => {
+                    (DHTMSG_EVENT_ACK_RECEIVED, from.to_string(), message.clone())
+                }
+                Event::PeerAuthenticated { from, id } => (
+                    DHTMSG_EVENT_PEER_AUTHENTICATED,
+                    from.to_string(),
+                    id.clone(),
+                ),
+                Event::MessageReceived { from, payload, .. } => (
+                    DHTMSG_EVENT_MESSAGE_RECEIVED,
+                    from.to_string(),
+                    String::from_utf8_lossy(payload).into_owned(),
+                ),
+                Event::MessageAcked { from, seq } => (
+                    DHTMSG_EVENT_MESSAGE_ACKED,
+                    from.to_string(),
+                    seq.to_string(),
+                ),
+                Event::PeerLeft { from, message } => {
+                    (DHTMSG_EVENT_PEER_LEFT, from.to_string(), message.clone())
+                }
+                Event::MessageExpired { peer_id, .. } => {
+                    (DHTMSG_EVENT_MESSAGE_EXPIRED, String::new(), peer_id.clone())
+                }
+                Event::VersionMismatch { from, version } => (
+                    DHTMSG_EVENT_VERSION_MISMATCH,
+                    from.to_string(),
+                    version.to_string(),
+                ),
+                Event::AnnounceFailed { error, .. } => {
+                    (DHTMSG_EVENT_ANNOUNCE_FAILED, String::new(), error.clone())
+                }
+                Event::RecordReceived { peer_id, record } => (
+                    DHTMSG_EVENT_RECORD_RECEIVED,
+                    record.addr.to_string(),
+                    peer_id.clone(),
+                ),
+                Event::MailboxMessage { sender, payload } => (
+                    DHTMSG_EVENT_MAILBOX_MESSAGE,
+                    sender.clone(),
+                    String::from_utf8_lossy(payload).into_owned(),
+                ),
+            };
+            let addr = CString::new(addr).unwrap_or_default();
+            let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+            callback(user_data.get(), kind, addr.as_ptr(), message.as_ptr());
+        });
+        Ok(())
+    };
+    status(register())
+}
+
+/// Register `callback` for received messages, with the payload unmodified.
+/// It runs on the node's threads.
+///
+/// # Safety
+///
+/// Same as `dhtmsg_set_callback`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dhtmsg_set_message_callback(
+    handle: *const DhtMsgHandle,
+    callback: DhtMsgMessageCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let register = || -> Result<()> {
+        let handle = unsafe { handle_arg(handle) }?;
+        let user_data = UserData(user_data);
+        handle.node.on_event(move |event| {
+            if let Event::MessageReceived {
+                from,
+                sender,
+                payload,
+                ..
+            } = event
+            {
+                let addr = CString::new(from.to_string()).unwrap_or_default();
+                let sender = CString::new(sender.replace('\0', " ")).unwrap_or_default();
+                callback(
+                    user_data.get(),
+                    addr.as_ptr(),
+                    sender.as_ptr(),
+                    payload.as_ptr(),
+                    payload.len(),
+                );
+            }
+        });
+        Ok(())
+    };
+    status(register())
+}
+
+/// Send `len` bytes from `data` as a message to `addr`; the peer acknowledges
+/// it with a `DHTMSG_EVENT_MESSAGE_ACKED` event. The message's sequence number
+/// is stored in `seq` unless it is NULL.
+///
+/// # Safety
+///
+/// `handle` must be live, `addr` valid, `data` valid for `len` reads and `seq`
+/// NULL or valid for a write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dhtmsg_send_message(
+    handle: *const DhtMsgHandle,
+    addr: *const DhtMsgAddr,
+    data: *const u8,
+    len: usize,
+    seq: *mut u32,
+) -> c_int {
+    let send = || -> Result<()> {
+        let handle = unsafe { handle_arg(handle) }?;
+        let addr = unsafe { addr.as_ref() }.ok_or_else(|| anyhow!("addr is NULL"))?;
+        if data.is_null() && len > 0 {
+            return Err(anyhow!("data is NULL"));
+        }
+        let payload = if len == 0 {
+            &[][..]
+        } else {
+            unsafe { slice::from_raw_parts(data, len) }
+        };
+        let sent = handle.node.send_message((*addr).into(), payload)?;
+        if let Some(seq) = unsafe { seq.as_mut() } {
+            *seq = sent;
+        }
+        Ok(())
+    };
+    status(send())
+}
+
+/// Message of the last failure on this thread, or NULL.
+#[unsafe(no_mangle)]
+pub extern "C" fn dhtmsg_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match last.borrow().as_ref() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Shut the node down (goodbye to peers, threads joined) and free the handle.
+/// NULL is ignored.
+///
+/// # Safety
+///
+/// `handle` must come from `dhtmsg_start` and not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dhtmsg_free(handle: *mut DhtMsgHandle) {
+    if !handle.is_null() {
+        let handle = unsafe { Box::from_raw(handle) };
+        handle.node.shutdown();
+    }
+}
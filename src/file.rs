@@ -0,0 +1,406 @@
+//! One-shot file transfer over a [`PeerStream`](crate::PeerStream) or a
+//! [`UtpStream`](crate::UtpStream).
+//!
+//! The sender writes a header line `dhtmsg-file <size> <name>` followed by the
+//! file bytes and finishes its side; the stream takes care of numbering,
+//! acknowledging and retransmitting the chunks. The receiver writes the file,
+//! checks that exactly `size` bytes arrived and answers `ok <size>` or
+//! `error <reason>`.
+//!
+//! Received files never replace existing ones: a taken name gets `-1`,
+//! `-2` and so on added before its extension. The bytes go to a hidden
+//! `.part` file beside it, renamed once all of them arrived.
+//!
+//! With compression the header starts with `dhtmsg-file-lz4` instead and the
+//! bytes travel as blocks `[kind u8][original length u32][length u32][data]`,
+//! where kind 1 is an LZ4 block and kind 0 stores data that did not shrink.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::compress;
+
+/// The channel files are sent on, when the receiver has channels
+/// ([`DhtMsg::channel`](crate::DhtMsg::channel)).
+pub const CHANNEL: &str = "files";
+
+const HEADER_PREFIX: &str = "dhtmsg-file ";
+const LZ4_HEADER_PREFIX: &str = "dhtmsg-file-lz4 ";
+/// File bytes per compressed block.
+const BLOCK_BYTES: usize = 64 * 1024;
+const BLOCK_STORED: u8 = 0;
+const BLOCK_LZ4: u8 = 1;
+/// Longest header line accepted, so a bogus peer cannot make us buffer forever.
+const MAX_HEADER_BYTES: u64 = 4096;
+/// Suffixed names tried for a received file whose name is taken.
+const MAX_RENAMES: u32 = 1000;
+
+/// Send the file at `path` and wait for the receiver's verdict. Returns the
+/// number of bytes delivered.
+pub fn send_file<S: Read + Write>(stream: &mut S, path: &Path) -> io::Result<u64> {
+    send(stream, path, false)
+}
+
+/// Like [`send_file`], compressing the data with LZ4. Use it only with peers
+/// that advertised support ([`DhtMsg::peer_supports_compression`]).
+///
+/// [`DhtMsg::peer_supports_compression`]: crate::DhtMsg::peer_supports_compression
+pub fn send_file_compressed<S: Read + Write>(stream: &mut S, path: &Path) -> io::Result<u64> {
+    send(stream, path, true)
+}
+
+fn send<S: Read + Write>(stream: &mut S, path: &Path, lz4: bool) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| !name.contains('\n'))
+        .ok_or_else(|| invalid(format!("{} has no usable file name", path.display())))?;
+    let prefix = if lz4 {
+        LZ4_HEADER_PREFIX
+    } else {
+        HEADER_PREFIX
+    };
+    writeln!(stream, "{prefix}{size} {name}")?;
+    let sent = if lz4 {
+        copy_compressed(&mut file, stream)?
+    } else {
+        io::copy(&mut file, stream)?
+    };
+    if sent != size {
+        return Err(invalid(format!(
+            "{} changed while sending ({sent} of {size} bytes)",
+            path.display()
+        )));
+    }
+    stream.flush()?;
+
+    let mut reply = String::new();
+    BufReader::new(stream)
+        .take(MAX_HEADER_BYTES)
+        .read_line(&mut reply)?;
+    match reply.trim_end().split_once(' ') {
+        Some(("ok", got)) if got == size.to_string() => Ok(size),
+        Some(("error", reason)) => Err(io::Error::other(format!("receiver: {reason}"))),
+        _ => Err(invalid(format!("unexpected reply {:?}", reply.trim_end()))),
+    }
+}
+
+/// Receive one file into `dir` and report the outcome to the sender. Returns
+/// where the file was written and its size.
+///
+/// Only the final path component of the sender's name is used, suffixed if
+/// a file of that name exists. A transfer that ends early or goes wrong
+/// fails and leaves nothing behind.
+pub fn receive_file<S: Read + Write>(stream: &mut S, dir: &Path) -> io::Result<(PathBuf, u64)> {
+    let result = receive_into(stream, dir);
+    let reply = match &result {
+        Ok((_, size)) => format!("ok {size}\n"),
+        Err(err) => format!("error {}\n", err.to_string().replace('\n', " ")),
+    };
+    // The sender may already be gone; the local result is what matters.
+    let _ = stream
+        .write_all(reply.as_bytes())
+        .and_then(|()| stream.flush());
+    result
+}
+
+fn receive_into<S: Read>(stream: &mut S, dir: &Path) -> io::Result<(PathBuf, u64)> {
+    let mut reader = BufReader::new(stream);
+    let mut header = String::new();
+    (&mut reader)
+        .take(MAX_HEADER_BYTES)
+        .read_line(&mut header)?;
+    let header_line = header.trim_end_matches('\n');
+    let (lz4, rest) = match header_line.strip_prefix(LZ4_HEADER_PREFIX) {
+        Some(rest) => (true, Some(rest)),
+        None => (false, header_line.strip_prefix(HEADER_PREFIX)),
+    };
+    let (size, name) = rest
+        .and_then(|rest| rest.split_once(' '))
+        .and_then(|(size, name)| Some((size.parse::<u64>().ok()?, name)))
+        .ok_or_else(|| invalid(format!("bad file header {:?}", header.trim_end())))?;
+    let name = Path::new(name)
+        .file_name()
+        .ok_or_else(|| invalid(format!("bad file name {name:?}")))?;
+    let path = reserve(dir, name)?;
+    let partial = partial_path(&path);
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&partial)
+    {
+        Ok(file) => file,
+        Err(err) => {
+            let _ = fs::remove_file(&path);
+            return Err(err);
+        }
+    };
+    let written = if lz4 {
+        copy_decompressed(&mut reader, &mut file, size)
+    } else {
+        io::copy(&mut (&mut reader).take(size), &mut file)
+    };
+    let result = written.and_then(|got| {
+        file.sync_all()?;
+        if got != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} truncated: got {got} of {size} bytes", path.display()),
+            ));
+        }
+        Ok(())
+    });
+    drop(file);
+    // Over the empty file holding the name.
+    if let Err(err) = result.and_then(|()| fs::rename(&partial, &path)) {
+        let _ = fs::remove_file(&partial);
+        let _ = fs::remove_file(&path);
+        return Err(err);
+    }
+    Ok((path, size))
+}
+
+/// Hold a free name in `dir` for a file called `name`, as an empty file:
+/// `name` itself, or `name` with `-1`, `-2` and so on before the extension.
+fn reserve(dir: &Path, name: &OsStr) -> io::Result<PathBuf> {
+    let name = Path::new(name);
+    let stem = name
+        .file_stem()
+        .unwrap_or(name.as_os_str())
+        .to_string_lossy();
+    let extension = name.extension().map(OsStr::to_string_lossy);
+    for attempt in 0..=MAX_RENAMES {
+        let path = match (attempt, &extension) {
+            (0, _) => dir.join(name),
+            (_, Some(extension)) => dir.join(format!("{stem}-{attempt}.{extension}")),
+            (_, None) => dir.join(format!("{stem}-{attempt}")),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(path),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!(
+            "{} and {MAX_RENAMES} variants of it exist",
+            dir.join(name).display()
+        ),
+    ))
+}
+
+/// The hidden file `path` is received into.
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.part"))
+}
+
+/// Write `file` to `stream` as compressed blocks; returns the bytes read.
+fn copy_compressed(file: &mut impl Read, stream: &mut impl Write) -> io::Result<u64> {
+    let mut block = vec![0; BLOCK_BYTES];
+    let mut total = 0;
+    loop {
+        let mut len = 0;
+        while len < BLOCK_BYTES {
+            match file.read(&mut block[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        if len == 0 {
+            return Ok(total);
+        }
+        let raw = &block[..len];
+        let packed = compress::compress(raw);
+        let (kind, data) = if packed.len() < raw.len() {
+            (BLOCK_LZ4, &packed[..])
+        } else {
+            (BLOCK_STORED, raw)
+        };
+        stream.write_all(&[kind])?;
+        stream.write_all(&(len as u32).to_be_bytes())?;
+        stream.write_all(&(data.len() as u32).to_be_bytes())?;
+        stream.write_all(data)?;
+        total += len as u64;
+    }
+}
+
+/// Read compressed blocks until `size` bytes were written to `file`.
+fn copy_decompressed(stream: &mut impl Read, file: &mut impl Write, size: u64) -> io::Result<u64> {
+    let mut total = 0;
+    while total < size {
+        let mut header = [0u8; 9];
+        match stream.read_exact(&mut header) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            result => result?,
+        }
+        let raw_len = u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize;
+        let data_len = u32::from_be_bytes(header[5..9].try_into().unwrap()) as usize;
+        if raw_len > BLOCK_BYTES || data_len > BLOCK_BYTES {
+            return Err(invalid(format!(
+                "oversized block ({raw_len}/{data_len} bytes)"
+            )));
+        }
+        let mut data = vec![0; data_len];
+        match stream.read_exact(&mut data) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            result => result?,
+        }
+        let raw = match header[0] {
+            BLOCK_STORED if data_len == raw_len => data,
+            BLOCK_LZ4 => compress::decompress(&data, raw_len)
+                .ok_or_else(|| invalid("corrupt compressed block".to_string()))?,
+            kind => return Err(invalid(format!("bad block kind {kind}"))),
+        };
+        if total + raw_len as u64 > size {
+            return Err(invalid(format!("more than the {size} bytes announced")));
+        }
+        file.write_all(&raw)?;
+        total += raw_len as u64;
+    }
+    Ok(total)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A fresh directory of its own for each test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dhtmsg-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn contents() -> Vec<u8> {
+        let mut contents = b"some text that repeats, ".repeat(5000);
+        contents
+            .extend((0..BLOCK_BYTES as u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8));
+        contents
+    }
+
+    #[test]
+    fn compressed_blocks_round_trip() {
+        let contents = contents();
+        let mut blocks = Vec::new();
+        assert_eq!(
+            copy_compressed(&mut &contents[..], &mut blocks).unwrap(),
+            contents.len() as u64
+        );
+        assert!(blocks.len() < contents.len());
+        let mut out = Vec::new();
+        let got = copy_decompressed(&mut &blocks[..], &mut out, contents.len() as u64).unwrap();
+        assert_eq!(got, contents.len() as u64);
+        assert_eq!(out, contents);
+    }
+
+    #[test]
+    fn refuses_more_than_announced() {
+        let contents = contents();
+        let mut blocks = Vec::new();
+        copy_compressed(&mut &contents[..], &mut blocks).unwrap();
+        let mut out = Vec::new();
+        let err = copy_decompressed(&mut &blocks[..], &mut out, 10).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn receives_a_file() {
+        let dir = test_dir("receive");
+        let contents = contents();
+        let mut stream = format!(
+            "{HEADER_PREFIX}{} ../../name.txt
+",
+            contents.len()
+        )
+        .into_bytes();
+        stream.extend_from_slice(&contents);
+        let (path, size) = receive_into(&mut Cursor::new(stream), &dir).unwrap();
+        assert_eq!(path, dir.join("name.txt"));
+        assert_eq!(size, contents.len() as u64);
+        assert_eq!(fs::read(&path).unwrap(), contents);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_existing_files() {
+        let dir = test_dir("existing");
+        fs::write(dir.join("name.txt"), "mine").unwrap();
+        fs::write(dir.join("name-1.txt"), "mine too").unwrap();
+        fs::write(dir.join("bare"), "mine").unwrap();
+        let receive = |name: &str, contents: &str| {
+            let stream = format!("{HEADER_PREFIX}{} {name}\n{contents}", contents.len());
+            receive_into(&mut Cursor::new(stream), &dir).unwrap().0
+        };
+        assert_eq!(receive("name.txt", "theirs"), dir.join("name-2.txt"));
+        assert_eq!(receive("bare", "theirs"), dir.join("bare-1"));
+        assert_eq!(receive(".hidden", "theirs"), dir.join(".hidden"));
+        assert_eq!(fs::read_to_string(dir.join("name.txt")).unwrap(), "mine");
+        assert_eq!(
+            fs::read_to_string(dir.join("name-1.txt")).unwrap(),
+            "mine too"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("name-2.txt")).unwrap(),
+            "theirs"
+        );
+        assert_eq!(fs::read_to_string(dir.join("bare-1")).unwrap(), "theirs");
+        // No partial files are left over.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 6);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removes_failed_files() {
+        let dir = test_dir("failed");
+        let contents = contents();
+        let mut blocks = Vec::new();
+        copy_compressed(&mut &contents[..], &mut blocks).unwrap();
+
+        let mut truncated = format!(
+            "{HEADER_PREFIX}{} short.bin
+",
+            contents.len()
+        )
+        .into_bytes();
+        truncated.extend_from_slice(&contents[..1000]);
+        let err = receive_into(&mut Cursor::new(truncated), &dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut oversized = format!(
+            "{LZ4_HEADER_PREFIX}1000 long.bin
+"
+        )
+        .into_bytes();
+        oversized.extend_from_slice(&blocks);
+        let err = receive_into(&mut Cursor::new(oversized), &dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut corrupt = format!(
+            "{LZ4_HEADER_PREFIX}{} corrupt.bin
+",
+            contents.len()
+        )
+        .into_bytes();
+        corrupt.extend_from_slice(&[BLOCK_LZ4, 0, 0, 1, 0, 0, 0, 0, 2, 0xff, 0xff]);
+        assert!(receive_into(&mut Cursor::new(corrupt), &dir).is_err());
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+//! Splitting datagrams too big for one UDP packet and putting them back
+//! together on the receiving side.
+//!
+//! Each fragment is `[tag][datagram id u32][index u16][count u16][chunk]`,
+//! sent as the body of a `Fragment` wire frame.
+//! Fragments may arrive in any order; a datagram missing pieces after
+//! [`REASSEMBLY_TIMEOUT`] is dropped.
+//!
+//! Pieces arrive before anyone is authenticated, so they are held to what
+//! [`split`] makes: every piece but the last is exactly `FRAGMENT_BYTES`
+//! long, the last no longer, and each sender has a few partial datagrams at
+//! most, so that one address cannot crowd out the others.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+/// First byte of a fragment.
+const TAG_FRAGMENT: u8 = 0xd3;
+const HEADER_BYTES: usize = 9;
+/// Chunk bytes per fragment, small enough to avoid IP fragmentation.
+const FRAGMENT_BYTES: usize = 1200;
+/// Most fragments one datagram may be split into.
+const MAX_FRAGMENTS: usize = 64;
+/// Largest datagram that can be sent in fragments.
+pub(crate) const MAX_FRAGMENTED_BYTES: usize = FRAGMENT_BYTES * MAX_FRAGMENTS;
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Partial datagrams kept at once from one address; its oldest is dropped
+/// to make room.
+const MAX_PENDING_PER_PEER: usize = 4;
+/// Partial datagrams kept at once in all, about 20 MB at most; the oldest
+/// is dropped to make room.
+const MAX_PENDING: usize = 256;
+
+pub(crate) fn is_fragment(datagram: &[u8]) -> bool {
+    datagram.first() == Some(&TAG_FRAGMENT) && datagram.len() > HEADER_BYTES
+}
+
+/// Cut `datagram` into fragments tagged with `id`.
+pub(crate) fn split(id: u32, datagram: &[u8]) -> Vec<Vec<u8>> {
+    assert!(datagram.len() <= MAX_FRAGMENTED_BYTES, "datagram too large");
+    let count = datagram.len().div_ceil(FRAGMENT_BYTES) as u16;
+    datagram
+        .chunks(FRAGMENT_BYTES)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut fragment = Vec::with_capacity(HEADER_BYTES + chunk.len());
+            fragment.push(TAG_FRAGMENT);
+            fragment.extend_from_slice(&id.to_be_bytes());
+            fragment.extend_from_slice(&(index as u16).to_be_bytes());
+            fragment.extend_from_slice(&count.to_be_bytes());
+            fragment.extend_from_slice(chunk);
+            fragment
+        })
+        .collect()
+}
+
+struct Partial {
+    chunks: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    started: Instant,
+}
+
+/// Fragments received so far, keyed by sender and datagram id.
+#[derive(Default)]
+pub(crate) struct Reassembler {
+    pending: HashMap<(SocketAddr, u32), Partial>,
+}
+
+impl Reassembler {
+    /// Add a fragment; returns the whole datagram once its last piece arrives.
+    pub(crate) fn push(&mut self, fragment: &[u8], peer: SocketAddr) -> Option<Vec<u8>> {
+        let id = u32::from_be_bytes(fragment[1..5].try_into().unwrap());
+        let index = u16::from_be_bytes(fragment[5..7].try_into().unwrap()) as usize;
+        let count = u16::from_be_bytes(fragment[7..9].try_into().unwrap()) as usize;
+        if count == 0 || count > MAX_FRAGMENTS || index >= count {
+            debug!("malformed fragment from {peer} (dropped)");
+            return None;
+        }
+        let chunk = &fragment[HEADER_BYTES..];
+        let last = index == count - 1;
+        if chunk.len() > FRAGMENT_BYTES || (!last && chunk.len() < FRAGMENT_BYTES) {
+            debug!("fragment of {} bytes from {peer} (dropped)", chunk.len());
+            return None;
+        }
+
+        let now = Instant::now();
+        self.pending
+            .retain(|_, partial| now.duration_since(partial.started) < REASSEMBLY_TIMEOUT);
+        let key = (peer, id);
+        if !self.pending.contains_key(&key) {
+            let from_peer = self
+                .pending
+                .keys()
+                .filter(|(from, _)| *from == peer)
+                .count();
+            if from_peer >= MAX_PENDING_PER_PEER {
+                self.drop_oldest(|from| from == peer);
+            } else if self.pending.len() >= MAX_PENDING {
+                self.drop_oldest(|_| true);
+            }
+        }
+        let partial = self.pending.entry(key).or_insert_with(|| Partial {
+            chunks: vec![None; count],
+            missing: count,
+            started: now,
+        });
+        if partial.chunks.len() != count {
+            debug!("fragment count mismatch from {peer} (dropped)");
+            return None;
+        }
+        let slot = &mut partial.chunks[index];
+        if slot.is_none() {
+            *slot = Some(chunk.to_vec());
+            partial.missing -= 1;
+        }
+        if partial.missing > 0 {
+            return None;
+        }
+        let partial = self.pending.remove(&key)?;
+        Some(partial.chunks.into_iter().flatten().flatten().collect())
+    }
+
+    /// Forget the oldest partial datagram from a sender `from` matches.
+    fn drop_oldest(&mut self, from: impl Fn(SocketAddr) -> bool) {
+        let oldest = self
+            .pending
+            .iter()
+            .filter(|((peer, _), _)| from(*peer))
+            .min_by_key(|(_, partial)| partial.started)
+            .map(|(key, _)| *key);
+        if let Some(oldest) = oldest {
+            self.pending.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let datagram: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        let mut fragments = split(7, &datagram);
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|fragment| is_fragment(fragment)));
+        fragments.reverse();
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(&fragments[0], peer(1)), None);
+        assert_eq!(reassembler.push(&fragments[1], peer(1)), None);
+        // A repeated piece changes nothing.
+        assert_eq!(reassembler.push(&fragments[1], peer(1)), None);
+        assert_eq!(reassembler.push(&fragments[2], peer(1)), Some(datagram));
+    }
+
+    #[test]
+    fn keeps_senders_apart() {
+        let datagram = vec![1u8; 2000];
+        let fragments = split(1, &datagram);
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(&fragments[0], peer(1)), None);
+        assert_eq!(reassembler.push(&fragments[1], peer(2)), None);
+        assert_eq!(reassembler.push(&fragments[1], peer(1)), Some(datagram));
+    }
+
+    #[test]
+    fn rejects_malformed_pieces() {
+        let mut reassembler = Reassembler::default();
+        let fragment = |index: u16, count: u16, len: usize| {
+            let mut fragment = vec![TAG_FRAGMENT, 0, 0, 0, 1];
+            fragment.extend_from_slice(&index.to_be_bytes());
+            fragment.extend_from_slice(&count.to_be_bytes());
+            fragment.resize(HEADER_BYTES + len, 0);
+            fragment
+        };
+        // Bad counts and indices.
+        assert_eq!(reassembler.push(&fragment(0, 0, 10), peer(1)), None);
+        assert_eq!(reassembler.push(&fragment(2, 2, 10), peer(1)), None);
+        let too_many = MAX_FRAGMENTS as u16 + 1;
+        assert_eq!(reassembler.push(&fragment(0, too_many, 10), peer(1)), None);
+        // Oversized pieces, and short ones before the last.
+        assert_eq!(
+            reassembler.push(&fragment(1, 2, FRAGMENT_BYTES + 1), peer(1)),
+            None
+        );
+        assert_eq!(reassembler.push(&fragment(0, 2, 100), peer(1)), None);
+        assert!(reassembler.pending.is_empty());
+        // A single short piece is the last as well.
+        assert_eq!(
+            reassembler.push(&fragment(0, 1, 100), peer(1)),
+            Some(vec![0; 100])
+        );
+    }
+
+    #[test]
+    fn bounds_partials_per_sender() {
+        let mut reassembler = Reassembler::default();
+        let datagram = vec![0u8; 2 * FRAGMENT_BYTES];
+        let honest = split(1, &datagram);
+        assert_eq!(reassembler.push(&honest[0], peer(1)), None);
+        // Another address starting many datagrams only evicts its own.
+        for id in 0..100 {
+            assert_eq!(reassembler.push(&split(id, &datagram)[0], peer(2)), None);
+        }
+        let from_attacker = reassembler
+            .pending
+            .keys()
+            .filter(|(from, _)| *from == peer(2));
+        assert_eq!(from_attacker.count(), MAX_PENDING_PER_PEER);
+        assert_eq!(reassembler.push(&honest[1], peer(1)), Some(datagram));
+    }
+
+    #[test]
+    fn bounds_partials_in_all() {
+        let mut reassembler = Reassembler::default();
+        let datagram = vec![0u8; 2 * FRAGMENT_BYTES];
+        for port in 0..MAX_PENDING as u16 + 10 {
+            assert_eq!(reassembler.push(&split(0, &datagram)[0], peer(port)), None);
+        }
+        assert_eq!(reassembler.pending.len(), MAX_PENDING);
+    }
+}
@@ -0,0 +1,115 @@
+//! HMAC-SHA256 (RFC 2104), used by the Noise handshake and pre-shared keys,
+//! and HMAC-SHA1, which TURN messages are authenticated with.
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+const BLOCK_BYTES: usize = 64;
+pub(crate) const HMAC_BYTES: usize = 32;
+
+/// HMAC of the concatenated `parts`; keys of any length are accepted.
+pub(crate) fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; HMAC_BYTES] {
+    let mut block = [0u8; BLOCK_BYTES];
+    if key.len() > BLOCK_BYTES {
+        block[..HMAC_BYTES].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new().chain_update(block.map(|byte| byte ^ 0x36));
+    for part in parts {
+        inner.update(part);
+    }
+    Sha256::new()
+        .chain_update(block.map(|byte| byte ^ 0x5c))
+        .chain_update(inner.finalize())
+        .finalize()
+        .into()
+}
+
+/// HMAC-SHA1 of `message`; keys of any length are accepted.
+pub(crate) fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut block = [0u8; BLOCK_BYTES];
+    if key.len() > BLOCK_BYTES {
+        block[..20].copy_from_slice(&<Sha1 as sha1::Digest>::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = <Sha1 as sha1::Digest>::new();
+    sha1::Digest::update(&mut inner, block.map(|byte| byte ^ 0x36));
+    sha1::Digest::update(&mut inner, message);
+    let mut outer = <Sha1 as sha1::Digest>::new();
+    sha1::Digest::update(&mut outer, block.map(|byte| byte ^ 0x5c));
+    sha1::Digest::update(&mut outer, sha1::Digest::finalize(inner));
+    sha1::Digest::finalize(outer).into()
+}
+
+/// Compare MACs in constant time, so a mismatch does not leak how much matched.
+pub(crate) fn verify(expected: &[u8], received: &[u8]) -> bool {
+    received.len() == expected.len()
+        && expected
+            .iter()
+            .zip(received)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc2202_hmac_sha1() {
+        let counting: [u8; 25] = std::array::from_fn(|i| i as u8 + 1);
+        let long_key = [0xaa; 80];
+        let cases: [(&[u8], &[u8], &str); 7] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b617318655057264e28bc0b6fb378c8ef146be00",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79",
+            ),
+            (
+                &[0xaa; 20],
+                &[0xdd; 50],
+                "125d7342b9ac11cd91a39af48aa17b4f63f175d3",
+            ),
+            (
+                &counting,
+                &[0xcd; 50],
+                "4c9007f4026250c6bc8414f9bf50c86c2d7235da",
+            ),
+            (
+                &[0x0c; 20],
+                b"Test With Truncation",
+                "4c1a03424b55e07fe7f27be1d58bb9324a9a5a04",
+            ),
+            (
+                &long_key,
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "aa4ae5e15272d00e95705637ce8a3b55ed402112",
+            ),
+            (
+                &long_key,
+                b"Test Using Larger Than Block-Size Key and Larger Than One Block-Size Data",
+                "e8e99d0f45237d786d6bbaa7965c7808bbff1a91",
+            ),
+        ];
+        for (i, (key, message, mac)) in cases.into_iter().enumerate() {
+            assert_eq!(hex::encode(hmac_sha1(key, message)), mac, "case {}", i + 1);
+        }
+    }
+
+    #[test]
+    fn verify_compares_whole_macs() {
+        let mac = hmac_sha1(b"key", b"message");
+        assert!(verify(&mac, &mac));
+        assert!(!verify(&mac, &mac[..19]));
+        let mut other = mac;
+        other[19] ^= 1;
+        assert!(!verify(&mac, &other));
+    }
+}
@@ -0,0 +1,448 @@
+use std::{
+    env, fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use curve25519_dalek::{MontgomeryPoint, Scalar};
+use ed25519_dalek::{
+    SECRET_KEY_LENGTH, Signature, Signer, SigningKey, VerifyingKey,
+    hazmat::{self, ExpandedSecretKey},
+};
+use mainline::Id;
+use rand::{RngCore, thread_rng};
+use sha1::{Digest, Sha1};
+use sha2::Sha512;
+
+use crate::{
+    error::{DhtMsgError, Result},
+    keyfile::{self, OpenError},
+};
+
+/// An Ed25519 keypair. The public key, as 64 hex characters, is the node's
+/// ID: peers find it under the infohash derived from that key and check the
+/// signature on everything it sends.
+///
+/// Clones share the counter that numbers frames, so nodes built from clones
+/// never reuse a nonce.
+#[derive(Clone)]
+pub struct Identity {
+    key: Key,
+    next_nonce: Arc<AtomicU64>,
+}
+
+/// Session identities (see [`Identity::for_session`]) have no seed, only
+/// the blinded scalar and hash prefix that a seed would expand to.
+#[derive(Clone)]
+enum Key {
+    Seed(SigningKey),
+    Session {
+        secret: Arc<ExpandedSecretKey>,
+        public: VerifyingKey,
+    },
+}
+
+const SESSION_CONTEXT: &[u8] = b"dhtmsg session";
+const SESSION_PREFIX_CONTEXT: &[u8] = b"dhtmsg session prefix";
+/// Length of nonces from [`session_nonce`].
+const SESSION_NONCE_BYTES: usize = 16;
+const RELAY_CONTEXT: &[u8] = b"dhtmsg relay";
+
+impl Identity {
+    pub fn generate() -> Self {
+        let mut secret = [0u8; SECRET_KEY_LENGTH];
+        thread_rng().fill_bytes(&mut secret);
+        Self::from_key(Key::Seed(SigningKey::from_bytes(&secret)))
+    }
+
+    /// Nonces start at the current time in microseconds, so they keep
+    /// growing across restarts unless a node sends a million frames a second.
+    fn from_key(key: Key) -> Self {
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros() as u64);
+        Self {
+            key,
+            next_nonce: Arc::new(AtomicU64::new(micros)),
+        }
+    }
+
+    /// Identity from a secret key as printed by [`secret_hex`](Self::secret_hex).
+    pub fn from_secret_hex(secret_hex: &str) -> Result<Self> {
+        let secret = hex::decode(secret_hex.trim()).map_err(|_| DhtMsgError::InvalidSecretKey)?;
+        Self::from_secret_bytes(&secret)
+    }
+
+    /// A seed, or the scalar and hash prefix of a session key.
+    fn from_secret_bytes(secret: &[u8]) -> Result<Self> {
+        if let Ok(seed) = <[u8; SECRET_KEY_LENGTH]>::try_from(secret) {
+            return Ok(Self::from_key(Key::Seed(SigningKey::from_bytes(&seed))));
+        }
+        let (scalar, hash_prefix) = secret
+            .split_first_chunk::<32>()
+            .and_then(|(scalar, rest)| Some((scalar, <[u8; 32]>::try_from(rest).ok()?)))
+            .ok_or(DhtMsgError::InvalidSecretKey)?;
+        let scalar = Option::from(Scalar::from_canonical_bytes(*scalar))
+            .ok_or(DhtMsgError::InvalidSecretKey)?;
+        Ok(Self::from_key(session_key(ExpandedSecretKey {
+            scalar,
+            hash_prefix,
+        })))
+    }
+
+    /// The identity of one session: a key blinded by `nonce`, the way Tor
+    /// blinds onion service keys. Its ID and infohashes have nothing in
+    /// common with ours or those of other sessions for whoever does not
+    /// know the nonce, while a peer that knows both our ID and the nonce
+    /// gets the session ID from [`derive_session_id`] and so still knows
+    /// it is talking to us.
+    pub fn for_session(&self, nonce: &[u8]) -> Self {
+        let (scalar, hash_prefix) = match &self.key {
+            Key::Seed(key) => {
+                let expanded = ExpandedSecretKey::from(&key.to_bytes());
+                (expanded.scalar, expanded.hash_prefix)
+            }
+            Key::Session { secret, .. } => (secret.scalar, secret.hash_prefix),
+        };
+        let prefix = sha512(&[SESSION_PREFIX_CONTEXT, &hash_prefix, nonce]);
+        let secret = ExpandedSecretKey {
+            scalar: blinding_factor(&self.verifying_key(), nonce) * scalar,
+            hash_prefix: prefix[..32].try_into().expect("SHA-512 digest is 64 bytes"),
+        };
+        Self {
+            key: session_key(secret),
+            next_nonce: Arc::clone(&self.next_nonce),
+        }
+    }
+
+    /// Identity stored in the file at `path`, or a new one saved there if the
+    /// file does not exist yet, so that the ID survives restarts. The file
+    /// holds the secret key as hex and is readable by its owner only.
+    ///
+    /// Fails with [`DhtMsgError::PassphraseRequired`] if the file is
+    /// protected by a passphrase.
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        Self::load_or_create(path, None)
+    }
+
+    /// Like [`load_or_generate`](Self::load_or_generate), but a new file is
+    /// protected by `passphrase`: the secret key is encrypted under a key
+    /// derived from it with Argon2id. Files without a passphrase are read as
+    /// well.
+    pub fn load_or_generate_with_passphrase(path: &Path, passphrase: &str) -> Result<Self> {
+        Self::load_or_create(path, Some(passphrase))
+    }
+
+    fn load_or_create(path: &Path, passphrase: Option<&str>) -> Result<Self> {
+        let file_error = |source| DhtMsgError::IdentityFile {
+            path: path.to_path_buf(),
+            source,
+        };
+        match fs::read_to_string(path) {
+            Ok(contents) => return Self::from_file_contents(path, &contents, passphrase),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(file_error(err)),
+        }
+        let identity = Self::generate();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(file_error)?;
+        }
+        // Fails if another process created it first rather than replacing
+        // the identity that process may already be using.
+        match owner_only_file(path, true) {
+            Ok(mut file) => {
+                writeln!(file, "{}", identity.file_contents(passphrase)).map_err(file_error)?;
+                Ok(identity)
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                Self::load_or_create(path, passphrase)
+            }
+            Err(err) => Err(file_error(err)),
+        }
+    }
+
+    /// Store the identity at `path`, replacing what is there, protected by
+    /// `passphrase` unless it is `None`. The file is replaced in one step,
+    /// so it never holds half a key.
+    pub fn save(&self, path: &Path, passphrase: Option<&str>) -> Result<()> {
+        let file_error = |source| DhtMsgError::IdentityFile {
+            path: path.to_path_buf(),
+            source,
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(file_error)?;
+        }
+        let mut temp = path.as_os_str().to_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let written = owner_only_file(&temp, false)
+            .and_then(|mut file| {
+                writeln!(file, "{}", self.file_contents(passphrase))?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&temp, path));
+        if let Err(err) = written {
+            let _ = fs::remove_file(&temp);
+            return Err(file_error(err));
+        }
+        Ok(())
+    }
+
+    fn file_contents(&self, passphrase: Option<&str>) -> String {
+        match passphrase {
+            Some(passphrase) => keyfile::protect(&self.secret_bytes(), passphrase),
+            None => self.secret_hex(),
+        }
+    }
+
+    fn from_file_contents(path: &Path, contents: &str, passphrase: Option<&str>) -> Result<Self> {
+        if !keyfile::is_protected(contents) {
+            return Self::from_secret_hex(contents);
+        }
+        let path = path.to_path_buf();
+        let Some(passphrase) = passphrase else {
+            return Err(DhtMsgError::PassphraseRequired { path });
+        };
+        match keyfile::open(contents, passphrase) {
+            Ok(secret) => Self::from_secret_bytes(&secret),
+            Err(OpenError::WrongPassphrase) => Err(DhtMsgError::WrongPassphrase { path }),
+            Err(OpenError::Malformed) => Err(DhtMsgError::IdentityFile {
+                path,
+                source: io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "malformed passphrase-protected identity",
+                ),
+            }),
+        }
+    }
+
+    /// Where [`load_or_generate`](Self::load_or_generate) keeps the identity
+    /// by default: `dhtmsg/identity` in `$XDG_CONFIG_HOME`, `~/.config` or,
+    /// on Windows, `%APPDATA%`. `None` if none of them is set.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+        Some(config_dir.join("dhtmsg").join("identity"))
+    }
+
+    /// The secret key as hex. Whoever has it can act as this node. Session
+    /// keys take 128 hex characters, the others 64.
+    pub fn secret_hex(&self) -> String {
+        hex::encode(self.secret_bytes())
+    }
+
+    fn secret_bytes(&self) -> Vec<u8> {
+        match &self.key {
+            Key::Seed(key) => key.to_bytes().to_vec(),
+            Key::Session { secret, .. } => {
+                let mut bytes = secret.scalar.to_bytes().to_vec();
+                bytes.extend_from_slice(&secret.hash_prefix);
+                bytes
+            }
+        }
+    }
+
+    /// Hex public key that peers use to find and authenticate us.
+    pub fn id(&self) -> String {
+        hex::encode(self.verifying_key().as_bytes())
+    }
+
+    pub(crate) fn verifying_key(&self) -> VerifyingKey {
+        match &self.key {
+            Key::Seed(key) => key.verifying_key(),
+            Key::Session { public, .. } => *public,
+        }
+    }
+
+    pub(crate) fn sign(&self, bytes: &[u8]) -> Signature {
+        match &self.key {
+            Key::Seed(key) => key.sign(bytes),
+            Key::Session { secret, public } => hazmat::raw_sign::<Sha512>(secret, bytes, public),
+        }
+    }
+
+    /// Nonce for the next frame.
+    pub(crate) fn next_nonce(&self) -> u64 {
+        self.next_nonce.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Our X25519 key agreement with `point`, the secret matching the
+    /// Montgomery form of our public key, so that peers can encrypt to our
+    /// ID (see the `sealed_box` module). Session scalars are not clamped,
+    /// so the cofactor is cleared from `point` instead: all zeros comes out
+    /// for a low-order point, as with clamping.
+    pub(crate) fn x25519_agree(&self, point: &MontgomeryPoint) -> MontgomeryPoint {
+        let scalar = match &self.key {
+            Key::Seed(key) => key.to_scalar(),
+            Key::Session { secret, .. } => secret.scalar,
+        };
+        let cofactor = Scalar::from(8u8);
+        (point * cofactor) * (scalar * cofactor.invert())
+    }
+}
+
+fn session_key(secret: ExpandedSecretKey) -> Key {
+    Key::Session {
+        public: VerifyingKey::from(&secret),
+        secret: Arc::new(secret),
+    }
+}
+
+/// What [`Identity::for_session`] multiplies the key by:
+/// `SHA512("dhtmsg session" || public key || nonce)`, reduced.
+fn blinding_factor(key: &VerifyingKey, nonce: &[u8]) -> Scalar {
+    Scalar::from_bytes_mod_order_wide(&sha512(&[SESSION_CONTEXT, key.as_bytes(), nonce]))
+}
+
+fn sha512(parts: &[&[u8]]) -> [u8; 64] {
+    use sha2::Digest as _;
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// The ID that the node with hex ID `id_hex` has in the session of
+/// `nonce` (see [`Identity::for_session`]).
+pub fn derive_session_id(id_hex: &str, nonce: &[u8]) -> Result<String> {
+    let key = public_key(id_hex)?;
+    let blinded = VerifyingKey::from(blinding_factor(&key, nonce) * key.to_edwards());
+    Ok(hex::encode(blinded.as_bytes()))
+}
+
+/// A fresh random nonce for [`Identity::for_session`], to share with the
+/// peer out of band.
+pub fn session_nonce() -> [u8; SESSION_NONCE_BYTES] {
+    let mut nonce = [0u8; SESSION_NONCE_BYTES];
+    thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Shows the ID only, keeping the secret key out of logs.
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Identity").field(&self.id()).finish()
+    }
+}
+
+/// `path` opened for writing, created readable by its owner only. With
+/// `exclusive`, fails if the file exists; otherwise truncates it.
+fn owner_only_file(path: &Path, exclusive: bool) -> io::Result<fs::File> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if exclusive {
+        options.create_new(true);
+    } else {
+        options.create(true).truncate(true);
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// Public key of a hex ID.
+pub(crate) fn public_key(id_hex: &str) -> Result<VerifyingKey> {
+    let raw_id = hex::decode(id_hex).map_err(|source| DhtMsgError::InvalidId {
+        id: id_hex.to_string(),
+        source,
+    })?;
+    raw_id
+        .as_slice()
+        .try_into()
+        .ok()
+        .and_then(|raw_id| VerifyingKey::from_bytes(raw_id).ok())
+        .ok_or_else(|| DhtMsgError::NotPublicKey {
+            id: id_hex.to_string(),
+        })
+}
+
+/// Derive the rendezvous infohash for a hex ID: SHA-1 of the public key bytes.
+pub fn derive_infohash(id_hex: &str) -> Result<Id> {
+    derive_salted_infohash(id_hex, &[])
+}
+
+/// Derive the rendezvous infohash for a hex ID under a shared `salt`: SHA-1
+/// of the public key bytes followed by the salt. Without the salt, knowing
+/// the ID is not enough to find or watch the rendezvous.
+pub fn derive_salted_infohash(id_hex: &str, salt: &[u8]) -> Result<Id> {
+    Ok(infohash(&public_key(id_hex)?, &[salt]))
+}
+
+/// Derive the infohash for a hex ID during time window number `window`
+/// (the Unix time divided by the rotation period): SHA-1 of the public key
+/// bytes, the salt and the window as a big-endian `u64`.
+pub fn derive_rotating_infohash(id_hex: &str, salt: &[u8], window: u64) -> Result<Id> {
+    Ok(infohash(
+        &public_key(id_hex)?,
+        &[salt, &window.to_be_bytes()],
+    ))
+}
+
+/// Derive the rendezvous infohash that two IDs share: SHA-1 of both public
+/// keys in ascending byte order, the salt and, if infohashes rotate, the
+/// window as a big-endian `u64`. Both sides derive the same one, so each
+/// announces and looks up a single infohash instead of two.
+pub fn derive_pairwise_infohash(
+    id_a: &str,
+    id_b: &str,
+    salt: &[u8],
+    window: Option<u64>,
+) -> Result<Id> {
+    let (a, b) = (public_key(id_a)?, public_key(id_b)?);
+    let (low, high) = if a.as_bytes() <= b.as_bytes() {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    let window = window.map(u64::to_be_bytes);
+    let window: &[u8] = window.as_ref().map_or(&[], |window| window);
+    Ok(infohash(&low, &[high.as_bytes(), salt, window]))
+}
+
+/// Derive replica number `replica` of `infohash`, another place to announce
+/// and look up in case the DHT nodes closest to the first are dead or
+/// hostile: SHA-1 of the infohash followed by the replica number as a byte.
+/// Replica 0 is `infohash` itself, so nodes with replicas still meet nodes
+/// without.
+pub fn derive_replica_infohash(infohash: Id, replica: u8) -> Id {
+    if replica == 0 {
+        return infohash;
+    }
+    let mut hasher = Sha1::new();
+    hasher.update(infohash.as_bytes());
+    hasher.update([replica]);
+    let digest = hasher.finalize();
+    Id::from_bytes(digest.as_slice()).expect("SHA-1 digest is 20 bytes")
+}
+
+/// Derive the infohash that relays announce (see
+/// [`DhtMsgBuilder::relay`](crate::DhtMsgBuilder::relay)): SHA-1 of
+/// `"dhtmsg relay"` followed by the salt, so that networks with their own
+/// salt keep their relays apart.
+pub fn derive_relay_infohash(salt: &[u8]) -> Id {
+    let mut hasher = Sha1::new();
+    hasher.update(RELAY_CONTEXT);
+    hasher.update(salt);
+    let digest = hasher.finalize();
+    Id::from_bytes(digest.as_slice()).expect("SHA-1 digest is 20 bytes")
+}
+
+fn infohash(key: &VerifyingKey, suffix: &[&[u8]]) -> Id {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    for part in suffix {
+        hasher.update(part);
+    }
+    let digest = hasher.finalize();
+    Id::from_bytes(digest.as_slice()).expect("SHA-1 digest is 20 bytes")
+}
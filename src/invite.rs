@@ -0,0 +1,251 @@
+//! `dhtmsg://` invites: all a peer needs to find a node, in one string to
+//! paste or put in a QR code.
+//!
+//! ```text
+//! dhtmsg://<id>?salt=<salt>&rotate=<seconds>&session=<nonce>&expires=<unix time>
+//! ```
+//!
+//! Only the ID is required. It is the node's Ed25519 public key, so it also
+//! authenticates the node; there is no separate key to carry. The salt is
+//! percent-encoded, the session nonce is hex, and the other parameters are
+//! decimal. Unknown parameters are ignored so that new ones can be added
+//! later.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use crate::{
+    error::{DhtMsgError, Result},
+    id::public_key,
+    wire,
+};
+
+const SCHEME: &str = "dhtmsg://";
+
+/// A parsed or newly made invite. Displays as its URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    /// ID of the inviting node, in lowercase hex.
+    pub id: String,
+    /// Salt of its infohashes (see
+    /// [`DhtMsgBuilder::infohash_salt`](crate::DhtMsgBuilder::infohash_salt)).
+    pub salt: Option<Vec<u8>>,
+    /// Rotation period of its infohashes (see
+    /// [`DhtMsgBuilder::rotate_infohash`](crate::DhtMsgBuilder::rotate_infohash)).
+    pub rotate: Option<Duration>,
+    /// Nonce of the session identities both sides use (see
+    /// [`Identity::for_session`](crate::Identity::for_session)). The ID is
+    /// the long-term one.
+    pub session: Option<Vec<u8>>,
+    /// Unix time after which the invite should no longer be used.
+    pub expires: Option<u64>,
+}
+
+impl Invite {
+    /// An invite to the node with ID `id`, without salt, rotation, session
+    /// or expiry.
+    pub fn new(id: &str) -> Result<Self> {
+        Ok(Self {
+            id: hex::encode(public_key(id)?.as_bytes()),
+            salt: None,
+            rotate: None,
+            session: None,
+            expires: None,
+        })
+    }
+
+    /// The invite, expiring `valid_for` from now.
+    pub fn expiring_in(mut self, valid_for: Duration) -> Self {
+        self.expires = Some(wire::unix_secs() + valid_for.as_secs());
+        self
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| wire::unix_secs() > expires)
+    }
+}
+
+impl fmt::Display for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{SCHEME}{}", self.id)?;
+        let mut separator = '?';
+        let mut param = |f: &mut fmt::Formatter<'_>, name: &str, value: &str| {
+            let result = write!(f, "{separator}{name}={value}");
+            separator = '&';
+            result
+        };
+        if let Some(salt) = &self.salt {
+            param(f, "salt", &percent_encode(salt))?;
+        }
+        if let Some(rotate) = self.rotate {
+            param(f, "rotate", &rotate.as_secs().to_string())?;
+        }
+        if let Some(session) = &self.session {
+            param(f, "session", &hex::encode(session))?;
+        }
+        if let Some(expires) = self.expires {
+            param(f, "expires", &expires.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Invite {
+    type Err = DhtMsgError;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        let invalid = |reason| DhtMsgError::InvalidInvite { reason };
+        let rest = uri
+            .trim()
+            .strip_prefix(SCHEME)
+            .ok_or(invalid("not a dhtmsg:// URI"))?;
+        let (id, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut invite = Self::new(id.trim_end_matches('/'))?;
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            match name {
+                "salt" => {
+                    let salt = percent_decode(value).ok_or(invalid("malformed salt"))?;
+                    invite.salt = Some(salt);
+                }
+                "rotate" => {
+                    let secs: u64 = value.parse().map_err(|_| invalid("malformed rotate"))?;
+                    invite.rotate = Some(Duration::from_secs(secs)).filter(|_| secs > 0);
+                }
+                "session" => {
+                    let session = hex::decode(value).map_err(|_| invalid("malformed session"))?;
+                    invite.session = Some(session);
+                }
+                "expires" => {
+                    let expires = value.parse().map_err(|_| invalid("malformed expires"))?;
+                    invite.expires = Some(expires);
+                }
+                _ => {}
+            }
+        }
+        Ok(invite)
+    }
+}
+
+/// `bytes` with everything but the unreserved characters of RFC 3986 as
+/// `%XX`.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+fn percent_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let (digits, tail) = rest.split_first_chunk::<2>()?;
+        let mut decoded = [0u8];
+        hex::decode_to_slice(digits, &mut decoded).ok()?;
+        bytes.push(decoded[0]);
+        rest = tail;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Identity;
+
+    fn id() -> String {
+        Identity::from_secret_hex(&"07".repeat(32)).unwrap().id()
+    }
+
+    #[test]
+    fn invites_round_trip() {
+        let bare = Invite::new(&id()).unwrap();
+        assert_eq!(bare.to_string(), format!("dhtmsg://{}", id()));
+        assert_eq!(bare.to_string().parse::<Invite>().unwrap(), bare);
+
+        let full = Invite {
+            salt: Some(b"pepper & salt/100%".to_vec()),
+            rotate: Some(Duration::from_secs(3600)),
+            session: Some(vec![0x4c, 0x8b, 0x40, 0xa1]),
+            expires: Some(1_700_000_000),
+            ..bare
+        };
+        let uri = full.to_string();
+        assert_eq!(
+            uri,
+            format!(
+                "dhtmsg://{}?salt=pepper%20%26%20salt%2F100%25&rotate=3600\
+                 &session=4c8b40a1&expires=1700000000",
+                id()
+            )
+        );
+        assert_eq!(uri.parse::<Invite>().unwrap(), full);
+        // Every byte survives the salt's encoding.
+        let salt = Invite {
+            salt: Some((0..=255).collect()),
+            ..Invite::new(&id()).unwrap()
+        };
+        assert_eq!(salt.to_string().parse::<Invite>().unwrap(), salt);
+    }
+
+    #[test]
+    fn lenient_parsing() {
+        let uri = format!(
+            "  dhtmsg://{}/?future=1&rotate=0&&salt=a%2fb ",
+            id().to_uppercase()
+        );
+        let invite: Invite = uri.parse().unwrap();
+        assert_eq!(invite.id, id());
+        assert_eq!(invite.salt.as_deref(), Some(&b"a/b"[..]));
+        assert_eq!(invite.rotate, None);
+        assert!(!invite.is_expired());
+        let expired: Invite = format!("dhtmsg://{}?expires=1", id()).parse().unwrap();
+        assert!(expired.is_expired());
+    }
+
+    #[test]
+    fn malformed_invites() {
+        let id = id();
+        for uri in [
+            format!("https://{id}"),
+            format!("dhtmsg:{id}"),
+            id.clone(),
+            "dhtmsg://".to_string(),
+            "dhtmsg://?salt=x".to_string(),
+            format!("dhtmsg://{}", &id[..62]),
+            format!("dhtmsg://{id}?salt=%"),
+            format!("dhtmsg://{id}?salt=%4"),
+            format!("dhtmsg://{id}?salt=%zz"),
+            format!("dhtmsg://{id}?rotate=soon"),
+            format!("dhtmsg://{id}?session=xyz"),
+            format!("dhtmsg://{id}?expires=-1"),
+        ] {
+            assert!(uri.parse::<Invite>().is_err(), "{uri}");
+        }
+    }
+
+    #[test]
+    fn percent_escapes() {
+        assert_eq!(percent_encode(b"a-Z_0.~"), "a-Z_0.~");
+        assert_eq!(percent_encode(&[0, b' ', 0xff]), "%00%20%FF");
+        assert_eq!(
+            percent_decode("%00%20%ff%FFx").unwrap(),
+            [0, b' ', 0xff, 0xff, b'x']
+        );
+        assert_eq!(percent_decode("").unwrap(), b"");
+        for bad in ["%", "%1", "x%", "%g0", "%+1"] {
+            assert_eq!(percent_decode(bad), None, "{bad}");
+        }
+    }
+}
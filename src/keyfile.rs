@@ -0,0 +1,207 @@
+//! Passphrase protection of stored secret keys.
+//!
+//! A protected identity file holds one line,
+//! `argon2id$m=<KiB>,t=<passes>,p=<lanes>$<salt>$<nonce>$<ciphertext>` with
+//! hex fields: the secret key sealed with ChaCha20-Poly1305 under the
+//! Argon2id hash of the passphrase. Everything before the nonce is
+//! authenticated along with the key, so the cost parameters cannot be
+//! lowered unnoticed.
+
+use rand::{RngCore, thread_rng};
+
+use crate::{
+    aead::{self, KEY_BYTES, NONCE_BYTES},
+    argon2::{self, Params},
+};
+
+const PREFIX: &str = "argon2id$";
+const SALT_BYTES: usize = 16;
+/// Cost of newly protected files, the minimum OWASP recommends: about a
+/// tenth of a second and 19 MiB.
+const PARAMS: Params = Params {
+    memory_kib: 19 * 1024,
+    passes: 2,
+    lanes: 1,
+};
+/// Limits on the parameters of files read, so that a crafted file cannot
+/// make us allocate or compute without end.
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_PASSES: u32 = 64;
+const MAX_LANES: u32 = 16;
+
+/// Why a protected secret could not be opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OpenError {
+    Malformed,
+    /// The passphrase is wrong, or the file was altered.
+    WrongPassphrase,
+}
+
+pub(crate) fn is_protected(contents: &str) -> bool {
+    contents.trim_start().starts_with(PREFIX)
+}
+
+/// The file contents protecting `secret` with `passphrase`.
+pub(crate) fn protect(secret: &[u8], passphrase: &str) -> String {
+    protect_with(secret, passphrase, PARAMS)
+}
+
+fn protect_with(secret: &[u8], passphrase: &str, params: Params) -> String {
+    let mut salt = [0u8; SALT_BYTES];
+    thread_rng().fill_bytes(&mut salt);
+    let mut nonce = [0u8; NONCE_BYTES];
+    thread_rng().fill_bytes(&mut nonce);
+    let Params {
+        memory_kib,
+        passes,
+        lanes,
+    } = params;
+    let header = format!(
+        "{PREFIX}m={memory_kib},t={passes},p={lanes}${}",
+        hex::encode(salt)
+    );
+    let key = derive_key(passphrase, &salt, params);
+    let sealed = aead::seal(&key, &nonce, header.as_bytes(), secret);
+    format!("{header}${}${}", hex::encode(nonce), hex::encode(sealed))
+}
+
+/// The secret protected in `contents`.
+pub(crate) fn open(contents: &str, passphrase: &str) -> Result<Vec<u8>, OpenError> {
+    let mut fields = contents.trim().rsplitn(3, '$');
+    let (Some(sealed), Some(nonce), Some(header)) = (fields.next(), fields.next(), fields.next())
+    else {
+        return Err(OpenError::Malformed);
+    };
+    let (params, salt) = header
+        .strip_prefix(PREFIX)
+        .and_then(|header| header.split_once('$'))
+        .ok_or(OpenError::Malformed)?;
+    let params = parse_params(params).ok_or(OpenError::Malformed)?;
+    let salt = hex::decode(salt).map_err(|_| OpenError::Malformed)?;
+    let mut nonce_bytes = [0u8; NONCE_BYTES];
+    hex::decode_to_slice(nonce, &mut nonce_bytes).map_err(|_| OpenError::Malformed)?;
+    let sealed = hex::decode(sealed).map_err(|_| OpenError::Malformed)?;
+
+    let key = derive_key(passphrase, &salt, params);
+    aead::open(&key, &nonce_bytes, header.as_bytes(), &sealed).ok_or(OpenError::WrongPassphrase)
+}
+
+fn parse_params(params: &str) -> Option<Params> {
+    let mut values = params.split(',').map(|param| param.split_once('='));
+    let mut value = |name: &str| match values.next()? {
+        Some((key, value)) if key == name => value.parse::<u32>().ok(),
+        _ => None,
+    };
+    let params = Params {
+        memory_kib: value("m")?,
+        passes: value("t")?,
+        lanes: value("p")?,
+    };
+    let valid = (1..=MAX_LANES).contains(&params.lanes)
+        && (1..=MAX_PASSES).contains(&params.passes)
+        && (8 * params.lanes..=MAX_MEMORY_KIB).contains(&params.memory_kib);
+    valid.then_some(params)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: Params) -> [u8; KEY_BYTES] {
+    argon2::hash(passphrase.as_bytes(), salt, params, KEY_BYTES)
+        .try_into()
+        .expect("asked for a key's worth of bytes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let secret = [7u8; 32];
+        let contents = protect(&secret, "correct horse");
+        assert!(is_protected(&contents));
+        assert!(!is_protected(&hex::encode(secret)));
+        assert_eq!(open(&contents, "correct horse").unwrap(), secret);
+        assert_eq!(
+            open(&contents, "wrong horse"),
+            Err(OpenError::WrongPassphrase)
+        );
+    }
+
+    const CHEAP: Params = Params {
+        memory_kib: 64,
+        passes: 1,
+        lanes: 2,
+    };
+
+    /// `contents` with its `index`th `$`-separated field changed by `edit`.
+    fn edit_field(contents: &str, index: usize, edit: impl FnOnce(&mut String)) -> String {
+        let mut fields = Vec::from_iter(contents.split('$').map(str::to_string));
+        edit(&mut fields[index]);
+        fields.join("$")
+    }
+
+    /// Flip the first hex digit of a field.
+    fn flip(field: &mut String) {
+        let first = if field.starts_with('0') { "1" } else { "0" };
+        field.replace_range(..1, first);
+    }
+
+    #[test]
+    fn edited_files_do_not_open() {
+        let contents = protect_with(&[7; 32], "pass", CHEAP);
+        assert!(contents.starts_with("argon2id$m=64,t=1,p=2$"));
+        assert_eq!(open(&contents, "pass").unwrap(), [7; 32]);
+        for params in ["m=72,t=1,p=2", "m=64,t=2,p=2", "m=64,t=1,p=1"] {
+            let edited = edit_field(&contents, 1, |field| *field = params.to_string());
+            assert_eq!(
+                open(&edited, "pass"),
+                Err(OpenError::WrongPassphrase),
+                "{params}"
+            );
+        }
+        // Salt, nonce and ciphertext.
+        for index in [2, 3, 4] {
+            let edited = edit_field(&contents, index, flip);
+            assert_eq!(open(&edited, "pass"), Err(OpenError::WrongPassphrase));
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_files() {
+        let contents = protect_with(&[7; 32], "pass", CHEAP);
+        let bad_params = [
+            // Out of range.
+            "m=0,t=1,p=2",
+            "m=8,t=1,p=2",
+            "m=1048577,t=1,p=2",
+            "m=64,t=0,p=2",
+            "m=64,t=65,p=2",
+            "m=64,t=1,p=0",
+            "m=64,t=1,p=17",
+            // Garbled.
+            "m=64,t=1",
+            "t=1,m=64,p=2",
+            "m=-1,t=1,p=2",
+            "m=64;t=1;p=2",
+        ];
+        for params in bad_params {
+            let edited = edit_field(&contents, 1, |field| *field = params.to_string());
+            assert_eq!(open(&edited, "pass"), Err(OpenError::Malformed), "{params}");
+        }
+        let short_nonce = edit_field(&contents, 3, |nonce| nonce.truncate(nonce.len() - 2));
+        assert_eq!(open(&short_nonce, "pass"), Err(OpenError::Malformed));
+        let odd_salt = edit_field(&contents, 2, |salt| salt.push('0'));
+        assert_eq!(open(&odd_salt, "pass"), Err(OpenError::Malformed));
+        for contents in [
+            "",
+            "argon2id$",
+            "argon2id$m=64,t=1,p=2$00$00",
+            "scrypt$a$b$c$d",
+        ] {
+            assert_eq!(
+                open(contents, "pass"),
+                Err(OpenError::Malformed),
+                "{contents:?}"
+            );
+        }
+    }
+}
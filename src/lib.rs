@@ -0,0 +1,108 @@
+//! Direct UDP messaging between peers that find each other through the
+//! BitTorrent mainline DHT.
+//!
+//! Each side's ID is an Ed25519 public key. It derives an infohash from its
+//! ID, announces it, and looks up the peer's infohash to learn candidate
+//! addresses for a UDP hello; everything sent is signed with the secret key
+//! and, after a Noise handshake with the peer, encrypted.
+//!
+//! ```no_run
+//! let identity = dhtmsg::Identity::from_secret_hex(&std::env::var("DHTMSG_SECRET").unwrap())?;
+//! let node = dhtmsg::DhtMsg::builder().identity(identity).build()?;
+//! node.announce()?;
+//! for addr in node.find_peer(&std::env::var("DHTMSG_PEER").unwrap())? {
+//!     node.send_hello(addr)?;
+//! }
+//! # Ok::<(), dhtmsg::DhtMsgError>(())
+//! ```
+
+mod address;
+mod aead;
+mod argon2;
+#[cfg(feature = "async")]
+pub mod asynch;
+mod birthday;
+mod bittorrent;
+mod blake2b;
+mod bootstrap;
+mod capabilities;
+#[cfg(feature = "cbor")]
+mod cbor;
+mod challenge;
+mod channel;
+mod compress;
+mod control;
+mod dedup;
+mod delegation;
+mod endpoint;
+mod error;
+mod event;
+mod extension;
+pub mod ffi;
+pub mod file;
+mod fragment;
+mod hmac;
+mod id;
+mod invite;
+mod keyfile;
+mod mailbox;
+mod md5;
+mod natpmp;
+mod negotiate;
+mod node;
+mod noise;
+mod onion;
+mod outbox;
+mod pair;
+mod portmap;
+mod psk;
+mod punch;
+mod relay;
+mod replay;
+mod sas;
+mod schedule;
+#[cfg(feature = "cbor")]
+pub mod schema;
+mod sealed_box;
+mod session;
+mod shutdown;
+mod socks;
+mod stats;
+mod stream;
+mod stun;
+mod tcp;
+mod transport;
+mod tun;
+pub mod tunnel;
+mod turn;
+mod upnp;
+mod utp;
+mod wire;
+
+pub use bootstrap::{BootstrapNode, BootstrapNodeBuilder, DEFAULT_BOOTSTRAP_PORT};
+pub use capabilities::Capabilities;
+pub use delegation::Delegation;
+pub use endpoint::EndpointRecord;
+pub use error::{DhtMsgError, Result};
+pub use event::Event;
+pub use extension::APPLICATION_KINDS;
+pub use flume;
+pub use id::{
+    Identity, derive_infohash, derive_pairwise_infohash, derive_relay_infohash,
+    derive_replica_infohash, derive_rotating_infohash, derive_salted_infohash, derive_session_id,
+    session_nonce,
+};
+pub use invite::Invite;
+pub use mailbox::MAX_MAILBOX_BYTES;
+pub use mainline::Id;
+pub use negotiate::TransportKind;
+pub use node::{DhtMsg, DhtMsgBuilder, MAX_MESSAGE_BYTES, MAX_PACKET_BYTES};
+pub use pair::{Paired, pairing_code};
+pub use sas::Sas;
+pub use shutdown::ShutdownHandle;
+pub use stats::DhtStats;
+pub use stream::PeerStream;
+pub use stun::NatMapping;
+pub use transport::Transport;
+pub use tun::TunDevice;
+pub use utp::UtpStream;
@@ -0,0 +1,98 @@
+//! Mailboxes: short messages left in the DHT for a peer that is offline.
+//!
+//! A message is sealed to the recipient's ID (see the `sealed_box` module)
+//! and put as a BEP 44 immutable item, which the DHT stores under the hash
+//! of its content. The sender then lists the hashes of what it left in a
+//! mutable item of its own, an index, under the salt
+//! `SHA1("dhtmsg mailbox" || recipient key || day)`, the day being the Unix
+//! time divided by 86400. The recipient knows the IDs it expects mail from
+//! and its own key, so it can derive where each sender's index is without
+//! being told; the index's signature says who left what it lists. The value
+//! of an index is `d1:m<20 bytes per message>e`.
+//!
+//! Delivery is best effort: DHT nodes drop items after a few hours unless
+//! they are put again, and the recipient only looks at today's and
+//! yesterday's indexes.
+
+use ed25519_dalek::VerifyingKey;
+use mainline::{Id, MutableItem};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::{
+    Identity,
+    endpoint::{signable, signed_by},
+    sealed_box, wire,
+};
+
+const SALT_CONTEXT: &[u8] = b"dhtmsg mailbox";
+const DAY_SECS: u64 = 86_400;
+/// Messages one index lists; the oldest are dropped first.
+pub(crate) const MAX_MAILBOX_MESSAGES: usize = 32;
+/// Largest payload left in a mailbox: the sealed box has to fit in the
+/// 1000 bytes of a BEP 44 value.
+pub const MAX_MAILBOX_BYTES: usize = 1000 - sealed_box::OVERHEAD;
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    #[serde(with = "serde_bytes")]
+    m: Vec<u8>,
+}
+
+/// The number of the current day, under which messages are left.
+pub(crate) fn today() -> u64 {
+    wire::unix_secs() / DAY_SECS
+}
+
+/// Yesterday and today, the days whose indexes the recipient looks at.
+pub(crate) fn days() -> [u64; 2] {
+    let today = today();
+    [today.saturating_sub(1), today]
+}
+
+/// The BEP 44 salt of indexes for `recipient` on `day`.
+pub(crate) fn salt(recipient: &VerifyingKey, day: u64) -> [u8; 20] {
+    let mut hasher = <Sha1 as sha1::Digest>::new();
+    sha1::Digest::update(&mut hasher, SALT_CONTEXT);
+    sha1::Digest::update(&mut hasher, recipient.as_bytes());
+    sha1::Digest::update(&mut hasher, day.to_be_bytes());
+    sha1::Digest::finalize(hasher).into()
+}
+
+/// An index of `messages`, signed by `identity`, newer than `previous`.
+pub(crate) fn index(
+    identity: &Identity,
+    messages: &[Id],
+    salt: &[u8],
+    previous: Option<i64>,
+) -> MutableItem {
+    let index = Index {
+        m: messages.iter().flat_map(|id| *id.as_bytes()).collect(),
+    };
+    let value = serde_bencode::to_bytes(&index).expect("indexes always encode");
+    // Two messages within a second still need increasing numbers.
+    let seq = (wire::unix_secs() as i64).max(previous.map_or(0, |seq| seq + 1));
+    let signature = identity.sign(&signable(seq, &value, salt));
+    MutableItem::new_signed_unchecked(
+        identity.verifying_key().to_bytes(),
+        signature.to_bytes(),
+        &value,
+        seq,
+        Some(salt),
+    )
+}
+
+/// The messages `item` lists if `sender` signed it under `salt`.
+pub(crate) fn messages(item: &MutableItem, sender: &VerifyingKey, salt: &[u8]) -> Vec<Id> {
+    if !signed_by(item, sender, salt) {
+        return Vec::new();
+    }
+    let Ok(index) = serde_bencode::from_bytes::<Index>(item.value()) else {
+        return Vec::new();
+    };
+    index
+        .m
+        .chunks_exact(20)
+        .filter_map(|id| Id::from_bytes(id).ok())
+        .collect()
+}
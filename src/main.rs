@@ -1,16 +1,21 @@
 use std::{
-    collections::HashSet,
-    net::{SocketAddrV4, UdpSocket},
+    io::{self, BufRead, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex, OnceLock},
     thread,
     time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
-use clap::Parser;
-use log::{error, info, warn};
-use mainline::Id;
-use rand::{RngCore, thread_rng};
-use sha1::{Digest, Sha1};
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand, ValueEnum};
+use dhtmsg::{
+    BootstrapNode, Capabilities, Delegation, DhtMsg, DhtMsgError, Event, Identity, Invite,
+    PeerStream, TransportKind, TunDevice, UtpStream, derive_session_id,
+};
+use log::{info, warn};
+use simplelog::LevelFilter;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -18,235 +23,2730 @@ use sha1::{Digest, Sha1};
     about = "Tiny UDP hello over BitTorrent DHT peer discovery"
 )]
 struct Args {
-    /// Local identifier hex string (random if omitted)
+    #[command(subcommand)]
+    command: Command,
+
+    /// Print log lines for people, or JSON lines for programs: one object
+    /// per event (peer-found, hello-sent, hello-received, ack, ...) and per
+    /// warning or error
+    #[arg(long, value_enum, global = true, default_value_t = Output::Human)]
+    output: Output,
+}
+
+/// Which identity to act as.
+#[derive(clap::Args, Debug, Default)]
+struct IdentityArgs {
+    /// Secret key hex string from `dhtmsg keygen` (stored identity if
+    /// omitted)
+    #[arg(long)]
+    secret: Option<String>,
+
+    /// Identity file to use, created on first run [default:
+    /// ~/.config/dhtmsg/identity]
+    #[arg(long, conflicts_with = "secret")]
+    identity: Option<PathBuf>,
+
+    /// Use a random identity for this run only
+    #[arg(long, conflicts_with_all = ["secret", "identity"])]
+    ephemeral: bool,
+}
+
+#[derive(clap::Args, Debug, Default)]
+struct SessionArgs {
+    /// Act as the identity blinded by this hex nonce from `dhtmsg
+    /// session-nonce`, and expect peers to do the same, so that DHT observers
+    /// cannot link sessions under different nonces; peer IDs are still given
+    /// as the usual ones
+    #[arg(long)]
+    session_nonce: Option<String>,
+}
+
+/// How our infohashes are derived, which peers must know to find us.
+#[derive(clap::Args, Debug, Default)]
+struct InfohashArgs {
+    #[command(flatten)]
+    session: SessionArgs,
+
+    /// Secret mixed into the infohashes we announce and look up, so that only
+    /// peers sharing it can find us [default: contents of
+    /// ~/.config/dhtmsg/salt, if it exists]
+    #[arg(long)]
+    salt: Option<String>,
+
+    /// Derive new infohashes every this many seconds, so the node is not
+    /// found under one infohash forever; peers must use the same period
+    #[arg(long)]
+    rotate_infohash_secs: Option<u64>,
+}
+
+/// Which DHT to join, and how to remember it.
+#[derive(clap::Args, Debug, Default)]
+struct DhtArgs {
+    /// DHT node to bootstrap from instead of the public ones (host:port);
+    /// repeatable [default: the lines of ~/.config/dhtmsg/bootstrap, if it
+    /// exists]
+    #[arg(long = "bootstrap", value_name = "HOST:PORT")]
+    bootstrap: Vec<String>,
+
+    /// Join only the DHT of the bootstrap nodes, never the public one
+    #[arg(long)]
+    private_dht: bool,
+
+    /// File keeping the DHT nodes we know, to bootstrap from on the next
+    /// start [default: ~/.config/dhtmsg/dht_nodes; none with --ephemeral or
+    /// --private-dht]
     #[arg(long)]
-    id: Option<String>,
+    routing_cache: Option<PathBuf>,
+}
+
+/// Options of the node that the commands talking to peers start.
+#[derive(clap::Args, Debug, Default)]
+struct NodeArgs {
+    #[command(flatten)]
+    identity: IdentityArgs,
+
+    #[command(flatten)]
+    infohash: InfohashArgs,
 
-    /// Target peer identifier hex string to contact (derives infohash)
+    #[command(flatten)]
+    dht: DhtArgs,
+
+    /// Only answer the peer with this ID; repeat to allow several
+    #[arg(long = "allow", value_name = "ID")]
+    allowed: Vec<String>,
+
+    /// File of peer IDs to allow, one per line [default:
+    /// ~/.config/dhtmsg/authorized_peers, if it exists]
     #[arg(long)]
-    peer: Option<String>,
+    authorized_peers: Option<PathBuf>,
 
-    /// Re-announce interval in seconds
+    /// Re-announce interval in seconds, shorter for the first rounds and
+    /// longer after failed ones
     #[arg(long, default_value_t = 45)]
     announce_secs: u64,
+
+    /// Queue messages in this directory and deliver them once the peer
+    /// responds, including ones left over from earlier runs
+    #[arg(long)]
+    outbox: Option<PathBuf>,
+
+    /// Do not offer or use LZ4 compression for large messages and files
+    #[arg(long)]
+    no_compress: bool,
+
+    /// Also talk unencrypted to peers that do not start a Noise handshake
+    /// (legacy behavior; encrypted sessions are still used when offered)
+    #[arg(long)]
+    plaintext: bool,
+
+    /// Instead of Noise sessions, encrypt each message to the peer's ID with
+    /// a one-time X25519 key (hellos then go unencrypted)
+    #[arg(long)]
+    sealed_box: bool,
+
+    /// Authenticate every frame with this pre-shared secret and drop frames
+    /// from anyone who does not know it
+    #[arg(long)]
+    psk: Option<String>,
+
+    /// Announce and look up this many infohashes per infohash (up to 8), so
+    /// that dead or hostile DHT nodes around one cannot hide the node;
+    /// peers should use the same count
+    #[arg(long, default_value_t = 1)]
+    infohash_replicas: u8,
+
+    /// Publish our address as a record signed by our ID, and find peers by
+    /// theirs, instead of trusting unauthenticated DHT announcements; peers
+    /// must enable it too
+    #[arg(long)]
+    signed_endpoints: bool,
+
+    /// Also publish a record signed by our ID with our addresses, relay
+    /// hints and protocol versions, and fetch the peer's during lookups
+    #[arg(long)]
+    signaling: bool,
+
+    /// Run the DHT as a full node that answers other nodes' queries from
+    /// the start [default: a client until others turn out to reach it]
+    #[arg(long)]
+    dht_server: bool,
+
+    /// Our public IPv4 address, to derive the DHT node ID from as BEP 42
+    /// asks [default: the address port discovery learns]
+    #[arg(long)]
+    public_ip: Option<Ipv4Addr>,
+
+    /// Bind the hello socket, the TCP listener and burst sockets to this
+    /// address only, e.g. a VPN's [default: every address]
+    #[arg(long, value_name = "IP")]
+    bind_addr: Option<IpAddr>,
+
+    /// Keep every socket, the DHT's included, on this network interface
+    /// (Linux only)
+    #[arg(long, value_name = "NAME")]
+    interface: Option<String>,
+
+    /// Also try private addresses (192.168.x.x, 127.x.x.x and the like)
+    /// found in lookups, as on a test DHT on loopback; those of peers behind
+    /// our own public address are tried anyway
+    #[arg(long, conflicts_with = "lan_only")]
+    allow_private: bool,
+
+    /// Only try private addresses and publish no public ones beyond what
+    /// the DHT requires, for peers that must meet on the local network
+    #[arg(long)]
+    lan_only: bool,
+
+    /// Do not ask the router to forward the hello port over UPnP, NAT-PMP
+    /// or PCP
+    #[arg(long)]
+    no_port_mapping: bool,
+
+    /// STUN server to learn the hello port's public address from
+    /// (host:port) instead of the default ones; repeatable
+    #[arg(long = "stun-server", value_name = "HOST:PORT")]
+    stun_servers: Vec<String>,
+
+    /// Learn the public port from a short-lived DHT only, without STUN
+    #[arg(long, conflicts_with = "stun_servers")]
+    no_stun: bool,
+
+    /// Punch holes from one socket even behind a NAT that gives each
+    /// destination another port, rather than predict its ports and spray
+    #[arg(long)]
+    no_port_prediction: bool,
+
+    /// Do not listen on the hello port over TCP, nor connect to peers over
+    /// TCP when UDP gets nothing through
+    #[arg(long)]
+    no_tcp_fallback: bool,
+
+    /// Offer a kind of path (udp, tcp, turn, relay or tor) to peers at this cost
+    /// rather than the default, lowest preferred, as KIND=COST; repeatable
+    #[arg(long = "transport-cost", value_name = "KIND=COST")]
+    transport_costs: Vec<String>,
+
+    /// Give up if the DHT has found no node after this many seconds
+    #[arg(long, default_value_t = 30)]
+    bootstrap_timeout_secs: u64,
+
+    /// Relay to suggest in our record (usually host:port); repeatable
+    #[arg(long = "relay-hint", value_name = "HINT")]
+    relay_hints: Vec<String>,
+
+    /// Serve as a relay for peers that cannot reach each other directly,
+    /// announced under the well-known relay infohash
+    #[arg(long)]
+    relay: bool,
+
+    /// Register with a relay found in the DHT and name it in our record, so
+    /// that peers can come through it when nothing direct gets through
+    #[arg(long)]
+    use_relay: bool,
+
+    /// TURN server to allocate a relayed address on and publish it as a
+    /// candidate (host:port)
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        requires_all = ["turn_username", "turn_password"]
+    )]
+    turn_server: Option<String>,
+
+    /// Username of our account on the TURN server
+    #[arg(long, value_name = "NAME", requires = "turn_server")]
+    turn_username: Option<String>,
+
+    /// Password of our account on the TURN server
+    #[arg(long, value_name = "PASSWORD", requires = "turn_server")]
+    turn_password: Option<String>,
+
+    /// Send hello traffic, and TCP fallback connections, through this SOCKS5
+    /// proxy (socks5://[user:password@]host:port); the DHT still goes direct
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// Run an onion service through the local Tor daemon, tell peers its
+    /// address in records sealed to them, and reach peers through Tor when
+    /// nothing else gets through
+    #[arg(long)]
+    onion: bool,
+
+    /// Reach peers through Tor only, so that they never learn our IP
+    /// address; implies --onion and announces nothing else
+    #[arg(long)]
+    onion_only: bool,
+
+    /// Tor's control port (default 127.0.0.1:9051)
+    #[arg(long, value_name = "HOST:PORT")]
+    tor_control: Option<String>,
+
+    /// Tor's SOCKS port (default 127.0.0.1:9050)
+    #[arg(long, value_name = "HOST:PORT")]
+    tor_socks: Option<String>,
+
+    /// Meet the peer at one infohash derived from both IDs instead of each
+    /// announcing its own; the peer must pass it too
+    #[arg(long)]
+    pairwise: bool,
+
+    /// Drop frames whose timestamp is further than this many seconds from
+    /// our clock
+    #[arg(long, default_value_t = 300)]
+    replay_window_secs: u64,
+
+    /// Move encrypted sessions on to new keys every this many seconds, and
+    /// forget the old ones
+    #[arg(long, default_value_t = 120)]
+    rekey_secs: u64,
+
+    /// Ping established peers after this many seconds of silence, keeping
+    /// the NAT mappings between us open; 0 turns the pings off
+    #[arg(long, default_value_t = 20)]
+    keepalive_secs: u64,
+
+    /// Refuse to send, and drop on receipt, messages larger than this
+    #[arg(long, default_value_t = dhtmsg::MAX_MESSAGE_BYTES)]
+    max_message_bytes: usize,
 }
 
-fn main() -> Result<()> {
-    init_logging();
-    let args = Args::parse();
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Output {
+    Human,
+    Json,
+}
 
-    let local_id = args.id.clone().unwrap_or_else(random_hex_id);
-    let local_infohash = derive_infohash(&local_id)?;
-    info!("local ID: {local_id}");
-    info!("derived infohash: {}", local_infohash);
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Announce and wait for peers to say hello, logging what they send;
+    /// Ctrl+C stops
+    Listen {
+        /// After the first hello/ack, send stdin lines to that peer and
+        /// write what it sends to stdout (logs go to stderr)
+        #[arg(long)]
+        pipe: bool,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Look a peer up, say hello and stay connected, logging what it sends;
+    /// Ctrl+C stops
+    Connect {
+        /// Peer ID (public key hex string), or a dhtmsg:// invite as printed
+        /// by `dhtmsg invite`, whose salt and rotation period find the peer
+        peer: String,
+        /// Once connected, send stdin lines to the peer and write what it
+        /// sends to stdout (logs go to stderr)
+        #[arg(long)]
+        pipe: bool,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Deliver a message to a peer, resending it until the peer
+    /// acknowledges it
+    Send {
+        /// Peer ID (public key hex string) to send to
+        #[arg(long)]
+        peer: String,
+        /// The message
+        #[arg(required_unless_present = "file", conflicts_with = "file")]
+        message: Option<String>,
+        /// Deliver the contents of this file instead
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// Resend interval in seconds
+        #[arg(long, default_value_t = 3)]
+        resend_secs: u64,
+        /// Give up on the message if it is not acknowledged within this many
+        /// seconds (it is also dropped from the --outbox then)
+        #[arg(long)]
+        ttl_secs: Option<u64>,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Say hello to a peer and time how long it takes to answer pings
+    Ping {
+        /// Peer ID (public key hex string) to ping
+        #[arg(long)]
+        peer: String,
+        /// Pings to send, one a second
+        #[arg(long, default_value_t = 4)]
+        count: u32,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Print the ID of the identity in use, the session ID with
+    /// --session-nonce
+    Id {
+        #[command(flatten)]
+        identity: IdentityArgs,
+        #[command(flatten)]
+        session: SessionArgs,
+    },
+    /// Create an identity and print its secret key and ID
+    Keygen,
+    /// Print a random nonce for --session-nonce, to share with the peer
+    SessionNonce,
+    /// Protect the stored identity with a new passphrase, or remove the
+    /// protection with an empty one; creates the identity if there is none
+    SetPassphrase {
+        #[command(flatten)]
+        identity: IdentityArgs,
+    },
+    /// Replace the stored identity by a new one, keeping the old one as
+    /// `<identity>.old`, and sign a move to it that contacts looking up the
+    /// old ID follow
+    RotateKey {
+        #[command(flatten)]
+        identity: IdentityArgs,
+    },
+    /// Line-oriented chat with a peer; `/quit` exits
+    Chat {
+        /// Peer ID (public key hex string) to chat with, or a dhtmsg://
+        /// invite
+        #[arg(long)]
+        peer: String,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Send a file to a peer running `receive-file`
+    SendFile {
+        /// File to send
+        path: PathBuf,
+        /// Peer ID (public key hex string) to send to
+        #[arg(long)]
+        peer: String,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Pair with a peer through a short code instead of exchanging IDs; the
+    /// peer's ID is printed and added to the authorized peers file if there
+    /// is one
+    Pair {
+        /// Code from the other side; a new one to pass on if omitted
+        code: Option<String>,
+        /// Give up after this many seconds
+        #[arg(long, default_value_t = 300)]
+        timeout_secs: u64,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Print a dhtmsg:// invite: our ID, with the salt and rotation period
+    /// in use, for a peer to pass to `connect` or `chat`
+    Invite {
+        /// Make the invite expire after this many seconds
+        #[arg(long)]
+        valid_secs: Option<u64>,
+        #[command(flatten)]
+        identity: IdentityArgs,
+        #[command(flatten)]
+        infohash: InfohashArgs,
+    },
+    /// Leave a short message in the mailbox of a peer that is offline; it
+    /// finds it in the DHT the next time it starts within a day or so
+    Mailbox {
+        /// Peer ID (public key hex string) to leave the message for
+        #[arg(long)]
+        peer: String,
+        /// The message
+        message: String,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Look the peer up and print the candidate addresses the DHT returns,
+    /// without saying hello, to check whether it announces at all
+    Scrape {
+        /// Peer ID (public key hex string) to look up
+        #[arg(long)]
+        peer: String,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Run a standalone DHT node for others to bootstrap from, such as the
+    /// first nodes of a --private-dht network; it keeps its routing table
+    /// in ~/.config/dhtmsg/bootstrap_node_cache unless --routing-cache says
+    /// otherwise or --ephemeral nowhere
+    BootstrapNode {
+        /// UDP port to listen on
+        #[arg(long, default_value_t = dhtmsg::DEFAULT_BOOTSTRAP_PORT)]
+        port: u16,
+        /// Keep the routing table nowhere, unless --routing-cache says where
+        #[arg(long)]
+        ephemeral: bool,
+        #[command(flatten)]
+        dht: DhtArgs,
+    },
+    /// Receive one file from a peer running `send-file`
+    ReceiveFile {
+        /// Peer ID (public key hex string) to receive from
+        #[arg(long)]
+        peer: String,
+        /// Directory to write the file to
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Accept TCP connections on a local port and tunnel each to a port on
+    /// the peer's machine, which must run `expose` for it
+    Forward {
+        /// Peer ID (public key hex string) to forward to
+        #[arg(long)]
+        peer: String,
+        /// Local port to listen on, on 127.0.0.1; without it a single
+        /// connection is carried over stdin and stdout, as an SSH
+        /// ProxyCommand
+        #[arg(long)]
+        local: Option<u16>,
+        /// Port on the peer's machine to connect to
+        #[arg(long)]
+        remote: u16,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Let a peer running `forward` tunnel connections to local ports, or
+    /// one running `socks` or `http-proxy` anywhere with --exit
+    Expose {
+        /// Local port the peer may connect to; repeat for several
+        #[arg(long = "port", required_unless_present = "exit")]
+        ports: Vec<u16>,
+        /// Let the peer connect to any host and port this machine reaches,
+        /// its own included, so that its connections exit from our network
+        #[arg(long)]
+        exit: bool,
+        /// Peer ID (public key hex string) allowed to connect
+        #[arg(long)]
+        peer: String,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Run a local SOCKS5 proxy whose connections are tunneled to the peer
+    /// and made from its network; it must run `expose --exit`
+    Socks {
+        /// Peer ID (public key hex string) to tunnel through
+        #[arg(long)]
+        peer: String,
+        /// Address to accept SOCKS5 clients on
+        #[arg(long, default_value = "127.0.0.1:1080")]
+        listen: SocketAddr,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Run a local HTTP proxy that tunnels CONNECT requests, as browsers and
+    /// curl make for HTTPS, to the peer, which makes the connections; it
+    /// must run `expose --exit`
+    HttpProxy {
+        /// Peer ID (public key hex string) to tunnel through
+        #[arg(long)]
+        peer: String,
+        /// Address to accept proxy clients on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Join the peer, which runs `bridge` too, to stdin and stdout, a
+    /// command or a TCP connection, as socat does: what comes out of one
+    /// side's end goes into the other's, both ways, until both are done
+    Bridge {
+        /// Peer ID (public key hex string) at the other end
+        #[arg(long)]
+        peer: String,
+        /// Run this shell command and join its stdin and stdout instead
+        #[arg(long, conflicts_with = "connect_tcp")]
+        exec: Option<String>,
+        /// Connect to HOST:PORT and join that connection instead
+        #[arg(long, value_name = "HOST:PORT")]
+        connect_tcp: Option<String>,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+    /// Open a TUN interface and carry its IP packets to and from the peer,
+    /// which runs `vpn` too, as a point-to-point VPN (Linux, needs
+    /// CAP_NET_ADMIN)
+    Vpn {
+        /// Peer ID (public key hex string) at the other end
+        #[arg(long)]
+        peer: String,
+        /// Our address on the VPN and its network, e.g. 10.77.0.1/24
+        #[arg(long)]
+        address: String,
+        /// Name of the interface; a %d in it is replaced by a free number
+        #[arg(long, default_value = "dhtmsg%d")]
+        interface_name: String,
+        #[command(flatten)]
+        options: NodeArgs,
+    },
+}
 
-    // Learn a public port for the app by briefly starting a DHT on a chosen local port.
-    let port_info = discover_public_port()?;
-    info!(
-        "discovered local hello port {} with public {:?}",
-        port_info.local_port, port_info.public_port
-    );
+/// The identity given on the command line or stored on disk; `None` for a
+/// random one.
+fn load_identity(args: &IdentityArgs) -> Result<Option<Identity>> {
+    if let Some(secret) = &args.secret {
+        return Ok(Some(Identity::from_secret_hex(secret)?));
+    }
+    if args.ephemeral {
+        return Ok(None);
+    }
+    let Some(path) = identity_path(args) else {
+        warn!("no config directory found; using a random identity");
+        return Ok(None);
+    };
+    let identity = load_stored_identity(&path)?;
+    info!("identity {} from {}", identity.id(), path.display());
+    Ok(Some(identity))
+}
 
-    let socket = UdpSocket::bind(("0.0.0.0", port_info.local_port))
-        .with_context(|| format!("failed to bind UDP socket on {}", port_info.local_port))?;
-    socket
-        .set_nonblocking(true)
-        .context("failed to set socket to non-blocking")?;
-    let hello_port = socket
-        .local_addr()
-        .context("failed to read bound port")?
-        .port();
-    info!("hello socket bound on UDP port {hello_port}");
-
-    // Bind the long-lived DHT to an ephemeral port (avoid default 6881).
-    let dht = mainline::Dht::builder()
-        .port(0)
-        .build()
-        .context("failed to start DHT node")?;
-    info!("DHT socket listening on {}", dht.info().local_addr());
-
-    info!("bootstrapping the DHT...");
-    thread::sleep(Duration::from_secs(2));
-    info!("bootstrapped: {}", dht.bootstrapped());
-
-    let announced_port = port_info.public_port.unwrap_or(hello_port);
-    announce(&dht, local_infohash, announced_port);
-
-    let recv_socket = socket.try_clone().context("failed to clone UDP socket")?;
-    let recv_id = local_id.clone();
-    thread::spawn(move || recv_loop(recv_socket, recv_id));
-
-    if let Some(peer_id) = args.peer.as_deref() {
-        let peer_infohash = derive_infohash(peer_id)?;
-        info!("peer ID: {peer_id}");
-        info!("peer infohash: {}", peer_infohash);
-        lookup_and_hello(
-            dht,
-            socket,
-            local_id,
-            local_infohash,
-            peer_infohash,
-            args.announce_secs,
-            announced_port,
-        );
+fn identity_path(args: &IdentityArgs) -> Option<PathBuf> {
+    args.identity.clone().or_else(Identity::default_path)
+}
+
+/// Variable holding the passphrase of the stored identity. If set, new
+/// identities are protected by it too.
+const PASSPHRASE_VAR: &str = "DHTMSG_PASSPHRASE";
+/// Prompts for the passphrase before giving up.
+const PASSPHRASE_ATTEMPTS: usize = 3;
+
+/// The identity at `path`, asking for its passphrase if it has one and
+/// `DHTMSG_PASSPHRASE` is not set.
+fn load_stored_identity(path: &Path) -> Result<Identity> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_VAR) {
+        return Ok(Identity::load_or_generate_with_passphrase(
+            path,
+            &passphrase,
+        )?);
+    }
+    let mut result = Identity::load_or_generate(path);
+    for _ in 0..PASSPHRASE_ATTEMPTS {
+        match result {
+            Err(DhtMsgError::PassphraseRequired { .. } | DhtMsgError::WrongPassphrase { .. }) => {
+                let prompt = format!("passphrase for {}: ", path.display());
+                let passphrase = read_passphrase(&prompt)?;
+                result = Identity::load_or_generate_with_passphrase(path, &passphrase);
+            }
+            result => return Ok(result?),
+        }
+    }
+    Ok(result?)
+}
+
+/// `dhtmsg set-passphrase`: store the identity again under a new passphrase.
+fn set_passphrase(args: &IdentityArgs) -> Result<()> {
+    let Some(path) = identity_path(args) else {
+        bail!("no config directory found; pass --identity");
+    };
+    let identity = load_stored_identity(&path)?;
+    let passphrase = read_new_passphrase()?;
+    identity.save(&path, passphrase.as_deref())?;
+    if passphrase.is_some() {
+        eprintln!("{} is now protected by the passphrase", path.display());
     } else {
-        info!("no peer provided; announcing and waiting for inbound hello. Ctrl+C to quit.");
-        idle_announce_loop(dht, local_infohash, args.announce_secs, announced_port);
+        eprintln!("{} is no longer protected", path.display());
+    }
+    Ok(())
+}
+
+/// A new passphrase typed twice, `None` if empty.
+fn read_new_passphrase() -> Result<Option<String>> {
+    let passphrase = read_passphrase("new passphrase (empty for none): ")?;
+    if read_passphrase("repeat it: ")? != passphrase {
+        bail!("the passphrases differ");
+    }
+    Ok(Some(passphrase).filter(|passphrase| !passphrase.is_empty()))
+}
+
+/// `path` with `suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Where the delegations of the stored identity are kept, one per line:
+/// `<identity>.delegations`. `None` for identities not stored.
+fn delegations_path(args: &IdentityArgs) -> Option<PathBuf> {
+    if args.secret.is_some() || args.ephemeral {
+        return None;
     }
+    Some(with_suffix(&identity_path(args)?, ".delegations"))
+}
 
+/// Delegations to keep in the DHT, from the delegations file if it exists.
+fn stored_delegations(args: &IdentityArgs) -> Result<Vec<Delegation>> {
+    let Some(path) = delegations_path(args) else {
+        return Ok(Vec::new());
+    };
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", path.display())),
+    };
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            line.parse()
+                .with_context(|| format!("bad delegation in {}", path.display()))
+        })
+        .collect()
+}
+
+/// `dhtmsg rotate-key`: move the stored identity to a new key.
+fn rotate_key(args: &IdentityArgs) -> Result<()> {
+    let (Some(path), Some(delegations)) = (identity_path(args), delegations_path(args)) else {
+        bail!("no stored identity to rotate; pass --identity");
+    };
+    let old = load_stored_identity(&path)?;
+    let new = Identity::generate();
+    let delegation = Delegation::new(&old, &new);
+    let passphrase = read_new_passphrase()?;
+    let backup = with_suffix(&path, ".old");
+    std::fs::copy(&path, &backup)
+        .with_context(|| format!("failed to copy {} to {}", path.display(), backup.display()))?;
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&delegations)
+        .with_context(|| format!("failed to open {}", delegations.display()))?;
+    writeln!(file, "{delegation}")
+        .with_context(|| format!("failed to write {}", delegations.display()))?;
+    new.save(&path, passphrase.as_deref())?;
+    println!("moved {} to {}", old.id(), new.id());
+    println!("old identity kept in {}", backup.display());
     Ok(())
 }
 
-fn init_logging() {
-    use simplelog::{ColorChoice, ConfigBuilder, LevelFilter, TermLogger, TerminalMode};
+/// A line read from the terminal with echo off, or from stdin if there is
+/// no terminal.
+fn read_passphrase(prompt: &str) -> Result<String> {
+    eprint!("{prompt}");
+    io::stderr().flush()?;
+    let mut line = String::new();
+    let read = match std::fs::File::open("/dev/tty") {
+        Ok(tty) => {
+            let _echo_off = EchoOff::new(&tty);
+            let read = io::BufReader::new(&tty).read_line(&mut line)?;
+            eprintln!();
+            read
+        }
+        Err(_) => io::stdin().lock().read_line(&mut line)?,
+    };
+    if read == 0 {
+        bail!("no passphrase given");
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
 
-    let config = ConfigBuilder::new()
-        .set_time_level(LevelFilter::Off)
-        .set_level_padding(simplelog::LevelPadding::Right)
-        .build();
+/// Turns off echo on a terminal while alive.
+struct EchoOff {
+    #[cfg(unix)]
+    restore: Option<(std::os::fd::RawFd, libc::termios)>,
+}
 
-    let _ = TermLogger::init(
-        LevelFilter::Info,
-        config,
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    );
+impl EchoOff {
+    #[cfg(unix)]
+    fn new(tty: &std::fs::File) -> Self {
+        use std::os::fd::AsRawFd;
+        let fd = tty.as_raw_fd();
+        // SAFETY: `fd` is open for the lifetime of `tty`, and `termios` is
+        // plain data that tcgetattr fills in.
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(fd, &mut termios) != 0 {
+                return Self { restore: None };
+            }
+            let mut quiet = termios;
+            quiet.c_lflag &= !libc::ECHO;
+            if libc::tcsetattr(fd, libc::TCSANOW, &quiet) != 0 {
+                return Self { restore: None };
+            }
+            Self {
+                restore: Some((fd, termios)),
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn new(_tty: &std::fs::File) -> Self {
+        Self {}
+    }
+}
+
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some((fd, termios)) = self.restore {
+            // SAFETY: as in `new`; the terminal outlives the guard.
+            unsafe {
+                libc::tcsetattr(fd, libc::TCSANOW, &termios);
+            }
+        }
+    }
 }
 
-fn random_hex_id() -> String {
-    let mut bytes = [0u8; 16];
-    thread_rng().fill_bytes(&mut bytes);
-    hex::encode(bytes)
+/// IDs from `--allow` and the authorized peers file. Lines of the file hold
+/// an ID each, optionally followed by a comment; `#` starts a comment line.
+fn authorized_peers(args: &NodeArgs) -> Result<Vec<String>> {
+    let mut peers = args.allowed.clone();
+    let Some(path) = authorized_peers_path(args) else {
+        return Ok(peers);
+    };
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    peers.extend(
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_whitespace().next())
+            .map(str::to_string),
+    );
+    info!("{} authorized peers from {}", peers.len(), path.display());
+    if let Some(nonce) = session_nonce(&args.infohash.session)? {
+        peers = peers
+            .iter()
+            .map(|peer| derive_session_id(peer, &nonce))
+            .collect::<Result<_, _>>()?;
+    }
+    Ok(peers)
 }
 
-fn derive_infohash(id_hex: &str) -> Result<Id> {
-    let raw_id = hex::decode(id_hex).with_context(|| format!("invalid hex ID string: {id_hex}"))?;
-    let mut hasher = Sha1::new();
-    hasher.update(&raw_id);
-    let digest = hasher.finalize();
-    Id::from_bytes(digest.as_slice()).context("failed to convert digest into infohash")
+/// `--session-nonce`, decoded.
+fn session_nonce(args: &SessionArgs) -> Result<Option<Vec<u8>>> {
+    args.session_nonce
+        .as_deref()
+        .map(|nonce| hex::decode(nonce.trim()).context("--session-nonce must be hex"))
+        .transpose()
 }
 
-#[derive(Debug, Clone, Copy)]
-struct PortInfo {
-    local_port: u16,
-    public_port: Option<u16>,
+/// Replace the subcommand's peer by its ID in the session of `nonce`.
+fn blind_peers(command: &mut Command, nonce: &[u8]) -> Result<()> {
+    if let Some(peer) = command_peer_mut(command) {
+        *peer = derive_session_id(peer, nonce)?;
+    }
+    Ok(())
 }
 
-fn discover_public_port() -> Result<PortInfo> {
-    // Let DHT bind a port (0 = OS picks). We reuse that local port for the app.
-    let temp = mainline::Dht::builder().port(0).build()?;
-    let mut public_port: Option<u16> = None;
-    let mut attempts: u32 = 0;
-    while public_port.is_none() {
-        let info = temp.info();
-        public_port = info.public_address().map(|a| a.port());
-        if public_port.is_some() {
-            break;
+/// Replace the subcommand's peer by the ID it has moved to, if it has.
+fn resolve_peers(command: &mut Command, node: &DhtMsg) -> Result<()> {
+    if let Some(peer) = command_peer_mut(command) {
+        let resolved = node.resolve_peer(peer)?;
+        if resolved != peer.to_ascii_lowercase() {
+            warn!("{peer} has moved to {resolved}");
+            *peer = resolved;
         }
-        attempts += 1;
-        if attempts % 20 == 0 {
-            info!("waiting for public port discovery ({} checks)...", attempts);
+    }
+    Ok(())
+}
+
+/// `--salt`, or the contents of the default salt file if it exists.
+fn infohash_salt(args: &InfohashArgs) -> Result<Option<String>> {
+    if let Some(salt) = &args.salt {
+        return Ok(Some(salt.clone()));
+    }
+    let Some(path) = Identity::default_path().map(|path| path.with_file_name("salt")) else {
+        return Ok(None);
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(salt) => {
+            info!("infohash salt from {}", path.display());
+            Ok(Some(salt.trim_end_matches(['\r', '\n']).to_string()))
         }
-        thread::sleep(Duration::from_millis(500));
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
     }
-    let local_port = temp.info().local_addr().port();
-    drop(temp);
-    thread::sleep(Duration::from_millis(200));
-    Ok(PortInfo {
-        local_port,
-        public_port,
-    })
 }
 
-fn announce(dht: &mainline::Dht, infohash: Id, port: u16) {
-    // Advertise the hello socket port; NAT may still rewrite, but many keep the mapping.
-    match dht.announce_peer(infohash, Some(port)) {
-        Ok(_) => info!("announced infohash {} on port {port}", infohash),
-        Err(err) => warn!("announce failed: {err}"),
+/// `--bootstrap`, or the nodes listed in the default bootstrap file if it
+/// exists: one `host:port` per line, `#` starting a comment line.
+fn bootstrap_nodes(args: &DhtArgs) -> Result<Vec<String>> {
+    if !args.bootstrap.is_empty() {
+        return Ok(args.bootstrap.clone());
+    }
+    let Some(path) = Identity::default_path().map(|path| path.with_file_name("bootstrap")) else {
+        return Ok(Vec::new());
+    };
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read {}", path.display()));
+        }
+    };
+    let nodes = Vec::from_iter(
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string),
+    );
+    info!("{} bootstrap nodes from {}", nodes.len(), path.display());
+    Ok(nodes)
+}
+
+/// `--routing-cache`, or the default file unless the node is to leave no
+/// trace. That file holds nodes of the public DHT, which a private one must
+/// not bootstrap from.
+fn routing_cache_path(args: &NodeArgs) -> Option<PathBuf> {
+    if let Some(path) = &args.dht.routing_cache {
+        return Some(path.clone());
+    }
+    if args.identity.ephemeral || args.dht.private_dht {
+        return None;
     }
+    Some(Identity::default_path()?.with_file_name("dht_nodes"))
 }
 
-fn recv_loop(socket: UdpSocket, local_id: String) {
-    let mut buf = [0u8; 1500];
+/// How often `bootstrap-node` saves its routing table and logs its stats.
+const BOOTSTRAP_NODE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `dhtmsg bootstrap-node`: serve the DHT until killed.
+fn run_bootstrap_node(args: &DhtArgs, ephemeral: bool, port: u16) -> Result<()> {
+    let mut builder = BootstrapNode::builder()
+        .port(port)
+        .private_dht(args.private_dht);
+    for node in bootstrap_nodes(args)? {
+        builder = builder.bootstrap(node);
+    }
+    let cache = match &args.routing_cache {
+        Some(path) => Some(path.clone()),
+        None if ephemeral => None,
+        None => Identity::default_path().map(|path| path.with_file_name("bootstrap_node_cache")),
+    };
+    if let Some(path) = cache {
+        builder = builder.routing_cache(path);
+    }
+    let node = builder.build()?;
     loop {
-        match socket.recv_from(&mut buf) {
-            Ok((len, peer)) => match std::str::from_utf8(&buf[..len]) {
-                Ok(msg) if msg.starts_with("hello") || msg.contains("hello") => {
-                    info!("received hello from {peer}: {msg}");
-                    let ack = format!("hello-ack from {local_id}");
-                    if let Err(err) = socket.send_to(ack.as_bytes(), peer) {
-                        warn!("failed to send ack to {peer}: {err}");
+        thread::sleep(BOOTSTRAP_NODE_SAVE_INTERVAL);
+        if let Err(err) = node.save_routing_cache() {
+            warn!("{err}");
+        }
+        info!("DHT: {}", node.stats());
+    }
+}
+
+/// The peer the command talks to.
+fn command_peer(command: &Command) -> Option<&str> {
+    match command {
+        Command::Connect { peer, .. }
+        | Command::Send { peer, .. }
+        | Command::Ping { peer, .. }
+        | Command::Chat { peer, .. }
+        | Command::SendFile { peer, .. }
+        | Command::ReceiveFile { peer, .. }
+        | Command::Forward { peer, .. }
+        | Command::Expose { peer, .. }
+        | Command::Socks { peer, .. }
+        | Command::HttpProxy { peer, .. }
+        | Command::Vpn { peer, .. }
+        | Command::Bridge { peer, .. }
+        | Command::Mailbox { peer, .. }
+        | Command::Scrape { peer, .. } => Some(peer),
+        Command::Listen { .. }
+        | Command::Id { .. }
+        | Command::Keygen
+        | Command::SessionNonce
+        | Command::SetPassphrase { .. }
+        | Command::RotateKey { .. }
+        | Command::Pair { .. }
+        | Command::Invite { .. }
+        | Command::BootstrapNode { .. } => None,
+    }
+}
+
+/// [`command_peer`], to replace.
+fn command_peer_mut(command: &mut Command) -> Option<&mut String> {
+    match command {
+        Command::Connect { peer, .. }
+        | Command::Send { peer, .. }
+        | Command::Ping { peer, .. }
+        | Command::Chat { peer, .. }
+        | Command::SendFile { peer, .. }
+        | Command::ReceiveFile { peer, .. }
+        | Command::Forward { peer, .. }
+        | Command::Expose { peer, .. }
+        | Command::Socks { peer, .. }
+        | Command::HttpProxy { peer, .. }
+        | Command::Vpn { peer, .. }
+        | Command::Bridge { peer, .. }
+        | Command::Mailbox { peer, .. }
+        | Command::Scrape { peer, .. } => Some(peer),
+        Command::Listen { .. }
+        | Command::Id { .. }
+        | Command::Keygen
+        | Command::SessionNonce
+        | Command::SetPassphrase { .. }
+        | Command::RotateKey { .. }
+        | Command::Pair { .. }
+        | Command::Invite { .. }
+        | Command::BootstrapNode { .. } => None,
+    }
+}
+
+/// The options of the node the command starts, if it starts one.
+fn command_options(command: &mut Command) -> Option<&mut NodeArgs> {
+    match command {
+        Command::Listen { options, .. }
+        | Command::Connect { options, .. }
+        | Command::Send { options, .. }
+        | Command::Ping { options, .. }
+        | Command::Chat { options, .. }
+        | Command::SendFile { options, .. }
+        | Command::Pair { options, .. }
+        | Command::Mailbox { options, .. }
+        | Command::Scrape { options, .. }
+        | Command::ReceiveFile { options, .. }
+        | Command::Forward { options, .. }
+        | Command::Expose { options, .. }
+        | Command::Socks { options, .. }
+        | Command::HttpProxy { options, .. }
+        | Command::Bridge { options, .. }
+        | Command::Vpn { options, .. } => Some(options),
+        Command::Id { .. }
+        | Command::Keygen
+        | Command::SessionNonce
+        | Command::SetPassphrase { .. }
+        | Command::RotateKey { .. }
+        | Command::Invite { .. }
+        | Command::BootstrapNode { .. } => None,
+    }
+}
+
+/// `dhtmsg id`: print the ID peers know us by.
+fn print_id(args: &IdentityArgs, session: &SessionArgs) -> Result<()> {
+    let Some(mut identity) = load_identity(args)? else {
+        bail!("a random identity has no lasting ID; pass --secret or --identity");
+    };
+    if let Some(nonce) = session_nonce(session)? {
+        identity = identity.for_session(&nonce);
+    }
+    println!("{}", identity.id());
+    Ok(())
+}
+
+/// What invites start with, where a peer ID may be given.
+const INVITE_SCHEME: &str = "dhtmsg://";
+
+/// `dhtmsg invite`: print what a peer needs to find us.
+fn print_invite(
+    args: &IdentityArgs,
+    infohash: &InfohashArgs,
+    valid_for: Option<Duration>,
+) -> Result<()> {
+    let Some(identity) = load_identity(args)? else {
+        bail!("a random identity cannot be invited to; pass --secret or --identity");
+    };
+    let mut invite = Invite::new(&identity.id())?;
+    invite.salt = infohash_salt(infohash)?.map(String::into_bytes);
+    invite.rotate = infohash
+        .rotate_infohash_secs
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs);
+    invite.session = session_nonce(&infohash.session)?;
+    if let Some(valid_for) = valid_for {
+        invite = invite.expiring_in(valid_for);
+    }
+    println!("{invite}");
+    Ok(())
+}
+
+/// `--authorized-peers`, or the default file if it exists.
+fn authorized_peers_path(args: &NodeArgs) -> Option<PathBuf> {
+    if let Some(path) = &args.authorized_peers {
+        return Some(path.clone());
+    }
+    let path = Identity::default_path()?.with_file_name("authorized_peers");
+    path.exists().then_some(path)
+}
+
+/// `dhtmsg pair`: find the peer with the same code and learn its ID.
+fn pair(args: &NodeArgs, node: &DhtMsg, code: Option<&str>, timeout: Duration) -> Result<()> {
+    let code = match code {
+        Some(code) => code.to_string(),
+        None => {
+            let code = dhtmsg::pairing_code();
+            println!("pairing code: {code}");
+            println!("on the other machine, run: dhtmsg pair {code}");
+            code
+        }
+    };
+    let paired = node.pair(&code, timeout)?;
+    println!("paired with {}", paired.peer_id);
+    println!("shared key: {}", hex::encode(paired.key));
+    if let Some(path) = authorized_peers_path(args) {
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let today = time::OffsetDateTime::now_local()
+            .unwrap_or_else(|_| time::OffsetDateTime::now_utc())
+            .date();
+        writeln!(file, "{} paired {today}", paired.peer_id)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        println!("added to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Check the mailboxes that `senders` may have left messages in, in the
+/// background; what is found arrives as `MailboxMessage` events.
+fn spawn_mailbox_check(node: &DhtMsg, mut senders: Vec<String>) {
+    senders.sort();
+    senders.dedup();
+    if senders.is_empty() {
+        return;
+    }
+    let node = node.clone();
+    std::thread::spawn(move || {
+        for sender in &senders {
+            match node.check_mailbox(sender) {
+                Ok(0) => {}
+                Ok(found) => info!("{found} mailbox messages from {sender}"),
+                Err(err) => warn!("checking the mailbox of {sender} failed: {err:#}"),
+            }
+        }
+    });
+}
+
+/// How often the peer's infohash is looked up until the peer is reached.
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(5);
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let output = args.output;
+    match run(args) {
+        Err(err) if output == Output::Json => {
+            print_json(&[
+                ("event", Json::Str("error")),
+                ("message", Json::Str(&format!("{err:#}"))),
+            ]);
+            std::process::exit(1);
+        }
+        result => result,
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    let Args {
+        mut command,
+        output,
+    } = args;
+    match &command {
+        Command::Id { identity, session } => {
+            init_logging(output, LevelFilter::Warn, true);
+            return print_id(identity, session);
+        }
+        Command::Keygen => {
+            let identity = Identity::generate();
+            println!("secret: {}", identity.secret_hex());
+            println!("id: {}", identity.id());
+            return Ok(());
+        }
+        Command::SessionNonce => {
+            println!("{}", hex::encode(dhtmsg::session_nonce()));
+            return Ok(());
+        }
+        Command::SetPassphrase { identity } => {
+            init_logging(output, LevelFilter::Warn, true);
+            return set_passphrase(identity);
+        }
+        Command::RotateKey { identity } => {
+            init_logging(output, LevelFilter::Warn, true);
+            return rotate_key(identity);
+        }
+        Command::Invite {
+            valid_secs,
+            identity,
+            infohash,
+        } => {
+            init_logging(output, LevelFilter::Warn, true);
+            return print_invite(identity, infohash, valid_secs.map(Duration::from_secs));
+        }
+        Command::BootstrapNode {
+            port,
+            ephemeral,
+            dht,
+        } => {
+            init_logging(output, LevelFilter::Info, false);
+            return run_bootstrap_node(dht, *ephemeral, *port);
+        }
+        // Stdout carries the piped lines.
+        Command::Listen { pipe, .. } | Command::Connect { pipe, .. } => {
+            init_logging(output, LevelFilter::Info, *pipe)
+        }
+        Command::Send { .. } => init_logging(output, LevelFilter::Info, false),
+        // Keep the REPL readable: only problems are logged, on stderr.
+        Command::Chat { .. } => init_logging(output, LevelFilter::Warn, true),
+        Command::Pair { .. } => init_logging(output, LevelFilter::Info, true),
+        // Stdout carries the tunneled bytes.
+        Command::Forward { local: None, .. }
+        | Command::Bridge {
+            exec: None,
+            connect_tcp: None,
+            ..
+        } => init_logging(output, LevelFilter::Warn, true),
+        Command::SendFile { .. }
+        | Command::ReceiveFile { .. }
+        | Command::Forward { .. }
+        | Command::Expose { .. }
+        | Command::Socks { .. }
+        | Command::HttpProxy { .. }
+        | Command::Vpn { .. }
+        | Command::Bridge { .. } => init_logging(output, LevelFilter::Info, false),
+        // Their results are JSON lines too, on stdout with the rest.
+        Command::Ping { .. } | Command::Mailbox { .. } | Command::Scrape { .. } => {
+            init_logging(output, LevelFilter::Warn, output == Output::Human)
+        }
+    }
+    let Some(options) = command_options(&mut command) else {
+        unreachable!("commands without a node have returned");
+    };
+    let mut options = std::mem::take(options);
+    let message = match &command {
+        Command::Send {
+            message: Some(text),
+            ..
+        } => text.clone().into_bytes(),
+        Command::Send {
+            file: Some(path), ..
+        } => std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?,
+        _ => Vec::new(),
+    };
+
+    // An invite stands in for the peer's ID, and says how the peer derives
+    // its infohashes.
+    let invite = match &mut command {
+        Command::Connect { peer, .. } | Command::Chat { peer, .. }
+            if peer.starts_with(INVITE_SCHEME) =>
+        {
+            let invite: Invite = peer.parse()?;
+            if invite.is_expired() {
+                bail!("the invite has expired");
+            }
+            peer.clone_from(&invite.id);
+            Some(invite)
+        }
+        _ => None,
+    };
+    if let Some(session) = invite.as_ref().and_then(|invite| invite.session.as_ref()) {
+        options.infohash.session.session_nonce = Some(hex::encode(session));
+    }
+    let session = session_nonce(&options.infohash.session)?;
+    if let Some(nonce) = &session {
+        // Pairing would store the peer's session ID as if it were its own.
+        if let Command::Pair { .. } = command {
+            bail!("pairing exchanges long-term IDs; drop --session-nonce");
+        }
+        blind_peers(&mut command, nonce)?;
+    }
+
+    let mut builder = DhtMsg::builder();
+    if let Some(mut identity) = load_identity(&options.identity)? {
+        if let Some(nonce) = &session {
+            identity = identity.for_session(nonce);
+            info!("session identity {}", identity.id());
+        }
+        builder = builder.identity(identity);
+    }
+    if let Some(dir) = &options.outbox {
+        builder = builder.outbox_dir(dir);
+    }
+    // A pairing peer is new, so it cannot be on the list yet.
+    if !matches!(command, Command::Pair { .. }) {
+        for peer_id in authorized_peers(&options)? {
+            builder = builder.allow_peer(peer_id);
+        }
+    }
+    if let Some(psk) = &options.psk {
+        builder = builder.psk(psk.as_bytes());
+    }
+    // The invite says how the peer derives its infohashes.
+    let (salt, rotation) = match &invite {
+        Some(invite) => (invite.salt.clone(), invite.rotate),
+        None => (
+            infohash_salt(&options.infohash)?.map(String::into_bytes),
+            options
+                .infohash
+                .rotate_infohash_secs
+                .map(Duration::from_secs),
+        ),
+    };
+    if let Some(salt) = salt {
+        builder = builder.infohash_salt(salt);
+    }
+    builder = builder.infohash_replicas(options.infohash_replicas);
+    if options.pairwise {
+        let Some(peer) = command_peer(&command) else {
+            bail!("--pairwise needs the peer's ID");
+        };
+        builder = builder.pairwise_rendezvous(peer);
+    }
+    if let Some(period) = rotation {
+        builder = builder.rotate_infohash(period);
+    }
+    let bootstrap = bootstrap_nodes(&options.dht)?;
+    if options.dht.private_dht && bootstrap.is_empty() {
+        bail!("--private-dht needs --bootstrap or ~/.config/dhtmsg/bootstrap");
+    }
+    for node in bootstrap {
+        builder = builder.bootstrap(node);
+    }
+    builder = builder.private_dht(options.dht.private_dht);
+    if let Some(path) = routing_cache_path(&options) {
+        builder = builder.routing_cache(path);
+    }
+    builder = builder
+        .bootstrap_timeout(Duration::from_secs(options.bootstrap_timeout_secs))
+        .dht_server_mode(options.dht_server)
+        .allow_private(options.allow_private)
+        .lan_only(options.lan_only)
+        .port_mapping(!options.no_port_mapping)
+        .stun(!options.no_stun)
+        .port_prediction(!options.no_port_prediction)
+        .tcp_fallback(!options.no_tcp_fallback)
+        .onion(options.onion)
+        .onion_only(options.onion_only);
+    if let Some(control) = &options.tor_control {
+        builder = builder.tor_control(control);
+    }
+    if let Some(socks) = &options.tor_socks {
+        builder = builder.tor_socks(socks);
+    }
+    for setting in &options.transport_costs {
+        let (kind, cost) = setting
+            .split_once('=')
+            .with_context(|| format!("--transport-cost {setting:?} is not KIND=COST"))?;
+        let kind: TransportKind = kind.parse().map_err(anyhow::Error::msg)?;
+        let cost = cost
+            .parse()
+            .with_context(|| format!("transport cost {cost:?} is not 0 to 255"))?;
+        builder = builder.transport_cost(kind, cost);
+    }
+    for server in &options.stun_servers {
+        builder = builder.stun_server(server);
+    }
+    if let Some(ip) = options.public_ip {
+        builder = builder.public_ip(ip);
+    }
+    if let Some(ip) = options.bind_addr {
+        builder = builder.bind_addr(ip);
+    }
+    if let Some(name) = &options.interface {
+        builder = builder.interface(name);
+    }
+    for hint in &options.relay_hints {
+        builder = builder.relay_hint(hint);
+    }
+    if let (Some(server), Some(username), Some(password)) = (
+        &options.turn_server,
+        &options.turn_username,
+        &options.turn_password,
+    ) {
+        builder = builder.turn_server(server, username, password);
+    }
+    if let Some(url) = &options.proxy {
+        let Some(rest) = url.strip_prefix("socks5://") else {
+            bail!("--proxy {url:?} is not socks5://[user:password@]host:port");
+        };
+        let server = match rest.rsplit_once('@') {
+            Some((login, server)) => {
+                let (username, password) = login.split_once(':').unwrap_or((login, ""));
+                builder = builder.socks5_login(username, password);
+                server
+            }
+            None => rest,
+        };
+        builder = builder.socks5_proxy(server.trim_end_matches('/'));
+    }
+    builder = builder
+        .compression(!options.no_compress)
+        .plaintext(options.plaintext)
+        .sealed_box(options.sealed_box)
+        .replay_window(Duration::from_secs(options.replay_window_secs))
+        .rekey_interval(Duration::from_secs(options.rekey_secs))
+        .keepalive_interval(Duration::from_secs(options.keepalive_secs))
+        .signed_endpoints(options.signed_endpoints)
+        .signaling(options.signaling)
+        .relay(options.relay)
+        .use_relay(options.use_relay)
+        .max_message_bytes(options.max_message_bytes);
+    match &command {
+        Command::ReceiveFile { .. } => {
+            builder = builder.capabilities(Capabilities::FILE_TRANSFER);
+        }
+        Command::Expose { .. } => builder = builder.capabilities(Capabilities::TUNNEL),
+        Command::Vpn { .. } => builder = builder.capabilities(Capabilities::VPN),
+        Command::Bridge { .. } => builder = builder.capabilities(Capabilities::BRIDGE),
+        _ => {}
+    }
+    // Publishing the moves of our long-term IDs would link sessions to them.
+    if session.is_none() {
+        for delegation in stored_delegations(&options.identity)? {
+            builder = builder.delegation(delegation);
+        }
+    }
+    let node = builder.build()?;
+    if output == Output::Json {
+        node.on_event(print_event);
+    }
+    let events = node.subscribe();
+    // Session IDs never move; the long-term ones they come from might have.
+    if session.is_none() {
+        resolve_peers(&mut command, &node)?;
+    }
+
+    // Only looking, so neither announcing nor answering.
+    if let Command::Scrape { peer, .. } = &command {
+        let result = node.find_peer(peer);
+        node.shutdown();
+        let found = result?;
+        if json_output() {
+            for addr in found {
+                print_json(&[
+                    ("event", Json::Str("candidate")),
+                    ("id", Json::Str(peer)),
+                    ("addr", Json::Str(&addr.to_string())),
+                ]);
+            }
+            return Ok(());
+        }
+        println!("candidates for {peer}: {}", found.len());
+        for addr in found {
+            println!("{addr}");
+        }
+        return Ok(());
+    }
+
+    node.spawn_receiver();
+    node.spawn_announcer(Duration::from_secs(options.announce_secs));
+    if !matches!(command, Command::Pair { .. } | Command::Mailbox { .. }) {
+        let mut senders = authorized_peers(&options)?;
+        senders.extend(command_peer(&command).map(str::to_string));
+        spawn_mailbox_check(&node, senders);
+    }
+
+    match &command {
+        Command::Listen { pipe, .. } => {
+            info!("announcing and waiting for inbound hellos; Ctrl+C to stop.");
+            serve(&node, &events, None, *pipe);
+        }
+        Command::Connect { peer, pipe, .. } => {
+            node.spawn_lookup(peer, LOOKUP_INTERVAL)?;
+            info!("looking up {peer}; Ctrl+C to stop.");
+            serve(&node, &events, Some(peer), *pipe);
+        }
+        Command::Send {
+            peer,
+            resend_secs,
+            ttl_secs,
+            ..
+        } => {
+            node.spawn_lookup(peer, LOOKUP_INTERVAL)?;
+            let resend = Duration::from_secs((*resend_secs).max(1));
+            let ttl = ttl_secs.map(Duration::from_secs);
+            let outbox = options.outbox.as_deref();
+            let result = send(&node, &events, outbox, peer, &message, resend, ttl);
+            node.shutdown();
+            return result;
+        }
+        Command::Ping { peer, count, .. } => {
+            node.spawn_lookup(peer, LOOKUP_INTERVAL)?;
+            let result = ping(&node, &events, peer, *count);
+            node.shutdown();
+            return result;
+        }
+        Command::Chat { peer, .. } => {
+            node.spawn_lookup(peer, LOOKUP_INTERVAL)?;
+            return chat(&node, &events, peer);
+        }
+        Command::SendFile { path, peer, .. } => {
+            node.spawn_lookup(peer, LOOKUP_INTERVAL)?;
+            let result = send_file(&node, &events, peer, path);
+            node.shutdown();
+            return result;
+        }
+        Command::Pair {
+            code, timeout_secs, ..
+        } => {
+            let result = pair(
+                &options,
+                &node,
+                code.as_deref(),
+                Duration::from_secs(*timeout_secs),
+            );
+            node.shutdown();
+            return result;
+        }
+        Command::Mailbox { peer, message, .. } => {
+            let result = node.leave_in_mailbox(peer, message.as_bytes());
+            node.shutdown();
+            let id = result?;
+            if json_output() {
+                print_json(&[
+                    ("event", Json::Str("mailbox-stored")),
+                    ("id", Json::Str(peer)),
+                    ("message_id", Json::Str(&id.to_string())),
+                ]);
+            } else {
+                println!("left {id} in the mailbox of {peer}");
+            }
+            return Ok(());
+        }
+        Command::ReceiveFile { peer, dir, .. } => {
+            node.spawn_lookup(peer, LOOKUP_INTERVAL)?;
+            let result = receive_file(&node, &events, peer, dir);
+            node.shutdown();
+            return result;
+        }
+        Command::Forward {
+            peer,
+            local,
+            remote,
+            ..
+        } => {
+            node.spawn_lookup(peer, LOOKUP_INTERVAL)?;
+            let result = forward(&node, &events, peer, *local, *remote);
+            node.shutdown();
+            return result;
+        }
+        Command::Expose {
+            ports, exit, peer, ..
+        } => {
+            node.spawn_lookup(peer, LOOKUP_INTERVAL)?;
+            let result = expose(&node, &events, peer, ports, *exit);
+            node.shutdown();
+            return result;
+        }
+        Command::Socks { peer, listen, .. } => {
+            node.spawn_lookup(peer, LOOKUP_INTERVAL)?;
+            let result = proxy(&node, &events, peer, *listen, Proxy::Socks5);
+            node.shutdown();
+            return result;
+        }
+        Command::HttpProxy { peer, listen, .. } => {
+            node.spawn_lookup(peer, LOOKUP_INTERVAL)?;
+            let result = proxy(&node, &events, peer, *listen, Proxy::HttpConnect);
+            node.shutdown();
+            return result;
+        }
+        Command::Bridge {
+            peer,
+            exec,
+            connect_tcp,
+            ..
+        } => {
+            node.spawn_lookup(peer, LOOKUP_INTERVAL)?;
+            let end = match (exec, connect_tcp) {
+                (Some(command), _) => BridgeEnd::Exec(command),
+                (None, Some(target)) => BridgeEnd::Tcp(target),
+                (None, None) => BridgeEnd::Stdio,
+            };
+            let result = bridge(&node, &events, peer, end);
+            node.shutdown();
+            return result;
+        }
+        Command::Vpn {
+            peer,
+            address,
+            interface_name,
+            ..
+        } => {
+            node.spawn_lookup(peer, LOOKUP_INTERVAL)?;
+            let result = vpn(&node, &events, peer, address, interface_name);
+            node.shutdown();
+            return result;
+        }
+        // Handled before the node runs.
+        Command::Id { .. }
+        | Command::Keygen
+        | Command::SessionNonce
+        | Command::SetPassphrase { .. }
+        | Command::RotateKey { .. }
+        | Command::Invite { .. }
+        | Command::BootstrapNode { .. }
+        | Command::Scrape { .. } => {}
+    }
+    node.shutdown();
+    Ok(())
+}
+
+/// `listen` and `connect`: log what peers send until the node stops. With
+/// `pipe`, the first peer to say hello (`peer_id`, if given) is joined to
+/// stdin and stdout instead.
+fn serve(node: &DhtMsg, events: &flume::Receiver<Event>, peer_id: Option<&str>, pipe: bool) {
+    let mut pipe_peer: Option<SocketAddr> = None;
+    while !node.shutdown_handle().is_shutdown() {
+        match events.recv_timeout(Duration::from_millis(200)) {
+            Ok(Event::PeerAuthenticated { from, id })
+                if pipe && pipe_peer.is_none() && peer_id.is_none_or(|peer_id| id == peer_id) =>
+            {
+                info!("pipe connected to {from}");
+                if let Some(sas) = sas_line(node, from) {
+                    info!("{sas}");
+                }
+                pipe_peer = Some(from);
+                spawn_stdin_pipe(node.clone(), from);
+            }
+            Ok(Event::MessageReceived {
+                from,
+                topic: None,
+                payload,
+                ..
+            }) => {
+                if pipe_peer == Some(from) {
+                    let mut stdout = io::stdout().lock();
+                    if let Err(err) = stdout.write_all(&payload).and_then(|()| stdout.flush()) {
+                        warn!("failed to write to stdout: {err}");
+                        node.shutdown();
                     }
+                } else {
+                    info!("message from {from}: {}", String::from_utf8_lossy(&payload));
                 }
-                Ok(_other) => {
-                    info!("received non-hello message from {peer} (ignored)");
+            }
+            Ok(Event::PeerLeft { from, .. }) if pipe_peer == Some(from) => {
+                info!("pipe peer {from} left");
+                node.shutdown();
+            }
+            Ok(Event::RecordReceived { peer_id, record }) => {
+                let addresses = record.addresses();
+                let addresses = Vec::from_iter(addresses.iter().map(ToString::to_string));
+                info!("record of {peer_id}: {}", addresses.join(", "));
+                if !record.relays.is_empty() {
+                    info!("relay hints: {}", record.relays.join(", "));
                 }
-                Err(_) => {
-                    info!("received non-UTF8 message from {peer} (ignored)");
+            }
+            Ok(Event::MailboxMessage { sender, payload }) => {
+                info!(
+                    "mailbox message from {sender}: {}",
+                    String::from_utf8_lossy(&payload)
+                );
+            }
+            Ok(Event::MessageExpired { peer_id, .. }) => {
+                warn!("queued message to {peer_id} expired undelivered");
+            }
+            Ok(_) | Err(flume::RecvTimeoutError::Timeout) => {}
+            Err(flume::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// `send`: deliver `message` to `peer_id`, resending it every `resend`
+/// until it is acknowledged or `ttl` is over. With an `outbox` the node
+/// delivers it instead, with what earlier runs left there for the peer.
+fn send(
+    node: &DhtMsg,
+    events: &flume::Receiver<Event>,
+    outbox: Option<&Path>,
+    peer_id: &str,
+    message: &[u8],
+    resend: Duration,
+    ttl: Option<Duration>,
+) -> Result<()> {
+    if let Some(dir) = outbox {
+        match ttl {
+            Some(ttl) => node.queue_message_with_ttl(peer_id, message, ttl)?,
+            None => node.queue_message(peer_id, message)?,
+        }
+        info!(
+            "queued message in {}; {} waiting for {peer_id}",
+            dir.display(),
+            node.queued_messages(peer_id)
+        );
+    }
+    info!("looking up {peer_id}; Ctrl+C to stop.");
+    let expires = ttl.map(|ttl| Instant::now() + ttl);
+    let mut candidates: Vec<SocketAddr> = Vec::new();
+    let mut next_resend = Instant::now() + resend;
+    let mut expired = 0;
+    while !node.shutdown_handle().is_shutdown() {
+        let timeout = next_resend.saturating_duration_since(Instant::now());
+        match events.recv_timeout(timeout) {
+            // The peer drops messages until it is established.
+            Ok(Event::PeerAuthenticated { from, id })
+                if outbox.is_none() && id == peer_id && !candidates.contains(&from) =>
+            {
+                candidates.push(from);
+                send_message(node, from, message);
+            }
+            Ok(Event::MessageAcked { from, .. })
+                if outbox.is_none() && candidates.contains(&from) =>
+            {
+                info!("message delivered to {from}");
+                return Ok(());
+            }
+            Ok(Event::MessageExpired { peer_id: to, .. }) => {
+                warn!("queued message to {to} expired undelivered");
+                if to == peer_id {
+                    expired += 1;
+                }
+            }
+            Ok(_) => {}
+            Err(flume::RecvTimeoutError::Timeout) => {
+                next_resend = Instant::now() + resend;
+                if outbox.is_none() {
+                    if expires.is_some_and(|expires| expires <= Instant::now()) {
+                        bail!("message not acknowledged within --ttl-secs");
+                    }
+                    for addr in &candidates {
+                        send_message(node, *addr, message);
+                    }
                 }
-            },
-            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
-                thread::sleep(Duration::from_millis(200));
             }
-            Err(err) => {
-                error!("UDP recv error: {err}");
-                thread::sleep(Duration::from_secs(1));
+            Err(flume::RecvTimeoutError::Disconnected) => break,
+        }
+        if outbox.is_some() && node.queued_messages(peer_id) == 0 {
+            if expired > 0 {
+                info!("outbox for {peer_id} emptied, {expired} messages expired");
+            } else {
+                info!("outbox for {peer_id} delivered");
             }
+            return Ok(());
         }
     }
+    Ok(())
 }
 
-fn lookup_and_hello(
-    dht: mainline::Dht,
-    socket: UdpSocket,
-    local_id: String,
-    local_infohash: Id,
-    peer_infohash: Id,
-    announce_secs: u64,
-    hello_port: u16,
-) {
-    let mut seen: HashSet<SocketAddrV4> = HashSet::new();
-    let mut last_announce = Instant::now();
-    info!("starting lookup loop; Ctrl+C to stop.");
+/// How long `ping` waits for each answer, and between pings.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `ping`: once `peer_id` is reached, print how long each of `count` pings
+/// takes to be answered.
+fn ping(node: &DhtMsg, events: &flume::Receiver<Event>, peer_id: &str, count: u32) -> Result<()> {
+    let Some(peer) = wait_for_peer(node, events, peer_id) else {
+        return Ok(());
+    };
+    if json_output() {
+        print_json(&[
+            ("event", Json::Str("peer-reached")),
+            ("id", Json::Str(peer_id)),
+            ("addr", Json::Str(&peer.to_string())),
+        ]);
+    } else {
+        println!("reached {peer_id} at {peer}");
+    }
+    let mut answered = 0;
+    for _ in 0..count {
+        let sent = Instant::now();
+        let next = sent + PING_INTERVAL;
+        node.send_ping(peer)?;
+        let rtt = loop {
+            match events.recv_timeout(next.saturating_duration_since(Instant::now())) {
+                Ok(Event::AckReceived { from, .. }) if from == peer => break Some(sent.elapsed()),
+                Ok(_) => {}
+                Err(_) => break None,
+            }
+        };
+        match rtt {
+            Some(rtt) => {
+                answered += 1;
+                let rtt_ms = rtt.as_secs_f64() * 1000.0;
+                if json_output() {
+                    print_json(&[
+                        ("event", Json::Str("ping-answer")),
+                        ("addr", Json::Str(&peer.to_string())),
+                        ("rtt_ms", Json::Float(rtt_ms)),
+                    ]);
+                } else {
+                    println!("answer from {peer}: {rtt_ms:.1} ms");
+                }
+                thread::sleep(next.saturating_duration_since(Instant::now()));
+            }
+            None if json_output() => print_json(&[
+                ("event", Json::Str("ping-timeout")),
+                ("addr", Json::Str(&peer.to_string())),
+            ]),
+            None => println!("no answer from {peer}"),
+        }
+        if node.shutdown_handle().is_shutdown() {
+            break;
+        }
+    }
+    if json_output() {
+        print_json(&[
+            ("event", Json::Str("ping-summary")),
+            ("sent", Json::Int(count.into())),
+            ("answered", Json::Int(answered)),
+        ]);
+    } else {
+        println!("{count} pings, {answered} answered");
+    }
+    if answered == 0 {
+        bail!("{peer_id} answered no ping");
+    }
+    Ok(())
+}
+
+fn send_message(node: &DhtMsg, addr: SocketAddr, message: &[u8]) {
+    match node.send_message(addr, message) {
+        Ok(seq) => info!("sent {}-byte message {seq} to {addr}", message.len()),
+        Err(err) => warn!("failed to send message to {addr}: {err}"),
+    }
+}
+
+/// Send stdin to `peer` line by line; shut the node down at end of input.
+fn spawn_stdin_pipe(node: DhtMsg, peer: SocketAddr) {
+    // Not a node thread: a blocking stdin read cannot be joined on shutdown.
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match stdin.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {
+                    // Over-long lines go out in pieces the peer accepts.
+                    for piece in line.chunks(node.max_message_bytes().max(1)) {
+                        if let Err(err) = node.send_message(peer, piece) {
+                            warn!("failed to send line to {peer}: {err}");
+                        }
+                    }
+                }
+                Err(err) => {
+                    warn!("failed to read stdin: {err}");
+                    break;
+                }
+            }
+        }
+        info!("end of input; closing pipe");
+        node.shutdown();
+    });
+}
+
+/// Chat session with `peer_id`: it starts at the first hello/ack from that ID
+/// and ends on `/quit`, end of input or the peer's goodbye; `/verify` shows
+/// the short authentication string.
+fn chat(node: &DhtMsg, events: &flume::Receiver<Event>, peer_id: &str) -> Result<()> {
+    println!("looking up {peer_id}; type /quit to exit, /verify to check the session");
+    let session: Arc<Mutex<Option<SocketAddr>>> = Arc::default();
+    {
+        let node = node.clone();
+        let session = session.clone();
+        // Not a node thread: a blocking stdin read cannot be joined on shutdown.
+        thread::spawn(move || {
+            for line in io::stdin().lines() {
+                let Ok(line) = line else { break };
+                let line = line.trim_end();
+                if line == "/quit" {
+                    break;
+                }
+                if line == "/verify" {
+                    let peer = *session.lock().unwrap();
+                    match peer.and_then(|peer| sas_line(&node, peer)) {
+                        Some(sas) => println!("{sas}"),
+                        None => eprintln!("no encrypted session yet"),
+                    }
+                    continue;
+                }
+                if line.is_empty() {
+                    continue;
+                }
+                match *session.lock().unwrap() {
+                    Some(peer) => {
+                        if let Err(err) = node.send_message(peer, line.as_bytes()) {
+                            eprintln!("not sent: {err}");
+                        }
+                    }
+                    None => eprintln!("not connected yet; message dropped"),
+                }
+            }
+            node.shutdown();
+        });
+    }
+
+    let is_peer = |from: SocketAddr| *session.lock().unwrap() == Some(from);
+    while !node.shutdown_handle().is_shutdown() {
+        let event = match events.recv_timeout(Duration::from_millis(200)) {
+            Ok(event) => event,
+            Err(flume::RecvTimeoutError::Timeout) => continue,
+            Err(flume::RecvTimeoutError::Disconnected) => break,
+        };
+        match event {
+            Event::PeerAuthenticated { from, id } if id == peer_id => {
+                let mut session = session.lock().unwrap();
+                if session.is_none() {
+                    *session = Some(from);
+                    println!("connected to {peer_id} at {from}");
+                    if let Some(sas) = sas_line(node, from) {
+                        println!("{sas}; /verify shows it again");
+                    }
+                }
+            }
+            Event::MessageReceived {
+                from,
+                sender,
+                topic: None,
+                payload,
+            } if is_peer(from) => {
+                println!(
+                    "[{}] {sender}: {}",
+                    timestamp(),
+                    String::from_utf8_lossy(&payload)
+                );
+            }
+            Event::MailboxMessage { sender, payload } if sender == peer_id => {
+                println!("[mailbox] {sender}: {}", String::from_utf8_lossy(&payload));
+            }
+            Event::PeerLeft { from, .. } if is_peer(from) => {
+                println!("{peer_id} left");
+                node.shutdown();
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The short authentication string of the session with `peer`, for the
+/// user to compare with the peer's.
+fn sas_line(node: &DhtMsg, peer: SocketAddr) -> Option<String> {
+    let sas = node.peer_sas(peer)?;
+    let emoji: Vec<String> = sas
+        .emoji()
+        .iter()
+        .map(|(emoji, name)| format!("{emoji} ({name})"))
+        .collect();
+    Some(format!(
+        "compare with the peer: {sas} / {}",
+        emoji.join(" ")
+    ))
+}
+
+/// Block until `peer_id` is established; `None` if the node stopped.
+fn wait_for_peer(
+    node: &DhtMsg,
+    events: &flume::Receiver<Event>,
+    peer_id: &str,
+) -> Option<SocketAddr> {
+    info!("waiting for {peer_id}; Ctrl+C to stop.");
+    while !node.shutdown_handle().is_shutdown() {
+        match events.recv_timeout(Duration::from_millis(200)) {
+            Ok(Event::PeerAuthenticated { from, id }) if id == peer_id => {
+                info!("connected to {peer_id} at {from}");
+                if let Some(sas) = sas_line(node, from) {
+                    info!("{sas}");
+                }
+                return Some(from);
+            }
+            Ok(_) | Err(flume::RecvTimeoutError::Timeout) => {}
+            Err(flume::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    None
+}
+
+fn send_file(
+    node: &DhtMsg,
+    events: &flume::Receiver<Event>,
+    peer_id: &str,
+    path: &Path,
+) -> Result<()> {
+    let Some(peer) = wait_for_peer(node, events, peer_id) else {
+        return Ok(());
+    };
+    let accepts_files = node
+        .peer_capabilities(peer)
+        .is_some_and(|caps| caps.contains(Capabilities::FILE_TRANSFER));
+    if !accepts_files {
+        bail!("{peer_id} is not accepting files (run `dhtmsg receive-file` there)");
+    }
+    let caps = node.peer_capabilities(peer).unwrap_or_default();
+    let lz4 = node.peer_supports_compression(peer);
+    let size = if caps.contains(Capabilities::CHANNELS) {
+        let mut stream = node.channel(peer, dhtmsg::file::CHANNEL)?;
+        send_file_over(&mut stream, path, lz4, "uTP")
+    } else if caps.contains(Capabilities::UTP) {
+        send_file_over(&mut node.utp_stream(peer)?, path, lz4, "uTP")
+    } else {
+        send_file_over(&mut node.stream(peer)?, path, lz4, "stream")
+    }
+    .with_context(|| format!("failed to send {}", path.display()))?;
+    info!("sent {} ({size} bytes)", path.display());
+    Ok(())
+}
+
+fn send_file_over(
+    stream: &mut (impl Read + Write),
+    path: &Path,
+    lz4: bool,
+    over: &str,
+) -> io::Result<u64> {
+    if lz4 {
+        info!("sending {} over {over} (LZ4)", path.display());
+        dhtmsg::file::send_file_compressed(stream, path)
+    } else {
+        info!("sending {} over {over}", path.display());
+        dhtmsg::file::send_file(stream, path)
+    }
+}
+
+/// A stream a peer opened to us, of either kind.
+enum Incoming {
+    Stream(PeerStream),
+    Utp(UtpStream),
+}
+
+fn receive_file(
+    node: &DhtMsg,
+    events: &flume::Receiver<Event>,
+    peer_id: &str,
+    dir: &Path,
+) -> Result<()> {
+    let Some(peer) = wait_for_peer(node, events, peer_id) else {
+        return Ok(());
+    };
+    // Senders pick the files channel when we support it, and a plain uTP
+    // stream or a stream when they do not.
+    let (tx, incoming) = flume::unbounded();
+    let acceptor = node.clone();
+    let streams = tx.clone();
+    thread::spawn(move || {
+        loop {
+            let accepted = acceptor.accept_stream().map(Incoming::Stream);
+            let failed = accepted.is_err();
+            if streams.send(accepted).is_err() || failed {
+                return;
+            }
+        }
+    });
+    let acceptor = node.clone();
+    let utp_streams = tx.clone();
+    thread::spawn(move || {
+        loop {
+            let accepted = acceptor.accept_utp_stream().map(Incoming::Utp);
+            let failed = accepted.is_err();
+            if utp_streams.send(accepted).is_err() || failed {
+                return;
+            }
+        }
+    });
+    let acceptor = node.clone();
+    thread::spawn(move || {
+        loop {
+            let accepted = acceptor
+                .accept_channel(dhtmsg::file::CHANNEL)
+                .map(Incoming::Utp);
+            let failed = accepted.is_err();
+            if tx.send(accepted).is_err() || failed {
+                return;
+            }
+        }
+    });
+    loop {
+        let received = match incoming.recv()?? {
+            Incoming::Stream(stream) if stream.peer_addr() != peer => {
+                warn!("ignoring stream from {}", stream.peer_addr());
+                continue;
+            }
+            Incoming::Utp(stream) if stream.peer_addr() != peer => {
+                warn!("ignoring uTP stream from {}", stream.peer_addr());
+                continue;
+            }
+            Incoming::Stream(mut stream) => dhtmsg::file::receive_file(&mut stream, dir),
+            Incoming::Utp(mut stream) => dhtmsg::file::receive_file(&mut stream, dir),
+        };
+        let (path, size) =
+            received.with_context(|| format!("failed to receive file from {peer}"))?;
+        info!("received {} ({size} bytes)", path.display());
+        return Ok(());
+    }
+}
+
+/// Block until `peer_id` is established, and check that it takes tunnels,
+/// as the `command` it should run makes it; `None` if the node stopped.
+fn wait_for_tunnels(
+    node: &DhtMsg,
+    events: &flume::Receiver<Event>,
+    peer_id: &str,
+    command: &str,
+) -> Result<Option<SocketAddr>> {
+    let Some(peer) = wait_for_peer(node, events, peer_id) else {
+        return Ok(None);
+    };
+    let accepts_tunnels = node
+        .peer_capabilities(peer)
+        .is_some_and(|caps| caps.contains(Capabilities::TUNNEL | Capabilities::CHANNELS));
+    if !accepts_tunnels {
+        bail!("{peer_id} is not accepting tunnels (run `dhtmsg {command}` there)");
+    }
+    Ok(Some(peer))
+}
+
+/// A uTP stream to `peer` that the other end connected to `target`.
+fn open_tunnel(node: &DhtMsg, peer: SocketAddr, target: &str) -> io::Result<UtpStream> {
+    let mut stream = node
+        .channel(peer, dhtmsg::tunnel::CHANNEL)
+        .map_err(io::Error::other)?;
+    dhtmsg::tunnel::request(&mut stream, target)?;
+    Ok(stream)
+}
+
+/// Tunnel every connection to `local` over its own uTP stream to `remote`
+/// on the peer's machine, or stdin and stdout without `local`.
+fn forward(
+    node: &DhtMsg,
+    events: &flume::Receiver<Event>,
+    peer_id: &str,
+    local: Option<u16>,
+    remote: u16,
+) -> Result<()> {
+    let Some(peer) = wait_for_tunnels(node, events, peer_id, "expose")? else {
+        return Ok(());
+    };
+    let target = format!("localhost:{remote}");
+    let Some(local) = local else {
+        return forward_stdio(node, peer, &target);
+    };
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, local))
+        .with_context(|| format!("failed to listen on port {local}"))?;
+    info!("forwarding 127.0.0.1:{local} to port {remote} of {peer_id}");
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("failed to accept a connection: {e}");
+                continue;
+            }
+        };
+        // The peer may have moved since it connected.
+        let addr = node.verified_peer(peer_id).unwrap_or(peer);
+        let node = node.clone();
+        let target = target.clone();
+        thread::spawn(move || {
+            let from = client.peer_addr().ok();
+            let tunneled = open_tunnel(&node, addr, &target)
+                .and_then(|stream| dhtmsg::tunnel::splice(&stream, &client));
+            match tunneled {
+                Ok((sent, received)) => {
+                    info!("tunnel from {from:?} closed: {sent} bytes sent, {received} received")
+                }
+                Err(e) => warn!("tunnel from {from:?} failed: {e}"),
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Tunnel stdin and stdout to `target` on the peer's machine, until the
+/// peer's side ends.
+fn forward_stdio(node: &DhtMsg, peer: SocketAddr, target: &str) -> Result<()> {
+    let stream =
+        open_tunnel(node, peer, target).with_context(|| format!("failed to tunnel to {target}"))?;
+    splice_stdio(stream)
+}
+
+/// Copy stdin to `stream` and `stream` to stdout, until the peer's side
+/// ends.
+fn splice_stdio(stream: UtpStream) -> Result<()> {
+    let stream = Arc::new(stream);
+    let outbound = stream.clone();
+    // Not joined: once the peer's side ends, so has the connection, and
+    // stdin may never close.
+    thread::spawn(move || {
+        if let Err(e) = io::copy(&mut io::stdin().lock(), &mut &*outbound) {
+            warn!("sending to the peer failed: {e}");
+        }
+        outbound.finish();
+    });
+    // Stdout buffers up to a newline.
+    copy_flushing(&stream, &mut io::stdout().lock())?;
+    Ok(())
+}
+
+/// Copy `stream` to `out` until the peer's side ends, flushing every chunk
+/// so that prompts and binary protocols go through at once.
+fn copy_flushing(stream: &UtpStream, out: &mut impl Write) -> io::Result<u64> {
+    let mut buf = [0u8; 16 * 1024];
+    let mut copied = 0;
     loop {
-        if last_announce.elapsed() >= Duration::from_secs(announce_secs) {
-            announce(&dht, local_infohash, hello_port);
-            last_announce = Instant::now();
-        }
-
-        let iter = dht.get_peers(peer_infohash);
-        for peers in iter {
-            for addr in peers {
-                if seen.insert(addr) {
-                    info!("found peer candidate {addr}, sending hello...");
-                    if let Err(err) = send_hello(&socket, addr, &local_id) {
-                        warn!("failed to send hello to {addr}: {err}");
+        let n = (&*stream).read(&mut buf)?;
+        if n == 0 {
+            return Ok(copied);
+        }
+        out.write_all(&buf[..n])?;
+        out.flush()?;
+        copied += n as u64;
+    }
+}
+
+/// How the clients of a local proxy name their targets.
+#[derive(Clone, Copy)]
+enum Proxy {
+    Socks5,
+    HttpConnect,
+}
+
+impl Proxy {
+    fn read_request(self, client: &mut TcpStream) -> io::Result<String> {
+        match self {
+            Self::Socks5 => dhtmsg::tunnel::read_socks_request(client),
+            Self::HttpConnect => dhtmsg::tunnel::read_http_connect(client),
+        }
+    }
+
+    fn answer(self, client: &mut TcpStream, error: Option<&io::Error>) -> io::Result<()> {
+        match self {
+            Self::Socks5 => dhtmsg::tunnel::answer_socks(client, error),
+            Self::HttpConnect => dhtmsg::tunnel::answer_http(client, error),
+        }
+    }
+}
+
+impl std::fmt::Display for Proxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Socks5 => "SOCKS5",
+            Self::HttpConnect => "HTTP",
+        })
+    }
+}
+
+/// Accept proxy clients on `listen` and tunnel each connection to the
+/// peer, which makes it.
+fn proxy(
+    node: &DhtMsg,
+    events: &flume::Receiver<Event>,
+    peer_id: &str,
+    listen: SocketAddr,
+    protocol: Proxy,
+) -> Result<()> {
+    let Some(peer) = wait_for_tunnels(node, events, peer_id, "expose --exit")? else {
+        return Ok(());
+    };
+    let listener =
+        TcpListener::bind(listen).with_context(|| format!("failed to listen on {listen}"))?;
+    info!("{protocol} proxy on {listen}, exiting from {peer_id}");
+    for client in listener.incoming() {
+        let mut client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("failed to accept a connection: {e}");
+                continue;
+            }
+        };
+        let addr = node.verified_peer(peer_id).unwrap_or(peer);
+        let node = node.clone();
+        thread::spawn(move || {
+            let tunneled = protocol.read_request(&mut client).and_then(|target| {
+                let stream = match open_tunnel(&node, addr, &target) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        protocol.answer(&mut client, Some(&e))?;
+                        return Err(io::Error::new(e.kind(), format!("{target}: {e}")));
                     }
+                };
+                protocol.answer(&mut client, None)?;
+                info!("tunneling to {target}");
+                dhtmsg::tunnel::splice(&stream, &client)
+            });
+            match tunneled {
+                Ok((sent, received)) => {
+                    info!("tunnel closed: {sent} bytes sent, {received} received")
                 }
+                Err(e) => warn!("tunnel failed: {e}"),
             }
+        });
+    }
+    Ok(())
+}
+
+/// Connect the uTP streams the peer opens to the local ports it names, if
+/// they are among `ports`, or to wherever they name with `exit`.
+fn expose(
+    node: &DhtMsg,
+    events: &flume::Receiver<Event>,
+    peer_id: &str,
+    ports: &[u16],
+    exit: bool,
+) -> Result<()> {
+    let Some(peer) = wait_for_peer(node, events, peer_id) else {
+        return Ok(());
+    };
+    if exit {
+        info!("letting {peer_id} connect anywhere");
+    } else {
+        info!("exposing ports {ports:?} to {peer_id}");
+    }
+    loop {
+        let mut stream = node.accept_channel(dhtmsg::tunnel::CHANNEL)?;
+        let from = stream.peer_addr();
+        if from != peer && node.verified_peer(peer_id) != Some(from) {
+            warn!("ignoring tunnel from {from}");
+            continue;
         }
+        let ports = ports.to_vec();
+        thread::spawn(move || {
+            let tunneled = dhtmsg::tunnel::read_request(&mut stream).and_then(|target| {
+                let exposed = target
+                    .strip_prefix("localhost:")
+                    .and_then(|port| port.parse::<u16>().ok())
+                    .is_some_and(|port| ports.contains(&port));
+                if !exit && !exposed {
+                    dhtmsg::tunnel::refuse(&mut stream, "not exposed")?;
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("refused {target}"),
+                    ));
+                }
+                let service = match TcpStream::connect(target.as_str()) {
+                    Ok(service) => service,
+                    Err(e) => {
+                        dhtmsg::tunnel::refuse(&mut stream, &e.to_string())?;
+                        return Err(e);
+                    }
+                };
+                dhtmsg::tunnel::accept(&mut stream)?;
+                info!("tunneling to {target}");
+                dhtmsg::tunnel::splice(&stream, &service)
+            });
+            match tunneled {
+                Ok((sent, received)) => {
+                    info!("tunnel closed: {sent} bytes sent, {received} received")
+                }
+                Err(e) => warn!("tunnel failed: {e}"),
+            }
+        });
+    }
+}
+
+/// The channel the two ends of `bridge` meet on.
+const BRIDGE_CHANNEL: &str = "bridge";
 
-        thread::sleep(Duration::from_secs(5));
+/// What `bridge` joins to the peer.
+enum BridgeEnd<'a> {
+    Stdio,
+    /// A shell command.
+    Exec(&'a str),
+    /// `host:port`.
+    Tcp(&'a str),
+}
+
+/// Join `end` to the peer's end of a uTP stream, opened by whichever of us
+/// has the lower ID so that both sides can run the same command.
+fn bridge(
+    node: &DhtMsg,
+    events: &flume::Receiver<Event>,
+    peer_id: &str,
+    end: BridgeEnd,
+) -> Result<()> {
+    let Some(peer) = wait_for_peer(node, events, peer_id) else {
+        return Ok(());
+    };
+    let bridges = node
+        .peer_capabilities(peer)
+        .is_some_and(|caps| caps.contains(Capabilities::BRIDGE | Capabilities::CHANNELS));
+    if !bridges {
+        bail!("{peer_id} is not bridging (run `dhtmsg bridge` there)");
+    }
+    let stream = if node.local_id() < peer_id.to_ascii_lowercase().as_str() {
+        node.channel(peer, BRIDGE_CHANNEL)?
+    } else {
+        loop {
+            let stream = node.accept_channel(BRIDGE_CHANNEL)?;
+            let from = stream.peer_addr();
+            if from == peer || node.verified_peer(peer_id) == Some(from) {
+                break stream;
+            }
+            warn!("ignoring uTP stream from {from}");
+        }
+    };
+    match end {
+        BridgeEnd::Stdio => splice_stdio(stream),
+        BridgeEnd::Exec(command) => bridge_command(&stream, command),
+        BridgeEnd::Tcp(target) => {
+            let tcp = TcpStream::connect(target)
+                .with_context(|| format!("failed to connect to {target}"))?;
+            let (sent, received) = dhtmsg::tunnel::splice(&stream, &tcp)?;
+            info!("bridge closed: {sent} bytes sent, {received} received");
+            Ok(())
+        }
     }
 }
 
-fn send_hello(socket: &UdpSocket, addr: SocketAddrV4, local_id: &str) -> Result<()> {
-    let payload = format!("hello from {local_id}");
-    socket
-        .send_to(payload.as_bytes(), addr)
-        .with_context(|| format!("sending hello to {addr}"))?;
+/// Run `command` with its stdin fed from `stream` and its stdout sent back,
+/// until it exits.
+fn bridge_command(stream: &UtpStream, command: &str) -> Result<()> {
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {command:?}"))?;
+    let mut stdin = child.stdin.take().expect("piped");
+    let mut stdout = child.stdout.take().expect("piped");
+    let sent = thread::scope(|scope| {
+        let outbound = scope.spawn(|| {
+            let sent = io::copy(&mut stdout, &mut &*stream);
+            stream.finish();
+            sent
+        });
+        // A command that stopped reading breaks the pipe; what it wrote
+        // still goes out.
+        match copy_flushing(stream, &mut stdin) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                warn!("passing on what the peer sent failed: {e}")
+            }
+            _ => {}
+        }
+        // Closing its stdin tells the command the peer is done.
+        drop(stdin);
+        outbound.join().expect("bridge copy panicked")
+    })?;
+    let status = child.wait()?;
+    info!("{command:?} exited with {status} after sending {sent} bytes");
     Ok(())
 }
 
-fn idle_announce_loop(dht: mainline::Dht, infohash: Id, announce_secs: u64, hello_port: u16) {
-    let mut last_announce = Instant::now();
+/// `command` run by the system's shell.
+fn shell(command: &str) -> std::process::Command {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut process = std::process::Command::new(shell);
+    process.arg(flag).arg(command);
+    process
+}
+
+/// Carry the IP packets of a new TUN interface with `address` (`ip/prefix`)
+/// to and from the peer.
+fn vpn(
+    node: &DhtMsg,
+    events: &flume::Receiver<Event>,
+    peer_id: &str,
+    address: &str,
+    interface_name: &str,
+) -> Result<()> {
+    let (ip, prefix) = address
+        .split_once('/')
+        .and_then(|(ip, prefix)| Some((ip.parse().ok()?, prefix.parse().ok()?)))
+        .with_context(|| format!("{address:?} is not an IPv4 address with a prefix length"))?;
+    let device = TunDevice::open(interface_name)
+        .with_context(|| format!("failed to create TUN interface {interface_name}"))?;
+    device
+        .configure(ip, prefix, dhtmsg::MAX_PACKET_BYTES as u32)
+        .with_context(|| format!("failed to configure {}", device.name()))?;
+    info!("{} is up as {address}", device.name());
+    // Taken before the peer is there, so that none of its packets is lost.
+    let packets = node.packets();
+    let Some(peer) = wait_for_peer(node, events, peer_id) else {
+        return Ok(());
+    };
+    let runs_vpn = node
+        .peer_capabilities(peer)
+        .is_some_and(|caps| caps.contains(Capabilities::VPN));
+    if !runs_vpn {
+        bail!("{peer_id} is not running a VPN (run `dhtmsg vpn` there)");
+    }
+    let device = Arc::new(device);
+    let inbound = device.clone();
+    let receiver = node.clone();
+    let inbound_peer = peer_id.to_string();
+    thread::spawn(move || {
+        for (from, packet) in packets.iter() {
+            if from != peer && receiver.verified_peer(&inbound_peer) != Some(from) {
+                continue;
+            }
+            if let Err(e) = inbound.write_packet(&packet) {
+                warn!("failed to pass on a packet from the peer: {e}");
+            }
+        }
+    });
+    // Only the first of a run of failures is logged: while the peer is
+    // away, every packet fails.
+    let mut failing = false;
+    let mut buf = vec![0u8; dhtmsg::MAX_PACKET_BYTES];
     loop {
-        if last_announce.elapsed() >= Duration::from_secs(announce_secs) {
-            announce(&dht, infohash, hello_port);
-            last_announce = Instant::now();
+        let len = device.read_packet(&mut buf)?;
+        // The peer may have moved since it connected.
+        let addr = node.verified_peer(peer_id).unwrap_or(peer);
+        match node.send_packet(addr, &buf[..len]) {
+            Ok(()) => failing = false,
+            Err(e) if !failing => {
+                warn!("failed to send packets to {peer_id}: {e}");
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Local wall-clock time as `HH:MM:SS` (UTC if the offset is unknown).
+fn timestamp() -> String {
+    let now = time::OffsetDateTime::now_local().unwrap_or_else(|_| time::OffsetDateTime::now_utc());
+    format!("{:02}:{:02}:{:02}", now.hour(), now.minute(), now.second())
+}
+
+/// Log at `level` to the terminal, on stderr if `to_stderr` because stdout
+/// carries data. JSON lines take the same way, but only warnings and errors
+/// are logged as such.
+fn init_logging(output: Output, level: LevelFilter, to_stderr: bool) {
+    use simplelog::{ColorChoice, ConfigBuilder, TermLogger, TerminalMode};
+
+    if output == Output::Json {
+        let _ = JSON_TO_STDERR.set(to_stderr);
+        if log::set_logger(&JsonLog).is_ok() {
+            log::set_max_level(LevelFilter::Warn);
+        }
+        return;
+    }
+
+    let config = ConfigBuilder::new()
+        .set_time_level(LevelFilter::Off)
+        .set_level_padding(simplelog::LevelPadding::Right)
+        .build();
+
+    let _ = TermLogger::init(
+        level,
+        config,
+        if to_stderr {
+            TerminalMode::Stderr
+        } else {
+            TerminalMode::Mixed
+        },
+        ColorChoice::Auto,
+    );
+}
+
+/// Whether `--output json` lines go to stderr; unset for `--output human`.
+static JSON_TO_STDERR: OnceLock<bool> = OnceLock::new();
+
+/// Whether `--output json` asked for JSON lines.
+fn json_output() -> bool {
+    JSON_TO_STDERR.get().is_some()
+}
+
+/// A field value of [`print_json`].
+enum Json<'a> {
+    Str(&'a str),
+    String(String),
+    Int(u64),
+    Float(f64),
+    List(Vec<String>),
+    Null,
+}
+
+impl Json<'_> {
+    fn encode(&self) -> String {
+        match self {
+            Json::Str(text) => json_string(text),
+            Json::String(text) => json_string(text),
+            Json::Int(number) => number.to_string(),
+            // JSON has no NaN or infinity.
+            Json::Float(number) if number.is_finite() => number.to_string(),
+            Json::Float(_) => "null".to_string(),
+            Json::List(items) => {
+                let items = Vec::from_iter(items.iter().map(|item| json_string(item)));
+                format!("[{}]", items.join(","))
+            }
+            Json::Null => "null".to_string(),
+        }
+    }
+}
+
+/// Print one JSON object on a line of its own, if `--output json` asked
+/// for them.
+fn print_json(fields: &[(&str, Json)]) {
+    let Some(&to_stderr) = JSON_TO_STDERR.get() else {
+        return;
+    };
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| format!("{}:{}", json_string(name), value.encode()))
+        .collect();
+    let line = format!("{{{}}}\n", fields.join(","));
+    // Nobody reads on when the pipe breaks.
+    let _ = if to_stderr {
+        io::stderr().lock().write_all(line.as_bytes())
+    } else {
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(line.as_bytes())
+            .and_then(|()| stdout.flush())
+    };
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A payload as text if it is UTF-8, otherwise in hex; `payload_encoding`
+/// tells which.
+fn payload_json(payload: &[u8]) -> Json<'_> {
+    match std::str::from_utf8(payload) {
+        Ok(text) => Json::Str(text),
+        Err(_) => Json::String(hex::encode(payload)),
+    }
+}
+
+fn payload_encoding(payload: &[u8]) -> Json<'static> {
+    Json::Str(match std::str::from_utf8(payload) {
+        Ok(_) => "utf-8",
+        Err(_) => "hex",
+    })
+}
+
+/// A node event as a JSON line.
+fn print_event(event: &Event) {
+    match event {
+        Event::PeerDiscovered { addr } => {
+            print_json(&[
+                ("event", Json::Str("peer-found")),
+                ("addr", Json::Str(&addr.to_string())),
+            ]);
+        }
+        Event::HelloSent { to } => {
+            print_json(&[
+                ("event", Json::Str("hello-sent")),
+                ("addr", Json::Str(&to.to_string())),
+            ]);
         }
+        Event::HelloReceived { from, message } => print_json(&[
+            ("event", Json::Str("hello-received")),
+            ("addr", Json::Str(&from.to_string())),
+            ("message", Json::Str(message)),
+        ]),
+        Event::AckReceived { from, message } => print_json(&[
+            ("event", Json::Str("ack")),
+            ("addr", Json::Str(&from.to_string())),
+            ("message", Json::Str(message)),
+        ]),
+        Event::PeerAuthenticated { from, id } => print_json(&[
+            ("event", Json::Str("peer-authenticated")),
+            ("addr", Json::Str(&from.to_string())),
+            ("id", Json::Str(id)),
+        ]),
+        Event::MessageReceived {
+            from,
+            sender,
+            topic,
+            payload,
+        } => print_json(&[
+            ("event", Json::Str("message-received")),
+            ("addr", Json::Str(&from.to_string())),
+            ("sender", Json::Str(sender)),
+            ("topic", topic.as_deref().map_or(Json::Null, Json::Str)),
+            ("payload", payload_json(payload)),
+            ("payload_encoding", payload_encoding(payload)),
+        ]),
+        Event::MessageAcked { from, seq } => print_json(&[
+            ("event", Json::Str("message-acked")),
+            ("addr", Json::Str(&from.to_string())),
+            ("seq", Json::Int(u64::from(*seq))),
+        ]),
+        Event::PeerLeft { from, message } => print_json(&[
+            ("event", Json::Str("peer-left")),
+            ("addr", Json::Str(&from.to_string())),
+            ("message", Json::Str(message)),
+        ]),
+        Event::MessageExpired { peer_id, payload } => print_json(&[
+            ("event", Json::Str("message-expired")),
+            ("id", Json::Str(peer_id)),
+            ("payload", payload_json(payload)),
+            ("payload_encoding", payload_encoding(payload)),
+        ]),
+        Event::VersionMismatch { from, version } => print_json(&[
+            ("event", Json::Str("error")),
+            ("addr", Json::Str(&from.to_string())),
+            (
+                "message",
+                Json::Str(&format!(
+                    "peer speaks no protocol version we do (newest {version})"
+                )),
+            ),
+        ]),
+        Event::AnnounceFailed { infohash, error } => print_json(&[
+            ("event", Json::Str("error")),
+            (
+                "message",
+                Json::Str(&format!("announcing {infohash} failed: {error}")),
+            ),
+        ]),
+        Event::MailboxMessage { sender, payload } => print_json(&[
+            ("event", Json::Str("mailbox-message")),
+            ("sender", Json::Str(sender)),
+            ("payload", payload_json(payload)),
+            ("payload_encoding", payload_encoding(payload)),
+        ]),
+        Event::RecordReceived { peer_id, record } => {
+            let addresses = record.addresses();
+            print_json(&[
+                ("event", Json::Str("record")),
+                ("id", Json::Str(peer_id)),
+                (
+                    "addrs",
+                    Json::List(addresses.iter().map(ToString::to_string).collect()),
+                ),
+            ]);
+        }
+    }
+}
+
+/// The logger of `--output json`: warnings and errors, as JSON lines.
+struct JsonLog;
+
+impl log::Log for JsonLog {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let event = match record.level() {
+            log::Level::Error => "error",
+            _ => "warning",
+        };
+        print_json(&[
+            ("event", Json::Str(event)),
+            ("message", Json::Str(&record.args().to_string())),
+        ]);
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string(""), r#""""#);
+        assert_eq!(json_string(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(json_string(r"C:\dir\"), r#""C:\\dir\\""#);
+        assert_eq!(json_string("a\nb\rc\td"), r#""a\nb\rc\td""#);
+        assert_eq!(
+            json_string("\0\u{1}\u{1b}\u{1f}"),
+            r#""\u0000\u0001\u001b\u001f""#
+        );
+        // Space and DEL are not control characters JSON requires escaping.
+        assert_eq!(json_string(" \u{7f}"), "\" \u{7f}\"");
+        assert_eq!(json_string("héllo ✓ 🦀"), "\"héllo ✓ 🦀\"");
+    }
 
-        thread::sleep(Duration::from_secs(5));
+    #[test]
+    fn json_values_encode() {
+        assert_eq!(Json::Null.encode(), "null");
+        assert_eq!(Json::Str("x").encode(), r#""x""#);
+        assert_eq!(Json::Int(7).encode(), "7");
+        assert_eq!(Json::Float(f64::NAN).encode(), "null");
+        let list = Json::List(vec!["a\"".to_string(), "b".to_string()]);
+        assert_eq!(list.encode(), r#"["a\"","b"]"#);
     }
 }
@@ -0,0 +1,548 @@
+//! NAT-PMP (RFC 6886) and PCP (RFC 6887) port mapping, for routers that
+//! do not speak UPnP IGD: Apple's, and many that ISPs hand out.
+//!
+//! Both are a UDP exchange with the default gateway on port 5351. PCP is
+//! NAT-PMP's successor and answers a NAT-PMP request with an error, and the
+//! other way round, so both are asked at once and the first to answer is
+//! kept. A PCP mapping is named by a random nonce, sent again to renew or
+//! remove it.
+
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    endpoint,
+    portmap::{LEASE, Mapping},
+};
+
+const PORT: u16 = 5351;
+/// Wait for the first answer; doubled for each of the retries, as the
+/// RFCs ask, until `TIMEOUT` runs out.
+const FIRST_RETRY: Duration = Duration::from_millis(250);
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+const NAT_PMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+/// NAT-PMP opcodes; answers add 128.
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+/// PCP opcodes; answers set the top bit.
+const OP_ANNOUNCE: u8 = 0;
+const OP_MAP: u8 = 1;
+const UDP: u8 = 17;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum NatPmpError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("no NAT-PMP or PCP router answered")]
+    NoAnswer,
+    #[error("the router refused with {protocol} result {code}")]
+    Refused { protocol: &'static str, code: u16 },
+}
+
+type Result<T> = std::result::Result<T, NatPmpError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    NatPmp,
+    Pcp,
+}
+
+/// A router that answered NAT-PMP or PCP.
+pub(crate) struct Gateway {
+    addr: SocketAddrV4,
+    protocol: Protocol,
+    /// Our address on the router's network, which PCP requests name.
+    local_ip: Ipv4Addr,
+    /// Nonce of our PCP mapping.
+    nonce: [u8; 12],
+}
+
+impl Gateway {
+    /// Ask the default gateway over both protocols at once.
+    pub(crate) fn find() -> Result<Self> {
+        let router = default_gateway().ok_or(NatPmpError::NoAnswer)?;
+        let addr = SocketAddrV4::new(router, PORT);
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.connect(addr)?;
+        let IpAddr::V4(local_ip) = socket.local_addr()?.ip() else {
+            return Err(NatPmpError::NoAnswer);
+        };
+        let gateway = |protocol| Self {
+            addr,
+            protocol,
+            local_ip,
+            nonce: rand::random(),
+        };
+        let announce = pcp_header(OP_ANNOUNCE, Duration::ZERO, local_ip);
+        let requests = [vec![NAT_PMP_VERSION, OP_EXTERNAL_ADDRESS], announce];
+        let protocol = exchange(&socket, &requests, announce_answer)?;
+        Ok(gateway(protocol))
+    }
+
+    pub(crate) fn protocol(&self) -> &'static str {
+        match self.protocol {
+            Protocol::NatPmp => "NAT-PMP",
+            Protocol::Pcp => "PCP",
+        }
+    }
+
+    /// Forward UDP `external_port`, or whichever port the router picks
+    /// instead, to `local_port` of ours.
+    pub(crate) fn map(&self, local_port: u16, external_port: u16) -> Result<Mapping> {
+        match self.protocol {
+            Protocol::NatPmp => {
+                let (external_port, lease) = self.nat_pmp_map(local_port, external_port, LEASE)?;
+                let ip = self.nat_pmp_external_ip()?;
+                Ok(Mapping {
+                    external: SocketAddrV4::new(ip, external_port),
+                    lease,
+                })
+            }
+            Protocol::Pcp => self.pcp_map(local_port, external_port, LEASE),
+        }
+    }
+
+    /// Stop forwarding to `local_port`.
+    pub(crate) fn unmap(&self, local_port: u16) -> Result<()> {
+        match self.protocol {
+            Protocol::NatPmp => self.nat_pmp_map(local_port, 0, Duration::ZERO).map(drop),
+            Protocol::Pcp => self.pcp_map(local_port, 0, Duration::ZERO).map(drop),
+        }
+    }
+
+    fn socket(&self) -> Result<UdpSocket> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.connect(self.addr)?;
+        Ok(socket)
+    }
+
+    fn nat_pmp_map(
+        &self,
+        local_port: u16,
+        external_port: u16,
+        lease: Duration,
+    ) -> Result<(u16, Duration)> {
+        let mut request = vec![NAT_PMP_VERSION, OP_MAP_UDP, 0, 0];
+        request.extend_from_slice(&local_port.to_be_bytes());
+        request.extend_from_slice(&external_port.to_be_bytes());
+        request.extend_from_slice(&lease_secs(lease).to_be_bytes());
+        exchange(&self.socket()?, &[request], nat_pmp_map_answer)?
+    }
+
+    fn nat_pmp_external_ip(&self) -> Result<Ipv4Addr> {
+        let request = vec![NAT_PMP_VERSION, OP_EXTERNAL_ADDRESS];
+        exchange(&self.socket()?, &[request], nat_pmp_address_answer)?
+    }
+
+    fn pcp_map(&self, local_port: u16, external_port: u16, lease: Duration) -> Result<Mapping> {
+        let mut request = pcp_header(OP_MAP, lease, self.local_ip);
+        request.extend_from_slice(&self.nonce);
+        request.extend_from_slice(&[UDP, 0, 0, 0]);
+        request.extend_from_slice(&local_port.to_be_bytes());
+        request.extend_from_slice(&external_port.to_be_bytes());
+        // Any external address.
+        request.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+        exchange(&self.socket()?, &[request], |answer| {
+            pcp_map_answer(answer, &self.nonce)
+        })?
+    }
+}
+
+/// The protocol of an answer to our NAT-PMP or PCP announcement, or None
+/// for anything else, such as the other protocol's refusal, whose answer
+/// may still come.
+fn announce_answer(answer: &[u8]) -> Option<Protocol> {
+    match answer {
+        [NAT_PMP_VERSION, op, 0, 0, ..] if *op == 128 + OP_EXTERNAL_ADDRESS => {
+            Some(Protocol::NatPmp)
+        }
+        [PCP_VERSION, op, _, 0, ..] if *op == 0x80 | OP_ANNOUNCE => Some(Protocol::Pcp),
+        _ => None,
+    }
+}
+
+/// The result code of a NAT-PMP answer, which is at least `len` bytes of
+/// `opcode` (plus 128); None if it is not one.
+fn nat_pmp_answer(answer: &[u8], opcode: u8, len: usize) -> Option<Result<&[u8]>> {
+    if answer.len() < len || answer[..2] != [NAT_PMP_VERSION, 128 + opcode] {
+        return None;
+    }
+    let code = u16::from_be_bytes([answer[2], answer[3]]);
+    if code != 0 {
+        return Some(Err(NatPmpError::Refused {
+            protocol: "NAT-PMP",
+            code,
+        }));
+    }
+    Some(Ok(answer))
+}
+
+/// The external port and lease of a NAT-PMP mapping answer.
+fn nat_pmp_map_answer(answer: &[u8]) -> Option<Result<(u16, Duration)>> {
+    Some(nat_pmp_answer(answer, OP_MAP_UDP, 16)?.map(|answer| {
+        let external_port = u16::from_be_bytes([answer[10], answer[11]]);
+        let lease = u32::from_be_bytes([answer[12], answer[13], answer[14], answer[15]]);
+        (external_port, Duration::from_secs(lease.into()))
+    }))
+}
+
+/// The address of a NAT-PMP external address answer.
+fn nat_pmp_address_answer(answer: &[u8]) -> Option<Result<Ipv4Addr>> {
+    Some(
+        nat_pmp_answer(answer, OP_EXTERNAL_ADDRESS, 12)?
+            .map(|answer| Ipv4Addr::new(answer[8], answer[9], answer[10], answer[11])),
+    )
+}
+
+/// The mapping of a PCP MAP answer for the mapping named `nonce`.
+fn pcp_map_answer(answer: &[u8], nonce: &[u8; 12]) -> Option<Result<Mapping>> {
+    if answer.len() < 60
+        || answer[..2] != [PCP_VERSION, 0x80 | OP_MAP]
+        || answer[24..36] != nonce[..]
+    {
+        return None;
+    }
+    if answer[3] != 0 {
+        return Some(Err(NatPmpError::Refused {
+            protocol: "PCP",
+            code: answer[3].into(),
+        }));
+    }
+    let lease = u32::from_be_bytes([answer[4], answer[5], answer[6], answer[7]]);
+    let external_port = u16::from_be_bytes([answer[42], answer[43]]);
+    let ip = <[u8; 16]>::try_from(&answer[44..60]).expect("16 bytes");
+    let ip = Ipv6Addr::from(ip)
+        .to_ipv4_mapped()
+        .unwrap_or(Ipv4Addr::UNSPECIFIED);
+    Some(Ok(Mapping {
+        external: SocketAddrV4::new(ip, external_port),
+        lease: Duration::from_secs(lease.into()),
+    }))
+}
+
+/// The 24-byte header of PCP requests with `opcode`.
+fn pcp_header(opcode: u8, lease: Duration, local_ip: Ipv4Addr) -> Vec<u8> {
+    let mut header = vec![PCP_VERSION, opcode, 0, 0];
+    header.extend_from_slice(&lease_secs(lease).to_be_bytes());
+    header.extend_from_slice(&local_ip.to_ipv6_mapped().octets());
+    header
+}
+
+fn lease_secs(lease: Duration) -> u32 {
+    u32::try_from(lease.as_secs()).unwrap_or(u32::MAX)
+}
+
+/// Send `requests` until `accept` takes an answer or time runs out.
+fn exchange<T>(
+    socket: &UdpSocket,
+    requests: &[Vec<u8>],
+    mut accept: impl FnMut(&[u8]) -> Option<T>,
+) -> Result<T> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut retry = FIRST_RETRY;
+    let mut buf = [0u8; 1100];
+    loop {
+        for request in requests {
+            // Unreachable gateways show up as errors here on some systems.
+            if socket.send(request).is_err() {
+                return Err(NatPmpError::NoAnswer);
+            }
+        }
+        let resend = (Instant::now() + retry).min(deadline);
+        loop {
+            let left = resend.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            socket.set_read_timeout(Some(left))?;
+            match socket.recv(&mut buf) {
+                Ok(len) => {
+                    if let Some(accepted) = accept(&buf[..len]) {
+                        return Ok(accepted);
+                    }
+                }
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break;
+                }
+                Err(_) => return Err(NatPmpError::NoAnswer),
+            }
+        }
+        if Instant::now() >= deadline {
+            return Err(NatPmpError::NoAnswer);
+        }
+        retry *= 2;
+    }
+}
+
+/// The router that the default route goes through: from the kernel's
+/// routing table on Linux, else guessed as the first host of our LAN.
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = fs::read_to_string("/proc/net/route").unwrap_or_default();
+    if let Some(gateway) = route_gateway(&routes) {
+        return Some(gateway);
+    }
+    let [a, b, c, _] = endpoint::lan_address()?.octets();
+    Some(Ipv4Addr::new(a, b, c, 1))
+}
+
+/// The gateway of the default route in the table of `/proc/net/route`.
+fn route_gateway(routes: &str) -> Option<Ipv4Addr> {
+    // Destination and gateway are in memory order, printed as hex.
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let destination = u32::from_str_radix(fields.get(1)?, 16).ok()?;
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        (destination == 0 && gateway != 0).then(|| Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, thread};
+
+    use super::*;
+
+    const NONCE: [u8; 12] = [7; 12];
+
+    /// A NAT-PMP answer to `opcode` with result `code`, epoch 1000 and `body`.
+    fn nat_pmp(opcode: u8, code: u16, body: &[u8]) -> Vec<u8> {
+        let mut answer = vec![NAT_PMP_VERSION, 128 + opcode];
+        answer.extend_from_slice(&code.to_be_bytes());
+        answer.extend_from_slice(&1000u32.to_be_bytes());
+        answer.extend_from_slice(body);
+        answer
+    }
+
+    /// A PCP MAP answer for `nonce`: port 40000 of 203.0.113.7 for an hour.
+    fn pcp_map(code: u8, nonce: &[u8; 12]) -> Vec<u8> {
+        let mut answer = vec![PCP_VERSION, 0x80 | OP_MAP, 0, code];
+        answer.extend_from_slice(&3600u32.to_be_bytes());
+        answer.extend_from_slice(&1000u32.to_be_bytes());
+        answer.extend_from_slice(&[0; 12]);
+        answer.extend_from_slice(nonce);
+        answer.extend_from_slice(&[UDP, 0, 0, 0]);
+        answer.extend_from_slice(&6881u16.to_be_bytes());
+        answer.extend_from_slice(&40000u16.to_be_bytes());
+        answer.extend_from_slice(&Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped().octets());
+        answer
+    }
+
+    #[test]
+    fn announce_answers() {
+        let address = nat_pmp(OP_EXTERNAL_ADDRESS, 0, &[203, 0, 113, 7]);
+        assert_eq!(announce_answer(&address), Some(Protocol::NatPmp));
+        let mut pcp = pcp_header(OP_ANNOUNCE, Duration::ZERO, Ipv4Addr::LOCALHOST);
+        pcp[1] |= 0x80;
+        assert_eq!(announce_answer(&pcp), Some(Protocol::Pcp));
+        // Refusals: a PCP router's UNSUPP_VERSION to NAT-PMP, and a
+        // NAT-PMP router's to PCP.
+        assert_eq!(announce_answer(&[PCP_VERSION, 0x80, 0, 1]), None);
+        assert_eq!(
+            announce_answer(&nat_pmp(OP_EXTERNAL_ADDRESS, 1, &[0; 4])),
+            None
+        );
+        assert_eq!(announce_answer(&[NAT_PMP_VERSION, 128]), None);
+        assert_eq!(announce_answer(&[]), None);
+    }
+
+    #[test]
+    fn nat_pmp_answers() {
+        let mut body = Vec::from(6881u16.to_be_bytes());
+        body.extend_from_slice(&40000u16.to_be_bytes());
+        body.extend_from_slice(&7200u32.to_be_bytes());
+        let answer = nat_pmp(OP_MAP_UDP, 0, &body);
+        assert_eq!(answer.len(), 16);
+        let (port, lease) = nat_pmp_map_answer(&answer).unwrap().unwrap();
+        assert_eq!((port, lease), (40000, Duration::from_secs(7200)));
+        let answer = nat_pmp(OP_EXTERNAL_ADDRESS, 0, &[203, 0, 113, 7]);
+        assert_eq!(
+            nat_pmp_address_answer(&answer).unwrap().unwrap(),
+            Ipv4Addr::new(203, 0, 113, 7)
+        );
+        // Refused: not authorized.
+        assert!(matches!(
+            nat_pmp_map_answer(&nat_pmp(OP_MAP_UDP, 2, &body)),
+            Some(Err(NatPmpError::Refused {
+                protocol: "NAT-PMP",
+                code: 2
+            }))
+        ));
+    }
+
+    #[test]
+    fn refuses_other_nat_pmp_answers() {
+        let answer = nat_pmp(OP_MAP_UDP, 0, &[0; 8]);
+        for len in 0..answer.len() {
+            assert!(nat_pmp_map_answer(&answer[..len]).is_none(), "{len} bytes");
+        }
+        let address = nat_pmp(OP_EXTERNAL_ADDRESS, 0, &[1, 2, 3, 4]);
+        for len in 0..address.len() {
+            assert!(
+                nat_pmp_address_answer(&address[..len]).is_none(),
+                "{len} bytes"
+            );
+        }
+        // Answers to the other opcode, requests, and another version.
+        assert!(nat_pmp_map_answer(&nat_pmp(OP_EXTERNAL_ADDRESS, 0, &[0; 8])).is_none());
+        assert!(nat_pmp_address_answer(&nat_pmp(OP_MAP_UDP, 0, &[0; 8])).is_none());
+        let mut request = answer.clone();
+        request[1] = OP_MAP_UDP;
+        assert!(nat_pmp_map_answer(&request).is_none());
+        let mut other = answer;
+        other[0] = 1;
+        assert!(nat_pmp_map_answer(&other).is_none());
+    }
+
+    #[test]
+    fn pcp_answers() {
+        let mapping = pcp_map_answer(&pcp_map(0, &NONCE), &NONCE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(mapping.external, "203.0.113.7:40000".parse().unwrap());
+        assert_eq!(mapping.lease, Duration::from_secs(3600));
+        // Refused: NO_RESOURCES.
+        assert!(matches!(
+            pcp_map_answer(&pcp_map(8, &NONCE), &NONCE),
+            Some(Err(NatPmpError::Refused {
+                protocol: "PCP",
+                code: 8
+            }))
+        ));
+        // An IPv6 external address is no use here.
+        let mut answer = pcp_map(0, &NONCE);
+        answer[44..60].copy_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        let mapping = pcp_map_answer(&answer, &NONCE).unwrap().unwrap();
+        assert_eq!(*mapping.external.ip(), Ipv4Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn refuses_other_pcp_answers() {
+        let answer = pcp_map(0, &NONCE);
+        for len in 0..answer.len() {
+            assert!(
+                pcp_map_answer(&answer[..len], &NONCE).is_none(),
+                "{len} bytes"
+            );
+        }
+        // Someone else's mapping, a request, an announcement, NAT-PMP.
+        assert!(pcp_map_answer(&pcp_map(0, &[8; 12]), &NONCE).is_none());
+        for (at, byte) in [(1, OP_MAP), (1, 0x80 | OP_ANNOUNCE), (0, NAT_PMP_VERSION)] {
+            let mut other = answer.clone();
+            other[at] = byte;
+            assert!(pcp_map_answer(&other, &NONCE).is_none(), "{at}: {byte:#x}");
+        }
+    }
+
+    #[test]
+    fn pcp_requests() {
+        let header = pcp_header(
+            OP_MAP,
+            Duration::from_secs(3600),
+            Ipv4Addr::new(192, 168, 1, 20),
+        );
+        assert_eq!(
+            hex::encode(header),
+            "0201000000000e1000000000000000000000ffffc0a80114"
+        );
+        assert_eq!(lease_secs(Duration::from_secs(1 << 40)), u32::MAX);
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn default_routes() {
+        let routes = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+wg0\t0000000A\t00000000\t0001\t0\t0\t0\t000000FF\t0\t0\t0
+eth0\t00000000\t0102A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth0\t0002A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+";
+        assert_eq!(route_gateway(routes), Some(Ipv4Addr::new(192, 168, 2, 1)));
+        assert_eq!(route_gateway(""), None);
+        assert_eq!(route_gateway("Iface\nbogus\teth0\n"), None);
+    }
+
+    /// A router on localhost that answers each request with `answer`.
+    fn router(answer: impl Fn(&[u8]) -> Vec<u8> + Send + 'static, requests: usize) -> SocketAddrV4 {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let SocketAddr::V4(addr) = socket.local_addr().unwrap() else {
+            unreachable!("bound to IPv4")
+        };
+        thread::spawn(move || {
+            let mut buf = [0u8; 1100];
+            for _ in 0..requests {
+                let (len, from) = socket.recv_from(&mut buf).unwrap();
+                socket.send_to(&answer(&buf[..len]), from).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn gateway(addr: SocketAddrV4, protocol: Protocol) -> Gateway {
+        Gateway {
+            addr,
+            protocol,
+            local_ip: Ipv4Addr::LOCALHOST,
+            nonce: NONCE,
+        }
+    }
+
+    #[test]
+    fn maps_with_nat_pmp() {
+        let addr = router(
+            |request| match request {
+                [
+                    NAT_PMP_VERSION,
+                    OP_MAP_UDP,
+                    0,
+                    0,
+                    internal @ ..,
+                    _,
+                    _,
+                    _,
+                    _,
+                    _,
+                    _,
+                ] => {
+                    let mut body = internal.to_vec();
+                    body.extend_from_slice(&40000u16.to_be_bytes());
+                    body.extend_from_slice(&request[8..12]);
+                    nat_pmp(OP_MAP_UDP, 0, &body)
+                }
+                [NAT_PMP_VERSION, OP_EXTERNAL_ADDRESS] => {
+                    nat_pmp(OP_EXTERNAL_ADDRESS, 0, &[203, 0, 113, 7])
+                }
+                _ => vec![NAT_PMP_VERSION, 128, 0, 5],
+            },
+            2,
+        );
+        let mapping = gateway(addr, Protocol::NatPmp).map(6881, 6881).unwrap();
+        assert_eq!(mapping.external, "203.0.113.7:40000".parse().unwrap());
+        assert_eq!(mapping.lease, LEASE);
+    }
+
+    #[test]
+    fn maps_with_pcp() {
+        let addr = router(
+            |request| {
+                assert_eq!(request.len(), 60);
+                assert_eq!(&request[..2], [PCP_VERSION, OP_MAP]);
+                assert_eq!(&request[24..36], NONCE);
+                assert_eq!(&request[40..42], 6881u16.to_be_bytes());
+                pcp_map(0, &NONCE)
+            },
+            1,
+        );
+        let mapping = gateway(addr, Protocol::Pcp).map(6881, 6881).unwrap();
+        assert_eq!(mapping.external, "203.0.113.7:40000".parse().unwrap());
+    }
+}
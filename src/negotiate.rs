@@ -0,0 +1,157 @@
+//! Negotiating which kind of path two peers keep when several work.
+//!
+//! Hellos and acks carry an extension record of kind [`TRANSPORTS`] listing
+//! the kinds of path the node takes part in, each with a cost, lowest
+//! preferred: `[kind u8][cost u8]...`. A path costs the pair the sum of both
+//! sides' costs for its kind, and a kind only one side lists is out. Of the
+//! addresses a peer talks from, the node keeps the cheapest; lookup loops
+//! move a peer reached over UDP to TCP when both list TCP cheaper.
+//!
+//! Peers without the record, from before it, are taken to list every kind
+//! at its default cost. Kinds this version does not know are skipped.
+
+use std::{fmt, str::FromStr};
+
+/// Extension kind of the transport record, reserved for dhtmsg.
+pub(crate) const TRANSPORTS: u16 = 0x0001;
+/// Most bytes the record takes in the extension area, header included.
+pub(crate) const MAX_RECORD_BYTES: usize = 4 + 2 * TransportKind::ALL.len();
+
+/// A kind of path between two peers, which hellos and acks offer with a cost
+/// (see [`DhtMsgBuilder::transport_cost`](crate::DhtMsgBuilder::transport_cost)).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportKind {
+    /// Datagrams between hello sockets, directly or through holes punched.
+    Udp,
+    /// Datagrams framed over a TCP connection to the hello port.
+    Tcp,
+    /// Datagrams through the TURN server of one of the peers.
+    Turn,
+    /// Datagrams through another dhtmsg node relaying for the peers.
+    Relay,
+    /// Datagrams framed over a TCP connection through Tor to an onion
+    /// service of one of the peers.
+    Tor,
+}
+
+impl TransportKind {
+    pub const ALL: [Self; 5] = [Self::Udp, Self::Tcp, Self::Turn, Self::Relay, Self::Tor];
+
+    /// Cost offered unless configured otherwise: direct paths first, then
+    /// those through a server, then relays, then Tor.
+    pub const fn default_cost(self) -> u8 {
+        match self {
+            Self::Udp => 10,
+            Self::Tcp => 20,
+            Self::Turn => 30,
+            Self::Relay => 40,
+            Self::Tor => 50,
+        }
+    }
+
+    const fn code(self) -> u8 {
+        match self {
+            Self::Udp => 0,
+            Self::Tcp => 1,
+            Self::Turn => 2,
+            Self::Relay => 3,
+            Self::Tor => 4,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.code() == code)
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Udp => "udp",
+            Self::Tcp => "tcp",
+            Self::Turn => "turn",
+            Self::Relay => "relay",
+            Self::Tor => "tor",
+        }
+    }
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown transport {s:?} (udp, tcp, turn, relay or tor)"))
+    }
+}
+
+/// The kinds of path a node takes part in, and what each costs it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Offer(Vec<(TransportKind, u8)>);
+
+impl Offer {
+    /// `kinds` at the costs in `costs`, or else at their defaults.
+    pub(crate) fn new(kinds: &[TransportKind], costs: &[(TransportKind, u8)]) -> Self {
+        Self(
+            kinds
+                .iter()
+                .map(|&kind| {
+                    let cost = costs.iter().rev().find(|(set, _)| *set == kind);
+                    (kind, cost.map_or(kind.default_cost(), |(_, cost)| *cost))
+                })
+                .collect(),
+        )
+    }
+
+    /// What peers that send no record are taken to offer.
+    pub(crate) fn legacy() -> Self {
+        Self::new(&TransportKind::ALL, &[])
+    }
+
+    pub(crate) fn cost(&self, kind: TransportKind) -> Option<u8> {
+        self.0
+            .iter()
+            .find(|(offered, _)| *offered == kind)
+            .map(|(_, cost)| *cost)
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        self.0
+            .iter()
+            .flat_map(|(kind, cost)| [kind.code(), *cost])
+            .collect()
+    }
+
+    /// The offer in a record value; None if it is not pairs of bytes.
+    pub(crate) fn parse(value: &[u8]) -> Option<Self> {
+        let (pairs, []) = value.as_chunks::<2>() else {
+            return None;
+        };
+        let offered = pairs
+            .iter()
+            .filter_map(|&[code, cost]| Some((TransportKind::from_code(code)?, cost)))
+            .collect();
+        Some(Self(offered))
+    }
+
+    /// What a path of `kind` costs us and the peer offering `theirs`
+    /// together; None unless both offer it.
+    pub(crate) fn pair_cost(&self, theirs: &Self, kind: TransportKind) -> Option<u16> {
+        Some(u16::from(self.cost(kind)?) + u16::from(theirs.cost(kind)?))
+    }
+
+    /// The cheapest kind both we and `theirs` offer, UDP first on a tie.
+    pub(crate) fn best(&self, theirs: &Self) -> Option<TransportKind> {
+        TransportKind::ALL
+            .into_iter()
+            .filter_map(|kind| Some((self.pair_cost(theirs, kind)?, kind)))
+            .min_by_key(|(cost, kind)| (*cost, kind.code()))
+            .map(|(_, kind)| kind)
+    }
+}
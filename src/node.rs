@@ -0,0 +1,4735 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, VerifyingKey};
+use log::{debug, error, info, trace, warn};
+use mainline::{Id, MutableItem};
+
+#[cfg(feature = "cbor")]
+use crate::schema;
+use crate::{
+    address::{self, Scope},
+    birthday::{self, Prediction, SprayTransport},
+    capabilities::Capabilities,
+    challenge::Challenges,
+    channel, compress,
+    control::Control,
+    dedup::Dedup,
+    delegation::{self, Delegation, MAX_DELEGATIONS},
+    endpoint::{self, EndpointRecord, Published},
+    error::{DhtMsgError, Result},
+    event::{Event, Events},
+    extension::Extensions,
+    fragment::{self, MAX_FRAGMENTED_BYTES, Reassembler, is_fragment},
+    id::{
+        Identity, derive_infohash, derive_pairwise_infohash, derive_relay_infohash,
+        derive_replica_infohash, derive_rotating_infohash, derive_salted_infohash, public_key,
+    },
+    mailbox::{self, MAX_MAILBOX_BYTES, MAX_MAILBOX_MESSAGES},
+    negotiate::{self, Offer, TransportKind},
+    noise::Rekey,
+    onion,
+    outbox::Outbox,
+    pair::{Code, Paired, Pairing},
+    portmap::{self, Router},
+    psk::PskTransport,
+    punch,
+    relay::{self, RelayTransport},
+    replay::ReplayGuard,
+    sas::Sas,
+    schedule::AnnounceSchedule,
+    sealed_box,
+    session::SecureTransport,
+    shutdown::ShutdownHandle,
+    socks::{self, SocksError, Unrelayed},
+    stats::{DhtStats, QueryCounts},
+    stream::{PeerStream, Streams, is_stream_frame},
+    stun::{self, NatMapping},
+    tcp::TcpTransport,
+    transport::{self, Bind, HelloSocket, SharedTransport, Transport},
+    turn::{self, TurnTransport},
+    utp::{UtpStream, UtpTransport},
+    wire::{self, Frame, FrameType, Rejected},
+};
+
+/// Largest message datagram sent in one piece; bigger ones are fragmented.
+/// Sealing it for the session and a pre-shared key tag add about 140 bytes,
+/// still within the MTU.
+const MAX_DATAGRAM_BYTES: usize = 1280;
+/// Receive buffer, large enough that no UDP datagram gets truncated.
+const RECV_BUFFER_BYTES: usize = 65536;
+/// How often the receiver looks for datagrams while uTP connections or
+/// streams are open, rather than every 200 ms.
+const STREAM_POLL: Duration = Duration::from_millis(5);
+/// Wait for the first ack of a reliable message; doubled after every attempt.
+const MESSAGE_INITIAL_RTO: Duration = Duration::from_millis(500);
+/// Attempts before a reliable message is reported undelivered.
+const MESSAGE_ATTEMPTS: u32 = 6;
+/// Room for extension records, leaving the rest of a hello unfragmented.
+const MAX_EXTENSION_BYTES: usize = 1024;
+/// Default of [`DhtMsgBuilder::replay_window`].
+const DEFAULT_REPLAY_WINDOW: Duration = Duration::from_secs(300);
+/// Defaults of [`DhtMsgBuilder::rekey_interval`] and
+/// [`DhtMsgBuilder::rekey_bytes`].
+const DEFAULT_REKEY_INTERVAL: Duration = Duration::from_secs(120);
+const DEFAULT_REKEY_BYTES: u64 = 64 * 1024 * 1024;
+/// Default of [`DhtMsgBuilder::keepalive_interval`], below the 30 seconds
+/// after which many NATs drop an idle UDP mapping.
+const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+/// Peers silent for this long are taken to be gone and no longer pinged.
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(300);
+/// How often queued messages are checked for an expired TTL.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait for an answer at the pairing rendezvous before looking it up again.
+const PAIRING_RETRY: Duration = Duration::from_secs(2);
+/// How often the pairing rendezvous is announced.
+const PAIRING_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Default of [`DhtMsgBuilder::bootstrap_timeout`].
+const DEFAULT_BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Pause between bootstrap attempts that found no node.
+const BOOTSTRAP_RETRY: Duration = Duration::from_millis(500);
+/// How often the routing table is looked at during bootstrap.
+const BOOTSTRAP_POLL: Duration = Duration::from_millis(100);
+/// Nodes in the routing table that make the DHT usable before the bootstrap
+/// lookup ends, as happens quickly with a routing table cache.
+const BOOTSTRAP_READY_NODES: usize = 8;
+/// Pause between rounds of hellos to the candidates of a peer to verify.
+const VERIFY_RETRY: Duration = Duration::from_secs(2);
+/// How often announcer threads look for a new public address.
+const ADDRESS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Most infohashes announced per infohash, counting the usual one.
+const MAX_INFOHASH_REPLICAS: u8 = 8;
+/// Datagrams sent to each candidate when punching holes.
+const PUNCH_PACKETS: u32 = 10;
+/// Pause between those; ten of them cover clocks a second or so apart.
+const PUNCH_SPACING: Duration = Duration::from_millis(200);
+/// How long lookup loops wait for a peer with candidates to answer before
+/// asking it to punch holes.
+const PUNCH_AFTER: Duration = Duration::from_secs(15);
+/// How often lookup loops ask again after that.
+const PUNCH_INTERVAL: Duration = Duration::from_secs(60);
+/// How often a node that asked for a punch looks for the peer's answer.
+const PUNCH_ANSWER_POLL: Duration = Duration::from_secs(3);
+/// How long after a sprayed burst hellos to the ports that stayed silent
+/// are given up, before their handshakes would time out one by one.
+const SPRAY_LINGER: Duration = Duration::from_secs(2);
+/// How long lookup loops wait for a peer to answer over UDP before
+/// connecting to its candidates over TCP, and how often they try again.
+const TCP_AFTER: Duration = Duration::from_secs(30);
+const TCP_INTERVAL: Duration = Duration::from_secs(120);
+/// How long lookup loops wait for a peer to answer before trying the
+/// relays its record names, and how often they try again.
+const RELAY_AFTER: Duration = Duration::from_secs(45);
+const RELAY_INTERVAL: Duration = Duration::from_secs(120);
+/// How long lookup loops wait for a peer to answer before connecting to
+/// its onion service through Tor, and how often they try again.
+const ONION_AFTER: Duration = Duration::from_secs(60);
+const ONION_INTERVAL: Duration = Duration::from_secs(120);
+/// How often lookup loops publish our onion record for their peer again;
+/// DHT nodes drop items after two hours or so.
+const ONION_REPUBLISH: Duration = Duration::from_secs(30 * 60);
+/// How often the relay keeper looks at its relays.
+const RELAY_TICK: Duration = Duration::from_secs(5);
+/// Wait before asking the router again after it refused a port mapping.
+const PORT_MAPPING_RETRY: Duration = Duration::from_secs(300);
+/// How long a reached peer may stay silent before lookup loops look it up
+/// at their full pace again, at least; three keepalive intervals if longer.
+const PEER_SILENCE: Duration = Duration::from_secs(60);
+/// How often lookup loops look up a peer that talks to us, in case it
+/// moved.
+const REACHED_LOOKUP_INTERVAL: Duration = Duration::from_secs(300);
+
+/// What a peer told us about itself in its latest hello or ack.
+#[derive(Debug, Clone, Default)]
+struct PeerInfo {
+    /// Protocol version agreed on with the peer.
+    version: u8,
+    caps: Capabilities,
+    extensions: Extensions,
+    /// Key of the ID the hello or ack came from.
+    key: Option<VerifyingKey>,
+}
+
+/// Largest message payload a node accepts, and the default limit.
+pub const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// Largest IP packet [`DhtMsg::send_packet`] takes: what fits in one
+/// datagram, and so the MTU to give a VPN's TUN interface.
+pub const MAX_PACKET_BYTES: usize = MAX_DATAGRAM_BYTES - wire::OVERHEAD_BYTES;
+/// VPN packets waiting for the application; more are dropped, as a busy
+/// network interface drops them.
+const PACKET_QUEUE: usize = 256;
+
+type PacketSender = flume::Sender<(SocketAddr, Vec<u8>)>;
+type PacketReceiver = flume::Receiver<(SocketAddr, Vec<u8>)>;
+
+/// Reliable messages waiting for their ack, keyed by peer and sequence number.
+type PendingAcks = Mutex<HashMap<(SocketAddr, u32), flume::Sender<()>>>;
+
+/// Configures and starts a [`DhtMsg`] node.
+#[derive(Clone)]
+pub struct DhtMsgBuilder {
+    identity: Option<Identity>,
+    discover_port: bool,
+    bootstrap: Vec<String>,
+    private_dht: bool,
+    routing_cache: Option<PathBuf>,
+    bootstrap_timeout: Duration,
+    dht_server: bool,
+    public_ip: Option<Ipv4Addr>,
+    allow_private: bool,
+    lan_only: bool,
+    port_mapping: bool,
+    stun: bool,
+    stun_servers: Vec<String>,
+    port_prediction: bool,
+    turn: Option<turn::Credentials>,
+    tcp_fallback: bool,
+    transport_costs: Vec<(TransportKind, u8)>,
+    proxy: Option<String>,
+    proxy_login: Option<(String, String)>,
+    bind: Bind,
+    onion: bool,
+    onion_only: bool,
+    tor_control: Option<String>,
+    tor_socks: Option<String>,
+    transport: Option<SharedTransport>,
+    outbox_dir: Option<PathBuf>,
+    compression: bool,
+    capabilities: Capabilities,
+    extensions: Extensions,
+    max_message_bytes: usize,
+    plaintext: bool,
+    sealed_box: bool,
+    psk: Option<Vec<u8>>,
+    infohash_salt: Vec<u8>,
+    infohash_period: Option<Duration>,
+    infohash_replicas: u8,
+    signed_endpoints: bool,
+    signaling: bool,
+    relay_hints: Vec<String>,
+    relay: bool,
+    use_relay: bool,
+    pairwise_peers: Vec<String>,
+    delegations: Vec<Delegation>,
+    replay_window: Duration,
+    rekey: Rekey,
+    keepalive: Duration,
+    allowed_peers: Vec<String>,
+}
+
+impl Default for DhtMsgBuilder {
+    fn default() -> Self {
+        Self {
+            identity: None,
+            discover_port: true,
+            bootstrap: Vec::new(),
+            private_dht: false,
+            routing_cache: None,
+            bootstrap_timeout: DEFAULT_BOOTSTRAP_TIMEOUT,
+            dht_server: false,
+            public_ip: None,
+            allow_private: false,
+            lan_only: false,
+            port_mapping: true,
+            stun: true,
+            stun_servers: Vec::new(),
+            port_prediction: true,
+            turn: None,
+            tcp_fallback: true,
+            transport_costs: Vec::new(),
+            proxy: None,
+            proxy_login: None,
+            bind: Bind::default(),
+            onion: false,
+            onion_only: false,
+            tor_control: None,
+            tor_socks: None,
+            transport: None,
+            outbox_dir: None,
+            compression: true,
+            capabilities: Capabilities::empty(),
+            extensions: Extensions::default(),
+            max_message_bytes: MAX_MESSAGE_BYTES,
+            plaintext: false,
+            sealed_box: false,
+            psk: None,
+            infohash_salt: Vec::new(),
+            infohash_period: None,
+            infohash_replicas: 1,
+            signed_endpoints: false,
+            signaling: false,
+            relay_hints: Vec::new(),
+            relay: false,
+            use_relay: false,
+            pairwise_peers: Vec::new(),
+            delegations: Vec::new(),
+            replay_window: DEFAULT_REPLAY_WINDOW,
+            rekey: Rekey {
+                interval: DEFAULT_REKEY_INTERVAL,
+                bytes: DEFAULT_REKEY_BYTES,
+            },
+            keepalive: DEFAULT_KEEPALIVE_INTERVAL,
+            allowed_peers: Vec::new(),
+        }
+    }
+}
+
+impl DhtMsgBuilder {
+    /// Sign everything with `identity`, whose public key is the node's ID
+    /// (a new random identity if not set).
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Bind the hello socket, its TCP listener and the sockets of bursts to
+    /// `ip` alone, for hosts with several addresses or a VPN, instead of to
+    /// every address. The socket then takes the family of `ip` only. The
+    /// DHT binds every address regardless, but on Linux is kept on the
+    /// interface holding `ip`, as with [`interface`](Self::interface), or
+    /// building fails.
+    pub fn bind_addr(mut self, ip: IpAddr) -> Self {
+        self.bind.ip = Some(ip);
+        self
+    }
+
+    /// Keep the hello socket, its TCP listener, the sockets of bursts and
+    /// the DHT's socket on the network interface `name`, with
+    /// `SO_BINDTODEVICE`, whatever the routing table says. Linux only;
+    /// building fails elsewhere, or if the DHT's socket cannot be kept on
+    /// `name`. Mainline binds that socket itself and starts bootstrapping
+    /// at once, so its first few queries, sent before it is kept on `name`,
+    /// follow the routing table.
+    pub fn interface(mut self, name: impl Into<String>) -> Self {
+        self.bind.interface = Some(name.into());
+        self
+    }
+
+    /// Learn the public port before binding the hello socket, over
+    /// [STUN](Self::stun) or else with a short-lived DHT (enabled by
+    /// default). When disabled, an ephemeral port is announced as-is.
+    pub fn discover_port(mut self, discover: bool) -> Self {
+        self.discover_port = discover;
+        self
+    }
+
+    /// Bootstrap from `node` (`host:port`) rather than the default
+    /// bootstrap nodes of the mainline DHT; repeat for several. Names that
+    /// do not resolve are skipped. For private or test networks, or where
+    /// the default nodes are blocked.
+    pub fn bootstrap(mut self, node: impl Into<String>) -> Self {
+        self.bootstrap.push(node.into());
+        self
+    }
+
+    /// Join only the DHT that the [`bootstrap`](Self::bootstrap) nodes
+    /// belong to, never the public mainline one (disabled by default): the
+    /// public bootstrap nodes are not tried even after the routing table
+    /// cache, and building fails without bootstrap nodes. For rendezvous
+    /// networks kept apart from the public DHT.
+    pub fn private_dht(mut self, enabled: bool) -> Self {
+        self.private_dht = enabled;
+        self
+    }
+
+    /// Keep the DHT nodes we know in `path`, one `host:port` per line, and
+    /// bootstrap from them first on the next start, which is quicker than
+    /// going through the bootstrap nodes alone. The file is written on
+    /// every announcement and on shutdown.
+    /// The DHT node ID is not kept: mainline chooses it, from our public IP
+    /// once known (BEP 42), and cannot be given one.
+    pub fn routing_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.routing_cache = Some(path.into());
+        self
+    }
+
+    /// Ask the router to forward the hello port over UPnP IGD, NAT-PMP or
+    /// PCP, whichever it answers first, and announce the external port it
+    /// gives (enabled by default). The port the DHT
+    /// sees is that of the DHT's own socket, so it is only a guess for the
+    /// hello socket; a forwarded port is not. The mapping is renewed while
+    /// the [announcer](DhtMsg::spawn_announcer) runs and removed on
+    /// shutdown. Nothing is mapped for a custom
+    /// [transport](Self::transport).
+    pub fn port_mapping(mut self, enabled: bool) -> Self {
+        self.port_mapping = enabled;
+        self
+    }
+
+    /// Ask STUN servers for the hello socket's public address during
+    /// [port discovery](Self::discover_port) (enabled by default), and
+    /// fall back to a short-lived DHT if none answers. A STUN server sees
+    /// the hello socket itself, where the DHT only sees its own socket, and
+    /// it answers at once. With a [private DHT](Self::private_dht), only
+    /// [configured](Self::stun_server) servers are asked.
+    pub fn stun(mut self, enabled: bool) -> Self {
+        self.stun = enabled;
+        self
+    }
+
+    /// Ask `server` (`host:port`) over STUN rather than the default public
+    /// servers; repeat for several. Two servers at different addresses also
+    /// tell whether the NAT keeps one public port for every destination
+    /// (see [`DhtMsg::nat_mapping`]).
+    pub fn stun_server(mut self, server: impl Into<String>) -> Self {
+        self.stun_servers.push(server.into());
+        self
+    }
+
+    /// Predict the NAT's next ports and spray hellos from many sockets when
+    /// [punching holes](DhtMsg::punch), if STUN found that the NAT gives
+    /// each destination another port (enabled by default; see the
+    /// `birthday` module). Peers without it still answer such punches from
+    /// one port.
+    pub fn port_prediction(mut self, enabled: bool) -> Self {
+        self.port_prediction = enabled;
+        self
+    }
+
+    /// Allocate a relayed address on the TURN server `server`
+    /// (`host:port`) with the account `username` and `password`, and
+    /// publish it in our endpoint record as one more candidate (implies
+    /// [signaling](Self::signaling)). Peers need no TURN of their own; the
+    /// server passes on what they send once we sent to them, looking them
+    /// up or answering their punch. For networks that run a TURN server
+    /// anyway; if the allocation fails, the node goes on without one.
+    pub fn turn_server(
+        mut self,
+        server: impl Into<String>,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.turn = Some(turn::Credentials {
+            server: server.into(),
+            username: username.into(),
+            password: password.into(),
+        });
+        self
+    }
+
+    /// Listen on the hello port over TCP as well, and connect to the
+    /// candidates of a peer that [lookup loops](DhtMsg::spawn_lookup) got
+    /// no answer from over UDP for 30 seconds, for networks that drop UDP
+    /// they do not know (enabled by default). Datagrams then go through
+    /// the connection, and everything else works as over UDP. Nothing
+    /// listens for a custom [transport](Self::transport).
+    pub fn tcp_fallback(mut self, enabled: bool) -> Self {
+        self.tcp_fallback = enabled;
+        self
+    }
+
+    /// Send hello traffic through the SOCKS5 proxy at `server`
+    /// (`host:port`), for networks that only let traffic out through one.
+    /// Datagrams go through a UDP association on the proxy, whose address
+    /// is published in our endpoint record as one more candidate (implies
+    /// [signaling](Self::signaling)), and [TCP fallback](Self::tcp_fallback)
+    /// connects through it; with a proxy that relays no UDP, peers are only
+    /// reached that way. Port discovery and mapping are skipped. The DHT
+    /// still goes direct: mainline binds a socket of its own.
+    pub fn socks5_proxy(mut self, server: impl Into<String>) -> Self {
+        self.proxy = Some(server.into());
+        self
+    }
+
+    /// Log in to the [SOCKS5 proxy](Self::socks5_proxy) as `username` with
+    /// `password`, if it asks.
+    pub fn socks5_login(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.proxy_login = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Ask the Tor daemon for an onion service forwarding to the hello
+    /// port over TCP, and tell each peer we look up, and each
+    /// [allowed](Self::allow_peer) one, its address in a record sealed to
+    /// the peer. [Lookup loops](DhtMsg::spawn_lookup) whose peer has not
+    /// answered for a minute connect to the peer's service through Tor's
+    /// SOCKS port. Tor must run with its control port open (see
+    /// [`tor_control`](Self::tor_control)); if the service cannot be made,
+    /// the node goes on without one. Needs the hello socket.
+    pub fn onion(mut self, enabled: bool) -> Self {
+        self.onion = enabled;
+        self
+    }
+
+    /// Reach peers through Tor only, so that they never learn our IP
+    /// address: implies [`onion`](Self::onion), announces neither
+    /// infohashes nor endpoint records, and has lookup loops go straight
+    /// to the peer's onion service. Only peers that run onion mode too can
+    /// be reached, and building fails without the service.
+    pub fn onion_only(mut self, enabled: bool) -> Self {
+        self.onion_only = enabled;
+        self
+    }
+
+    /// Where Tor's control port listens (`host:port`), by default
+    /// `127.0.0.1:9051`.
+    pub fn tor_control(mut self, control: impl Into<String>) -> Self {
+        self.tor_control = Some(control.into());
+        self
+    }
+
+    /// Where Tor's SOCKS port listens (`host:port`), by default
+    /// `127.0.0.1:9050`.
+    pub fn tor_socks(mut self, socks: impl Into<String>) -> Self {
+        self.tor_socks = Some(socks.into());
+        self
+    }
+
+    /// Offer paths of `kind` to peers at `cost` rather than the
+    /// [default](TransportKind::default_cost), lowest preferred. Hellos and
+    /// acks carry each side's costs, and of the paths that work with a
+    /// peer, the one costing both sides least together is kept; TCP is
+    /// only offered with [TCP fallback](Self::tcp_fallback). Say UDP is
+    /// throttled here: a UDP cost above TCP's moves peers that listen over
+    /// TCP there once reached.
+    pub fn transport_cost(mut self, kind: TransportKind, cost: u8) -> Self {
+        self.transport_costs.push((kind, cost));
+        self
+    }
+
+    /// Give up building the node if the DHT has not found a single node
+    /// after `timeout` (30 seconds by default).
+    pub fn bootstrap_timeout(mut self, timeout: Duration) -> Self {
+        self.bootstrap_timeout = timeout;
+        self
+    }
+
+    /// Run the long-lived DHT as a full node from the start, answering other
+    /// nodes' queries and storing their announcements and items (disabled
+    /// by default). That helps the network and, with traffic coming in all
+    /// the time, keeps NAT mappings open. Otherwise the DHT starts as a
+    /// client that only sends queries, which suits constrained devices, and
+    /// turns into a full node once it has run for a while and found that
+    /// other nodes can reach it; mainline offers no way to stay a client.
+    pub fn dht_server_mode(mut self, enabled: bool) -> Self {
+        self.dht_server = enabled;
+        self
+    }
+
+    /// Derive the DHT node ID from `ip`, our public address, as BEP 42 asks;
+    /// other nodes may ignore an ID that does not match where it comes from.
+    /// Port discovery learns the address too, so this is for when it is
+    /// [disabled](Self::discover_port); otherwise mainline derives the ID
+    /// once the DHT has confirmed our address.
+    pub fn public_ip(mut self, ip: Ipv4Addr) -> Self {
+        self.public_ip = Some(ip);
+        self
+    }
+
+    /// Also take private addresses (RFC 1918, loopback, link-local, unique
+    /// local and carrier-grade NAT ones) found in lookups for candidates
+    /// (disabled by default). Useful on private networks; elsewhere a
+    /// hello to a private address is wasted. Either way, the LAN
+    /// candidates in the record of a peer behind our own public address
+    /// are taken, and tried first: the peer shares our network, and many
+    /// NATs do not pass datagrams from inside back in to their public
+    /// address. Addresses nothing can answer at, such as multicast ones or
+    /// port 0, are dropped either way.
+    pub fn allow_private(mut self, enabled: bool) -> Self {
+        self.allow_private = enabled;
+        self
+    }
+
+    /// Only take private addresses for candidates, and publish no public
+    /// ones but the address the DHT sees, which endpoint records require
+    /// (disabled by default). For peers that must only meet on the local
+    /// network, such as in a lab.
+    pub fn lan_only(mut self, enabled: bool) -> Self {
+        self.lan_only = enabled;
+        self
+    }
+
+    /// Carry hello traffic over `transport` instead of binding a UDP socket.
+    /// Port discovery is skipped; the transport's local port is announced.
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Keep messages queued with [`DhtMsg::queue_message`] in `dir`, so they
+    /// survive restarts. Without it the queue lives in memory.
+    pub fn outbox_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.outbox_dir = Some(dir.into());
+        self
+    }
+
+    /// Advertise LZ4 support and compress large messages to peers that
+    /// advertise it too (enabled by default).
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// Refuse to send message payloads over `max` bytes and drop (without
+    /// acknowledging) received ones over it. Defaults to and is capped at
+    /// [`MAX_MESSAGE_BYTES`](crate::MAX_MESSAGE_BYTES).
+    pub fn max_message_bytes(mut self, max: usize) -> Self {
+        self.max_message_bytes = max.min(MAX_MESSAGE_BYTES);
+        self
+    }
+
+    /// Also talk unencrypted, as nodes did before sessions were encrypted:
+    /// accept plaintext frames, and send them to peers without a session
+    /// instead of starting a handshake (disabled by default). Peers that
+    /// start one still get an encrypted session.
+    pub fn plaintext(mut self, enabled: bool) -> Self {
+        self.plaintext = enabled;
+        self
+    }
+
+    /// Instead of starting Noise sessions, encrypt every message payload to
+    /// the recipient's ID with a fresh X25519 key (disabled by default).
+    /// Hellos and acks then travel in the clear; payload-carrying frames that
+    /// are not encrypted either way are dropped unless
+    /// [`plaintext`](Self::plaintext) allows them. Streams need a session
+    /// that the peer started, and messages are not compressed.
+    pub fn sealed_box(mut self, enabled: bool) -> Self {
+        self.sealed_box = enabled;
+        self
+    }
+
+    /// Tag every frame with an HMAC keyed with `key` and the current time,
+    /// and drop received frames whose tag is missing, wrong or more than
+    /// five minutes off. Only peers configured with the same key get
+    /// through, hellos and acks included.
+    pub fn psk(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.psk = Some(key.into());
+        self
+    }
+
+    /// Derive our infohash and those of the peers we look up from the ID and
+    /// `salt` (see [`derive_salted_infohash`](crate::derive_salted_infohash)),
+    /// so that only those who know the salt can find or watch where we
+    /// announce. Peers must use the same salt to find each other.
+    pub fn infohash_salt(mut self, salt: impl Into<Vec<u8>>) -> Self {
+        self.infohash_salt = salt.into();
+        self
+    }
+
+    /// Derive infohashes anew every `period` from the ID and the time window
+    /// (see [`derive_rotating_infohash`](crate::derive_rotating_infohash)),
+    /// so that a long-lived node is not seen under one infohash forever. The
+    /// windows before and after the current one are announced and looked up
+    /// too, which covers clocks a little apart. Peers must use the same
+    /// period to find each other.
+    pub fn rotate_infohash(mut self, period: Duration) -> Self {
+        self.infohash_period = Some(period).filter(|period| period.as_secs() > 0);
+        self
+    }
+
+    /// Announce and look up `count` infohashes instead of one (up to
+    /// eight): the usual one and replicas of it (see
+    /// [`derive_replica_infohash`](crate::derive_replica_infohash)). The
+    /// DHT nodes closest to an infohash may be dead or hostile and drop
+    /// announcements; the replicas lie elsewhere in the DHT, so the peer
+    /// still finds us. Each replica costs one more announcement and lookup.
+    /// Peers should use the same count; one with fewer still finds us
+    /// under the replicas it looks up.
+    pub fn infohash_replicas(mut self, count: u8) -> Self {
+        self.infohash_replicas = count.clamp(1, MAX_INFOHASH_REPLICAS);
+        self
+    }
+
+    /// Publish our address as a BEP 44 mutable item signed by the identity
+    /// instead of announcing the infohash, and find peers by their signed
+    /// items instead of `get_peers` (disabled by default). Anyone can
+    /// announce any address under an infohash, but only the peer can sign
+    /// its record, so lookups return no address the peer did not publish.
+    /// Both sides must enable it. Salt and rotation apply to records as
+    /// they do to infohashes.
+    pub fn signed_endpoints(mut self, enabled: bool) -> Self {
+        self.signed_endpoints = enabled;
+        self
+    }
+
+    /// Publish our endpoint record alongside the announcements, and fetch
+    /// the peer's during lookups (disabled by default; implied by
+    /// [`signed_endpoints`](Self::signed_endpoints)). Records carry more
+    /// than an address: candidate addresses such as our LAN one, relay
+    /// hints, protocol versions and capabilities, which a peer learns
+    /// before it says hello. Fetched records show up as
+    /// [`Event::RecordReceived`] and in [`DhtMsg::peer_record`]; their
+    /// addresses join the lookup's candidates.
+    pub fn signaling(mut self, enabled: bool) -> Self {
+        self.signaling = enabled;
+        self
+    }
+
+    /// Suggest the relay `hint` (usually `host:port`) in our endpoint
+    /// record; repeat for up to eight, of which those that fit in the
+    /// record's 1000 bytes are published. What a peer does with it is up
+    /// to the peer's application.
+    pub fn relay_hint(mut self, hint: impl Into<String>) -> Self {
+        self.relay_hints.push(hint.into());
+        self
+    }
+
+    /// Serve as a relay (disabled by default): announce the relay infohash
+    /// (see [`derive_relay_infohash`](crate::derive_relay_infohash)) and
+    /// pass datagrams on between peers registered with us that cannot
+    /// reach each other directly. Their traffic stays encrypted end to end;
+    /// each gets 256 KiB a second. Only worth it on a node that others can
+    /// reach, with a public address or a forwarded port.
+    pub fn relay(mut self, enabled: bool) -> Self {
+        self.relay = enabled;
+        self
+    }
+
+    /// Register with a relay found under the relay infohash while the
+    /// [announcer](DhtMsg::spawn_announcer) runs, and name it in our
+    /// endpoint record, so that peers whose datagrams cannot get through
+    /// to us directly can come through it (disabled by default; implies
+    /// [signaling](Self::signaling)). For nodes behind NATs that map every
+    /// destination to another port (see [`DhtMsg::nat_mapping`]).
+    pub fn use_relay(mut self, enabled: bool) -> Self {
+        self.use_relay = enabled;
+        self
+    }
+
+    /// Meet the peer with ID `peer_id` at the one infohash both IDs share
+    /// (see [`derive_pairwise_infohash`](crate::derive_pairwise_infohash))
+    /// instead of each side announcing its own and looking up the other's.
+    /// Once any peer is set this way, our own infohash is no longer
+    /// announced, so only such peers find us. The peer must set our ID the
+    /// same way; salt and rotation apply as to other infohashes.
+    pub fn pairwise_rendezvous(mut self, peer_id: impl Into<String>) -> Self {
+        self.pairwise_peers.push(peer_id.into());
+        self
+    }
+
+    /// Keep `delegation` in the DHT, putting it again with every
+    /// announcement, so that lookups of the ID it moves from find us (see
+    /// [`DhtMsg::resolve_peer`]). Repeat it for each delegation of a chain
+    /// of rotated keys.
+    pub fn delegation(mut self, delegation: Delegation) -> Self {
+        self.delegations.push(delegation);
+        self
+    }
+
+    /// Drop frames whose timestamp is more than `window` away from our clock
+    /// (5 minutes by default), along with frames whose nonce their sender
+    /// already used, so captured traffic cannot be replayed. A wider window
+    /// tolerates worse clocks.
+    pub fn replay_window(mut self, window: Duration) -> Self {
+        self.replay_window = window;
+        self
+    }
+
+    /// Move the keys of every encrypted session on to new ones derived from
+    /// them after `interval` (2 minutes by default), and forget the old
+    /// ones, so that keys taken from a running node do not reveal traffic
+    /// sent more than an interval earlier. Each side rekeys what it sends
+    /// on its own schedule; peers need not agree.
+    pub fn rekey_interval(mut self, interval: Duration) -> Self {
+        self.rekey.interval = interval;
+        self
+    }
+
+    /// Also move session keys on once `bytes` were sent under one key
+    /// (64 MiB by default), so busy sessions rekey sooner.
+    pub fn rekey_bytes(mut self, bytes: u64) -> Self {
+        self.rekey.bytes = bytes;
+        self
+    }
+
+    /// Ping every established peer we have not heard from for `interval`
+    /// (20 seconds by default; zero turns it off) while the
+    /// [receiver](DhtMsg::spawn_receiver) runs, and stop, taking it to be
+    /// gone, after five minutes of silence. Its ack and our ping both keep
+    /// the NAT mappings between us open when no messages flow, which
+    /// otherwise expire after half a minute or so on many routers. With
+    /// both sides doing this, each also keeps its own mapping open.
+    pub fn keepalive_interval(mut self, interval: Duration) -> Self {
+        self.keepalive = interval;
+        self
+    }
+
+    /// Only accept frames from the peer with ID `peer_id`, and from other
+    /// peers allowed the same way. Once any peer is allowed, hellos and
+    /// everything else from the rest are dropped without an answer and
+    /// counted in [`DhtMsg::unauthorized_packets`].
+    pub fn allow_peer(mut self, peer_id: impl Into<String>) -> Self {
+        self.allowed_peers.push(peer_id.into());
+        self
+    }
+
+    /// Advertise `capabilities` in hellos and acks, on top of
+    /// [`Capabilities::LZ4`] which follows [`compression`](Self::compression)
+    /// and [`Capabilities::ENCRYPTION`], [`Capabilities::UTP`],
+    /// [`Capabilities::CHANNELS`] and [`Capabilities::TOPICS`] which are
+    /// always set.
+    /// Only claim what the application actually handles, for example
+    /// [`Capabilities::FILE_TRANSFER`] when it accepts file streams.
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Offer an extension record in every hello and ack; peers read it with
+    /// [`DhtMsg::peer_extension`]. Applications should pick kinds from
+    /// [`APPLICATION_KINDS`](crate::APPLICATION_KINDS) up; setting a kind
+    /// again replaces its value.
+    pub fn extension(mut self, kind: u16, value: impl Into<Vec<u8>>) -> Self {
+        self.extensions.set(kind, value.into());
+        self
+    }
+
+    /// Bind the hello socket, start the long-lived DHT and wait for it to bootstrap.
+    pub fn build(self) -> Result<DhtMsg> {
+        // Room is kept for the transport record, added once we know what
+        // we offer.
+        let max_extension_bytes = MAX_EXTENSION_BYTES - negotiate::MAX_RECORD_BYTES;
+        let extension_bytes = self.extensions.encode().len();
+        if extension_bytes > max_extension_bytes {
+            return Err(DhtMsgError::MessageTooLarge {
+                len: extension_bytes,
+                max: max_extension_bytes,
+            });
+        }
+        let mut capabilities = self.capabilities;
+        if self.compression {
+            capabilities.insert(Capabilities::LZ4);
+        } else {
+            capabilities.remove(Capabilities::LZ4);
+        }
+        capabilities.insert(
+            Capabilities::ENCRYPTION
+                | Capabilities::UTP
+                | Capabilities::CHANNELS
+                | Capabilities::TOPICS,
+        );
+        if self.relay {
+            capabilities.insert(Capabilities::RELAY);
+        }
+        let allowed_peers = self
+            .allowed_peers
+            .iter()
+            .map(|id| public_key(id).map(|key| key.to_bytes()))
+            .collect::<Result<HashSet<_>>>()?;
+        let pairwise_peers = self
+            .pairwise_peers
+            .iter()
+            .map(|id| public_key(id).map(|key| hex::encode(key.as_bytes())))
+            .collect::<Result<Vec<_>>>()?;
+        let identity = Arc::new(self.identity.unwrap_or_else(Identity::generate));
+        let local_id = identity.id();
+        let local_infohashes = match self.infohash_period {
+            Some(_) => "rotating".to_string(),
+            None => derive_salted_infohash(&local_id, &self.infohash_salt)?.to_string(),
+        };
+        info!("local ID: {local_id}");
+        info!("derived infohash: {local_infohashes}");
+        let outbox = Outbox::open(self.outbox_dir).map_err(DhtMsgError::Outbox)?;
+
+        if self.private_dht && self.bootstrap.is_empty() {
+            return Err(DhtMsgError::NoBootstrapNodes);
+        }
+        let bootstrap = with_cached_nodes(
+            self.routing_cache.as_deref(),
+            self.bootstrap,
+            self.private_dht,
+        );
+
+        let proxy = self.proxy.map(|server| socks::Proxy {
+            server,
+            login: self.proxy_login,
+        });
+        let port_mapping = self.port_mapping && self.transport.is_none() && proxy.is_none();
+        let stun_servers = if !self.stun {
+            Vec::new()
+        } else if self.stun_servers.is_empty() && !self.private_dht {
+            stun::DEFAULT_SERVERS
+                .iter()
+                .map(ToString::to_string)
+                .collect()
+        } else {
+            self.stun_servers
+        };
+        let mut proxied = None;
+        // Whatever we did not bind ourselves may well carry either family.
+        let (mut ipv4, mut ipv6) = (true, true);
+        let (inner, spray, port_info) = match (self.transport, &proxy) {
+            (Some(transport), _) => (transport, None, None),
+            (None, Some(proxy)) => match proxy.associate() {
+                Ok(association) => {
+                    info!(
+                        "SOCKS5 proxy {} relays our datagrams at {}",
+                        proxy.server,
+                        association.relay()
+                    );
+                    proxied = Some(association.relay());
+                    (Arc::new(association) as SharedTransport, None, None)
+                }
+                Err(err @ SocksError::NoUdp(_)) => {
+                    warn!("{err}; reaching peers over TCP through it only");
+                    (Arc::new(Unrelayed) as SharedTransport, None, None)
+                }
+                Err(err) => {
+                    let context = format!("failed to set up SOCKS5 proxy {}", proxy.server);
+                    return Err(DhtMsgError::socket(context, io::Error::other(err)));
+                }
+            },
+            (None, None) => {
+                let (socket, port_info) =
+                    bind_hello_socket(self.discover_port, &stun_servers, &bootstrap, &self.bind)?;
+                // Peers learn our IPv6 address from endpoint records.
+                ipv4 = !matches!(socket, HelloSocket::V6(_));
+                ipv6 = !matches!(socket, HelloSocket::V4(_)) && endpoint::ipv6_address().is_some();
+                if ipv6 {
+                    capabilities.insert(Capabilities::IPV6);
+                }
+                let spray = Arc::new(SprayTransport::new(Arc::new(socket)));
+                (
+                    spray.clone() as SharedTransport,
+                    Some(spray),
+                    Some(port_info),
+                )
+            }
+        };
+        let turn = self.turn.as_ref().and_then(|credentials| {
+            match turn::allocate(inner.as_ref(), credentials) {
+                Ok(allocation) => Some(Arc::new(TurnTransport::new(inner.clone(), allocation))),
+                Err(err) => {
+                    warn!("going on without TURN: {err}");
+                    None
+                }
+            }
+        });
+        if let Some(turn) = &turn {
+            info!("TURN relays {} to us", turn.relayed());
+        }
+        let inner = match &turn {
+            Some(turn) => turn.clone() as SharedTransport,
+            None => inner,
+        };
+        let onion_wanted = self.onion || self.onion_only;
+        let tcp = match (inner.local_addr(), &proxy) {
+            (_, Some(proxy)) if self.tcp_fallback => Some(Arc::new(TcpTransport::through(
+                inner.clone(),
+                proxy.clone(),
+            ))),
+            (Ok(local), None) if (self.tcp_fallback || onion_wanted) && port_info.is_some() => {
+                Some(Arc::new(TcpTransport::listen(
+                    inner.clone(),
+                    local.port(),
+                    &self.bind,
+                )))
+            }
+            _ => None,
+        };
+        let inner = match &tcp {
+            Some(tcp) => tcp.clone() as SharedTransport,
+            None => inner,
+        };
+        let service = match (&tcp, inner.local_addr()) {
+            _ if !onion_wanted => None,
+            (Some(_), Ok(local)) if port_info.is_some() => {
+                let control = self
+                    .tor_control
+                    .as_deref()
+                    .unwrap_or(onion::DEFAULT_CONTROL);
+                match onion::Service::publish(control, local.port()) {
+                    Ok(service) => {
+                        info!(
+                            "onion service {} leads to the hello port",
+                            service.address()
+                        );
+                        Some(Arc::new(service))
+                    }
+                    Err(err) if self.onion_only => {
+                        let context = "failed to set up the onion service";
+                        return Err(DhtMsgError::socket(context, io::Error::other(err)));
+                    }
+                    Err(err) => {
+                        warn!("going on without an onion service: {err}");
+                        None
+                    }
+                }
+            }
+            _ if self.onion_only => {
+                let err = io::Error::from(io::ErrorKind::Unsupported);
+                return Err(DhtMsgError::socket(
+                    "onion mode needs the hello socket",
+                    err,
+                ));
+            }
+            _ => {
+                warn!("onion mode needs the hello socket; going on without it");
+                None
+            }
+        };
+        let tor = (tcp.is_some() && onion_wanted).then(|| socks::Proxy {
+            server: (self.tor_socks.clone()).unwrap_or_else(|| onion::DEFAULT_SOCKS.to_string()),
+            login: None,
+        });
+        // Anyone can send to a TURN address or through a relay.
+        let kinds: Vec<_> = TransportKind::ALL
+            .into_iter()
+            .filter(|kind| match kind {
+                TransportKind::Tcp => tcp.is_some() && self.tcp_fallback,
+                TransportKind::Tor => service.is_some(),
+                _ => true,
+            })
+            .collect();
+        let offer = Offer::new(&kinds, &self.transport_costs);
+        let mut extensions = self.extensions;
+        extensions.set(negotiate::TRANSPORTS, offer.encode());
+        let public = port_info.and_then(|info| info.public);
+        let relay = Arc::new(RelayTransport::new(inner, identity.clone(), self.relay));
+        let shutdown = ShutdownHandle::default();
+        let streams = Arc::new(Streams::new(identity.clone(), shutdown.clone()));
+        let utp = Arc::new(UtpTransport::new(
+            relay.clone(),
+            streams.clone(),
+            shutdown.clone(),
+        ));
+        let inner = utp.clone() as SharedTransport;
+        let psk = self
+            .psk
+            .map(|key| Arc::new(PskTransport::new(inner.clone(), key)));
+        let inner = match &psk {
+            Some(psk) => psk.clone() as SharedTransport,
+            None => inner,
+        };
+        let ignored_packets = Arc::new(AtomicU64::new(0));
+        let secure = Arc::new(SecureTransport::new(
+            inner,
+            identity.clone(),
+            self.plaintext || self.sealed_box,
+            self.rekey,
+            ignored_packets.clone(),
+        ));
+        let transport = secure.clone() as SharedTransport;
+        let hello_port = transport
+            .local_addr()
+            .map_err(|err| DhtMsgError::socket("failed to read bound port", err))?
+            .port();
+
+        // Bind the long-lived DHT to an ephemeral port (avoid default 6881).
+        let mut dht_builder = dht_builder(&bootstrap);
+        if self.dht_server {
+            dht_builder.server_mode();
+        }
+        // A node ID derived from the IP (BEP 42) from the first query on, or
+        // nodes that check IDs ignore us until mainline derives one itself.
+        if let Some(ip) = self.public_ip.or(public.map(|public| *public.ip())) {
+            dht_builder.public_ip(ip);
+        }
+        let dht = dht_builder.build().map_err(DhtMsgError::Bootstrap)?;
+        if let Some(device) = self.bind.device() {
+            let port = dht.info().local_addr().port();
+            transport::confine_udp_port(port, &device).map_err(|err| {
+                DhtMsgError::socket(format!("failed to keep the DHT socket on {device}"), err)
+            })?;
+            info!("DHT socket kept on {device}; its first queries may have followed routing");
+        } else if let Some(ip) = self.bind.ip {
+            warn!("no interface holds {ip}; the DHT socket stays on every address");
+        }
+        if proxy.is_some() {
+            warn!("the DHT does not go through the SOCKS5 proxy");
+        }
+        info!(
+            "DHT socket listening on {} in {} mode",
+            dht.info().local_addr(),
+            if self.dht_server { "server" } else { "client" }
+        );
+
+        info!("bootstrapping the DHT...");
+        wait_for_bootstrap(&dht, self.bootstrap_timeout)?;
+
+        let goodbye = Control::Goodbye {
+            id: local_id.clone(),
+        };
+        let goodbye_identity = identity.clone();
+        let goodbye_transport = transport.clone();
+        let goodbye_streams = streams.clone();
+        let routing_cache: Option<Arc<Path>> = self.routing_cache.map(Into::into);
+        let cached_dht = dht.clone();
+        let cache_path = routing_cache.clone();
+        let pending_acks = Arc::new(PendingAcks::default());
+        let waiting = pending_acks.clone();
+        let released = turn.clone();
+        let closed = utp.clone();
+        let removed = service.clone();
+        shutdown.on_shutdown(move || {
+            // Waiting senders see their channel close and give up.
+            waiting.lock().unwrap().clear();
+            // Encoded now, so that it carries the current time.
+            let goodbye = wire::encode(FrameType::Control, &goodbye.encode(), &goodbye_identity);
+            for peer in goodbye_streams.established_peers() {
+                if let Err(err) = goodbye_transport.send_to(&goodbye, peer) {
+                    warn!("failed to send goodbye to {peer}: {err}");
+                }
+            }
+            goodbye_streams.close();
+            closed.close();
+            if let Some(turn) = &released {
+                turn.release();
+            }
+            if let Some(service) = &removed {
+                service.remove();
+            }
+            if let Some(path) = &cache_path
+                && let Err(err) = write_routing_cache(&cached_dht, path)
+            {
+                warn!("{err}");
+            }
+        });
+
+        let node = DhtMsg {
+            dht,
+            transport,
+            secure,
+            psk,
+            local_id: local_id.into(),
+            identity,
+            infohash_salt: self.infohash_salt.into(),
+            infohash_period: self.infohash_period,
+            infohash_replicas: self.infohash_replicas,
+            signed_endpoints: self.signed_endpoints,
+            signaling: self.signaling
+                || self.signed_endpoints
+                || self.use_relay
+                || turn.is_some()
+                || proxied.is_some(),
+            relay_hints: self
+                .relay_hints
+                .into_iter()
+                .take(endpoint::MAX_RELAY_HINTS)
+                .collect(),
+            relay,
+            serve_relay: self.relay,
+            use_relay: self.use_relay,
+            relay_keeper: Arc::default(),
+            records: Arc::default(),
+            routing_cache,
+            mailbox_seen: Arc::default(),
+            local_port: hello_port,
+            pairwise_peers: pairwise_peers.into(),
+            delegations: self.delegations.into(),
+            announced_port: Arc::new(AtomicU16::new(
+                public.map_or(hello_port, |public| public.port()),
+            )),
+            events: Arc::new(Events::default()),
+            discovered: Arc::default(),
+            shared_network: Arc::default(),
+            port_mapping,
+            mapped: Arc::default(),
+            stun: port_info.and_then(|info| info.stun),
+            stun_servers: stun_servers.into(),
+            spray,
+            port_prediction: self.port_prediction,
+            turn,
+            tcp,
+            proxy: proxy.is_some(),
+            proxied,
+            bind: self.bind.into(),
+            ipv4,
+            ipv6,
+            tcp_fallback: self.tcp_fallback,
+            onion: service,
+            onion_only: self.onion_only,
+            tor,
+            onion_peers: Arc::default(),
+            scope: if self.lan_only {
+                Scope::Lan
+            } else if self.allow_private {
+                Scope::Any
+            } else {
+                Scope::Public
+            },
+            streams,
+            utp,
+            packets: Arc::default(),
+            next_fragmented_id: Arc::new(AtomicU32::new(rand::random())),
+            next_message_seq: Arc::new(AtomicU32::new(rand::random())),
+            pending_acks,
+            challenges: Arc::default(),
+            pairing: Arc::default(),
+            outbox: Arc::new(outbox),
+            expiry_sweeper: Arc::default(),
+            compression: self.compression,
+            plaintext: self.plaintext,
+            sealed_box: self.sealed_box,
+            max_message_bytes: self.max_message_bytes,
+            replay_window: self.replay_window,
+            keepalive: self.keepalive,
+            allowed_peers: Arc::new(Mutex::new(allowed_peers)),
+            oversize_messages: Arc::default(),
+            capabilities,
+            extensions: extensions.encode().into(),
+            offer: offer.into(),
+            peers: Arc::default(),
+            peer_addrs: Arc::default(),
+            heard: Arc::default(),
+            punches: Arc::default(),
+            ignored_packets,
+            corrupted_packets: Arc::default(),
+            replayed_packets: Arc::default(),
+            unauthorized_packets: Arc::default(),
+            unproven_packets: Arc::default(),
+            bittorrent_packets: Arc::default(),
+            queries: Arc::default(),
+            shutdown,
+        };
+        if node.outbox.has_expiring() {
+            node.spawn_expiry_sweeper();
+        }
+        Ok(node)
+    }
+}
+
+/// A running DHT rendezvous node with its UDP hello socket.
+///
+/// Clones are cheap and refer to the same node. The node runs until
+/// [`shutdown`](Self::shutdown) is called.
+#[derive(Clone)]
+pub struct DhtMsg {
+    dht: mainline::Dht,
+    /// `secure`, as the transport everything is sent through.
+    transport: SharedTransport,
+    secure: Arc<SecureTransport>,
+    /// Below `secure`, if frames carry pre-shared key tags.
+    psk: Option<Arc<PskTransport>>,
+    local_id: Arc<str>,
+    identity: Arc<Identity>,
+    /// Salt of our infohash and of peers'.
+    infohash_salt: Arc<[u8]>,
+    /// How often infohashes change, if they do.
+    infohash_period: Option<Duration>,
+    /// How many infohashes each is announced under.
+    infohash_replicas: u8,
+    /// Whether we publish and look up signed endpoint records rather than
+    /// announcements.
+    signed_endpoints: bool,
+    /// Whether endpoint records are published and fetched, with or without
+    /// signed endpoints.
+    signaling: bool,
+    relay_hints: Arc<[String]>,
+    /// Below the pre-shared key layer, if any: relays for others if
+    /// `serve_relay`, and carries our traffic to peers we reach through
+    /// relays.
+    relay: Arc<RelayTransport>,
+    serve_relay: bool,
+    /// Whether we keep registered with a relay and name it in our record.
+    use_relay: bool,
+    relay_keeper: Arc<AtomicBool>,
+    /// Newest endpoint record fetched for each peer ID, in lowercase hex.
+    records: Arc<Mutex<HashMap<String, EndpointRecord>>>,
+    /// Where the DHT nodes we know are kept across restarts.
+    routing_cache: Option<Arc<Path>>,
+    /// Mailbox messages already emitted, by the hash they are stored under.
+    mailbox_seen: Arc<Mutex<HashSet<Id>>>,
+    /// Port of the hello socket itself, before any NAT.
+    local_port: u16,
+    /// IDs we meet at a shared infohash, in lowercase hex.
+    pairwise_peers: Arc<[String]>,
+    /// Delegations we keep in the DHT.
+    delegations: Arc<[Delegation]>,
+    /// Hello port to announce, as the NAT maps it; it changes with the
+    /// public address.
+    announced_port: Arc<AtomicU16>,
+    events: Arc<Events>,
+    discovered: Arc<Mutex<HashSet<SocketAddr>>>,
+    /// LAN candidates of peers behind our own public address, which are
+    /// taken whatever the scope, and tried first.
+    shared_network: Arc<Mutex<HashSet<SocketAddr>>>,
+    /// Whether the announcer asks the router to forward the hello port.
+    port_mapping: bool,
+    /// Where the router forwards the hello port from, if it does.
+    mapped: Arc<Mutex<Option<SocketAddrV4>>>,
+    /// What STUN servers saw of the hello socket at startup, if any
+    /// answered.
+    stun: Option<stun::Binding>,
+    /// Asked again to predict ports.
+    stun_servers: Arc<[String]>,
+    /// Right above the hello socket, if we bound it: the extra sockets of
+    /// bursts with port prediction.
+    spray: Option<Arc<SprayTransport>>,
+    port_prediction: bool,
+    /// Above `spray`, if a TURN server relays an address to us.
+    turn: Option<Arc<TurnTransport>>,
+    /// Above `turn`, if we listen over TCP too, or connect through the
+    /// proxy.
+    tcp: Option<Arc<TcpTransport>>,
+    /// Whether hello traffic goes through a SOCKS5 proxy.
+    proxy: bool,
+    /// Where the proxy relays our datagrams, if it does.
+    proxied: Option<SocketAddr>,
+    /// Where the sockets of bursts bind.
+    bind: Arc<Bind>,
+    /// Whether IPv4 candidates are worth a hello: false if the hello socket
+    /// we bound is IPv6 only.
+    ipv4: bool,
+    /// Whether IPv6 candidates are worth a hello: false if the hello socket
+    /// we bound has no IPv6, or the host no IPv6 address beyond the link.
+    ipv6: bool,
+    /// Whether lookup loops fall back to TCP; `tcp` may be there for the
+    /// onion service alone.
+    tcp_fallback: bool,
+    /// Our onion service, if Tor made one.
+    onion: Option<Arc<onion::Service>>,
+    onion_only: bool,
+    /// Tor's SOCKS port, to reach the onion services of peers through.
+    tor: Option<socks::Proxy>,
+    /// Virtual addresses of the peers we reach at their onion services.
+    onion_peers: Arc<Mutex<HashSet<SocketAddr>>>,
+    /// Which addresses are candidates and get published.
+    scope: Scope,
+    streams: Arc<Streams>,
+    /// Between `relay` and `psk`: our uTP connections.
+    utp: Arc<UtpTransport>,
+    /// Where VPN packets from peers go, once the application takes them.
+    packets: Arc<Mutex<Option<(PacketSender, PacketReceiver)>>>,
+    /// Id of the next datagram sent in fragments.
+    next_fragmented_id: Arc<AtomicU32>,
+    next_message_seq: Arc<AtomicU32>,
+    pending_acks: Arc<PendingAcks>,
+    /// Outstanding challenges of our hellos and acks.
+    challenges: Arc<Challenges>,
+    /// Our side of the pairing started last, kept after it finishes to
+    /// answer the peer's resends.
+    pairing: Arc<Mutex<Option<Pairing>>>,
+    outbox: Arc<Outbox>,
+    /// Set once the thread dropping expired queued messages runs.
+    expiry_sweeper: Arc<AtomicBool>,
+    compression: bool,
+    plaintext: bool,
+    sealed_box: bool,
+    max_message_bytes: usize,
+    replay_window: Duration,
+    /// Silence after which established peers get pinged; zero if never.
+    keepalive: Duration,
+    /// Keys of the only peers we answer; empty to answer everyone.
+    /// Resolving an allowed ID allows the ID it moved to as well.
+    allowed_peers: Arc<Mutex<HashSet<[u8; PUBLIC_KEY_LENGTH]>>>,
+    /// Received messages dropped for exceeding `max_message_bytes`.
+    oversize_messages: Arc<AtomicU64>,
+    /// Advertised in our hellos and acks.
+    capabilities: Capabilities,
+    /// Encoded extension area of our hellos and acks.
+    extensions: Arc<[u8]>,
+    /// The kinds of path we offer peers, and their costs.
+    offer: Arc<Offer>,
+    peers: Arc<Mutex<HashMap<SocketAddr, PeerInfo>>>,
+    /// Where each peer ID last proved itself from.
+    peer_addrs: Arc<Mutex<HashMap<String, SocketAddr>>>,
+    /// When each address last sent an authentic frame.
+    heard: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+    /// Start of the latest punch request answered, by peer ID in lowercase
+    /// hex.
+    punches: Arc<Mutex<HashMap<String, SystemTime>>>,
+    /// Inbound datagrams dropped for not being well-formed frames of our
+    /// protocol version.
+    ignored_packets: Arc<AtomicU64>,
+    /// Inbound frames dropped for failing their checksum.
+    corrupted_packets: Arc<AtomicU64>,
+    /// Inbound frames dropped as replays.
+    replayed_packets: Arc<AtomicU64>,
+    /// Inbound frames dropped for coming from peers not allowed.
+    unauthorized_packets: Arc<AtomicU64>,
+    /// Inbound data frames dropped for coming from peers not established.
+    unproven_packets: Arc<AtomicU64>,
+    /// Inbound BitTorrent client traffic, dropped silently.
+    bittorrent_packets: Arc<AtomicU64>,
+    /// DHT queries started, for [`DhtStats`].
+    queries: Arc<QueryCounts>,
+    shutdown: ShutdownHandle,
+}
+
+impl DhtMsg {
+    pub fn builder() -> DhtMsgBuilder {
+        DhtMsgBuilder::default()
+    }
+
+    pub fn local_id(&self) -> &str {
+        &self.local_id
+    }
+
+    /// Our own infohash, in the current time window if it rotates. It is
+    /// announced unless the node has
+    /// [pairwise rendezvous](DhtMsgBuilder::pairwise_rendezvous) peers.
+    pub fn infohash(&self) -> Id {
+        let window = self.infohash_windows().map(|windows| windows[1]);
+        self.infohash_of(&self.local_id, window)
+            .expect("our own ID is a public key")
+    }
+
+    /// Port advertised in announcements (public port if discovered, else the bound one).
+    pub fn announced_port(&self) -> u16 {
+        self.announced_port.load(Ordering::Relaxed)
+    }
+
+    pub fn dht(&self) -> &mainline::Dht {
+        &self.dht
+    }
+
+    /// The DHT, for a lookup that counts in the stats.
+    fn dht_lookup(&self) -> &mainline::Dht {
+        self.queries.lookup();
+        &self.dht
+    }
+
+    /// The DHT, for an announcement or put that counts in the stats.
+    fn dht_store(&self) -> &mainline::Dht {
+        self.queries.store();
+        &self.dht
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn queries(&self) -> &QueryCounts {
+        &self.queries
+    }
+
+    /// Routing table size, public address and other statistics of the
+    /// long-lived DHT. Announcer threads log them after every round.
+    pub fn dht_stats(&self) -> DhtStats {
+        self.queries.stats(&self.dht)
+    }
+
+    /// Whether the long-lived DHT runs as a full node, answering other
+    /// nodes' queries, rather than as a client (see
+    /// [`DhtMsgBuilder::dht_server_mode`]). A client turns into a full node
+    /// once other nodes turn out to reach it.
+    pub fn dht_server_mode(&self) -> bool {
+        self.dht.info().server_mode()
+    }
+
+    /// Subscribe to node events. Every subscriber receives every event emitted
+    /// after it subscribed; drop the receiver to unsubscribe.
+    pub fn subscribe(&self) -> flume::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Register a callback for every event. Callbacks run on the thread that
+    /// produced the event, so they should return quickly.
+    pub fn on_event(&self, handler: impl Fn(&Event) + Send + Sync + 'static) {
+        self.events.add_handler(Box::new(handler));
+    }
+
+    pub fn on_peer_discovered(&self, handler: impl Fn(SocketAddr) + Send + Sync + 'static) {
+        self.on_event(move |event| {
+            if let Event::PeerDiscovered { addr } = event {
+                handler(*addr);
+            }
+        });
+    }
+
+    pub fn on_hello_received(&self, handler: impl Fn(SocketAddr, &str) + Send + Sync + 'static) {
+        self.on_event(move |event| {
+            if let Event::HelloReceived { from, message } = event {
+                handler(*from, message);
+            }
+        });
+    }
+
+    pub fn on_ack(&self, handler: impl Fn(SocketAddr, &str) + Send + Sync + 'static) {
+        self.on_event(move |event| {
+            if let Event::AckReceived { from, message } = event {
+                handler(*from, message);
+            }
+        });
+    }
+
+    pub fn on_announce_failed(&self, handler: impl Fn(&str) + Send + Sync + 'static) {
+        self.on_event(move |event| {
+            if let Event::AnnounceFailed { error, .. } = event {
+                handler(error);
+            }
+        });
+    }
+
+    /// Announce the local infohash with the hello port (the infohashes of
+    /// the current and adjacent windows if they rotate), unless
+    /// [signed endpoints](DhtMsgBuilder::signed_endpoints) replace it;
+    /// publish our endpoint records with signed endpoints or
+    /// [signaling](DhtMsgBuilder::signaling); and put our
+    /// [delegations](DhtMsgBuilder::delegation). Fails if any announcement
+    /// does.
+    pub fn announce(&self) -> Result<()> {
+        let delegated = self.publish_delegations();
+        let published = if self.signaling && !self.onion_only {
+            self.publish_endpoint()
+        } else {
+            Ok(())
+        };
+        let announced = if self.signed_endpoints || self.onion_only {
+            Ok(())
+        } else {
+            self.announce_infohashes()
+        };
+        let onions = self.publish_onions();
+        let relaying = if self.serve_relay {
+            self.announce_relay()
+        } else {
+            Ok(())
+        };
+        announced
+            .and(published)
+            .and(delegated)
+            .and(relaying)
+            .and(onions)
+    }
+
+    fn announce_relay(&self) -> Result<()> {
+        let infohash = derive_relay_infohash(&self.infohash_salt);
+        match self
+            .dht_store()
+            .announce_peer(infohash, Some(self.announced_port()))
+        {
+            Ok(_) => {
+                info!(
+                    "announced relay infohash {infohash} on port {}",
+                    self.announced_port()
+                );
+                Ok(())
+            }
+            Err(source) => {
+                self.report_announce_failed(infohash, &source);
+                Err(DhtMsgError::Announce { infohash, source })
+            }
+        }
+    }
+
+    fn announce_infohashes(&self) -> Result<()> {
+        let mut result = Ok(());
+        for infohash in self.announced_infohashes()? {
+            // Advertise the hello socket port; NAT may still rewrite, but many keep the mapping.
+            match self
+                .dht_store()
+                .announce_peer(infohash, Some(self.announced_port()))
+            {
+                Ok(_) => info!(
+                    "announced infohash {infohash} on port {}",
+                    self.announced_port()
+                ),
+                Err(source) => {
+                    self.report_announce_failed(infohash, &source);
+                    result = Err(DhtMsgError::Announce { infohash, source });
+                }
+            }
+        }
+        result
+    }
+
+    /// Run one `get_peers` lookup for each of the peer's derived infohashes,
+    /// unless [signed endpoints](DhtMsgBuilder::signed_endpoints) replace
+    /// it, and with signed endpoints or [signaling](DhtMsgBuilder::signaling)
+    /// fetch its records and add the addresses in the newest validly signed
+    /// one. If the peer has moved to another ID, that one is looked up
+    /// instead (see [`resolve_peer`](Self::resolve_peer)).
+    pub fn find_peer(&self, peer_id: &str) -> Result<Vec<SocketAddr>> {
+        let peer_id = self.resolve_peer(peer_id)?;
+        let mut seen = HashSet::new();
+        let mut found = Vec::new();
+        if !self.signed_endpoints {
+            for infohash in self.peer_infohashes(&peer_id)? {
+                for peers in self.dht_lookup().get_peers(infohash) {
+                    for addr in peers {
+                        if seen.insert(addr.into()) {
+                            found.push(addr.into());
+                        }
+                    }
+                }
+            }
+        }
+        if self.signaling {
+            let record = self.fetch_record(&peer_id)?;
+            for addr in self.received_record(&peer_id, record) {
+                if seen.insert(addr) {
+                    found.push(addr);
+                }
+            }
+        }
+        Ok(self.report_discovered(found))
+    }
+
+    /// The ID that `peer_id` has moved to, following its
+    /// [delegations](Delegation) in the DHT up to a chain of eight, or
+    /// `peer_id` if it has not moved; in lowercase hex either way. If
+    /// `peer_id` is an [allowed peer](DhtMsgBuilder::allow_peer), the ID it
+    /// moved to is allowed from now on too.
+    pub fn resolve_peer(&self, peer_id: &str) -> Result<String> {
+        let from = public_key(peer_id)?;
+        let mut key = from;
+        let mut seen = HashSet::from([key.to_bytes()]);
+        for _ in 0..MAX_DELEGATIONS {
+            let items = self
+                .dht_lookup()
+                .get_mutable(key.as_bytes(), Some(delegation::SALT), None);
+            match delegation::newest(items, &key) {
+                Some(next) if seen.insert(next.to_bytes()) => key = next,
+                _ => break,
+            }
+        }
+        Ok(self.moved(&from, &key))
+    }
+
+    /// Write the DHT nodes we know to the
+    /// [routing table cache](DhtMsgBuilder::routing_cache), if there is one.
+    pub fn save_routing_cache(&self) -> Result<()> {
+        match &self.routing_cache {
+            Some(path) => write_routing_cache(&self.dht, path),
+            None => Ok(()),
+        }
+    }
+
+    /// `to` in hex, after allowing it if it is where the allowed `from`
+    /// moved to.
+    pub(crate) fn moved(&self, from: &VerifyingKey, to: &VerifyingKey) -> String {
+        let to_id = hex::encode(to.as_bytes());
+        if from != to {
+            debug!("{} has moved to {to_id}", hex::encode(from.as_bytes()));
+            let mut allowed = self.allowed_peers.lock().unwrap();
+            if allowed.contains(from.as_bytes()) {
+                allowed.insert(to.to_bytes());
+            }
+        }
+        to_id
+    }
+
+    fn publish_delegations(&self) -> Result<()> {
+        let mut result = Ok(());
+        for delegation in self.delegations.iter() {
+            let item = delegation.item().clone();
+            let target = *item.target();
+            match self.dht_store().put_mutable(item, None) {
+                Ok(_) => info!(
+                    "published delegation from {} to {}",
+                    delegation.from_id(),
+                    delegation.to_id()
+                ),
+                Err(source) => {
+                    self.report_announce_failed(target, &source);
+                    result = Err(DhtMsgError::PublishDelegation { target, source });
+                }
+            }
+        }
+        result
+    }
+
+    fn publish_endpoint(&self) -> Result<()> {
+        let mut result = Ok(());
+        for item in self.endpoint_records()? {
+            let target = *item.target();
+            match self.dht_store().put_mutable(item, None) {
+                Ok(_) => info!(
+                    "published endpoint record {target} with port {}",
+                    self.announced_port()
+                ),
+                Err(source) => {
+                    self.report_announce_failed(target, &source);
+                    result = Err(DhtMsgError::PublishEndpoint { target, source });
+                }
+            }
+        }
+        result
+    }
+
+    fn fetch_record(&self, peer_id: &str) -> Result<Option<EndpointRecord>> {
+        let key = public_key(peer_id)?;
+        Ok(self
+            .endpoint_salts()
+            .iter()
+            .filter_map(|salt| {
+                let items = self
+                    .dht_lookup()
+                    .get_mutable(key.as_bytes(), Some(salt), None);
+                endpoint::newest(items, &key, salt)
+            })
+            .max_by_key(|record| record.seq))
+    }
+
+    /// The addresses in `record`, fetched for `peer_id`; keeps it and emits
+    /// `RecordReceived` if it is newer than the one we had.
+    pub(crate) fn received_record(
+        &self,
+        peer_id: &str,
+        record: Option<EndpointRecord>,
+    ) -> Vec<SocketAddr> {
+        let Some(record) = record else {
+            return Vec::new();
+        };
+        let addresses = record.addresses();
+        let ours = self.dht.info().public_address().map(|public| *public.ip());
+        if ours == Some(*record.addr.ip()) {
+            let lan = record
+                .candidates
+                .iter()
+                .filter(|addr| address::is_private(addr.ip()) && Scope::Any.accepts(addr));
+            let mut shared = self.shared_network.lock().unwrap();
+            for addr in lan {
+                if shared.insert(*addr) {
+                    info!(
+                        "{peer_id} is behind our public address; trying its LAN address {addr} first"
+                    );
+                }
+            }
+        }
+        let mut records = self.records.lock().unwrap();
+        if records
+            .get(peer_id)
+            .is_none_or(|known| known.seq < record.seq)
+        {
+            records.insert(peer_id.to_string(), record.clone());
+            drop(records);
+            self.events.emit(Event::RecordReceived {
+                peer_id: peer_id.to_string(),
+                record,
+            });
+        }
+        addresses
+    }
+
+    /// The newest endpoint record that lookups of `peer_id` have fetched
+    /// (see [`DhtMsgBuilder::signaling`]).
+    pub fn peer_record(&self, peer_id: &str) -> Option<EndpointRecord> {
+        let key = public_key(peer_id).ok()?;
+        let records = self.records.lock().unwrap();
+        records.get(&hex::encode(key.as_bytes())).cloned()
+    }
+
+    /// Leave `payload` in the mailbox of `peer_id` for it to find with
+    /// [`check_mailbox`](Self::check_mailbox) when it comes online. The
+    /// payload is sealed to the peer and the DHT keeps it for a few hours
+    /// at best, so this is no substitute for [`send`](Self::send) when the
+    /// peer is reachable. At most [`MAX_MAILBOX_BYTES`].
+    pub fn leave_in_mailbox(&self, peer_id: &str, payload: &[u8]) -> Result<Id> {
+        if payload.len() > MAX_MAILBOX_BYTES {
+            return Err(DhtMsgError::MessageTooLarge {
+                len: payload.len(),
+                max: MAX_MAILBOX_BYTES,
+            });
+        }
+        let recipient = public_key(peer_id)?;
+        let sealed = sealed_box::seal(&recipient, payload);
+        let message = self.dht_store().put_immutable(&sealed).map_err(|source| {
+            DhtMsgError::LeaveInMailbox {
+                peer_id: peer_id.to_string(),
+                source,
+            }
+        })?;
+        let salt = mailbox::salt(&recipient, mailbox::today());
+        let key = self.identity.verifying_key();
+        let previous = self
+            .dht_lookup()
+            .get_mutable_most_recent(key.as_bytes(), Some(&salt));
+        let mut messages = previous
+            .as_ref()
+            .map(|item| mailbox::messages(item, &key, &salt))
+            .unwrap_or_default();
+        messages.retain(|listed| *listed != message);
+        messages.push(message);
+        let excess = messages.len().saturating_sub(MAX_MAILBOX_MESSAGES);
+        messages.drain(..excess);
+        let item = mailbox::index(
+            &self.identity,
+            &messages,
+            &salt,
+            previous.map(|item| item.seq()),
+        );
+        let target = *item.target();
+        self.dht_store()
+            .put_mutable(item, None)
+            .map_err(|source| DhtMsgError::PublishMailbox { target, source })?;
+        info!("left message {message} in the mailbox of {peer_id}");
+        Ok(message)
+    }
+
+    /// Fetch what `sender_id` left in our mailbox during the last two days
+    /// and emit a `MailboxMessage` for each message not emitted before;
+    /// returns how many there were. Messages that no longer open, or
+    /// that the DHT has dropped, are skipped.
+    pub fn check_mailbox(&self, sender_id: &str) -> Result<usize> {
+        let sender_key = public_key(sender_id)?;
+        let sender = hex::encode(sender_key.as_bytes());
+        let recipient = self.identity.verifying_key();
+        let mut received = 0;
+        for day in mailbox::days() {
+            let salt = mailbox::salt(&recipient, day);
+            let Some(item) = self
+                .dht_lookup()
+                .get_mutable_most_recent(sender_key.as_bytes(), Some(&salt))
+            else {
+                continue;
+            };
+            for message in mailbox::messages(&item, &sender_key, &salt) {
+                if self.mailbox_seen.lock().unwrap().contains(&message) {
+                    continue;
+                }
+                let Some(sealed) = self.dht_lookup().get_immutable(message) else {
+                    debug!("mailbox message {message} from {sender} is gone");
+                    continue;
+                };
+                let Some(payload) = sealed_box::open(&self.identity, &sealed) else {
+                    debug!("mailbox message {message} from {sender} does not open");
+                    continue;
+                };
+                if self.mailbox_seen.lock().unwrap().insert(message) {
+                    received += 1;
+                    self.events.emit(Event::MailboxMessage {
+                        sender: sender.clone(),
+                        payload,
+                    });
+                }
+            }
+        }
+        Ok(received)
+    }
+
+    /// Records of our public address, hello port and the rest to publish,
+    /// one per salt of [`endpoint_salts`](Self::endpoint_salts).
+    pub(crate) fn endpoint_records(&self) -> Result<Vec<MutableItem>> {
+        let public = self
+            .dht
+            .info()
+            .public_address()
+            .ok_or(DhtMsgError::UnknownPublicAddress)?;
+        let addr = SocketAddrV4::new(*public.ip(), self.announced_port());
+        // For peers behind the same NAT, which often cannot reach us at
+        // the public address from inside.
+        let lan = endpoint::lan_address()
+            .filter(|lan| lan != public.ip() && !self.proxy)
+            .map(|lan| SocketAddr::from((lan, self.local_port)));
+        // IPv6 addresses need no NAT, so the hello port is reachable as is.
+        let global = endpoint::ipv6_address()
+            .filter(|_| self.capabilities.contains(Capabilities::IPV6) && self.scope != Scope::Lan)
+            .map(|ip| SocketAddr::from((ip, self.local_port)));
+        // Our TURN address comes after the LAN one, as the path of last resort.
+        let relayed = (self.turn.as_ref()).map(|turn| SocketAddr::V4(turn.relayed()));
+        let candidates = Vec::from_iter(
+            (lan.into_iter().chain(global))
+                .chain(self.proxied)
+                .chain(relayed),
+        );
+        // Relays we are registered with come first; a peer must use one.
+        let relays: Vec<String> = (self.relay.live_relays().iter())
+            .map(ToString::to_string)
+            .chain(self.relay_hints.iter().cloned())
+            .take(endpoint::MAX_RELAY_HINTS)
+            .collect();
+        let published = Published {
+            addr,
+            candidates: &candidates,
+            relays: &relays,
+            capabilities: self.capabilities,
+        };
+        Ok(self
+            .endpoint_salts()
+            .iter()
+            .map(|salt| endpoint::record(&self.identity, &published, salt))
+            .collect())
+    }
+
+    /// BEP 44 salts of endpoint records, given our salt: one, or with
+    /// rotation those of the previous, current and next windows.
+    pub(crate) fn endpoint_salts(&self) -> Vec<[u8; 20]> {
+        match self.infohash_windows() {
+            Some(windows) => windows
+                .iter()
+                .map(|&window| endpoint::salt(&self.infohash_salt, Some(window)))
+                .collect(),
+            None => vec![endpoint::salt(&self.infohash_salt, None)],
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn signed_endpoints(&self) -> bool {
+        self.signed_endpoints
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn signaling(&self) -> bool {
+        self.signaling
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn delegations(&self) -> &[Delegation] {
+        &self.delegations
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn infohash_salt(&self) -> &[u8] {
+        &self.infohash_salt
+    }
+
+    /// What we announce: our own infohashes, or those we share with each
+    /// [pairwise rendezvous](DhtMsgBuilder::pairwise_rendezvous) peer.
+    pub(crate) fn announced_infohashes(&self) -> Result<Vec<Id>> {
+        if self.pairwise_peers.is_empty() {
+            return Ok(self.with_replicas(self.infohashes(&self.local_id)?));
+        }
+        let mut infohashes = Vec::new();
+        for peer_id in self.pairwise_peers.iter() {
+            infohashes.extend(self.pairwise_infohashes(peer_id)?);
+        }
+        Ok(self.with_replicas(infohashes))
+    }
+
+    /// Where we look for `peer_id`: the infohashes we share if it is a
+    /// pairwise rendezvous peer, the ones it announces itself otherwise.
+    pub(crate) fn peer_infohashes(&self, peer_id: &str) -> Result<Vec<Id>> {
+        let key = hex::encode(public_key(peer_id)?.as_bytes());
+        let infohashes = if self.pairwise_peers.contains(&key) {
+            self.pairwise_infohashes(&key)?
+        } else {
+            self.infohashes(peer_id)?
+        };
+        Ok(self.with_replicas(infohashes))
+    }
+
+    /// `infohashes` with their replicas after them.
+    fn with_replicas(&self, infohashes: Vec<Id>) -> Vec<Id> {
+        (0..self.infohash_replicas)
+            .flat_map(|replica| {
+                infohashes
+                    .iter()
+                    .map(move |&infohash| derive_replica_infohash(infohash, replica))
+            })
+            .collect()
+    }
+
+    fn pairwise_infohashes(&self, peer_id: &str) -> Result<Vec<Id>> {
+        let infohash =
+            |window| derive_pairwise_infohash(&self.local_id, peer_id, &self.infohash_salt, window);
+        match self.infohash_windows() {
+            Some(windows) => windows
+                .iter()
+                .map(|&window| infohash(Some(window)))
+                .collect(),
+            None => Ok(vec![infohash(None)?]),
+        }
+    }
+
+    /// Where `id` announces, given our salt: one infohash, or with rotation
+    /// those of the previous, current and next windows.
+    fn infohashes(&self, id: &str) -> Result<Vec<Id>> {
+        match self.infohash_windows() {
+            Some(windows) => windows
+                .iter()
+                .map(|&window| self.infohash_of(id, Some(window)))
+                .collect(),
+            None => Ok(vec![self.infohash_of(id, None)?]),
+        }
+    }
+
+    fn infohash_of(&self, id: &str, window: Option<u64>) -> Result<Id> {
+        match window {
+            Some(window) => derive_rotating_infohash(id, &self.infohash_salt, window),
+            None => derive_salted_infohash(id, &self.infohash_salt),
+        }
+    }
+
+    /// Numbers of the previous, current and next rotation windows.
+    fn infohash_windows(&self) -> Option<[u64; 3]> {
+        let window = wire::unix_secs() / self.infohash_period?.as_secs();
+        Some([window.saturating_sub(1), window, window + 1])
+    }
+
+    pub(crate) fn report_announce_failed(&self, infohash: Id, err: &dyn std::fmt::Display) {
+        self.events.emit(Event::AnnounceFailed {
+            infohash,
+            error: err.to_string(),
+        });
+    }
+
+    /// The candidates in `found` that are worth a hello in the node's
+    /// [scope](DhtMsgBuilder::allow_private), LAN addresses of peers on our
+    /// network first, emitting `PeerDiscovered` for those this node has not
+    /// reported yet.
+    pub(crate) fn report_discovered(&self, mut found: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let before = found.len();
+        let shared = self.shared_network.lock().unwrap().clone();
+        found.retain(|addr| self.scope.accepts(addr) || shared.contains(addr));
+        if found.len() < before {
+            debug!("dropped {} candidates out of scope", before - found.len());
+        }
+        let before = found.len();
+        found.retain(|addr| if addr.is_ipv4() { self.ipv4 } else { self.ipv6 });
+        if found.len() < before {
+            let dropped = before - found.len();
+            debug!("dropped {dropped} candidates of a family we cannot reach");
+        }
+        // IPv6 needs no NAT, so its hellos most likely get through first.
+        found.sort_by_key(|addr| (!shared.contains(addr), addr.is_ipv4()));
+        let mut discovered = self.discovered.lock().unwrap();
+        for addr in &found {
+            if discovered.insert(*addr) {
+                self.events.emit(Event::PeerDiscovered { addr: *addr });
+            }
+        }
+        found
+    }
+
+    /// Send a raw datagram from the hello socket, encrypted like everything
+    /// else unless the node allows plaintext. Unless it is a wire frame, a
+    /// dhtmsg peer counts it as foreign and ignores it.
+    pub fn send(&self, addr: SocketAddr, payload: &[u8]) -> Result<()> {
+        self.transport
+            .send_to(payload, addr)
+            .map_err(|err| DhtMsgError::socket(format!("sending to {addr}"), err))?;
+        Ok(())
+    }
+
+    pub fn send_hello(&self, addr: SocketAddr) -> Result<()> {
+        self.send_control(
+            addr,
+            &Control::Hello {
+                id: self.local_id.to_string(),
+                seq: self.next_message_seq.fetch_add(1, Ordering::Relaxed),
+                caps: self.capabilities(),
+                ext: self.extensions.to_vec(),
+                vmin: wire::MIN_VERSION,
+                v: wire::VERSION,
+                ch: self.challenges.issue(addr).to_vec(),
+            },
+        )?;
+        self.events.emit(Event::HelloSent { to: addr });
+        Ok(())
+    }
+
+    /// Ask `addr` for an ack, keeping the NAT mapping towards it open.
+    pub fn send_ping(&self, addr: SocketAddr) -> Result<()> {
+        self.send_control(
+            addr,
+            &Control::Ping {
+                id: self.local_id.to_string(),
+                seq: self.next_message_seq.fetch_add(1, Ordering::Relaxed),
+            },
+        )
+    }
+
+    /// Answer a hello or ping, naming the protocol `version` of the session.
+    /// In answer to a hello, echo its `challenge` and issue one of ours.
+    fn send_ack(&self, peer: SocketAddr, version: u8, challenge: Option<&[u8]>) {
+        let (re, ch) = match challenge {
+            Some(challenge) => (challenge.to_vec(), self.challenges.issue(peer).to_vec()),
+            None => (Vec::new(), Vec::new()),
+        };
+        let ack = Control::Ack {
+            id: self.local_id.to_string(),
+            caps: self.capabilities(),
+            ext: self.extensions.to_vec(),
+            v: version,
+            re,
+            ch,
+        };
+        if let Err(err) = self.send_control(peer, &ack) {
+            warn!("failed to send ack to {peer}: {err}");
+        }
+    }
+
+    /// Answer the `challenge` of an ack.
+    fn send_proof(&self, peer: SocketAddr, challenge: &[u8]) {
+        let proof = Control::Proof {
+            id: self.local_id.to_string(),
+            re: challenge.to_vec(),
+        };
+        if let Err(err) = self.send_control(peer, &proof) {
+            warn!("failed to send proof to {peer}: {err}");
+        }
+    }
+
+    /// Count a data frame from a peer that has not answered our challenge.
+    fn count_unproven(&self, kind: FrameType, peer: SocketAddr) {
+        debug!("{kind:?} frame from {peer} before it proved its ID (dropped)");
+        let unproven = self.unproven_packets.fetch_add(1, Ordering::Relaxed) + 1;
+        if unproven.is_power_of_two() {
+            info!("dropped {unproven} frames from peers not established so far");
+        }
+    }
+
+    fn send_control(&self, addr: SocketAddr, message: &Control) -> Result<()> {
+        let frame = wire::encode(FrameType::Control, &message.encode(), &self.identity);
+        self.transport
+            .send_to(&frame, addr)
+            .map_err(|err| DhtMsgError::socket(format!("sending to {addr}"), err))?;
+        Ok(())
+    }
+
+    /// Send an application payload once and return its sequence number; the
+    /// peer answers with a message ack ([`Event::MessageAcked`]) carrying it.
+    /// Payloads that do not fit in one datagram are sent in fragments and
+    /// reassembled by the peer.
+    pub fn send_message(&self, addr: SocketAddr, payload: &[u8]) -> Result<u32> {
+        self.send_on(addr, None, payload)
+    }
+
+    /// Send an application payload and block until the peer acknowledges it,
+    /// retransmitting with exponential backoff. Fails with
+    /// [`DhtMsgError::Undelivered`] when every attempt goes unanswered.
+    pub fn send_message_reliable(&self, addr: SocketAddr, payload: &[u8]) -> Result<u32> {
+        self.send_reliable_on(addr, None, payload)
+    }
+
+    /// Like [`send_message`](Self::send_message), tagged with `topic`, such
+    /// as `control`, `clipboard` or `files/photos`: the peer's
+    /// [`Event::MessageReceived`] names it, and
+    /// [`subscribe_topic`](Self::subscribe_topic) there picks it out. Topics
+    /// are 1 to 255 bytes long.
+    pub fn send_topic(&self, addr: SocketAddr, topic: &str, payload: &[u8]) -> Result<u32> {
+        check_topic(topic)?;
+        self.send_on(addr, Some(topic), payload)
+    }
+
+    /// Like [`send_message_reliable`](Self::send_message_reliable), tagged
+    /// with `topic` as [`send_topic`](Self::send_topic) does.
+    pub fn send_topic_reliable(
+        &self,
+        addr: SocketAddr,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<u32> {
+        check_topic(topic)?;
+        self.send_reliable_on(addr, Some(topic), payload)
+    }
+
+    /// Messages that peers send on `topic` or any topic below it: `files`
+    /// gets those on `files/photos` too. Other messages still reach
+    /// [`subscribe`](Self::subscribe), this one's included.
+    pub fn subscribe_topic(&self, topic: &str) -> flume::Receiver<Event> {
+        self.events.subscribe_topic(topic)
+    }
+
+    fn send_on(&self, addr: SocketAddr, topic: Option<&str>, payload: &[u8]) -> Result<u32> {
+        let seq = self.next_message_seq.fetch_add(1, Ordering::Relaxed);
+        let datagram = self.message_datagram(seq, topic, payload, addr)?;
+        self.send_datagram(addr, &datagram)?;
+        Ok(seq)
+    }
+
+    fn send_reliable_on(
+        &self,
+        addr: SocketAddr,
+        topic: Option<&str>,
+        payload: &[u8],
+    ) -> Result<u32> {
+        let seq = self.next_message_seq.fetch_add(1, Ordering::Relaxed);
+        let key = (addr, seq);
+        let (tx, rx) = flume::bounded(1);
+        self.pending_acks.lock().unwrap().insert(key, tx);
+        let result = self.retransmit_until_acked(addr, seq, topic, payload, &rx);
+        self.pending_acks.lock().unwrap().remove(&key);
+        result.map(|()| seq)
+    }
+
+    fn retransmit_until_acked(
+        &self,
+        addr: SocketAddr,
+        seq: u32,
+        topic: Option<&str>,
+        payload: &[u8],
+        acked: &flume::Receiver<()>,
+    ) -> Result<()> {
+        let mut rto = MESSAGE_INITIAL_RTO;
+        for attempt in 1..=MESSAGE_ATTEMPTS {
+            if attempt > 1 {
+                debug!("resending message {seq} to {addr} (attempt {attempt})");
+            }
+            // Encoded afresh every time: the peer drops a frame it has seen
+            // as a replay, even when only our ack got lost.
+            let datagram = self.message_datagram(seq, topic, payload, addr)?;
+            self.send_datagram(addr, &datagram)?;
+            match acked.recv_timeout(rto) {
+                Ok(()) => return Ok(()),
+                Err(flume::RecvTimeoutError::Timeout) => rto *= 2,
+                Err(flume::RecvTimeoutError::Disconnected) => {
+                    return Err(DhtMsgError::Closed("node"));
+                }
+            }
+        }
+        Err(DhtMsgError::Undelivered {
+            addr,
+            seq,
+            attempts: MESSAGE_ATTEMPTS,
+        })
+    }
+
+    /// Queue a message for `peer_id`. It is sent reliably as soon as the peer
+    /// is established by a hello exchange (right away if it already is), and
+    /// kept queued if delivery fails.
+    pub fn queue_message(&self, peer_id: &str, payload: &[u8]) -> Result<()> {
+        self.enqueue(peer_id, payload, None)
+    }
+
+    /// Like [`queue_message`](Self::queue_message), but the message is dropped
+    /// with [`Event::MessageExpired`] if it is still unacknowledged after
+    /// `ttl`. The expiry is kept in the outbox directory across restarts.
+    pub fn queue_message_with_ttl(
+        &self,
+        peer_id: &str,
+        payload: &[u8],
+        ttl: Duration,
+    ) -> Result<()> {
+        self.enqueue(peer_id, payload, Some(SystemTime::now() + ttl))?;
+        self.spawn_expiry_sweeper();
+        Ok(())
+    }
+
+    fn enqueue(&self, peer_id: &str, payload: &[u8], expires: Option<SystemTime>) -> Result<()> {
+        derive_infohash(peer_id)?;
+        self.check_message_len(payload.len())?;
+        self.outbox
+            .push(peer_id, payload, expires)
+            .map_err(DhtMsgError::Outbox)?;
+        let known = self.peer_addrs.lock().unwrap().get(peer_id).copied();
+        if let Some(addr) = known {
+            self.flush_outbox(peer_id, addr);
+        }
+        Ok(())
+    }
+
+    /// Messages queued for `peer_id` and not acknowledged yet.
+    pub fn queued_messages(&self, peer_id: &str) -> usize {
+        self.outbox.len(peer_id)
+    }
+
+    /// Deliver what is queued for `peer_id` from a background thread.
+    fn flush_outbox(&self, peer_id: &str, addr: SocketAddr) {
+        if !self.outbox.start_flush(peer_id) {
+            return;
+        }
+        let node = self.clone();
+        let peer_id = peer_id.to_string();
+        self.shutdown.spawn("dhtmsg-outbox", move || {
+            let mut delivered = 0;
+            loop {
+                node.expire_queued();
+                let Some((number, payload)) = node.outbox.next(&peer_id) else {
+                    break;
+                };
+                if let Err(err) = node.send_message_reliable(addr, &payload) {
+                    warn!("queued message to {peer_id} not delivered: {err}");
+                    node.outbox.abort_flush(&peer_id);
+                    break;
+                }
+                delivered += 1;
+                if let Err(err) = node.outbox.pop(&peer_id, number) {
+                    warn!("failed to remove delivered message from the outbox: {err}");
+                }
+            }
+            if delivered > 0 {
+                info!("delivered {delivered} queued messages to {peer_id} at {addr}");
+            }
+        });
+    }
+
+    /// Drop queued messages past their TTL, reporting each one.
+    fn expire_queued(&self) {
+        for (peer_id, payload) in self.outbox.expire(SystemTime::now()) {
+            info!("queued {}-byte message to {peer_id} expired", payload.len());
+            self.events.emit(Event::MessageExpired { peer_id, payload });
+        }
+    }
+
+    /// Expire queued messages every second until shutdown (once per node).
+    fn spawn_expiry_sweeper(&self) {
+        if self.expiry_sweeper.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let node = self.clone();
+        self.shutdown.spawn("dhtmsg-expiry", move || {
+            while node.shutdown.sleep(EXPIRY_SWEEP_INTERVAL) {
+                node.expire_queued();
+            }
+        });
+    }
+
+    /// Capabilities advertised in our hellos and acks.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Capabilities `peer` advertised in its latest hello or ack.
+    pub fn peer_capabilities(&self, peer: SocketAddr) -> Option<Capabilities> {
+        self.peers.lock().unwrap().get(&peer).map(|info| info.caps)
+    }
+
+    /// Whether `peer` advertised LZ4 support in its hello or ack (and
+    /// compression is enabled locally).
+    pub fn peer_supports_compression(&self, peer: SocketAddr) -> bool {
+        self.compression
+            && self
+                .peer_capabilities(peer)
+                .is_some_and(|caps| caps.contains(Capabilities::LZ4))
+    }
+
+    /// Protocol version agreed on with `peer` in the hello/ack exchange.
+    pub fn peer_version(&self, peer: SocketAddr) -> Option<u8> {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(&peer)
+            .map(|info| info.version)
+    }
+
+    /// Whether traffic with `peer` is encrypted, i.e. a handshake with it
+    /// completed.
+    pub fn peer_encrypted(&self, peer: SocketAddr) -> bool {
+        self.secure.is_encrypted(&peer)
+    }
+
+    /// Short authentication string of the encrypted session with `peer`, to
+    /// compare with the one the peer shows: if they match, nobody sits in
+    /// the middle. `None` until a handshake completed. A new handshake, for
+    /// example after either side restarts, gives a new one.
+    pub fn peer_sas(&self, peer: SocketAddr) -> Option<Sas> {
+        self.secure
+            .handshake_hash(&peer)
+            .map(|hash| Sas::new(&hash))
+    }
+
+    /// Value of the extension record of `kind` that `peer` sent in its latest
+    /// hello or ack.
+    pub fn peer_extension(&self, peer: SocketAddr, kind: u16) -> Option<Vec<u8>> {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(&peer)?
+            .extensions
+            .get(kind)
+            .map(<[u8]>::to_vec)
+    }
+
+    /// Record the version agreed on with `peer` and the capabilities and
+    /// extensions it advertised in a hello or ack.
+    fn note_peer(&self, message: &Control, version: u8, peer: SocketAddr) {
+        let extensions = Extensions::parse(message.extension_area()).unwrap_or_else(|| {
+            debug!("malformed extension area from {peer} (ignored)");
+            Extensions::default()
+        });
+        let info = PeerInfo {
+            version,
+            caps: message.capabilities(),
+            extensions,
+            key: public_key(message.id()).ok(),
+        };
+        self.peers.lock().unwrap().insert(peer, info);
+    }
+
+    /// Remember where `peer_id` talks from and hand it its queued messages,
+    /// unless a better path to it still works: a LAN address over a public
+    /// one, which may go through a NAT that does not pass datagrams from
+    /// inside back in, IPv6 over IPv4, which needs no NAT at all, and any
+    /// of them over a relay.
+    fn peer_seen(&self, peer_id: &str, peer: SocketAddr) {
+        let mut peer_addrs = self.peer_addrs.lock().unwrap();
+        if let Some(known) = peer_addrs.get(peer_id).copied()
+            && known != peer
+            && self.path_rank(&known) < self.path_rank(&peer)
+            && self
+                .heard
+                .lock()
+                .unwrap()
+                .get(&known)
+                .is_some_and(|heard| heard.elapsed() < PEER_SILENCE)
+        {
+            debug!("{peer_id} also talks from {peer}; keeping the better path at {known}");
+            return;
+        }
+        peer_addrs.insert(peer_id.to_string(), peer);
+        drop(peer_addrs);
+        self.flush_outbox(peer_id, peer);
+    }
+
+    /// What the path to `addr` costs us and the peer there together,
+    /// lowest first (see [`DhtMsgBuilder::transport_cost`]); between UDP
+    /// paths, a LAN address goes before a public one, and then IPv6 before
+    /// IPv4.
+    fn path_rank(&self, addr: &SocketAddr) -> (u16, bool, bool) {
+        let kind = self.peer_transport(*addr);
+        let cost = (self.offer)
+            .pair_cost(&self.peer_offer(*addr), kind)
+            .unwrap_or(u16::MAX);
+        (cost, !address::is_private(addr.ip()), addr.is_ipv4())
+    }
+
+    /// How datagrams to `addr` travel: through a relay, through Tor, over
+    /// TCP, through our TURN server, or else straight over UDP.
+    pub fn peer_transport(&self, addr: SocketAddr) -> TransportKind {
+        if self.relayed_through(&addr).is_some() {
+            TransportKind::Relay
+        } else if self.over_tor(&addr) {
+            TransportKind::Tor
+        } else if self.over_tcp(&addr) {
+            TransportKind::Tcp
+        } else if self.turn.as_ref().is_some_and(|turn| turn.carries(&addr)) {
+            TransportKind::Turn
+        } else {
+            TransportKind::Udp
+        }
+    }
+
+    /// The kind of path costing us and `peer` least together, of those we
+    /// both offer; `None` if we share none. Peers that offered nothing,
+    /// from before negotiation, count as offering every kind at its
+    /// default cost.
+    pub fn negotiated_transport(&self, peer: SocketAddr) -> Option<TransportKind> {
+        self.offer.best(&self.peer_offer(peer))
+    }
+
+    /// The kinds of path `peer` offered in its latest hello or ack.
+    fn peer_offer(&self, peer: SocketAddr) -> Offer {
+        let peers = self.peers.lock().unwrap();
+        let record = peers
+            .get(&peer)
+            .and_then(|info| info.extensions.get(negotiate::TRANSPORTS));
+        record.and_then(Offer::parse).unwrap_or_else(Offer::legacy)
+    }
+
+    fn check_message_len(&self, len: usize) -> Result<()> {
+        if len > self.max_message_bytes {
+            return Err(DhtMsgError::MessageTooLarge {
+                len,
+                max: self.max_message_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Frame a message, on `topic` if any: sealed to the peer's ID in
+    /// sealed-box mode, else compressed if `to` advertised support and it
+    /// pays off.
+    fn message_datagram(
+        &self,
+        seq: u32,
+        topic: Option<&str>,
+        payload: &[u8],
+        peer: SocketAddr,
+    ) -> Result<Vec<u8>> {
+        let (kind, body) = self.message_frame(seq, payload, peer)?;
+        Ok(match topic {
+            Some(topic) => wire::encode(
+                FrameType::Topic,
+                &wire::topic_body(kind, topic, &body),
+                &self.identity,
+            ),
+            None => wire::encode(kind, &body, &self.identity),
+        })
+    }
+
+    /// The type and body of the frame carrying a message.
+    fn message_frame(
+        &self,
+        seq: u32,
+        payload: &[u8],
+        peer: SocketAddr,
+    ) -> Result<(FrameType, Vec<u8>)> {
+        self.check_message_len(payload.len())?;
+        if self.sealed_box {
+            let key = self
+                .peers
+                .lock()
+                .unwrap()
+                .get(&peer)
+                .and_then(|info| info.key)
+                .ok_or(DhtMsgError::NotEstablished(peer))?;
+            return Ok((
+                FrameType::SealedMessage,
+                wire::message_body(seq, &self.local_id, &sealed_box::seal(&key, payload)),
+            ));
+        }
+        if payload.len() >= compress::THRESHOLD && self.peer_supports_compression(peer) {
+            let mut packed = (payload.len() as u32).to_be_bytes().to_vec();
+            packed.extend_from_slice(&compress::compress(payload));
+            if packed.len() < payload.len() {
+                let body = wire::message_body(seq, &self.local_id, &packed);
+                if body.len() + wire::OVERHEAD_BYTES <= MAX_FRAGMENTED_BYTES {
+                    return Ok((FrameType::CompressedMessage, body));
+                }
+            }
+        }
+        Ok((
+            FrameType::Message,
+            wire::message_body(seq, &self.local_id, payload),
+        ))
+    }
+
+    /// Send `datagram`, in fragments if it does not fit in one.
+    fn send_datagram(&self, addr: SocketAddr, datagram: &[u8]) -> Result<()> {
+        if datagram.len() <= MAX_DATAGRAM_BYTES {
+            return self.send(addr, datagram);
+        }
+        let id = self.next_fragmented_id.fetch_add(1, Ordering::Relaxed);
+        for fragment in fragment::split(id, datagram) {
+            let fragment = wire::encode(FrameType::Fragment, &fragment, &self.identity);
+            self.send(addr, &fragment)?;
+        }
+        Ok(())
+    }
+
+    /// Send a typed message once, as a CBOR frame. Data messages are
+    /// acknowledged like [`send_message`](Self::send_message) payloads.
+    #[cfg(feature = "cbor")]
+    pub fn send_cbor(&self, addr: SocketAddr, message: &schema::Message) -> Result<()> {
+        if let schema::Message::Data { payload, .. } = message {
+            self.check_message_len(payload.len())?;
+            self.check_encrypted(addr)?;
+        }
+        let datagram = wire::encode(FrameType::Cbor, &message.to_cbor(), &self.identity);
+        if datagram.len() > MAX_FRAGMENTED_BYTES {
+            return Err(DhtMsgError::MessageTooLarge {
+                len: datagram.len(),
+                max: MAX_FRAGMENTED_BYTES,
+            });
+        }
+        self.send_datagram(addr, &datagram)
+    }
+
+    /// Open a reliable byte stream to an established peer, one that answered
+    /// the challenge of our hello or ack ([`Event::PeerAuthenticated`]).
+    /// Requires a running receiver (see [`spawn_receiver`](Self::spawn_receiver)).
+    pub fn stream(&self, peer: SocketAddr) -> Result<PeerStream> {
+        if !self.streams.is_established(&peer) {
+            return Err(DhtMsgError::NotEstablished(peer));
+        }
+        self.check_encrypted(peer)?;
+        Ok(self.streams.open(&self.transport, peer))
+    }
+
+    /// Whether payloads other than sealed messages may go to `peer` in the
+    /// clear.
+    fn allows_cleartext(&self, peer: SocketAddr) -> bool {
+        !self.sealed_box || self.plaintext || self.secure.is_encrypted(&peer)
+    }
+
+    /// In sealed-box mode, fail unless a session encrypts what goes to `peer`.
+    fn check_encrypted(&self, peer: SocketAddr) -> Result<()> {
+        if self.allows_cleartext(peer) {
+            Ok(())
+        } else {
+            Err(DhtMsgError::NotEncrypted(peer))
+        }
+    }
+
+    /// Wait for a peer to open a stream to us.
+    pub fn accept_stream(&self) -> Result<PeerStream> {
+        self.streams
+            .acceptor()
+            .recv()
+            .map_err(|_| DhtMsgError::Closed("stream table"))
+    }
+
+    /// Open a new stream like [`stream`](Self::stream), on the channel
+    /// `name`, which the peer takes with
+    /// [`accept_stream_channel`](Self::accept_stream_channel). Unlike the
+    /// one plain stream to a peer, there may be any number of these at
+    /// once, each ordered, retransmitted and paced on its own. Names are 1
+    /// to 255 bytes long.
+    pub fn stream_channel(&self, peer: SocketAddr, name: &str) -> Result<PeerStream> {
+        check_channel(name)?;
+        if !self.streams.is_established(&peer) {
+            return Err(DhtMsgError::NotEstablished(peer));
+        }
+        self.check_encrypted(peer)?;
+        self.streams
+            .open_channel(&self.transport, peer, name)
+            .map_err(|err| DhtMsgError::socket(format!("opening channel {name:?} to {peer}"), err))
+    }
+
+    /// Wait for a peer to open a stream on the channel `name`. Until this
+    /// is first called for some name, streams on channels go unanswered;
+    /// after that, those on a name nobody accepts are finished at once.
+    pub fn accept_stream_channel(&self, name: &str) -> Result<PeerStream> {
+        self.streams
+            .channel_acceptor(name)
+            .recv()
+            .map_err(|_| DhtMsgError::Closed("stream table"))
+    }
+
+    /// Open an encrypted uTP stream to an established peer we have a Noise
+    /// session with, for bulk data: unlike [`stream`](Self::stream), it
+    /// sizes its window to the path (see [`UtpStream`]). Writes wait until
+    /// the peer answers. Requires a running receiver on both sides.
+    pub fn utp_stream(&self, peer: SocketAddr) -> Result<UtpStream> {
+        if !self.streams.is_established(&peer) {
+            return Err(DhtMsgError::NotEstablished(peer));
+        }
+        let handshake_hash = self
+            .secure
+            .handshake_hash(&peer)
+            .ok_or(DhtMsgError::NotEncrypted(peer))?;
+        Ok(UtpStream::connect(&self.utp, peer, handshake_hash))
+    }
+
+    /// Wait for a peer to open a uTP stream to us. Until this is first
+    /// called, uTP connections from peers go unanswered.
+    pub fn accept_utp_stream(&self) -> Result<UtpStream> {
+        UtpStream::accept(&self.utp, |peer| self.secure.handshake_hash(peer))
+            .ok_or(DhtMsgError::Closed("uTP connections"))
+    }
+
+    /// Open a uTP stream like [`utp_stream`](Self::utp_stream), on the
+    /// channel `name`, which the peer takes with
+    /// [`accept_channel`](Self::accept_channel). Each stream is a uTP
+    /// connection of its own, so a file going out on one channel never
+    /// holds up the bytes of another, and the name travels sealed, as the
+    /// first bytes of the stream. Names are 1 to 255 bytes long.
+    pub fn channel(&self, peer: SocketAddr, name: &str) -> Result<UtpStream> {
+        check_channel(name)?;
+        if !self.streams.is_established(&peer) {
+            return Err(DhtMsgError::NotEstablished(peer));
+        }
+        let handshake_hash = self
+            .secure
+            .handshake_hash(&peer)
+            .ok_or(DhtMsgError::NotEncrypted(peer))?;
+        UtpStream::connect_channel(&self.utp, peer, handshake_hash, name)
+            .map_err(|err| DhtMsgError::socket(format!("opening channel {name:?} to {peer}"), err))
+    }
+
+    /// Wait for a peer to open a uTP stream on the channel `name`. Until
+    /// this is first called for a name, streams opened on it are reset;
+    /// those on other channels, or on none, wait for their own accepts.
+    pub fn accept_channel(&self, name: &str) -> Result<UtpStream> {
+        let secure = self.secure.clone();
+        UtpStream::accept_channel(&self.utp, name, move |peer| secure.handshake_hash(peer))
+            .ok_or(DhtMsgError::Closed("uTP channels"))
+    }
+
+    /// Send `packet`, an IP packet of a VPN, to an established peer once,
+    /// unacknowledged and unordered: the protocols inside recover from
+    /// losses themselves. At most [`MAX_PACKET_BYTES`] long.
+    pub fn send_packet(&self, peer: SocketAddr, packet: &[u8]) -> Result<()> {
+        if packet.len() > MAX_PACKET_BYTES {
+            return Err(DhtMsgError::MessageTooLarge {
+                len: packet.len(),
+                max: MAX_PACKET_BYTES,
+            });
+        }
+        if !self.streams.is_established(&peer) {
+            return Err(DhtMsgError::NotEstablished(peer));
+        }
+        self.check_encrypted(peer)?;
+        self.send(
+            peer,
+            &wire::encode(FrameType::Packet, packet, &self.identity),
+        )
+    }
+
+    /// The packets established peers send us with
+    /// [`send_packet`](Self::send_packet), with the address of each sender.
+    /// Until this is first called, they are dropped.
+    pub fn packets(&self) -> flume::Receiver<(SocketAddr, Vec<u8>)> {
+        let mut packets = self.packets.lock().unwrap();
+        let (_, rx) = packets.get_or_insert_with(|| flume::bounded(PACKET_QUEUE));
+        rx.clone()
+    }
+
+    /// Queue a VPN packet from `peer` for the application, if it takes
+    /// them and keeps up.
+    fn deliver_packet(&self, packet: &[u8], peer: SocketAddr) {
+        if let Some((tx, _)) = &*self.packets.lock().unwrap()
+            && tx.try_send((peer, packet.to_vec())).is_err()
+        {
+            debug!("VPN packet from {peer} dropped: the queue is full");
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn stream_acceptor(&self) -> flume::Receiver<PeerStream> {
+        self.streams.acceptor()
+    }
+
+    /// Spawn a thread answering inbound hellos with acks.
+    pub fn spawn_receiver(&self) {
+        self.spawn_receiver_with(|_, _| {});
+    }
+
+    /// Like [`spawn_receiver`](Self::spawn_receiver), but also hands every inbound
+    /// datagram (hellos and ignored foreign ones included, fragments reassembled,
+    /// stream and BitTorrent traffic excluded) to `handler`.
+    pub fn spawn_receiver_with<F>(&self, handler: F)
+    where
+        F: FnMut(&[u8], SocketAddr) + Send + 'static,
+    {
+        let receiver = Receiver {
+            node: self.clone(),
+            dedup: Dedup::default(),
+            keys: HashMap::new(),
+            proven: HashMap::new(),
+            replay: ReplayGuard::new(self.replay_window),
+        };
+        self.shutdown
+            .spawn("dhtmsg-recv", move || receiver.run(handler));
+        if !self.keepalive.is_zero() {
+            self.spawn_keepalive();
+        }
+    }
+
+    /// Ping peers we heard from, each time they have been silent for a
+    /// keepalive interval, until they have been for `KEEPALIVE_TIMEOUT`.
+    fn spawn_keepalive(&self) {
+        let node = self.clone();
+        let tick = (self.keepalive / 4).max(Duration::from_millis(100));
+        self.shutdown.spawn("dhtmsg-keepalive", move || {
+            let mut pinged: HashMap<SocketAddr, Instant> = HashMap::new();
+            loop {
+                let silent: Vec<SocketAddr> = {
+                    let heard = node.heard.lock().unwrap();
+                    pinged.retain(|addr, _| heard.contains_key(addr));
+                    heard
+                        .iter()
+                        .filter(|(_, at)| {
+                            (node.keepalive..KEEPALIVE_TIMEOUT).contains(&at.elapsed())
+                        })
+                        .map(|(addr, _)| *addr)
+                        .collect()
+                };
+                for addr in silent {
+                    if pinged
+                        .get(&addr)
+                        .is_some_and(|at| at.elapsed() < node.keepalive)
+                    {
+                        continue;
+                    }
+                    pinged.insert(addr, Instant::now());
+                    debug!("keepalive ping to {addr}");
+                    if let Err(err) = node.send_ping(addr) {
+                        debug!("failed to ping {addr}: {err}");
+                    }
+                }
+                if !node.shutdown.sleep(tick) {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Announce now and then about every `interval` until shutdown, saving
+    /// the [routing table cache](DhtMsgBuilder::routing_cache) each time.
+    /// The first rounds come sooner, rounds are spread a little at random,
+    /// and after failed ones the wait doubles, up to 15 minutes. When
+    /// the public address changes, say after a reconnect, announce again
+    /// right away (see [`follow_public_address`](Self::follow_public_address)).
+    pub fn spawn_announcer(&self, interval: Duration) {
+        self.spawn_address_watch();
+        if self.port_mapping {
+            self.spawn_port_mapping();
+        }
+        if self.use_relay {
+            self.spawn_relay_keeper();
+        }
+        if self.turn.is_some() {
+            self.spawn_turn_keeper();
+        }
+        let node = self.clone();
+        self.shutdown.spawn("dhtmsg-announce", move || {
+            let mut schedule = AnnounceSchedule::new(interval);
+            loop {
+                let announced = node.announce();
+                if let Err(err) = &announced {
+                    warn!("announce failed: {err}");
+                }
+                if let Err(err) = node.save_routing_cache() {
+                    warn!("{err}");
+                }
+                info!("DHT: {}", node.dht_stats());
+                let delay = schedule.next(announced.is_ok());
+                debug!("announcing again in {delay:.0?}");
+                if !node.shutdown.sleep(delay) {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn spawn_address_watch(&self) {
+        let node = self.clone();
+        self.shutdown.spawn("dhtmsg-address-watch", move || {
+            let mut current = node.dht.info().public_address();
+            let mut pending = None;
+            while node.shutdown.sleep(ADDRESS_CHECK_INTERVAL) {
+                let seen = node.dht.info().public_address();
+                let Some(public) = seen.filter(|&seen| Some(seen) != current) else {
+                    pending = None;
+                    continue;
+                };
+                // Nodes vote on our address, and a vote may waver: only act
+                // on an address that holds for two checks.
+                if pending.replace(public) != Some(public) {
+                    continue;
+                }
+                pending = None;
+                // The first address learned is the one announced already.
+                if let Some(old) = current.replace(public) {
+                    info!("public address changed from {old} to {public}");
+                    node.follow_public_address(public);
+                }
+            }
+        });
+    }
+
+    /// Announce again for the new `public` address. The hello port we
+    /// announce follows it if the NAT keeps the DHT socket's port, as it
+    /// then likely keeps the hello socket's too; otherwise nothing from
+    /// outside tells the new mapping, and the old port stays.
+    fn follow_public_address(&self, public: SocketAddrV4) {
+        // A forwarded port stays what it is, and so does one STUN saw.
+        if public.port() == self.dht.info().local_addr().port()
+            && self.mapped_address().is_none()
+            && self.stun.is_none()
+        {
+            let old = self.announced_port.swap(self.local_port, Ordering::Relaxed);
+            if old != self.local_port {
+                info!("announcing hello port {} instead of {old}", self.local_port);
+            }
+        }
+        if let Err(err) = self.announce() {
+            warn!("announce after the address change failed: {err}");
+        }
+    }
+
+    /// Keep our TURN allocation and the permissions on it that are in use
+    /// until shutdown, when the shutdown hook releases it.
+    fn spawn_turn_keeper(&self) {
+        let node = self.clone();
+        self.shutdown.spawn("dhtmsg-turn", move || {
+            let Some(turn) = &node.turn else {
+                return;
+            };
+            loop {
+                turn.tick();
+                if !node.shutdown.sleep(turn::TICK) {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Keep the hello port forwarded by the router until shutdown, then
+    /// remove the mapping.
+    fn spawn_port_mapping(&self) {
+        let node = self.clone();
+        self.shutdown.spawn("dhtmsg-port-mapping", move || {
+            let router = match Router::find() {
+                Ok(router) => router,
+                Err(err) => {
+                    info!("not forwarding the hello port: {err}");
+                    return;
+                }
+            };
+            debug!(
+                "asking the router to forward the hello port over {}",
+                router.protocol()
+            );
+            let mut external_port = node.local_port;
+            loop {
+                let renew = match router.map(node.local_port, external_port) {
+                    Ok(mapping) => {
+                        external_port = mapping.external.port();
+                        node.adopt_mapping(mapping.external);
+                        // Permanent mappings are renewed too, in case the
+                        // router restarts.
+                        Some(mapping.lease)
+                            .filter(|lease| !lease.is_zero())
+                            .unwrap_or(portmap::LEASE)
+                            / 2
+                    }
+                    Err(err) => {
+                        warn!("forwarding the hello port failed: {err}");
+                        PORT_MAPPING_RETRY
+                    }
+                };
+                if !node.shutdown.sleep(renew) {
+                    break;
+                }
+            }
+            if node.mapped.lock().unwrap().take().is_some()
+                && let Err(err) = router.unmap(node.local_port, external_port)
+            {
+                warn!("removing the port mapping failed: {err}");
+            }
+        });
+    }
+
+    /// Announce the port the router forwards from `external`, unless the
+    /// router is behind another NAT.
+    fn adopt_mapping(&self, external: SocketAddrV4) {
+        if address::is_private(IpAddr::V4(*external.ip())) {
+            info!("the router's external address {external} is private; not announcing it");
+            return;
+        }
+        if self.mapped.lock().unwrap().replace(external) == Some(external) {
+            return;
+        }
+        info!("the router forwards {external} to the hello port");
+        let old = self.announced_port.swap(external.port(), Ordering::Relaxed);
+        if old != external.port() {
+            info!("announcing hello port {} instead of {old}", external.port());
+            if let Err(err) = self.announce() {
+                warn!("announce after the port mapping failed: {err}");
+            }
+        }
+    }
+
+    /// Where the router forwards the hello port from, if it does (see
+    /// [`DhtMsgBuilder::port_mapping`]).
+    pub fn mapped_address(&self) -> Option<SocketAddrV4> {
+        *self.mapped.lock().unwrap()
+    }
+
+    /// Whether the NAT keeps one public port for the hello socket whatever
+    /// it sends to, as two STUN servers saw at startup; unknown unless two
+    /// at different addresses answered (see [`DhtMsgBuilder::stun`]).
+    pub fn nat_mapping(&self) -> Option<NatMapping> {
+        self.stun.and_then(|binding| binding.mapping)
+    }
+
+    /// The address the TURN server relays to us, if it gave us one (see
+    /// [`DhtMsgBuilder::turn_server`]).
+    pub fn turn_address(&self) -> Option<SocketAddrV4> {
+        self.turn.as_ref().map(|turn| turn.relayed())
+    }
+
+    /// The address the SOCKS5 proxy relays our datagrams at, if it does
+    /// (see [`DhtMsgBuilder::socks5_proxy`]).
+    pub fn proxy_address(&self) -> Option<SocketAddr> {
+        self.proxied
+    }
+
+    /// Look up `peer_id` every `interval` until shutdown, sending a hello to
+    /// each candidate the first time it shows up.
+    ///
+    /// With [signaling](DhtMsgBuilder::signaling), the loop also answers
+    /// the peer's [punch](Self::punch) requests, and makes its own if the
+    /// peer has candidates but does not answer.
+    ///
+    /// Once the peer is reached, the loop only looks it up every five
+    /// minutes, in case it moved, while
+    /// [keepalive](DhtMsgBuilder::keepalive_interval) pings keep it
+    /// talking. If it stays silent for a minute, or three keepalive
+    /// intervals if longer, lookups go back to every `interval`.
+    pub fn spawn_lookup(&self, peer_id: &str, interval: Duration) -> Result<()> {
+        let peer_infohashes = self.peer_infohashes(peer_id)?;
+        info!("peer ID: {peer_id}");
+        info!("peer infohash: {}", join_infohashes(&peer_infohashes));
+        let node = self.clone();
+        let peer_id = peer_id.to_string();
+        self.shutdown.spawn("dhtmsg-lookup", move || {
+            let mut seen: HashSet<SocketAddr> = HashSet::new();
+            let mut looked_up: Option<Instant> = None;
+            let mut punch_at: Option<Instant> = None;
+            let mut tcp_at: Option<Instant> = None;
+            let mut relay_at: Option<Instant> = None;
+            let mut onion_at: Option<Instant> = None;
+            let mut onion_published: Option<Instant> = None;
+            let mut moved_to_tcp = false;
+            let mut reached = false;
+            info!("starting lookup loop");
+            loop {
+                let silence = node.peer_silence(&peer_id);
+                let limit = PEER_SILENCE.max(node.keepalive * 3);
+                if silence.is_some_and(|silence| silence < limit) != reached {
+                    reached = !reached;
+                    if reached {
+                        info!("peer {peer_id} reached; slowing lookups down");
+                    } else {
+                        info!("peer {peer_id} went silent; looking it up again");
+                        // It may be back at an address we tried before.
+                        seen.clear();
+                        punch_at = None;
+                        tcp_at = None;
+                        relay_at = None;
+                        onion_at = None;
+                        moved_to_tcp = false;
+                    }
+                }
+                let due = if reached {
+                    REACHED_LOOKUP_INTERVAL
+                } else {
+                    Duration::ZERO
+                };
+                if !node.onion_only && looked_up.is_none_or(|at| at.elapsed() >= due) {
+                    looked_up = Some(Instant::now());
+                    for addr in node.find_peer(&peer_id).unwrap_or_default() {
+                        if seen.insert(addr) {
+                            info!("found peer candidate {addr}, sending hello...");
+                            if let Err(err) = node.send_hello(addr) {
+                                warn!("failed to send hello to {addr}: {err}");
+                            }
+                        }
+                    }
+                }
+                // Some networks drop UDP altogether.
+                if node.tcp_fallback && node.tcp.is_some() && !reached && !seen.is_empty() {
+                    let at = *tcp_at.get_or_insert_with(|| Instant::now() + TCP_AFTER);
+                    if Instant::now() >= at {
+                        tcp_at = Some(Instant::now() + TCP_INTERVAL);
+                        node.spawn_tcp_attempts(&peer_id, seen.iter().copied().collect());
+                    }
+                }
+                // Both sides would rather talk over TCP, and UDP works.
+                if reached
+                    && !moved_to_tcp
+                    && let Some(addr) = node.verified_peer(&peer_id)
+                    && node.peer_transport(addr) == TransportKind::Udp
+                    && node.negotiated_transport(addr) == Some(TransportKind::Tcp)
+                {
+                    moved_to_tcp = true;
+                    info!("moving {peer_id} at {addr} to TCP, which both sides prefer");
+                    // The peer sees us at a new address, where a new
+                    // session starts.
+                    node.secure.forget(&addr);
+                    node.spawn_tcp_attempts(&peer_id, vec![addr]);
+                }
+                // Hellos alone fail when both NATs drop what comes first.
+                if node.signaling && !reached && !node.onion_only {
+                    if let Err(err) = node.answer_punch(&peer_id) {
+                        debug!("looking for a punch request failed: {err}");
+                    }
+                    if !seen.is_empty() {
+                        let at = *punch_at.get_or_insert_with(|| Instant::now() + PUNCH_AFTER);
+                        if Instant::now() >= at {
+                            punch_at = Some(Instant::now() + PUNCH_INTERVAL);
+                            if let Err(err) = node.request_punch(&peer_id) {
+                                warn!("asking {peer_id} to punch holes failed: {err}");
+                            }
+                        }
+                    }
+                    // Nothing direct got through: go by a relay it named.
+                    let at = *relay_at.get_or_insert_with(|| Instant::now() + RELAY_AFTER);
+                    if Instant::now() >= at {
+                        relay_at = Some(Instant::now() + RELAY_INTERVAL);
+                        node.try_relays(&peer_id);
+                    }
+                }
+                if node.onion.is_some()
+                    && onion_published.is_none_or(|at| at.elapsed() >= ONION_REPUBLISH)
+                {
+                    onion_published = Some(Instant::now());
+                    match public_key(&peer_id).and_then(|key| node.publish_onion(&key)) {
+                        Ok(()) => info!("told {peer_id} where our onion service is"),
+                        Err(err) => {
+                            warn!("publishing our onion record for {peer_id} failed: {err}")
+                        }
+                    }
+                }
+                // Tor gets through wherever TCP goes out, if slowly.
+                if node.tor.is_some() && !reached {
+                    let after = if node.onion_only {
+                        Duration::ZERO
+                    } else {
+                        ONION_AFTER
+                    };
+                    let at = *onion_at.get_or_insert_with(|| Instant::now() + after);
+                    if Instant::now() >= at {
+                        onion_at = Some(Instant::now() + ONION_INTERVAL);
+                        node.spawn_onion_attempt(&peer_id);
+                    }
+                }
+                if !node.shutdown.sleep(interval) {
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Connect to each of `candidates` of `peer_id` over TCP in the
+    /// background, saying hello through each connection.
+    fn spawn_tcp_attempts(&self, peer_id: &str, candidates: Vec<SocketAddr>) {
+        let node = self.clone();
+        let peer_id = peer_id.to_string();
+        self.shutdown.spawn("dhtmsg-tcp", move || {
+            for addr in candidates {
+                if node.shutdown.is_shutdown() {
+                    break;
+                }
+                match node.connect_tcp(addr) {
+                    Ok(()) => info!("saying hello to {peer_id} at {addr} over TCP"),
+                    Err(err) => debug!("connecting to {addr} over TCP failed: {err}"),
+                }
+            }
+        });
+    }
+
+    /// Reach `addr` over TCP, at the same port, and say hello through the
+    /// connection; datagrams to `addr` go through it from now on (see
+    /// [`DhtMsgBuilder::tcp_fallback`]). Lookup loops do this on their own
+    /// when nothing gets through over UDP.
+    pub fn connect_tcp(&self, addr: SocketAddr) -> Result<()> {
+        let tcp = self.tcp.as_ref().ok_or_else(|| {
+            DhtMsgError::socket(
+                "TCP fallback is off",
+                io::Error::from(io::ErrorKind::Unsupported),
+            )
+        })?;
+        tcp.connect(addr).map_err(|err| {
+            DhtMsgError::socket(format!("failed to connect to {addr} over TCP"), err)
+        })?;
+        // The session over UDP, if any, went nowhere.
+        if !self.secure.is_encrypted(&addr) {
+            self.secure.forget(&addr);
+        }
+        self.send_hello(addr)
+    }
+
+    /// Connect to `peer_id`'s onion service in the background, saying hello
+    /// through the connection.
+    fn spawn_onion_attempt(&self, peer_id: &str) {
+        let node = self.clone();
+        let peer_id = peer_id.to_string();
+        self.shutdown
+            .spawn("dhtmsg-onion", move || match node.connect_onion(&peer_id) {
+                Ok(addr) => info!("saying hello to {peer_id} at {addr} through Tor"),
+                Err(err) => debug!("reaching {peer_id} through Tor failed: {err}"),
+            });
+    }
+
+    /// Reach `peer_id` at the onion service its onion record for us names,
+    /// through Tor's SOCKS port, and say hello through the connection (see
+    /// [`DhtMsgBuilder::onion`]). Datagrams to the returned virtual address
+    /// go through it from now on. Lookup loops do this on their own when
+    /// nothing else gets through, or straight away in
+    /// [onion-only](DhtMsgBuilder::onion_only) mode.
+    pub fn connect_onion(&self, peer_id: &str) -> Result<SocketAddr> {
+        let peer_id = self.resolve_peer(peer_id)?;
+        let key = public_key(&peer_id)?;
+        let (Some(tor), Some(tcp)) = (&self.tor, &self.tcp) else {
+            return Err(DhtMsgError::socket(
+                "onion mode is off",
+                io::Error::from(io::ErrorKind::Unsupported),
+            ));
+        };
+        let addr = onion::virtual_addr(&key);
+        if !tcp.carries(&addr) {
+            let (host, port) = self.find_onion(&key).ok_or_else(|| {
+                DhtMsgError::socket(
+                    format!("{peer_id} left us no onion record"),
+                    io::Error::from(io::ErrorKind::NotFound),
+                )
+            })?;
+            let stream = tor.connect_name(&host, port).map_err(|err| {
+                DhtMsgError::socket(
+                    format!("failed to reach {host} through Tor"),
+                    io::Error::other(err),
+                )
+            })?;
+            tcp.attach(addr, stream).map_err(|err| {
+                DhtMsgError::socket(format!("failed to reach {host} through Tor"), err)
+            })?;
+            self.onion_peers.lock().unwrap().insert(addr);
+            // A session from an earlier connection went nowhere.
+            self.secure.forget(&addr);
+        }
+        self.send_hello(addr)?;
+        Ok(addr)
+    }
+
+    /// Our onion service's address, if Tor made one (see
+    /// [`DhtMsgBuilder::onion`]).
+    pub fn onion_address(&self) -> Option<&str> {
+        self.onion.as_deref().map(onion::Service::address)
+    }
+
+    /// Whether datagrams to `addr` go through Tor: to the onion service of
+    /// a peer, or back to one that came in through ours.
+    pub fn over_tor(&self, addr: &SocketAddr) -> bool {
+        self.onion_peers.lock().unwrap().contains(addr)
+            || (self.onion.is_some() && addr.ip().is_loopback() && self.over_tcp(addr))
+    }
+
+    /// Whether datagrams to `addr` go over TCP (see
+    /// [`connect_tcp`](Self::connect_tcp)).
+    pub fn over_tcp(&self, addr: &SocketAddr) -> bool {
+        self.tcp.as_ref().is_some_and(|tcp| tcp.carries(addr))
+    }
+
+    /// Say hello to `peer_id` through every relay its endpoint record
+    /// names.
+    fn try_relays(&self, peer_id: &str) {
+        let Some(record) = self.peer_record(peer_id) else {
+            return;
+        };
+        for hint in &record.relays {
+            let Some(relay) = hint
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.find(SocketAddr::is_ipv4))
+            else {
+                debug!("relay hint {hint} of {peer_id} does not resolve");
+                continue;
+            };
+            match self.relay_to(peer_id, relay) {
+                Ok(addr) => info!("saying hello to {peer_id} through relay {relay} at {addr}"),
+                Err(err) => warn!("going through relay {relay} failed: {err}"),
+            }
+        }
+    }
+
+    /// Reach `peer_id` through the relay at `relay`: register with it and
+    /// say hello to the peer's virtual address there, which is returned.
+    /// The peer answers from that address and proves itself as usual (see
+    /// [`verified_peer`](Self::verified_peer)) if it is registered with the
+    /// same relay, as peers that [use a relay](DhtMsgBuilder::use_relay)
+    /// are with those their endpoint record names. Lookup loops do this on
+    /// their own when nothing direct gets through. The registration is
+    /// renewed until shutdown.
+    pub fn relay_to(&self, peer_id: &str, relay: SocketAddr) -> Result<SocketAddr> {
+        let peer_id = self.resolve_peer(peer_id)?;
+        let key = public_key(&peer_id)?;
+        self.spawn_relay_keeper();
+        if !self.relay.uses(&relay) {
+            self.relay.register(relay).map_err(|err| {
+                DhtMsgError::socket(format!("failed to register with relay {relay}"), err)
+            })?;
+        }
+        let addr = self.relay.route(&key, relay);
+        self.send_hello(addr)?;
+        Ok(addr)
+    }
+
+    /// Relays announced under the relay infohash, other than us.
+    pub fn find_relays(&self) -> Result<Vec<SocketAddr>> {
+        let infohash = derive_relay_infohash(&self.infohash_salt);
+        let mut found = Vec::new();
+        for peers in self.dht_lookup().get_peers(infohash) {
+            for addr in peers {
+                let addr = SocketAddr::from(addr);
+                if !found.contains(&addr) {
+                    found.push(addr);
+                }
+            }
+        }
+        Ok(self.other_relays(found))
+    }
+
+    /// `found` without ourselves and addresses out of scope.
+    pub(crate) fn other_relays(&self, mut found: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let us = self
+            .dht
+            .info()
+            .public_address()
+            .map(|public| SocketAddr::from((*public.ip(), self.announced_port())));
+        found.retain(|addr| Some(*addr) != us && self.scope.accepts(addr));
+        found
+    }
+
+    /// Relays that answered our registration lately.
+    pub fn registered_relays(&self) -> Vec<SocketAddr> {
+        self.relay.live_relays()
+    }
+
+    /// The relay that `addr` leads through, if it is the virtual address
+    /// of a peer we reach through one (see [`relay_to`](Self::relay_to)).
+    pub fn relayed_through(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        self.relay.relay_of(addr)
+    }
+
+    /// Renew our registrations with relays until shutdown, and with
+    /// [`use_relay`](DhtMsgBuilder::use_relay) register with a relay
+    /// whenever we have none, republishing our record as they change.
+    fn spawn_relay_keeper(&self) {
+        if self.relay_keeper.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let node = self.clone();
+        self.shutdown.spawn("dhtmsg-relay", move || {
+            let mut renewed: Option<Instant> = None;
+            let mut tried: HashSet<SocketAddr> = HashSet::new();
+            let mut published: Vec<SocketAddr> = Vec::new();
+            loop {
+                if renewed.is_none_or(|at| at.elapsed() >= relay::REGISTER_INTERVAL) {
+                    renewed = Some(Instant::now());
+                    node.relay.renew();
+                }
+                if node.use_relay && !node.relay.has_relays() {
+                    node.pick_relay(&mut tried);
+                }
+                let live = node.relay.live_relays();
+                if live != published {
+                    published = live;
+                    if node.signaling
+                        && let Err(err) = node.publish_endpoint()
+                    {
+                        warn!("publishing our relays failed: {err}");
+                    }
+                }
+                if !node.shutdown.sleep(RELAY_TICK) {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Register with a relay not in `tried`, starting over once all were.
+    fn pick_relay(&self, tried: &mut HashSet<SocketAddr>) {
+        let found = match self.find_relays() {
+            Ok(found) => found,
+            Err(err) => {
+                warn!("looking for relays failed: {err}");
+                return;
+            }
+        };
+        if found.iter().all(|relay| tried.contains(relay)) {
+            tried.clear();
+        }
+        let Some(relay) = found.into_iter().find(|relay| tried.insert(*relay)) else {
+            debug!("no relay found");
+            return;
+        };
+        info!("registering with relay {relay}");
+        if let Err(err) = self.relay.register(relay) {
+            warn!("registering with relay {relay} failed: {err}");
+        }
+    }
+
+    /// How long since `peer_id` last sent an authentic frame from where it
+    /// [proved itself](Self::verified_peer); `None` if it has not, or
+    /// said goodbye since.
+    pub fn peer_silence(&self, peer_id: &str) -> Option<Duration> {
+        let addr = self.verified_peer(peer_id)?;
+        let heard = *self.heard.lock().unwrap().get(&addr)?;
+        Some(heard.elapsed())
+    }
+
+    /// Where `peer_id` last proved it holds the key of its ID, answering the
+    /// challenge of a hello exchange; `None` if no candidate has yet.
+    pub fn verified_peer(&self, peer_id: &str) -> Option<SocketAddr> {
+        let peer_id = peer_id.to_ascii_lowercase();
+        self.peer_addrs.lock().unwrap().get(&peer_id).copied()
+    }
+
+    /// Find the address of `peer_id` that proves to be the peer within
+    /// `timeout`: look it up and say hello to every candidate, again every
+    /// couple of seconds, until one answers the challenge as the holder of
+    /// the ID's key. Lookups of public infohashes also return BitTorrent
+    /// clients and addresses others announced for us, which never get that
+    /// far. Requires a running receiver (see
+    /// [`spawn_receiver`](Self::spawn_receiver)).
+    pub fn verify_peer(&self, peer_id: &str, timeout: Duration) -> Result<SocketAddr> {
+        let events = self.subscribe();
+        let peer_id = self.resolve_peer(peer_id)?;
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(addr) = self.verified_peer(&peer_id) {
+                return Ok(addr);
+            }
+            for addr in self.find_peer(&peer_id)? {
+                if let Err(err) = self.send_hello(addr) {
+                    debug!("failed to send hello to {addr}: {err}");
+                }
+            }
+            let round = (Instant::now() + VERIFY_RETRY).min(deadline);
+            while let Ok(event) =
+                events.recv_timeout(round.saturating_duration_since(Instant::now()))
+            {
+                if let Event::PeerAuthenticated { from, id } = event
+                    && id == peer_id
+                {
+                    return Ok(from);
+                }
+            }
+        }
+        Err(DhtMsgError::PeerNotVerified { peer_id, timeout })
+    }
+
+    /// Punch holes through the NATs between us and `peer_id`: ask it in the
+    /// DHT (see the `punch` module) to send to us at the same moment as we
+    /// send to it, 20 s from now, and wait up to `timeout` for one of its
+    /// candidates to prove to be the peer. This gets through NATs that
+    /// drop hellos from where nothing was sent to, as long as both keep a
+    /// port per local one. The peer answers requests during its
+    /// [lookups](Self::spawn_lookup) if it enabled
+    /// [signaling](DhtMsgBuilder::signaling). Requires a running receiver.
+    ///
+    /// Behind a NAT that gives each destination another port, the request
+    /// carries a port prediction, the start is 40 s ahead, and both sides
+    /// spray hellos from many sockets or at many ports (see
+    /// [`DhtMsgBuilder::port_prediction`]).
+    pub fn punch(&self, peer_id: &str, timeout: Duration) -> Result<SocketAddr> {
+        let events = self.subscribe();
+        let peer_id = self.resolve_peer(peer_id)?;
+        let deadline = Instant::now() + timeout;
+        self.request_punch(&peer_id)?;
+        while let Ok(event) =
+            events.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            if let Event::PeerAuthenticated { from, id } = event
+                && id == peer_id
+            {
+                return Ok(from);
+            }
+        }
+        Err(DhtMsgError::PeerNotVerified { peer_id, timeout })
+    }
+
+    /// Publish a punch request to `peer_id` and burst at its candidates at
+    /// the start it names.
+    fn request_punch(&self, peer_id: &str) -> Result<()> {
+        let key = public_key(peer_id)?;
+        let prediction = self.predict_ports();
+        let lead = if prediction.is_some() {
+            punch::PREDICTION_LEAD
+        } else {
+            punch::LEAD
+        };
+        let start = punch::start_in(lead);
+        self.publish_punch(&key, start, prediction)?;
+        // The peer may answer with a request for the same start, which is
+        // not one to answer in turn.
+        let peer_id = hex::encode(key.as_bytes());
+        self.punches.lock().unwrap().insert(peer_id.clone(), start);
+        info!("asked {peer_id} to punch holes in {lead:?}");
+        self.spawn_burst(
+            &peer_id,
+            Burst {
+                start,
+                spray: prediction.is_some(),
+                theirs: None,
+                await_answer: true,
+            },
+        );
+        Ok(())
+    }
+
+    fn publish_punch(
+        &self,
+        key: &VerifyingKey,
+        start: SystemTime,
+        prediction: Option<Prediction>,
+    ) -> Result<()> {
+        let salt = punch::salt(&self.infohash_salt, key);
+        let item = punch::request(&self.identity, start, prediction, &salt);
+        let target = *item.target();
+        self.dht_store()
+            .put_mutable(item, None)
+            .map_err(|source| DhtMsgError::PublishPunch { target, source })?;
+        Ok(())
+    }
+
+    /// Tell the peer with `key` where our onion service is, if we have one.
+    fn publish_onion(&self, key: &VerifyingKey) -> Result<()> {
+        let Some(service) = &self.onion else {
+            return Ok(());
+        };
+        let salt = onion::salt(&self.infohash_salt, key);
+        let item = onion::record(&self.identity, service, key, &salt);
+        let target = *item.target();
+        self.dht_store()
+            .put_mutable(item, None)
+            .map_err(|source| DhtMsgError::PublishOnion { target, source })?;
+        Ok(())
+    }
+
+    /// Publish our onion record for every allowed peer, which may look us
+    /// up without our looking them up.
+    fn publish_onions(&self) -> Result<()> {
+        if self.onion.is_none() {
+            return Ok(());
+        }
+        let allowed = Vec::from_iter(self.allowed_peers.lock().unwrap().iter().copied());
+        let mut result = Ok(());
+        for key in allowed {
+            let Ok(key) = VerifyingKey::from_bytes(&key) else {
+                continue;
+            };
+            if let Err(err) = self.publish_onion(&key) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    /// Where the onion service of `key` is, from the newest onion record it
+    /// left for us.
+    fn find_onion(&self, key: &VerifyingKey) -> Option<(String, u16)> {
+        let salt = onion::salt(&self.infohash_salt, &self.identity.verifying_key());
+        let items = self
+            .dht_lookup()
+            .get_mutable(key.as_bytes(), Some(&salt), None);
+        onion::newest(items, key, &salt, &self.identity)
+    }
+
+    /// The newest punch request `key` left for us, if it is current.
+    fn find_punch(&self, key: &VerifyingKey) -> Option<punch::Punch> {
+        let salt = punch::salt(&self.infohash_salt, &self.identity.verifying_key());
+        let items = self
+            .dht_lookup()
+            .get_mutable(key.as_bytes(), Some(&salt), None);
+        punch::newest(items, key, &salt)
+    }
+
+    /// Where the NAT maps our next sockets, if it gives each destination
+    /// another port and port prediction is enabled.
+    fn predict_ports(&self) -> Option<Prediction> {
+        if !self.port_prediction
+            || self.spray.is_none()
+            || self.nat_mapping() != Some(NatMapping::EndpointDependent)
+        {
+            return None;
+        }
+        let prediction = birthday::predict(&self.stun_servers, &self.bind);
+        match prediction {
+            Some(prediction) if prediction.span == 0 => {
+                info!("the NAT picks ports at random; peers spray all of them");
+            }
+            Some(prediction) => info!(
+                "predicted the NAT's next ports from {}, every {}",
+                prediction.port, prediction.step
+            ),
+            None => warn!("no STUN server answered; punching without a port prediction"),
+        }
+        prediction
+    }
+
+    /// Look for a punch request from `peer_id` and, if there is one we have
+    /// not answered, burst at the peer's candidates at the start it names.
+    /// Returns whether there was a new request. Lookup loops call this on
+    /// every round until the peer is reached, if signaling is enabled.
+    pub fn answer_punch(&self, peer_id: &str) -> Result<bool> {
+        let key = public_key(peer_id)?;
+        let Some(request) = self.find_punch(&key) else {
+            return Ok(false);
+        };
+        let peer_id = hex::encode(key.as_bytes());
+        let mut punches = self.punches.lock().unwrap();
+        if punches
+            .get(&peer_id)
+            .is_some_and(|&answered| answered >= request.start)
+        {
+            return Ok(false);
+        }
+        punches.insert(peer_id.clone(), request.start);
+        drop(punches);
+        info!("{peer_id} asked us to punch holes");
+        // The peer needs our prediction to aim at, if we have one.
+        let prediction = self.predict_ports();
+        if prediction.is_some() {
+            self.publish_punch(&key, request.start, prediction)?;
+        }
+        self.spawn_burst(
+            &peer_id,
+            Burst {
+                start: request.start,
+                spray: prediction.is_some(),
+                theirs: request.prediction,
+                await_answer: false,
+            },
+        );
+        Ok(true)
+    }
+
+    /// Send to the candidates of `peer_id` in quick succession from the
+    /// start on, or spray hellos if either side predicted its ports.
+    fn spawn_burst(&self, peer_id: &str, burst: Burst) {
+        let node = self.clone();
+        let peer_id = peer_id.to_string();
+        self.shutdown.spawn("dhtmsg-punch", move || {
+            let candidates = node.find_peer(&peer_id).unwrap_or_default();
+            let mut theirs = burst.theirs;
+            if burst.await_answer {
+                theirs = node.await_punch_answer(&peer_id, burst.start);
+            }
+            let wait = burst
+                .start
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            if candidates.is_empty() || !node.shutdown.sleep(wait) {
+                return;
+            }
+            if burst.spray || theirs.is_some() {
+                node.spray_burst(&peer_id, &candidates, burst.spray, theirs);
+                return;
+            }
+            debug!(
+                "punching holes to {} candidates of {peer_id}",
+                candidates.len()
+            );
+            // The first hello starts a handshake; sending its first message
+            // again is what goes out after.
+            for &addr in &candidates {
+                if let Err(err) = node.send_hello(addr) {
+                    debug!("failed to send hello to {addr}: {err}");
+                }
+            }
+            for _ in 1..PUNCH_PACKETS {
+                if !node.shutdown.sleep(PUNCH_SPACING) {
+                    return;
+                }
+                for addr in &candidates {
+                    node.secure.resend_handshake(addr);
+                }
+            }
+        });
+    }
+
+    /// The prediction the peer answers our request for `start` with, if
+    /// it does before the start.
+    fn await_punch_answer(&self, peer_id: &str, start: SystemTime) -> Option<Prediction> {
+        let key = public_key(peer_id).ok()?;
+        while SystemTime::now() + PUNCH_ANSWER_POLL < start {
+            if let Some(answer) = self.find_punch(&key)
+                && answer.start == start
+            {
+                if answer.prediction.is_some() {
+                    debug!("{peer_id} answered our punch request with a port prediction");
+                }
+                return answer.prediction;
+            }
+            if !self.shutdown.sleep(PUNCH_ANSWER_POLL) {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Spray hellos at the ports `theirs` predicts for the peer, or at its
+    /// candidates if its NAT keeps one port: from extra sockets, each
+    /// aiming at one port, if `extra`, else from the hello socket.
+    fn spray_burst(
+        &self,
+        peer_id: &str,
+        candidates: &[SocketAddr],
+        extra: bool,
+        theirs: Option<Prediction>,
+    ) {
+        // Predictions are about the NAT in front of the public addresses.
+        let mut ips: Vec<Ipv4Addr> = Vec::new();
+        for candidate in candidates {
+            if let SocketAddr::V4(candidate) = candidate
+                && !ips.contains(candidate.ip())
+            {
+                ips.push(*candidate.ip());
+            }
+        }
+        if ips.iter().any(|ip| !address::is_private((*ip).into())) {
+            ips.retain(|ip| !address::is_private((*ip).into()));
+        }
+        let pool: Vec<SocketAddr> = match theirs {
+            Some(prediction) => {
+                let ports = prediction.ports();
+                ips.iter()
+                    .flat_map(|ip| ports.iter().map(|port| SocketAddr::from((*ip, *port))))
+                    .collect()
+            }
+            None => candidates
+                .iter()
+                .filter(|candidate| match candidate {
+                    SocketAddr::V4(candidate) => ips.contains(candidate.ip()),
+                    SocketAddr::V6(_) => false,
+                })
+                .copied()
+                .collect(),
+        };
+        let spray = self.spray.as_ref().filter(|_| extra);
+        let targets = match spray {
+            Some(spray) => {
+                let sockets = match birthday::open(birthday::SOCKETS, &self.bind) {
+                    Ok(sockets) => sockets,
+                    Err(err) => {
+                        warn!("failed to open sockets to spray hellos from: {err}");
+                        return;
+                    }
+                };
+                let targets = birthday::draw(&pool, sockets.len());
+                if targets.is_empty() {
+                    return;
+                }
+                let mut aims: HashMap<SocketAddr, Vec<Arc<UdpSocket>>> = HashMap::new();
+                for (i, socket) in sockets.into_iter().enumerate() {
+                    aims.entry(targets[i % targets.len()])
+                        .or_default()
+                        .push(socket);
+                }
+                spray.aim(aims);
+                targets
+            }
+            None => birthday::draw(&pool, birthday::PROBES),
+        };
+        debug!(
+            "spraying hellos at {} addresses of {peer_id} from {}",
+            targets.len(),
+            if spray.is_some() {
+                "extra sockets"
+            } else {
+                "the hello socket"
+            }
+        );
+        for &addr in &targets {
+            if let Err(err) = self.send_hello(addr) {
+                debug!("failed to send hello to {addr}: {err}");
+            }
+        }
+        for _ in 1..PUNCH_PACKETS {
+            if !self.shutdown.sleep(PUNCH_SPACING) {
+                return;
+            }
+            for addr in &targets {
+                self.secure.resend_handshake(addr);
+            }
+        }
+        if !self.shutdown.sleep(SPRAY_LINGER) {
+            return;
+        }
+        // Give up on what did not answer, rather than retry it for seconds.
+        for addr in &targets {
+            if !candidates.contains(addr) && !self.secure.is_encrypted(addr) {
+                self.secure.forget(addr);
+                self.challenges.forget(addr);
+            }
+        }
+        if let Some(spray) = spray {
+            spray.settle(&targets);
+        }
+    }
+
+    /// Pair with whoever calls this with the same `code` (see
+    /// [`pairing_code`](crate::pairing_code)) within `timeout`, learning its
+    /// ID and a key only the two of us know. Both sides announce and look up
+    /// the rendezvous the code names and run a PAKE there; a peer with
+    /// another code makes it fail with [`DhtMsgError::PairingFailed`].
+    ///
+    /// The receiver must be running. Allowed peers, if any, still apply, so
+    /// a node pairing with new peers is usually built without them.
+    pub fn pair(&self, code: &str, timeout: Duration) -> Result<Paired> {
+        let code = Code::parse(code)?;
+        let rendezvous = code.rendezvous();
+        let (pairing, outcome) = Pairing::new(&code, &self.local_id);
+        let message = pairing.message().to_vec();
+        *self.pairing.lock().unwrap() = Some(pairing);
+        info!("pairing at rendezvous {rendezvous}");
+        let deadline = Instant::now() + timeout;
+        let mut announced: Option<Instant> = None;
+        let mut candidates = HashSet::new();
+        loop {
+            if announced.is_none_or(|at| at.elapsed() >= PAIRING_ANNOUNCE_INTERVAL) {
+                if let Err(err) = self
+                    .dht_store()
+                    .announce_peer(rendezvous, Some(self.announced_port()))
+                {
+                    warn!("announcing the pairing rendezvous failed: {err}");
+                }
+                announced = Some(Instant::now());
+            }
+            let public_ip = self.dht.info().public_address().map(|addr| *addr.ip());
+            // Our own announcement is among the peers found.
+            let ours = |addr: &SocketAddrV4| {
+                addr.port() == self.announced_port()
+                    && (addr.ip().is_loopback() || Some(*addr.ip()) == public_ip)
+            };
+            let found = self.dht_lookup().get_peers(rendezvous).flatten();
+            candidates.extend(
+                found
+                    .filter(|addr| !ours(addr))
+                    .map(SocketAddr::V4)
+                    .filter(|addr| self.scope.accepts(addr)),
+            );
+            if let Some(pairing) = self.pairing.lock().unwrap().as_ref() {
+                candidates.extend(pairing.peers());
+            }
+            for &addr in &candidates {
+                self.send_pake(addr, &message);
+            }
+            match outcome.recv_timeout(PAIRING_RETRY) {
+                Ok(Ok(paired)) => {
+                    info!("paired with {} at {}", paired.peer_id, paired.addr);
+                    return Ok(paired);
+                }
+                Ok(Err(err)) => return Err(err),
+                Err(_) if self.shutdown.is_shutdown() => return Err(DhtMsgError::Closed("node")),
+                Err(_) if Instant::now() >= deadline => {
+                    return Err(DhtMsgError::PairingTimedOut(timeout));
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn send_pake(&self, addr: SocketAddr, message: &[u8]) {
+        let pake = Control::Pake {
+            id: self.local_id.to_string(),
+            msg: message.to_vec(),
+        };
+        if let Err(err) = self.send_control(addr, &pake) {
+            debug!("failed to send pairing message to {addr}: {err}");
+        }
+    }
+
+    /// Limit on message payloads (see [`DhtMsgBuilder::max_message_bytes`]).
+    pub fn max_message_bytes(&self) -> usize {
+        self.max_message_bytes
+    }
+
+    /// Received messages dropped so far for exceeding
+    /// [`max_message_bytes`](Self::max_message_bytes).
+    pub fn oversize_messages(&self) -> u64 {
+        self.oversize_messages.load(Ordering::Relaxed)
+    }
+
+    /// Inbound datagrams dropped so far because they were not frames of this
+    /// protocol version (stray traffic, other software, incompatible peers)
+    /// or were not signed by the ID they claim.
+    pub fn ignored_packets(&self) -> u64 {
+        self.ignored_packets.load(Ordering::Relaxed)
+    }
+
+    /// Unencrypted frames dropped so far because the node does not allow
+    /// [`plaintext`](DhtMsgBuilder::plaintext).
+    pub fn plaintext_packets(&self) -> u64 {
+        self.secure.plaintext_dropped()
+    }
+
+    /// Frames dropped so far because their pre-shared key tag was missing,
+    /// wrong or stale (see [`DhtMsgBuilder::psk`]).
+    pub fn unauthenticated_packets(&self) -> u64 {
+        self.psk.as_ref().map_or(0, |psk| psk.unauthenticated())
+    }
+
+    /// Authentic frames dropped so far as replays: their timestamp was
+    /// outside the [replay window](DhtMsgBuilder::replay_window) or their
+    /// sender had used the nonce before.
+    pub fn replayed_packets(&self) -> u64 {
+        self.replayed_packets.load(Ordering::Relaxed)
+    }
+
+    /// Data frames dropped so far because their sender had not yet answered
+    /// the challenge of our hello or ack, and so was not established.
+    pub fn unproven_packets(&self) -> u64 {
+        self.unproven_packets.load(Ordering::Relaxed)
+    }
+
+    /// Authentic frames dropped so far because their sender is not an
+    /// [allowed peer](DhtMsgBuilder::allow_peer).
+    pub fn unauthorized_packets(&self) -> u64 {
+        self.unauthorized_packets.load(Ordering::Relaxed)
+    }
+
+    /// Frames received so far whose checksum did not match, i.e. damaged in
+    /// transit. They are dropped before anything parses them.
+    pub fn corrupted_packets(&self) -> u64 {
+        self.corrupted_packets.load(Ordering::Relaxed)
+    }
+
+    /// Packets from BitTorrent clients (peer handshakes, uTP, DHT queries)
+    /// that reached the hello port because it is announced in the torrent
+    /// DHT. They are dropped unanswered and kept out of
+    /// [`ignored_packets`](Self::ignored_packets) and the logs.
+    pub fn bittorrent_packets(&self) -> u64 {
+        self.bittorrent_packets.load(Ordering::Relaxed)
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Stop all loops, say goodbye to established peers and join the node's
+    /// threads. Same as `shutdown_handle().shutdown()`.
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown();
+    }
+}
+
+/// Fail unless `name` is 1 to 255 bytes long, as the start of a stream on
+/// a channel carries.
+fn check_channel(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > channel::MAX_NAME_BYTES {
+        return Err(DhtMsgError::InvalidChannel {
+            name: name.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Fail unless `topic` is 1 to 255 bytes long, as a topic frame carries.
+fn check_topic(topic: &str) -> Result<()> {
+    if topic.is_empty() || topic.len() > usize::from(u8::MAX) {
+        return Err(DhtMsgError::InvalidTopic {
+            topic: topic.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Bind the non-blocking UDP hello socket, optionally on a port whose public
+/// mapping was learned first. Returns the socket and the public port, if known.
+fn join_infohashes(infohashes: &[Id]) -> String {
+    let infohashes: Vec<String> = infohashes.iter().map(Id::to_string).collect();
+    infohashes.join(", ")
+}
+
+/// The bootstrap nodes of the mainline DHT, which the `mainline` crate
+/// uses by default but does not export.
+const PUBLIC_BOOTSTRAP_NODES: [&str; 4] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "dht.libtorrent.org:25401",
+    "relay.pkarr.org:6881",
+];
+
+/// Block until `dht` is usable, so that announcements and lookups have
+/// somewhere to go: its bootstrap lookup found nodes, or its routing table
+/// holds enough of them already. Fails after `timeout`.
+fn wait_for_bootstrap(dht: &mainline::Dht, timeout: Duration) -> Result<()> {
+    let started = Instant::now();
+    let (done_tx, done) = flume::bounded(1);
+    let querying = dht.clone();
+    // Not a node thread: it ends with its current lookup once we stop
+    // waiting.
+    thread::spawn(move || {
+        // Each attempt waits for a full lookup of our own ID.
+        while !querying.bootstrapped() {
+            if done_tx.is_disconnected() {
+                return;
+            }
+            debug!("no DHT node found yet; retrying");
+            thread::sleep(BOOTSTRAP_RETRY);
+        }
+        let _ = done_tx.send(());
+    });
+    loop {
+        if done.recv_timeout(BOOTSTRAP_POLL).is_ok()
+            || dht.to_bootstrap().len() >= BOOTSTRAP_READY_NODES
+        {
+            break;
+        }
+        if started.elapsed() >= timeout {
+            return Err(DhtMsgError::BootstrapTimedOut(timeout));
+        }
+    }
+    info!(
+        "bootstrapped in {:.1?} with {} nodes known",
+        started.elapsed(),
+        dht.to_bootstrap().len()
+    );
+    Ok(())
+}
+
+/// The nodes to bootstrap from: those in the routing table cache at
+/// `routing_cache` first, if any, then `bootstrap`. The cache may be stale,
+/// so without `bootstrap` the public nodes follow it, unless the DHT is
+/// `private`; an empty list leaves the choice to mainline.
+pub(crate) fn with_cached_nodes(
+    routing_cache: Option<&Path>,
+    bootstrap: Vec<String>,
+    private: bool,
+) -> Vec<String> {
+    let cached = routing_cache.map(read_routing_cache).unwrap_or_default();
+    if cached.is_empty() {
+        return bootstrap;
+    }
+    info!("bootstrapping from {} cached DHT nodes first", cached.len());
+    let usual = if bootstrap.is_empty() && !private {
+        PUBLIC_BOOTSTRAP_NODES.map(str::to_string).to_vec()
+    } else {
+        bootstrap
+    };
+    [cached, usual].concat()
+}
+
+/// The nodes listed in the routing table cache at `path`; none if it cannot
+/// be read.
+fn read_routing_cache(path: &Path) -> Vec<String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => {
+            warn!("ignoring routing table cache {}: {err}", path.display());
+            Vec::new()
+        }
+    }
+}
+
+/// Replace the routing table cache at `path` by the nodes `dht` knows now,
+/// unless it knows none.
+pub(crate) fn write_routing_cache(dht: &mainline::Dht, path: &Path) -> Result<()> {
+    let nodes = dht.to_bootstrap();
+    if nodes.is_empty() {
+        return Ok(());
+    }
+    let error = |source| DhtMsgError::RoutingCache {
+        path: path.to_path_buf(),
+        source,
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(error)?;
+    }
+    // Written aside and renamed, so that a crash leaves the old list.
+    let partial = path.with_extension("partial");
+    fs::write(&partial, nodes.join("\n") + "\n").map_err(error)?;
+    fs::rename(&partial, path).map_err(error)?;
+    debug!("saved {} DHT nodes to {}", nodes.len(), path.display());
+    Ok(())
+}
+
+/// A DHT on an ephemeral port, bootstrapping from `bootstrap` unless it is
+/// empty.
+fn dht_builder(bootstrap: &[String]) -> mainline::DhtBuilder {
+    let mut builder = mainline::Dht::builder();
+    builder.port(0);
+    if !bootstrap.is_empty() {
+        builder.bootstrap(bootstrap);
+    }
+    builder
+}
+
+/// Bind the hello socket and learn its public address if
+/// `discover_port`: from `stun_servers`, or else on the local port whose
+/// public one a short-lived DHT discovers. It cannot be the long-lived
+/// DHT's socket: mainline binds its own and reads every datagram on it, so
+/// KRPC and hello traffic cannot be told apart on one socket.
+fn bind_hello_socket(
+    discover_port: bool,
+    stun_servers: &[String],
+    bootstrap: &[String],
+    bind: &Bind,
+) -> Result<(HelloSocket, PortInfo)> {
+    let (socket, port_info) = if discover_port {
+        match discover_with_stun(stun_servers, bind)? {
+            Some(found) => found,
+            None => {
+                // Learn a public port for the app by briefly starting a DHT on a chosen local port.
+                let port_info = discover_public_port(bootstrap)?;
+                info!(
+                    "discovered local hello port {} with public address {:?}",
+                    port_info.local_port, port_info.public
+                );
+                (bind_port(port_info.local_port, bind)?, port_info)
+            }
+        }
+    } else {
+        let port_info = PortInfo {
+            local_port: 0,
+            public: None,
+            stun: None,
+        };
+        (bind_port(0, bind)?, port_info)
+    };
+    let hello_port = socket
+        .local_addr()
+        .map_err(|err| DhtMsgError::socket("failed to read bound port", err))?
+        .port();
+    let families = match socket {
+        HelloSocket::V4(_) => "IPv4",
+        HelloSocket::V6(_) => "IPv6",
+        HelloSocket::DualStack(_) | HelloSocket::Split { .. } => "IPv4 and IPv6",
+    };
+    info!("hello socket bound on UDP port {hello_port} ({families})");
+    Ok((socket, port_info))
+}
+
+fn bind_port(port: u16, bind: &Bind) -> Result<HelloSocket> {
+    HelloSocket::bind(port, bind)
+        .map_err(|err| DhtMsgError::socket(format!("failed to bind UDP socket on {port}"), err))
+}
+
+/// A burst of a punch, to run at `start`.
+struct Burst {
+    start: SystemTime,
+    /// Whether we spray from extra sockets, having predicted our ports.
+    spray: bool,
+    /// The peer's prediction, if it made one.
+    theirs: Option<Prediction>,
+    /// Whether to look for the peer's answer to our request, which may
+    /// carry its prediction.
+    await_answer: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PortInfo {
+    local_port: u16,
+    public: Option<SocketAddrV4>,
+    /// Set if `public` is what STUN servers saw.
+    stun: Option<stun::Binding>,
+}
+
+/// Bind the hello socket on any port and ask `stun_servers` where the NAT
+/// maps it. None if none answers.
+fn discover_with_stun(
+    stun_servers: &[String],
+    bind: &Bind,
+) -> Result<Option<(HelloSocket, PortInfo)>> {
+    if stun_servers.is_empty() {
+        return Ok(None);
+    }
+    let socket = bind_port(0, bind)?;
+    let Some(binding) = stun::discover(&socket, stun_servers) else {
+        info!("no STUN server answered; discovering the public port with a DHT");
+        return Ok(None);
+    };
+    let local_port = socket
+        .local_addr()
+        .map_err(|err| DhtMsgError::socket("failed to read bound port", err))?
+        .port();
+    info!(
+        "STUN servers see local hello port {local_port} at {}",
+        binding.public
+    );
+    match binding.mapping {
+        Some(NatMapping::EndpointIndependent) => {
+            info!("the NAT keeps one public port whatever we send to")
+        }
+        Some(NatMapping::EndpointDependent) => warn!(
+            "the NAT maps the hello socket to another public port for each destination; \
+             peers behind NATs can hardly reach us directly"
+        ),
+        None => debug!("only one STUN server answered; NAT mapping behaviour unknown"),
+    }
+    let port_info = PortInfo {
+        local_port,
+        public: Some(binding.public),
+        stun: Some(binding),
+    };
+    Ok(Some((socket, port_info)))
+}
+
+fn discover_public_port(bootstrap: &[String]) -> Result<PortInfo> {
+    // Let DHT bind a port (0 = OS picks). We reuse that local port for the app.
+    let temp = dht_builder(bootstrap)
+        .build()
+        .map_err(DhtMsgError::Bootstrap)?;
+    let mut public: Option<SocketAddrV4> = None;
+    let mut attempts: u32 = 0;
+    while public.is_none() {
+        public = temp.info().public_address();
+        if public.is_some() {
+            break;
+        }
+        attempts += 1;
+        if attempts.is_multiple_of(20) {
+            info!("waiting for public port discovery ({} checks)...", attempts);
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    let local_port = temp.info().local_addr().port();
+    drop(temp);
+    thread::sleep(Duration::from_millis(200));
+    Ok(PortInfo {
+        local_port,
+        public,
+        stun: None,
+    })
+}
+
+/// State owned by the receive thread.
+struct Receiver {
+    node: DhtMsg,
+    dedup: Dedup,
+    /// Key each address last signed a valid frame with; stream frames, which
+    /// name no sender, are checked against it.
+    keys: HashMap<SocketAddr, VerifyingKey>,
+    /// Keys that answered a challenge from each address: established peers.
+    proven: HashMap<SocketAddr, VerifyingKey>,
+    replay: ReplayGuard,
+}
+
+impl Receiver {
+    fn run<F>(mut self, mut handler: F)
+    where
+        F: FnMut(&[u8], SocketAddr),
+    {
+        let node = self.node.clone();
+        let mut buf = vec![0u8; RECV_BUFFER_BYTES];
+        let mut reassembler = Reassembler::default();
+        while !node.shutdown.is_shutdown() {
+            match node.transport.recv_from(&mut buf) {
+                Ok((len, peer)) => {
+                    let mut datagram = &buf[..len];
+                    let whole;
+                    match wire::decode(datagram) {
+                        Ok(frame)
+                            if matches!(frame.kind, FrameType::Stream | FrameType::Packet) =>
+                        {
+                            let stream = frame.kind == FrameType::Stream;
+                            if stream && !is_stream_frame(frame.body) {
+                                self.ignore(Rejected::Malformed, peer);
+                            } else if !node.allows_cleartext(peer) {
+                                node.secure.drop_plaintext(frame.kind, peer);
+                            } else if !self.proven.contains_key(&peer) {
+                                node.count_unproven(frame.kind, peer);
+                            } else if !frame.verify(&self.proven[&peer]) {
+                                self.ignore(Rejected::Forged, peer);
+                            } else if !self.fresh(&frame, self.proven[&peer], peer) {
+                                // Counted as replayed.
+                            } else {
+                                node.heard.lock().unwrap().insert(peer, Instant::now());
+                                if stream {
+                                    node.streams.dispatch(&node.transport, frame.body, peer);
+                                } else {
+                                    node.deliver_packet(frame.body, peer);
+                                }
+                            }
+                            continue;
+                        }
+                        // Pieces are only held to their shape, since the
+                        // reassembled frame is signed.
+                        Ok(frame) if frame.kind == FrameType::Fragment => {
+                            if !is_fragment(frame.body) {
+                                self.ignore(Rejected::Malformed, peer);
+                                continue;
+                            }
+                            match reassembler.push(frame.body, peer) {
+                                Some(reassembled) => whole = reassembled,
+                                None => continue,
+                            }
+                            datagram = &whole;
+                            match wire::decode(datagram) {
+                                Ok(frame) => self.answer_frame(&frame, peer),
+                                Err(rejected) => self.ignore(rejected, peer),
+                            }
+                        }
+                        Ok(frame) => self.answer_frame(&frame, peer),
+                        Err(Rejected::BitTorrent) => {
+                            self.ignore(Rejected::BitTorrent, peer);
+                            continue;
+                        }
+                        Err(rejected) => self.ignore(rejected, peer),
+                    }
+                    handler(datagram, peer);
+                }
+                // uTP packets and stream segments are only read here, so
+                // look again soon while a connection or stream is open: the
+                // windows of both follow round trips, which sleeping here
+                // would stretch. VPN packets would wait as long.
+                Err(err)
+                    if err.kind() == std::io::ErrorKind::WouldBlock
+                        && (node.utp.busy()
+                            || node.streams.busy()
+                            || node.packets.lock().unwrap().is_some()) =>
+                {
+                    node.shutdown.sleep(STREAM_POLL);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    node.shutdown.sleep(Duration::from_millis(200));
+                }
+                Err(err) => {
+                    error!("UDP recv error: {err}");
+                    node.shutdown.sleep(Duration::from_secs(1));
+                }
+            }
+        }
+    }
+
+    /// Count a datagram that is not a frame we understand, or is damaged.
+    fn ignore(&self, rejected: Rejected, peer: SocketAddr) {
+        match rejected {
+            Rejected::Foreign => debug!("foreign packet from {peer} (ignored)"),
+            // Expected noise on a port announced in the torrent DHT.
+            Rejected::BitTorrent => {
+                trace!("BitTorrent packet from {peer} (dropped)");
+                self.node.bittorrent_packets.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Rejected::Version(version) => {
+                debug!("protocol version {version} packet from {peer} (ignored)");
+            }
+            Rejected::Malformed => debug!("malformed frame from {peer} (ignored)"),
+            Rejected::Forged => debug!("frame from {peer} with a bad signature (ignored)"),
+            // Our own traffic, damaged on the way: counted separately.
+            Rejected::Corrupt => {
+                debug!("frame from {peer} failed its checksum (dropped)");
+                let corrupted = self.node.corrupted_packets.fetch_add(1, Ordering::Relaxed) + 1;
+                if corrupted.is_power_of_two() {
+                    warn!("dropped {corrupted} corrupted frames so far");
+                }
+                return;
+            }
+        }
+        let ignored = self.node.ignored_packets.fetch_add(1, Ordering::Relaxed) + 1;
+        if ignored.is_power_of_two() {
+            info!("ignored {ignored} foreign, malformed or forged packets so far");
+        }
+    }
+
+    /// Check `frame` against the key of the ID it claims to be from,
+    /// counting it as forged if it does not match, as unauthorized if the ID
+    /// is not allowed and as replayed if it is not new.
+    fn authenticate(&mut self, frame: &Frame, id: &[u8], peer: SocketAddr) -> bool {
+        let key = std::str::from_utf8(id)
+            .ok()
+            .and_then(|id| public_key(id).ok())
+            .filter(|key| frame.verify(key));
+        let Some(key) = key else {
+            self.ignore(Rejected::Forged, peer);
+            return false;
+        };
+        if !self.allowed(frame, &key, peer) || !self.fresh(frame, key, peer) {
+            return false;
+        }
+        self.keys.insert(peer, key);
+        self.node.heard.lock().unwrap().insert(peer, Instant::now());
+        true
+    }
+
+    /// Whether `key` belongs to an allowed peer; if not, count `frame`.
+    fn allowed(&self, frame: &Frame, key: &VerifyingKey, peer: SocketAddr) -> bool {
+        {
+            let allowed = self.node.allowed_peers.lock().unwrap();
+            if allowed.is_empty() || allowed.contains(key.as_bytes()) {
+                return true;
+            }
+        }
+        debug!(
+            "{:?} frame from {peer}: {} is not an allowed peer (dropped)",
+            frame.kind,
+            hex::encode(key.as_bytes())
+        );
+        let unauthorized = self
+            .node
+            .unauthorized_packets
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        if unauthorized.is_power_of_two() {
+            info!("dropped {unauthorized} frames from peers not allowed so far");
+        }
+        false
+    }
+
+    /// Whether an authentic `frame` signed by `key` is not a replay; if it
+    /// is, count it.
+    fn fresh(&mut self, frame: &Frame, key: VerifyingKey, peer: SocketAddr) -> bool {
+        let Err(replay) = self.replay.check(&key, frame.timestamp, frame.nonce) else {
+            return true;
+        };
+        debug!("{:?} frame from {peer}: {replay} (dropped)", frame.kind);
+        let replayed = self.node.replayed_packets.fetch_add(1, Ordering::Relaxed) + 1;
+        if replayed.is_power_of_two() {
+            info!("dropped {replayed} replayed frames so far");
+        }
+        false
+    }
+
+    /// Whether the sender of an authenticated data frame of `kind` is
+    /// established, i.e. answered our challenge with the key that signed
+    /// the frame; if not, count the frame.
+    fn established(&self, kind: FrameType, peer: SocketAddr) -> bool {
+        let established = self
+            .proven
+            .get(&peer)
+            .is_some_and(|key| self.keys.get(&peer) == Some(key));
+        if !established {
+            self.node.count_unproven(kind, peer);
+        }
+        established
+    }
+
+    /// `peer` answered our challenge with the key of `peer_id`, which
+    /// signed the answer: it is established.
+    fn prove(&mut self, peer_id: &str, peer: SocketAddr) {
+        let node = &self.node;
+        let key = self.keys[&peer];
+        // Before the event, so that whoever waits for it can open streams.
+        node.streams.mark_established(peer);
+        if self.proven.insert(peer, key) == Some(key) {
+            debug!("{peer} proved its ID {peer_id} again");
+        } else {
+            match node.relayed_through(&peer) {
+                Some(relay) => {
+                    info!("{peer} proved it holds the key of {peer_id}, through relay {relay}")
+                }
+                None if node
+                    .spray
+                    .as_ref()
+                    .is_some_and(|spray| spray.carries(&peer)) =>
+                {
+                    info!("{peer} proved it holds the key of {peer_id}, through an extra socket")
+                }
+                None if node.over_tcp(&peer) => {
+                    info!("{peer} proved it holds the key of {peer_id}, over TCP")
+                }
+                None => info!("{peer} proved it holds the key of {peer_id}"),
+            }
+            node.events.emit(Event::PeerAuthenticated {
+                from: peer,
+                id: peer_id.to_string(),
+            });
+        }
+        node.peer_seen(peer_id, peer);
+    }
+
+    fn answer_frame(&mut self, frame: &Frame, peer: SocketAddr) {
+        let (kind, body) = (frame.kind, frame.body);
+        match kind {
+            FrameType::Message | FrameType::CompressedMessage | FrameType::SealedMessage => {
+                self.answer_message_frame(frame, kind, body, None, peer);
+            }
+            FrameType::Topic => {
+                let Some((kind, topic, body)) = wire::parse_topic(body) else {
+                    info!("malformed topic message from {peer} (ignored)");
+                    return;
+                };
+                let Ok(topic) = std::str::from_utf8(topic) else {
+                    info!("topic message from {peer} on a topic that is not UTF-8 (ignored)");
+                    return;
+                };
+                self.answer_message_frame(frame, kind, body, Some(topic), peer);
+            }
+            FrameType::MessageAck => {
+                let Some((seq, sender)) = wire::parse_ack(body) else {
+                    info!("malformed message ack from {peer} (ignored)");
+                    return;
+                };
+                if !self.authenticate(frame, sender, peer) {
+                    return;
+                }
+                let node = &self.node;
+                info!(
+                    "message {seq} delivered to {peer} ({})",
+                    String::from_utf8_lossy(sender)
+                );
+                if let Some(waiter) = node.pending_acks.lock().unwrap().get(&(peer, seq)) {
+                    let _ = waiter.try_send(());
+                }
+                node.events.emit(Event::MessageAcked { from: peer, seq });
+            }
+            FrameType::Control => {
+                let Some(message) = Control::decode(body) else {
+                    info!("malformed control message from {peer} (ignored)");
+                    return;
+                };
+                if self.authenticate(frame, message.id().as_bytes(), peer) {
+                    self.answer_control(message, peer);
+                }
+            }
+            FrameType::Cbor => self.answer_cbor(frame, peer),
+            // Streams, packets and fragments are handled before reassembly,
+            // handshakes, sealed and roaming frames by the transport; one
+            // inside a reassembled or sealed datagram is bogus.
+            FrameType::Stream
+            | FrameType::Packet
+            | FrameType::Fragment
+            | FrameType::Handshake
+            | FrameType::Sealed
+            | FrameType::Roam => {
+                info!("fragmented {kind:?} frame from {peer} (ignored)");
+            }
+        }
+    }
+
+    /// Answer a message, compressed or sealed message frame of type `kind`,
+    /// on `topic` if any.
+    fn answer_message_frame(
+        &mut self,
+        frame: &Frame,
+        kind: FrameType,
+        body: &[u8],
+        topic: Option<&str>,
+        peer: SocketAddr,
+    ) {
+        let Some((seq, sender, payload)) = wire::parse_message(body) else {
+            info!("malformed message from {peer} (ignored)");
+            return;
+        };
+        if !self.authenticate(frame, sender, peer) || !self.established(kind, peer) {
+            return;
+        }
+        let node = &self.node;
+        let payload = match kind {
+            FrameType::SealedMessage => {
+                let len = payload.len().saturating_sub(sealed_box::OVERHEAD);
+                if len > node.max_message_bytes {
+                    return self.drop_oversize(seq, len, peer);
+                }
+                let Some(opened) = sealed_box::open(&node.identity, payload) else {
+                    info!("sealed message {seq} from {peer} that we cannot open (ignored)");
+                    return;
+                };
+                Cow::Owned(opened)
+            }
+            _ if !node.allows_cleartext(peer) => {
+                return node.secure.drop_plaintext(kind, peer);
+            }
+            FrameType::CompressedMessage => {
+                let Some((len, block)) = payload.split_first_chunk::<4>() else {
+                    info!("undecodable compressed message from {peer} (ignored)");
+                    return;
+                };
+                let len = u32::from_be_bytes(*len) as usize;
+                if len > node.max_message_bytes {
+                    return self.drop_oversize(seq, len, peer);
+                }
+                let Some(unpacked) = compress::decompress(block, len) else {
+                    info!("undecodable compressed message from {peer} (ignored)");
+                    return;
+                };
+                Cow::Owned(unpacked)
+            }
+            _ => Cow::Borrowed(payload),
+        };
+        self.answer_message(seq, sender, topic, &payload, peer);
+    }
+
+    /// Count and drop an over-limit message. It is not acknowledged, so a
+    /// reliable sender reports it undelivered.
+    fn drop_oversize(&self, seq: u32, len: usize, peer: SocketAddr) {
+        let node = &self.node;
+        node.oversize_messages.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "dropped {len}-byte message {seq} from {peer}: over the {}-byte limit",
+            node.max_message_bytes
+        );
+    }
+
+    fn answer_message(
+        &mut self,
+        seq: u32,
+        sender: &[u8],
+        topic: Option<&str>,
+        payload: &[u8],
+        peer: SocketAddr,
+    ) {
+        let node = &self.node;
+        if payload.len() > node.max_message_bytes {
+            return self.drop_oversize(seq, payload.len(), peer);
+        }
+        // Ack duplicates too: the first ack may be what got lost.
+        let ack = wire::encode(
+            FrameType::MessageAck,
+            &wire::ack_body(seq, &node.local_id),
+            &node.identity,
+        );
+        if let Err(err) = node.transport.send_to(&ack, peer) {
+            warn!("failed to send message ack to {peer}: {err}");
+        }
+        if !self.dedup.accept(peer, seq) {
+            debug!("duplicate message {seq} from {peer} (dropped)");
+            return;
+        }
+        let sender = String::from_utf8_lossy(sender).into_owned();
+        match topic {
+            Some(topic) => info!(
+                "received {}-byte message {seq} on {topic:?} from {peer} ({sender})",
+                payload.len()
+            ),
+            None => info!(
+                "received {}-byte message {seq} from {peer} ({sender})",
+                payload.len()
+            ),
+        }
+        node.events.emit(Event::MessageReceived {
+            from: peer,
+            sender,
+            topic: topic.map(str::to_string),
+            payload: payload.to_vec(),
+        });
+    }
+
+    #[cfg(feature = "cbor")]
+    fn answer_cbor(&mut self, frame: &Frame, peer: SocketAddr) {
+        let message = match schema::Message::from_cbor(frame.body) {
+            Ok(message) => message,
+            Err(err) => {
+                info!("malformed CBOR message from {peer}: {err} (ignored)");
+                return;
+            }
+        };
+        if !self.authenticate(frame, message.id().as_bytes(), peer) {
+            return;
+        }
+        let control = match message {
+            schema::Message::Data { .. } if !self.established(frame.kind, peer) => return,
+            schema::Message::Data { .. } if !self.node.allows_cleartext(peer) => {
+                return self.node.secure.drop_plaintext(frame.kind, peer);
+            }
+            schema::Message::Data { id, seq, payload } => {
+                return self.answer_message(seq, id.as_bytes(), None, &payload, peer);
+            }
+            schema::Message::Hello {
+                id,
+                seq,
+                caps,
+                ext,
+                vmin,
+                v,
+                ch,
+            } => Control::Hello {
+                id,
+                seq,
+                caps,
+                ext,
+                vmin,
+                v,
+                ch,
+            },
+            schema::Message::Ack {
+                id,
+                caps,
+                ext,
+                v,
+                re,
+                ch,
+            } => Control::Ack {
+                id,
+                caps,
+                ext,
+                v,
+                re,
+                ch,
+            },
+            schema::Message::Proof { id, re } => Control::Proof { id, re },
+            schema::Message::Ping { id, seq } => Control::Ping { id, seq },
+        };
+        self.answer_control(control, peer);
+    }
+
+    #[cfg(not(feature = "cbor"))]
+    fn answer_cbor(&mut self, _frame: &Frame, peer: SocketAddr) {
+        info!("CBOR message from {peer} (built without the cbor feature; ignored)");
+    }
+
+    /// Drop all we hold for `peer`: its streams, session, keys and what we
+    /// know of its messages.
+    fn forget_peer(&mut self, peer: SocketAddr) {
+        let node = &self.node;
+        node.streams.forget(&peer);
+        node.utp.forget(&peer);
+        node.peers.lock().unwrap().remove(&peer);
+        node.heard.lock().unwrap().remove(&peer);
+        node.secure.forget(&peer);
+        node.challenges.forget(&peer);
+        self.dedup.forget(&peer);
+        self.keys.remove(&peer);
+        self.proven.remove(&peer);
+    }
+
+    /// Stop talking to a peer that speaks protocol versions `min..=max` only.
+    fn refuse(&mut self, peer: SocketAddr, min: u8, max: u8) {
+        warn!(
+            "{peer} speaks protocol versions {min}..={max}, we speak {}..={}; ignoring it",
+            wire::MIN_VERSION,
+            wire::VERSION
+        );
+        self.forget_peer(peer);
+        self.node.events.emit(Event::VersionMismatch {
+            from: peer,
+            version: max,
+        });
+    }
+
+    fn answer_control(&mut self, message: Control, peer: SocketAddr) {
+        let node = &self.node;
+        match &message {
+            Control::Hello { seq, .. } | Control::Ping { seq, .. } => {
+                let version = match message {
+                    Control::Hello { vmin, v, .. } => match wire::negotiate(vmin, v) {
+                        Some(version) => version,
+                        None => {
+                            // Our newest version in the ack lets the peer see the mismatch too.
+                            node.send_ack(peer, wire::VERSION, None);
+                            self.refuse(peer, vmin, v);
+                            return;
+                        }
+                    },
+                    _ => node.peer_version(peer).unwrap_or(wire::VERSION),
+                };
+                let challenge = match &message {
+                    Control::Hello { ch, .. } => Some(ch.as_slice()),
+                    _ => None,
+                };
+                node.send_ack(peer, version, challenge);
+                if !self.dedup.accept(peer, *seq) {
+                    debug!("duplicate {message} from {peer} (dropped)");
+                    return;
+                }
+                if let Control::Ping { .. } = message {
+                    debug!("received {message} from {peer}");
+                    return;
+                }
+                info!("received hello from {peer} (protocol version {version}): {message}");
+                node.note_peer(&message, version, peer);
+                node.events.emit(Event::HelloReceived {
+                    from: peer,
+                    message: message.to_string(),
+                });
+            }
+            Control::Ack { v, re, ch, .. } => {
+                if !(wire::MIN_VERSION..=wire::VERSION).contains(v) {
+                    self.refuse(peer, *v, *v);
+                    return;
+                }
+                info!("received ack from {peer} (protocol version {v}): {message}");
+                node.note_peer(&message, *v, peer);
+                if !ch.is_empty() {
+                    node.send_proof(peer, ch);
+                }
+                if !re.is_empty() && node.challenges.answer(peer, re) {
+                    self.prove(message.id(), peer);
+                }
+                self.node.events.emit(Event::AckReceived {
+                    from: peer,
+                    message: message.to_string(),
+                });
+            }
+            Control::Proof { re, .. } => {
+                if node.challenges.answer(peer, re) {
+                    self.prove(message.id(), peer);
+                } else {
+                    debug!("{message} from {peer} answers no challenge (ignored)");
+                }
+            }
+            Control::Pake { msg, .. } => {
+                let id = message.id();
+                if id == &*node.local_id {
+                    return;
+                }
+                let mut pairing = node.pairing.lock().unwrap();
+                let Some(pairing) = pairing.as_mut() else {
+                    debug!("{message} from {peer} while not pairing (ignored)");
+                    return;
+                };
+                let Some((confirmation, first)) = pairing.receive_message(peer, id, msg) else {
+                    debug!("{message} from {peer} not answered");
+                    return;
+                };
+                // Our message goes along the first time only, so that two
+                // sides answering each other do not go on forever.
+                if first {
+                    info!("answering {message} from {peer}");
+                    node.send_pake(peer, pairing.message());
+                }
+                let confirm = Control::Confirm {
+                    id: node.local_id.to_string(),
+                    mac: confirmation.to_vec(),
+                };
+                if let Err(err) = node.send_control(peer, &confirm) {
+                    debug!("failed to send pairing confirmation to {peer}: {err}");
+                }
+            }
+            Control::Confirm { mac, .. } => {
+                if let Some(pairing) = node.pairing.lock().unwrap().as_mut() {
+                    pairing.receive_confirmation(peer, message.id(), mac);
+                }
+            }
+            Control::Goodbye { .. } => {
+                // Any allowed key can sign a goodbye; only the one the peer
+                // proved it holds may end what we hold for it.
+                if self
+                    .proven
+                    .get(&peer)
+                    .is_none_or(|key| self.keys.get(&peer) != Some(key))
+                {
+                    debug!("{message} from {peer} not signed by its proven key (ignored)");
+                    return;
+                }
+                info!("received goodbye from {peer}: {message}");
+                self.forget_peer(peer);
+                self.node.events.emit(Event::PeerLeft {
+                    from: peer,
+                    message: message.to_string(),
+                });
+            }
+        }
+    }
+}
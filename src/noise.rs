@@ -0,0 +1,620 @@
+//! The Noise XX handshake (`Noise_XX_25519_ChaChaPoly_SHA256`) and the
+//! transport keys it yields.
+//!
+//! XX needs no prior knowledge of the other side, which suits nodes that
+//! answer whoever says hello:
+//!
+//! ```text
+//! -> e
+//! <- e, ee, s, es, payload
+//! -> s, se, payload
+//! ```
+//!
+//! Static keys are X25519 keys made per node. Each side's payload is its
+//! Ed25519 ID and a signature over its static key, so the session ends up
+//! bound to the same identity that signs its frames.
+
+use std::time::{Duration, Instant};
+
+use curve25519_dalek::MontgomeryPoint;
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH, Signature, VerifyingKey};
+use rand::{RngCore, thread_rng};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    Identity,
+    aead::{self, NONCE_BYTES, TAG_BYTES},
+    hmac::hmac,
+};
+
+const PROTOCOL_NAME: &[u8; 32] = b"Noise_XX_25519_ChaChaPoly_SHA256";
+/// Mixed into the handshake hash so that only dhtmsg nodes of this wire
+/// version complete handshakes with each other.
+const PROLOGUE: &[u8] = b"dhtmsg 7";
+/// Prefix of what an identity signs to vouch for its static key.
+const STATIC_KEY_CONTEXT: &[u8] = b"dhtmsg noise static key:";
+const DH_BYTES: usize = 32;
+const HASH_BYTES: usize = 32;
+/// Bytes of the epoch and of the explicit nonce in front of every transport
+/// message.
+const EPOCH_BYTES: usize = 4;
+const COUNTER_BYTES: usize = 8;
+/// How long the key of the previous epoch still opens messages, which may
+/// have been sent just before the peer moved on and been delayed.
+const EPOCH_GRACE: Duration = Duration::from_secs(30);
+/// Epochs a message may be ahead of the last one we opened, enough for the
+/// peer sending while our messages are lost; more look like garbage rather
+/// than worth ratcheting for.
+const MAX_EPOCH_SKIP: u32 = 64;
+/// Counters below the highest one opened in an epoch that may still arrive,
+/// reordered; older ones are refused with the replays.
+const COUNTER_WINDOW: u64 = 1024;
+
+/// An X25519 keypair.
+#[derive(Clone)]
+pub(crate) struct KeyPair {
+    secret: [u8; DH_BYTES],
+    public: [u8; DH_BYTES],
+}
+
+impl KeyPair {
+    pub(crate) fn generate() -> Self {
+        let mut secret = [0u8; DH_BYTES];
+        thread_rng().fill_bytes(&mut secret);
+        Self {
+            secret,
+            public: MontgomeryPoint::mul_base_clamped(secret).to_bytes(),
+        }
+    }
+
+    fn dh(&self, public: &[u8; DH_BYTES]) -> [u8; DH_BYTES] {
+        MontgomeryPoint(*public).mul_clamped(self.secret).to_bytes()
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; HASH_BYTES] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// The two-output HKDF of the Noise spec.
+fn hkdf(chaining_key: &[u8; HASH_BYTES], input: &[u8]) -> ([u8; HASH_BYTES], [u8; HASH_BYTES]) {
+    let temp = hmac(chaining_key, &[input]);
+    let first = hmac(&temp, &[&[1]]);
+    let second = hmac(&temp, &[&first, &[2]]);
+    (first, second)
+}
+
+fn nonce(counter: u64) -> [u8; NONCE_BYTES] {
+    let mut nonce = [0u8; NONCE_BYTES];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+#[derive(Clone)]
+pub(crate) struct SymmetricState {
+    chaining_key: [u8; HASH_BYTES],
+    hash: [u8; HASH_BYTES],
+    key: Option<[u8; 32]>,
+    counter: u64,
+}
+
+impl SymmetricState {
+    fn new() -> Self {
+        let mut state = Self {
+            chaining_key: *PROTOCOL_NAME,
+            hash: *PROTOCOL_NAME,
+            key: None,
+            counter: 0,
+        };
+        state.mix_hash(PROLOGUE);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.hash = hash(&[&self.hash, data]);
+    }
+
+    fn mix_key(&mut self, input: &[u8]) {
+        let (chaining_key, key) = hkdf(&self.chaining_key, input);
+        self.chaining_key = chaining_key;
+        self.key = Some(key);
+        self.counter = 0;
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = match &self.key {
+            Some(key) => aead::seal(key, &nonce(self.counter), &self.hash, plaintext),
+            None => plaintext.to_vec(),
+        };
+        self.counter += u64::from(self.key.is_some());
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let plaintext = match &self.key {
+            Some(key) => aead::open(key, &nonce(self.counter), &self.hash, ciphertext)?,
+            None => ciphertext.to_vec(),
+        };
+        self.counter += u64::from(self.key.is_some());
+        self.mix_hash(ciphertext);
+        Some(plaintext)
+    }
+
+    /// Transport keys: the initiator sends with the first one.
+    fn split(&self) -> ([u8; 32], [u8; 32]) {
+        hkdf(&self.chaining_key, &[])
+    }
+}
+
+/// Ed25519 ID followed by its signature over our static key.
+fn identity_payload(identity: &Identity, static_key: &KeyPair) -> Vec<u8> {
+    let mut payload = hex::decode(identity.id()).expect("IDs are hex");
+    let signature = identity.sign(&[STATIC_KEY_CONTEXT, &static_key.public].concat());
+    payload.extend_from_slice(&signature.to_bytes());
+    payload
+}
+
+/// The ID in `payload` if it vouches for `static_key`.
+fn check_identity(payload: &[u8], static_key: &[u8; DH_BYTES]) -> Option<VerifyingKey> {
+    let (id, signature) = payload.split_first_chunk::<PUBLIC_KEY_LENGTH>()?;
+    let signature: &[u8; SIGNATURE_LENGTH] = signature.try_into().ok()?;
+    let key = VerifyingKey::from_bytes(id).ok()?;
+    key.verify_strict(
+        &[STATIC_KEY_CONTEXT, static_key].concat(),
+        &Signature::from_bytes(signature),
+    )
+    .ok()?;
+    Some(key)
+}
+
+fn read_key(message: &[u8]) -> Option<([u8; DH_BYTES], &[u8])> {
+    let (key, rest) = message.split_first_chunk::<DH_BYTES>()?;
+    Some((*key, rest))
+}
+
+/// Our side of a handshake in progress.
+pub(crate) enum Handshake {
+    /// Initiator that sent `-> e`.
+    SentFirst {
+        state: SymmetricState,
+        ephemeral: KeyPair,
+    },
+    /// Responder that answered with `<- e, ee, s, es`.
+    SentSecond {
+        state: SymmetricState,
+        ephemeral: KeyPair,
+    },
+}
+
+impl Handshake {
+    pub(crate) fn is_initiator(&self) -> bool {
+        matches!(self, Self::SentFirst { .. })
+    }
+
+    /// Start as initiator; returns the first message.
+    pub(crate) fn initiate() -> (Self, Vec<u8>) {
+        let mut state = SymmetricState::new();
+        let ephemeral = KeyPair::generate();
+        state.mix_hash(&ephemeral.public);
+        let mut message = ephemeral.public.to_vec();
+        message.extend(state.encrypt_and_hash(&[]));
+        (Self::SentFirst { state, ephemeral }, message)
+    }
+
+    /// Answer a first message as responder; returns the second message.
+    pub(crate) fn respond(
+        first: &[u8],
+        identity: &Identity,
+        static_key: &KeyPair,
+    ) -> Option<(Self, Vec<u8>)> {
+        let mut state = SymmetricState::new();
+        let (remote_ephemeral, rest) = read_key(first)?;
+        state.mix_hash(&remote_ephemeral);
+        state.decrypt_and_hash(rest)?;
+
+        let ephemeral = KeyPair::generate();
+        state.mix_hash(&ephemeral.public);
+        let mut message = ephemeral.public.to_vec();
+        state.mix_key(&ephemeral.dh(&remote_ephemeral));
+        message.extend(state.encrypt_and_hash(&static_key.public));
+        state.mix_key(&static_key.dh(&remote_ephemeral));
+        message.extend(state.encrypt_and_hash(&identity_payload(identity, static_key)));
+        Some((Self::SentSecond { state, ephemeral }, message))
+    }
+
+    /// Initiator: read the second message and produce the third. Returns the
+    /// session and the responder's ID. A bad message leaves the handshake
+    /// as it was, so a stray datagram cannot abort it.
+    pub(crate) fn finish_initiator(
+        &self,
+        second: &[u8],
+        identity: &Identity,
+        static_key: &KeyPair,
+    ) -> Option<(Session, VerifyingKey, Vec<u8>)> {
+        let Self::SentFirst { state, ephemeral } = self else {
+            return None;
+        };
+        let mut state = state.clone();
+        let (remote_ephemeral, rest) = read_key(second)?;
+        state.mix_hash(&remote_ephemeral);
+        state.mix_key(&ephemeral.dh(&remote_ephemeral));
+        let (encrypted_static, payload) = rest.split_at_checked(DH_BYTES + TAG_BYTES)?;
+        let remote_static: [u8; DH_BYTES] =
+            state.decrypt_and_hash(encrypted_static)?.try_into().ok()?;
+        state.mix_key(&ephemeral.dh(&remote_static));
+        let remote_id = check_identity(&state.decrypt_and_hash(payload)?, &remote_static)?;
+
+        let mut message = state.encrypt_and_hash(&static_key.public);
+        state.mix_key(&static_key.dh(&remote_ephemeral));
+        message.extend(state.encrypt_and_hash(&identity_payload(identity, static_key)));
+        let (send, receive) = state.split();
+        Some((Session::new(send, receive, state.hash), remote_id, message))
+    }
+
+    /// Responder: read the third message. Returns the session and the
+    /// initiator's ID.
+    pub(crate) fn finish_responder(&self, third: &[u8]) -> Option<(Session, VerifyingKey)> {
+        let Self::SentSecond { state, ephemeral } = self else {
+            return None;
+        };
+        let mut state = state.clone();
+        let (encrypted_static, payload) = third.split_at_checked(DH_BYTES + TAG_BYTES)?;
+        let remote_static: [u8; DH_BYTES] =
+            state.decrypt_and_hash(encrypted_static)?.try_into().ok()?;
+        state.mix_key(&ephemeral.dh(&remote_static));
+        let remote_id = check_identity(&state.decrypt_and_hash(payload)?, &remote_static)?;
+        let (receive, send) = state.split();
+        Some((Session::new(send, receive, state.hash), remote_id))
+    }
+}
+
+/// When a session moves its keys on: after `interval`, or after `bytes` of
+/// plaintext sent under one key, whichever comes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Rekey {
+    pub(crate) interval: Duration,
+    pub(crate) bytes: u64,
+}
+
+/// Transport keys of a completed handshake.
+///
+/// Datagrams get lost and reordered, so every message carries its epoch and
+/// counter (`[epoch u32 LE][counter u64 LE][ciphertext]`) instead of both
+/// sides keeping them in step as Noise over a stream would.
+///
+/// Each side ratchets its sending key forward with the Noise `REKEY`
+/// function as [`Rekey`] says, bumping the epoch and starting the counter
+/// anew, and forgets the old key. The receiver follows when the first
+/// message of a later epoch opens, and keeps the key before it for
+/// [`EPOCH_GRACE`] for stragglers. Someone who learns the keys of a session
+/// therefore cannot read what it carried more than an epoch earlier.
+///
+/// A message opens once: the counters of each epoch already seen are kept
+/// in a [`CounterWindow`].
+pub(crate) struct Session {
+    send_key: [u8; 32],
+    send_epoch: u32,
+    next_counter: u64,
+    epoch_started: Instant,
+    epoch_bytes: u64,
+    receive_key: [u8; 32],
+    receive_epoch: u32,
+    receive_window: CounterWindow,
+    /// The key of the epoch before `receive_epoch`, the counters opened
+    /// under it, and when we moved on.
+    previous_receive_key: Option<([u8; 32], CounterWindow, Instant)>,
+    /// The final handshake hash, the same on both sides: it commits to the
+    /// whole transcript.
+    handshake_hash: [u8; HASH_BYTES],
+}
+
+impl Session {
+    fn new(send_key: [u8; 32], receive_key: [u8; 32], handshake_hash: [u8; HASH_BYTES]) -> Self {
+        Self {
+            send_key,
+            send_epoch: 0,
+            next_counter: 0,
+            epoch_started: Instant::now(),
+            epoch_bytes: 0,
+            receive_key,
+            receive_epoch: 0,
+            receive_window: CounterWindow::default(),
+            previous_receive_key: None,
+            handshake_hash,
+        }
+    }
+
+    pub(crate) fn handshake_hash(&self) -> &[u8; HASH_BYTES] {
+        &self.handshake_hash
+    }
+
+    /// The epoch messages are sent in now.
+    pub(crate) fn send_epoch(&self) -> u32 {
+        self.send_epoch
+    }
+
+    pub(crate) fn seal(&mut self, plaintext: &[u8], rekey: &Rekey) -> Vec<u8> {
+        if self.epoch_started.elapsed() >= rekey.interval || self.epoch_bytes >= rekey.bytes {
+            self.send_key = ratchet(&self.send_key);
+            self.send_epoch += 1;
+            self.next_counter = 0;
+            self.epoch_started = Instant::now();
+            self.epoch_bytes = 0;
+        }
+        let counter = self.next_counter;
+        self.next_counter += 1;
+        self.epoch_bytes += plaintext.len() as u64;
+        let mut sealed = self.send_epoch.to_le_bytes().to_vec();
+        sealed.extend_from_slice(&counter.to_le_bytes());
+        sealed.extend(aead::seal(&self.send_key, &nonce(counter), &[], plaintext));
+        sealed
+    }
+
+    pub(crate) fn open(&mut self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let (epoch, rest) = sealed.split_first_chunk::<EPOCH_BYTES>()?;
+        let (counter, ciphertext) = rest.split_first_chunk::<COUNTER_BYTES>()?;
+        let epoch = u32::from_le_bytes(*epoch);
+        let counter = u64::from_le_bytes(*counter);
+        let nonce = nonce(counter);
+        if self
+            .previous_receive_key
+            .is_some_and(|(_, _, since)| since.elapsed() >= EPOCH_GRACE)
+        {
+            self.previous_receive_key = None;
+        }
+
+        if epoch == self.receive_epoch {
+            if !self.receive_window.is_fresh(counter) {
+                return None;
+            }
+            let plaintext = aead::open(&self.receive_key, &nonce, &[], ciphertext)?;
+            self.receive_window.mark(counter);
+            return Some(plaintext);
+        }
+        if epoch.checked_add(1) == Some(self.receive_epoch) {
+            let (key, window, _) = self.previous_receive_key.as_mut()?;
+            if !window.is_fresh(counter) {
+                return None;
+            }
+            let plaintext = aead::open(key, &nonce, &[], ciphertext)?;
+            window.mark(counter);
+            return Some(plaintext);
+        }
+        let skipped = epoch.checked_sub(self.receive_epoch)?;
+        if skipped > MAX_EPOCH_SKIP {
+            return None;
+        }
+        let mut previous = self.receive_key;
+        for _ in 1..skipped {
+            previous = ratchet(&previous);
+        }
+        let key = ratchet(&previous);
+        let plaintext = aead::open(&key, &nonce, &[], ciphertext)?;
+        // What opened under the key we move off stays opened.
+        let previous_window = if skipped == 1 {
+            self.receive_window
+        } else {
+            CounterWindow::default()
+        };
+        self.receive_key = key;
+        self.receive_epoch = epoch;
+        self.receive_window = CounterWindow::default();
+        self.receive_window.mark(counter);
+        self.previous_receive_key = Some((previous, previous_window, Instant::now()));
+        Some(plaintext)
+    }
+}
+
+/// The counters of one epoch opened so far: the highest, and which of the
+/// [`COUNTER_WINDOW`] below it, one bit each in a ring.
+#[derive(Clone, Copy, Default)]
+struct CounterWindow {
+    /// One above the highest counter opened; 0 before any.
+    next: u64,
+    seen: [u64; (COUNTER_WINDOW / 64) as usize],
+}
+
+impl CounterWindow {
+    /// Whether `counter` is neither opened already nor too old to tell.
+    fn is_fresh(&self, counter: u64) -> bool {
+        if counter >= self.next {
+            return true;
+        }
+        self.next - counter <= COUNTER_WINDOW && !self.bit(counter)
+    }
+
+    fn mark(&mut self, counter: u64) {
+        if counter >= self.next {
+            // Bits of the counters skipped over are those of old ones.
+            let start = self.next.max((counter + 1).saturating_sub(COUNTER_WINDOW));
+            for skipped in start..counter {
+                self.set(skipped, false);
+            }
+            self.next = counter + 1;
+        }
+        self.set(counter, true);
+    }
+
+    fn bit(&self, counter: u64) -> bool {
+        let bit = counter % COUNTER_WINDOW;
+        self.seen[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+
+    fn set(&mut self, counter: u64, value: bool) {
+        let bit = counter % COUNTER_WINDOW;
+        let word = &mut self.seen[(bit / 64) as usize];
+        if value {
+            *word |= 1 << (bit % 64);
+        } else {
+            *word &= !(1 << (bit % 64));
+        }
+    }
+}
+
+/// The key after `key`: the Noise `REKEY` function, the first 32 bytes of
+/// zeros sealed under `key` with the maximum nonce.
+fn ratchet(key: &[u8; 32]) -> [u8; 32] {
+    let sealed = aead::seal(key, &nonce(u64::MAX), &[], &[0u8; 32]);
+    sealed[..32].try_into().expect("sealed 32 bytes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keys move on with every message.
+    const ALWAYS: Rekey = Rekey {
+        interval: Duration::MAX,
+        bytes: 0,
+    };
+    const NEVER: Rekey = Rekey {
+        interval: Duration::MAX,
+        bytes: u64::MAX,
+    };
+
+    /// Sessions of an initiator and a responder after a full handshake.
+    fn handshake() -> (Session, Session) {
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+        let (alice_static, bob_static) = (KeyPair::generate(), KeyPair::generate());
+        let (initiator, first) = Handshake::initiate();
+        assert!(initiator.is_initiator());
+        let (responder, second) = Handshake::respond(&first, &bob, &bob_static).unwrap();
+        assert!(!responder.is_initiator());
+        let (alice_session, bob_id, third) = initiator
+            .finish_initiator(&second, &alice, &alice_static)
+            .unwrap();
+        let (bob_session, alice_id) = responder.finish_responder(&third).unwrap();
+        assert_eq!(hex::encode(bob_id.as_bytes()), bob.id());
+        assert_eq!(hex::encode(alice_id.as_bytes()), alice.id());
+        assert_eq!(alice_session.handshake_hash(), bob_session.handshake_hash());
+        (alice_session, bob_session)
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        let (mut alice, mut bob) = handshake();
+        let sealed = alice.seal(b"hello", &NEVER);
+        assert_eq!(bob.open(&sealed).unwrap(), b"hello");
+        let sealed = bob.seal(b"hello back", &NEVER);
+        assert_eq!(alice.open(&sealed).unwrap(), b"hello back");
+        // Each side sends with the key the other receives with, not its own.
+        let sealed = alice.seal(b"to bob", &NEVER);
+        assert_eq!(alice.open(&sealed), None);
+    }
+
+    #[test]
+    fn bad_handshake_messages_change_nothing() {
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+        let (alice_static, bob_static) = (KeyPair::generate(), KeyPair::generate());
+        let (initiator, first) = Handshake::initiate();
+        let (_, second) = Handshake::respond(&first, &bob, &bob_static).unwrap();
+        let mut tampered = second.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(
+            initiator
+                .finish_initiator(&tampered, &alice, &alice_static)
+                .is_none()
+        );
+        assert!(
+            initiator
+                .finish_initiator(&second[..40], &alice, &alice_static)
+                .is_none()
+        );
+        assert!(
+            initiator
+                .finish_initiator(&second, &alice, &alice_static)
+                .is_some()
+        );
+        assert!(Handshake::respond(&first[..DH_BYTES - 1], &bob, &bob_static).is_none());
+    }
+
+    #[test]
+    fn rejects_tampered_messages() {
+        let (mut alice, mut bob) = handshake();
+        let mut sealed = alice.seal(b"hello", &NEVER);
+        *sealed.last_mut().unwrap() ^= 1;
+        assert_eq!(bob.open(&sealed), None);
+        assert_eq!(bob.open(&sealed[..EPOCH_BYTES + COUNTER_BYTES]), None);
+    }
+
+    #[test]
+    fn follows_epoch_rollover() {
+        let (mut alice, mut bob) = handshake();
+        let first = alice.seal(b"first", &NEVER);
+        let second = alice.seal(b"second", &ALWAYS);
+        assert_eq!(alice.send_epoch(), 1);
+        assert_eq!(bob.open(&second).unwrap(), b"second");
+        // Stragglers of the epoch before still open.
+        assert_eq!(bob.open(&first).unwrap(), b"first");
+        // Skipped epochs are ratcheted over...
+        for _ in 0..10 {
+            alice.seal(b"lost", &ALWAYS);
+        }
+        let later = alice.seal(b"later", &ALWAYS);
+        assert_eq!(alice.send_epoch(), 12);
+        assert_eq!(bob.open(&later).unwrap(), b"later");
+        // ...but what is older than the epoch before is gone.
+        assert_eq!(bob.open(&second), None);
+    }
+
+    #[test]
+    fn refuses_epochs_too_far_ahead() {
+        let (mut alice, mut bob) = handshake();
+        for _ in 0..=MAX_EPOCH_SKIP {
+            alice.seal(b"lost", &ALWAYS);
+        }
+        let sealed = alice.seal(b"too far", &ALWAYS);
+        assert_eq!(bob.open(&sealed), None);
+    }
+
+    #[test]
+    fn rejects_replays() {
+        let (mut alice, mut bob) = handshake();
+        let sealed = alice.seal(b"once", &NEVER);
+        assert_eq!(bob.open(&sealed).unwrap(), b"once");
+        assert_eq!(bob.open(&sealed), None);
+
+        // Also across an epoch change.
+        let next = alice.seal(b"next epoch", &ALWAYS);
+        assert_eq!(bob.open(&next).unwrap(), b"next epoch");
+        assert_eq!(bob.open(&sealed), None);
+        assert_eq!(bob.open(&next), None);
+    }
+
+    #[test]
+    fn keeps_a_window_of_counters() {
+        let (mut alice, mut bob) = handshake();
+        let sealed = Vec::from_iter((0..COUNTER_WINDOW + 10).map(|_| alice.seal(b"x", &NEVER)));
+        assert!(bob.open(sealed.last().unwrap()).is_some());
+        // Reordered within the window: opens once.
+        assert!(bob.open(&sealed[100]).is_some());
+        assert!(bob.open(&sealed[100]).is_none());
+        // Too old to tell from a replay.
+        assert!(bob.open(&sealed[9]).is_none());
+        assert!(bob.open(&sealed[10]).is_some());
+    }
+
+    #[test]
+    fn counter_window_moves_on() {
+        let mut window = CounterWindow::default();
+        assert!(window.is_fresh(0));
+        window.mark(0);
+        assert!(!window.is_fresh(0));
+        window.mark(5);
+        assert!(window.is_fresh(3));
+        window.mark(3);
+        assert!(!window.is_fresh(3));
+        // A jump of more than the window leaves no stale bits behind.
+        window.mark(3 + COUNTER_WINDOW);
+        assert!(!window.is_fresh(3 + COUNTER_WINDOW));
+        assert!(window.is_fresh(2 + COUNTER_WINDOW) && window.is_fresh(5 + COUNTER_WINDOW));
+        assert!(!window.is_fresh(3));
+        window.mark(10 * COUNTER_WINDOW);
+        assert!(window.is_fresh(10 * COUNTER_WINDOW - 3));
+    }
+}
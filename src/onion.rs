@@ -0,0 +1,328 @@
+//! Onion mode: reaching peers through Tor, when nothing direct works or a
+//! node would rather not show a peer its IP address.
+//!
+//! At startup the node asks the Tor daemon, over its control port, for an
+//! onion service that forwards to the hello port over TCP (see the `tcp`
+//! module), and keeps the control connection open for as long as the
+//! service should last. Peers learn the service's address from onion
+//! records: BEP 44 mutable items under our key with the salt
+//! `SHA1("dhtmsg onion" || salt || peer key)`, the salt being that of the
+//! infohashes, one per peer. The value is `d1:o<sealed>e`, `sealed` being
+//! `<address>.onion:<port>` sealed to the peer (see the `sealed_box`
+//! module), so that only the peer it is meant for can read it. The sequence
+//! number is the Unix time of publication.
+//!
+//! A peer connects to the address through Tor's SOCKS port, and the
+//! connection then carries datagrams as any other TCP connection would. It
+//! shows up at a virtual address made of our key; what comes in through
+//! the service shows up at the loopback address Tor connects from.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6, TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ed25519_dalek::VerifyingKey;
+use log::debug;
+use mainline::MutableItem;
+use rand::{RngCore, thread_rng};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::{
+    Identity,
+    endpoint::{signable, signed_by},
+    hmac::hmac,
+    sealed_box,
+};
+
+const SALT_CONTEXT: &[u8] = b"dhtmsg onion";
+/// What onion service addresses end with, after 56 base32 characters.
+const SUFFIX: &str = ".onion";
+const ADDRESS_CHARS: usize = 56;
+
+/// Where Tor listens unless told otherwise.
+pub(crate) const DEFAULT_CONTROL: &str = "127.0.0.1:9051";
+pub(crate) const DEFAULT_SOCKS: &str = "127.0.0.1:9050";
+
+/// How long Tor may take to answer a command; making a service takes a
+/// moment.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Keys of the safe cookie authentication (Tor's control-spec 3.24).
+const SERVER_TO_CONTROLLER: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+const CONTROLLER_TO_SERVER: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+const NONCE_BYTES: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum OnionError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Tor control port {0} has no address")]
+    Resolve(String),
+    #[error("Tor offers none of our ways to log in ({0})")]
+    NoAuth(String),
+    #[error("Tor refused {command}: {reply}")]
+    Refused {
+        command: &'static str,
+        reply: String,
+    },
+    #[error("Tor sent a malformed answer to {0}")]
+    Malformed(&'static str),
+}
+
+type Result<T> = std::result::Result<T, OnionError>;
+
+/// An onion service Tor runs for us while the control connection stays
+/// open.
+pub(crate) struct Service {
+    control: Mutex<Control>,
+    /// `<56 characters>.onion`.
+    address: String,
+    port: u16,
+}
+
+impl Service {
+    /// Ask the Tor daemon at `control` (`host:port`) for a new onion
+    /// service forwarding `port` to the same port on the loopback address.
+    pub(crate) fn publish(control: &str, port: u16) -> Result<Self> {
+        let server = (control.to_socket_addrs().ok())
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| OnionError::Resolve(control.to_string()))?;
+        let mut control = Control::connect(server)?;
+        control.authenticate()?;
+        let lines = control.command(
+            "ADD_ONION",
+            &format!("ADD_ONION NEW:ED25519-V3 Flags=DiscardPK Port={port},127.0.0.1:{port}"),
+        )?;
+        let service_id = (lines.iter())
+            .find_map(|line| line.strip_prefix("ServiceID="))
+            .filter(|id| id.len() == ADDRESS_CHARS)
+            .ok_or(OnionError::Malformed("ADD_ONION"))?;
+        Ok(Self {
+            address: format!("{service_id}{SUFFIX}"),
+            port,
+            control: Mutex::new(control),
+        })
+    }
+
+    pub(crate) fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Take the service down now rather than when the node is dropped.
+    pub(crate) fn remove(&self) {
+        let service_id = self.address.trim_end_matches(SUFFIX);
+        let mut control = self.control.lock().unwrap();
+        if let Err(err) = control.command("DEL_ONION", &format!("DEL_ONION {service_id}")) {
+            debug!("removing the onion service failed: {err}");
+        }
+    }
+}
+
+/// A logged-in connection to Tor's control port.
+struct Control {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Control {
+    fn connect(server: SocketAddr) -> Result<Self> {
+        let writer = TcpStream::connect_timeout(&server, TIMEOUT)?;
+        writer.set_read_timeout(Some(TIMEOUT))?;
+        Ok(Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    /// Send `line` and read the reply, returning its lines without the
+    /// status code; fails unless the status is 250.
+    fn command(&mut self, command: &'static str, line: &str) -> Result<Vec<String>> {
+        self.writer.write_all(format!("{line}\r\n").as_bytes())?;
+        let mut lines = Vec::new();
+        loop {
+            let mut reply = String::new();
+            if self.reader.read_line(&mut reply)? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let reply = reply.trim_end();
+            let (status, rest) = reply
+                .split_at_checked(3)
+                .ok_or(OnionError::Malformed(command))?;
+            let (separator, text) = rest.split_at_checked(1).unwrap_or((" ", ""));
+            if status != "250" {
+                return Err(OnionError::Refused {
+                    command,
+                    reply: reply.to_string(),
+                });
+            }
+            lines.push(text.to_string());
+            // `-` goes on, `+` starts data up to a lone dot, and a space
+            // ends the reply.
+            match separator {
+                "-" => {}
+                "+" => loop {
+                    let mut data = String::new();
+                    if self.reader.read_line(&mut data)? == 0 || data.trim_end() == "." {
+                        break;
+                    }
+                },
+                _ => return Ok(lines),
+            }
+        }
+    }
+
+    /// Log in with no secret, the cookie, or the safe cookie handshake,
+    /// whichever Tor takes.
+    fn authenticate(&mut self) -> Result<()> {
+        let lines = self.command("PROTOCOLINFO", "PROTOCOLINFO 1")?;
+        let auth = (lines.iter())
+            .find_map(|line| line.strip_prefix("AUTH "))
+            .ok_or(OnionError::Malformed("PROTOCOLINFO"))?;
+        let methods: Vec<&str> = (auth.split(' '))
+            .find_map(|field| field.strip_prefix("METHODS="))
+            .map(|methods| methods.split(',').collect())
+            .unwrap_or_default();
+        let cookie_file = (auth.split_once("COOKIEFILE="))
+            .and_then(|(_, rest)| unquote(rest))
+            .map(PathBuf::from);
+        let secret = if methods.contains(&"NULL") {
+            String::new()
+        } else if let Some(path) = (cookie_file.as_ref()).filter(|_| methods.contains(&"COOKIE")) {
+            hex::encode(std::fs::read(path)?)
+        } else if let Some(path) =
+            (cookie_file.as_ref()).filter(|_| methods.contains(&"SAFECOOKIE"))
+        {
+            self.safe_cookie(&std::fs::read(path)?)?
+        } else {
+            return Err(OnionError::NoAuth(methods.join(",")));
+        };
+        let line = if secret.is_empty() {
+            "AUTHENTICATE".to_string()
+        } else {
+            format!("AUTHENTICATE {secret}")
+        };
+        self.command("AUTHENTICATE", &line)?;
+        Ok(())
+    }
+
+    /// What AUTHENTICATE takes after the safe cookie challenge, which
+    /// proves both sides read the cookie without showing it.
+    fn safe_cookie(&mut self, cookie: &[u8]) -> Result<String> {
+        let mut ours = [0u8; NONCE_BYTES];
+        thread_rng().fill_bytes(&mut ours);
+        let lines = self.command(
+            "AUTHCHALLENGE",
+            &format!("AUTHCHALLENGE SAFECOOKIE {}", hex::encode(ours)),
+        )?;
+        let field = |name: &str| {
+            (lines.iter())
+                .flat_map(|line| line.split(' '))
+                .find_map(|field| field.strip_prefix(name))
+                .and_then(|value| hex::decode(value).ok())
+                .ok_or(OnionError::Malformed("AUTHCHALLENGE"))
+        };
+        let server_hash = field("SERVERHASH=")?;
+        let theirs = field("SERVERNONCE=")?;
+        if hmac(SERVER_TO_CONTROLLER, &[cookie, &ours, &theirs]).as_slice() != server_hash {
+            return Err(OnionError::Malformed("AUTHCHALLENGE"));
+        }
+        Ok(hex::encode(hmac(
+            CONTROLLER_TO_SERVER,
+            &[cookie, &ours, &theirs],
+        )))
+    }
+}
+
+/// The quoted string at the start of `s`, with its escapes undone.
+fn unquote(s: &str) -> Option<String> {
+    let mut chars = s.strip_prefix('"')?.chars();
+    let mut unquoted = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(unquoted),
+            '\\' => unquoted.push(chars.next()?),
+            c => unquoted.push(c),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    #[serde(with = "serde_bytes")]
+    o: Vec<u8>,
+}
+
+/// The BEP 44 salt of records for `peer` under `salt`.
+pub(crate) fn salt(salt: &[u8], peer: &VerifyingKey) -> [u8; 20] {
+    let mut hasher = <Sha1 as sha1::Digest>::new();
+    sha1::Digest::update(&mut hasher, SALT_CONTEXT);
+    sha1::Digest::update(&mut hasher, salt);
+    sha1::Digest::update(&mut hasher, peer.as_bytes());
+    sha1::Digest::finalize(hasher).into()
+}
+
+/// A record by `identity` telling `peer` where `service` is, signed under
+/// `salt`.
+pub(crate) fn record(
+    identity: &Identity,
+    service: &Service,
+    peer: &VerifyingKey,
+    salt: &[u8],
+) -> MutableItem {
+    let seq = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64);
+    let sealed = sealed_box::seal(
+        peer,
+        format!("{}:{}", service.address, service.port).as_bytes(),
+    );
+    let value = serde_bencode::to_bytes(&Record { o: sealed }).expect("records always encode");
+    let signature = identity.sign(&signable(seq, &value, salt));
+    MutableItem::new_signed_unchecked(
+        identity.verifying_key().to_bytes(),
+        signature.to_bytes(),
+        &value,
+        seq,
+        Some(salt),
+    )
+}
+
+/// The onion address and port in the newest of `items` that `key` signed
+/// under `salt` and sealed to `identity`.
+pub(crate) fn newest(
+    items: impl IntoIterator<Item = MutableItem>,
+    key: &VerifyingKey,
+    salt: &[u8],
+    identity: &Identity,
+) -> Option<(String, u16)> {
+    let item = items
+        .into_iter()
+        .filter(|item| signed_by(item, key, salt))
+        .max_by_key(MutableItem::seq)?;
+    let record = serde_bencode::from_bytes::<Record>(item.value()).ok()?;
+    let opened = String::from_utf8(sealed_box::open(identity, &record.o)?).ok()?;
+    let (address, port) = opened.rsplit_once(':')?;
+    let service_id = address.strip_suffix(SUFFIX)?;
+    let valid = service_id.len() == ADDRESS_CHARS
+        && (service_id.bytes()).all(|b| b.is_ascii_lowercase() || (b'2'..=b'7').contains(&b));
+    if !valid {
+        return None;
+    }
+    Some((address.to_string(), port.parse().ok()?))
+}
+
+/// `100::` followed by bytes 10 to 17 of `key`, on the port of the next
+/// two: apart from the virtual addresses of relays, which take the first
+/// ten.
+pub(crate) fn virtual_addr(key: &VerifyingKey) -> SocketAddr {
+    let key = key.as_bytes();
+    let mut octets = [0u8; 16];
+    octets[0] = 0x01;
+    octets[8..].copy_from_slice(&key[10..18]);
+    let port = u16::from_be_bytes([key[18], key[19]]);
+    SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+}
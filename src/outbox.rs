@@ -0,0 +1,229 @@
+//! Messages waiting for a peer that has not shown up yet.
+//!
+//! With a directory configured, every queued message is also a file
+//! `<dir>/<peer id>/<n>.msg`, so the queue survives restarts. Files are
+//! removed once the peer acknowledges the message. A message with a TTL is
+//! stored as `<n>-<expiry in Unix seconds>.msg` instead.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+
+struct Queued {
+    number: u64,
+    payload: Vec<u8>,
+    file: Option<PathBuf>,
+    expires: Option<SystemTime>,
+}
+
+impl Queued {
+    fn expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    queued: HashMap<String, VecDeque<Queued>>,
+    /// Peers whose queue a thread is currently delivering.
+    flushing: HashSet<String>,
+    /// Number for the next message (and its file).
+    next_number: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Outbox {
+    dir: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+impl Outbox {
+    /// In-memory outbox, or one backed by `dir` (loading what it holds).
+    pub(crate) fn open(dir: Option<PathBuf>) -> io::Result<Self> {
+        let mut state = State::default();
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir)?;
+            for peer_dir in fs::read_dir(dir)? {
+                let peer_dir = peer_dir?;
+                let Some(peer_id) = peer_dir.file_name().to_str().map(str::to_string) else {
+                    continue;
+                };
+                let mut files = Vec::new();
+                for file in fs::read_dir(peer_dir.path())? {
+                    let path = file?.path();
+                    if let Some((number, expires)) = parse_file_name(&path) {
+                        files.push((number, expires, path));
+                    }
+                }
+                files.sort();
+                let queue = state.queued.entry(peer_id).or_default();
+                for (number, expires, path) in files {
+                    state.next_number = state.next_number.max(number + 1);
+                    queue.push_back(Queued {
+                        number,
+                        payload: fs::read(&path)?,
+                        file: Some(path),
+                        expires,
+                    });
+                }
+            }
+        }
+        Ok(Self {
+            dir,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Queue `payload`, to be dropped by [`expire`](Self::expire) once
+    /// `expires` has passed.
+    pub(crate) fn push(
+        &self,
+        peer_id: &str,
+        payload: &[u8],
+        expires: Option<SystemTime>,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let number = state.next_number;
+        let file = match &self.dir {
+            Some(dir) => {
+                let peer_dir = dir.join(peer_id);
+                fs::create_dir_all(&peer_dir)?;
+                let name = match expires {
+                    Some(expires) => format!("{number:020}-{}.msg", unix_secs(expires)),
+                    None => format!("{number:020}.msg"),
+                };
+                let path = peer_dir.join(name);
+                fs::write(&path, payload)?;
+                Some(path)
+            }
+            None => None,
+        };
+        state.next_number += 1;
+        state
+            .queued
+            .entry(peer_id.to_string())
+            .or_default()
+            .push_back(Queued {
+                number,
+                payload: payload.to_vec(),
+                file,
+                expires,
+            });
+        Ok(())
+    }
+
+    /// Claim delivery of `peer_id`'s queue; false if there is nothing to send
+    /// or another thread is on it.
+    pub(crate) fn start_flush(&self, peer_id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let pending = state
+            .queued
+            .get(peer_id)
+            .is_some_and(|queue| !queue.is_empty());
+        pending && state.flushing.insert(peer_id.to_string())
+    }
+
+    /// Number and payload of the oldest message for `peer_id`, left queued
+    /// until [`pop`](Self::pop). An empty queue ends the flush.
+    pub(crate) fn next(&self, peer_id: &str) -> Option<(u64, Vec<u8>)> {
+        let mut state = self.state.lock().unwrap();
+        let next = state
+            .queued
+            .get(peer_id)
+            .and_then(|queue| queue.front())
+            .map(|message| (message.number, message.payload.clone()));
+        if next.is_none() {
+            state.flushing.remove(peer_id);
+        }
+        next
+    }
+
+    /// Drop message `number` for `peer_id` after it was delivered (unless it
+    /// expired meanwhile).
+    pub(crate) fn pop(&self, peer_id: &str, number: u64) -> io::Result<()> {
+        let message = {
+            let mut state = self.state.lock().unwrap();
+            let queue = state.queued.get_mut(peer_id);
+            queue.and_then(|queue| {
+                let index = queue.iter().position(|message| message.number == number)?;
+                queue.remove(index)
+            })
+        };
+        match message.and_then(|message| message.file) {
+            Some(path) => fs::remove_file(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Remove messages whose TTL has run out, returning each with its peer.
+    pub(crate) fn expire(&self, now: SystemTime) -> Vec<(String, Vec<u8>)> {
+        let mut expired = Vec::new();
+        let mut state = self.state.lock().unwrap();
+        for (peer_id, queue) in &mut state.queued {
+            if !queue.iter().any(|message| message.expired(now)) {
+                continue;
+            }
+            let (stale, fresh) = std::mem::take(queue)
+                .into_iter()
+                .partition(|message| message.expired(now));
+            *queue = fresh;
+            for message in stale {
+                if let Some(path) = message.file
+                    && let Err(err) = fs::remove_file(&path)
+                {
+                    warn!("failed to remove expired {}: {err}", path.display());
+                }
+                expired.push((peer_id.clone(), message.payload));
+            }
+        }
+        expired
+    }
+
+    /// Whether any queued message has a TTL.
+    pub(crate) fn has_expiring(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .queued
+            .values()
+            .flatten()
+            .any(|message| message.expires.is_some())
+    }
+
+    /// Give up delivering for now, keeping the queue.
+    pub(crate) fn abort_flush(&self, peer_id: &str) {
+        self.state.lock().unwrap().flushing.remove(peer_id);
+    }
+
+    pub(crate) fn len(&self, peer_id: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .queued
+            .get(peer_id)
+            .map_or(0, VecDeque::len)
+    }
+}
+
+/// Number and expiry of a message file named by [`Outbox::push`].
+fn parse_file_name(path: &Path) -> Option<(u64, Option<SystemTime>)> {
+    let stem = path.file_name()?.to_str()?.strip_suffix(".msg")?;
+    match stem.split_once('-') {
+        Some((number, expires)) => {
+            let expires = UNIX_EPOCH + Duration::from_secs(expires.parse().ok()?);
+            Some((number.parse().ok()?, Some(expires)))
+        }
+        None => Some((stem.parse().ok()?, None)),
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    // Rounded up, so a message reloaded after a restart never expires early.
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since.as_secs() + u64::from(since.subsec_nanos() > 0)
+}
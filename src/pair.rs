@@ -0,0 +1,451 @@
+//! Pairing with a peer through a short code typed in on both sides, in the
+//! manner of magic-wormhole.
+//!
+//! A code such as `7-copper-walrus` starts with a nameplate, the number,
+//! which picks the rendezvous: both sides announce and look up the infohash
+//! derived from it and swap the messages of symmetric SPAKE2 over
+//! edwards25519 there. The rest of the code enters the PAKE only, so watching
+//! the DHT or the exchange gives nothing to test guesses against offline;
+//! each guess takes a live exchange, only a few are answered, and a wrong
+//! one ends the pairing.
+//!
+//! PAKE messages travel in control frames signed by the sender's ID and both
+//! IDs go into the derived key, so a confirmation that checks out shows the
+//! peer knows the code and holds the key of the ID it claims.
+
+use std::{collections::HashMap, net::SocketAddr};
+
+use curve25519_dalek::{
+    constants::ED25519_BASEPOINT_POINT,
+    edwards::{CompressedEdwardsY, EdwardsPoint},
+    scalar::Scalar,
+    traits::IsIdentity,
+};
+use mainline::Id;
+use rand::{Rng, RngCore, thread_rng};
+use sha1::Sha1;
+use sha2::{Digest, Sha512};
+
+use crate::{
+    error::{DhtMsgError, Result},
+    hmac::{self, HMAC_BYTES},
+};
+
+/// Words of generated codes; each adds 8 bits.
+pub(crate) const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "adult", "agent", "alarm", "album", "alley", "amber", "angle",
+    "ankle", "apple", "april", "arena", "armor", "arrow", "atlas", "attic", "audio", "autumn",
+    "badge", "bagel", "baker", "bamboo", "banjo", "barrel", "basin", "beach", "beard", "bench",
+    "berry", "bison", "blade", "blanket", "blossom", "bonus", "border", "bottle", "bracket",
+    "bread", "brick", "bridge", "bronze", "brush", "bucket", "buffalo", "butter", "button",
+    "cabin", "cactus", "camel", "canal", "candle", "canoe", "canyon", "carbon", "carpet", "castle",
+    "cedar", "chalk", "cherry", "chess", "circus", "clock", "cloud", "clover", "cobalt", "cocoa",
+    "comet", "copper", "coral", "cotton", "cousin", "coyote", "crayon", "cricket", "crystal",
+    "daisy", "dancer", "delta", "denim", "desert", "diesel", "dinner", "dolphin", "domino",
+    "donkey", "dragon", "drum", "eagle", "easel", "echo", "eclipse", "elbow", "ember", "engine",
+    "falcon", "feather", "fiddle", "finch", "flute", "forest", "fossil", "fountain", "fox",
+    "galaxy", "garden", "garlic", "gecko", "ginger", "glacier", "globe", "gopher", "granite",
+    "gravel", "guitar", "hammer", "harbor", "harvest", "hazel", "helmet", "hermit", "hockey",
+    "honey", "hornet", "husky", "igloo", "indigo", "island", "ivory", "jacket", "jaguar",
+    "jasmine", "jelly", "jersey", "jigsaw", "jungle", "kayak", "kernel", "kettle", "kitten",
+    "koala", "ladder", "lagoon", "lantern", "laser", "lemon", "lentil", "lilac", "limbo", "linen",
+    "lizard", "llama", "lobster", "locket", "lotus", "magnet", "mango", "maple", "marble",
+    "meadow", "melon", "mirror", "mitten", "monsoon", "mosaic", "muffin", "mustard", "napkin",
+    "nectar", "needle", "nickel", "noodle", "nutmeg", "oasis", "ocean", "olive", "onion", "orbit",
+    "orchid", "otter", "oyster", "paddle", "panda", "papaya", "parrot", "peanut", "pebble",
+    "pepper", "pickle", "pigeon", "pillow", "pilot", "planet", "plum", "pocket", "polka", "pony",
+    "poppy", "puzzle", "quartz", "quiver", "rabbit", "radar", "radish", "raven", "ribbon",
+    "rocket", "saddle", "salmon", "sandal", "satin", "saturn", "scarf", "shadow", "sherpa",
+    "silver", "sketch", "sleet", "socket", "spider", "spiral", "sponge", "squid", "stapler",
+    "storm", "sugar", "summit", "sunset", "swan", "tango", "teapot", "tennis", "thistle",
+    "thunder", "tiger", "timber", "toast", "tomato", "topaz", "torch", "tractor", "trumpet",
+    "tulip", "tunnel", "turnip", "turtle", "tuxedo", "umbrella", "unicorn", "valley", "velvet",
+    "violin", "volcano", "waffle", "walnut",
+];
+const CODE_WORDS: usize = 2;
+/// Nameplates of generated codes run from 1 to this.
+const MAX_NAMEPLATE: u32 = 999;
+/// Peers whose PAKE message is answered, i.e. guesses of the code allowed.
+const MAX_SESSIONS: usize = 3;
+pub(crate) const MESSAGE_BYTES: usize = 32;
+
+/// A fresh pairing code, such as `7-copper-walrus`, for [`DhtMsg::pair`]
+/// on both sides.
+///
+/// [`DhtMsg::pair`]: crate::DhtMsg::pair
+pub fn pairing_code() -> String {
+    let mut rng = thread_rng();
+    let mut code = rng.gen_range(1..=MAX_NAMEPLATE).to_string();
+    for _ in 0..CODE_WORDS {
+        code.push('-');
+        code.push_str(WORDS[rng.gen_range(0..WORDS.len())]);
+    }
+    code
+}
+
+/// Outcome of a successful [`DhtMsg::pair`](crate::DhtMsg::pair).
+#[derive(Clone)]
+pub struct Paired {
+    /// ID of the peer, whose key signed its side of the exchange.
+    pub peer_id: String,
+    /// Where the peer paired from.
+    pub addr: SocketAddr,
+    /// Secret only the two sides know, for example to use as a pre-shared
+    /// key.
+    pub key: [u8; 32],
+}
+
+/// Shows the peer only, keeping the key out of logs.
+impl std::fmt::Debug for Paired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Paired")
+            .field("peer_id", &self.peer_id)
+            .field("addr", &self.addr)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A pairing code, split into its parts.
+pub(crate) struct Code {
+    nameplate: String,
+    /// The whole code, lowercased.
+    password: String,
+}
+
+impl Code {
+    pub(crate) fn parse(code: &str) -> Result<Self> {
+        let password = code.trim().to_ascii_lowercase();
+        let invalid = || DhtMsgError::InvalidPairingCode {
+            code: code.to_string(),
+        };
+        let (nameplate, words) = password.split_once('-').ok_or_else(invalid)?;
+        let valid = !nameplate.is_empty()
+            && nameplate.bytes().all(|byte| byte.is_ascii_digit())
+            && words
+                .split('-')
+                .all(|word| !word.is_empty() && word.bytes().all(|b| b.is_ascii_alphanumeric()));
+        if !valid {
+            return Err(invalid());
+        }
+        Ok(Self {
+            nameplate: nameplate.to_string(),
+            password: password.clone(),
+        })
+    }
+
+    /// Infohash both sides of the pairing announce and look up.
+    pub(crate) fn rendezvous(&self) -> Id {
+        let seed = format!("dhtmsg pairing {}", self.nameplate);
+        let digest = <Sha1 as sha1::Digest>::digest(seed.as_bytes());
+        Id::from_bytes(digest.as_slice()).expect("SHA-1 digest is 20 bytes")
+    }
+}
+
+struct Session {
+    peer_id: String,
+    message: [u8; MESSAGE_BYTES],
+    confirm_key: [u8; 32],
+    key: [u8; 32],
+}
+
+/// Our side of a pairing in progress.
+pub(crate) struct Pairing {
+    local_id: String,
+    /// The code as a scalar, the `w` of SPAKE2.
+    password: Scalar,
+    secret: Scalar,
+    message: [u8; MESSAGE_BYTES],
+    sessions: HashMap<SocketAddr, Session>,
+    /// Set once a confirmation arrived, right or wrong.
+    finished: bool,
+    done: flume::Sender<Result<Paired>>,
+}
+
+impl Pairing {
+    /// A pairing with `code`, and where its outcome is delivered.
+    pub(crate) fn new(code: &Code, local_id: &str) -> (Self, flume::Receiver<Result<Paired>>) {
+        let password = hash_to_scalar(&[b"dhtmsg pairing password", code.password.as_bytes()]);
+        let mut random = [0u8; 64];
+        thread_rng().fill_bytes(&mut random);
+        let secret = Scalar::from_bytes_mod_order_wide(&random);
+        let message = EdwardsPoint::mul_base(&secret) + blinding_point() * password;
+        let (done, outcome) = flume::bounded(1);
+        let pairing = Self {
+            local_id: local_id.to_string(),
+            password,
+            secret,
+            message: message.compress().to_bytes(),
+            sessions: HashMap::new(),
+            finished: false,
+            done,
+        };
+        (pairing, outcome)
+    }
+
+    /// Our PAKE message.
+    pub(crate) fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// Addresses of the peers whose PAKE message we answered.
+    pub(crate) fn peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.sessions.keys().copied()
+    }
+
+    /// Take the PAKE message of `peer_id` from `peer`. Returns our
+    /// confirmation for it, and whether this is the first time we answer,
+    /// or `None` if we do not answer it.
+    pub(crate) fn receive_message(
+        &mut self,
+        peer: SocketAddr,
+        peer_id: &str,
+        message: &[u8],
+    ) -> Option<([u8; HMAC_BYTES], bool)> {
+        if let Some(session) = self.sessions.get(&peer) {
+            let repeated = session.peer_id == peer_id && session.message[..] == *message;
+            return repeated.then(|| (confirmation(&session.confirm_key, &self.local_id), false));
+        }
+        if self.finished || self.sessions.len() >= MAX_SESSIONS {
+            return None;
+        }
+        let message: [u8; MESSAGE_BYTES] = message.try_into().ok()?;
+        let theirs = CompressedEdwardsY(message)
+            .decompress()
+            .filter(|point| point.is_torsion_free() && !point.is_small_order())?;
+        let shared = (theirs - blinding_point() * self.password) * self.secret;
+        if shared.is_identity() {
+            return None;
+        }
+        // Sorted by message, so both sides hash the same transcript.
+        let ours = (self.local_id.as_str(), &self.message);
+        let theirs = (peer_id, &message);
+        let (first, second) = if ours.1 < theirs.1 {
+            (ours, theirs)
+        } else {
+            (theirs, ours)
+        };
+        let derived = Sha512::new()
+            .chain_update(b"dhtmsg pairing key")
+            .chain_update(self.password.as_bytes())
+            .chain_update(first.0.as_bytes())
+            .chain_update(first.1)
+            .chain_update(second.0.as_bytes())
+            .chain_update(second.1)
+            .chain_update(shared.compress().as_bytes())
+            .finalize();
+        let session = Session {
+            peer_id: peer_id.to_string(),
+            message,
+            confirm_key: derived[..32].try_into().unwrap(),
+            key: derived[32..].try_into().unwrap(),
+        };
+        let ours = confirmation(&session.confirm_key, &self.local_id);
+        self.sessions.insert(peer, session);
+        Some((ours, true))
+    }
+
+    /// Take the confirmation `mac` of `peer_id` from `peer`, which settles
+    /// the pairing unless we never answered that peer.
+    pub(crate) fn receive_confirmation(&mut self, peer: SocketAddr, peer_id: &str, mac: &[u8]) {
+        let Some(session) = self.sessions.get(&peer) else {
+            return;
+        };
+        if self.finished || session.peer_id != peer_id {
+            return;
+        }
+        self.finished = true;
+        let outcome = if hmac::verify(&confirmation(&session.confirm_key, peer_id), mac) {
+            Ok(Paired {
+                peer_id: peer_id.to_string(),
+                addr: peer,
+                key: session.key,
+            })
+        } else {
+            Err(DhtMsgError::PairingFailed(peer))
+        };
+        let _ = self.done.try_send(outcome);
+    }
+}
+
+/// What a side sends to show it derived the key: a MAC of its ID.
+fn confirmation(confirm_key: &[u8; 32], id: &str) -> [u8; HMAC_BYTES] {
+    hmac::hmac(confirm_key, &[b"dhtmsg pairing confirm", id.as_bytes()])
+}
+
+fn hash_to_scalar(parts: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    Scalar::from_bytes_mod_order_wide(&hasher.finalize().into())
+}
+
+/// The point hiding the password in PAKE messages, the `M = N` of symmetric
+/// SPAKE2. It is hashed from a fixed string, so nobody knows its discrete
+/// logarithm.
+fn blinding_point() -> EdwardsPoint {
+    (0u8..)
+        .find_map(|counter| {
+            let digest = Sha512::new()
+                .chain_update(b"dhtmsg pairing blinding point")
+                .chain_update([counter])
+                .finalize();
+            let point = CompressedEdwardsY(digest[..32].try_into().unwrap())
+                .decompress()?
+                .mul_by_cofactor();
+            (!point.is_identity() && point != ED25519_BASEPOINT_POINT).then_some(point)
+        })
+        .expect("a point turns up within 256 tries")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "alice-id";
+    const BOB: &str = "bob-id";
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    fn pairing(code: &str, id: &str) -> (Pairing, flume::Receiver<Result<Paired>>) {
+        Pairing::new(&Code::parse(code).unwrap(), id)
+    }
+
+    #[test]
+    fn same_codes_pair() {
+        let (mut alice, alice_done) = pairing("7-copper-walrus", ALICE);
+        let (mut bob, bob_done) = pairing("7-Copper-Walrus ", BOB);
+        let (alice_mac, first) = alice.receive_message(addr(2), BOB, bob.message()).unwrap();
+        assert!(first);
+        let (bob_mac, _) = bob
+            .receive_message(addr(1), ALICE, alice.message())
+            .unwrap();
+        assert_ne!(alice_mac, bob_mac);
+        // A repeated message gets the same answer, as not the first.
+        let repeated = alice.receive_message(addr(2), BOB, bob.message()).unwrap();
+        assert_eq!(repeated, (alice_mac, false));
+
+        alice.receive_confirmation(addr(2), BOB, &bob_mac);
+        bob.receive_confirmation(addr(1), ALICE, &alice_mac);
+        let alice_paired = alice_done.try_recv().unwrap().unwrap();
+        let bob_paired = bob_done.try_recv().unwrap().unwrap();
+        assert_eq!(alice_paired.peer_id, BOB);
+        assert_eq!(alice_paired.addr, addr(2));
+        assert_eq!(bob_paired.peer_id, ALICE);
+        assert_eq!(alice_paired.key, bob_paired.key);
+    }
+
+    #[test]
+    fn wrong_codes_end_the_pairing() {
+        let (mut alice, _) = pairing("7-copper-walrus", ALICE);
+        let (mut bob, bob_done) = pairing("7-copper-otter", BOB);
+        let (alice_mac, _) = alice.receive_message(addr(2), BOB, bob.message()).unwrap();
+        bob.receive_message(addr(1), ALICE, alice.message())
+            .unwrap();
+        bob.receive_confirmation(addr(1), ALICE, &alice_mac);
+        assert!(matches!(
+            bob_done.try_recv().unwrap(),
+            Err(DhtMsgError::PairingFailed(peer)) if peer == addr(1)
+        ));
+        // No more guesses.
+        let (other, _) = pairing("7-copper-otter", "other-id");
+        assert!(
+            bob.receive_message(addr(3), "other-id", other.message())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn confirmations_must_match_the_session() {
+        let (mut alice, alice_done) = pairing("7-copper-walrus", ALICE);
+        let (mut bob, _) = pairing("7-copper-walrus", BOB);
+        alice.receive_message(addr(2), BOB, bob.message()).unwrap();
+        let (bob_mac, _) = bob
+            .receive_message(addr(1), ALICE, alice.message())
+            .unwrap();
+        // From a peer never answered, or claiming another ID: ignored.
+        alice.receive_confirmation(addr(3), BOB, &bob_mac);
+        alice.receive_confirmation(addr(2), "mallory-id", &bob_mac);
+        assert!(alice_done.is_empty());
+        alice.receive_confirmation(addr(2), BOB, &bob_mac);
+        assert!(alice_done.try_recv().unwrap().is_ok());
+    }
+
+    #[test]
+    fn refuses_bad_points() {
+        let (mut alice, _) = pairing("7-copper-walrus", ALICE);
+        let mut identity = [0u8; MESSAGE_BYTES];
+        identity[0] = 1;
+        // y = -1, the point of order two.
+        let mut order_two = [0xff; MESSAGE_BYTES];
+        order_two[0] = 0xec;
+        order_two[31] = 0x7f;
+        let torsion = CompressedEdwardsY(order_two).decompress().unwrap();
+        let (bob, _) = pairing("7-copper-walrus", BOB);
+        let bob_point = CompressedEdwardsY(bob.message().try_into().unwrap())
+            .decompress()
+            .unwrap();
+        let with_torsion = (bob_point + torsion).compress().to_bytes();
+        // Not on the curve.
+        let mut off_curve = [0u8; MESSAGE_BYTES];
+        off_curve[0] = 2;
+        assert!(CompressedEdwardsY(off_curve).decompress().is_none());
+        for message in [identity, order_two, with_torsion, off_curve] {
+            assert!(alice.receive_message(addr(2), BOB, &message).is_none());
+        }
+        assert!(
+            alice
+                .receive_message(addr(2), BOB, &bob.message()[1..])
+                .is_none()
+        );
+        assert_eq!(alice.peers().count(), 0);
+        assert!(alice.receive_message(addr(2), BOB, bob.message()).is_some());
+    }
+
+    #[test]
+    fn answers_few_peers() {
+        let (mut alice, _) = pairing("7-copper-walrus", ALICE);
+        for port in 0..MAX_SESSIONS as u16 {
+            let (guess, _) = pairing(&format!("7-guess-{port}"), BOB);
+            assert!(
+                alice
+                    .receive_message(addr(port), BOB, guess.message())
+                    .is_some()
+            );
+        }
+        let (last, _) = pairing("7-copper-walrus", BOB);
+        assert!(
+            alice
+                .receive_message(addr(100), BOB, last.message())
+                .is_none()
+        );
+        assert_eq!(alice.peers().count(), MAX_SESSIONS);
+    }
+
+    #[test]
+    fn parses_codes() {
+        let code = Code::parse(&pairing_code()).unwrap();
+        assert_eq!(code.password.split('-').count(), 1 + CODE_WORDS);
+        assert_eq!(
+            Code::parse("7-a").unwrap().rendezvous(),
+            Code::parse("7-b").unwrap().rendezvous()
+        );
+        assert_ne!(
+            Code::parse("7-a").unwrap().rendezvous(),
+            Code::parse("8-a").unwrap().rendezvous()
+        );
+        for code in [
+            "",
+            "7",
+            "-walrus",
+            "x-walrus",
+            "7-",
+            "7--walrus",
+            "7-wal rus",
+        ] {
+            assert!(Code::parse(code).is_err(), "{code:?}");
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Asking the router to forward the hello port, over whichever of UPnP IGD
+//! (see the `upnp` module) and NAT-PMP or PCP (see the `natpmp` module) it
+//! speaks. All are tried at once and the first router to answer is kept.
+
+use std::{net::SocketAddrV4, thread, time::Duration};
+
+use crate::{
+    natpmp::{self, NatPmpError},
+    upnp::{self, UpnpError},
+};
+
+/// Lease asked for; the node renews at half of what it gets.
+pub(crate) const LEASE: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum PortMapError {
+    #[error(transparent)]
+    Upnp(#[from] UpnpError),
+    #[error(transparent)]
+    NatPmp(#[from] NatPmpError),
+    #[error("no router answered UPnP, NAT-PMP or PCP")]
+    NoRouter,
+}
+
+type Result<T> = std::result::Result<T, PortMapError>;
+
+/// A port the router forwards to us.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Mapping {
+    pub(crate) external: SocketAddrV4,
+    /// Zero if the router only grants permanent ones.
+    pub(crate) lease: Duration,
+}
+
+pub(crate) enum Router {
+    Upnp(upnp::Gateway),
+    NatPmp(natpmp::Gateway),
+}
+
+impl Router {
+    /// The router that answers first.
+    pub(crate) fn find() -> Result<Self> {
+        let (tx, rx) = flume::bounded(2);
+        let upnp = tx.clone();
+        thread::spawn(move || {
+            let _ = upnp.send(
+                upnp::Gateway::find()
+                    .map(Router::Upnp)
+                    .map_err(PortMapError::from),
+            );
+        });
+        thread::spawn(move || {
+            let _ = tx.send(
+                natpmp::Gateway::find()
+                    .map(Router::NatPmp)
+                    .map_err(PortMapError::from),
+            );
+        });
+        let mut failures = Vec::new();
+        for found in rx.iter() {
+            match found {
+                Ok(router) => return Ok(router),
+                Err(err) => failures.push(err),
+            }
+        }
+        // Both failed: a router that answered but offers nothing says more.
+        Err(failures
+            .into_iter()
+            .find(|err| {
+                !matches!(
+                    err,
+                    PortMapError::Upnp(UpnpError::NoGateway)
+                        | PortMapError::NatPmp(NatPmpError::NoAnswer)
+                )
+            })
+            .unwrap_or(PortMapError::NoRouter))
+    }
+
+    pub(crate) fn protocol(&self) -> &'static str {
+        match self {
+            Router::Upnp(_) => "UPnP",
+            Router::NatPmp(gateway) => gateway.protocol(),
+        }
+    }
+
+    /// Forward UDP `external_port`, or another one if the router will not,
+    /// to `local_port` of ours.
+    pub(crate) fn map(&self, local_port: u16, external_port: u16) -> Result<Mapping> {
+        Ok(match self {
+            Router::Upnp(gateway) => gateway.map(local_port, external_port)?,
+            Router::NatPmp(gateway) => gateway.map(local_port, external_port)?,
+        })
+    }
+
+    /// Stop forwarding `external_port` to `local_port`.
+    pub(crate) fn unmap(&self, local_port: u16, external_port: u16) -> Result<()> {
+        match self {
+            Router::Upnp(gateway) => gateway.unmap(external_port)?,
+            Router::NatPmp(gateway) => gateway.unmap(local_port)?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+//! Frames authenticated with a pre-shared key.
+//!
+//! With a key configured, every frame sent gets `[unix time u64 BE][HMAC]`
+//! appended, the HMAC-SHA256 keyed with the pre-shared key over the frame
+//! and the time. Received frames without a valid tag, or with a time too far
+//! from ours, are dropped before anything parses them, so whoever scrapes
+//! the announced address off the DHT cannot get an ack or a message in.
+//! Datagrams that are not frames at all are passed on to be classified as
+//! foreign, as usual.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use log::{debug, info};
+
+use crate::{
+    hmac::{self, HMAC_BYTES, hmac},
+    transport::{SharedTransport, Transport},
+    wire::{self, unix_secs},
+};
+
+const TIMESTAMP_BYTES: usize = 8;
+const TAG_BYTES: usize = TIMESTAMP_BYTES + HMAC_BYTES;
+/// Largest difference between a tag's time and ours, in seconds; it allows
+/// for clocks that are a few minutes apart.
+const MAX_SKEW_SECS: u64 = 300;
+
+pub(crate) struct PskTransport {
+    inner: SharedTransport,
+    key: Vec<u8>,
+    /// Frames dropped for a missing, wrong or stale tag.
+    unauthenticated: AtomicU64,
+}
+
+impl PskTransport {
+    pub(crate) fn new(inner: SharedTransport, key: Vec<u8>) -> Self {
+        Self {
+            inner,
+            key,
+            unauthenticated: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn unauthenticated(&self) -> u64 {
+        self.unauthenticated.load(Ordering::Relaxed)
+    }
+
+    /// `datagram` with the tag for `timestamp` appended.
+    fn tag(&self, datagram: &[u8], timestamp: u64) -> Vec<u8> {
+        let mut tagged = Vec::with_capacity(datagram.len() + TAG_BYTES);
+        tagged.extend_from_slice(datagram);
+        tagged.extend_from_slice(&timestamp.to_be_bytes());
+        let mac = hmac(&self.key, &[&tagged]);
+        tagged.extend_from_slice(&mac);
+        tagged
+    }
+
+    /// Length of the frame in `datagram` if its tag checks out.
+    fn check(&self, datagram: &[u8]) -> Option<usize> {
+        let frame_len = datagram.len().checked_sub(TAG_BYTES)?;
+        let (signed, mac) = datagram.split_at(frame_len + TIMESTAMP_BYTES);
+        let timestamp = u64::from_be_bytes(signed[frame_len..].try_into().ok()?);
+        if timestamp.abs_diff(unix_secs()) > MAX_SKEW_SECS {
+            return None;
+        }
+        hmac::verify(&hmac(&self.key, &[signed]), mac).then_some(frame_len)
+    }
+}
+
+impl Transport for PskTransport {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.inner.send_to(&self.tag(datagram, unix_secs()), addr)?;
+        Ok(datagram.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, peer) = self.inner.recv_from(buf)?;
+            if !wire::has_magic(&buf[..len]) {
+                return Ok((len, peer));
+            }
+            if let Some(frame_len) = self.check(&buf[..len]) {
+                return Ok((frame_len, peer));
+            }
+            debug!("frame from {peer} without a valid pre-shared key tag (dropped)");
+            let dropped = self.unauthenticated.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                info!("dropped {dropped} frames not authenticated with the pre-shared key so far");
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, sync::Arc, time::Duration};
+
+    use super::*;
+
+    const FRAME: &[u8] = b"DM a frame, as far as the tag is concerned";
+
+    fn psk(key: &[u8]) -> PskTransport {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        PskTransport::new(Arc::new(socket), key.to_vec())
+    }
+
+    #[test]
+    fn accepts_valid_tags() {
+        let psk = psk(b"key");
+        let tagged = psk.tag(FRAME, unix_secs());
+        assert_eq!(tagged.len(), FRAME.len() + TAG_BYTES);
+        assert_eq!(psk.check(&tagged), Some(FRAME.len()));
+        // Even around an empty frame.
+        assert_eq!(psk.check(&psk.tag(&[], unix_secs())), Some(0));
+    }
+
+    #[test]
+    fn refuses_other_keys_and_tampering() {
+        let tagged = psk(b"key").tag(FRAME, unix_secs());
+        assert_eq!(psk(b"other key").check(&tagged), None);
+        assert_eq!(psk(b"").check(&tagged), None);
+        let psk = psk(b"key");
+        for at in 0..tagged.len() {
+            let mut tampered = tagged.clone();
+            tampered[at] ^= 1;
+            assert_eq!(psk.check(&tampered), None, "byte {at}");
+        }
+    }
+
+    #[test]
+    fn refuses_clock_skew() {
+        let psk = psk(b"key");
+        let now = unix_secs();
+        for skew in [0, 1, MAX_SKEW_SECS - 1] {
+            assert_eq!(psk.check(&psk.tag(FRAME, now + skew)), Some(FRAME.len()));
+            assert_eq!(psk.check(&psk.tag(FRAME, now - skew)), Some(FRAME.len()));
+        }
+        for skew in [MAX_SKEW_SECS + 2, 3600, now] {
+            assert_eq!(
+                psk.check(&psk.tag(FRAME, now + skew)),
+                None,
+                "{skew} s ahead"
+            );
+            assert_eq!(
+                psk.check(&psk.tag(FRAME, now - skew)),
+                None,
+                "{skew} s behind"
+            );
+        }
+        assert_eq!(psk.check(&psk.tag(FRAME, u64::MAX)), None);
+    }
+
+    #[test]
+    fn refuses_datagrams_shorter_than_a_tag() {
+        let psk = psk(b"key");
+        let tagged = psk.tag(FRAME, unix_secs());
+        for len in 0..TAG_BYTES {
+            assert_eq!(
+                psk.check(&tagged[tagged.len() - len..]),
+                None,
+                "{len} bytes"
+            );
+            assert_eq!(psk.check(&tagged[..len]), None, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn drops_untagged_frames_only() {
+        let sender = psk(b"key");
+        let receiver = psk(b"key");
+        let to = receiver.local_addr().unwrap();
+        let raw = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buf = [0u8; 1500];
+
+        sender.send_to(FRAME, to).unwrap();
+        let (len, _) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], FRAME);
+
+        // An untagged frame is dropped; a BitTorrent datagram passes as is.
+        raw.send_to(FRAME, to).unwrap();
+        raw.send_to(b"d1:y1:qe", to).unwrap();
+        let (len, _) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"d1:y1:qe");
+        assert_eq!(receiver.unauthenticated(), 1);
+    }
+}
@@ -0,0 +1,140 @@
+//! Punch requests: BEP 44 mutable items in which a node asks a peer to
+//! send to it at an agreed time.
+//!
+//! Behind most NATs, a datagram from outside only gets in once we sent one
+//! to where it comes from. If only one side says hello, its hellos are
+//! dropped by the other's NAT and nothing opens the way back. If both send
+//! at the same moment, each NAT sees its own datagram leave first and lets
+//! the peer's in, which works for every NAT that keeps one public port per
+//! local one.
+//!
+//! A request sits under the key of the node asking with the salt
+//! `SHA1("dhtmsg punch" || salt || peer key)`, the salt being that of the
+//! infohashes, so only the peer it is meant for looks for it. The value is
+//! the bencoded dictionary `d1:ti<start>ee`, `start` being the Unix time
+//! in milliseconds at which both sides send, which is also the sequence
+//! number. Both clocks must be right to within a second or so.
+//!
+//! Behind a NAT that maps each destination to another port, the request
+//! also carries a port prediction (see the `birthday` module) in `p`, `s`
+//! and `n`: `d1:ni512e1:pi40001e1:si1e1:ti<start>ee`. The peer then answers
+//! with a request of its own for the same start, with its prediction if
+//! its NAT is like that too, and both spray hellos over the other's range.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ed25519_dalek::VerifyingKey;
+use mainline::MutableItem;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::{
+    Identity,
+    birthday::Prediction,
+    endpoint::{signable, signed_by},
+};
+
+const SALT_CONTEXT: &[u8] = b"dhtmsg punch";
+
+/// How far ahead of the request the start is: enough for the request to
+/// be stored and for the peer's next lookup round to find it.
+pub(crate) const LEAD: Duration = Duration::from_secs(20);
+/// How far ahead the start of a request with a prediction is, leaving
+/// time for the peer's answer to be stored and found as well.
+pub(crate) const PREDICTION_LEAD: Duration = Duration::from_secs(40);
+/// Requests starting further ahead than this are ignored.
+const MAX_AHEAD: Duration = Duration::from_secs(60);
+/// Requests that started longer ago than this are ignored: the peer's
+/// burst is over.
+const MAX_LATE: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    n: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    p: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    s: Option<i32>,
+    t: i64,
+}
+
+/// A request found for us.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Punch {
+    pub(crate) start: SystemTime,
+    /// Where the peer's NAT maps the sockets it sprays from, if it maps
+    /// each destination to another port.
+    pub(crate) prediction: Option<Prediction>,
+}
+
+/// `lead` from now, in the whole milliseconds requests carry, so that an
+/// answer for the same start compares equal.
+pub(crate) fn start_in(lead: Duration) -> SystemTime {
+    let start = SystemTime::now() + lead;
+    UNIX_EPOCH + Duration::from_millis(unix_millis(start) as u64)
+}
+
+/// The BEP 44 salt of requests to `peer` under `salt`.
+pub(crate) fn salt(salt: &[u8], peer: &VerifyingKey) -> [u8; 20] {
+    let mut hasher = <Sha1 as sha1::Digest>::new();
+    sha1::Digest::update(&mut hasher, SALT_CONTEXT);
+    sha1::Digest::update(&mut hasher, salt);
+    sha1::Digest::update(&mut hasher, peer.as_bytes());
+    sha1::Digest::finalize(hasher).into()
+}
+
+/// A request by `identity` to start at `start`, signed under `salt`.
+pub(crate) fn request(
+    identity: &Identity,
+    start: SystemTime,
+    prediction: Option<Prediction>,
+    salt: &[u8],
+) -> MutableItem {
+    let t = unix_millis(start);
+    let request = Request {
+        n: prediction.map(|prediction| prediction.span),
+        p: prediction.map(|prediction| prediction.port),
+        s: prediction.map(|prediction| prediction.step),
+        t,
+    };
+    let value = serde_bencode::to_bytes(&request).expect("requests always encode");
+    let signature = identity.sign(&signable(t, &value, salt));
+    MutableItem::new_signed_unchecked(
+        identity.verifying_key().to_bytes(),
+        signature.to_bytes(),
+        &value,
+        t,
+        Some(salt),
+    )
+}
+
+/// The newest of `items` that `key` signed under `salt`, if it is neither
+/// long past nor far ahead.
+pub(crate) fn newest(
+    items: impl IntoIterator<Item = MutableItem>,
+    key: &VerifyingKey,
+    salt: &[u8],
+) -> Option<Punch> {
+    let request = items
+        .into_iter()
+        .filter(|item| signed_by(item, key, salt))
+        .filter_map(|item| serde_bencode::from_bytes::<Request>(item.value()).ok())
+        .max_by_key(|request| request.t)?;
+    let start = UNIX_EPOCH + Duration::from_millis(u64::try_from(request.t).ok()?);
+    let now = SystemTime::now();
+    let near = match start.duration_since(now) {
+        Ok(ahead) => ahead <= MAX_AHEAD,
+        Err(late) => late.duration() <= MAX_LATE,
+    };
+    let prediction = match (request.p, request.s, request.n) {
+        (Some(port), Some(step), Some(span)) => Some(Prediction { port, step, span }),
+        _ => None,
+    };
+    near.then_some(Punch { start, prediction })
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
@@ -0,0 +1,568 @@
+//! Relaying for peers that cannot reach each other directly, such as two
+//! behind NATs that map every destination to another port.
+//!
+//! A relay is a publicly reachable node that announces the well-known relay
+//! infohash (see [`derive_relay_infohash`](crate::derive_relay_infohash)).
+//! Clients register with it from their hello socket, under their ID, and
+//! send it datagrams for other registered clients, named by key, which it
+//! passes on naming the sender instead. What it passes on are the frames of
+//! the Noise session between the two clients, so it can read and forge
+//! nothing; all it learns is who talks to whom, and how much.
+//!
+//! To the layers above, a peer reached through a relay sits at a virtual
+//! address in the discard-only prefix `100::/64` (RFC 6666) made of its
+//! key, which [`RelayTransport`] maps to the relay and back. Relay
+//! datagrams start with the magic `DR` and their type:
+//!
+//! - register `[1][key 32][time u64 BE][signature 64]`, the time in Unix
+//!   milliseconds, newer for each registration; the signature is over
+//!   `"dhtmsg relay" || key || time`
+//! - registered `[2][time u64 BE]`, echoing the registration's time
+//! - send `[3][recipient key 32][datagram]`
+//! - deliver `[4][sender key 32][datagram]`
+//!
+//! Registrations last 90 seconds and are renewed every 25, which also keeps
+//! the NAT mapping towards the relay open. The signature cannot cover the
+//! client's address, which its NAT picks, so a registration moving a key
+//! to another address must be at least half a renewal newer than the last.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use log::{debug, info};
+
+use crate::{
+    Identity,
+    transport::{SharedTransport, Transport},
+    wire::unix_millis,
+};
+
+const MAGIC: &[u8; 2] = b"DR";
+const SIGNATURE_CONTEXT: &[u8] = b"dhtmsg relay";
+const REGISTER: u8 = 1;
+const REGISTERED: u8 = 2;
+const SEND: u8 = 3;
+const DELIVER: u8 = 4;
+const KEY_BYTES: usize = 32;
+const REGISTER_BYTES: usize = MAGIC.len() + 1 + KEY_BYTES + 8 + 64;
+/// Bytes a relayed datagram grows by.
+const OVERHEAD: usize = MAGIC.len() + 1 + KEY_BYTES;
+
+/// How often clients renew their registrations.
+pub(crate) const REGISTER_INTERVAL: Duration = Duration::from_secs(25);
+/// How long a registration lasts, and a relay that stops answering is
+/// kept.
+const CLIENT_TTL: Duration = Duration::from_secs(90);
+/// Registrations a relay keeps at once.
+const MAX_CLIENTS: usize = 4096;
+/// How much newer than a key's last registration one from another address
+/// must be: renewals are this far apart at least, while a copy of one
+/// replayed from elsewhere is no newer than the registrations around it.
+const MOVE_INTERVAL: Duration = Duration::from_millis(REGISTER_INTERVAL.as_millis() as u64 / 2);
+/// Registrations whose time is further from ours are refused.
+const MAX_SKEW: Duration = Duration::from_secs(300);
+/// Bytes a relay passes on for each client per second.
+const MAX_BYTES_PER_SEC: usize = 256 * 1024;
+
+struct Client {
+    addr: SocketAddr,
+    /// Time of the newest registration, which replays must exceed.
+    time: u64,
+    registered: Instant,
+    /// Start of the current second, and what was sent in it.
+    window: Instant,
+    sent: usize,
+}
+
+/// Clients registered with us, if we relay.
+#[derive(Default)]
+struct Clients {
+    by_key: HashMap<[u8; KEY_BYTES], Client>,
+    by_addr: HashMap<SocketAddr, [u8; KEY_BYTES]>,
+}
+
+impl Clients {
+    fn live(&self, key: &[u8; KEY_BYTES]) -> Option<&Client> {
+        self.by_key
+            .get(key)
+            .filter(|client| client.registered.elapsed() < CLIENT_TTL)
+    }
+
+    fn register(&mut self, key: [u8; KEY_BYTES], time: u64, addr: SocketAddr) -> bool {
+        if !self.by_key.contains_key(&key) && self.by_key.len() >= MAX_CLIENTS {
+            self.by_key
+                .retain(|_, client| client.registered.elapsed() < CLIENT_TTL);
+            let by_key = &self.by_key;
+            self.by_addr.retain(|_, key| by_key.contains_key(key));
+            if self.by_key.len() >= MAX_CLIENTS {
+                return false;
+            }
+        }
+        let now = Instant::now();
+        match self.by_key.get_mut(&key) {
+            Some(client) if time <= client.time => return false,
+            Some(client)
+                if client.addr != addr
+                    && Duration::from_millis(time - client.time) < MOVE_INTERVAL =>
+            {
+                return false;
+            }
+            Some(client) => {
+                if client.addr != addr {
+                    self.by_addr.remove(&client.addr);
+                    client.addr = addr;
+                }
+                client.time = time;
+                client.registered = now;
+            }
+            None => {
+                self.by_key.insert(
+                    key,
+                    Client {
+                        addr,
+                        time,
+                        registered: now,
+                        window: now,
+                        sent: 0,
+                    },
+                );
+            }
+        }
+        // A client that took over the address of another has replaced it.
+        if let Some(old) = self.by_addr.insert(addr, key)
+            && old != key
+            && self
+                .by_key
+                .get(&old)
+                .is_some_and(|client| client.addr == addr)
+        {
+            self.by_key.remove(&old);
+        }
+        true
+    }
+}
+
+/// A relay we are registered with.
+struct Relay {
+    /// Time of our last registration.
+    time: u64,
+    added: Instant,
+    answered: Option<Instant>,
+}
+
+impl Relay {
+    fn is_live(&self) -> bool {
+        self.answered
+            .is_some_and(|answered| answered.elapsed() < CLIENT_TTL)
+    }
+}
+
+/// Where a virtual address leads.
+#[derive(Clone, Copy)]
+struct Route {
+    relay: SocketAddr,
+    key: [u8; KEY_BYTES],
+}
+
+/// Relays datagrams for others if it serves as a relay, and carries ours
+/// through relays to peers at virtual addresses.
+pub(crate) struct RelayTransport {
+    inner: SharedTransport,
+    identity: Arc<Identity>,
+    serve: bool,
+    clients: Mutex<Clients>,
+    relays: Mutex<HashMap<SocketAddr, Relay>>,
+    routes: Mutex<HashMap<SocketAddr, Route>>,
+}
+
+impl RelayTransport {
+    pub(crate) fn new(inner: SharedTransport, identity: Arc<Identity>, serve: bool) -> Self {
+        Self {
+            inner,
+            identity,
+            serve,
+            clients: Mutex::default(),
+            relays: Mutex::default(),
+            routes: Mutex::default(),
+        }
+    }
+
+    /// Register with `relay`, or renew the registration.
+    pub(crate) fn register(&self, relay: SocketAddr) -> io::Result<()> {
+        let time = {
+            let mut relays = self.relays.lock().unwrap();
+            let entry = relays.entry(relay).or_insert_with(|| Relay {
+                time: 0,
+                added: Instant::now(),
+                answered: None,
+            });
+            // Newer than the last, even within a millisecond.
+            entry.time = unix_millis().max(entry.time + 1);
+            entry.time
+        };
+        let key = self.identity.verifying_key().to_bytes();
+        let mut datagram = Vec::with_capacity(REGISTER_BYTES);
+        datagram.extend_from_slice(MAGIC);
+        datagram.push(REGISTER);
+        datagram.extend_from_slice(&key);
+        datagram.extend_from_slice(&time.to_be_bytes());
+        datagram.extend_from_slice(&self.identity.sign(&signable(&key, time)).to_bytes());
+        self.inner.send_to(&datagram, relay).map(drop)
+    }
+
+    /// Renew every registration, and drop relays that stopped answering
+    /// or never did.
+    pub(crate) fn renew(&self) {
+        let relays: Vec<SocketAddr> = {
+            let mut relays = self.relays.lock().unwrap();
+            relays.retain(|relay, state| {
+                let keep = match state.answered {
+                    Some(answered) => answered.elapsed() < CLIENT_TTL,
+                    None => state.added.elapsed() < CLIENT_TTL,
+                };
+                if !keep {
+                    info!("relay {relay} stopped answering");
+                }
+                keep
+            });
+            relays.keys().copied().collect()
+        };
+        for relay in relays {
+            if let Err(err) = self.register(relay) {
+                debug!("renewing the registration with relay {relay} failed: {err}");
+            }
+        }
+    }
+
+    /// Relays that answered our registration lately.
+    pub(crate) fn live_relays(&self) -> Vec<SocketAddr> {
+        let relays = self.relays.lock().unwrap();
+        let mut live: Vec<SocketAddr> = relays
+            .iter()
+            .filter(|(_, relay)| relay.is_live())
+            .map(|(addr, _)| *addr)
+            .collect();
+        live.sort();
+        live
+    }
+
+    /// Whether we registered with any relay, answered or not.
+    pub(crate) fn has_relays(&self) -> bool {
+        !self.relays.lock().unwrap().is_empty()
+    }
+
+    /// Whether we registered with `relay`, answered or not.
+    pub(crate) fn uses(&self, relay: &SocketAddr) -> bool {
+        self.relays.lock().unwrap().contains_key(relay)
+    }
+
+    /// The virtual address of `key` through `relay`, from now on.
+    pub(crate) fn route(&self, key: &VerifyingKey, relay: SocketAddr) -> SocketAddr {
+        let key = key.to_bytes();
+        let addr = virtual_addr(&key);
+        self.routes
+            .lock()
+            .unwrap()
+            .insert(addr, Route { relay, key });
+        addr
+    }
+
+    /// The relay that `addr` leads through, if it is a virtual address.
+    pub(crate) fn relay_of(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        self.routes
+            .lock()
+            .unwrap()
+            .get(addr)
+            .map(|route| route.relay)
+    }
+
+    /// Handle the relay datagram in `buf[..len]` from `from`; what it
+    /// delivers, if anything, is moved to the start of `buf` and its length
+    /// returned with the sender's virtual address.
+    fn handle(&self, buf: &mut [u8], len: usize, from: SocketAddr) -> Option<(usize, SocketAddr)> {
+        let datagram = &buf[..len];
+        match datagram[MAGIC.len()] {
+            REGISTER if self.serve && len == REGISTER_BYTES => {
+                self.registration(datagram, from);
+                None
+            }
+            REGISTERED if len == MAGIC.len() + 1 + 8 => {
+                let time = u64::from_be_bytes(datagram[3..11].try_into().ok()?);
+                let mut relays = self.relays.lock().unwrap();
+                let relay = relays.get_mut(&from).filter(|relay| relay.time == time)?;
+                if !relay.is_live() {
+                    info!("registered with relay {from}");
+                }
+                relay.answered = Some(Instant::now());
+                None
+            }
+            SEND if self.serve && len > OVERHEAD => {
+                self.pass_on(datagram, from);
+                None
+            }
+            DELIVER if len > OVERHEAD && self.uses(&from) => {
+                let key: [u8; KEY_BYTES] = datagram[3..OVERHEAD].try_into().ok()?;
+                let addr = virtual_addr(&key);
+                self.routes
+                    .lock()
+                    .unwrap()
+                    .insert(addr, Route { relay: from, key });
+                buf.copy_within(OVERHEAD..len, 0);
+                Some((len - OVERHEAD, addr))
+            }
+            _ => {
+                debug!("unexpected relay datagram from {from} (dropped)");
+                None
+            }
+        }
+    }
+
+    fn registration(&self, datagram: &[u8], from: SocketAddr) {
+        let key: [u8; KEY_BYTES] = datagram[3..35].try_into().expect("32 bytes");
+        let time_bytes: [u8; 8] = datagram[35..43].try_into().expect("8 bytes");
+        let time = u64::from_be_bytes(time_bytes);
+        let signature = Signature::from_bytes(&datagram[43..].try_into().expect("64 bytes"));
+        let authentic = VerifyingKey::from_bytes(&key).is_ok_and(|verifying| {
+            verifying
+                .verify_strict(&signable(&key, time), &signature)
+                .is_ok()
+        });
+        let fresh = Duration::from_millis(time.abs_diff(unix_millis())) <= MAX_SKEW;
+        if !authentic || !fresh {
+            debug!("forged or stale relay registration from {from} (dropped)");
+            return;
+        }
+        if !self.clients.lock().unwrap().register(key, time, from) {
+            debug!("relay registration from {from} refused");
+            return;
+        }
+        let mut answer = Vec::with_capacity(MAGIC.len() + 1 + 8);
+        answer.extend_from_slice(MAGIC);
+        answer.push(REGISTERED);
+        answer.extend_from_slice(&time_bytes);
+        if let Err(err) = self.inner.send_to(&answer, from) {
+            debug!("answering the relay registration from {from} failed: {err}");
+        }
+    }
+
+    /// Pass a send datagram on to its recipient, if both ends are
+    /// registered and the sender has not used up its share.
+    fn pass_on(&self, datagram: &[u8], from: SocketAddr) {
+        let to: [u8; KEY_BYTES] = datagram[3..OVERHEAD].try_into().expect("32 bytes");
+        let payload = &datagram[OVERHEAD..];
+        let (sender, recipient) = {
+            let mut clients = self.clients.lock().unwrap();
+            let Some(sender) = clients.by_addr.get(&from).copied() else {
+                debug!("relay datagram from unregistered {from} (dropped)");
+                return;
+            };
+            let Some(recipient) = clients.live(&to).map(|client| client.addr) else {
+                debug!("relay datagram from {from} for an unregistered key (dropped)");
+                return;
+            };
+            let Some(client) = clients.by_key.get_mut(&sender) else {
+                return;
+            };
+            if client.window.elapsed() >= Duration::from_secs(1) {
+                client.window = Instant::now();
+                client.sent = 0;
+            }
+            client.sent += payload.len();
+            if client.sent > MAX_BYTES_PER_SEC {
+                debug!("relay datagram from {from} over its share (dropped)");
+                return;
+            }
+            (sender, recipient)
+        };
+        let mut delivered = Vec::with_capacity(datagram.len());
+        delivered.extend_from_slice(MAGIC);
+        delivered.push(DELIVER);
+        delivered.extend_from_slice(&sender);
+        delivered.extend_from_slice(payload);
+        if let Err(err) = self.inner.send_to(&delivered, recipient) {
+            debug!("relaying to {recipient} failed: {err}");
+        }
+    }
+}
+
+impl Transport for RelayTransport {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let Some(route) = self.routes.lock().unwrap().get(&addr).copied() else {
+            return self.inner.send_to(datagram, addr);
+        };
+        let mut relayed = Vec::with_capacity(OVERHEAD + datagram.len());
+        relayed.extend_from_slice(MAGIC);
+        relayed.push(SEND);
+        relayed.extend_from_slice(&route.key);
+        relayed.extend_from_slice(datagram);
+        self.inner.send_to(&relayed, route.relay)?;
+        Ok(datagram.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, from) = self.inner.recv_from(buf)?;
+            if len <= MAGIC.len() || &buf[..MAGIC.len()] != MAGIC {
+                return Ok((len, from));
+            }
+            if let Some(delivered) = self.handle(buf, len, from) {
+                return Ok(delivered);
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+fn signable(key: &[u8; KEY_BYTES], time: u64) -> Vec<u8> {
+    let mut signable = Vec::with_capacity(SIGNATURE_CONTEXT.len() + KEY_BYTES + 8);
+    signable.extend_from_slice(SIGNATURE_CONTEXT);
+    signable.extend_from_slice(key);
+    signable.extend_from_slice(&time.to_be_bytes());
+    signable
+}
+
+/// `100::` followed by the first eight bytes of `key`, on the port of the
+/// next two.
+fn virtual_addr(key: &[u8; KEY_BYTES]) -> SocketAddr {
+    let mut octets = [0u8; 16];
+    octets[0] = 0x01;
+    octets[8..].copy_from_slice(&key[..8]);
+    let port = u16::from_be_bytes([key[8], key[9]]);
+    SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    use super::*;
+
+    const KEY: [u8; KEY_BYTES] = [1; KEY_BYTES];
+    const OTHER_KEY: [u8; KEY_BYTES] = [2; KEY_BYTES];
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::new(198, 51, 100, 1), port))
+    }
+
+    #[test]
+    fn registrations_only_move_on_renewal() {
+        let mut clients = Clients::default();
+        assert!(clients.register(KEY, 1_000, addr(1)));
+        // Replays, and registrations from elsewhere between two renewals.
+        assert!(!clients.register(KEY, 1_000, addr(2)));
+        assert!(!clients.register(KEY, 1_001, addr(2)));
+        assert_eq!(clients.by_key[&KEY].addr, addr(1));
+        // From the same address, any newer one renews.
+        assert!(clients.register(KEY, 1_001, addr(1)));
+        let renewal = 1_001 + MOVE_INTERVAL.as_millis() as u64;
+        assert!(clients.register(KEY, renewal, addr(2)));
+        assert_eq!(clients.by_key[&KEY].addr, addr(2));
+        assert!(!clients.by_addr.contains_key(&addr(1)));
+        assert_eq!(clients.by_addr[&addr(2)], KEY);
+    }
+
+    #[test]
+    fn new_keys_replace_old_ones_at_an_address() {
+        let mut clients = Clients::default();
+        assert!(clients.register(KEY, 1_000, addr(1)));
+        assert!(clients.register(OTHER_KEY, 1_000, addr(1)));
+        assert!(clients.live(&KEY).is_none());
+        assert_eq!(clients.by_addr[&addr(1)], OTHER_KEY);
+        assert_eq!(clients.by_key.len(), 1);
+
+        // One that has moved on keeps its registration.
+        assert!(clients.register(KEY, 1_000, addr(2)));
+        let later = 1_000 + MOVE_INTERVAL.as_millis() as u64;
+        assert!(clients.register(KEY, later, addr(3)));
+        assert!(clients.register(OTHER_KEY, later, addr(2)));
+        assert_eq!(clients.live(&KEY).unwrap().addr, addr(3));
+        assert_eq!(clients.live(&OTHER_KEY).unwrap().addr, addr(2));
+        assert_eq!(clients.by_key.len(), 2);
+        assert_eq!(clients.by_addr.len(), 2);
+    }
+
+    /// A relay transport on a localhost socket that gives up reading soon.
+    fn transport(serve: bool) -> RelayTransport {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        RelayTransport::new(Arc::new(socket), Arc::new(Identity::generate()), serve)
+    }
+
+    /// Handle what arrived at `transport` until it delivers something or
+    /// nothing is left.
+    fn pump(transport: &RelayTransport) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buf = [0u8; 2048];
+        let (len, from) = transport.recv_from(&mut buf).ok()?;
+        Some((buf[..len].to_vec(), from))
+    }
+
+    #[test]
+    fn relays_between_registered_clients() {
+        let relay = transport(true);
+        let relay_addr = relay.local_addr().unwrap();
+        let (alice, bob) = (transport(false), transport(false));
+        alice.register(relay_addr).unwrap();
+        bob.register(relay_addr).unwrap();
+        assert_eq!(pump(&relay), None);
+        assert_eq!(pump(&alice), None);
+        assert_eq!(pump(&bob), None);
+        assert_eq!(alice.live_relays(), [relay_addr]);
+        assert_eq!(bob.live_relays(), [relay_addr]);
+
+        let to_bob = alice.route(&bob.identity.verifying_key(), relay_addr);
+        assert_eq!(alice.relay_of(&to_bob), Some(relay_addr));
+        alice.send_to(b"hello bob", to_bob).unwrap();
+        assert_eq!(pump(&relay), None);
+        let alice_key = alice.identity.verifying_key().to_bytes();
+        assert_eq!(
+            pump(&bob),
+            Some((b"hello bob".to_vec(), virtual_addr(&alice_key)))
+        );
+    }
+
+    #[test]
+    fn forged_and_replayed_registrations_are_refused() {
+        let relay = transport(true);
+        let relay_addr = relay.local_addr().unwrap();
+        let client = transport(false);
+        let sniffer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        client.register(sniffer.local_addr().unwrap()).unwrap();
+        let mut registration = [0u8; REGISTER_BYTES];
+        assert_eq!(sniffer.recv(&mut registration).unwrap(), REGISTER_BYTES);
+        let key = client.identity.verifying_key().to_bytes();
+
+        let first = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        first.send_to(&registration, relay_addr).unwrap();
+        assert_eq!(pump(&relay), None);
+        let registered = relay.clients.lock().unwrap().by_key[&key].addr;
+        assert_eq!(registered, first.local_addr().unwrap());
+
+        // The same registration from elsewhere, one with another time than
+        // was signed, and one signed long ago.
+        let second = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        second.send_to(&registration, relay_addr).unwrap();
+        let mut forged = registration;
+        forged[42] ^= 1;
+        second.send_to(&forged, relay_addr).unwrap();
+        let stale = unix_millis() - 2 * MAX_SKEW.as_millis() as u64;
+        let mut old = registration[..35].to_vec();
+        old.extend_from_slice(&stale.to_be_bytes());
+        old.extend_from_slice(&client.identity.sign(&signable(&key, stale)).to_bytes());
+        second.send_to(&old, relay_addr).unwrap();
+        assert_eq!(pump(&relay), None);
+        let registered = relay.clients.lock().unwrap().by_key[&key].addr;
+        assert_eq!(registered, first.local_addr().unwrap());
+        assert_eq!(relay.clients.lock().unwrap().by_addr.len(), 1);
+    }
+}
@@ -0,0 +1,224 @@
+//! Rejection of replayed frames, per sender key.
+//!
+//! A frame passes if its timestamp is within the window of our clock and
+//! its nonce is one the sender has not used. Senders number frames upwards,
+//! so each key needs only the highest nonce seen and a bitmap of the ones
+//! just below it, like the receive windows in `dedup`.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, VerifyingKey};
+
+use crate::wire::unix_secs;
+
+/// Nonces remembered behind the highest one seen. Frames sent concurrently
+/// or reordered on the way may arrive this far out of order.
+const WINDOW: u64 = 128;
+/// Keys tracked before idle ones are dropped.
+const MAX_TRACKED: usize = 1024;
+
+/// Why a frame was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Replay {
+    /// Its timestamp is outside the window.
+    Stale,
+    /// The sender used its nonce before, or it is too old to tell.
+    Repeated,
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Stale => "timestamp outside the replay window",
+            Self::Repeated => "nonce already seen",
+        })
+    }
+}
+
+struct NonceWindow {
+    highest: u64,
+    /// Bit `i` is set if `highest - i` was seen.
+    seen: u128,
+    last_seen: Instant,
+}
+
+impl NonceWindow {
+    fn accept(&mut self, nonce: u64) -> bool {
+        if nonce > self.highest {
+            let ahead = nonce - self.highest;
+            self.seen = if ahead < WINDOW {
+                (self.seen << ahead) | 1
+            } else {
+                1
+            };
+            self.highest = nonce;
+            return true;
+        }
+        let behind = self.highest - nonce;
+        if behind >= WINDOW {
+            return false;
+        }
+        let bit = 1u128 << behind;
+        let fresh = self.seen & bit == 0;
+        self.seen |= bit;
+        fresh
+    }
+}
+
+pub(crate) struct ReplayGuard {
+    window: Duration,
+    senders: HashMap<[u8; PUBLIC_KEY_LENGTH], NonceWindow>,
+}
+
+impl ReplayGuard {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            senders: HashMap::new(),
+        }
+    }
+
+    /// Accept a frame signed by `key`, or say why it looks replayed.
+    pub(crate) fn check(
+        &mut self,
+        key: &VerifyingKey,
+        timestamp: u64,
+        nonce: u64,
+    ) -> Result<(), Replay> {
+        if timestamp.abs_diff(unix_secs()) > self.window.as_secs() {
+            return Err(Replay::Stale);
+        }
+        if self.senders.len() >= MAX_TRACKED {
+            // A key idle for two windows can be forgotten: a frame of it
+            // replayed now is stale even if the sender's clock ran ahead.
+            let idle = self.window * 2;
+            self.senders
+                .retain(|_, window| window.last_seen.elapsed() <= idle);
+        }
+        let window = self.senders.entry(*key.as_bytes()).or_insert(NonceWindow {
+            highest: nonce,
+            seen: 0,
+            last_seen: Instant::now(),
+        });
+        window.last_seen = Instant::now();
+        if window.accept(nonce) {
+            Ok(())
+        } else {
+            Err(Replay::Repeated)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Identity;
+
+    fn window(first: u64) -> NonceWindow {
+        NonceWindow {
+            highest: first,
+            seen: 0,
+            last_seen: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn refuses_exact_repeats() {
+        let mut window = window(1000);
+        assert!(window.accept(1000));
+        assert!(!window.accept(1000));
+        assert!(window.accept(1001));
+        assert!(!window.accept(1001));
+        assert!(!window.accept(1000));
+    }
+
+    #[test]
+    fn accepts_reordering_inside_the_window() {
+        let mut window = window(1000);
+        assert!(window.accept(1000));
+        assert!(window.accept(1010));
+        for nonce in (1001..1010).rev() {
+            assert!(window.accept(nonce), "{nonce}");
+        }
+        for nonce in 1000..=1010 {
+            assert!(!window.accept(nonce), "{nonce} again");
+        }
+        // The oldest nonce the window still holds.
+        assert!(window.accept(1010 - (WINDOW - 1)));
+        assert!(!window.accept(1010 - (WINDOW - 1)));
+    }
+
+    #[test]
+    fn refuses_nonces_older_than_the_window() {
+        let mut window = window(1000);
+        assert!(window.accept(1000));
+        assert!(!window.accept(1000 - WINDOW));
+        assert!(!window.accept(0));
+        assert!(window.accept(1000 + WINDOW));
+        // 1000 is now just out of the window; 1001 is the oldest in it.
+        assert!(!window.accept(1000));
+        assert!(!window.accept(999));
+        assert!(window.accept(1001));
+    }
+
+    #[test]
+    fn forgets_everything_after_a_jump() {
+        for jump in [WINDOW, WINDOW + 1, 1 << 40] {
+            let mut window = window(1000);
+            for nonce in 1000..1000 + WINDOW {
+                assert!(window.accept(nonce));
+            }
+            let far = 1000 + WINDOW - 1 + jump;
+            assert!(window.accept(far), "{jump}");
+            assert_eq!(window.seen, 1);
+            assert!(!window.accept(far));
+            for behind in 1..WINDOW {
+                assert!(window.accept(far - behind), "{jump}, {behind} behind");
+            }
+            assert!(!window.accept(far - WINDOW));
+        }
+        // A jump just inside the window keeps what was seen.
+        let mut window = window(1000);
+        assert!(window.accept(1000));
+        assert!(window.accept(1000 + WINDOW - 1));
+        assert!(!window.accept(1000));
+    }
+
+    #[test]
+    fn guard_checks_nonces_per_key() {
+        let mut guard = ReplayGuard::new(Duration::from_secs(60));
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+        let now = unix_secs();
+        assert_eq!(guard.check(&alice.verifying_key(), now, 5), Ok(()));
+        assert_eq!(
+            guard.check(&alice.verifying_key(), now, 5),
+            Err(Replay::Repeated)
+        );
+        // Another sender may use the same nonce.
+        assert_eq!(guard.check(&bob.verifying_key(), now, 5), Ok(()));
+        assert_eq!(guard.check(&alice.verifying_key(), now, 4), Ok(()));
+        assert_eq!(guard.check(&alice.verifying_key(), now, 6), Ok(()));
+    }
+
+    #[test]
+    fn guard_refuses_stale_timestamps() {
+        let mut guard = ReplayGuard::new(Duration::from_secs(60));
+        let key = Identity::generate().verifying_key();
+        let now = unix_secs();
+        assert_eq!(guard.check(&key, now - 3600, 1), Err(Replay::Stale));
+        assert_eq!(guard.check(&key, now + 3600, 2), Err(Replay::Stale));
+        assert_eq!(guard.check(&key, 0, 3), Err(Replay::Stale));
+        assert_eq!(guard.check(&key, u64::MAX, 4), Err(Replay::Stale));
+        // Stale frames leave no trace.
+        assert!(guard.senders.is_empty());
+        assert_eq!(guard.check(&key, now - 30, 1), Ok(()));
+        assert_eq!(guard.check(&key, now + 30, 2), Ok(()));
+        // A replayed frame is refused for its time first, once it is old.
+        assert_eq!(guard.check(&key, now - 62, 1), Err(Replay::Stale));
+        assert_eq!(guard.check(&key, now, 1), Err(Replay::Repeated));
+    }
+}
@@ -0,0 +1,131 @@
+//! Short authentication strings of encrypted sessions.
+//!
+//! Both ends of a Noise session end up with the same handshake hash, which
+//! commits to every key exchanged. Someone relaying a handshake in the
+//! middle runs one with each side and cannot make the two hashes equal, so
+//! people who read a few words or emoji derived from it to each other, over
+//! the phone or in person, notice. Like Signal's safety numbers, this is a
+//! check on the IDs too: a peer that is not who the user thinks shows
+//! different words.
+
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::pair::WORDS;
+
+/// Words shown, 8 bits each.
+const SAS_WORDS: usize = 5;
+/// Emoji shown, 6 bits each.
+const SAS_EMOJI: usize = 7;
+
+/// Emoji with their names, for terminals that cannot draw them. The same
+/// set as Matrix's SAS verification, chosen to be easy to tell apart.
+const EMOJI: [(&str, &str); 64] = [
+    ("🐶", "dog"),
+    ("🐱", "cat"),
+    ("🦁", "lion"),
+    ("🐎", "horse"),
+    ("🦄", "unicorn"),
+    ("🐷", "pig"),
+    ("🐘", "elephant"),
+    ("🐰", "rabbit"),
+    ("🐼", "panda"),
+    ("🐓", "rooster"),
+    ("🐧", "penguin"),
+    ("🐢", "turtle"),
+    ("🐟", "fish"),
+    ("🐙", "octopus"),
+    ("🦋", "butterfly"),
+    ("🌷", "flower"),
+    ("🌳", "tree"),
+    ("🌵", "cactus"),
+    ("🍄", "mushroom"),
+    ("🌏", "globe"),
+    ("🌙", "moon"),
+    ("☁️", "cloud"),
+    ("🔥", "fire"),
+    ("🍌", "banana"),
+    ("🍎", "apple"),
+    ("🍓", "strawberry"),
+    ("🌽", "corn"),
+    ("🍕", "pizza"),
+    ("🎂", "cake"),
+    ("❤️", "heart"),
+    ("😀", "smiley"),
+    ("🤖", "robot"),
+    ("🎩", "hat"),
+    ("👓", "glasses"),
+    ("🔧", "spanner"),
+    ("🎅", "santa"),
+    ("👍", "thumbs up"),
+    ("☂️", "umbrella"),
+    ("⌛", "hourglass"),
+    ("⏰", "clock"),
+    ("🎁", "gift"),
+    ("💡", "light bulb"),
+    ("📕", "book"),
+    ("✏️", "pencil"),
+    ("📎", "paperclip"),
+    ("✂️", "scissors"),
+    ("🔒", "lock"),
+    ("🔑", "key"),
+    ("🔨", "hammer"),
+    ("☎️", "telephone"),
+    ("🏁", "flag"),
+    ("🚂", "train"),
+    ("🚲", "bicycle"),
+    ("✈️", "aeroplane"),
+    ("🚀", "rocket"),
+    ("🏆", "trophy"),
+    ("⚽", "ball"),
+    ("🎸", "guitar"),
+    ("🎺", "trumpet"),
+    ("🔔", "bell"),
+    ("⚓", "anchor"),
+    ("🎧", "headphones"),
+    ("📁", "folder"),
+    ("📌", "pin"),
+];
+
+/// Short authentication string of a session, from
+/// [`DhtMsg::peer_sas`](crate::DhtMsg::peer_sas). Compare either the words
+/// or the emoji; both come from the same bits. Displays as the words.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sas {
+    digest: [u8; 32],
+}
+
+impl Sas {
+    pub(crate) fn new(handshake_hash: &[u8; 32]) -> Self {
+        let digest = Sha256::new()
+            .chain_update(b"dhtmsg sas")
+            .chain_update(handshake_hash)
+            .finalize();
+        Self {
+            digest: digest.into(),
+        }
+    }
+
+    /// Five words, 40 bits.
+    pub fn words(&self) -> Vec<&'static str> {
+        self.digest[..SAS_WORDS]
+            .iter()
+            .map(|&byte| WORDS[usize::from(byte)])
+            .collect()
+    }
+
+    /// Seven emoji with their names, 42 bits.
+    pub fn emoji(&self) -> Vec<(&'static str, &'static str)> {
+        let bits = u64::from_be_bytes(self.digest[..8].try_into().unwrap());
+        (0..SAS_EMOJI)
+            .map(|i| EMOJI[(bits >> (58 - 6 * i)) as usize & 63])
+            .collect()
+    }
+}
+
+impl fmt::Display for Sas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.words().join(" "))
+    }
+}
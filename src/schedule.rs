@@ -0,0 +1,59 @@
+//! When announcer threads announce next.
+//!
+//! Announcing every node at the same fixed interval lines up their DHT
+//! traffic, and hammering unreachable DHT nodes helps nobody. Delays are
+//! therefore spread by ±10 %, shortened for the first rounds so that a new
+//! node becomes discoverable quickly, and doubled after each failed round
+//! up to a cap.
+
+use std::time::Duration;
+
+use rand::{Rng, thread_rng};
+
+/// Rounds after startup that come sooner than the interval.
+const STARTUP_ROUNDS: u32 = 3;
+/// Fraction of the interval waited for during those.
+const STARTUP_DIVISOR: u32 = 4;
+/// Longest wait after failed rounds.
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+/// Most that delays are moved either way, in percent.
+const JITTER_PERCENT: u64 = 10;
+
+pub(crate) struct AnnounceSchedule {
+    interval: Duration,
+    rounds: u32,
+    failures: u32,
+}
+
+impl AnnounceSchedule {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            rounds: 0,
+            failures: 0,
+        }
+    }
+
+    /// How long to wait after a round that succeeded or not.
+    pub(crate) fn next(&mut self, succeeded: bool) -> Duration {
+        self.rounds += 1;
+        let delay = if !succeeded {
+            self.failures += 1;
+            let backoff = self.interval.saturating_mul(1 << self.failures.min(16));
+            backoff.min(MAX_BACKOFF.max(self.interval))
+        } else {
+            self.failures = 0;
+            if self.rounds <= STARTUP_ROUNDS {
+                self.interval / STARTUP_DIVISOR
+            } else {
+                self.interval
+            }
+        };
+        jitter(delay)
+    }
+}
+
+fn jitter(delay: Duration) -> Duration {
+    let percent = thread_rng().gen_range(100 - JITTER_PERCENT..=100 + JITTER_PERCENT);
+    delay.mul_f64(percent as f64 / 100.0)
+}
@@ -0,0 +1,100 @@
+//! Typed model of the messages peers exchange, serialized as CBOR.
+//!
+//! Another implementation can speak to dhtmsg nodes by sending
+//! [`Message::to_cbor`] as the body of a wire frame of type 7, signed with the
+//! secret key of `id` (see the README for the frame layout). Unless the node
+//! allows plaintext, the frame must travel inside an encrypted session. Maps
+//! use text keys and the kind of message is under `"type"`:
+//!
+//! ```text
+//! {"type": "hello", "id": "<hex public key>", "seq": 7, "caps": 9, "vmin": 7, "v": 7, "ch": h'...'}
+//! {"type": "data", "id": "<hex public key>", "seq": 8, "payload": h'...'}
+//! ```
+//!
+//! Nodes answer a `hello` or `ping` with an `ack`, and a `data` message with
+//! the usual binary message ack. The ack to a hello echoes its challenge
+//! `ch` under `re` and brings one of its own, which the hello's sender
+//! echoes in a `proof`; only then does the node accept `data` from it.
+
+use serde::{Deserialize, Serialize};
+
+pub use crate::cbor::Error as CborError;
+use crate::{Capabilities, wire::legacy_version};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Message {
+    /// First contact; `seq` lets the receiver drop duplicates.
+    Hello {
+        id: String,
+        seq: u32,
+        /// Bitmap of optional features the sender supports.
+        #[serde(default)]
+        caps: Capabilities,
+        /// TLV extension records, `[kind u16][length u16][value]` each.
+        #[serde(default, with = "serde_bytes")]
+        ext: Vec<u8>,
+        /// Oldest and newest protocol versions the sender speaks (2 if absent).
+        #[serde(default = "legacy_version")]
+        vmin: u8,
+        #[serde(default = "legacy_version")]
+        v: u8,
+        /// Random bytes the ack must echo.
+        #[serde(default, with = "serde_bytes")]
+        ch: Vec<u8>,
+    },
+    /// Answer to a hello or ping.
+    Ack {
+        id: String,
+        #[serde(default)]
+        caps: Capabilities,
+        #[serde(default, with = "serde_bytes")]
+        ext: Vec<u8>,
+        /// Version chosen for the session; a node refusing the hello sends
+        /// its newest one instead.
+        #[serde(default = "legacy_version")]
+        v: u8,
+        /// The hello's challenge, echoed.
+        #[serde(default, with = "serde_bytes")]
+        re: Vec<u8>,
+        /// Random bytes the proof must echo.
+        #[serde(default, with = "serde_bytes")]
+        ch: Vec<u8>,
+    },
+    /// Echo of an ack's challenge, proving the sender holds the key of `id`.
+    Proof {
+        id: String,
+        #[serde(with = "serde_bytes")]
+        re: Vec<u8>,
+    },
+    /// An application payload, delivered as [`Event::MessageReceived`](crate::Event::MessageReceived).
+    Data {
+        id: String,
+        seq: u32,
+        #[serde(with = "serde_bytes")]
+        payload: Vec<u8>,
+    },
+    /// Asks for an ack, keeping the path to the peer open.
+    Ping { id: String, seq: u32 },
+}
+
+impl Message {
+    pub fn to_cbor(&self) -> Vec<u8> {
+        crate::cbor::to_vec(self).expect("messages always encode")
+    }
+
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, CborError> {
+        crate::cbor::from_slice(bytes)
+    }
+
+    /// ID of the sender, whose key must have signed the frame.
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Hello { id, .. }
+            | Self::Ack { id, .. }
+            | Self::Proof { id, .. }
+            | Self::Data { id, .. }
+            | Self::Ping { id, .. } => id,
+        }
+    }
+}
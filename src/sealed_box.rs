@@ -0,0 +1,136 @@
+//! Sealed boxes: a payload encrypted to a peer's ID, for nodes that do not
+//! run Noise sessions.
+//!
+//! Each box is `[ephemeral X25519 key][ciphertext][tag]`. The sender makes a
+//! fresh keypair per box and agrees on a key with the Montgomery form of the
+//! recipient's Ed25519 key; only the holder of its secret key can open the
+//! box. Boxes are not bound to the sender (the frame signature is), and a
+//! key is used once, so the ChaCha20-Poly1305 nonce is zero.
+
+use curve25519_dalek::MontgomeryPoint;
+use ed25519_dalek::VerifyingKey;
+use rand::{RngCore, thread_rng};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    Identity,
+    aead::{self, KEY_BYTES, NONCE_BYTES, TAG_BYTES},
+};
+
+const CONTEXT: &[u8] = b"dhtmsg sealed box";
+const PUBLIC_KEY_BYTES: usize = 32;
+/// What sealing adds to a payload.
+pub(crate) const OVERHEAD: usize = PUBLIC_KEY_BYTES + TAG_BYTES;
+
+fn box_key(
+    shared: &MontgomeryPoint,
+    ephemeral: &MontgomeryPoint,
+    recipient: &MontgomeryPoint,
+) -> [u8; KEY_BYTES] {
+    Sha256::new()
+        .chain_update(CONTEXT)
+        .chain_update(shared.as_bytes())
+        .chain_update(ephemeral.as_bytes())
+        .chain_update(recipient.as_bytes())
+        .finalize()
+        .into()
+}
+
+/// Encrypt `payload` so that only the owner of `recipient` can read it.
+pub(crate) fn seal(recipient: &VerifyingKey, payload: &[u8]) -> Vec<u8> {
+    let mut secret = [0u8; 32];
+    thread_rng().fill_bytes(&mut secret);
+    let ephemeral = MontgomeryPoint::mul_base_clamped(secret);
+    let recipient = recipient.to_montgomery();
+    let key = box_key(&recipient.mul_clamped(secret), &ephemeral, &recipient);
+    let mut sealed = ephemeral.to_bytes().to_vec();
+    sealed.extend(aead::seal(&key, &[0; NONCE_BYTES], &[], payload));
+    sealed
+}
+
+/// Payload of a box sealed to `identity`, or `None` if it was sealed to
+/// someone else or tampered with.
+pub(crate) fn open(identity: &Identity, sealed: &[u8]) -> Option<Vec<u8>> {
+    let (ephemeral, ciphertext) = sealed.split_first_chunk::<PUBLIC_KEY_BYTES>()?;
+    let ephemeral = MontgomeryPoint(*ephemeral);
+    let shared = identity.x25519_agree(&ephemeral);
+    // A low-order ephemeral key would make the shared secret predictable.
+    if shared.as_bytes() == &[0; 32] {
+        return None;
+    }
+    let recipient = identity.verifying_key().to_montgomery();
+    let key = box_key(&shared, &ephemeral, &recipient);
+    aead::open(&key, &[0; NONCE_BYTES], &[], ciphertext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Points of small order on Curve25519, some of them encoded with `u`
+    /// past the field prime.
+    const LOW_ORDER: [&str; 7] = [
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        "0100000000000000000000000000000000000000000000000000000000000000",
+        "e0eb7a7c3b41b8ae1656e3faf19fc46ada098deb9c32b1fd866205165f49b800",
+        "5f9c95bca3508c24b1d0b1559c83ef5b04445cc4581c8e86d8224eddd09f1157",
+        "ecffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f",
+        "edffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f",
+        "eeffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f",
+    ];
+
+    #[test]
+    fn round_trip() {
+        let identity = Identity::generate();
+        for payload in [&b""[..], b"hello", &[0x5a; 4096]] {
+            let sealed = seal(&identity.verifying_key(), payload);
+            assert_eq!(sealed.len(), payload.len() + OVERHEAD);
+            assert_eq!(open(&identity, &sealed).unwrap(), payload);
+        }
+        // A fresh ephemeral key every time.
+        let key = identity.verifying_key();
+        assert_ne!(seal(&key, b"hello"), seal(&key, b"hello"));
+    }
+
+    #[test]
+    fn only_the_recipient_opens() {
+        let recipient = Identity::generate();
+        let sealed = seal(&recipient.verifying_key(), b"for your eyes only");
+        assert_eq!(open(&Identity::generate(), &sealed), None);
+    }
+
+    #[test]
+    fn refuses_tampering() {
+        let identity = Identity::generate();
+        let sealed = seal(&identity.verifying_key(), b"attack at dawn");
+        for at in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[at] ^= 1;
+            assert_eq!(open(&identity, &tampered), None, "byte {at}");
+        }
+        for len in 0..sealed.len() {
+            assert_eq!(open(&identity, &sealed[..len]), None, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn refuses_low_order_ephemeral_keys() {
+        let identity = Identity::generate();
+        for point in LOW_ORDER {
+            let point: [u8; 32] = hex::decode(point).unwrap().try_into().unwrap();
+            assert_eq!(
+                identity.x25519_agree(&MontgomeryPoint(point)).to_bytes(),
+                [0; 32]
+            );
+            // Whatever key the sender could have predicted.
+            let key = box_key(
+                &MontgomeryPoint([0; 32]),
+                &MontgomeryPoint(point),
+                &identity.verifying_key().to_montgomery(),
+            );
+            let mut sealed = point.to_vec();
+            sealed.extend(aead::seal(&key, &[0; NONCE_BYTES], &[], b"forged"));
+            assert_eq!(open(&identity, &sealed), None, "{}", hex::encode(point));
+        }
+    }
+}
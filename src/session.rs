@@ -0,0 +1,662 @@
+//! Encrypted sessions between peers, layered under everything the node sends.
+//!
+//! [`SecureTransport`] wraps the node's transport. The first datagram for an
+//! address starts a Noise XX handshake (see the `noise` module) in
+//! [`FrameType::Handshake`] frames and waits in a queue until it completes;
+//! from then on every datagram travels as a [`FrameType::Sealed`] frame that
+//! only that peer can open. Handshake messages lost on the way are resent
+//! while the receive thread polls.
+//!
+//! Plaintext frames are dropped unless the node opted into legacy plaintext,
+//! in which case it also sends them to peers that do not start a handshake
+//! themselves.
+//!
+//! Sessions play the part a QUIC connection would, over whichever path the
+//! layers below pick, which is why there is no QUIC mode. Like QUIC ones,
+//! and mosh's, they survive the peer moving, say from Wi-Fi to LTE: a sealed
+//! frame that opens under a session but comes from another address makes us
+//! send that address a [`FrameType::Roam`] probe, a challenge sealed for the
+//! session. Once the peer echoes it from there, the session's datagrams go
+//! to the new address, while the node above still knows the session by the
+//! address it started at. A captured frame replayed from elsewhere thus
+//! reroutes nothing.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use ed25519_dalek::VerifyingKey;
+use log::{debug, info, warn};
+use rand::{RngCore, thread_rng};
+
+use crate::{
+    Identity,
+    noise::{Handshake, KeyPair, Rekey, Session},
+    transport::{SharedTransport, Transport},
+    wire::{self, Frame, FrameType},
+};
+
+/// Wait for the answer to a handshake message before resending it.
+const HANDSHAKE_RETRY: Duration = Duration::from_secs(1);
+/// Sends of a handshake message before the handshake is given up.
+const HANDSHAKE_ATTEMPTS: u32 = 5;
+/// Datagrams held per peer while its handshake runs; the oldest go first.
+const MAX_QUEUED: usize = 64;
+/// Bytes of the challenge in a roaming probe.
+const PROBE_BYTES: usize = 16;
+/// Wait before probing the same address again.
+const PROBE_RETRY: Duration = HANDSHAKE_RETRY;
+/// Age at which an unanswered probe is forgotten.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A handshake on its way, and what waits for it.
+struct Pending {
+    handshake: Handshake,
+    /// Our latest handshake frame, resent until answered.
+    sent: Vec<u8>,
+    /// The first message we answered, if we are the responder.
+    answering: Option<Vec<u8>>,
+    sent_at: Instant,
+    attempts: u32,
+    queue: VecDeque<Vec<u8>>,
+    /// Sealed frames that overtook the last handshake message.
+    early: Vec<Vec<u8>>,
+}
+
+struct Established {
+    session: Session,
+    key: VerifyingKey,
+    /// The initiator's last handshake frame, sent again if the responder
+    /// repeats its answer because this one got lost.
+    last_sent: Option<Vec<u8>>,
+}
+
+/// A roaming probe awaiting its echo, keyed by the address it went to.
+struct Probe {
+    /// The address the session is known by.
+    session: SocketAddr,
+    challenge: [u8; PROBE_BYTES],
+    sent_at: Instant,
+}
+
+/// Where the sessions whose peers moved send now.
+#[derive(Default)]
+struct Routes {
+    /// Current address, by the address the session is known by.
+    to: HashMap<SocketAddr, SocketAddr>,
+    /// Address the session is known by, by its current address.
+    from: HashMap<SocketAddr, SocketAddr>,
+}
+
+#[derive(Default)]
+struct Peer {
+    established: Option<Established>,
+    /// A newer handshake; an established session stays in use until it
+    /// completes, so an unauthenticated first message cannot break it.
+    pending: Option<Pending>,
+}
+
+/// What became of an inbound datagram.
+enum Inbound {
+    /// Not ours to handle, returned to the node as received.
+    Pass,
+    /// A sealed frame's contents, and the address its session is known by.
+    Opened(Vec<u8>, SocketAddr),
+    /// Handled or dropped here.
+    Consumed,
+}
+
+pub(crate) struct SecureTransport {
+    inner: SharedTransport,
+    identity: Arc<Identity>,
+    static_key: KeyPair,
+    plaintext: bool,
+    rekey: Rekey,
+    peers: Mutex<HashMap<SocketAddr, Peer>>,
+    /// The node's counter of frames dropped as foreign or forged.
+    ignored: Arc<AtomicU64>,
+    /// Plaintext frames dropped because encryption is required.
+    plaintext_dropped: AtomicU64,
+    /// Opened early frames, handed out before anything new is received.
+    opened: Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+    routes: Mutex<Routes>,
+    probes: Mutex<HashMap<SocketAddr, Probe>>,
+}
+
+impl SecureTransport {
+    pub(crate) fn new(
+        inner: SharedTransport,
+        identity: Arc<Identity>,
+        plaintext: bool,
+        rekey: Rekey,
+        ignored: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            inner,
+            identity,
+            static_key: KeyPair::generate(),
+            plaintext,
+            rekey,
+            peers: Mutex::default(),
+            ignored,
+            plaintext_dropped: AtomicU64::new(0),
+            opened: Mutex::default(),
+            routes: Mutex::default(),
+            probes: Mutex::default(),
+        }
+    }
+
+    /// Whether traffic with `peer` is encrypted.
+    pub(crate) fn is_encrypted(&self, peer: &SocketAddr) -> bool {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(peer)
+            .is_some_and(|state| state.established.is_some())
+    }
+
+    /// Handshake hash of the session with `peer`, if there is one.
+    pub(crate) fn handshake_hash(&self, peer: &SocketAddr) -> Option<[u8; 32]> {
+        let peers = self.peers.lock().unwrap();
+        let established = peers.get(peer)?.established.as_ref()?;
+        Some(*established.session.handshake_hash())
+    }
+
+    /// Drop the session with `peer`; the next datagram starts a new one.
+    pub(crate) fn forget(&self, peer: &SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.remove(peer);
+        self.reroute(&mut peers, *peer, *peer);
+    }
+
+    /// Where the session known by `peer` sends now.
+    fn route(&self, peer: SocketAddr) -> SocketAddr {
+        let routes = self.routes.lock().unwrap();
+        routes.to.get(&peer).copied().unwrap_or(peer)
+    }
+
+    /// The address the session of datagrams from `from` is known by.
+    fn known_as(&self, from: SocketAddr) -> SocketAddr {
+        let routes = self.routes.lock().unwrap();
+        routes.from.get(&from).copied().unwrap_or(from)
+    }
+
+    /// Send the datagrams of the session known by `session` to `to` from
+    /// now on, or back to `session` itself.
+    fn reroute(&self, peers: &mut HashMap<SocketAddr, Peer>, session: SocketAddr, to: SocketAddr) {
+        let mut routes = self.routes.lock().unwrap();
+        if let Some(old) = routes.to.remove(&session) {
+            routes.from.remove(&old);
+        }
+        if to == session {
+            return;
+        }
+        // Whatever else was at the new address now belongs to the session.
+        peers.remove(&to);
+        if let Some(other) = routes.from.insert(to, session) {
+            routes.to.remove(&other);
+        }
+        routes.to.insert(session, to);
+    }
+
+    /// Send our pending handshake message to `addr` again right away, not
+    /// counting it as an attempt: while punching holes, datagrams must
+    /// leave when the peer's arrive, not a second later.
+    pub(crate) fn resend_handshake(&self, addr: &SocketAddr) {
+        let peers = self.peers.lock().unwrap();
+        if let Some(pending) = peers.get(addr).and_then(|state| state.pending.as_ref()) {
+            self.send_raw(&pending.sent, *addr);
+        }
+    }
+
+    pub(crate) fn plaintext_dropped(&self) -> u64 {
+        self.plaintext_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Count a frame dropped for travelling unencrypted.
+    pub(crate) fn drop_plaintext(&self, kind: FrameType, peer: SocketAddr) {
+        debug!("plaintext {kind:?} frame from {peer} (dropped)");
+        let dropped = self.plaintext_dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped.is_power_of_two() {
+            info!("dropped {dropped} plaintext frames so far; plaintext peers are not allowed");
+        }
+    }
+
+    fn ignore(&self, what: &str, peer: SocketAddr) {
+        debug!("{what} from {peer} (ignored)");
+        self.ignored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handshake_frame(&self, step: u8, message: &[u8]) -> Vec<u8> {
+        let mut body = vec![step];
+        body.extend_from_slice(message);
+        wire::encode(FrameType::Handshake, &body, &self.identity)
+    }
+
+    fn send_raw(&self, datagram: &[u8], addr: SocketAddr) {
+        if let Err(err) = self.inner.send_to(datagram, self.route(addr)) {
+            warn!("failed to send handshake to {addr}: {err}");
+        }
+    }
+
+    /// Send our first handshake message to `addr`, queueing `queue` for the
+    /// session.
+    fn initiate(&self, state: &mut Peer, addr: SocketAddr, queue: VecDeque<Vec<u8>>) {
+        debug!("starting a handshake with {addr}");
+        let (handshake, first) = Handshake::initiate();
+        let sent = self.handshake_frame(1, &first);
+        self.send_raw(&sent, addr);
+        state.pending = Some(Pending {
+            handshake,
+            sent,
+            answering: None,
+            sent_at: Instant::now(),
+            attempts: 1,
+            queue,
+            early: Vec::new(),
+        });
+    }
+
+    fn seal(&self, established: &mut Established, datagram: &[u8]) -> Vec<u8> {
+        let epoch = established.session.send_epoch();
+        let sealed = established.session.seal(datagram, &self.rekey);
+        if established.session.send_epoch() != epoch {
+            debug!(
+                "session with {} rekeyed to epoch {}",
+                hex::encode(established.key.as_bytes()),
+                established.session.send_epoch()
+            );
+        }
+        wire::encode(FrameType::Sealed, &sealed, &self.identity)
+    }
+
+    fn roam_frame(&self, established: &mut Established, step: u8, challenge: &[u8]) -> Vec<u8> {
+        let mut body = vec![step];
+        body.extend(established.session.seal(challenge, &self.rekey));
+        wire::encode(FrameType::Roam, &body, &self.identity)
+    }
+
+    /// Ask `to`, which sent a frame of the session known by `session`,
+    /// to echo a challenge sealed for that session.
+    fn probe(&self, established: &mut Established, session: SocketAddr, to: SocketAddr) {
+        let mut probes = self.probes.lock().unwrap();
+        if probes
+            .get(&to)
+            .is_some_and(|probe| probe.sent_at.elapsed() < PROBE_RETRY)
+        {
+            return;
+        }
+        probes.retain(|_, probe| probe.sent_at.elapsed() < PROBE_TIMEOUT);
+        let mut challenge = [0u8; PROBE_BYTES];
+        thread_rng().fill_bytes(&mut challenge);
+        debug!("{session} writes from {to}; checking whether it moved there");
+        let frame = self.roam_frame(established, 1, &challenge);
+        if let Err(err) = self.inner.send_to(&frame, to) {
+            warn!("failed to send a roaming probe to {to}: {err}");
+        }
+        probes.insert(
+            to,
+            Probe {
+                session,
+                challenge,
+                sent_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Switch `peer` to the session a finished handshake produced and send
+    /// what waited for it.
+    fn establish(&self, state: &mut Peer, peer: SocketAddr, mut established: Established) {
+        let (queue, early) = state
+            .pending
+            .take()
+            .map(|pending| (pending.queue, pending.early))
+            .unwrap_or_default();
+        info!(
+            "encrypted session with {peer} ({})",
+            hex::encode(established.key.as_bytes())
+        );
+        let mut opened = self.opened.lock().unwrap();
+        for datagram in early {
+            if let Ok(frame) = wire::decode(&datagram)
+                && frame.verify(&established.key)
+                && let Some(inner) = established.session.open(frame.body)
+            {
+                opened.push_back((inner, peer));
+            }
+        }
+        for datagram in queue {
+            let frame = self.seal(&mut established, &datagram);
+            if let Err(err) = self.inner.send_to(&frame, self.route(peer)) {
+                warn!("failed to send queued datagram to {peer}: {err}");
+            }
+        }
+        state.established = Some(established);
+    }
+
+    /// Handle a datagram from `from`, whose session is known by `peer`.
+    fn receive(&self, datagram: &[u8], peer: SocketAddr, from: SocketAddr) -> Inbound {
+        // The node counts what is not a frame of ours.
+        let Ok(frame) = wire::decode(datagram) else {
+            return Inbound::Pass;
+        };
+        match frame.kind {
+            FrameType::Handshake => {
+                self.answer_handshake(&frame, datagram, peer);
+                Inbound::Consumed
+            }
+            FrameType::Sealed => self.open(&frame, datagram, peer, from),
+            FrameType::Roam => {
+                self.answer_roam(&frame, peer, from);
+                Inbound::Consumed
+            }
+            _ if self.plaintext => Inbound::Pass,
+            _ => {
+                self.drop_plaintext(frame.kind, peer);
+                Inbound::Consumed
+            }
+        }
+    }
+
+    fn open(&self, frame: &Frame, datagram: &[u8], peer: SocketAddr, from: SocketAddr) -> Inbound {
+        let mut peers = self.peers.lock().unwrap();
+        if peer == from
+            && peers
+                .get(&peer)
+                .is_none_or(|state| state.established.is_none() && state.pending.is_none())
+            && let Some((session, inner)) = self.open_moved(&mut peers, frame, from)
+        {
+            return Inbound::Opened(inner, session);
+        }
+        let state = peers.entry(peer).or_default();
+        let opened = state
+            .established
+            .as_mut()
+            .filter(|established| frame.verify(&established.key))
+            .and_then(|established| {
+                let inner = established.session.open(frame.body)?;
+                // Back where it started, or at yet another address.
+                if from != self.route(peer) {
+                    self.probe(established, peer, from);
+                }
+                Some(inner)
+            });
+        if let Some(inner) = opened {
+            return Inbound::Opened(inner, peer);
+        }
+        if let Some(pending) = &mut state.pending
+            && !pending.handshake.is_initiator()
+            && pending.early.len() < MAX_QUEUED
+        {
+            pending.early.push(datagram.to_vec());
+            return Inbound::Consumed;
+        }
+        // The peer holds a session we lost, probably by restarting.
+        if state.established.is_none() && state.pending.is_none() {
+            self.initiate(state, peer, VecDeque::new());
+        }
+        self.ignore("undecryptable sealed frame", peer);
+        Inbound::Consumed
+    }
+
+    /// Open a sealed frame from `from`, which has no session, with the
+    /// session of a peer that may have moved there, and probe `from` if it
+    /// opens. Returns the address the session is known by and the contents.
+    fn open_moved(
+        &self,
+        peers: &mut HashMap<SocketAddr, Peer>,
+        frame: &Frame,
+        from: SocketAddr,
+    ) -> Option<(SocketAddr, Vec<u8>)> {
+        let (session, established, inner) = peers.iter_mut().find_map(|(addr, state)| {
+            let established = state
+                .established
+                .as_mut()
+                .filter(|established| frame.verify(&established.key))?;
+            let inner = established.session.open(frame.body)?;
+            Some((*addr, established, inner))
+        })?;
+        self.probe(established, session, from);
+        Some((session, inner))
+    }
+
+    /// Echo a roaming probe, or move a session whose probe was echoed.
+    fn answer_roam(&self, frame: &Frame, peer: SocketAddr, from: SocketAddr) {
+        let Some((&step, sealed)) = frame.body.split_first() else {
+            return self.ignore("empty roaming frame", from);
+        };
+        let mut peers = self.peers.lock().unwrap();
+        match step {
+            1 => {
+                let Some(established) = peers
+                    .get_mut(&peer)
+                    .and_then(|state| state.established.as_mut())
+                    .filter(|established| frame.verify(&established.key))
+                else {
+                    return self.ignore("roaming probe outside a session", from);
+                };
+                let Some(challenge) = established.session.open(sealed) else {
+                    return self.ignore("undecryptable roaming probe", from);
+                };
+                let answer = self.roam_frame(established, 2, &challenge);
+                if let Err(err) = self.inner.send_to(&answer, self.route(peer)) {
+                    warn!("failed to answer a roaming probe from {from}: {err}");
+                }
+            }
+            2 => {
+                let mut probes = self.probes.lock().unwrap();
+                let Some(probe) = probes.get(&from) else {
+                    return self.ignore("unexpected roaming answer", from);
+                };
+                let session = probe.session;
+                let Some(established) = peers
+                    .get_mut(&session)
+                    .and_then(|state| state.established.as_mut())
+                    .filter(|established| frame.verify(&established.key))
+                else {
+                    return self.ignore("roaming answer with a bad signature", from);
+                };
+                if established.session.open(sealed).as_deref() != Some(&probe.challenge[..]) {
+                    return self.ignore("wrong roaming answer", from);
+                }
+                probes.remove(&from);
+                info!(
+                    "session with {session} ({}) moved to {from}",
+                    hex::encode(established.key.as_bytes())
+                );
+                self.reroute(&mut peers, session, from);
+            }
+            _ => self.ignore("unknown roaming step", from),
+        }
+    }
+
+    fn answer_handshake(&self, frame: &Frame, datagram: &[u8], peer: SocketAddr) {
+        let Some((&step, message)) = frame.body.split_first() else {
+            return self.ignore("empty handshake frame", peer);
+        };
+        let mut peers = self.peers.lock().unwrap();
+        let state = peers.entry(peer).or_default();
+        match step {
+            1 => {
+                if let Some(pending) = &mut state.pending {
+                    if pending.answering.as_deref() == Some(message) {
+                        // Our answer got lost.
+                        self.send_raw(&pending.sent, peer);
+                        return;
+                    }
+                    // Both sides started at once: the higher frame wins and
+                    // stays initiator; the other side respects the same rule.
+                    if pending.handshake.is_initiator() && pending.sent.as_slice() > datagram {
+                        return;
+                    }
+                }
+                let Some((handshake, second)) =
+                    Handshake::respond(message, &self.identity, &self.static_key)
+                else {
+                    return self.ignore("malformed handshake", peer);
+                };
+                let sent = self.handshake_frame(2, &second);
+                self.send_raw(&sent, peer);
+                let queue = state
+                    .pending
+                    .take()
+                    .map(|pending| pending.queue)
+                    .unwrap_or_default();
+                state.pending = Some(Pending {
+                    handshake,
+                    sent,
+                    answering: Some(message.to_vec()),
+                    sent_at: Instant::now(),
+                    attempts: 1,
+                    queue,
+                    early: Vec::new(),
+                });
+            }
+            2 => {
+                let finished = state.pending.as_ref().and_then(|pending| {
+                    pending
+                        .handshake
+                        .finish_initiator(message, &self.identity, &self.static_key)
+                });
+                let Some((session, key, third)) = finished else {
+                    // Our third message got lost and the responder repeats itself.
+                    if let Some(last_sent) = state
+                        .established
+                        .as_ref()
+                        .and_then(|established| established.last_sent.as_ref())
+                        .filter(|_| state.pending.is_none())
+                    {
+                        self.send_raw(last_sent, peer);
+                        return;
+                    }
+                    return self.ignore("unexpected handshake answer", peer);
+                };
+                if !frame.verify(&key) {
+                    return self.ignore("handshake answer with a bad signature", peer);
+                }
+                let sent = self.handshake_frame(3, &third);
+                self.send_raw(&sent, peer);
+                let established = Established {
+                    session,
+                    key,
+                    last_sent: Some(sent),
+                };
+                self.establish(state, peer, established);
+            }
+            3 => {
+                let finished = state
+                    .pending
+                    .as_ref()
+                    .and_then(|pending| pending.handshake.finish_responder(message))
+                    .filter(|(_, key)| frame.verify(key));
+                let Some((session, key)) = finished else {
+                    return self.ignore("unexpected handshake message", peer);
+                };
+                let established = Established {
+                    session,
+                    key,
+                    last_sent: None,
+                };
+                self.establish(state, peer, established);
+            }
+            _ => self.ignore("unknown handshake step", peer),
+        }
+    }
+
+    /// Resend handshake messages that went unanswered, giving up on
+    /// handshakes out of attempts.
+    fn retry_handshakes(&self) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|peer, state| {
+            let Some(pending) = &mut state.pending else {
+                return state.established.is_some();
+            };
+            if pending.sent_at.elapsed() < HANDSHAKE_RETRY {
+                return true;
+            }
+            if pending.attempts >= HANDSHAKE_ATTEMPTS {
+                warn!(
+                    "no handshake with {peer} after {HANDSHAKE_ATTEMPTS} attempts; \
+                     dropped {} queued datagrams",
+                    pending.queue.len()
+                );
+                state.pending = None;
+                return state.established.is_some();
+            }
+            pending.attempts += 1;
+            pending.sent_at = Instant::now();
+            if let Err(err) = self.inner.send_to(&pending.sent, self.route(*peer)) {
+                warn!("failed to resend handshake to {peer}: {err}");
+            }
+            true
+        });
+    }
+}
+
+impl Transport for SecureTransport {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let mut peers = self.peers.lock().unwrap();
+        match peers.get_mut(&addr) {
+            Some(Peer {
+                established: Some(established),
+                ..
+            }) => {
+                let frame = self.seal(established, datagram);
+                self.inner.send_to(&frame, self.route(addr))?;
+            }
+            Some(Peer {
+                pending: Some(pending),
+                ..
+            }) => {
+                if pending.queue.len() == MAX_QUEUED {
+                    pending.queue.pop_front();
+                }
+                pending.queue.push_back(datagram.to_vec());
+            }
+            _ if self.plaintext => return self.inner.send_to(datagram, self.route(addr)),
+            _ => {
+                let state = peers.entry(addr).or_default();
+                self.initiate(state, addr, VecDeque::from([datagram.to_vec()]));
+            }
+        }
+        Ok(datagram.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        if let Some((inner, peer)) = self.opened.lock().unwrap().pop_front() {
+            buf[..inner.len()].copy_from_slice(&inner);
+            return Ok((inner.len(), peer));
+        }
+        loop {
+            let (len, peer) = match self.inner.recv_from(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    // Nothing pending: a good moment to look after handshakes.
+                    self.retry_handshakes();
+                    return Err(err);
+                }
+                result => result?,
+            };
+            let from = peer;
+            let peer = self.known_as(from);
+            match self.receive(&buf[..len], peer, from) {
+                Inbound::Pass => return Ok((len, peer)),
+                Inbound::Opened(inner, session) => {
+                    buf[..inner.len()].copy_from_slice(&inner);
+                    return Ok((inner.len(), session));
+                }
+                Inbound::Consumed => {}
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+type Hook = Box<dyn Fn() + Send + Sync>;
+
+#[derive(Default)]
+struct State {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    hooks: Mutex<Vec<Hook>>,
+}
+
+/// Stops a node: its loops exit, established peers get a goodbye, and the
+/// node's threads are joined. Clones control the same node.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle(Arc<State>);
+
+impl ShutdownHandle {
+    /// Stop the node and wait for its threads to exit. Calling it again, or from
+    /// one of the node's own callbacks, is harmless.
+    pub fn shutdown(&self) {
+        {
+            let mut stopped = self.0.stopped.lock().unwrap();
+            if *stopped {
+                return;
+            }
+            *stopped = true;
+        }
+        self.0.wake.notify_all();
+        for hook in self.0.hooks.lock().unwrap().iter() {
+            hook();
+        }
+        let current = thread::current().id();
+        let threads = std::mem::take(&mut *self.0.threads.lock().unwrap());
+        for handle in threads {
+            // A callback running on a node thread cannot join itself.
+            if handle.thread().id() == current {
+                continue;
+            }
+            let name = handle.thread().name().unwrap_or("node").to_string();
+            if handle.join().is_err() {
+                warn!("{name} thread panicked");
+            }
+        }
+        debug!("node shut down");
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.0.stopped.lock().unwrap()
+    }
+
+    /// Block until [`shutdown`](Self::shutdown) is called.
+    pub fn wait(&self) {
+        let stopped = self.0.stopped.lock().unwrap();
+        let _stopped = self
+            .0
+            .wake
+            .wait_while(stopped, |stopped| !*stopped)
+            .unwrap();
+    }
+
+    /// Sleep for `duration` or until shutdown; returns false if shut down.
+    pub(crate) fn sleep(&self, duration: Duration) -> bool {
+        let stopped = self.0.stopped.lock().unwrap();
+        let (stopped, _) = self
+            .0
+            .wake
+            .wait_timeout_while(stopped, duration, |stopped| !*stopped)
+            .unwrap();
+        !*stopped
+    }
+
+    /// Spawn a node thread to be joined on shutdown. Nothing is spawned once the
+    /// node is stopped.
+    pub(crate) fn spawn(&self, name: &str, f: impl FnOnce() + Send + 'static) {
+        if self.is_shutdown() {
+            return;
+        }
+        let handle = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(f)
+            .expect("failed to spawn node thread");
+        let mut threads = self.0.threads.lock().unwrap();
+        threads.retain(|handle| !handle.is_finished());
+        threads.push(handle);
+    }
+
+    /// Run `hook` during shutdown, after loops are told to stop and before joining.
+    pub(crate) fn on_shutdown(&self, hook: impl Fn() + Send + Sync + 'static) {
+        self.0.hooks.lock().unwrap().push(Box::new(hook));
+    }
+}
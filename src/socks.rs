@@ -0,0 +1,442 @@
+//! SOCKS5 (RFC 1928), for networks that only let traffic out through a
+//! proxy.
+//!
+//! Hello traffic goes through a UDP association: a TCP connection to the
+//! proxy that asks it to relay datagrams, which then go to the address it
+//! names, each behind a header with the peer's address, and come back the
+//! same way. The association lasts as long as the connection. Proxies that
+//! relay no UDP, Tor among them, leave the node to reach peers over TCP,
+//! through a CONNECT per peer (see the `tcp` module). Logins are RFC 1929
+//! usernames and passwords.
+//!
+//! The DHT does not go through the proxy: mainline binds a UDP socket of
+//! its own.
+//!
+//! The server side here is only as much as a local proxy for tunnels needs
+//! (see the `tunnel` module): CONNECT without a login.
+
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Mutex,
+    time::Duration,
+};
+
+use log::debug;
+
+use crate::transport::Transport;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0x00;
+const LOGIN: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+const LOGIN_VERSION: u8 = 1;
+
+const CONNECT: u8 = 0x01;
+const UDP_ASSOCIATE: u8 = 0x03;
+
+const IPV4: u8 = 0x01;
+const DOMAIN: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const NOT_ALLOWED: u8 = 0x02;
+const CONNECTION_REFUSED: u8 = 0x05;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// How long the proxy may take to answer while setting up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Bytes of the header of a relayed datagram, up to the address.
+const UDP_HEADER_BYTES: usize = 3;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum SocksError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("SOCKS5 proxy {0} has no address")]
+    Resolve(String),
+    #[error("SOCKS5 proxy {0} takes none of our login methods")]
+    NoMethod(SocketAddr),
+    #[error("SOCKS5 proxy {0} refused our login")]
+    LoginRefused(SocketAddr),
+    #[error("SOCKS5 proxy {0} relays no UDP")]
+    NoUdp(SocketAddr),
+    #[error("SOCKS5 proxy {proxy} refused with {reason}")]
+    Refused {
+        proxy: SocketAddr,
+        reason: &'static str,
+    },
+    #[error("SOCKS5 proxy {0} sent a malformed answer")]
+    Malformed(SocketAddr),
+}
+
+type Result<T> = std::result::Result<T, SocksError>;
+
+/// A SOCKS5 proxy and, if it wants one, our login on it.
+#[derive(Debug, Clone)]
+pub(crate) struct Proxy {
+    /// `host:port`.
+    pub(crate) server: String,
+    pub(crate) login: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// A connection to the proxy, logged in and ready for a request.
+    fn open(&self) -> Result<(TcpStream, SocketAddr)> {
+        let server = (self.server.to_socket_addrs().ok())
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| SocksError::Resolve(self.server.clone()))?;
+        let mut stream = TcpStream::connect_timeout(&server, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        let methods: &[u8] = match self.login {
+            Some(_) => &[NO_AUTH, LOGIN],
+            None => &[NO_AUTH],
+        };
+        let mut greeting = vec![VERSION, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        stream.write_all(&greeting)?;
+        let mut chosen = [0u8; 2];
+        stream.read_exact(&mut chosen)?;
+        match chosen {
+            [VERSION, NO_AUTH] => {}
+            [VERSION, LOGIN] if let Some((username, password)) = &self.login => {
+                let mut request = vec![LOGIN_VERSION];
+                for field in [username, password] {
+                    let len = u8::try_from(field.len()).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "SOCKS5 login too long")
+                    })?;
+                    request.push(len);
+                    request.extend_from_slice(field.as_bytes());
+                }
+                stream.write_all(&request)?;
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status)?;
+                if status[1] != SUCCEEDED {
+                    return Err(SocksError::LoginRefused(server));
+                }
+            }
+            [VERSION, NO_ACCEPTABLE_METHOD] => return Err(SocksError::NoMethod(server)),
+            _ => return Err(SocksError::Malformed(server)),
+        }
+        Ok((stream, server))
+    }
+
+    /// A TCP connection to `addr` through the proxy.
+    pub(crate) fn connect(&self, addr: SocketAddr) -> Result<TcpStream> {
+        let mut target = Vec::new();
+        encode_addr(&mut target, addr);
+        self.connect_to(&target)
+    }
+
+    /// A TCP connection to `host`, a name the proxy resolves, at `port`.
+    pub(crate) fn connect_name(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let len = u8::try_from(host.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "host name too long"))?;
+        let mut target = vec![DOMAIN, len];
+        target.extend_from_slice(host.as_bytes());
+        target.extend_from_slice(&port.to_be_bytes());
+        self.connect_to(&target)
+    }
+
+    fn connect_to(&self, target: &[u8]) -> Result<TcpStream> {
+        let (mut stream, server) = self.open()?;
+        request(&mut stream, server, CONNECT, target)?;
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+
+    /// Ask the proxy to relay datagrams for us.
+    pub(crate) fn associate(&self) -> Result<UdpAssociation> {
+        let (mut control, server) = self.open()?;
+        let local = control.local_addr()?;
+        let socket = UdpSocket::bind(SocketAddr::new(local.ip(), 0))?;
+        // Where our datagrams will come from, as far as we know.
+        let from = SocketAddr::new(local.ip(), socket.local_addr()?.port());
+        let mut target = Vec::new();
+        encode_addr(&mut target, from);
+        let mut relay = request(&mut control, server, UDP_ASSOCIATE, &target)?;
+        // A proxy with one address may leave it for us to fill in.
+        if relay.ip().is_unspecified() {
+            relay.set_ip(server.ip());
+        }
+        socket.set_nonblocking(true)?;
+        control.set_nonblocking(true)?;
+        Ok(UdpAssociation {
+            control: Mutex::new(control),
+            socket,
+            relay,
+        })
+    }
+}
+
+/// Send a request for `command` to `target`, an encoded address, returning
+/// the address the proxy answers with.
+fn request(
+    stream: &mut TcpStream,
+    server: SocketAddr,
+    command: u8,
+    target: &[u8],
+) -> Result<SocketAddr> {
+    let mut message = vec![VERSION, command, 0];
+    message.extend_from_slice(target);
+    stream.write_all(&message)?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    let [VERSION, status, _, kind] = reply else {
+        return Err(SocksError::Malformed(server));
+    };
+    let addr_bytes = match kind {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN => {
+            let mut len = [0u8];
+            stream.read_exact(&mut len)?;
+            usize::from(len[0])
+        }
+        _ => return Err(SocksError::Malformed(server)),
+    };
+    let mut rest = vec![0u8; addr_bytes + 2];
+    stream.read_exact(&mut rest)?;
+    match status {
+        SUCCEEDED => {}
+        COMMAND_NOT_SUPPORTED if command == UDP_ASSOCIATE => {
+            return Err(SocksError::NoUdp(server));
+        }
+        status => {
+            return Err(SocksError::Refused {
+                proxy: server,
+                reason: reason(status),
+            });
+        }
+    }
+    let port = u16::from_be_bytes([rest[addr_bytes], rest[addr_bytes + 1]]);
+    let ip = match kind {
+        IPV4 => IpAddr::from(<[u8; 4]>::try_from(&rest[..4]).unwrap()),
+        IPV6 => IpAddr::from(<[u8; 16]>::try_from(&rest[..16]).unwrap()),
+        // A name we would have to resolve; the proxy's own address does.
+        _ => server.ip(),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn encode_addr(out: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            out.push(IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// The address at the start of `datagram` and the length of its header;
+/// None for names, which peers do not send from.
+fn decode_addr(datagram: &[u8]) -> Option<(SocketAddr, usize)> {
+    let (&kind, rest) = datagram.split_first()?;
+    let (ip, rest) = match kind {
+        IPV4 => {
+            let (ip, rest) = rest.split_first_chunk::<4>()?;
+            (IpAddr::from(*ip), rest)
+        }
+        IPV6 => {
+            let (ip, rest) = rest.split_first_chunk::<16>()?;
+            (IpAddr::from(*ip).to_canonical(), rest)
+        }
+        _ => return None,
+    };
+    let (port, rest) = rest.split_first_chunk::<2>()?;
+    let addr = SocketAddr::new(ip, u16::from_be_bytes(*port));
+    Some((addr, datagram.len() - rest.len()))
+}
+
+/// Take a client's greeting and CONNECT request, returning the `host:port`
+/// it wants; answer it with [`reply`]. Other requests are turned down here.
+pub(crate) fn read_connect<S: Read + Write>(client: &mut S) -> io::Result<String> {
+    let mut greeting = [0u8; 2];
+    client.read_exact(&mut greeting)?;
+    if greeting[0] != VERSION {
+        return Err(malformed("not a SOCKS5 client"));
+    }
+    let mut methods = vec![0u8; usize::from(greeting[1])];
+    client.read_exact(&mut methods)?;
+    if !methods.contains(&NO_AUTH) {
+        client.write_all(&[VERSION, NO_ACCEPTABLE_METHOD])?;
+        return Err(malformed("SOCKS5 client insists on a login"));
+    }
+    client.write_all(&[VERSION, NO_AUTH])?;
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request)?;
+    let [VERSION, command, _, kind] = request else {
+        return Err(malformed("malformed SOCKS5 request"));
+    };
+    let host = match kind {
+        IPV4 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip)?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        IPV6 => {
+            let mut ip = [0u8; 16];
+            client.read_exact(&mut ip)?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        DOMAIN => {
+            let mut len = [0u8];
+            client.read_exact(&mut len)?;
+            let mut name = vec![0u8; usize::from(len[0])];
+            client.read_exact(&mut name)?;
+            String::from_utf8(name).map_err(|_| malformed("SOCKS5 host name is not UTF-8"))?
+        }
+        _ => {
+            answer(client, ADDRESS_NOT_SUPPORTED)?;
+            return Err(malformed("unknown SOCKS5 address type"));
+        }
+    };
+    let mut port = [0u8; 2];
+    client.read_exact(&mut port)?;
+    if command != CONNECT {
+        answer(client, COMMAND_NOT_SUPPORTED)?;
+        return Err(malformed("SOCKS5 client asked for more than CONNECT"));
+    }
+    Ok(format!("{host}:{}", u16::from_be_bytes(port)))
+}
+
+/// Tell a client whose request [`read_connect`] took whether its connection
+/// stands, or why not.
+pub(crate) fn reply<S: Write>(client: &mut S, error: Option<&io::Error>) -> io::Result<()> {
+    let status = match error.map(io::Error::kind) {
+        None => SUCCEEDED,
+        Some(io::ErrorKind::PermissionDenied) => NOT_ALLOWED,
+        Some(io::ErrorKind::ConnectionRefused) => CONNECTION_REFUSED,
+        Some(_) => GENERAL_FAILURE,
+    };
+    answer(client, status)
+}
+
+/// A reply with `status` and no bound address: the connection is not ours
+/// to describe.
+fn answer<S: Write>(client: &mut S, status: u8) -> io::Result<()> {
+    let mut message = vec![VERSION, status, 0];
+    encode_addr(&mut message, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    client.write_all(&message)?;
+    client.flush()
+}
+
+fn malformed(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn reason(status: u8) -> &'static str {
+    match status {
+        0x01 => "general failure",
+        0x02 => "not allowed by its rules",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "an unknown error",
+    }
+}
+
+/// Datagrams relayed by the proxy for as long as the control connection
+/// stays open.
+pub(crate) struct UdpAssociation {
+    control: Mutex<TcpStream>,
+    socket: UdpSocket,
+    relay: SocketAddr,
+}
+
+impl UdpAssociation {
+    /// Where the proxy takes our datagrams; peers see us at its address.
+    pub(crate) fn relay(&self) -> SocketAddr {
+        self.relay
+    }
+
+    /// Fails once the proxy closed the control connection, which ends the
+    /// association.
+    fn check(&self) -> io::Result<()> {
+        let mut control = self.control.lock().unwrap();
+        let mut buf = [0u8; 64];
+        match control.read(&mut buf) {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "SOCKS5 proxy ended the UDP association",
+            )),
+            // Nothing is due on it; whatever came is ignored.
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Transport for UdpAssociation {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let mut relayed = vec![0u8; UDP_HEADER_BYTES];
+        encode_addr(&mut relayed, addr);
+        relayed.extend_from_slice(datagram);
+        self.socket.send_to(&relayed, self.relay)?;
+        Ok(datagram.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut relayed = [0u8; 64 * 1024];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut relayed) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.check()?;
+                    return Err(err);
+                }
+                result => result?,
+            };
+            let relayed = &relayed[..len];
+            // Fragments are rare enough to drop, as RFC 1928 allows.
+            let header = relayed
+                .get(..UDP_HEADER_BYTES)
+                .filter(|header| **header == [0, 0, 0]);
+            let decoded = header.and_then(|_| decode_addr(&relayed[UDP_HEADER_BYTES..]));
+            let Some((peer, addr_bytes)) = decoded.filter(|_| from == self.relay) else {
+                debug!("dropping a datagram from {from} that the SOCKS5 proxy did not relay");
+                continue;
+            };
+            let payload = &relayed[UDP_HEADER_BYTES + addr_bytes..];
+            // Like a UDP socket, cut what does not fit.
+            let len = payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&payload[..len]);
+            return Ok((len, peer));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.relay)
+    }
+}
+
+/// What hello traffic goes through when the proxy relays no UDP: nothing,
+/// so that peers are only reached over TCP.
+pub(crate) struct Unrelayed;
+
+impl Transport for Unrelayed {
+    fn send_to(&self, datagram: &[u8], _addr: SocketAddr) -> io::Result<usize> {
+        Ok(datagram.len())
+    }
+
+    fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+    }
+}
@@ -0,0 +1,91 @@
+//! Statistics of the long-lived DHT node.
+
+use std::{
+    fmt,
+    net::SocketAddrV4,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A snapshot of the DHT behind a node (see
+/// [`DhtMsg::dht_stats`](crate::DhtMsg::dht_stats)).
+///
+/// mainline does not tell how many announcements and items it stores for
+/// other nodes, so that is missing here.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhtStats {
+    /// Nodes in the routing table that answered recently.
+    pub routing_table: usize,
+    /// How many nodes the whole DHT has, as estimated from lookups.
+    pub dht_size_estimate: usize,
+    /// Our address as other nodes see it, once enough of them agree.
+    pub public_address: Option<SocketAddrV4>,
+    /// Whether other nodes failed to reach us at the public address so far.
+    pub firewalled: bool,
+    /// Whether the node ID is derived from the public address as BEP 42
+    /// asks; false while the address is unknown.
+    pub secure_id: bool,
+    /// Whether the DHT answers other nodes' queries (see
+    /// [`DhtMsgBuilder::dht_server_mode`](crate::DhtMsgBuilder::dht_server_mode)).
+    pub server_mode: bool,
+    /// Lookups started so far: peers, endpoint records, delegations and
+    /// mailboxes.
+    pub lookups: u64,
+    /// Announcements and puts started so far.
+    pub stores: u64,
+}
+
+impl fmt::Display for DhtStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} nodes in the routing table, about {} in the DHT, ",
+            self.routing_table, self.dht_size_estimate
+        )?;
+        match self.public_address {
+            Some(addr) => write!(f, "public address {addr}")?,
+            None => write!(f, "public address unknown")?,
+        }
+        write!(
+            f,
+            "{}{}, {} mode, {} lookups, {} stores",
+            if self.firewalled { " (firewalled)" } else { "" },
+            if self.secure_id { "" } else { ", no BEP 42 ID" },
+            if self.server_mode { "server" } else { "client" },
+            self.lookups,
+            self.stores
+        )
+    }
+}
+
+/// Counts the DHT queries a node starts.
+#[derive(Default)]
+pub(crate) struct QueryCounts {
+    lookups: AtomicU64,
+    stores: AtomicU64,
+}
+
+impl QueryCounts {
+    pub(crate) fn lookup(&self) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn store(&self) {
+        self.stores.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self, dht: &mainline::Dht) -> DhtStats {
+        let info = dht.info();
+        DhtStats {
+            routing_table: dht.to_bootstrap().len(),
+            dht_size_estimate: info.dht_size_estimate().0,
+            public_address: info.public_address(),
+            firewalled: info.firewalled(),
+            secure_id: info
+                .public_address()
+                .is_some_and(|public| info.id().is_valid_for_ip(*public.ip())),
+            server_mode: info.server_mode(),
+            lookups: self.lookups.load(Ordering::Relaxed),
+            stores: self.stores.load(Ordering::Relaxed),
+        }
+    }
+}
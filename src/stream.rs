@@ -0,0 +1,848 @@
+//! Reliable ordered byte stream to one peer over the hello socket.
+//!
+//! Data is cut into numbered segments; the receiver delivers them in order and
+//! answers with cumulative acks, and unacknowledged segments are retransmitted
+//! with exponential backoff. An empty segment marks the end of the stream.
+//!
+//! The window of segments in flight follows LEDBAT (RFC 6817), as uTP's does,
+//! so a file sent this way yields to interactive traffic on the link. Acks
+//! carry no timestamps, so the queuing delay is how much longer a round trip
+//! takes than the shortest one seen. The window grows while that stays under
+//! 100 ms, shrinks once it goes past and halves on a timeout, within what
+//! receivers accept ahead of the next segment they expect.
+//!
+//! That is at most `MAX_WINDOW` segments, and fewer once the application
+//! falls behind reading: every ack carries the receiver's window, what it
+//! still has room for, so a slow reader (a disk-bound file writer, a TCP
+//! socket that does not drain) holds the sender back rather than losing
+//! what it sends. A closed window opens again with an ack of its own once
+//! the reader catches up; until then the sender keeps one segment in
+//! flight, retransmitted as a probe, and only gives up on a peer that stops
+//! answering.
+//!
+//! A peer has one plain stream, which both sides share, and any number on
+//! channels (see the `channel` module). Those are numbered by whoever opens
+//! them, and each has its own segments, acks and window, so a transfer on
+//! one does not wait for the losses of another.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    Identity,
+    channel::{self, Channel, Channels},
+    shutdown::ShutdownHandle,
+    transport::SharedTransport,
+    wire::{self, FrameType},
+};
+
+/// First byte of a stream data segment (the body of a
+/// [`FrameType::Stream`] frame): `[tag][seq u32][payload]`.
+const TAG_DATA: u8 = 0xd1;
+/// First byte of a cumulative stream ack:
+/// `[tag][next expected seq u32][window u16]`, where the window is how many
+/// segments from the next expected one the receiver takes.
+const TAG_ACK: u8 = 0xd2;
+/// The same for a stream on a channel (plain streams are number 0):
+/// `[tag][stream u32][seq u32][payload]` and
+/// `[tag][stream u32][next expected seq u32][window u16]`.
+const TAG_CHANNEL_DATA: u8 = 0xd3;
+const TAG_CHANNEL_ACK: u8 = 0xd4;
+
+/// Payload bytes per segment, small enough to avoid IP fragmentation.
+const SEGMENT_BYTES: usize = 1200;
+/// Segments in flight before writers block, when a stream starts.
+const WINDOW: usize = 32;
+/// Segments a receiver takes ahead of the next one it expects, and so the
+/// largest window.
+const MAX_WINDOW: usize = 2 * WINDOW;
+const MIN_WINDOW: f64 = 2.0;
+/// Segments received but not yet read before the window we advertise
+/// closes.
+const RECV_BUFFER: usize = 1024;
+/// Segments the window must have grown by since our last ack before a read
+/// tells the sender, so that it does not trickle out one at a time.
+const WINDOW_UPDATE: usize = 16;
+/// Queuing delay the window aims for.
+const TARGET_DELAY: Duration = Duration::from_millis(100);
+/// Most segments the window grows by in a round trip, with no delay at all.
+const MAX_GROWTH: f64 = 2.5;
+/// How long the shortest round trip seen counts as the path's own; it is
+/// measured anew after that, in case the route changed.
+const BASE_DELAY_WINDOW: Duration = Duration::from_secs(60);
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+const MAX_RETRIES: u32 = 8;
+/// Retries a segment backs off to at most while the peer keeps answering:
+/// probes of a closed window go out every two seconds.
+const PROBE_RETRIES: u32 = 2;
+const TICK: Duration = Duration::from_millis(100);
+/// Streams opened by peers waiting for `accept_stream()`.
+const ACCEPT_QUEUE: usize = 16;
+
+pub(crate) fn is_stream_frame(datagram: &[u8]) -> bool {
+    match datagram.first() {
+        Some(&TAG_DATA | &TAG_ACK) => datagram.len() >= 5,
+        Some(&TAG_CHANNEL_DATA | &TAG_CHANNEL_ACK) => datagram.len() >= 9,
+        _ => false,
+    }
+}
+
+/// Stream number, whether it is data rather than an ack, sequence number
+/// and payload of a stream frame.
+fn parse(datagram: &[u8]) -> (u32, bool, u32, &[u8]) {
+    let u32_at = |at: usize| u32::from_be_bytes(datagram[at..at + 4].try_into().unwrap());
+    match datagram[0] {
+        TAG_DATA | TAG_ACK => (0, datagram[0] == TAG_DATA, u32_at(1), &datagram[5..]),
+        tag => (
+            u32_at(1),
+            tag == TAG_CHANNEL_DATA,
+            u32_at(5),
+            &datagram[9..],
+        ),
+    }
+}
+
+/// `[tag][stream u32, unless 0][seq u32][payload]`.
+fn frame(id: u32, tags: [u8; 2], seq: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(9 + payload.len());
+    if id == 0 {
+        frame.push(tags[0]);
+    } else {
+        frame.push(tags[1]);
+        frame.extend_from_slice(&id.to_be_bytes());
+    }
+    frame.extend_from_slice(&seq.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+enum Inbound {
+    Data(Vec<u8>),
+    Eof,
+    Broken,
+}
+
+struct Segment {
+    payload: Vec<u8>,
+    sent: Instant,
+    retries: u32,
+}
+
+struct SendState {
+    next_seq: u32,
+    unacked: BTreeMap<u32, Segment>,
+    fin_queued: bool,
+    /// LEDBAT's window, in segments.
+    window: f64,
+    /// Shortest round trips seen in this base delay window and the one before.
+    base_delays: [Option<Duration>; 2],
+    base_delay_since: Instant,
+    /// When the window last halved for a timeout; it does at most once an
+    /// initial RTO.
+    shrunk_at: Option<Instant>,
+    /// The receiver's window, from its latest ack.
+    peer_window: usize,
+    /// When the latest ack arrived.
+    heard: Option<Instant>,
+}
+
+impl Default for SendState {
+    fn default() -> Self {
+        Self {
+            next_seq: 0,
+            unacked: BTreeMap::new(),
+            fin_queued: false,
+            window: WINDOW as f64,
+            base_delays: [None; 2],
+            base_delay_since: Instant::now(),
+            shrunk_at: None,
+            peer_window: MAX_WINDOW,
+            heard: None,
+        }
+    }
+}
+
+impl SendState {
+    /// Grow or shrink the window by how far the queuing in round trip
+    /// `rtt` is from the target, for `acked` segments acknowledged.
+    fn ledbat(&mut self, rtt: Duration, acked: usize) {
+        if self.base_delay_since.elapsed() >= BASE_DELAY_WINDOW {
+            self.base_delays = [None, self.base_delays[0]];
+            self.base_delay_since = Instant::now();
+        }
+        let current = self.base_delays[0].get_or_insert(rtt);
+        *current = (*current).min(rtt);
+        let base = self
+            .base_delays
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+            .unwrap_or(rtt);
+        let queuing = rtt.saturating_sub(base).as_secs_f64();
+        let target = TARGET_DELAY.as_secs_f64();
+        let off_target = (target - queuing) / target;
+        let growth = MAX_GROWTH * off_target * acked as f64 / self.window;
+        self.window = (self.window + growth).clamp(MIN_WINDOW, MAX_WINDOW as f64);
+    }
+}
+
+#[derive(Default)]
+struct RecvState {
+    next_seq: u32,
+    out_of_order: BTreeMap<u32, Vec<u8>>,
+    fin_received: bool,
+    /// Segments delivered to the application and not yet read.
+    unread: usize,
+    /// The window in our latest ack.
+    advertised: usize,
+}
+
+impl RecvState {
+    /// Segments from `next_seq` on that we take.
+    fn window(&self) -> usize {
+        MAX_WINDOW.min(RECV_BUFFER.saturating_sub(self.unread))
+    }
+}
+
+pub(crate) struct StreamShared {
+    peer: SocketAddr,
+    /// 0 for the plain stream, else the number of one on a channel.
+    id: u32,
+    transport: SharedTransport,
+    identity: Arc<Identity>,
+    send: Mutex<SendState>,
+    recv: Mutex<RecvState>,
+    inbound_tx: flume::Sender<Inbound>,
+    inbound_rx: flume::Receiver<Inbound>,
+    // One token per free window slot; writers take one before each segment.
+    permits_tx: flume::Sender<()>,
+    permits_rx: flume::Receiver<()>,
+    /// Signalled when the last segment in flight is acknowledged, for
+    /// `flush`.
+    drained_tx: flume::Sender<()>,
+    drained_rx: flume::Receiver<()>,
+    /// Why the stream failed, once it did.
+    broken: Mutex<Option<&'static str>>,
+    /// Live `PeerStream` handles; the last one to drop finishes the stream.
+    handles: Mutex<usize>,
+}
+
+impl StreamShared {
+    fn new(transport: SharedTransport, identity: Arc<Identity>, peer: SocketAddr, id: u32) -> Self {
+        let (inbound_tx, inbound_rx) = flume::unbounded();
+        let (permits_tx, permits_rx) = flume::bounded(MAX_WINDOW);
+        for _ in 0..WINDOW {
+            let _ = permits_tx.send(());
+        }
+        let (drained_tx, drained_rx) = flume::bounded(1);
+        Self {
+            peer,
+            id,
+            transport,
+            identity,
+            send: Mutex::default(),
+            recv: Mutex::default(),
+            inbound_tx,
+            inbound_rx,
+            permits_tx,
+            permits_rx,
+            drained_tx,
+            drained_rx,
+            broken: Mutex::new(None),
+            handles: Mutex::new(0),
+        }
+    }
+
+    /// Whether written segments still wait for their ack.
+    fn in_flight(&self) -> bool {
+        !self.is_broken() && !self.send.lock().unwrap().unacked.is_empty()
+    }
+
+    fn is_broken(&self) -> bool {
+        self.broken.lock().unwrap().is_some()
+    }
+
+    fn check_broken(&self) -> io::Result<()> {
+        match *self.broken.lock().unwrap() {
+            Some(reason) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("stream to {} {reason}", self.peer),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Queue and transmit one segment; the caller already holds a window permit.
+    fn push_segment(&self, payload: Vec<u8>) {
+        let mut send = self.send.lock().unwrap();
+        let seq = send.next_seq;
+        send.next_seq = send.next_seq.wrapping_add(1);
+        self.transmit(seq, &payload);
+        send.unacked.insert(
+            seq,
+            Segment {
+                payload,
+                sent: Instant::now(),
+                retries: 0,
+            },
+        );
+    }
+
+    fn transmit(&self, seq: u32, payload: &[u8]) {
+        let frame = frame(self.id, [TAG_DATA, TAG_CHANNEL_DATA], seq, payload);
+        let frame = wire::encode(FrameType::Stream, &frame, &self.identity);
+        if let Err(err) = self.transport.send_to(&frame, self.peer) {
+            debug!("stream send to {} failed: {err}", self.peer);
+        }
+    }
+
+    fn send_ack(&self, recv: &mut RecvState) {
+        recv.advertised = recv.window();
+        let window = (recv.advertised as u16).to_be_bytes();
+        let frame = frame(self.id, [TAG_ACK, TAG_CHANNEL_ACK], recv.next_seq, &window);
+        let frame = wire::encode(FrameType::Stream, &frame, &self.identity);
+        if let Err(err) = self.transport.send_to(&frame, self.peer) {
+            debug!("stream ack to {} failed: {err}", self.peer);
+        }
+    }
+
+    fn on_frame(&self, data: bool, seq: u32, payload: &[u8]) {
+        if data {
+            self.on_data(seq, payload);
+        } else {
+            // Receivers that predate windows take what we send.
+            let window = payload.first_chunk::<2>().map_or(MAX_WINDOW, |window| {
+                usize::from(u16::from_be_bytes(*window))
+            });
+            self.on_ack(seq, window);
+        }
+    }
+
+    fn on_data(&self, seq: u32, payload: &[u8]) {
+        let mut recv = self.recv.lock().unwrap();
+        // Segments behind `next_seq` are retransmissions of delivered data,
+        // and those past the window find no room: just re-ack.
+        if seq.wrapping_sub(recv.next_seq) < recv.window() as u32 && !recv.fin_received {
+            // Nobody reads once every handle is gone; what arrives is
+            // dropped rather than kept, and the window stays open.
+            let reading = *self.handles.lock().unwrap() > 0;
+            recv.out_of_order
+                .entry(seq)
+                .or_insert_with(|| payload.to_vec());
+            loop {
+                let next_seq = recv.next_seq;
+                let Some(payload) = recv.out_of_order.remove(&next_seq) else {
+                    break;
+                };
+                recv.next_seq = next_seq.wrapping_add(1);
+                if payload.is_empty() {
+                    recv.fin_received = true;
+                    let _ = self.inbound_tx.send(Inbound::Eof);
+                    break;
+                }
+                if reading {
+                    recv.unread += 1;
+                    let _ = self.inbound_tx.send(Inbound::Data(payload));
+                }
+            }
+        }
+        self.send_ack(&mut recv);
+    }
+
+    /// The application read a segment: tell the sender once the window
+    /// opened again by enough.
+    fn on_read(&self) {
+        let mut recv = self.recv.lock().unwrap();
+        recv.unread = recv.unread.saturating_sub(1);
+        if recv.window() >= recv.advertised + WINDOW_UPDATE {
+            self.send_ack(&mut recv);
+        }
+    }
+
+    fn on_ack(&self, next_seq: u32, window: usize) {
+        let mut send = self.send.lock().unwrap();
+        send.heard = Some(Instant::now());
+        send.peer_window = window;
+        let acked: Vec<u32> = send
+            .unacked
+            .keys()
+            .copied()
+            .filter(|seq| next_seq.wrapping_sub(*seq).wrapping_sub(1) < MAX_WINDOW as u32)
+            .collect();
+        // Round trips of retransmitted segments are ambiguous (Karn).
+        let rtt = acked
+            .iter()
+            .filter_map(|seq| send.unacked.remove(seq))
+            .filter(|segment| segment.retries == 0)
+            .map(|segment| segment.sent.elapsed())
+            .min();
+        if let Some(rtt) = rtt {
+            send.ledbat(rtt, acked.len());
+        }
+        // One segment may always be on its way, or a closed window would
+        // only hear it opened from an ack that may get lost.
+        let window = (send.window as usize)
+            .min(send.peer_window)
+            .max(usize::from(send.unacked.is_empty()));
+        let busy = send.unacked.len() + self.permits_rx.len();
+        for _ in busy..window {
+            let _ = self.permits_tx.try_send(());
+        }
+        if send.unacked.is_empty() {
+            let _ = self.drained_tx.try_send(());
+        }
+    }
+
+    /// Retransmit overdue segments; returns false once the stream is finished.
+    fn tick(&self) -> bool {
+        let mut send = self.send.lock().unwrap();
+        let mut timed_out = false;
+        let heard = send.heard;
+        for (seq, segment) in send.unacked.iter_mut() {
+            if segment.sent.elapsed() < INITIAL_RTO * 2u32.pow(segment.retries) {
+                continue;
+            }
+            timed_out = true;
+            // A peer that answered the last try has no room, and is
+            // probed on until it does.
+            let answered = heard.is_some_and(|heard| heard >= segment.sent);
+            if segment.retries >= MAX_RETRIES && !answered {
+                warn!(
+                    "stream to {} gave up after {MAX_RETRIES} retries",
+                    self.peer
+                );
+                drop(send);
+                self.set_broken("timed out");
+                return false;
+            }
+            segment.retries += 1;
+            if answered {
+                segment.retries = segment.retries.min(PROBE_RETRIES);
+            }
+            segment.sent = Instant::now();
+            self.transmit(*seq, &segment.payload);
+        }
+        // Probes a full receiver drops say nothing about the path.
+        let congested = send.peer_window > 0;
+        if timed_out && congested && send.shrunk_at.is_none_or(|at| at.elapsed() >= INITIAL_RTO) {
+            send.window = (send.window / 2.0).max(MIN_WINDOW);
+            send.shrunk_at = Some(Instant::now());
+        }
+        let sent_all = send.fin_queued && send.unacked.is_empty();
+        drop(send);
+        let done_reading =
+            self.recv.lock().unwrap().fin_received || *self.handles.lock().unwrap() == 0;
+        !(sent_all && done_reading)
+    }
+
+    fn set_broken(&self, reason: &'static str) {
+        self.broken.lock().unwrap().get_or_insert(reason);
+        let _ = self.inbound_tx.send(Inbound::Broken);
+        // Wake up writers blocked on the window, and flushes.
+        for _ in 0..MAX_WINDOW {
+            let _ = self.permits_tx.try_send(());
+        }
+        let _ = self.drained_tx.try_send(());
+    }
+
+    fn finish(&self) {
+        let mut send = self.send.lock().unwrap();
+        if send.fin_queued || self.is_broken() {
+            return;
+        }
+        send.fin_queued = true;
+        drop(send);
+        self.push_segment(Vec::new());
+    }
+}
+
+/// Per-node table of streams keyed by peer address and stream number.
+pub(crate) struct Streams {
+    by_peer: Mutex<HashMap<(SocketAddr, u32), Arc<StreamShared>>>,
+    /// Peers that completed a hello/ack exchange with us.
+    established: Mutex<HashSet<SocketAddr>>,
+    accept: Mutex<Option<(flume::Sender<PeerStream>, flume::Receiver<PeerStream>)>>,
+    channels: Arc<Channels<PeerStream>>,
+    /// Signs our segments and acks.
+    identity: Arc<Identity>,
+    shutdown: ShutdownHandle,
+}
+
+impl Streams {
+    pub(crate) fn new(identity: Arc<Identity>, shutdown: ShutdownHandle) -> Self {
+        Self {
+            by_peer: Mutex::default(),
+            established: Mutex::default(),
+            accept: Mutex::default(),
+            channels: Arc::new(Channels::new("stream")),
+            identity,
+            shutdown,
+        }
+    }
+
+    pub(crate) fn mark_established(&self, peer: SocketAddr) {
+        self.established.lock().unwrap().insert(peer);
+    }
+
+    pub(crate) fn established_peers(&self) -> Vec<SocketAddr> {
+        self.established.lock().unwrap().iter().copied().collect()
+    }
+
+    /// The peer said goodbye: fail its streams and require a new hello
+    /// exchange.
+    pub(crate) fn forget(&self, peer: &SocketAddr) {
+        self.established.lock().unwrap().remove(peer);
+        self.by_peer.lock().unwrap().retain(|(addr, _), shared| {
+            if addr == peer {
+                shared.set_broken("closed by peer");
+            }
+            addr != peer
+        });
+    }
+
+    /// Fail every stream and stop accepting new ones.
+    pub(crate) fn close(&self) {
+        self.accept.lock().unwrap().take();
+        self.channels.close();
+        for (_, shared) in self.by_peer.lock().unwrap().drain() {
+            shared.set_broken("closed: node shut down");
+        }
+    }
+
+    /// Whether a stream is open, so that its segments should be read
+    /// without delay.
+    pub(crate) fn busy(&self) -> bool {
+        !self.by_peer.lock().unwrap().is_empty()
+    }
+
+    pub(crate) fn is_established(&self, peer: &SocketAddr) -> bool {
+        self.established.lock().unwrap().contains(peer)
+    }
+
+    /// Start accepting peer-initiated streams (on first call) and return the queue.
+    pub(crate) fn acceptor(&self) -> flume::Receiver<PeerStream> {
+        let mut accept = self.accept.lock().unwrap();
+        let (_, rx) = accept.get_or_insert_with(|| flume::bounded(ACCEPT_QUEUE));
+        rx.clone()
+    }
+
+    /// Get the plain stream to `peer`, creating it (and its retransmit
+    /// thread) if needed.
+    pub(crate) fn open(
+        self: &Arc<Self>,
+        transport: &SharedTransport,
+        peer: SocketAddr,
+    ) -> PeerStream {
+        let mut by_peer = self.by_peer.lock().unwrap();
+        if let Some(shared) = by_peer.get(&(peer, 0)) {
+            return PeerStream::new(shared.clone());
+        }
+        self.create(&mut by_peer, transport, peer, 0)
+    }
+
+    /// Open a new stream to `peer` on the channel `name`, at most
+    /// [`MAX_NAME_BYTES`](channel::MAX_NAME_BYTES) long.
+    pub(crate) fn open_channel(
+        self: &Arc<Self>,
+        transport: &SharedTransport,
+        peer: SocketAddr,
+        name: &str,
+    ) -> io::Result<PeerStream> {
+        let mut by_peer = self.by_peer.lock().unwrap();
+        let id = loop {
+            let id: u32 = rand::random();
+            if id != 0 && !by_peer.contains_key(&(peer, id)) {
+                break id;
+            }
+        };
+        let mut stream = self.create(&mut by_peer, transport, peer, id);
+        drop(by_peer);
+        channel::write_name(&mut stream, name)?;
+        Ok(stream.named(name.to_string()))
+    }
+
+    /// Start accepting streams on the channel `name` (on first call) and
+    /// return the queue.
+    pub(crate) fn channel_acceptor(&self, name: &str) -> flume::Receiver<PeerStream> {
+        self.channels.accept(name)
+    }
+
+    /// Add stream `id` to `peer` and start its retransmit thread.
+    fn create(
+        self: &Arc<Self>,
+        by_peer: &mut HashMap<(SocketAddr, u32), Arc<StreamShared>>,
+        transport: &SharedTransport,
+        peer: SocketAddr,
+        id: u32,
+    ) -> PeerStream {
+        let shared = Arc::new(StreamShared::new(
+            transport.clone(),
+            self.identity.clone(),
+            peer,
+            id,
+        ));
+        by_peer.insert((peer, id), shared.clone());
+        let streams = self.clone();
+        let ticking = shared.clone();
+        self.shutdown.spawn("dhtmsg-stream", move || {
+            while ticking.tick() {
+                if !streams.shutdown.sleep(TICK) || ticking.is_broken() {
+                    break;
+                }
+            }
+            let key = (ticking.peer, ticking.id);
+            let mut by_peer = streams.by_peer.lock().unwrap();
+            if by_peer
+                .get(&key)
+                .is_some_and(|shared| Arc::ptr_eq(shared, &ticking))
+            {
+                by_peer.remove(&key);
+            }
+        });
+        PeerStream::new(shared)
+    }
+
+    /// Route the body of a stream frame from the receive loop.
+    pub(crate) fn dispatch(
+        self: &Arc<Self>,
+        transport: &SharedTransport,
+        datagram: &[u8],
+        peer: SocketAddr,
+    ) {
+        if !self.is_established(&peer) {
+            debug!("stream frame from {peer} before it was established (dropped)");
+            return;
+        }
+        let (id, data, seq, payload) = parse(datagram);
+        let existing = self.by_peer.lock().unwrap().get(&(peer, id)).cloned();
+        let shared = match existing {
+            Some(shared) => shared,
+            None if !data => return,
+            // A stream on a channel starts with its first segment; later
+            // ones are left over from a stream that ended. Nobody accepting
+            // channels stays silent, like nobody accepting streams.
+            None if id != 0 => {
+                if seq != 0 || !self.channels.accepting() {
+                    return;
+                }
+                let stream = self.create(&mut self.by_peer.lock().unwrap(), transport, peer, id);
+                let shared = stream.shared.clone();
+                self.channels.name(stream);
+                shared
+            }
+            None => {
+                let accept = self
+                    .accept
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .map(|(tx, _)| tx.clone());
+                let Some(accept) = accept else {
+                    // Nobody accepts streams: stay silent so the peer's retries time out.
+                    return;
+                };
+                let stream = self.open(transport, peer);
+                let shared = stream.shared.clone();
+                if accept.try_send(stream).is_err() {
+                    debug!("accept queue full; dropping stream from {peer}");
+                    return;
+                }
+                shared
+            }
+        };
+        shared.on_frame(data, seq, payload);
+    }
+}
+
+/// Byte stream to one peer, available after a hello/ack exchange.
+///
+/// Dropping the stream (or calling [`finish`](Self::finish)) sends an
+/// end-of-stream marker, so the peer's reads return 0.
+pub struct PeerStream {
+    shared: Arc<StreamShared>,
+    channel: Option<String>,
+    pending: Vec<u8>,
+    eof: bool,
+}
+
+impl PeerStream {
+    fn new(shared: Arc<StreamShared>) -> Self {
+        *shared.handles.lock().unwrap() += 1;
+        Self {
+            shared,
+            channel: None,
+            pending: Vec::new(),
+            eof: false,
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.shared.peer
+    }
+
+    /// The channel the stream was opened on, if any.
+    pub fn channel(&self) -> Option<&str> {
+        self.channel.as_deref()
+    }
+
+    /// Signal end of stream to the peer. Further writes fail.
+    pub fn finish(&self) {
+        self.shared.finish();
+    }
+
+    fn take_pending(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        n
+    }
+
+    fn accept_inbound(&mut self, inbound: Inbound, buf: &mut [u8]) -> io::Result<usize> {
+        match inbound {
+            Inbound::Data(data) => {
+                self.shared.on_read();
+                self.pending = data;
+                Ok(self.take_pending(buf))
+            }
+            Inbound::Eof => {
+                self.eof = true;
+                Ok(0)
+            }
+            Inbound::Broken => {
+                // Keep reporting the failure on subsequent reads.
+                let _ = self.shared.inbound_tx.send(Inbound::Broken);
+                self.shared.check_broken().map(|_| 0)
+            }
+        }
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        self.shared.check_broken()?;
+        if self.shared.send.lock().unwrap().fin_queued {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "stream already finished",
+            ));
+        }
+        Ok(())
+    }
+
+    fn closed_error() -> io::Error {
+        io::Error::new(io::ErrorKind::BrokenPipe, "node stopped")
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn read_async(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            return Ok(self.take_pending(buf));
+        }
+        if self.eof || buf.is_empty() {
+            return Ok(0);
+        }
+        let inbound = self.shared.inbound_rx.recv_async().await;
+        let inbound = inbound.map_err(|_| Self::closed_error())?;
+        self.accept_inbound(inbound, buf)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn write_async(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_writable()?;
+        let n = buf.len().min(SEGMENT_BYTES);
+        if n == 0 {
+            return Ok(0);
+        }
+        let permit = self.shared.permits_rx.recv_async().await;
+        permit.map_err(|_| Self::closed_error())?;
+        self.shared.check_broken()?;
+        self.shared.push_segment(buf[..n].to_vec());
+        Ok(n)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn flush_async(&mut self) -> io::Result<()> {
+        while self.shared.in_flight() {
+            let drained = self.shared.drained_rx.recv_async().await;
+            drained.map_err(|_| Self::closed_error())?;
+        }
+        self.shared.check_broken()
+    }
+}
+
+impl Channel for PeerStream {
+    fn peer(&self) -> SocketAddr {
+        self.shared.peer
+    }
+
+    fn named(mut self, name: String) -> Self {
+        self.channel = Some(name);
+        self
+    }
+
+    /// Dropped, it finishes the stream.
+    fn abandon(self) {}
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            return Ok(self.take_pending(buf));
+        }
+        if self.eof || buf.is_empty() {
+            return Ok(0);
+        }
+        let inbound = self
+            .shared
+            .inbound_rx
+            .recv()
+            .map_err(|_| Self::closed_error())?;
+        self.accept_inbound(inbound, buf)
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_writable()?;
+        let n = buf.len().min(SEGMENT_BYTES);
+        if n == 0 {
+            return Ok(0);
+        }
+        self.shared
+            .permits_rx
+            .recv()
+            .map_err(|_| Self::closed_error())?;
+        self.shared.check_broken()?;
+        self.shared.push_segment(buf[..n].to_vec());
+        Ok(n)
+    }
+
+    /// Block until every written byte has been acknowledged by the peer.
+    fn flush(&mut self) -> io::Result<()> {
+        while self.shared.in_flight() {
+            self.shared
+                .drained_rx
+                .recv()
+                .map_err(|_| Self::closed_error())?;
+        }
+        self.shared.check_broken()
+    }
+}
+
+impl Drop for PeerStream {
+    fn drop(&mut self) {
+        // Other handles to the same peer keep the stream open.
+        let mut handles = self.shared.handles.lock().unwrap();
+        *handles -= 1;
+        if *handles == 0 {
+            drop(handles);
+            self.shared.finish();
+        }
+    }
+}
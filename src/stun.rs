@@ -0,0 +1,402 @@
+//! STUN (RFC 5389) Binding requests, sent from the hello socket before the
+//! node starts: a server answers with the address the request came from,
+//! which is where the NAT maps the socket.
+//!
+//! Unlike the port a short-lived DHT learns for its own socket, which the
+//! NAT may map differently, this is the hello socket's own mapping. Asking
+//! two servers also tells whether the NAT keeps that mapping for every
+//! destination (endpoint-independent mapping, RFC 4787), which hole
+//! punching relies on, or hands out a new port for each one.
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::transport::Transport;
+
+/// Servers asked unless others are configured.
+pub(crate) const DEFAULT_SERVERS: &[&str] =
+    &["stun.l.google.com:19302", "stun.cloudflare.com:3478"];
+
+pub(crate) const MAGIC_COOKIE: u32 = 0x2112_A442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 0x01;
+pub(crate) const HEADER_BYTES: usize = 20;
+
+/// Requests are sent again after each of these, counted from the first.
+const RETRIES: [Duration; 2] = [Duration::from_millis(500), Duration::from_millis(1500)];
+const TIMEOUT: Duration = Duration::from_millis(2500);
+/// How long the other servers get once one has answered.
+const STRAGGLERS: Duration = Duration::from_millis(500);
+const POLL: Duration = Duration::from_millis(20);
+
+/// How the NAT maps the hello socket, as seen by two STUN servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatMapping {
+    /// Every destination sees the same public address and port; hole
+    /// punching works.
+    EndpointIndependent,
+    /// Each destination sees another public port (a "symmetric" NAT), so
+    /// the port announced is only right for the STUN server; peers behind
+    /// NATs can hardly reach us directly.
+    EndpointDependent,
+}
+
+/// What the servers told us.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Binding {
+    pub(crate) public: SocketAddrV4,
+    /// Unknown unless two servers at different addresses answered.
+    pub(crate) mapping: Option<NatMapping>,
+}
+
+/// Ask each of `servers` (`host:port`) for the public address of
+/// `transport`, which nothing else may be reading yet. None if no server
+/// answered; names that do not resolve to an IPv4 address are skipped.
+pub(crate) fn discover(transport: &dyn Transport, servers: &[String]) -> Option<Binding> {
+    let mut servers: Vec<(SocketAddr, [u8; 12])> = servers
+        .iter()
+        .filter_map(|server| {
+            let resolved = server.as_str().to_socket_addrs();
+            let addr = resolved
+                .ok()
+                .and_then(|mut addrs| addrs.find(SocketAddr::is_ipv4));
+            if addr.is_none() {
+                debug!("skipping STUN server {server}: no IPv4 address");
+            }
+            addr
+        })
+        .map(|addr| (addr, rand::random()))
+        .collect();
+    servers.dedup_by_key(|(addr, _)| *addr);
+    if servers.is_empty() {
+        return None;
+    }
+
+    let start = Instant::now();
+    let mut deadline = start + TIMEOUT;
+    let mut retries = RETRIES.iter().map(|after| start + *after).peekable();
+    let mut answers: Vec<(SocketAddr, SocketAddrV4)> = Vec::new();
+    let mut buf = [0u8; 576];
+    send_requests(transport, &servers, &answers);
+    while answers.len() < servers.len() && Instant::now() < deadline {
+        if retries.next_if(|at| Instant::now() >= *at).is_some() {
+            send_requests(transport, &servers, &answers);
+        }
+        match transport.recv_from(&mut buf) {
+            Ok((len, from)) => {
+                let Some((_, transaction)) = servers.iter().find(|(addr, _)| *addr == from) else {
+                    continue;
+                };
+                if answers.iter().any(|(server, _)| *server == from) {
+                    continue;
+                }
+                if let Some(mapped) = parse_response(&buf[..len], transaction) {
+                    debug!("STUN server {from} sees us at {mapped}");
+                    answers.push((from, mapped));
+                    deadline = deadline.min(Instant::now() + STRAGGLERS);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL),
+            // Such as ICMP port unreachable from a server, on some systems.
+            Err(err) => debug!("STUN receive failed: {err}"),
+        }
+    }
+
+    let (first_server, public) = *answers.first()?;
+    let other = answers
+        .iter()
+        .find(|(server, _)| server.ip() != first_server.ip());
+    let mapping = other.map(|(_, mapped)| {
+        if *mapped == public {
+            NatMapping::EndpointIndependent
+        } else {
+            NatMapping::EndpointDependent
+        }
+    });
+    Some(Binding { public, mapping })
+}
+
+/// A request to each server that has not answered yet.
+fn send_requests(
+    transport: &dyn Transport,
+    servers: &[(SocketAddr, [u8; 12])],
+    answers: &[(SocketAddr, SocketAddrV4)],
+) {
+    for (server, transaction) in servers {
+        if answers.iter().any(|(answered, _)| answered == server) {
+            continue;
+        }
+        if let Err(err) = transport.send_to(&request(transaction), *server) {
+            debug!("STUN request to {server} failed: {err}");
+        }
+    }
+}
+
+/// A Binding request without attributes.
+fn request(transaction: &[u8; 12]) -> [u8; HEADER_BYTES] {
+    let mut request = [0u8; HEADER_BYTES];
+    request[..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // The length of the attributes stays zero.
+    request[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request[8..].copy_from_slice(transaction);
+    request
+}
+
+/// The IPv4 address a Binding success response to `transaction` names,
+/// from XOR-MAPPED-ADDRESS or, for servers that predate it, MAPPED-ADDRESS.
+fn parse_response(response: &[u8], transaction: &[u8; 12]) -> Option<SocketAddrV4> {
+    if message_type(response)? != BINDING_SUCCESS || response[8..HEADER_BYTES] != transaction[..] {
+        return None;
+    }
+    let mut mapped = None;
+    for (kind, value) in attributes(response)? {
+        match kind {
+            XOR_MAPPED_ADDRESS => return ipv4_address(value, true),
+            MAPPED_ADDRESS => mapped = ipv4_address(value, false),
+            _ => {}
+        }
+    }
+    mapped
+}
+
+/// The type of the STUN message `message`, if it is one.
+pub(crate) fn message_type(message: &[u8]) -> Option<u16> {
+    (message.len() >= HEADER_BYTES
+        && message[0] & 0xc0 == 0
+        && message[4..8] == MAGIC_COOKIE.to_be_bytes())
+    .then(|| u16::from_be_bytes([message[0], message[1]]))
+}
+
+/// The attributes of the STUN message `message`, types and values, in
+/// order; None if they run past its end.
+pub(crate) fn attributes(message: &[u8]) -> Option<Vec<(u16, &[u8])>> {
+    let length = usize::from(u16::from_be_bytes(*message.get(2..4)?.first_chunk()?));
+    let mut attributes = message.get(HEADER_BYTES..HEADER_BYTES + length)?;
+    let mut parsed = Vec::new();
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = usize::from(u16::from_be_bytes([attributes[2], attributes[3]]));
+        parsed.push((kind, attributes.get(4..4 + len)?));
+        // Values are padded to four bytes.
+        let padded = (4 + len).next_multiple_of(4);
+        attributes = attributes.get(padded..).unwrap_or_default();
+    }
+    Some(parsed)
+}
+
+/// An IPv4 address attribute value, XORed with the magic cookie if `xor`.
+pub(crate) fn ipv4_address(value: &[u8], xor: bool) -> Option<SocketAddrV4> {
+    if value.len() < 8 || value[1] != FAMILY_IPV4 {
+        return None;
+    }
+    let mut port = u16::from_be_bytes([value[2], value[3]]);
+    let mut ip = u32::from_be_bytes([value[4], value[5], value[6], value[7]]);
+    if xor {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+        ip ^= MAGIC_COOKIE;
+    }
+    Some(SocketAddrV4::new(Ipv4Addr::from(ip), port))
+}
+
+/// The value of an XOR address attribute naming `addr`.
+pub(crate) fn xor_ipv4_address(addr: SocketAddrV4) -> [u8; 8] {
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = u32::from(*addr.ip()) ^ MAGIC_COOKIE;
+    let mut value = [0, FAMILY_IPV4, 0, 0, 0, 0, 0, 0];
+    value[2..4].copy_from_slice(&port.to_be_bytes());
+    value[4..].copy_from_slice(&ip.to_be_bytes());
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::*;
+
+    const TRANSACTION: &str = "b7e7a701bc34d686fa87dfae";
+
+    /// RFC 5769, section 2.2: a response naming 192.0.2.1:32853, with
+    /// SOFTWARE, MESSAGE-INTEGRITY and FINGERPRINT attributes around it.
+    const IPV4_RESPONSE: &str = concat!(
+        "0101003c2112a442b7e7a701bc34d686fa87dfae",
+        "8022000b7465737420766563746f7220",
+        "002000080001a147e112a643",
+        "000800142b91f599fd9e90c38c7489f92af9ba53f06be7d7",
+        "80280004c07d4c96",
+    );
+    /// RFC 5769, section 2.3: the same for 2001:db8:1234:5678:11:2233:4455:6677.
+    const IPV6_RESPONSE: &str = concat!(
+        "010100482112a442b7e7a701bc34d686fa87dfae",
+        "8022000b7465737420766563746f7220",
+        "002000140002a1470113a9faa5d3f179bc25f4b5bed2b9d9",
+        "00080014a382954e4be67bf11784c97c8292c275bfe3ed41",
+        "80280004c8fb0b4c",
+    );
+
+    fn transaction() -> [u8; 12] {
+        hex::decode(TRANSACTION).unwrap().try_into().unwrap()
+    }
+
+    fn mapped() -> SocketAddrV4 {
+        "192.0.2.1:32853".parse().unwrap()
+    }
+
+    /// A Binding success response to `transaction` with `attributes`.
+    fn success(transaction: &[u8; 12], attributes: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut response = request(transaction).to_vec();
+        response[..2].copy_from_slice(&BINDING_SUCCESS.to_be_bytes());
+        for (kind, value) in attributes {
+            response.extend_from_slice(&kind.to_be_bytes());
+            response.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            response.extend_from_slice(value);
+            response.resize(response.len().next_multiple_of(4), 0);
+        }
+        let length = (response.len() - HEADER_BYTES) as u16;
+        response[2..4].copy_from_slice(&length.to_be_bytes());
+        response
+    }
+
+    #[test]
+    fn rfc5769_responses() {
+        let response = hex::decode(IPV4_RESPONSE).unwrap();
+        assert_eq!(message_type(&response), Some(BINDING_SUCCESS));
+        let kinds = Vec::from_iter(attributes(&response).unwrap().iter().map(|&(kind, _)| kind));
+        assert_eq!(kinds, [0x8022, XOR_MAPPED_ADDRESS, 0x0008, 0x8028]);
+        assert_eq!(attributes(&response).unwrap()[0].1, b"test vector");
+        assert_eq!(parse_response(&response, &transaction()), Some(mapped()));
+        // Only IPv4 addresses are of use.
+        let response = hex::decode(IPV6_RESPONSE).unwrap();
+        assert_eq!(attributes(&response).unwrap().len(), 4);
+        assert_eq!(parse_response(&response, &transaction()), None);
+    }
+
+    #[test]
+    fn xor_mapped_addresses() {
+        let value = xor_ipv4_address(mapped());
+        assert_eq!(hex::encode(value), "0001a147e112a643");
+        assert_eq!(ipv4_address(&value, true), Some(mapped()));
+        // Without the XOR, the same bytes name another address.
+        assert_eq!(
+            ipv4_address(&value, false),
+            Some("225.18.166.67:41287".parse().unwrap())
+        );
+        for addr in ["0.0.0.0:0", "255.255.255.255:65535", "10.1.2.3:443"] {
+            let addr = addr.parse().unwrap();
+            assert_eq!(ipv4_address(&xor_ipv4_address(addr), true), Some(addr));
+        }
+        assert_eq!(ipv4_address(&value[..7], true), None);
+        assert_eq!(ipv4_address(&[0, 2, 0, 0, 0, 0, 0, 0], true), None);
+    }
+
+    #[test]
+    fn prefers_xor_mapped_addresses() {
+        let old = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1);
+        let mut plain = [0, FAMILY_IPV4, 0, 1, 10, 0, 0, 1];
+        let response = success(
+            &transaction(),
+            &[
+                (MAPPED_ADDRESS, &plain),
+                (XOR_MAPPED_ADDRESS, &xor_ipv4_address(mapped())),
+            ],
+        );
+        assert_eq!(parse_response(&response, &transaction()), Some(mapped()));
+        let response = success(&transaction(), &[(MAPPED_ADDRESS, &plain)]);
+        assert_eq!(parse_response(&response, &transaction()), Some(old));
+        plain[1] = 2;
+        let response = success(&transaction(), &[(MAPPED_ADDRESS, &plain)]);
+        assert_eq!(parse_response(&response, &transaction()), None);
+    }
+
+    #[test]
+    fn refuses_other_messages() {
+        let response = hex::decode(IPV4_RESPONSE).unwrap();
+        assert_eq!(parse_response(&response, &[0; 12]), None);
+        // A request, an error response, not STUN, and no magic cookie.
+        for (at, byte) in [(0, 0x00), (1, 0x11), (0, 0x41), (4, 0x00)] {
+            let mut other = response.clone();
+            other[at] = byte;
+            assert_eq!(
+                parse_response(&other, &transaction()),
+                None,
+                "{at}: {byte:#x}"
+            );
+        }
+        assert_eq!(message_type(b"d1:y1:qe"), None);
+        assert_eq!(message_type(&response[..HEADER_BYTES - 1]), None);
+    }
+
+    #[test]
+    fn refuses_truncated_and_overlong_attributes() {
+        let response = hex::decode(IPV4_RESPONSE).unwrap();
+        // Cut anywhere, the message no longer holds what its length says.
+        for len in HEADER_BYTES..response.len() {
+            assert_eq!(attributes(&response[..len]), None, "{len} bytes");
+            assert_eq!(
+                parse_response(&response[..len], &transaction()),
+                None,
+                "{len} bytes"
+            );
+        }
+        // With the length fixed, a cut between attributes leaves the ones
+        // before it, and a cut inside one fails.
+        let cut = |length: usize| {
+            let mut cut = response[..HEADER_BYTES + length].to_vec();
+            cut[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+            attributes(&cut).map(|attributes| attributes.len())
+        };
+        assert_eq!(cut(16), Some(1));
+        assert_eq!(cut(28), Some(2));
+        for length in [8, 20, 24, 40, 56] {
+            assert_eq!(cut(length), None, "{length}");
+        }
+        // An attribute claiming more than the message holds.
+        let mut overlong = success(
+            &transaction(),
+            &[(XOR_MAPPED_ADDRESS, &xor_ipv4_address(mapped()))],
+        );
+        overlong[HEADER_BYTES + 3] = 200;
+        assert_eq!(attributes(&overlong), None);
+        assert_eq!(parse_response(&overlong, &transaction()), None);
+        // A message length past the datagram.
+        let mut overlong = response.clone();
+        overlong[3] += 4;
+        assert_eq!(parse_response(&overlong, &transaction()), None);
+        assert_eq!(attributes(&[]), None);
+    }
+
+    #[test]
+    fn discovers_the_mapped_address() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let answering = thread::spawn(move || {
+            let mut buf = [0u8; 576];
+            let (len, from) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(message_type(&buf[..len]), Some(BINDING_REQUEST));
+            let SocketAddr::V4(from) = from else {
+                unreachable!("bound to IPv4")
+            };
+            let transaction = buf[8..HEADER_BYTES].try_into().unwrap();
+            let answer = success(
+                transaction,
+                &[(XOR_MAPPED_ADDRESS, &xor_ipv4_address(from))],
+            );
+            server.send_to(&answer, from).unwrap();
+            from
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let binding = discover(&socket, &[server_addr.to_string()]).unwrap();
+        assert_eq!(binding.public, answering.join().unwrap());
+        assert_eq!(binding.mapping, None);
+    }
+}
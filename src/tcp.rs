@@ -0,0 +1,299 @@
+//! TCP fallback, for networks that drop UDP they do not know.
+//!
+//! Every node that bound its own hello socket also listens on the same port
+//! over TCP. A lookup loop whose peer stayed silent over UDP for a while
+//! connects to the peer's candidates there, and from then on datagrams to
+//! such an address go through the connection, each behind a two-byte
+//! big-endian length, and come back the same way. The layers above see
+//! datagrams as ever: the Noise session, acks and retransmissions run
+//! unchanged, so nothing above this layer knows whether a peer is reached
+//! over UDP or TCP.
+//!
+//! Peers that connect to us show up at the address of their end of the
+//! connection, which is not their hello port; our answers go back through
+//! the connection all the same.
+//!
+//! Behind a SOCKS5 proxy nothing listens, and connections go out through the
+//! proxy (see the `socks` module).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    socks::Proxy,
+    transport::{Bind, SharedTransport, Transport},
+};
+
+/// How long connecting to a candidate may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Bytes of a frame's length prefix.
+const LENGTH_BYTES: usize = 2;
+/// Most bytes waiting to be written to a connection; datagrams beyond it
+/// are dropped, as a full UDP socket would.
+const MAX_OUTBOUND_BYTES: usize = 256 * 1024;
+/// Most connections at a time; more are refused.
+const MAX_CONNECTIONS: usize = 64;
+/// Connections silent this long are closed.
+const IDLE: Duration = Duration::from_secs(300);
+
+struct Connection {
+    stream: TcpStream,
+    /// Bytes read but not yet a whole frame.
+    inbound: Vec<u8>,
+    /// Frames not yet written.
+    outbound: Vec<u8>,
+    heard: Instant,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            inbound: Vec::new(),
+            outbound: Vec::new(),
+            heard: Instant::now(),
+        })
+    }
+
+    /// Write what the socket takes of `outbound`.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.outbound.is_empty() {
+            match self.stream.write(&self.outbound) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.outbound.drain(..written);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read what has arrived, moving whole frames to `frames`. Fails once
+    /// the peer closed the connection.
+    fn fill(
+        &mut self,
+        from: SocketAddr,
+        frames: &mut VecDeque<(Vec<u8>, SocketAddr)>,
+    ) -> io::Result<()> {
+        let mut buf = [0u8; 16 * 1024];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => {
+                    self.inbound.extend_from_slice(&buf[..len]);
+                    self.heard = Instant::now();
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        while self.inbound.len() >= LENGTH_BYTES {
+            let len = usize::from(u16::from_be_bytes([self.inbound[0], self.inbound[1]]));
+            if self.inbound.len() < LENGTH_BYTES + len {
+                break;
+            }
+            let frame = self.inbound[LENGTH_BYTES..LENGTH_BYTES + len].to_vec();
+            self.inbound.drain(..LENGTH_BYTES + len);
+            frames.push_back((frame, from));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    connections: HashMap<SocketAddr, Connection>,
+    /// Frames read but not yet received.
+    frames: VecDeque<(Vec<u8>, SocketAddr)>,
+}
+
+/// Carries datagrams to the addresses it has connections to over TCP, and
+/// the rest over the transport beneath.
+pub(crate) struct TcpTransport {
+    inner: SharedTransport,
+    listeners: Vec<TcpListener>,
+    proxy: Option<Proxy>,
+    state: Mutex<State>,
+}
+
+impl TcpTransport {
+    /// Listen on `port` over TCP, on IPv6 and IPv4 where the host has them,
+    /// or where `bind` says; without a listener we still connect out.
+    pub(crate) fn listen(inner: SharedTransport, port: u16, bind: &Bind) -> Self {
+        let mut listeners = Vec::new();
+        let ips = match bind.ip {
+            Some(ip) => vec![ip],
+            None => vec![Ipv6Addr::UNSPECIFIED.into(), Ipv4Addr::UNSPECIFIED.into()],
+        };
+        for ip in ips {
+            // An IPv6 listener may hold the IPv4 port too.
+            let listener = bind
+                .tcp(ip, port)
+                .and_then(|listener| listener.set_nonblocking(true).map(|()| listener));
+            match listener {
+                Ok(listener) => listeners.push(listener),
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse && !listeners.is_empty() => {}
+                Err(err) => debug!("not listening over TCP on {ip}: {err}"),
+            }
+        }
+        if listeners.is_empty() {
+            warn!("not listening on TCP port {port}; peers cannot fall back to TCP");
+        } else {
+            info!("listening on TCP port {port} for peers that cannot use UDP");
+        }
+        Self {
+            inner,
+            listeners,
+            proxy: None,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Connect out through `proxy` only, listening nowhere.
+    pub(crate) fn through(inner: SharedTransport, proxy: Proxy) -> Self {
+        Self {
+            inner,
+            listeners: Vec::new(),
+            proxy: Some(proxy),
+            state: Mutex::default(),
+        }
+    }
+
+    /// Connect to `addr` over TCP, unless already connected, so that
+    /// datagrams to it go that way from now on.
+    pub(crate) fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        if self.carries(&addr) {
+            return Ok(());
+        }
+        let stream = match &self.proxy {
+            Some(proxy) => proxy.connect(addr).map_err(io::Error::other)?,
+            None => TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?,
+        };
+        self.attach(addr, stream)
+    }
+
+    /// Carry datagrams to `addr` over `stream`, connected elsewhere, such
+    /// as to an onion service.
+    pub(crate) fn attach(&self, addr: SocketAddr, stream: TcpStream) -> io::Result<()> {
+        let connection = Connection::new(stream)?;
+        let mut state = self.state.lock().unwrap();
+        if state.connections.len() >= MAX_CONNECTIONS {
+            return Err(io::Error::other("too many TCP connections"));
+        }
+        state.connections.entry(addr).or_insert(connection);
+        Ok(())
+    }
+
+    /// Whether datagrams to `addr` go over TCP.
+    pub(crate) fn carries(&self, addr: &SocketAddr) -> bool {
+        self.state.lock().unwrap().connections.contains_key(addr)
+    }
+
+    /// Take new connections, write what waits and read what came, closing
+    /// connections that broke or fell silent.
+    fn poll(&self, state: &mut State) {
+        for listener in &self.listeners {
+            loop {
+                match listener.accept() {
+                    Ok((stream, from)) => {
+                        let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+                        if state.connections.len() >= MAX_CONNECTIONS {
+                            debug!("refusing TCP connection from {from}: too many");
+                            continue;
+                        }
+                        match Connection::new(stream) {
+                            Ok(connection) => {
+                                debug!("{from} connected over TCP");
+                                state.connections.insert(from, connection);
+                            }
+                            Err(err) => debug!("TCP connection from {from} failed: {err}"),
+                        }
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        debug!("accepting a TCP connection failed: {err}");
+                        break;
+                    }
+                }
+            }
+        }
+        let State {
+            connections,
+            frames,
+        } = state;
+        connections.retain(|addr, connection| {
+            let result = connection
+                .flush()
+                .and_then(|()| connection.fill(*addr, frames));
+            match result {
+                Err(err) => {
+                    debug!("TCP connection with {addr} closed: {err}");
+                    false
+                }
+                Ok(()) if connection.heard.elapsed() >= IDLE => {
+                    debug!("closing TCP connection with {addr}, silent for {IDLE:?}");
+                    false
+                }
+                Ok(()) => true,
+            }
+        });
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let Some(connection) = state.connections.get_mut(&addr) else {
+            drop(state);
+            return self.inner.send_to(datagram, addr);
+        };
+        let len = u16::try_from(datagram.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "datagram too large"))?;
+        if connection.outbound.len() + LENGTH_BYTES + datagram.len() > MAX_OUTBOUND_BYTES {
+            debug!("TCP connection with {addr} is backed up; dropping a datagram");
+            return Ok(datagram.len());
+        }
+        connection.outbound.extend_from_slice(&len.to_be_bytes());
+        connection.outbound.extend_from_slice(datagram);
+        if let Err(err) = connection.flush() {
+            state.connections.remove(&addr);
+            return Err(err);
+        }
+        Ok(datagram.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let err = match self.inner.recv_from(buf) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => err,
+            result => return result,
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.frames.is_empty() {
+            self.poll(&mut state);
+        }
+        let Some((frame, from)) = state.frames.pop_front() else {
+            return Err(err);
+        };
+        // Like a UDP socket, cut what does not fit.
+        let len = frame.len().min(buf.len());
+        buf[..len].copy_from_slice(&frame[..len]);
+        Ok((len, from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
@@ -0,0 +1,394 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+/// Datagram path carrying dhtmsg traffic (hellos, acks, stream segments).
+///
+/// The node uses a non-blocking [`UdpSocket`] by default. Other transports,
+/// such as WebRTC data channels or WebSocket relays, can be injected with
+/// [`DhtMsgBuilder::transport`](crate::DhtMsgBuilder::transport) as long as
+/// they can address peers by `SocketAddr`.
+pub trait Transport: Send + Sync {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Receive one datagram without blocking; fails with
+    /// [`io::ErrorKind::WouldBlock`] when nothing is pending.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, datagram, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+pub(crate) type SharedTransport = Arc<dyn Transport>;
+
+/// Where the sockets the node binds itself go: to one source address, to
+/// one network interface, or both (see
+/// [`DhtMsgBuilder::bind_addr`](crate::DhtMsgBuilder::bind_addr)).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Bind {
+    pub(crate) ip: Option<IpAddr>,
+    pub(crate) interface: Option<String>,
+}
+
+impl Bind {
+    /// A UDP socket on `port` at our address if it is of the family of
+    /// `any`, or else at `any`.
+    pub(crate) fn udp(&self, any: IpAddr, port: u16) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind((self.ip_like(any), port))?;
+        self.to_device(&socket)?;
+        Ok(socket)
+    }
+
+    /// A TCP listener on `port`, bound as [`udp`](Self::udp) binds.
+    pub(crate) fn tcp(&self, any: IpAddr, port: u16) -> io::Result<TcpListener> {
+        let listener = TcpListener::bind((self.ip_like(any), port))?;
+        self.to_device(&listener)?;
+        Ok(listener)
+    }
+
+    fn ip_like(&self, any: IpAddr) -> IpAddr {
+        self.ip
+            .filter(|ip| ip.is_ipv4() == any.is_ipv4())
+            .unwrap_or(any)
+    }
+
+    /// The interface traffic is kept on: the one named, or else the one
+    /// that holds our address.
+    pub(crate) fn device(&self) -> Option<String> {
+        self.interface
+            .clone()
+            .or_else(|| self.ip.and_then(interface_of))
+    }
+
+    /// Keep what `socket` sends and receives on the interface named, with
+    /// `SO_BINDTODEVICE`.
+    #[cfg(target_os = "linux")]
+    fn to_device(&self, socket: &impl std::os::fd::AsRawFd) -> io::Result<()> {
+        match &self.interface {
+            Some(interface) => bind_to_device(socket.as_raw_fd(), interface),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn to_device<S>(&self, _socket: &S) -> io::Result<()> {
+        match &self.interface {
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "binding to an interface needs Linux",
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(fd: std::os::fd::RawFd, interface: &str) -> io::Result<()> {
+    // SAFETY: the name is passed with its length, and fd is a socket.
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr().cast(),
+            interface.len() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Keep the UDP socket of this process bound to `port` on every IPv4
+/// address on `interface`: for sockets a library binds without asking us,
+/// such as the DHT's. Fails unless exactly one socket matches, so that
+/// nothing is left unconfined because we confined the wrong one.
+///
+/// The socket is already bound, and may have sent, by the time it is found:
+/// whatever it sent before then followed the routing table.
+#[cfg(target_os = "linux")]
+pub(crate) fn confine_udp_port(port: u16, interface: &str) -> io::Result<()> {
+    let mut found = open_fds()?.into_iter().filter(|&fd| {
+        // SAFETY: both buffers are as large as the lengths passed, and the
+        // address is only read as IPv4 when the kernel says it is.
+        unsafe {
+            let mut addr: libc::sockaddr_storage = std::mem::zeroed();
+            let mut len = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            if libc::getsockname(fd, (&raw mut addr).cast(), &mut len) != 0
+                || i32::from(addr.ss_family) != libc::AF_INET
+            {
+                return false;
+            }
+            let addr = &*(&raw const addr).cast::<libc::sockaddr_in>();
+            let mut kind = 0i32;
+            let mut len = size_of::<i32>() as libc::socklen_t;
+            let typed = libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_TYPE,
+                (&raw mut kind).cast(),
+                &mut len,
+            ) == 0;
+            typed
+                && kind == libc::SOCK_DGRAM
+                && u16::from_be(addr.sin_port) == port
+                && addr.sin_addr.s_addr == u32::from(Ipv4Addr::UNSPECIFIED)
+        }
+    });
+    match (found.next(), found.next()) {
+        (Some(fd), None) => bind_to_device(fd, interface),
+        (None, _) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no UDP socket on port {port}"),
+        )),
+        (Some(_), Some(_)) => Err(io::Error::other(format!(
+            "several UDP sockets on port {port}"
+        ))),
+    }
+}
+
+/// The file descriptors open in this process: from `/proc` where it is
+/// mounted, or else every number below the limit on open files.
+#[cfg(target_os = "linux")]
+fn open_fds() -> io::Result<Vec<std::os::fd::RawFd>> {
+    if let Ok(entries) = std::fs::read_dir("/proc/self/fd") {
+        let mut fds = Vec::new();
+        for entry in entries {
+            if let Some(fd) = (entry?.file_name().to_str()).and_then(|name| name.parse().ok()) {
+                fds.push(fd);
+            }
+        }
+        return Ok(fds);
+    }
+    // SAFETY: the buffer is an rlimit, as getrlimit expects.
+    let limit = unsafe {
+        let mut limit: libc::rlimit = std::mem::zeroed();
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        limit.rlim_cur
+    };
+    let limit = std::os::fd::RawFd::try_from(limit).unwrap_or(std::os::fd::RawFd::MAX);
+    // SAFETY: F_GETFD only reads the flags of the descriptor, if open.
+    Ok((0..limit)
+        .filter(|&fd| unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1)
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn confine_udp_port(_port: u16, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface needs Linux",
+    ))
+}
+
+/// The interface holding `ip`, if any.
+#[cfg(unix)]
+fn interface_of(ip: IpAddr) -> Option<String> {
+    let mut addrs = std::ptr::null_mut();
+    // SAFETY: the list is only read until it is freed, and each address is
+    // read as the family it says it is.
+    unsafe {
+        if libc::getifaddrs(&mut addrs) != 0 {
+            return None;
+        }
+        let mut found = None;
+        let mut next = addrs;
+        while let Some(entry) = next.as_ref() {
+            next = entry.ifa_next;
+            let Some(addr) = entry.ifa_addr.as_ref() else {
+                continue;
+            };
+            let held = match i32::from(addr.sa_family) {
+                libc::AF_INET => {
+                    let addr = &*(entry.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::from(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(entry.ifa_addr as *const libc::sockaddr_in6);
+                    IpAddr::from(Ipv6Addr::from(addr.sin6_addr.s6_addr))
+                }
+                _ => continue,
+            };
+            if held == ip {
+                let name = std::ffi::CStr::from_ptr(entry.ifa_name);
+                found = Some(name.to_string_lossy().into_owned());
+                break;
+            }
+        }
+        libc::freeifaddrs(addrs);
+        found
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_of(_ip: IpAddr) -> Option<String> {
+    None
+}
+
+/// The hello socket: UDP on both IPv4 and IPv6 where the host has IPv6.
+///
+/// Where an IPv6 socket also takes IPv4 traffic (the Linux default), one
+/// socket serves both and IPv4 peers show up as mapped addresses, which are
+/// turned back into IPv4 ones so that a peer has one address whichever
+/// family we saw it on. Elsewhere a second, IPv4 socket shares the port.
+/// Bound to one address, the socket only takes that address's family.
+pub(crate) enum HelloSocket {
+    V4(UdpSocket),
+    /// Bound to one IPv6 address, and so IPv6 only.
+    V6(UdpSocket),
+    DualStack(UdpSocket),
+    Split {
+        v4: UdpSocket,
+        v6: UdpSocket,
+        /// Which socket to read first, alternating so that neither starves.
+        v6_first: AtomicBool,
+    },
+}
+
+impl HelloSocket {
+    /// Non-blocking sockets bound to `port` (any free port if 0) on all
+    /// addresses, or on the one of `bind`.
+    pub(crate) fn bind(port: u16, bind: &Bind) -> io::Result<Self> {
+        let socket = match bind.ip {
+            Some(IpAddr::V4(ip)) => Self::V4(bind.udp(ip.into(), port)?),
+            Some(IpAddr::V6(ip)) => Self::V6(bind.udp(ip.into(), port)?),
+            None => Self::bind_any(port, bind)?,
+        };
+        match &socket {
+            Self::V4(socket)
+            | Self::V6(socket)
+            | Self::DualStack(socket)
+            | Self::Split { v6: socket, .. } => {
+                socket.set_nonblocking(true)?;
+            }
+        }
+        Ok(socket)
+    }
+
+    fn bind_any(port: u16, bind: &Bind) -> io::Result<Self> {
+        let socket = match bind.udp(Ipv6Addr::UNSPECIFIED.into(), port) {
+            Ok(v6) => {
+                let port = v6.local_addr()?.port();
+                match bind.udp(Ipv4Addr::UNSPECIFIED.into(), port) {
+                    Ok(v4) => {
+                        v4.set_nonblocking(true)?;
+                        Self::Split {
+                            v4,
+                            v6,
+                            v6_first: AtomicBool::new(false),
+                        }
+                    }
+                    // The IPv6 socket holds the IPv4 port too.
+                    Err(err) if err.kind() == io::ErrorKind::AddrInUse => Self::DualStack(v6),
+                    Err(err) => return Err(err),
+                }
+            }
+            // No IPv6 on this host.
+            Err(_) => Self::V4(bind.udp(Ipv4Addr::UNSPECIFIED.into(), port)?),
+        };
+        Ok(socket)
+    }
+}
+
+impl Transport for HelloSocket {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match (self, addr) {
+            (Self::DualStack(socket), SocketAddr::V4(v4)) => {
+                let mapped = SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0);
+                socket.send_to(datagram, mapped)
+            }
+            (Self::Split { v4, .. }, SocketAddr::V4(_)) => v4.send_to(datagram, addr),
+            (Self::Split { v6, .. }, SocketAddr::V6(_)) => v6.send_to(datagram, addr),
+            (Self::V4(socket) | Self::V6(socket) | Self::DualStack(socket), _) => {
+                socket.send_to(datagram, addr)
+            }
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            Self::V4(socket) | Self::V6(socket) => socket.recv_from(buf),
+            Self::DualStack(socket) => {
+                let (len, from) = socket.recv_from(buf)?;
+                Ok((len, SocketAddr::new(from.ip().to_canonical(), from.port())))
+            }
+            Self::Split { v4, v6, v6_first } => {
+                let (first, second) = if v6_first.fetch_xor(true, Ordering::Relaxed) {
+                    (v6, v4)
+                } else {
+                    (v4, v6)
+                };
+                match first.recv_from(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => second.recv_from(buf),
+                    result => result,
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::V4(socket)
+            | Self::V6(socket)
+            | Self::DualStack(socket)
+            | Self::Split { v4: socket, .. } => socket.local_addr(),
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confines_the_one_socket_on_a_port() {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        confine_udp_port(port, "lo").unwrap();
+        // SAFETY: the buffer is as large as the length passed.
+        let device = unsafe {
+            let mut name = [0u8; libc::IFNAMSIZ];
+            let mut len = name.len() as libc::socklen_t;
+            let fd = std::os::fd::AsRawFd::as_raw_fd(&socket);
+            let got = libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                name.as_mut_ptr().cast(),
+                &mut len,
+            );
+            assert_eq!(got, 0);
+            std::ffi::CStr::from_bytes_until_nul(&name)
+                .unwrap()
+                .to_owned()
+        };
+        assert_eq!(device.to_str().unwrap(), "lo");
+
+        // Only a socket on every address is the DHT's.
+        let local = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = local.local_addr().unwrap().port();
+        let err = confine_udp_port(port, "lo").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,164 @@
+//! TUN interfaces, for carrying the IP packets of a small VPN between peers
+//! (see [`DhtMsg::send_packet`](crate::DhtMsg::send_packet)).
+//!
+//! Only Linux has them here: `/dev/net/tun`, without the packet information
+//! header, so reads and writes are bare IPv4 or IPv6 packets. Creating one
+//! and giving it an address needs `CAP_NET_ADMIN`.
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    net::Ipv4Addr,
+};
+
+/// A TUN interface, which goes away when this is dropped.
+///
+/// Reads block until the kernel routes a packet to the interface; reads and
+/// writes may happen on different threads at once.
+pub struct TunDevice {
+    file: File,
+    name: String,
+}
+
+impl TunDevice {
+    /// Create the interface `name`, such as `dhtmsg0`; a `%d` in it is
+    /// replaced by the first free number.
+    pub fn open(name: &str) -> io::Result<Self> {
+        open(name)
+    }
+
+    /// The interface's name, with any `%d` filled in.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Give the interface `addr` in a network of `prefix` bits, limit its
+    /// packets to `mtu` bytes, and bring it up.
+    pub fn configure(&self, addr: Ipv4Addr, prefix: u8, mtu: u32) -> io::Result<()> {
+        configure(&self.name, addr, prefix, mtu)
+    }
+
+    /// The next packet routed to the interface, into `buf`; returns its
+    /// length. Packets longer than `buf` are cut.
+    pub fn read_packet(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.file).read(buf)
+    }
+
+    /// Hand `packet` to the kernel as if it arrived on the interface.
+    pub fn write_packet(&self, packet: &[u8]) -> io::Result<()> {
+        (&self.file).write(packet).map(drop)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open(name: &str) -> io::Result<TunDevice> {
+    use std::os::fd::AsRawFd;
+
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")?;
+    let mut request = request(name)?;
+    request.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+    // SAFETY: TUNSETIFF reads and fills in an ifreq, which request is.
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut request) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The kernel wrote back the name it chose; it ends with a NUL.
+    let name = request
+        .ifr_name
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8 as char)
+        .collect();
+    Ok(TunDevice { file, name })
+}
+
+#[cfg(target_os = "linux")]
+fn configure(name: &str, addr: Ipv4Addr, prefix: u8, mtu: u32) -> io::Result<()> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    if prefix > 32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("/{prefix} is no IPv4 prefix"),
+        ));
+    }
+    // Interfaces are configured through any socket of their family.
+    // SAFETY: a new descriptor, owned from here on.
+    let socket = unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        OwnedFd::from_raw_fd(fd)
+    };
+    let control = |op: libc::Ioctl, request: &mut libc::ifreq| {
+        // SAFETY: each of these requests reads or fills in an ifreq.
+        if unsafe { libc::ioctl(socket.as_raw_fd(), op, request) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    let netmask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+    let mut request = request(name)?;
+    request.ifr_ifru.ifru_addr = sockaddr(addr);
+    control(libc::SIOCSIFADDR, &mut request)?;
+    request.ifr_ifru.ifru_netmask = sockaddr(Ipv4Addr::from(netmask));
+    control(libc::SIOCSIFNETMASK, &mut request)?;
+    request.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+    control(libc::SIOCSIFMTU, &mut request)?;
+    control(libc::SIOCGIFFLAGS, &mut request)?;
+    // SAFETY: SIOCGIFFLAGS just filled in the flags.
+    unsafe { request.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short };
+    control(libc::SIOCSIFFLAGS, &mut request)
+}
+
+/// An empty request about the interface `name`.
+#[cfg(target_os = "linux")]
+fn request(name: &str) -> io::Result<libc::ifreq> {
+    // SAFETY: all zeroes is a valid ifreq.
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    // One byte stays for the NUL.
+    if name.len() >= request.ifr_name.len() || name.contains('\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{name:?} is no interface name"),
+        ));
+    }
+    for (slot, byte) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *slot = byte as libc::c_char;
+    }
+    Ok(request)
+}
+
+#[cfg(target_os = "linux")]
+fn sockaddr(addr: Ipv4Addr) -> libc::sockaddr {
+    let addr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(addr).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: sockaddr_in is no larger than sockaddr, which the kernel reads
+    // as one for AF_INET.
+    unsafe { std::mem::transmute::<libc::sockaddr_in, libc::sockaddr>(addr) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open(_name: &str) -> io::Result<TunDevice> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TUN interfaces need Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn configure(_name: &str, _addr: Ipv4Addr, _prefix: u8, _mtu: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TUN interfaces need Linux",
+    ))
+}
@@ -0,0 +1,174 @@
+//! TCP connections carried over [`UtpStream`]s, one stream per connection
+//! on the channel [`CHANNEL`], for forwarding ports between peers.
+//!
+//! The side that opens the stream writes a request line
+//! `dhtmsg-tunnel <host>:<port>` naming where the other side should
+//! connect. The other side answers `ok` once it is connected, or
+//! `error <reason>` if it cannot or will not, and from then on the stream
+//! carries the connection's bytes both ways. Each side finishes the stream
+//! when its TCP connection stops sending, and stops sending on the TCP
+//! connection when the stream ends, so half-closed connections work.
+//!
+//! Applications that let clients pick the target, as a local SOCKS5 or
+//! HTTP proxy does, learn it with [`read_socks_request`] or
+//! [`read_http_connect`] and pass it on.
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    thread,
+};
+
+use crate::{UtpStream, socks};
+
+/// The channel tunnels are opened on
+/// ([`DhtMsg::channel`](crate::DhtMsg::channel)).
+pub const CHANNEL: &str = "tunnel";
+
+const REQUEST_PREFIX: &str = "dhtmsg-tunnel ";
+const OK: &str = "ok";
+const ERROR_PREFIX: &str = "error ";
+/// Longest request or answer line accepted, so a bogus peer cannot make us
+/// buffer forever.
+const MAX_LINE_BYTES: usize = 1024;
+/// Most header lines accepted from an HTTP proxy client, for the same
+/// reason.
+const MAX_HEADER_LINES: usize = 100;
+
+/// Ask the other end of `stream` to connect to `target` (`host:port`) and
+/// wait for its answer. Fails with [`io::ErrorKind::ConnectionRefused`] and
+/// the peer's reason if it would not.
+pub fn request<S: Read + Write>(stream: &mut S, target: &str) -> io::Result<()> {
+    if target.contains('\n') {
+        return Err(invalid(format!("{target:?} is not a tunnel target")));
+    }
+    writeln!(stream, "{REQUEST_PREFIX}{target}")?;
+    let answer = read_line(stream)?;
+    if answer == OK {
+        return Ok(());
+    }
+    let reason = answer.strip_prefix(ERROR_PREFIX).unwrap_or(&answer);
+    Err(io::Error::new(
+        io::ErrorKind::ConnectionRefused,
+        reason.to_string(),
+    ))
+}
+
+/// Read the target a peer asks us to connect to; answer it with
+/// [`accept`] or [`refuse`].
+pub fn read_request<S: Read>(stream: &mut S) -> io::Result<String> {
+    let line = read_line(stream)?;
+    line.strip_prefix(REQUEST_PREFIX)
+        .map(str::to_string)
+        .ok_or_else(|| invalid(format!("{line:?} is not a tunnel request")))
+}
+
+/// Tell the peer we connected where it asked.
+pub fn accept<S: Write>(stream: &mut S) -> io::Result<()> {
+    writeln!(stream, "{OK}")?;
+    stream.flush()
+}
+
+/// Tell the peer we will not or could not connect where it asked, and why.
+pub fn refuse<S: Write>(stream: &mut S, reason: &str) -> io::Result<()> {
+    writeln!(stream, "{ERROR_PREFIX}{}", reason.replace('\n', " "))?;
+    stream.flush()
+}
+
+/// Take the greeting and CONNECT request of a SOCKS5 client, returning the
+/// `host:port` it asks for; answer it with [`answer_socks`] once the tunnel
+/// stands or failed. Names are left for the other end to resolve.
+pub fn read_socks_request<S: Read + Write>(client: &mut S) -> io::Result<String> {
+    socks::read_connect(client)
+}
+
+/// Tell a SOCKS5 client that its connection stands (`None`) or why it does
+/// not, after [`read_socks_request`].
+pub fn answer_socks<S: Write>(client: &mut S, error: Option<&io::Error>) -> io::Result<()> {
+    socks::reply(client, error)
+}
+
+/// Take the `CONNECT host:port HTTP/1.x` request of an HTTP proxy client
+/// and its headers, returning the `host:port`; answer it with
+/// [`answer_http`]. Other methods are turned down here, since a proxy for
+/// tunnels has nothing to forward them to.
+pub fn read_http_connect<S: Read + Write>(client: &mut S) -> io::Result<String> {
+    let request = read_line(client)?;
+    let mut headers = 0;
+    while !read_line(client)?.trim_end_matches('\r').is_empty() {
+        headers += 1;
+        if headers == MAX_HEADER_LINES {
+            return Err(invalid("too many HTTP headers".to_string()));
+        }
+    }
+    let mut words = request.trim_end_matches('\r').split(' ');
+    match (words.next(), words.next(), words.next()) {
+        (Some("CONNECT"), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            Ok(target.to_string())
+        }
+        (Some(_), Some(_), Some(_)) => {
+            client.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\n\r\n")?;
+            Err(invalid(format!("{request:?} is no CONNECT request")))
+        }
+        _ => Err(invalid(format!("{request:?} is no HTTP request"))),
+    }
+}
+
+/// Tell an HTTP proxy client that its tunnel stands (`None`) or why it does
+/// not, after [`read_http_connect`].
+pub fn answer_http<S: Write>(client: &mut S, error: Option<&io::Error>) -> io::Result<()> {
+    let status = match error.map(io::Error::kind) {
+        None => "200 Connection established",
+        Some(io::ErrorKind::PermissionDenied) => "403 Forbidden",
+        Some(io::ErrorKind::TimedOut) => "504 Gateway Timeout",
+        Some(_) => "502 Bad Gateway",
+    };
+    write!(client, "HTTP/1.1 {status}\r\n\r\n")?;
+    client.flush()
+}
+
+/// Copy bytes both ways between `stream` and `tcp` until both directions
+/// ended. Returns the bytes sent to the peer and received from it.
+pub fn splice(stream: &UtpStream, tcp: &TcpStream) -> io::Result<(u64, u64)> {
+    thread::scope(|scope| {
+        let outbound = scope.spawn(|| {
+            let sent = io::copy(&mut &*tcp, &mut &*stream);
+            stream.finish();
+            sent
+        });
+        let received = io::copy(&mut &*stream, &mut &*tcp);
+        // A broken stream also stops the copy the other way.
+        let how = if received.is_ok() {
+            Shutdown::Write
+        } else {
+            Shutdown::Both
+        };
+        let _ = tcp.shutdown(how);
+        let sent = outbound.join().expect("tunnel copy panicked");
+        Ok((sent?, received?))
+    })
+}
+
+/// One line, without its newline, read a byte at a time so that nothing
+/// after it is consumed.
+fn read_line<S: Read>(stream: &mut S) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() == MAX_LINE_BYTES {
+            return Err(invalid("tunnel line too long".to_string()));
+        }
+        line.push(byte[0]);
+    }
+    String::from_utf8(line).map_err(|_| invalid("tunnel line is not UTF-8".to_string()))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
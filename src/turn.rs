@@ -0,0 +1,681 @@
+//! TURN (RFC 5766) over UDP, for networks that already run TURN servers:
+//! a relayed address on the server, published as one more candidate, for
+//! when neither a direct path nor a dhtmsg relay (see the `relay` module)
+//! gets through.
+//!
+//! The allocation is made from the hello socket at startup, with the
+//! server's long-term credentials (RFC 5389 section 10.2), and refreshed
+//! for as long as the node runs. Peers need no TURN of their own: to them
+//! the relayed address is an address like any other. The server only
+//! passes on datagrams from IP addresses we gave a permission, so every
+//! address we send to gets one, which covers the peers we look up and
+//! those that ask us to punch holes. Peers heard through the server are
+//! answered through it, in Send indications, since their NATs may only
+//! let the server's address in.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    hmac::{self, hmac_sha1},
+    md5::{self, md5},
+    stun::{self, HEADER_BYTES, MAGIC_COOKIE},
+    transport::{SharedTransport, Transport},
+};
+
+const ALLOCATE: u16 = 0x0003;
+const REFRESH: u16 = 0x0004;
+const SEND: u16 = 0x0006;
+const DATA: u16 = 0x0007;
+const CREATE_PERMISSION: u16 = 0x0008;
+/// Message classes, added to the method.
+const INDICATION: u16 = 0x0010;
+const SUCCESS: u16 = 0x0100;
+const ERROR: u16 = 0x0110;
+
+const USERNAME: u16 = 0x0006;
+const MESSAGE_INTEGRITY: u16 = 0x0008;
+const ERROR_CODE: u16 = 0x0009;
+const LIFETIME: u16 = 0x000D;
+const XOR_PEER_ADDRESS: u16 = 0x0012;
+const DATA_ATTRIBUTE: u16 = 0x0013;
+const REALM: u16 = 0x0014;
+const NONCE: u16 = 0x0015;
+const XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const REQUESTED_TRANSPORT: u16 = 0x0019;
+const UDP: u8 = 17;
+const MESSAGE_INTEGRITY_BYTES: usize = 4 + 20;
+
+const UNAUTHORIZED: u16 = 401;
+const STALE_NONCE: u16 = 438;
+
+/// Lifetime asked for; the allocation is refreshed at half of what the
+/// server grants.
+const LEASE: Duration = Duration::from_secs(600);
+/// How long permissions last on the server, and how often those still in
+/// use are renewed.
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const PERMISSION_REFRESH: Duration = Duration::from_secs(240);
+/// Wait this long for a permission before asking again.
+const PERMISSION_RETRY: Duration = Duration::from_secs(1);
+/// Requests not answered by then are forgotten.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the node looks after the allocation and its permissions.
+pub(crate) const TICK: Duration = Duration::from_secs(30);
+
+/// Requests are sent again after each of these, counted from the first.
+const RETRIES: [Duration; 2] = [Duration::from_millis(500), Duration::from_millis(1500)];
+const TIMEOUT: Duration = Duration::from_millis(2500);
+const POLL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum TurnError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("TURN server {0} has no IPv4 address")]
+    Resolve(String),
+    #[error("TURN server {0} did not answer")]
+    NoAnswer(SocketAddr),
+    #[error("TURN server {server} refused with {code} {reason}")]
+    Refused {
+        server: SocketAddr,
+        code: u16,
+        reason: String,
+    },
+    #[error("TURN server {0} sent a malformed or forged answer")]
+    Malformed(SocketAddr),
+}
+
+type Result<T> = std::result::Result<T, TurnError>;
+
+/// A TURN server and our account on it.
+#[derive(Debug, Clone)]
+pub(crate) struct Credentials {
+    /// `host:port`.
+    pub(crate) server: String,
+    pub(crate) username: String,
+    pub(crate) password: String,
+}
+
+/// What requests are signed with.
+struct Auth {
+    username: String,
+    realm: Vec<u8>,
+    nonce: Vec<u8>,
+    key: [u8; md5::DIGEST_BYTES],
+}
+
+/// An allocation made at startup.
+pub(crate) struct Allocation {
+    server: SocketAddr,
+    relayed: SocketAddrV4,
+    lifetime: Duration,
+    auth: Auth,
+}
+
+/// Allocate a relayed address from `transport`, which nothing else may be
+/// reading yet.
+pub(crate) fn allocate(transport: &dyn Transport, credentials: &Credentials) -> Result<Allocation> {
+    let server = credentials
+        .server
+        .as_str()
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.find(SocketAddr::is_ipv4))
+        .ok_or_else(|| TurnError::Resolve(credentials.server.clone()))?;
+    let transport_attribute = [UDP, 0, 0, 0];
+    let lifetime = lifetime_value(LEASE);
+    let attributes: [(u16, &[u8]); 2] = [
+        (REQUESTED_TRANSPORT, &transport_attribute),
+        (LIFETIME, &lifetime),
+    ];
+
+    // The first request is turned down with the realm and a nonce to sign
+    // the next with.
+    let answer = exchange(transport, server, |transaction| {
+        message(ALLOCATE, transaction, &attributes, None)
+    })?;
+    let mut auth = match error_code(&answer) {
+        Some((UNAUTHORIZED, _)) => Auth {
+            key: md5(&[
+                credentials.username.as_bytes(),
+                b":",
+                attribute(&answer, REALM).ok_or(TurnError::Malformed(server))?,
+                b":",
+                credentials.password.as_bytes(),
+            ]),
+            username: credentials.username.clone(),
+            realm: attribute(&answer, REALM).unwrap_or_default().to_vec(),
+            nonce: attribute(&answer, NONCE)
+                .ok_or(TurnError::Malformed(server))?
+                .to_vec(),
+        },
+        Some((code, reason)) => {
+            return Err(TurnError::Refused {
+                server,
+                code,
+                reason,
+            });
+        }
+        None => return Err(TurnError::Malformed(server)),
+    };
+    let mut answer = Vec::new();
+    for _ in 0..2 {
+        answer = exchange(transport, server, |transaction| {
+            message(ALLOCATE, transaction, &attributes, Some(&auth))
+        })?;
+        match error_code(&answer) {
+            Some((STALE_NONCE, _)) => {
+                auth.nonce = attribute(&answer, NONCE)
+                    .ok_or(TurnError::Malformed(server))?
+                    .to_vec();
+            }
+            _ => break,
+        }
+    }
+    if let Some((code, reason)) = error_code(&answer) {
+        return Err(TurnError::Refused {
+            server,
+            code,
+            reason,
+        });
+    }
+    if stun::message_type(&answer) != Some(ALLOCATE | SUCCESS) || !authentic(&answer, &auth.key) {
+        return Err(TurnError::Malformed(server));
+    }
+    let relayed = attribute(&answer, XOR_RELAYED_ADDRESS)
+        .and_then(|value| stun::ipv4_address(value, true))
+        .ok_or(TurnError::Malformed(server))?;
+    Ok(Allocation {
+        server,
+        relayed,
+        lifetime: granted_lifetime(&answer).unwrap_or(LEASE),
+        auth,
+    })
+}
+
+/// Send the request `message` makes for a transaction until an answer to
+/// it comes.
+fn exchange(
+    transport: &dyn Transport,
+    server: SocketAddr,
+    message: impl Fn(&[u8; 12]) -> Vec<u8>,
+) -> Result<Vec<u8>> {
+    let transaction: [u8; 12] = rand::random();
+    let request = message(&transaction);
+    let start = Instant::now();
+    let mut retries = RETRIES.iter().map(|after| start + *after).peekable();
+    let mut buf = [0u8; 1500];
+    transport.send_to(&request, server)?;
+    while start.elapsed() < TIMEOUT {
+        if retries.next_if(|at| Instant::now() >= *at).is_some() {
+            transport.send_to(&request, server)?;
+        }
+        match transport.recv_from(&mut buf) {
+            Ok((len, from)) => {
+                let answer = &buf[..len];
+                if from == server
+                    && stun::message_type(answer).is_some()
+                    && answer[8..HEADER_BYTES] == transaction
+                {
+                    return Ok(answer.to_vec());
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL),
+            Err(err) => debug!("TURN receive failed: {err}"),
+        }
+    }
+    Err(TurnError::NoAnswer(server))
+}
+
+/// A STUN message of `kind` with `attributes`, signed with `auth` if given.
+fn message(
+    kind: u16,
+    transaction: &[u8; 12],
+    attributes: &[(u16, &[u8])],
+    auth: Option<&Auth>,
+) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_BYTES + 64);
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&[0, 0]);
+    message.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    message.extend_from_slice(transaction);
+    for (kind, value) in attributes {
+        push_attribute(&mut message, *kind, value);
+    }
+    if let Some(auth) = auth {
+        push_attribute(&mut message, USERNAME, auth.username.as_bytes());
+        push_attribute(&mut message, REALM, &auth.realm);
+        push_attribute(&mut message, NONCE, &auth.nonce);
+        // The integrity covers the header with the length it ends up with.
+        set_length(&mut message, MESSAGE_INTEGRITY_BYTES);
+        let mac = hmac_sha1(&auth.key, &message);
+        push_attribute(&mut message, MESSAGE_INTEGRITY, &mac);
+    } else {
+        set_length(&mut message, 0);
+    }
+    message
+}
+
+fn push_attribute(message: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    message.extend_from_slice(value);
+    message.resize(message.len().next_multiple_of(4), 0);
+}
+
+/// Set the header's length to what follows it, plus `extra` bytes.
+fn set_length(message: &mut [u8], extra: usize) {
+    let length = (message.len() - HEADER_BYTES + extra) as u16;
+    message[2..4].copy_from_slice(&length.to_be_bytes());
+}
+
+/// Whether the MESSAGE-INTEGRITY of `message`, if any, matches `key`;
+/// attributes after it are ignored.
+fn authentic(message: &[u8], key: &[u8]) -> bool {
+    let Some(attributes) = stun::attributes(message) else {
+        return false;
+    };
+    let mut offset = HEADER_BYTES;
+    for (kind, value) in attributes {
+        if kind == MESSAGE_INTEGRITY {
+            let mut signed = message[..offset].to_vec();
+            set_length(&mut signed, MESSAGE_INTEGRITY_BYTES);
+            return hmac::verify(&hmac_sha1(key, &signed), value);
+        }
+        offset += (4 + value.len()).next_multiple_of(4);
+    }
+    false
+}
+
+/// The first value of attribute `kind` in `message`.
+fn attribute(message: &[u8], kind: u16) -> Option<&[u8]> {
+    stun::attributes(message)?
+        .into_iter()
+        .find(|(found, _)| *found == kind)
+        .map(|(_, value)| value)
+}
+
+/// The code and reason of an error response.
+fn error_code(message: &[u8]) -> Option<(u16, String)> {
+    if stun::message_type(message)? & ERROR != ERROR {
+        return None;
+    }
+    let value = attribute(message, ERROR_CODE)?;
+    if value.len() < 4 {
+        return None;
+    }
+    let code = u16::from(value[2] & 0x07) * 100 + u16::from(value[3]);
+    Some((code, String::from_utf8_lossy(&value[4..]).into_owned()))
+}
+
+fn granted_lifetime(message: &[u8]) -> Option<Duration> {
+    let value: [u8; 4] = attribute(message, LIFETIME)?.try_into().ok()?;
+    Some(Duration::from_secs(u32::from_be_bytes(value).into()))
+}
+
+fn lifetime_value(lifetime: Duration) -> [u8; 4] {
+    u32::try_from(lifetime.as_secs())
+        .unwrap_or(u32::MAX)
+        .to_be_bytes()
+}
+
+/// What a request in flight asked for.
+#[derive(Clone, Copy)]
+enum Request {
+    Refresh(Duration),
+    Permission(Ipv4Addr),
+}
+
+struct Permission {
+    requested: Option<Instant>,
+    granted: Option<Instant>,
+    /// When we last sent to the address.
+    used: Instant,
+}
+
+struct State {
+    auth: Auth,
+    lifetime: Duration,
+    refreshed: Instant,
+    pending: HashMap<[u8; 12], (Request, Instant)>,
+    permissions: HashMap<Ipv4Addr, Permission>,
+    /// Peers heard through the server, answered through it, and when.
+    relayed_peers: HashMap<SocketAddr, Instant>,
+}
+
+/// Keeps an allocation on a TURN server, gives what we send to permissions
+/// on it, and answers peers heard through it through it.
+pub(crate) struct TurnTransport {
+    inner: SharedTransport,
+    server: SocketAddr,
+    relayed: SocketAddrV4,
+    state: Mutex<State>,
+}
+
+impl TurnTransport {
+    pub(crate) fn new(inner: SharedTransport, allocation: Allocation) -> Self {
+        Self {
+            inner,
+            server: allocation.server,
+            relayed: allocation.relayed,
+            state: Mutex::new(State {
+                auth: allocation.auth,
+                lifetime: allocation.lifetime,
+                refreshed: Instant::now(),
+                pending: HashMap::new(),
+                permissions: HashMap::new(),
+                relayed_peers: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Our address on the server, which peers send to.
+    pub(crate) fn relayed(&self) -> SocketAddrV4 {
+        self.relayed
+    }
+
+    /// Whether datagrams to `addr` go through the server.
+    pub(crate) fn carries(&self, addr: &SocketAddr) -> bool {
+        self.state.lock().unwrap().relayed_peers.contains_key(addr)
+    }
+
+    /// Refresh the allocation when half of it has run out, and permissions
+    /// still in use when they are about to; forget the others.
+    pub(crate) fn tick(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.refreshed.elapsed() >= state.lifetime / 2 {
+            self.request(&mut state, Request::Refresh(LEASE));
+        }
+        let mut renew = Vec::new();
+        state.permissions.retain(|ip, permission| {
+            if permission.used.elapsed() >= PERMISSION_LIFETIME {
+                return false;
+            }
+            if permission
+                .granted
+                .is_some_and(|granted| granted.elapsed() >= PERMISSION_REFRESH)
+            {
+                renew.push(*ip);
+            }
+            true
+        });
+        for ip in renew {
+            self.request(&mut state, Request::Permission(ip));
+        }
+        state
+            .pending
+            .retain(|_, (_, sent)| sent.elapsed() < ANSWER_TIMEOUT);
+        state
+            .relayed_peers
+            .retain(|_, heard| heard.elapsed() < PERMISSION_LIFETIME);
+    }
+
+    /// Give the allocation up, on shutdown.
+    pub(crate) fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.request(&mut state, Request::Refresh(Duration::ZERO));
+    }
+
+    fn request(&self, state: &mut State, request: Request) {
+        let transaction: [u8; 12] = rand::random();
+        let message = match request {
+            Request::Refresh(lifetime) => message(
+                REFRESH,
+                &transaction,
+                &[(LIFETIME, &lifetime_value(lifetime))],
+                Some(&state.auth),
+            ),
+            Request::Permission(ip) => {
+                let peer = stun::xor_ipv4_address(SocketAddrV4::new(ip, 0));
+                message(
+                    CREATE_PERMISSION,
+                    &transaction,
+                    &[(XOR_PEER_ADDRESS, &peer)],
+                    Some(&state.auth),
+                )
+            }
+        };
+        if let Request::Permission(ip) = request
+            && let Some(permission) = state.permissions.get_mut(&ip)
+        {
+            permission.requested = Some(Instant::now());
+        }
+        state.pending.insert(transaction, (request, Instant::now()));
+        if let Err(err) = self.inner.send_to(&message, self.server) {
+            debug!("TURN request to {} failed: {err}", self.server);
+        }
+    }
+
+    /// Make sure the server lets datagrams from `ip` through.
+    fn permit(&self, ip: Ipv4Addr) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let permission = state.permissions.entry(ip).or_insert(Permission {
+            requested: None,
+            granted: None,
+            used: now,
+        });
+        permission.used = now;
+        let ask = permission.granted.is_none()
+            && (permission.requested).is_none_or(|at| at.elapsed() >= PERMISSION_RETRY);
+        if ask {
+            self.request(&mut state, Request::Permission(ip));
+        }
+    }
+
+    /// Handle the STUN message in `buf[..len]` from the server; a Data
+    /// indication's datagram is moved to the start of `buf` and its length
+    /// returned with the peer that sent it.
+    fn handle(&self, buf: &mut [u8], len: usize) -> Option<(usize, SocketAddr)> {
+        let message = &buf[..len];
+        let kind = stun::message_type(message)?;
+        if kind == DATA | INDICATION {
+            let peer = attribute(message, XOR_PEER_ADDRESS)
+                .and_then(|value| stun::ipv4_address(value, true))?;
+            let data = attribute(message, DATA_ATTRIBUTE)?;
+            let (start, data_len) = (
+                data.as_ptr() as usize - message.as_ptr() as usize,
+                data.len(),
+            );
+            let peer = SocketAddr::V4(peer);
+            self.state
+                .lock()
+                .unwrap()
+                .relayed_peers
+                .insert(peer, Instant::now());
+            buf.copy_within(start..start + data_len, 0);
+            return Some((data_len, peer));
+        }
+        let transaction: [u8; 12] = message[8..HEADER_BYTES].try_into().ok()?;
+        let mut state = self.state.lock().unwrap();
+        let (request, _) = state.pending.remove(&transaction)?;
+        match error_code(message) {
+            None if authentic(message, &state.auth.key) => match request {
+                Request::Refresh(lifetime) if lifetime.is_zero() => {}
+                Request::Refresh(_) => {
+                    state.refreshed = Instant::now();
+                    state.lifetime = granted_lifetime(message).unwrap_or(LEASE);
+                }
+                Request::Permission(ip) => {
+                    if let Some(permission) = state.permissions.get_mut(&ip) {
+                        if permission.granted.is_none() {
+                            debug!("TURN server {} lets {ip} through", self.server);
+                        }
+                        permission.granted = Some(Instant::now());
+                    }
+                }
+            },
+            None => debug!("forged TURN answer from {} (dropped)", self.server),
+            Some((UNAUTHORIZED | STALE_NONCE, _)) => {
+                // Nonces expire; sign again with the new one.
+                if let Some(nonce) = attribute(message, NONCE) {
+                    state.auth.nonce = nonce.to_vec();
+                    self.request(&mut state, request);
+                }
+            }
+            Some((code, reason)) => match request {
+                Request::Refresh(_) => {
+                    warn!(
+                        "TURN server {} refused to refresh our allocation: {code} {reason}",
+                        self.server
+                    )
+                }
+                Request::Permission(ip) => {
+                    debug!(
+                        "TURN server {} refused a permission for {ip}: {code} {reason}",
+                        self.server
+                    )
+                }
+            },
+        }
+        None
+    }
+}
+
+impl Transport for TurnTransport {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let SocketAddr::V4(peer) = addr else {
+            return self.inner.send_to(datagram, addr);
+        };
+        if addr == self.server {
+            return self.inner.send_to(datagram, addr);
+        }
+        self.permit(*peer.ip());
+        let relayed = self.state.lock().unwrap().relayed_peers.contains_key(&addr);
+        if !relayed {
+            return self.inner.send_to(datagram, addr);
+        }
+        let transaction: [u8; 12] = rand::random();
+        let peer = stun::xor_ipv4_address(peer);
+        let indication = message(
+            SEND | INDICATION,
+            &transaction,
+            &[(XOR_PEER_ADDRESS, &peer), (DATA_ATTRIBUTE, datagram)],
+            None,
+        );
+        self.inner.send_to(&indication, self.server)?;
+        Ok(datagram.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, from) = self.inner.recv_from(buf)?;
+            if from == self.server && stun::message_type(&buf[..len]).is_some() {
+                if let Some(delivered) = self.handle(buf, len) {
+                    return Ok(delivered);
+                }
+                continue;
+            }
+            // A peer heard directly is answered directly again.
+            let mut state = self.state.lock().unwrap();
+            if state.relayed_peers.remove(&from).is_some() {
+                info!("{from} reaches us directly, not through TURN any more");
+            }
+            return Ok((len, from));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 5769, section 2.4: a request with long-term authentication.
+    const SAMPLE_REQUEST: &str = "000100602112a44278ad3433c6ad72c029da412e\
+        00060012e3839ee38388e383aae38383e382afe382b90000\
+        0015001c662f2f3439396b39353464364f4c33346f4c39465354767936347341\
+        0014000b6578616d706c652e6f726700\
+        00080014f67024656dd64a3e02b8e0712e85c9a28ca89666";
+    const USERNAME_VALUE: &str = "\u{30DE}\u{30C8}\u{30EA}\u{30C3}\u{30AF}\u{30B9}";
+    /// The password after SASLprep.
+    const PASSWORD: &str = "TheMatrIX";
+
+    fn sample_key() -> [u8; md5::DIGEST_BYTES] {
+        md5(&[
+            USERNAME_VALUE.as_bytes(),
+            b":example.org:",
+            PASSWORD.as_bytes(),
+        ])
+    }
+
+    #[test]
+    fn rfc5769_message_integrity() {
+        let sample = hex::decode(SAMPLE_REQUEST).unwrap();
+        assert_eq!(stun::message_type(&sample), Some(0x0001));
+        assert_eq!(
+            attribute(&sample, USERNAME).unwrap(),
+            USERNAME_VALUE.as_bytes()
+        );
+        assert_eq!(attribute(&sample, REALM).unwrap(), b"example.org");
+        assert_eq!(
+            attribute(&sample, NONCE).unwrap(),
+            b"f//499k954d6OL34oL9FSTvy64sA"
+        );
+        let key = sample_key();
+        assert_eq!(hex::encode(key), "e8ca7ad59d5eb0518e312911d2dab2a9");
+        assert!(authentic(&sample, &key));
+
+        assert!(!authentic(&sample, &md5(&[b"someone:example.org:else"])));
+        for i in [0, 9, HEADER_BYTES + 5, sample.len() - 1] {
+            let mut tampered = sample.clone();
+            tampered[i] ^= 1;
+            assert!(!authentic(&tampered, &key), "byte {i}");
+        }
+        // No integrity at all.
+        let unsigned = message(0x0001, &[0; 12], &[(REALM, b"example.org")], None);
+        assert!(!authentic(&unsigned, &key));
+    }
+
+    #[test]
+    fn signs_what_it_checks() {
+        let auth = Auth {
+            username: USERNAME_VALUE.to_string(),
+            realm: b"example.org".to_vec(),
+            nonce: b"f//499k954d6OL34oL9FSTvy64sA".to_vec(),
+            key: sample_key(),
+        };
+        let transaction = [
+            0x78, 0xad, 0x34, 0x33, 0xc6, 0xad, 0x72, 0xc0, 0x29, 0xda, 0x41, 0x2e,
+        ];
+        let request = message(
+            ALLOCATE,
+            &transaction,
+            &[(LIFETIME, &lifetime_value(LEASE))],
+            Some(&auth),
+        );
+        assert_eq!(stun::message_type(&request), Some(ALLOCATE));
+        assert_eq!(
+            usize::from(u16::from_be_bytes([request[2], request[3]])),
+            request.len() - HEADER_BYTES
+        );
+        assert!(authentic(&request, &auth.key));
+        assert_eq!(granted_lifetime(&request), Some(LEASE));
+        assert_eq!(attribute(&request, NONCE).unwrap(), auth.nonce);
+    }
+
+    #[test]
+    fn reads_error_codes() {
+        let mut value = vec![0, 0, 4, 1];
+        value.extend_from_slice(b"Unauthorized");
+        let answer = message(ALLOCATE | ERROR, &[0; 12], &[(ERROR_CODE, &value)], None);
+        assert_eq!(
+            error_code(&answer),
+            Some((UNAUTHORIZED, "Unauthorized".to_string()))
+        );
+        let success = message(ALLOCATE | SUCCESS, &[0; 12], &[(ERROR_CODE, &value)], None);
+        assert_eq!(error_code(&success), None);
+    }
+}
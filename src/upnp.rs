@@ -0,0 +1,550 @@
+//! UPnP IGD port mapping: asking the home router to forward the hello port.
+//!
+//! The NAT picks the public port of each mapping itself, and the DHT only
+//! tells the one of its own socket, so the hello port we announce is often
+//! a guess. Routers that speak UPnP's Internet Gateway Device protocol
+//! forward a port on request instead. An SSDP search on the local network
+//! finds the router, its description names the control URL of its WAN
+//! connection service, and SOAP calls to that URL add the mapping and tell
+//! the external address. Mappings are leased; the node renews them.
+//!
+//! Only what this needs is implemented: HTTP/1.1 without keep-alive, and
+//! XML read by looking for tags.
+
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::portmap::{LEASE, Mapping};
+
+const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+/// How long routers get to answer the search.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long HTTP requests to the router may take.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(3);
+/// Services able to map ports, preferred first.
+const SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+/// Other external ports tried when the one asked for is taken.
+const PORT_ATTEMPTS: u32 = 3;
+
+/// UPnP error codes that change what is asked next.
+const CONFLICT_IN_MAPPING_ENTRY: u32 = 718;
+const ONLY_PERMANENT_LEASES_SUPPORTED: u32 = 725;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum UpnpError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("no UPnP gateway answered")]
+    NoGateway,
+    #[error("the gateway offers no WAN connection service")]
+    NoService,
+    #[error("{action} failed with UPnP error {code}")]
+    Action { action: &'static str, code: u32 },
+    #[error("unexpected answer from the gateway")]
+    Malformed,
+}
+
+type Result<T> = std::result::Result<T, UpnpError>;
+
+/// The WAN connection service of the router.
+pub(crate) struct Gateway {
+    host: SocketAddr,
+    control_path: String,
+    service: &'static str,
+    /// Our address on the router's network.
+    local_ip: Ipv4Addr,
+}
+
+impl Gateway {
+    /// Search the local network for a router and read its description.
+    pub(crate) fn find() -> Result<Self> {
+        let location = search()?;
+        debug!("UPnP gateway described at {location}");
+        let (host, path) = parse_url(&location).ok_or(UpnpError::Malformed)?;
+        let (_, description) = http(
+            host,
+            &format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n"),
+        )?;
+        let (service, control) = wan_service(&description).ok_or(UpnpError::NoService)?;
+        let (host, control_path) = if control.starts_with("http://") {
+            parse_url(control).ok_or(UpnpError::Malformed)?
+        } else if control.starts_with('/') {
+            (host, control.to_string())
+        } else {
+            (host, format!("/{control}"))
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.connect(host)?;
+        let IpAddr::V4(local_ip) = socket.local_addr()?.ip() else {
+            return Err(UpnpError::Malformed);
+        };
+        Ok(Self {
+            host,
+            control_path,
+            service,
+            local_ip,
+        })
+    }
+
+    /// Forward UDP `external_port` or, if it is taken, another one to
+    /// `local_port` of ours.
+    pub(crate) fn map(&self, local_port: u16, external_port: u16) -> Result<Mapping> {
+        let mut port = external_port;
+        let mut lease = LEASE;
+        let mut attempts = 0;
+        loop {
+            match self.add_mapping(port, local_port, lease) {
+                Ok(()) => break,
+                Err(UpnpError::Action { code, .. })
+                    if code == ONLY_PERMANENT_LEASES_SUPPORTED && !lease.is_zero() =>
+                {
+                    lease = Duration::ZERO;
+                }
+                Err(UpnpError::Action { code, .. })
+                    if code == CONFLICT_IN_MAPPING_ENTRY && attempts < PORT_ATTEMPTS =>
+                {
+                    attempts += 1;
+                    port = rand::random::<u16>().max(1024);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let external = SocketAddrV4::new(self.external_ip()?, port);
+        Ok(Mapping { external, lease })
+    }
+
+    /// Stop forwarding `external_port`.
+    pub(crate) fn unmap(&self, external_port: u16) -> Result<()> {
+        self.call(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", "UDP".to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn add_mapping(&self, external_port: u16, local_port: u16, lease: Duration) -> Result<()> {
+        self.call(
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", "UDP".to_string()),
+                ("NewInternalPort", local_port.to_string()),
+                ("NewInternalClient", self.local_ip.to_string()),
+                ("NewEnabled", "1".to_string()),
+                ("NewPortMappingDescription", "dhtmsg".to_string()),
+                ("NewLeaseDuration", lease.as_secs().to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn external_ip(&self) -> Result<Ipv4Addr> {
+        let answer = self.call("GetExternalIPAddress", &[])?;
+        tag(&answer, "NewExternalIPAddress")
+            .and_then(|ip| ip.trim().parse().ok())
+            .ok_or(UpnpError::Malformed)
+    }
+
+    /// Call `action` of the service with `args`, returning the answer.
+    fn call(&self, action: &'static str, args: &[(&str, String)]) -> Result<String> {
+        let service = self.service;
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body>\
+             </s:Envelope>"
+        );
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+             SOAPAction: \"{service}#{action}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.control_path,
+            self.host,
+            body.len()
+        );
+        let (status, answer) = http(self.host, &request)?;
+        if status == 200 {
+            return Ok(answer);
+        }
+        let code = tag(&answer, "errorCode")
+            .and_then(|code| code.trim().parse().ok())
+            .unwrap_or(u32::from(status));
+        Err(UpnpError::Action { action, code })
+    }
+}
+
+/// The location of the first router's description that answers an SSDP
+/// search.
+fn search() -> Result<String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDR}\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+         MAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n"
+    );
+    socket.send_to(request.as_bytes(), SSDP_ADDR)?;
+    let deadline = Instant::now() + SEARCH_TIMEOUT;
+    let mut buf = [0u8; 2048];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(UpnpError::NoGateway);
+        }
+        socket.set_read_timeout(Some(left))?;
+        let len = match socket.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(UpnpError::NoGateway);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let answer = String::from_utf8_lossy(&buf[..len]);
+        if let Some(location) = header(&answer, "location") {
+            return Ok(location.to_string());
+        }
+    }
+}
+
+/// The status and body of the answer to `request`, sent to `host`.
+fn http(host: SocketAddr, request: &str) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect_timeout(&host, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    stream.write_all(request.as_bytes())?;
+    let mut answer = Vec::new();
+    stream.read_to_end(&mut answer)?;
+    let answer = String::from_utf8_lossy(&answer);
+    let (head, body) = answer.split_once("\r\n\r\n").ok_or(UpnpError::Malformed)?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or(UpnpError::Malformed)?;
+    let body = if header(head, "transfer-encoding")
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+    {
+        dechunk(body).ok_or(UpnpError::Malformed)?
+    } else {
+        body.to_string()
+    };
+    Ok((status, body))
+}
+
+/// The value of header `name` in `head`, any case.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// A body sent in chunks, put back together.
+fn dechunk(mut body: &str) -> Option<String> {
+    let mut whole = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n")?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(whole);
+        }
+        whole.push_str(rest.get(..size)?);
+        body = rest.get(size..)?.strip_prefix("\r\n")?;
+    }
+}
+
+/// Host and path of an `http://` URL.
+fn parse_url(url: &str) -> Option<(SocketAddr, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let host = match authority.parse() {
+        Ok(host) => host,
+        Err(_) if authority.contains(':') => authority.to_socket_addrs().ok()?.next()?,
+        Err(_) => (authority, 80).to_socket_addrs().ok()?.next()?,
+    };
+    Some((host, path.to_string()))
+}
+
+/// The most preferred port mapping service in `description`, with its
+/// control URL.
+fn wan_service(description: &str) -> Option<(&'static str, &str)> {
+    let services: Vec<(&str, &str)> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|service| {
+            Some((
+                tag(service, "serviceType")?.trim(),
+                tag(service, "controlURL")?.trim(),
+            ))
+        })
+        .collect();
+    SERVICE_TYPES.iter().find_map(|&wanted| {
+        services
+            .iter()
+            .find(|(service, _)| *service == wanted)
+            .map(|&(_, control)| (wanted, control))
+    })
+}
+
+/// The text of the first `name` element in `xml`, with or without a
+/// namespace prefix.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("{name}>");
+    let mut rest = xml;
+    while let Some(at) = rest.find(&open) {
+        let before = &rest[..at];
+        rest = &rest[at + open.len()..];
+        // `<name>` or `<prefix:name>`, not `</name>` or `<othername>`.
+        let Some(start) = before.rfind('<') else {
+            continue;
+        };
+        let prefix = &before[start + 1..];
+        if prefix.is_empty() || (prefix.ends_with(':') && !prefix.starts_with('/')) {
+            let end = rest.find("</")?;
+            return Some(&rest[..end]);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    /// Abridged from the description of a common router.
+    const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+        <controlURL>/ctl/L3F</controlURL>
+      </service>
+    </serviceList>
+    <deviceList><device><deviceList><device>
+      <serviceList>
+        <service>
+          <serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>
+          <controlURL>/ctl/PPP</controlURL>
+        </service>
+        <service>
+          <serviceType>
+            urn:schemas-upnp-org:service:WANIPConnection:1
+          </serviceType>
+          <controlURL> /ctl/IPConn </controlURL>
+        </service>
+      </serviceList>
+    </device></deviceList></device></deviceList>
+  </device>
+</root>"#;
+
+    const SOAP_FAULT: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+<s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>
+<detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0">
+<errorCode>718</errorCode><errorDescription>ConflictInMappingEntry</errorDescription>
+</UPnPError></detail></s:Fault></s:Body></s:Envelope>"#;
+
+    #[test]
+    fn ssdp_answers() {
+        let answer = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+            ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+            Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(
+            header(answer, "location"),
+            Some("http://192.168.1.1:5000/rootDesc.xml")
+        );
+        assert_eq!(header(answer, "LOCATION"), header(answer, "location"));
+        assert_eq!(header(answer, "cache-control"), Some("max-age=120"));
+        assert_eq!(header(answer, "usn"), None);
+        assert_eq!(header("NOTIFY * HTTP/1.1", "location"), None);
+    }
+
+    #[test]
+    fn urls() {
+        let (host, path) = parse_url("http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(host, "192.168.1.1:5000".parse().unwrap());
+        assert_eq!(path, "/rootDesc.xml");
+        let (host, path) = parse_url("http://10.0.0.138").unwrap();
+        assert_eq!(host, "10.0.0.138:80".parse().unwrap());
+        assert_eq!(path, "/");
+        assert_eq!(parse_url("https://192.168.1.1/desc.xml"), None);
+        assert_eq!(parse_url("/ctl/IPConn"), None);
+        assert_eq!(parse_url("http://192.168.1.1:99999/"), None);
+    }
+
+    #[test]
+    fn descriptions() {
+        assert_eq!(
+            wan_service(DESCRIPTION),
+            Some((
+                "urn:schemas-upnp-org:service:WANIPConnection:1",
+                "/ctl/IPConn"
+            ))
+        );
+        // Without the IP connection, the PPP one.
+        let ppp_only = DESCRIPTION.replace("WANIPConnection", "WANIPv6FirewallControl");
+        assert_eq!(
+            wan_service(&ppp_only),
+            Some((
+                "urn:schemas-upnp-org:service:WANPPPConnection:1",
+                "/ctl/PPP"
+            ))
+        );
+        let none = ppp_only.replace("WANPPPConnection", "WANCommonInterfaceConfig");
+        assert_eq!(wan_service(&none), None);
+        // A service without a control URL is no use.
+        assert_eq!(
+            wan_service(&DESCRIPTION.replace("controlURL", "eventSubURL")),
+            None
+        );
+        assert_eq!(wan_service(""), None);
+    }
+
+    #[test]
+    fn tags() {
+        assert_eq!(tag(SOAP_FAULT, "errorCode"), Some("718"));
+        assert_eq!(tag(SOAP_FAULT, "faultcode"), Some("s:Client"));
+        // With a namespace prefix, but not a longer name or a closing tag.
+        assert_eq!(
+            tag(
+                "<u:NewExternalIPAddress>203.0.113.7</u:NewExternalIPAddress>",
+                "NewExternalIPAddress"
+            ),
+            Some("203.0.113.7")
+        );
+        assert_eq!(
+            tag("<othercode>1</othercode><code>2</code>", "code"),
+            Some("2")
+        );
+        assert_eq!(tag("</code><code>3</code>", "code"), Some("3"));
+        assert_eq!(tag("code> <code>4</code>", "code"), Some("4"));
+        assert_eq!(tag("<code>unterminated", "code"), None);
+        assert_eq!(tag("", "code"), None);
+    }
+
+    #[test]
+    fn chunked_bodies() {
+        assert_eq!(
+            dechunk("4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\n\r\n").unwrap(),
+            "Wikipedia"
+        );
+        assert_eq!(dechunk("A\r\n0123456789\r\n0\r\n").unwrap(), "0123456789");
+        for bad in [
+            "",
+            "4\r\nWik",
+            "4\r\nWikipedia\r\n0\r\n",
+            "x\r\nWiki\r\n0\r\n",
+            "4\r\nWiki",
+        ] {
+            assert_eq!(dechunk(bad), None, "{bad:?}");
+        }
+    }
+
+    /// A router on localhost that answers each of `answers` to one
+    /// connection, and hands back the requests.
+    fn router(answers: Vec<String>) -> (SocketAddr, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for answer in answers {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 4096];
+                let len = stream.read(&mut request).unwrap();
+                requests.push(String::from_utf8_lossy(&request[..len]).into_owned());
+                stream.write_all(answer.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (addr, serving)
+    }
+
+    fn ok(body: &str) -> String {
+        format!("HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\n\r\n{body}")
+    }
+
+    #[test]
+    fn http_answers() {
+        let (host, serving) = router(vec![
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n<a\r\n1\r\n>\r\n0\r\n\r\n"
+                .into(),
+            "HTTP/1.1 404 Not Found\r\n\r\n".into(),
+            "garbage".into(),
+        ]);
+        assert_eq!(
+            http(host, "GET / HTTP/1.1\r\n\r\n").unwrap(),
+            (200, "<a>".into())
+        );
+        assert_eq!(
+            http(host, "GET / HTTP/1.1\r\n\r\n").unwrap(),
+            (404, String::new())
+        );
+        assert!(matches!(
+            http(host, "GET / HTTP/1.1\r\n\r\n"),
+            Err(UpnpError::Malformed)
+        ));
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn maps_around_conflicts() {
+        let fault = format!("HTTP/1.1 500 Internal Server Error\r\n\r\n{SOAP_FAULT}");
+        let address = "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+            <NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>\
+            </u:GetExternalIPAddressResponse></s:Body></s:Envelope>";
+        let (host, serving) = router(vec![fault, ok(""), ok(address)]);
+        let gateway = Gateway {
+            host,
+            control_path: "/ctl/IPConn".into(),
+            service: SERVICE_TYPES[1],
+            local_ip: Ipv4Addr::new(192, 168, 1, 20),
+        };
+        let mapping = gateway.map(6881, 6881).unwrap();
+        assert_eq!(*mapping.external.ip(), Ipv4Addr::new(203, 0, 113, 7));
+        assert_ne!(mapping.external.port(), 6881);
+        assert_eq!(mapping.lease, LEASE);
+        let requests = serving.join().unwrap();
+        assert!(requests[0].starts_with("POST /ctl/IPConn HTTP/1.1\r\n"));
+        assert!(requests[0].contains(&format!(
+            "SOAPAction: \"{}#AddPortMapping\"",
+            SERVICE_TYPES[1]
+        )));
+        assert!(requests[0].contains("<NewExternalPort>6881</NewExternalPort>"));
+        assert!(requests[0].contains("<NewInternalClient>192.168.1.20</NewInternalClient>"));
+        let port = format!(
+            "<NewExternalPort>{}</NewExternalPort>",
+            mapping.external.port()
+        );
+        assert!(requests[1].contains(&port));
+        assert!(requests[2].contains("GetExternalIPAddress"));
+    }
+}
@@ -0,0 +1,1487 @@
+//! uTP (BEP 29) streams on the hello socket, for bulk data such as files.
+//!
+//! [`PeerStream`](crate::PeerStream)s keep at most 64 segments in flight,
+//! which leaves a fast path idle, and guess at queuing from round trips.
+//! uTP connections grow and shrink their window with LEDBAT (RFC 6817) with
+//! no such cap: the `timestamp_difference` every packet carries tells the
+//! sender how long its packets queue on the way, and the window grows while
+//! that stays under 100 ms and shrinks once it goes past, so a transfer
+//! takes what the path has to spare and no more. A new connection doubles its window every
+//! round trip until queuing or a loss shows up, and losses are found from
+//! selective acks, many in a round trip, rather than one timeout at a time.
+//! On the wire they are uTP like any BitTorrent client speaks, headers and
+//! all, below the session layer.
+//!
+//! Every packet also says how much room its sender has left for data,
+//! about 1 MiB less what waits unread, and nothing goes out past that: a
+//! receiver whose reader falls behind holds the sender back, and drops
+//! what would not fit. While the window is closed one packet stays in
+//! flight as a probe, resent every 2 s at most without counting as a
+//! timeout, so a slow reader stalls the connection without breaking it.
+//!
+//! The payload, though, is no one's business but the peer's: the bytes of
+//! a [`UtpStream`] go in records `[length u16 BE][ChaCha20-Poly1305 sealed]`,
+//! numbered from zero, under a key for each direction derived from the
+//! handshake hash of the Noise session with the peer and a random salt that
+//! leads the direction's bytes. Only peers we have a session with can open
+//! a stream, and only they can read it.
+//!
+//! A stream may belong to a channel, so that chat, files and tunnels each
+//! take theirs from a queue of their own. Every stream is a connection of
+//! its own anyway, with its own window and its own losses, so channels
+//! share the path and nothing else. A SYN with our `CHANNEL` extension says
+//! that the stream's first bytes are `[length u8][name]`: the name is
+//! sealed like the rest, and the uTP header only tells that there is one.
+//!
+//! Datagrams that are not uTP, or belong to no connection of ours, go on
+//! up unchanged; those of BitTorrent clients are dropped there as usual.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    aead,
+    channel::{self, Channel, Channels},
+    hmac::hmac,
+    shutdown::ShutdownHandle,
+    stream::Streams,
+    transport::{SharedTransport, Transport},
+};
+
+const HEADER_BYTES: usize = 20;
+const VERSION: u8 = 1;
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+/// Extension naming the packets received past the first one missing.
+const SELECTIVE_ACK: u8 = 1;
+/// Extension of ours, empty, on the SYNs of streams that belong to a
+/// channel; other clients skip it like any they do not know.
+const CHANNEL: u8 = 0x64;
+/// Most bytes of a selective ack bitmask, one bit per packet.
+const MAX_SELECTIVE_ACK_BYTES: usize = 128;
+/// Payload bytes per packet, within the MTU like stream segments.
+const PACKET_BYTES: usize = 1200;
+/// Queuing delay LEDBAT aims for.
+const TARGET_DELAY_MICROS: f64 = 100_000.0;
+/// Most the window grows by in a round trip, with no delay at all.
+const MAX_GROWTH_BYTES: f64 = 3000.0;
+const MIN_WINDOW: f64 = 2.0 * PACKET_BYTES as f64;
+const MAX_WINDOW: f64 = 4.0 * 1024.0 * 1024.0;
+/// How long the smallest delay seen counts as the path's own; it is
+/// measured anew after that, in case the route changed.
+const BASE_DELAY_WINDOW: Duration = Duration::from_secs(60);
+/// Bytes written but not yet sent before writers block, and bytes received
+/// but not yet read before the window we advertise closes.
+const SEND_BUFFER_BYTES: usize = 1024 * 1024;
+const RECV_BUFFER_BYTES: usize = 1024 * 1024;
+/// Packets held that arrived ahead of a lost one.
+const MAX_REORDERED: usize = 1024;
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest wait between probes of a window the peer keeps closed.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Timeouts in a row before a connection is given up.
+const MAX_TIMEOUTS: u32 = 8;
+/// Duplicate acks, or packets selectively acked after it, that make a
+/// packet count as lost.
+const DUPLICATE_ACKS: u32 = 3;
+const TICK: Duration = Duration::from_millis(50);
+/// Connections opened by peers waiting for `accept_utp_stream()`, or for
+/// a name when on a channel.
+const ACCEPT_QUEUE: usize = 16;
+
+/// Plaintext bytes per record.
+const RECORD_BYTES: usize = 16 * 1024;
+const LENGTH_BYTES: usize = 2;
+const SALT_BYTES: usize = 16;
+const KEY_CONTEXT: &[u8] = b"dhtmsg utp";
+
+/// Whether `a` comes before `b` among sequence numbers that wrap.
+fn before(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+/// Whether the selective ack bitmask of a packet acking `ack` says that
+/// packet `seq` arrived.
+fn selectively_acked(selective_ack: &[u8], ack: u16, seq: u16) -> bool {
+    let bit = usize::from(seq.wrapping_sub(ack).wrapping_sub(2));
+    selective_ack
+        .get(bit / 8)
+        .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    kind: u8,
+    connection: u16,
+    timestamp: u32,
+    difference: u32,
+    window: u32,
+    seq: u16,
+    ack: u16,
+    /// Whether the packet carries the `CHANNEL` extension.
+    channel: bool,
+}
+
+impl Header {
+    /// The header of `packet`, its selective ack bitmask if any, and its
+    /// payload after the extensions.
+    fn parse(packet: &[u8]) -> Option<(Self, &[u8], &[u8])> {
+        let header = packet.get(..HEADER_BYTES)?;
+        if header[0] & 0x0f != VERSION || header[0] >> 4 > ST_SYN {
+            return None;
+        }
+        let u16_at = |at: usize| u16::from_be_bytes([header[at], header[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes(header[at..at + 4].try_into().unwrap());
+        // Each extension names the next: `[next u8][length u8][data]`.
+        let mut next = header[1];
+        let mut rest = &packet[HEADER_BYTES..];
+        let mut selective_ack: &[u8] = &[];
+        let mut channel = false;
+        while next != 0 {
+            let (&[following, len], tail) = rest.split_first_chunk::<2>()?;
+            let (data, tail) = tail.split_at_checked(usize::from(len))?;
+            match next {
+                SELECTIVE_ACK => selective_ack = data,
+                CHANNEL => channel = true,
+                _ => {}
+            }
+            next = following;
+            rest = tail;
+        }
+        let parsed = Self {
+            kind: header[0] >> 4,
+            connection: u16_at(2),
+            timestamp: u32_at(4),
+            difference: u32_at(8),
+            window: u32_at(12),
+            seq: u16_at(16),
+            ack: u16_at(18),
+            channel,
+        };
+        Some((parsed, selective_ack, rest))
+    }
+
+    fn encode(&self, selective_ack: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut extensions: Vec<(u8, &[u8])> = Vec::new();
+        if self.channel {
+            extensions.push((CHANNEL, &[]));
+        }
+        if !selective_ack.is_empty() {
+            extensions.push((SELECTIVE_ACK, selective_ack));
+        }
+        let mut packet = Vec::with_capacity(HEADER_BYTES + 4 + selective_ack.len() + payload.len());
+        packet.push(self.kind << 4 | VERSION);
+        packet.push(extensions.first().map_or(0, |&(kind, _)| kind));
+        packet.extend_from_slice(&self.connection.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.difference.to_be_bytes());
+        packet.extend_from_slice(&self.window.to_be_bytes());
+        packet.extend_from_slice(&self.seq.to_be_bytes());
+        packet.extend_from_slice(&self.ack.to_be_bytes());
+        for (index, (_, data)) in extensions.iter().enumerate() {
+            let next = extensions.get(index + 1).map_or(0, |&(kind, _)| kind);
+            packet.extend_from_slice(&[next, data.len() as u8]);
+            packet.extend_from_slice(data);
+        }
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    SynSent,
+    Connected,
+}
+
+/// A packet sent and not yet acknowledged.
+struct Packet {
+    kind: u8,
+    seq: u16,
+    payload: Vec<u8>,
+    sent: Instant,
+    transmissions: u32,
+}
+
+struct State {
+    phase: Phase,
+    /// Why the connection failed, once it did.
+    broken: Option<&'static str>,
+    /// Sequence number of the next packet we send.
+    seq: u16,
+    /// Bytes written but not yet in a packet.
+    unsent: VecDeque<u8>,
+    in_flight: VecDeque<Packet>,
+    fin_queued: bool,
+    fin_sent: bool,
+    /// LEDBAT's window, in bytes.
+    window: f64,
+    /// Whether the window still doubles every round trip, as it does
+    /// until the first loss or queuing delay near the target.
+    slow_start: bool,
+    /// What the peer last said it has room for.
+    peer_window: usize,
+    /// When the peer's last packet arrived.
+    heard: Option<Instant>,
+    rtt: Option<Duration>,
+    rtt_variance: Duration,
+    timeout: Duration,
+    /// Timeouts since the last ack that moved on.
+    timeouts: u32,
+    last_ack: u16,
+    duplicate_acks: u32,
+    /// When the window last shrank for a loss; it does once a round trip.
+    last_loss: Option<Instant>,
+    /// Smallest delays seen in this base delay window and the one before.
+    base_delays: [u32; 2],
+    base_since: Instant,
+    /// How long the peer's last packet took, by the clocks of both, which
+    /// we send back for its LEDBAT.
+    reply_micros: u32,
+    /// Sequence number of the last packet received in order.
+    ack: u16,
+    reordered: HashMap<u16, (u8, Vec<u8>)>,
+    readable: VecDeque<u8>,
+    eof: bool,
+    /// Live handles; the last one to drop finishes our side.
+    handles: usize,
+}
+
+impl State {
+    fn new(phase: Phase, seq: u16, ack: u16) -> Self {
+        Self {
+            phase,
+            broken: None,
+            seq,
+            unsent: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            fin_queued: false,
+            fin_sent: false,
+            window: MIN_WINDOW,
+            slow_start: true,
+            peer_window: RECV_BUFFER_BYTES,
+            heard: None,
+            rtt: None,
+            rtt_variance: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+            timeouts: 0,
+            last_ack: ack,
+            duplicate_acks: 0,
+            last_loss: None,
+            base_delays: [u32::MAX; 2],
+            base_since: Instant::now(),
+            reply_micros: 0,
+            ack,
+            reordered: HashMap::new(),
+            readable: VecDeque::new(),
+            eof: false,
+            handles: 0,
+        }
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight
+            .iter()
+            .map(|packet| packet.payload.len())
+            .sum()
+    }
+
+    /// Room we advertise for the peer's data.
+    fn receive_window(&self) -> u32 {
+        let held: usize = self.reordered.values().map(|(_, data)| data.len()).sum();
+        let free = RECV_BUFFER_BYTES.saturating_sub(self.readable.len() + held);
+        u32::try_from(free).unwrap_or(u32::MAX)
+    }
+
+    /// Grow or shrink the window by how far `delay` is from the target, for
+    /// `acked` bytes that got through.
+    fn on_delay(&mut self, delay: u32, acked: usize) {
+        if self.base_since.elapsed() >= BASE_DELAY_WINDOW {
+            self.base_delays = [u32::MAX, self.base_delays[0]];
+            self.base_since = Instant::now();
+        }
+        // The clocks need not agree, so delays are only comparable with
+        // each other, and may wrap.
+        if self.base_delays[0] == u32::MAX || (delay.wrapping_sub(self.base_delays[0]) as i32) < 0 {
+            self.base_delays[0] = delay;
+        }
+        let base = match self.base_delays[1] {
+            u32::MAX => self.base_delays[0],
+            older if (older.wrapping_sub(self.base_delays[0]) as i32) < 0 => older,
+            _ => self.base_delays[0],
+        };
+        let queuing = f64::from((delay.wrapping_sub(base) as i32).max(0));
+        if self.slow_start && queuing < TARGET_DELAY_MICROS / 2.0 {
+            self.window = (self.window + acked as f64).min(MAX_WINDOW);
+            return;
+        }
+        self.slow_start = false;
+        let off_target = (TARGET_DELAY_MICROS - queuing) / TARGET_DELAY_MICROS;
+        let growth = MAX_GROWTH_BYTES * off_target * acked as f64 / self.window;
+        self.window = (self.window + growth).clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    /// Halve the window for a lost packet, unless it did this round trip.
+    fn on_loss(&mut self) {
+        let rtt = self.rtt.unwrap_or(self.timeout);
+        if self.last_loss.is_some_and(|at| at.elapsed() < rtt) {
+            return;
+        }
+        self.last_loss = Some(Instant::now());
+        self.window = (self.window / 2.0).max(MIN_WINDOW);
+        self.slow_start = false;
+    }
+
+    /// The packets held past the first missing one, as a selective ack
+    /// bitmask: bit `i` is packet `ack + 2 + i`, from the low bit up.
+    fn selective_ack(&self) -> Vec<u8> {
+        let Some(last) = (self.reordered.keys())
+            .map(|seq| usize::from(seq.wrapping_sub(self.ack).wrapping_sub(2)))
+            .max()
+        else {
+            return Vec::new();
+        };
+        // Whole words of four bytes, as the extension requires.
+        let len = (last / 8 + 1)
+            .next_multiple_of(4)
+            .min(MAX_SELECTIVE_ACK_BYTES);
+        let mut mask = vec![0u8; len];
+        for seq in self.reordered.keys() {
+            let bit = usize::from(seq.wrapping_sub(self.ack).wrapping_sub(2));
+            if bit < len * 8 {
+                mask[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        mask
+    }
+
+    /// The timeout the round trips measured call for, once backing off is over.
+    fn reset_timeout(&mut self) {
+        self.timeout = match self.rtt {
+            Some(smoothed) => (smoothed + self.rtt_variance * 4).max(MIN_TIMEOUT),
+            None => INITIAL_TIMEOUT,
+        };
+    }
+
+    fn on_rtt(&mut self, rtt: Duration) {
+        let (smoothed, variance) = match self.rtt {
+            None => (rtt, rtt / 2),
+            Some(smoothed) => (
+                (smoothed * 7 + rtt) / 8,
+                (self.rtt_variance * 3 + smoothed.abs_diff(rtt)) / 4,
+            ),
+        };
+        self.rtt = Some(smoothed);
+        self.rtt_variance = variance;
+        self.reset_timeout();
+    }
+}
+
+/// One uTP connection, shared by the handles to it and the transport.
+struct Connection {
+    peer: SocketAddr,
+    /// What the peer's packets carry, and what ours do.
+    recv_id: u16,
+    send_id: u16,
+    /// Whether the stream belongs to a channel, as our SYN tells.
+    channel: bool,
+    transport: SharedTransport,
+    epoch: Instant,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Connection {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn micros(&self) -> u32 {
+        self.epoch.elapsed().as_micros() as u32
+    }
+
+    fn send_packet(&self, state: &State, kind: u8, seq: u16, payload: &[u8]) {
+        let header = Header {
+            kind,
+            // A SYN names the id we receive on; the rest the one the peer does.
+            connection: if kind == ST_SYN {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+            timestamp: self.micros(),
+            difference: state.reply_micros,
+            window: state.receive_window(),
+            seq,
+            ack: state.ack,
+            channel: kind == ST_SYN && self.channel,
+        };
+        let selective_ack = match kind {
+            ST_STATE | ST_DATA => state.selective_ack(),
+            _ => Vec::new(),
+        };
+        let packet = header.encode(&selective_ack, payload);
+        if let Err(err) = self.transport.send_to(&packet, self.peer) {
+            debug!("uTP send to {} failed: {err}", self.peer);
+        }
+    }
+
+    fn send_state(&self, state: &State) {
+        self.send_packet(state, ST_STATE, state.seq, &[]);
+    }
+
+    /// Send what the window lets through: data written, then our FIN.
+    fn push(&self, state: &mut State) {
+        if state.phase != Phase::Connected || state.broken.is_some() {
+            return;
+        }
+        let window = (state.window as usize).min(state.peer_window);
+        let mut in_flight = state.bytes_in_flight();
+        while !state.unsent.is_empty() {
+            let len = state.unsent.len().min(PACKET_BYTES);
+            // One packet may always be on its way, or a closed window
+            // would never hear it opened again.
+            if in_flight + len > window && !state.in_flight.is_empty() {
+                return;
+            }
+            let payload: Vec<u8> = state.unsent.drain(..len).collect();
+            self.transmit(state, ST_DATA, payload);
+            in_flight += len;
+        }
+        if state.fin_queued && !state.fin_sent {
+            state.fin_sent = true;
+            self.transmit(state, ST_FIN, Vec::new());
+        }
+        self.changed.notify_all();
+    }
+
+    fn transmit(&self, state: &mut State, kind: u8, payload: Vec<u8>) {
+        let seq = state.seq;
+        state.seq = seq.wrapping_add(1);
+        self.send_packet(state, kind, seq, &payload);
+        state.in_flight.push_back(Packet {
+            kind,
+            seq,
+            payload,
+            sent: Instant::now(),
+            transmissions: 1,
+        });
+    }
+
+    /// Send the `index`th packet in flight again.
+    fn resend(&self, state: &mut State, index: usize) {
+        let Some(packet) = state.in_flight.get(index) else {
+            return;
+        };
+        let (kind, seq, payload) = (packet.kind, packet.seq, packet.payload.clone());
+        self.send_packet(state, kind, seq, &payload);
+        if let Some(packet) = state.in_flight.get_mut(index) {
+            packet.sent = Instant::now();
+            packet.transmissions += 1;
+        }
+    }
+
+    fn on_packet(&self, header: Header, selective_ack: &[u8], payload: &[u8]) {
+        let mut state = self.lock();
+        if state.broken.is_some() {
+            return;
+        }
+        if header.kind == ST_RESET {
+            drop(state);
+            self.set_broken("reset by peer");
+            return;
+        }
+        if header.kind == ST_SYN {
+            // Repeated: our answer got lost.
+            self.send_state(&state);
+            return;
+        }
+        state.reply_micros = self.micros().wrapping_sub(header.timestamp);
+        state.peer_window = header.window as usize;
+        state.heard = Some(Instant::now());
+        if state.phase == Phase::SynSent {
+            if header.kind != ST_STATE {
+                return;
+            }
+            state.phase = Phase::Connected;
+            state.ack = header.seq.wrapping_sub(1);
+            state.last_ack = header.ack;
+            debug!("uTP connection with {} up", self.peer);
+        }
+        self.on_ack(&mut state, &header, selective_ack);
+        match header.kind {
+            ST_DATA | ST_FIN => {
+                self.on_data(&mut state, header.kind, header.seq, payload);
+                self.send_state(&state);
+            }
+            _ => {}
+        }
+        self.push(&mut state);
+        self.changed.notify_all();
+    }
+
+    fn on_ack(&self, state: &mut State, header: &Header, selective_ack: &[u8]) {
+        let mut acked = 0;
+        let mut index = 0;
+        while let Some(packet) = state.in_flight.get(index) {
+            if before(header.ack, packet.seq)
+                && !selectively_acked(selective_ack, header.ack, packet.seq)
+            {
+                index += 1;
+                continue;
+            }
+            let packet = state.in_flight.remove(index).unwrap();
+            // Only packets sent once tell the round trip for sure.
+            if packet.transmissions == 1 {
+                state.on_rtt(packet.sent.elapsed());
+            }
+            acked += packet.payload.len();
+        }
+        let moved_on = header.ack != state.last_ack && !before(header.ack, state.last_ack);
+        if moved_on {
+            state.last_ack = header.ack;
+            state.duplicate_acks = 0;
+            state.timeouts = 0;
+            state.reset_timeout();
+        }
+        if acked > 0 && header.difference != 0 {
+            state.on_delay(header.difference, acked);
+        }
+        if !selective_ack.is_empty() {
+            // Packets with enough acked after them count as lost; each is
+            // sent again at most once a round trip.
+            let rtt = state.rtt.unwrap_or(state.timeout);
+            let acked_bits: Vec<usize> = (0..selective_ack.len() * 8)
+                .filter(|bit| selective_ack[bit / 8] & (1 << (bit % 8)) != 0)
+                .collect();
+            let mut lost = Vec::new();
+            for (index, packet) in state.in_flight.iter().enumerate() {
+                // Bits from `offset` up are the packets after this one.
+                let offset = usize::from(packet.seq.wrapping_sub(header.ack).wrapping_sub(1));
+                let after = acked_bits.iter().filter(|&&bit| bit >= offset).count();
+                if after < DUPLICATE_ACKS as usize {
+                    break;
+                }
+                if packet.sent.elapsed() >= rtt {
+                    lost.push(index);
+                }
+            }
+            if !lost.is_empty() {
+                state.on_loss();
+            }
+            for index in lost {
+                self.resend(state, index);
+            }
+        } else if !moved_on
+            && header.kind == ST_STATE
+            && header.ack == state.last_ack
+            && !state.in_flight.is_empty()
+        {
+            state.duplicate_acks += 1;
+            if state.duplicate_acks == DUPLICATE_ACKS {
+                state.on_loss();
+                self.resend(state, 0);
+            }
+        }
+    }
+
+    fn on_data(&self, state: &mut State, kind: u8, seq: u16, payload: &[u8]) {
+        if state.eof || !before(state.ack, seq) {
+            // Delivered already; the answer says so again.
+            return;
+        }
+        if seq != state.ack.wrapping_add(1) {
+            if state.reordered.len() < MAX_REORDERED
+                && payload.len() <= state.receive_window() as usize
+            {
+                state.reordered.insert(seq, (kind, payload.to_vec()));
+            }
+            return;
+        }
+        // Past the room we advertised: the peer sends it again once
+        // reads make some.
+        if kind == ST_DATA && payload.len() > state.receive_window() as usize {
+            return;
+        }
+        let mut next = Some((kind, payload.to_vec()));
+        while let Some((kind, data)) = next {
+            state.ack = state.ack.wrapping_add(1);
+            if kind == ST_FIN {
+                state.eof = true;
+                state.reordered.clear();
+                break;
+            }
+            state.readable.extend(data);
+            next = state.reordered.remove(&state.ack.wrapping_add(1));
+        }
+    }
+
+    /// Resend what timed out; returns false once the connection is over.
+    fn tick(&self) -> bool {
+        let mut state = self.lock();
+        if state.broken.is_some() {
+            return false;
+        }
+        let overdue = state
+            .in_flight
+            .front()
+            .is_some_and(|packet| packet.sent.elapsed() >= state.timeout);
+        // A peer that answered with its window closed has no room for what
+        // is in flight, and is probed until it does.
+        let closed = state.in_flight.front().is_some_and(|packet| {
+            state.heard.is_some_and(|heard| heard >= packet.sent)
+                && state.peer_window < packet.payload.len()
+        });
+        if overdue && closed {
+            state.timeout = state.timeout.min(PROBE_TIMEOUT);
+            self.resend(&mut state, 0);
+        } else if overdue {
+            state.timeouts += 1;
+            if state.timeouts > MAX_TIMEOUTS {
+                warn!("uTP connection with {} timed out", self.peer);
+                drop(state);
+                self.set_broken("timed out");
+                return false;
+            }
+            state.window = MIN_WINDOW;
+            state.slow_start = false;
+            state.timeout = (state.timeout * 2).min(MAX_TIMEOUT);
+            self.resend(&mut state, 0);
+        }
+        self.push(&mut state);
+        let finished = state.fin_sent && state.in_flight.is_empty();
+        !(finished && (state.eof || state.handles == 0))
+    }
+
+    fn set_broken(&self, reason: &'static str) {
+        let mut state = self.lock();
+        state.broken.get_or_insert(reason);
+        self.changed.notify_all();
+    }
+
+    fn reset(&self) {
+        let state = self.lock();
+        self.send_packet(&state, ST_RESET, state.seq, &[]);
+        drop(state);
+        self.set_broken("reset");
+    }
+
+    fn broken_error(&self, reason: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            format!("uTP connection with {} {reason}", self.peer),
+        )
+    }
+
+    /// Queue `buf` whole, blocking while the send buffer is full.
+    fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        let mut state = self.lock();
+        while !buf.is_empty() {
+            if let Some(reason) = state.broken {
+                return Err(self.broken_error(reason));
+            }
+            if state.fin_queued {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "stream already finished",
+                ));
+            }
+            let room = SEND_BUFFER_BYTES.saturating_sub(state.unsent.len());
+            if room == 0 {
+                state = self.changed.wait(state).unwrap();
+                continue;
+            }
+            let len = room.min(buf.len());
+            state.unsent.extend(&buf[..len]);
+            buf = &buf[len..];
+            self.push(&mut state);
+        }
+        Ok(())
+    }
+
+    /// Read what arrived, blocking until something did; 0 at the end.
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.lock();
+        loop {
+            if !state.readable.is_empty() {
+                let was_full = state.receive_window() < PACKET_BYTES as u32;
+                let len = buf.len().min(state.readable.len());
+                for (slot, byte) in buf.iter_mut().zip(state.readable.drain(..len)) {
+                    *slot = byte;
+                }
+                // Tell a sender waiting on our window that it opened.
+                if was_full {
+                    self.send_state(&state);
+                }
+                return Ok(len);
+            }
+            if let Some(reason) = state.broken {
+                return Err(self.broken_error(reason));
+            }
+            if state.eof {
+                return Ok(0);
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Block until everything written is acknowledged.
+    fn flush(&self) -> io::Result<()> {
+        let mut state = self.lock();
+        loop {
+            if let Some(reason) = state.broken {
+                return Err(self.broken_error(reason));
+            }
+            if state.unsent.is_empty() && state.in_flight.is_empty() {
+                return Ok(());
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn finish(&self) {
+        let mut state = self.lock();
+        if !state.fin_queued && state.broken.is_none() {
+            state.fin_queued = true;
+            self.push(&mut state);
+        }
+    }
+}
+
+type Connections = HashMap<(SocketAddr, u16), Arc<Connection>>;
+type Acceptor = (
+    flume::Sender<Arc<Connection>>,
+    flume::Receiver<Arc<Connection>>,
+);
+
+/// Below the session layer, handles the packets of our uTP connections
+/// and passes everything else on.
+pub(crate) struct UtpTransport {
+    inner: SharedTransport,
+    /// Connections by peer and the id their packets carry.
+    connections: Arc<Mutex<Connections>>,
+    accept: Mutex<Option<Acceptor>>,
+    /// Connections opened on a channel, not yet named.
+    accept_channels: Mutex<Option<Acceptor>>,
+    channels: Arc<Channels<UtpStream>>,
+    naming: AtomicBool,
+    /// Only peers that exchanged a hello or ack with us may connect.
+    streams: Arc<Streams>,
+    epoch: Instant,
+    ticking: AtomicBool,
+    shutdown: ShutdownHandle,
+}
+
+impl UtpTransport {
+    pub(crate) fn new(
+        inner: SharedTransport,
+        streams: Arc<Streams>,
+        shutdown: ShutdownHandle,
+    ) -> Self {
+        Self {
+            inner,
+            connections: Arc::default(),
+            accept: Mutex::default(),
+            accept_channels: Mutex::default(),
+            channels: Arc::new(Channels::new("uTP stream")),
+            naming: AtomicBool::new(false),
+            streams,
+            epoch: Instant::now(),
+            ticking: AtomicBool::new(false),
+            shutdown,
+        }
+    }
+
+    /// Whether a connection is open, so that its packets should be read
+    /// without delay.
+    pub(crate) fn busy(&self) -> bool {
+        !self.connections.lock().unwrap().is_empty()
+    }
+
+    /// Start connecting to `peer`, on a channel or not; writes wait until
+    /// the connection is up.
+    fn connect(&self, peer: SocketAddr, channel: bool) -> Arc<Connection> {
+        let mut connections = self.connections.lock().unwrap();
+        let recv_id = loop {
+            let id: u16 = rand::random();
+            if !connections.contains_key(&(peer, id))
+                && !connections.contains_key(&(peer, id.wrapping_add(1)))
+            {
+                break id;
+            }
+        };
+        let connection = Arc::new(Connection {
+            peer,
+            recv_id,
+            send_id: recv_id.wrapping_add(1),
+            channel,
+            transport: self.inner.clone(),
+            epoch: self.epoch,
+            state: Mutex::new(State::new(Phase::SynSent, 1, 0)),
+            changed: Condvar::new(),
+        });
+        let mut state = connection.lock();
+        connection.transmit(&mut state, ST_SYN, Vec::new());
+        drop(state);
+        connections.insert((peer, recv_id), connection.clone());
+        drop(connections);
+        self.spawn_ticker();
+        connection
+    }
+
+    /// Start accepting connections from peers (on first call) and return
+    /// the queue.
+    fn acceptor(&self) -> flume::Receiver<Arc<Connection>> {
+        let mut accept = self.accept.lock().unwrap();
+        let (_, rx) = accept.get_or_insert_with(|| flume::bounded(ACCEPT_QUEUE));
+        rx.clone()
+    }
+
+    /// Start taking connections opened on channels and reading their names,
+    /// with the handshake hash of the session `hash` finds for each peer,
+    /// until shutdown (once per node).
+    fn spawn_namer(&self, hash: impl Fn(&SocketAddr) -> Option<[u8; 32]> + Send + 'static) {
+        if self.naming.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let acceptor = {
+            let mut accept = self.accept_channels.lock().unwrap();
+            let (_, rx) = accept.get_or_insert_with(|| flume::bounded(ACCEPT_QUEUE));
+            rx.clone()
+        };
+        let channels = self.channels.clone();
+        self.shutdown.spawn("dhtmsg-utp-channels", move || {
+            while let Ok(connection) = acceptor.recv() {
+                let peer = connection.peer;
+                let Some(handshake_hash) = hash(&peer) else {
+                    debug!("uTP channel from {peer} without a session (reset)");
+                    connection.reset();
+                    continue;
+                };
+                channels.name(UtpStream::new(connection, handshake_hash));
+            }
+        });
+    }
+
+    /// Break every connection and stop accepting new ones.
+    pub(crate) fn close(&self) {
+        self.accept.lock().unwrap().take();
+        self.accept_channels.lock().unwrap().take();
+        self.channels.close();
+        for (_, connection) in self.connections.lock().unwrap().drain() {
+            connection.set_broken("closed: node shut down");
+        }
+    }
+
+    /// The peer said goodbye: fail its connections.
+    pub(crate) fn forget(&self, peer: &SocketAddr) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|(addr, _), connection| {
+            if addr == peer {
+                connection.set_broken("closed by peer");
+            }
+            addr != peer
+        });
+    }
+
+    /// Answer a SYN from `peer`, if it may connect and someone accepts;
+    /// false if the SYN is not for us.
+    fn on_syn(&self, peer: SocketAddr, header: Header) -> bool {
+        let recv_id = header.connection.wrapping_add(1);
+        let existing = self
+            .connections
+            .lock()
+            .unwrap()
+            .get(&(peer, recv_id))
+            .cloned();
+        if let Some(existing) = existing {
+            existing.on_packet(header, &[], &[]);
+            return true;
+        }
+        // Peers that did not say hello are BitTorrent clients, or not
+        // allowed yet; either way they get no answer.
+        if !self.streams.is_established(&peer) {
+            return false;
+        }
+        let accept = if header.channel {
+            &self.accept_channels
+        } else {
+            &self.accept
+        };
+        let accept = (accept.lock().unwrap().as_ref()).map(|(tx, _)| tx.clone());
+        let Some(accept) = accept else {
+            debug!("uTP connection from {peer} while nobody accepts them (dropped)");
+            return true;
+        };
+        let connection = Arc::new(Connection {
+            peer,
+            recv_id,
+            send_id: header.connection,
+            channel: header.channel,
+            transport: self.inner.clone(),
+            epoch: self.epoch,
+            state: Mutex::new(State::new(Phase::Connected, rand::random(), header.seq)),
+            changed: Condvar::new(),
+        });
+        {
+            let mut state = connection.lock();
+            state.reply_micros = connection.micros().wrapping_sub(header.timestamp);
+            connection.send_state(&state);
+        }
+        if accept.try_send(connection.clone()).is_err() {
+            debug!("uTP accept queue full; resetting the connection from {peer}");
+            connection.reset();
+            return true;
+        }
+        debug!("uTP connection from {peer}");
+        self.connections
+            .lock()
+            .unwrap()
+            .insert((peer, recv_id), connection);
+        self.spawn_ticker();
+        true
+    }
+
+    /// Resend what timed out on every connection, forgetting those that
+    /// are over, until shutdown (once per node).
+    fn spawn_ticker(&self) {
+        if self.ticking.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let connections = self.connections.clone();
+        let shutdown = self.shutdown.clone();
+        self.shutdown.spawn("dhtmsg-utp", move || {
+            while shutdown.sleep(TICK) {
+                let open: Vec<_> = (connections.lock().unwrap().iter())
+                    .map(|(key, connection)| (*key, connection.clone()))
+                    .collect();
+                for (key, connection) in open {
+                    if !connection.tick() {
+                        debug!("uTP connection with {} closed", connection.peer);
+                        connections.lock().unwrap().remove(&key);
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Transport for UtpTransport {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.inner.send_to(datagram, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, from) = self.inner.recv_from(buf)?;
+            let Some((header, selective_ack, payload)) = Header::parse(&buf[..len]) else {
+                return Ok((len, from));
+            };
+            if header.kind == ST_SYN && payload.is_empty() {
+                if self.on_syn(from, header) {
+                    continue;
+                }
+                return Ok((len, from));
+            }
+            let connection = (self.connections.lock().unwrap())
+                .get(&(from, header.connection))
+                .cloned();
+            match connection {
+                Some(connection) => connection.on_packet(header, selective_ack, payload),
+                // Someone else's BitTorrent traffic.
+                None => return Ok((len, from)),
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Sealing or opening the records of one direction.
+struct Direction {
+    key: [u8; aead::KEY_BYTES],
+    /// Records so far, the nonce of the next.
+    records: u64,
+}
+
+impl Direction {
+    fn new(handshake_hash: &[u8; 32], salt: &[u8]) -> Self {
+        Self {
+            key: hmac(handshake_hash, &[KEY_CONTEXT, salt]),
+            records: 0,
+        }
+    }
+
+    fn nonce(&mut self) -> [u8; aead::NONCE_BYTES] {
+        let mut nonce = [0u8; aead::NONCE_BYTES];
+        nonce[4..].copy_from_slice(&self.records.to_le_bytes());
+        self.records += 1;
+        nonce
+    }
+
+    /// The next record, `[length u16 BE][sealed]`, holding `plaintext` of
+    /// at most [`RECORD_BYTES`].
+    fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce();
+        let sealed = aead::seal(&self.key, &nonce, &[], plaintext);
+        let mut record = Vec::with_capacity(LENGTH_BYTES + sealed.len());
+        record.extend_from_slice(&(sealed.len() as u16).to_be_bytes());
+        record.extend_from_slice(&sealed);
+        record
+    }
+
+    /// Open the next record, past its length.
+    fn open(&mut self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let nonce = self.nonce();
+        aead::open(&self.key, &nonce, &[], sealed)
+    }
+}
+
+/// Encrypted byte stream to one peer over uTP, for bulk data; see
+/// [`DhtMsg::utp_stream`](crate::DhtMsg::utp_stream) and
+/// [`DhtMsg::channel`](crate::DhtMsg::channel).
+///
+/// Dropping the stream (or calling [`finish`](Self::finish)) finishes our
+/// side, so the peer's reads return 0. Like a `TcpStream`, `&UtpStream`
+/// reads and writes too, so one thread can read while another writes.
+pub struct UtpStream {
+    connection: Arc<Connection>,
+    handshake_hash: [u8; 32],
+    channel: Option<String>,
+    sending: Mutex<Option<Direction>>,
+    receiving: Mutex<Receiving>,
+}
+
+/// The reading side of a [`UtpStream`].
+#[derive(Default)]
+struct Receiving {
+    direction: Option<Direction>,
+    /// Bytes read from the connection and not yet a whole record.
+    buffered: Vec<u8>,
+    /// Opened bytes not yet read.
+    pending: Vec<u8>,
+}
+
+impl UtpStream {
+    /// Connect to `peer`, whose session has `handshake_hash`.
+    pub(crate) fn connect(utp: &UtpTransport, peer: SocketAddr, handshake_hash: [u8; 32]) -> Self {
+        Self::new(utp.connect(peer, false), handshake_hash)
+    }
+
+    /// Connect to `peer` on the channel `name`, at most
+    /// [`MAX_NAME_BYTES`](channel::MAX_NAME_BYTES) long.
+    pub(crate) fn connect_channel(
+        utp: &UtpTransport,
+        peer: SocketAddr,
+        handshake_hash: [u8; 32],
+        name: &str,
+    ) -> io::Result<Self> {
+        let mut stream = Self::new(utp.connect(peer, true), handshake_hash);
+        channel::write_name(&mut stream, name)?;
+        Ok(stream.named(name.to_string()))
+    }
+
+    /// The next stream a peer opened on the channel `name`, with the
+    /// handshake hash of the session `hash` finds for its peer. None once
+    /// the node shut down.
+    pub(crate) fn accept_channel(
+        utp: &UtpTransport,
+        name: &str,
+        hash: impl Fn(&SocketAddr) -> Option<[u8; 32]> + Send + 'static,
+    ) -> Option<Self> {
+        let queue = utp.channels.accept(name);
+        utp.spawn_namer(hash);
+        queue.recv().ok()
+    }
+
+    /// The next connection a peer opened, with the handshake hash of the
+    /// session `hash` finds for it; connections from peers without one are
+    /// reset. None once the node shut down.
+    pub(crate) fn accept(
+        utp: &UtpTransport,
+        hash: impl Fn(&SocketAddr) -> Option<[u8; 32]>,
+    ) -> Option<Self> {
+        let acceptor = utp.acceptor();
+        loop {
+            let connection = acceptor.recv().ok()?;
+            match hash(&connection.peer) {
+                Some(handshake_hash) => return Some(Self::new(connection, handshake_hash)),
+                None => {
+                    debug!(
+                        "uTP connection from {} without a session (reset)",
+                        connection.peer
+                    );
+                    connection.reset();
+                }
+            }
+        }
+    }
+
+    fn new(connection: Arc<Connection>, handshake_hash: [u8; 32]) -> Self {
+        connection.lock().handles += 1;
+        Self {
+            connection,
+            handshake_hash,
+            channel: None,
+            sending: Mutex::default(),
+            receiving: Mutex::default(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.connection.peer
+    }
+
+    /// The channel the stream was opened on, if any.
+    pub fn channel(&self) -> Option<&str> {
+        self.channel.as_deref()
+    }
+
+    /// Signal end of stream to the peer. Further writes fail.
+    pub fn finish(&self) {
+        self.connection.finish();
+    }
+
+    /// Read from the connection until `receiving` buffers `len` bytes;
+    /// false at the end of the stream.
+    fn fill(&self, receiving: &mut Receiving, len: usize) -> io::Result<bool> {
+        let mut buf = [0u8; 16 * 1024];
+        while receiving.buffered.len() < len {
+            let read = self.connection.read(&mut buf)?;
+            if read == 0 {
+                return Ok(false);
+            }
+            receiving.buffered.extend_from_slice(&buf[..read]);
+        }
+        Ok(true)
+    }
+
+    /// Open the next record into `pending`; false at the end of the stream.
+    fn next_record(&self, receiving: &mut Receiving) -> io::Result<bool> {
+        if receiving.direction.is_none() {
+            if !self.fill(receiving, SALT_BYTES)? {
+                return Ok(false);
+            }
+            let salt: Vec<u8> = receiving.buffered.drain(..SALT_BYTES).collect();
+            receiving.direction = Some(Direction::new(&self.handshake_hash, &salt));
+        }
+        if !self.fill(receiving, LENGTH_BYTES)? {
+            return Ok(false);
+        }
+        let len = usize::from(u16::from_be_bytes([
+            receiving.buffered[0],
+            receiving.buffered[1],
+        ]));
+        if !self.fill(receiving, LENGTH_BYTES + len)? {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let sealed: Vec<u8> = receiving
+            .buffered
+            .drain(..LENGTH_BYTES + len)
+            .skip(LENGTH_BYTES)
+            .collect();
+        let direction = receiving.direction.as_mut().unwrap();
+        let opened = direction.open(&sealed).ok_or_else(|| {
+            self.connection.reset();
+            io::Error::new(io::ErrorKind::InvalidData, "uTP record failed to open")
+        })?;
+        receiving.pending = opened;
+        Ok(true)
+    }
+}
+
+impl Channel for UtpStream {
+    fn peer(&self) -> SocketAddr {
+        self.connection.peer
+    }
+
+    fn named(mut self, name: String) -> Self {
+        self.channel = Some(name);
+        self
+    }
+
+    fn abandon(self) {
+        self.connection.reset();
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Read for &UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut receiving = self.receiving.lock().unwrap();
+        while receiving.pending.is_empty() {
+            if !self.next_record(&mut receiving)? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(receiving.pending.len());
+        buf[..len].copy_from_slice(&receiving.pending[..len]);
+        receiving.pending.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Write for &UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(RECORD_BYTES);
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut sending = self.sending.lock().unwrap();
+        let sending = match &mut *sending {
+            Some(sending) => sending,
+            None => {
+                let salt: [u8; SALT_BYTES] = rand::random();
+                self.connection.write_all(&salt)?;
+                sending.insert(Direction::new(&self.handshake_hash, &salt))
+            }
+        };
+        let record = sending.seal(&buf[..len]);
+        self.connection.write_all(&record)?;
+        Ok(len)
+    }
+
+    /// Block until every written byte has been acknowledged by the peer.
+    fn flush(&mut self) -> io::Result<()> {
+        self.connection.flush()
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut state = self.connection.lock();
+        state.handles -= 1;
+        if state.handles == 0 {
+            drop(state);
+            self.connection.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        Header {
+            kind: ST_DATA,
+            connection: 0x1234,
+            timestamp: 0xdead_beef,
+            difference: 7,
+            window: 1 << 20,
+            seq: 65535,
+            ack: 41,
+            channel: false,
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        for channel in [false, true] {
+            for selective_ack in [&[][..], &[0b1010_0001, 0, 0, 0x80]] {
+                let header = Header {
+                    channel,
+                    ..header()
+                };
+                let packet = header.encode(selective_ack, b"payload");
+                let (parsed, sack, payload) = Header::parse(&packet).unwrap();
+                assert_eq!(parsed, header);
+                assert_eq!(sack, selective_ack);
+                assert_eq!(payload, b"payload");
+            }
+        }
+        // The layout of BEP 29.
+        let packet = header().encode(&[], &[]);
+        assert_eq!(
+            hex::encode(packet),
+            "01001234deadbeef0000000700100000ffff0029"
+        );
+    }
+
+    #[test]
+    fn refuses_other_packets() {
+        let packet = header().encode(&[], &[]);
+        assert!(Header::parse(&packet[..HEADER_BYTES - 1]).is_none());
+        // Version 2, and a type past ST_SYN.
+        assert!(Header::parse(&[&[0x02][..], &packet[1..]].concat()).is_none());
+        assert!(Header::parse(&[&[0x51][..], &packet[1..]].concat()).is_none());
+        // A BitTorrent KRPC message.
+        assert!(
+            Header::parse(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe").is_none()
+        );
+    }
+
+    #[test]
+    fn extension_chains() {
+        let mut packet = header().encode(&[], &[]);
+        // Unknown extension 9, then a selective ack, then the payload.
+        packet[1] = 9;
+        packet.extend_from_slice(&[SELECTIVE_ACK, 2, 0xaa, 0xbb]);
+        packet.extend_from_slice(&[0, 4, 1, 2, 3, 4]);
+        packet.extend_from_slice(b"data");
+        let (parsed, sack, payload) = Header::parse(&packet).unwrap();
+        assert!(!parsed.channel);
+        assert_eq!(sack, [1, 2, 3, 4]);
+        assert_eq!(payload, b"data");
+        // Every cut inside the extensions.
+        for len in HEADER_BYTES..packet.len() - 4 {
+            assert!(Header::parse(&packet[..len]).is_none(), "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn refuses_looping_extension_chains() {
+        // Each extension names another one after it, until the packet ends.
+        let mut packet = header().encode(&[], &[]);
+        packet[1] = SELECTIVE_ACK;
+        for _ in 0..1000 {
+            packet.extend_from_slice(&[SELECTIVE_ACK, 0]);
+        }
+        assert!(Header::parse(&packet).is_none());
+        // An extension longer than the packet.
+        let mut packet = header().encode(&[], &[]);
+        packet[1] = CHANNEL;
+        packet.extend_from_slice(&[0, 255, 1, 2, 3]);
+        assert!(Header::parse(&packet).is_none());
+    }
+
+    fn state_with(ack: u16, received: &[u16]) -> State {
+        let mut state = State::new(Phase::Connected, 1, ack);
+        for &seq in received {
+            state.reordered.insert(seq, (ST_DATA, vec![0]));
+        }
+        state
+    }
+
+    #[test]
+    fn selective_ack_bitmaps() {
+        assert!(state_with(100, &[]).selective_ack().is_empty());
+        let state = state_with(100, &[102, 105, 133]);
+        let mask = state.selective_ack();
+        assert_eq!(mask, [0b0000_1001, 0, 0, 0x80]);
+        for seq in 101..200 {
+            let expected = [102, 105, 133].contains(&seq);
+            assert_eq!(selectively_acked(&mask, 100, seq), expected, "{seq}");
+        }
+        // Whole words of four bytes.
+        assert_eq!(state_with(100, &[134]).selective_ack().len(), 8);
+        // Packets past the longest mask are left out.
+        let far = 100 + 2 + 8 * MAX_SELECTIVE_ACK_BYTES as u16;
+        let mask = state_with(100, &[102, far]).selective_ack();
+        assert_eq!(mask.len(), MAX_SELECTIVE_ACK_BYTES);
+        assert!(selectively_acked(&mask, 100, 102));
+        assert!(!selectively_acked(&mask, 100, far));
+        // Bits past the mask, and the ack itself, are not acked.
+        assert!(!selectively_acked(&[0xff; 4], 100, 134));
+        assert!(!selectively_acked(&[0xff; 4], 100, 100));
+    }
+
+    #[test]
+    fn selective_acks_wrap() {
+        let state = state_with(65534, &[0, 1, 30]);
+        let mask = state.selective_ack();
+        assert_eq!(mask, [0b0000_0011, 0, 0, 0b0100_0000]);
+        for seq in [0, 1, 30] {
+            assert!(selectively_acked(&mask, 65534, seq));
+        }
+        assert!(!selectively_acked(&mask, 65534, 65535));
+        assert!(!selectively_acked(&mask, 65534, 2));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert!(before(1, 2));
+        assert!(!before(2, 1));
+        assert!(!before(7, 7));
+        assert!(before(65535, 0));
+        assert!(before(65000, 100));
+        assert!(!before(100, 65000));
+        // Less than half the space ahead is after; exactly half is neither.
+        assert!(before(0, 32767));
+        assert!(!before(0, 32768));
+        assert!(!before(32768, 0));
+        assert!(before(32769, 0));
+    }
+
+    fn directions(salt: &[u8]) -> (Direction, Direction) {
+        let hash = [7; 32];
+        (Direction::new(&hash, salt), Direction::new(&hash, salt))
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let (mut sending, mut receiving) = directions(&[1; SALT_BYTES]);
+        for plaintext in [&b"first"[..], &[], &[0xab; RECORD_BYTES]] {
+            let record = sending.seal(plaintext);
+            let len = usize::from(u16::from_be_bytes([record[0], record[1]]));
+            assert_eq!(len, record.len() - LENGTH_BYTES);
+            assert_eq!(len, plaintext.len() + aead::TAG_BYTES);
+            assert_eq!(receiving.open(&record[LENGTH_BYTES..]).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn refuses_tampered_records() {
+        let (mut sending, _) = directions(&[1; SALT_BYTES]);
+        let record = sending.seal(b"attack at dawn");
+        for at in LENGTH_BYTES..record.len() {
+            let mut tampered = record.clone();
+            tampered[at] ^= 1;
+            let (_, mut receiving) = directions(&[1; SALT_BYTES]);
+            assert_eq!(receiving.open(&tampered[LENGTH_BYTES..]), None, "byte {at}");
+        }
+        // Another salt or handshake hash gives another key.
+        let (_, mut other) = directions(&[2; SALT_BYTES]);
+        assert_eq!(other.open(&record[LENGTH_BYTES..]), None);
+        let mut other = Direction::new(&[8; 32], &[1; SALT_BYTES]);
+        assert_eq!(other.open(&record[LENGTH_BYTES..]), None);
+    }
+
+    #[test]
+    fn refuses_reordered_records() {
+        let (mut sending, mut receiving) = directions(&[1; SALT_BYTES]);
+        let first = sending.seal(b"one");
+        let second = sending.seal(b"two");
+        // Each record opens under its own number only.
+        assert_eq!(receiving.open(&second[LENGTH_BYTES..]), None);
+        assert_eq!(receiving.open(&first[LENGTH_BYTES..]), None);
+        let (_, mut receiving) = directions(&[1; SALT_BYTES]);
+        assert_eq!(receiving.open(&first[LENGTH_BYTES..]).unwrap(), b"one");
+        assert_eq!(receiving.open(&first[LENGTH_BYTES..]), None);
+    }
+}
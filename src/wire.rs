@@ -0,0 +1,429 @@
+//! Framing of every hello-socket datagram:
+//! `[magic "DM"][version u8][type u8][timestamp u64 BE][nonce u64 BE][body length u32 BE][CRC-32 u32 BE][body][signature]`.
+//!
+//! Frames carry their payload untouched, so binary data reaches the
+//! application byte for byte. Datagrams without the magic, or from another
+//! protocol version, are foreign and get dropped. The CRC-32 (the IEEE one
+//! used by Ethernet and zip) covers the header before it and the body, so
+//! corruption the UDP checksum missed, or that happened on a path that
+//! zeroes it, is caught too.
+//!
+//! The trailing 64-byte Ed25519 signature covers everything before it and is
+//! made with the sender's [`Identity`]. Which key checks it depends on the
+//! frame: most name their sender's ID in the body, streams and VPN packets
+//! belong to the address that last proved its ID, sealed frames and handshake answers to
+//! the ID that the handshake authenticated. The opening handshake message
+//! cannot be checked at all; nothing is trusted because of it.
+//!
+//! The timestamp (Unix seconds) and nonce are signed too, so a captured
+//! frame cannot be passed off as new: receivers drop frames from outside
+//! their replay window and nonces a sender already used (see the `replay`
+//! module). Every [`Identity`] numbers its frames with one counter.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crc::{CRC_32_ISO_HDLC, Crc};
+use ed25519_dalek::{SIGNATURE_LENGTH, Signature, VerifyingKey};
+
+use crate::{Identity, bittorrent::is_bittorrent};
+
+const MAGIC: &[u8; 2] = b"DM";
+/// Newest protocol version we speak, sent in every header and offered in
+/// hellos. Bumped on incompatible changes to the header or any frame body.
+pub(crate) const VERSION: u8 = 7;
+/// Oldest protocol version we still speak. Version 2 frames had no
+/// checksum, version 3 ones no signature, version 4 ones no timestamp or
+/// nonce, version 5 hellos no challenge, and version 6 sessions no epochs.
+pub(crate) const MIN_VERSION: u8 = 7;
+/// Bytes before the checksum, which it covers.
+const PREFIX_BYTES: usize = MAGIC.len() + 1 + 1 + 8 + 8 + 4;
+const HEADER_BYTES: usize = PREFIX_BYTES + 4;
+/// Bytes a frame adds to its body.
+pub(crate) const OVERHEAD_BYTES: usize = HEADER_BYTES + SIGNATURE_LENGTH;
+const CHECKSUM: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum FrameType {
+    /// `[seq u32][sender ID length u8][sender ID][payload]`
+    Message = 1,
+    /// `[seq u32][sender ID]`
+    MessageAck = 2,
+    /// Like [`Message`](Self::Message), with the payload replaced by
+    /// `[original length u32][LZ4 block]`.
+    CompressedMessage = 3,
+    /// A bencoded hello, ack, ping or goodbye (see the `control` module).
+    Control = 4,
+    /// A stream segment or stream ack (see the `stream` module).
+    Stream = 5,
+    /// One piece of a bigger frame (see the `fragment` module).
+    Fragment = 6,
+    /// A CBOR-encoded `schema::Message`; understood with the `cbor` feature.
+    Cbor = 7,
+    /// `[step u8][Noise message]`, one of the three handshake messages (see
+    /// the `session` module).
+    Handshake = 8,
+    /// `[counter u64 LE][ciphertext][tag]`: a whole frame, encrypted for the
+    /// session with the sender's address.
+    Sealed = 9,
+    /// Like [`Message`](Self::Message), with the payload replaced by a box
+    /// sealed to the recipient's ID (see the `sealed_box` module).
+    SealedMessage = 10,
+    /// `[step u8][sealed challenge]`: a check that the peer of a session
+    /// moved to the address this came from (see the `session` module).
+    Roam = 11,
+    /// `[IP packet]`: one packet of a VPN, which belongs to the address that
+    /// last proved its ID, as streams do, and is never acknowledged.
+    Packet = 12,
+    /// `[message type u8][topic length u8][topic][message body]`: a
+    /// [`Message`](Self::Message), [`CompressedMessage`](Self::CompressedMessage)
+    /// or [`SealedMessage`](Self::SealedMessage) on a topic.
+    Topic = 13,
+}
+
+impl FrameType {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(Self::Message),
+            2 => Some(Self::MessageAck),
+            3 => Some(Self::CompressedMessage),
+            4 => Some(Self::Control),
+            5 => Some(Self::Stream),
+            6 => Some(Self::Fragment),
+            7 => Some(Self::Cbor),
+            8 => Some(Self::Handshake),
+            9 => Some(Self::Sealed),
+            10 => Some(Self::SealedMessage),
+            11 => Some(Self::Roam),
+            12 => Some(Self::Packet),
+            13 => Some(Self::Topic),
+            _ => None,
+        }
+    }
+}
+
+/// Version assumed for peers whose hello or ack does not name one; they
+/// predate version negotiation.
+pub(crate) fn legacy_version() -> u8 {
+    2
+}
+
+/// Newest version both we and a peer speaking `min..=max` understand.
+pub(crate) fn negotiate(min: u8, max: u8) -> Option<u8> {
+    let version = max.min(VERSION);
+    (version >= min.max(MIN_VERSION)).then_some(version)
+}
+
+pub(crate) fn encode(kind: FrameType, body: &[u8], identity: &Identity) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_BYTES + body.len() + SIGNATURE_LENGTH);
+    frame.extend_from_slice(MAGIC);
+    frame.push(VERSION);
+    frame.push(kind as u8);
+    frame.extend_from_slice(&unix_secs().to_be_bytes());
+    frame.extend_from_slice(&identity.next_nonce().to_be_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&checksum(&frame, body).to_be_bytes());
+    frame.extend_from_slice(body);
+    let signature = identity.sign(&frame);
+    frame.extend_from_slice(&signature.to_bytes());
+    frame
+}
+
+pub(crate) fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn checksum(prefix: &[u8], body: &[u8]) -> u32 {
+    let mut digest = CHECKSUM.digest();
+    digest.update(prefix);
+    digest.update(body);
+    digest.finalize()
+}
+
+/// Why [`decode`] rejected a datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rejected {
+    /// No magic: stray traffic from something else entirely.
+    Foreign,
+    /// No magic, and recognizably from a BitTorrent client.
+    BitTorrent,
+    /// Our magic, but a protocol version we do not speak.
+    Version(u8),
+    /// Right magic and version, but a bad length or unknown frame type.
+    Malformed,
+    /// A well-formed frame whose checksum does not match: damaged in transit.
+    Corrupt,
+    /// An intact frame whose signature does not match the ID it claims, or
+    /// that names no ID known to us.
+    Forged,
+}
+
+/// A decoded frame, its signature not checked yet.
+pub(crate) struct Frame<'a> {
+    pub(crate) kind: FrameType,
+    /// When the sender made the frame, in Unix seconds.
+    pub(crate) timestamp: u64,
+    pub(crate) nonce: u64,
+    pub(crate) body: &'a [u8],
+    signed: &'a [u8],
+    signature: Signature,
+}
+
+impl Frame<'_> {
+    pub(crate) fn verify(&self, key: &VerifyingKey) -> bool {
+        key.verify_strict(self.signed, &self.signature).is_ok()
+    }
+}
+
+/// Whether `datagram` starts like one of our frames, of any version.
+pub(crate) fn has_magic(datagram: &[u8]) -> bool {
+    datagram.starts_with(MAGIC)
+}
+
+/// Split a datagram into its frame parts.
+pub(crate) fn decode(datagram: &[u8]) -> Result<Frame<'_>, Rejected> {
+    let Some(rest) = datagram.strip_prefix(MAGIC) else {
+        return Err(if is_bittorrent(datagram) {
+            Rejected::BitTorrent
+        } else {
+            Rejected::Foreign
+        });
+    };
+    let (&version, rest) = rest.split_first().ok_or(Rejected::Malformed)?;
+    if !(MIN_VERSION..=VERSION).contains(&version) {
+        return Err(Rejected::Version(version));
+    }
+    let (&kind, rest) = rest.split_first().ok_or(Rejected::Malformed)?;
+    let (timestamp, rest) = rest.split_first_chunk::<8>().ok_or(Rejected::Malformed)?;
+    let (nonce, rest) = rest.split_first_chunk::<8>().ok_or(Rejected::Malformed)?;
+    let (len, rest) = rest.split_first_chunk::<4>().ok_or(Rejected::Malformed)?;
+    let (sum, rest) = rest.split_first_chunk::<4>().ok_or(Rejected::Malformed)?;
+    let (body, signature) = rest
+        .split_last_chunk::<SIGNATURE_LENGTH>()
+        .ok_or(Rejected::Malformed)?;
+    if u32::from_be_bytes(*len) as usize != body.len() {
+        return Err(Rejected::Malformed);
+    }
+    if u32::from_be_bytes(*sum) != checksum(&datagram[..PREFIX_BYTES], body) {
+        return Err(Rejected::Corrupt);
+    }
+    let kind = FrameType::from_byte(kind).ok_or(Rejected::Malformed)?;
+    Ok(Frame {
+        kind,
+        timestamp: u64::from_be_bytes(*timestamp),
+        nonce: u64::from_be_bytes(*nonce),
+        body,
+        signed: &datagram[..datagram.len() - SIGNATURE_LENGTH],
+        signature: Signature::from_bytes(signature),
+    })
+}
+
+/// Body of a [`FrameType::Message`] frame.
+pub(crate) fn message_body(seq: u32, sender: &str, payload: &[u8]) -> Vec<u8> {
+    let sender = &sender.as_bytes()[..sender.len().min(u8::MAX as usize)];
+    let mut body = Vec::with_capacity(4 + 1 + sender.len() + payload.len());
+    body.extend_from_slice(&seq.to_be_bytes());
+    body.push(sender.len() as u8);
+    body.extend_from_slice(sender);
+    body.extend_from_slice(payload);
+    body
+}
+
+/// Sequence number, sender ID and payload of a message body.
+pub(crate) fn parse_message(body: &[u8]) -> Option<(u32, &[u8], &[u8])> {
+    let (seq, rest) = body.split_first_chunk::<4>()?;
+    let (&sender_len, rest) = rest.split_first()?;
+    let sender_len = sender_len as usize;
+    if rest.len() < sender_len {
+        return None;
+    }
+    let (sender, payload) = rest.split_at(sender_len);
+    Some((u32::from_be_bytes(*seq), sender, payload))
+}
+
+/// Body of a [`FrameType::Topic`] frame, around the body of a message
+/// frame of type `kind`. `topic` is at most 255 bytes long.
+pub(crate) fn topic_body(kind: FrameType, topic: &str, message: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + topic.len() + message.len());
+    body.push(kind as u8);
+    body.push(topic.len() as u8);
+    body.extend_from_slice(topic.as_bytes());
+    body.extend_from_slice(message);
+    body
+}
+
+/// Message type, topic and message body of a topic body.
+pub(crate) fn parse_topic(body: &[u8]) -> Option<(FrameType, &[u8], &[u8])> {
+    let (&[kind, topic_len], rest) = body.split_first_chunk::<2>()?;
+    let kind = FrameType::from_byte(kind).filter(|kind| {
+        matches!(
+            kind,
+            FrameType::Message | FrameType::CompressedMessage | FrameType::SealedMessage
+        )
+    })?;
+    let (topic, message) = rest.split_at_checked(usize::from(topic_len))?;
+    Some((kind, topic, message))
+}
+
+/// Body of a [`FrameType::MessageAck`] frame.
+pub(crate) fn ack_body(seq: u32, sender: &str) -> Vec<u8> {
+    let mut body = seq.to_be_bytes().to_vec();
+    body.extend_from_slice(sender.as_bytes());
+    body
+}
+
+/// Sequence number and sender ID of an ack body.
+pub(crate) fn parse_ack(body: &[u8]) -> Option<(u32, &[u8])> {
+    let (seq, sender) = body.split_first_chunk::<4>()?;
+    Some((u32::from_be_bytes(*seq), sender))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let identity = Identity::generate();
+        let body = b"binary \x00\xff body";
+        let first = encode(FrameType::Message, body, &identity);
+        let second = encode(FrameType::Message, &[], &identity);
+        assert_eq!(first.len(), OVERHEAD_BYTES + body.len());
+        assert!(has_magic(&first));
+
+        let frame = decode(&first).unwrap();
+        assert_eq!(frame.kind, FrameType::Message);
+        assert_eq!(frame.body, body);
+        assert!(frame.timestamp.abs_diff(unix_secs()) <= 1);
+        assert!(frame.verify(&identity.verifying_key()));
+        assert!(!frame.verify(&Identity::generate().verifying_key()));
+        let other = decode(&second).unwrap();
+        assert!(other.body.is_empty());
+        assert_ne!(frame.nonce, other.nonce);
+    }
+
+    #[test]
+    fn every_frame_type_decodes() {
+        let identity = Identity::generate();
+        for byte in 0..=u8::MAX {
+            match FrameType::from_byte(byte) {
+                Some(kind) => {
+                    assert_eq!(kind as u8, byte);
+                    let frame = encode(kind, b"body", &identity);
+                    assert_eq!(decode(&frame).unwrap().kind, kind);
+                }
+                None => assert!(byte == 0 || byte > FrameType::Topic as u8),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let frame = encode(FrameType::Control, b"some body", &Identity::generate());
+        for len in 0..frame.len() {
+            let expected = if len < MAGIC.len() {
+                Rejected::Foreign
+            } else {
+                Rejected::Malformed
+            };
+            assert_eq!(decode(&frame[..len]).err(), Some(expected), "{len} bytes");
+        }
+        let mut longer = frame.clone();
+        longer.push(0);
+        assert_eq!(decode(&longer).err(), Some(Rejected::Malformed));
+    }
+
+    #[test]
+    fn rejects_damage() {
+        let identity = Identity::generate();
+        let frame = encode(FrameType::Control, b"some body", &identity);
+        // The header after the version, and the body.
+        for at in 3..frame.len() - SIGNATURE_LENGTH {
+            let mut damaged = frame.clone();
+            damaged[at] ^= 0x10;
+            let rejected = decode(&damaged).err();
+            assert!(
+                matches!(rejected, Some(Rejected::Corrupt | Rejected::Malformed)),
+                "byte {at}: {rejected:?}"
+            );
+        }
+        // The signature is not covered by the checksum, only checked.
+        let mut forged = frame.clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(!decode(&forged).unwrap().verify(&identity.verifying_key()));
+        // Unknown types, with a matching checksum.
+        let mut unknown = frame.clone();
+        unknown[3] = 0xee;
+        let sum = checksum(&unknown[..PREFIX_BYTES], b"some body");
+        unknown[PREFIX_BYTES..HEADER_BYTES].copy_from_slice(&sum.to_be_bytes());
+        assert_eq!(decode(&unknown).err(), Some(Rejected::Malformed));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let frame = encode(FrameType::Control, b"hello", &Identity::generate());
+        for version in [0, 2, MIN_VERSION - 1, VERSION + 1, u8::MAX] {
+            let mut other = frame.clone();
+            other[2] = version;
+            assert!(has_magic(&other));
+            assert_eq!(decode(&other).err(), Some(Rejected::Version(version)));
+        }
+        assert_eq!(negotiate(MIN_VERSION, VERSION + 3), Some(VERSION));
+        assert_eq!(negotiate(2, MIN_VERSION - 1), None);
+        assert_eq!(negotiate(VERSION + 1, VERSION + 2), None);
+        assert_eq!(negotiate(legacy_version(), legacy_version()), None);
+    }
+
+    #[test]
+    fn rejects_foreign_datagrams() {
+        let krpc = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let mut handshake = b"\x13BitTorrent protocol".to_vec();
+        handshake.extend_from_slice(&[0; 48]);
+        // A uTP ST_SYN: type 4, version 1.
+        let mut utp = vec![0x41, 0];
+        utp.resize(20, 0);
+        for datagram in [&krpc[..], &handshake, &utp] {
+            assert!(!has_magic(datagram));
+            assert_eq!(decode(datagram).err(), Some(Rejected::BitTorrent));
+        }
+        for datagram in [&b""[..], b"GET / HTTP/1.1\r\n", b"D", b"dM\x07"] {
+            assert_eq!(decode(datagram).err(), Some(Rejected::Foreign));
+        }
+    }
+
+    #[test]
+    fn message_bodies() {
+        let body = message_body(7, "sender", b"payload");
+        assert_eq!(
+            parse_message(&body),
+            Some((7, &b"sender"[..], &b"payload"[..]))
+        );
+        assert_eq!(parse_message(&body[..4]), None);
+        assert_eq!(parse_message(&body[..8]), None);
+        let long_sender = "x".repeat(300);
+        let long_body = message_body(1, &long_sender, b"p");
+        let (_, sender, payload) = parse_message(&long_body).unwrap();
+        assert_eq!((sender.len(), payload), (255, &b"p"[..]));
+
+        let ack = ack_body(9, "sender");
+        assert_eq!(parse_ack(&ack), Some((9, &b"sender"[..])));
+        assert_eq!(parse_ack(&ack[..3]), None);
+
+        let topic = topic_body(FrameType::Message, "news", &body);
+        assert_eq!(
+            parse_topic(&topic),
+            Some((FrameType::Message, &b"news"[..], &body[..]))
+        );
+        assert_eq!(parse_topic(&topic[..4]), None);
+        let wrong_kind = topic_body(FrameType::Control, "news", &body);
+        assert_eq!(parse_topic(&wrong_kind), None);
+    }
+}